@@ -0,0 +1,550 @@
+use drmem_api::{
+    device,
+    driver::{self, DriverConfig},
+    Error, Result,
+};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde_json::json;
+use std::convert::Infallible;
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+// How long to wait for a service to accept a notification.
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+// The text of a logic block alarm's notification, if the
+// configuration doesn't give a template.
+
+const ALARM_TEMPLATE: &str = "{name} {event} ({severity}): {message}";
+
+// The services that can deliver notifications.
+
+enum Service {
+    Ntfy {
+        url: String,
+        token: Option<String>,
+    },
+    Pushover {
+        token: String,
+        user: String,
+    },
+    Telegram {
+        token: String,
+        chat_id: String,
+    },
+    Email {
+        smtp: Box<AsyncSmtpTransport<Tokio1Executor>>,
+        from: Mailbox,
+        to: Vec<Mailbox>,
+    },
+}
+
+pub struct Instance {
+    service: Service,
+    client: reqwest::Client,
+    title: Option<String>,
+    template: Option<String>,
+}
+
+pub struct Devices {
+    d_channel: driver::ReadWriteDevice<String>,
+}
+
+// Returns the string parameter `key`, if it was given.
+
+fn get_opt_str(cfg: &DriverConfig, key: &str) -> Result<Option<String>> {
+    match cfg.get(key) {
+        Some(toml::value::Value::String(v)) => Ok(Some(v.clone())),
+        Some(_) => Err(Error::ConfigError(format!(
+            "'{}' config parameter should be a string",
+            key
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn get_str(cfg: &DriverConfig, key: &str) -> Result<String> {
+    get_opt_str(cfg, key)?.ok_or_else(|| {
+        Error::ConfigError(format!("missing '{}' parameter in config", key))
+    })
+}
+
+fn get_mailbox(text: &str) -> Result<Mailbox> {
+    text.parse().map_err(|_| {
+        Error::ConfigError(format!("'{}' isn't a valid email address", text))
+    })
+}
+
+impl Instance {
+    pub const NAME: &'static str = "notify";
+
+    pub const SUMMARY: &'static str =
+        "sends notifications by email, Pushover, Telegram, or ntfy";
+
+    pub const DESCRIPTION: &'static str = include_str!("../README.md");
+
+    // Gets the name of the channel's device from the configuration.
+
+    fn get_cfg_channel(cfg: &DriverConfig) -> Result<device::Base> {
+        get_str(cfg, "channel")?
+            .parse::<device::Base>()
+            .map_err(|_| {
+                Error::ConfigError(String::from(
+                    "'channel' isn't a proper, base name for a device",
+                ))
+            })
+    }
+
+    fn get_cfg_email(cfg: &DriverConfig) -> Result<Service> {
+        let host = get_str(cfg, "host")?;
+        let port = match cfg.get("port") {
+            Some(toml::value::Value::Integer(v)) => {
+                u16::try_from(*v).map_err(|_| {
+                    Error::ConfigError(String::from("'port' is out of range"))
+                })?
+            }
+            Some(_) => {
+                return Err(Error::ConfigError(String::from(
+                    "'port' config parameter should be an integer",
+                )))
+            }
+            None => 587,
+        };
+
+        // Port 465 uses TLS from the start. Other ports have to
+        // switch to TLS before anything is sent.
+
+        let smtp = if port == 465 {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&host)
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)
+        }
+        .map_err(|e| Error::ConfigError(format!("bad 'host' -- {}", e)))?
+        .port(port)
+        .timeout(Some(TIMEOUT));
+        let smtp = match (
+            get_opt_str(cfg, "username")?,
+            get_opt_str(cfg, "password")?,
+        ) {
+            (Some(user), Some(pass)) => {
+                smtp.credentials(Credentials::new(user, pass))
+            }
+            (None, None) => smtp,
+            _ => {
+                return Err(Error::ConfigError(String::from(
+                    "'username' and 'password' have to be given together",
+                )))
+            }
+        };
+        let to = match cfg.get("to") {
+            Some(toml::value::Value::String(v)) => vec![get_mailbox(v)?],
+            Some(toml::value::Value::Array(v)) if !v.is_empty() => v
+                .iter()
+                .map(|v| match v {
+                    toml::value::Value::String(v) => get_mailbox(v),
+                    _ => Err(Error::ConfigError(String::from(
+                        "'to' should only contain strings",
+                    ))),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => {
+                return Err(Error::ConfigError(String::from(
+                    "'to' should be an address or an array of them",
+                )))
+            }
+            None => {
+                return Err(Error::ConfigError(String::from(
+                    "missing 'to' parameter in config",
+                )))
+            }
+        };
+
+        Ok(Service::Email {
+            smtp: Box::new(smtp.build()),
+            from: get_mailbox(&get_str(cfg, "from")?)?,
+            to,
+        })
+    }
+
+    // Gets the service, and its parameters, from the configuration.
+
+    fn get_cfg_service(cfg: &DriverConfig) -> Result<Service> {
+        match get_str(cfg, "service")?.as_str() {
+            "ntfy" => {
+                let url = get_str(cfg, "url")?;
+
+                if !(url.starts_with("http://") || url.starts_with("https://"))
+                {
+                    return Err(Error::ConfigError(String::from(
+                        "'url' needs to be an HTTP or HTTPS URL",
+                    )));
+                }
+                Ok(Service::Ntfy {
+                    url,
+                    token: get_opt_str(cfg, "token")?,
+                })
+            }
+            "pushover" => Ok(Service::Pushover {
+                token: get_str(cfg, "token")?,
+                user: get_str(cfg, "user")?,
+            }),
+            "telegram" => Ok(Service::Telegram {
+                token: get_str(cfg, "token")?,
+                chat_id: match cfg.get("chat_id") {
+                    Some(toml::value::Value::Integer(v)) => v.to_string(),
+                    _ => get_str(cfg, "chat_id")?,
+                },
+            }),
+            "email" => Instance::get_cfg_email(cfg),
+            v => Err(Error::ConfigError(format!(
+                "'service' has an unknown value: {}",
+                v
+            ))),
+        }
+    }
+
+    fn new(cfg: &DriverConfig) -> Result<Instance> {
+        Ok(Instance {
+            service: Instance::get_cfg_service(cfg)?,
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .map_err(|e| Error::OperationError(e.to_string()))?,
+            title: get_opt_str(cfg, "title")?,
+            template: get_opt_str(cfg, "template")?,
+        })
+    }
+
+    // Builds the text of a notification from the message that was
+    // sent to the device. The notifications of logic block alarms are
+    // JSON objects, so their fields can be used in the template.
+
+    fn text(&self, message: &str) -> String {
+        let alarm = serde_json::from_str::<serde_json::Value>(message)
+            .ok()
+            .filter(|v| v["message"].is_string());
+
+        match alarm {
+            Some(alarm) => {
+                let field = |key: &str| alarm[key].as_str().unwrap_or("");
+
+                self.template
+                    .as_deref()
+                    .unwrap_or(ALARM_TEMPLATE)
+                    .replace("{name}", field("name"))
+                    .replace("{severity}", field("severity"))
+                    .replace("{event}", field("event"))
+                    .replace("{message}", field("message"))
+            }
+            None => self
+                .template
+                .as_deref()
+                .unwrap_or("{message}")
+                .replace("{message}", message),
+        }
+    }
+
+    // Builds the request that sends a notification to one of the web
+    // services. Email isn't sent with HTTP, so it returns `None` for
+    // it.
+
+    fn request(&self, text: &str) -> Option<reqwest::RequestBuilder> {
+        match &self.service {
+            Service::Ntfy { url, token } => {
+                let mut req = self.client.post(url).body(text.to_string());
+
+                if let Some(title) = &self.title {
+                    req = req.header("Title", title)
+                }
+                if let Some(token) = token {
+                    req = req.bearer_auth(token)
+                }
+                Some(req)
+            }
+            Service::Pushover { token, user } => {
+                let mut body = json!({
+                    "token": token,
+                    "user": user,
+                    "message": text,
+                });
+
+                if let Some(title) = &self.title {
+                    body["title"] = json!(title)
+                }
+                Some(
+                    self.client
+                        .post("https://api.pushover.net/1/messages.json")
+                        .json(&body),
+                )
+            }
+            Service::Telegram { token, chat_id } => {
+                let text = match &self.title {
+                    Some(title) => format!("{}\n{}", title, text),
+                    None => text.to_string(),
+                };
+
+                Some(
+                    self.client
+                        .post(format!(
+                            "https://api.telegram.org/bot{}/sendMessage",
+                            token
+                        ))
+                        .json(&json!({ "chat_id": chat_id, "text": text })),
+                )
+            }
+            Service::Email { .. } => None,
+        }
+    }
+
+    async fn send(&self, message: &str) -> Result<()> {
+        let text = self.text(message);
+
+        if let Service::Email { smtp, from, to } = &self.service {
+            let mut msg = Message::builder()
+                .from(from.clone())
+                .subject(self.title.as_deref().unwrap_or("DrMem"))
+                .header(ContentType::TEXT_PLAIN);
+
+            for addr in to {
+                msg = msg.to(addr.clone())
+            }
+
+            let msg = msg
+                .body(text)
+                .map_err(|e| Error::OperationError(e.to_string()))?;
+
+            return smtp
+                .send(msg)
+                .await
+                .map(|_| ())
+                .map_err(|e| Error::OperationError(e.to_string()));
+        }
+
+        self.request(&text)
+            .unwrap()
+            .send()
+            .await
+            .and_then(|v| v.error_for_status())
+            .map(|_| ())
+            .map_err(|e| Error::OperationError(e.to_string()))
+    }
+}
+
+impl driver::API for Instance {
+    type DeviceSet = Devices;
+
+    fn register_devices(
+        core: driver::RequestChan,
+        cfg: &DriverConfig,
+        max_history: Option<usize>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
+        let channel = Instance::get_cfg_channel(cfg);
+
+        // Check the rest of the configuration now so mistakes are
+        // reported when `drmemd` starts.
+
+        let valid = Instance::new(cfg).map(|_| ());
+
+        Box::pin(async move {
+            let channel = channel?;
+
+            valid?;
+
+            Ok(Devices {
+                d_channel: core
+                    .add_rw_device(channel, None, max_history)
+                    .await?,
+            })
+        })
+    }
+
+    fn create_instance(
+        cfg: &DriverConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let inst = Instance::new(cfg);
+
+        Box::pin(async move { inst.map(Box::new) })
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
+        let fut = async move {
+            let mut devices = devices.lock().await;
+
+            // Each setting is a message to send. The setting is only
+            // accepted, and reported as the device's value, once the
+            // service has taken it.
+
+            while let Some((v, reply)) = devices.d_channel.next_setting().await
+            {
+                if v.trim().is_empty() {
+                    reply(Err(Error::InvArgument("empty message".into())));
+                    continue;
+                }
+
+                match self.send(&v).await {
+                    Ok(()) => {
+                        reply(Ok(v.clone()));
+                        devices.d_channel.report_update(v).await
+                    }
+                    Err(e) => {
+                        warn!("couldn't send notification -- {}", &e);
+                        reply(Err(e))
+                    }
+                }
+            }
+            panic!("can no longer receive settings");
+        };
+
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> DriverConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    // Returns the URL, a header, and the body of the request sent for
+    // a message.
+
+    fn sent(
+        cfg: &str,
+        header: &str,
+        msg: &str,
+    ) -> (String, Option<String>, String) {
+        let inst = Instance::new(&config(cfg)).unwrap();
+        let req = inst.request(&inst.text(msg)).unwrap().build().unwrap();
+
+        (
+            req.url().to_string(),
+            req.headers()
+                .get(header)
+                .map(|v| v.to_str().unwrap().to_string()),
+            String::from_utf8(req.body().unwrap().as_bytes().unwrap().to_vec())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_config() {
+        let cfg = config("channel = \"phone\"");
+
+        assert_eq!(
+            Instance::get_cfg_channel(&cfg).unwrap(),
+            "phone".parse::<device::Base>().unwrap()
+        );
+        assert!(Instance::get_cfg_channel(&config("channel = 5")).is_err());
+        assert!(
+            Instance::get_cfg_channel(&config("channel = \"a b\"")).is_err()
+        );
+
+        assert!(Instance::new(&config("service = \"fax\"")).is_err());
+        assert!(Instance::new(&config("service = \"pushover\"")).is_err());
+        assert!(Instance::new(&config(
+            "service = \"ntfy\"\nurl = \"ntfy.sh/sump\""
+        ))
+        .is_err());
+        assert!(Instance::new(&config(
+            "service = \"email\"\nhost = \"smtp.example.com\"\n\
+             from = \"drmem@example.com\"\nto = [\"me@example.com\"]\n\
+             username = \"drmem\""
+        ))
+        .is_err());
+        assert!(Instance::new(&config(
+            "service = \"email\"\nhost = \"smtp.example.com\"\n\
+             from = \"drmem@example.com\"\nto = \"not an address\""
+        ))
+        .is_err());
+        assert!(Instance::new(&config(
+            "service = \"email\"\nhost = \"smtp.example.com\"\nport = 465\n\
+             from = \"DrMem <drmem@example.com>\"\n\
+             to = [\"me@example.com\", \"you@example.com\"]\n\
+             username = \"drmem\"\npassword = \"secret\""
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn test_requests() {
+        let (url, title, body) = sent(
+            "service = \"ntfy\"\nurl = \"https://ntfy.sh/sump\"\n\
+             title = \"Basement\"\ntemplate = \"sump: {message}\"",
+            "Title",
+            "high water!",
+        );
+
+        assert_eq!(url, "https://ntfy.sh/sump");
+        assert_eq!(title.as_deref(), Some("Basement"));
+        assert_eq!(body, "sump: high water!");
+
+        let (url, _, body) = sent(
+            "service = \"pushover\"\ntoken = \"app\"\nuser = \"me\"",
+            "",
+            "high water!",
+        );
+
+        assert_eq!(url, "https://api.pushover.net/1/messages.json");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!({ "token": "app", "user": "me", "message": "high water!" })
+        );
+
+        let (url, _, body) = sent(
+            "service = \"telegram\"\ntoken = \"123:abc\"\nchat_id = 42\n\
+             title = \"Basement\"",
+            "",
+            "high water!",
+        );
+
+        assert_eq!(url, "https://api.telegram.org/bot123:abc/sendMessage");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!({ "chat_id": "42", "text": "Basement\nhigh water!" })
+        );
+    }
+
+    #[test]
+    fn test_alarms() {
+        let alarm = json!({
+            "name": "sump-high",
+            "severity": "critical",
+            "event": "raised",
+            "message": "sump water level is 31.2 cm",
+            "stamp": "2024-05-01T13:02:11+00:00",
+        })
+        .to_string();
+        let inst = |template: &str| {
+            Instance::new(&config(&format!(
+                "service = \"pushover\"\ntoken = \"app\"\nuser = \"me\"\n{}",
+                template
+            )))
+            .unwrap()
+        };
+
+        assert_eq!(
+            inst("").text(&alarm),
+            "sump-high raised (critical): sump water level is 31.2 cm"
+        );
+        assert_eq!(
+            inst("template = \"{severity}! {message}\"").text(&alarm),
+            "critical! sump water level is 31.2 cm"
+        );
+
+        // Other JSON is sent as it is.
+
+        assert_eq!(inst("").text("[1, 2]"), "[1, 2]");
+        assert_eq!(inst("").text("{\"a\": 1}"), "{\"a\": 1}");
+    }
+}
@@ -0,0 +1,316 @@
+// Talks to the GraphQL interface of the other `drmemd`. Settings are
+// sent as mutations over HTTP. Readings arrive over a WebSocket that
+// uses the `graphql-transport-ws` protocol; each mirrored device has
+// its own subscription on the one connection.
+
+use drmem_api::{
+    device,
+    graphql::{self, get_data},
+    Error, Result,
+};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value as Json};
+use tokio::{sync::mpsc, time::Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+// How long a setting can take before it's reported as failed.
+
+const SETTING_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MONITOR: &str = "subscription ($name: String!) \
+                       { monitorDevice(device: $name) \
+                       { boolValue intValue floatValue stringValue \
+                       colorValue } }";
+
+const SET: &str = "mutation ($name: String!, $value: SettingData!) \
+                   { setDevice(name: $name, value: $value) \
+                   { boolValue intValue floatValue stringValue \
+                   colorValue } }";
+
+// What the connection to the other node reports.
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Connected(bool),
+    Reading(usize, device::Value),
+}
+
+// Converts a reading returned by `drmemd` to a value.
+
+fn to_value(reading: &Json) -> Option<device::Value> {
+    if let Some(v) = reading["boolValue"].as_bool() {
+        Some(v.into())
+    } else if let Some(v) = reading["intValue"].as_i64() {
+        Some((v as i32).into())
+    } else if let Some(v) = reading["floatValue"].as_f64() {
+        Some(v.into())
+    } else if let Some(v) = reading["stringValue"].as_str() {
+        Some(v.into())
+    } else {
+        let c: Vec<u8> = reading["colorValue"]
+            .as_array()?
+            .iter()
+            .map(|v| v.as_u64().map(|v| v as u8))
+            .collect::<Option<_>>()?;
+
+        match c[..] {
+            [r, g, b] => Some(palette::LinSrgba::new(r, g, b, 255).into()),
+            [r, g, b, a] => Some(palette::LinSrgba::new(r, g, b, a).into()),
+            _ => None,
+        }
+    }
+}
+
+// Converts a value to the `SettingData` argument of a `setDevice`
+// mutation.
+
+fn to_setting(value: &device::Value) -> Json {
+    match value {
+        device::Value::Bool(v) => json!({ "bool": v }),
+        device::Value::Int(v) => json!({ "int": v }),
+        device::Value::Flt(v) => json!({ "flt": v }),
+        device::Value::Str(v) => json!({ "str": v.as_ref() }),
+        device::Value::Color(v) => {
+            json!({ "color": [v.red, v.green, v.blue, v.alpha] })
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Client(graphql::Client);
+
+impl Client {
+    pub fn new(url: &str, client_id: Option<String>) -> Result<Self> {
+        graphql::Client::new(url, client_id, Some(SETTING_TIMEOUT))
+            .map(Client)
+            .map_err(|e| Error::ConfigError(format!("'url' -- {}", e)))
+    }
+
+    pub fn url(&self) -> &str {
+        self.0.url()
+    }
+
+    // Sends a setting to a device of the other node. Returns the
+    // value its driver used.
+
+    pub async fn set(
+        &self,
+        name: &str,
+        value: device::Value,
+    ) -> Result<device::Value> {
+        let data = self
+            .0
+            .query(SET, json!({ "name": name, "value": to_setting(&value) }))
+            .await?;
+
+        to_value(&data["setDevice"]).ok_or_else(|| {
+            Error::ProtocolError("reply to setting has no value".into())
+        })
+    }
+
+    // Monitors the devices named in `names`. Readings are sent to
+    // `tx` with the index of their device. Returns when the
+    // connection is lost.
+
+    pub async fn monitor(
+        &self,
+        names: &[String],
+        tx: &mpsc::Sender<Event>,
+    ) -> Result<()> {
+        let mut ws = self.0.connect().await?;
+        let lost = |e: tokio_tungstenite::tungstenite::Error| {
+            Error::MissingPeer(format!("connection failed -- {}", e))
+        };
+
+        while let Some(msg) = ws.next().await {
+            let msg = match msg.map_err(lost)? {
+                Message::Text(v) => v,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let mut msg: Json = serde_json::from_str(&msg)
+                .map_err(|e| Error::ProtocolError(e.to_string()))?;
+            let idx = msg["id"]
+                .as_str()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|v| *v < names.len());
+
+            match (msg["type"].as_str(), idx) {
+                (Some("connection_ack"), _) => {
+                    for (idx, name) in names.iter().enumerate() {
+                        let start = json!({
+                            "id": idx.to_string(),
+                            "type": "subscribe",
+                            "payload": {
+                                "query": MONITOR,
+                                "variables": { "name": name },
+                            },
+                        });
+
+                        ws.send(Message::text(start.to_string()))
+                            .await
+                            .map_err(lost)?
+                    }
+
+                    if tx.send(Event::Connected(true)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                (Some("ping"), _) => ws
+                    .send(Message::text(json!({ "type": "pong" }).to_string()))
+                    .await
+                    .map_err(lost)?,
+                (Some("next"), Some(idx)) => {
+                    let data = get_data(msg["payload"].take())?;
+
+                    match to_value(&data["monitorDevice"]) {
+                        Some(v) => {
+                            if tx.send(Event::Reading(idx, v)).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        None => warn!(
+                            "{} sent a reading without a value",
+                            names[idx]
+                        ),
+                    }
+                }
+
+                // A device that can't be monitored is reported, but
+                // the others continue to be mirrored.
+                (Some("error"), Some(idx)) => {
+                    match get_data(json!({ "errors": msg["payload"] })) {
+                        Err(e) => {
+                            warn!("can't monitor {} -- {}", names[idx], e)
+                        }
+                        Ok(_) => warn!("can't monitor {}", names[idx]),
+                    }
+                }
+                (Some("complete"), Some(idx)) => {
+                    return Err(Error::OperationError(format!(
+                        "monitor of {} was ended by the other node",
+                        names[idx]
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Err(Error::MissingPeer(format!(
+            "{} closed the connection",
+            self.url()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::WebSocketStream;
+
+    async fn recv(ws: &mut WebSocketStream<TcpStream>) -> Json {
+        match ws.next().await {
+            Some(Ok(Message::Text(v))) => serde_json::from_str(&v).unwrap(),
+            v => panic!("unexpected message {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_values() {
+        assert!(Client::new("host:3000", None).is_err());
+
+        for v in [
+            device::Value::Bool(true),
+            device::Value::Int(-3),
+            device::Value::Flt(1.5),
+            device::Value::Str("on".into()),
+            device::Value::Color(palette::LinSrgba::new(1, 2, 3, 4)),
+        ] {
+            let setting = to_setting(&v);
+            let reading = match setting.as_object().unwrap().iter().next() {
+                Some((k, v)) => match k.as_str() {
+                    "bool" => json!({ "boolValue": v }),
+                    "int" => json!({ "intValue": v }),
+                    "flt" => json!({ "floatValue": v }),
+                    "str" => json!({ "stringValue": v }),
+                    _ => json!({ "colorValue": v }),
+                },
+                None => panic!("empty setting"),
+            };
+
+            assert_eq!(to_value(&reading), Some(v));
+        }
+
+        // Whole floats, and colors without an alpha channel, are
+        // accepted.
+
+        assert_eq!(
+            to_value(&json!({ "floatValue": 2.0 })),
+            Some(device::Value::Flt(2.0))
+        );
+        assert_eq!(
+            to_value(&json!({ "colorValue": [1, 2, 3] })),
+            Some(device::Value::Color(palette::LinSrgba::new(1, 2, 3, 255)))
+        );
+        assert_eq!(to_value(&json!({ "stamp": "" })), None);
+    }
+
+    #[tokio::test]
+    async fn test_monitor() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Fake the other node. It acknowledges the connection, sends a
+        // reading of the first device, refuses the second and then
+        // closes the connection.
+
+        tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(s).await.unwrap();
+            assert_eq!(recv(&mut ws).await["type"], "connection_init");
+            ws.send(Message::text(r#"{"type":"connection_ack"}"#))
+                .await
+                .unwrap();
+
+            for idx in ["0", "1"] {
+                let msg = recv(&mut ws).await;
+
+                assert_eq!(msg["id"], idx);
+                assert_eq!(msg["type"], "subscribe");
+            }
+
+            let msgs = [
+                json!({
+                    "id": "0",
+                    "type": "next",
+                    "payload": { "data": { "monitorDevice": {
+                        "floatValue": 71.5
+                    } } },
+                }),
+                json!({
+                    "id": "1",
+                    "type": "error",
+                    "payload": [{ "message": "device not found" }],
+                }),
+            ];
+
+            for msg in msgs {
+                ws.send(Message::text(msg.to_string())).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+        });
+
+        let client = Client::new(&format!("http://{}/", addr), None).unwrap();
+        let (tx, mut rx) = mpsc::channel(10);
+        let names = ["porch:temp".to_string(), "porch:gone".to_string()];
+
+        assert!(client.monitor(&names, &tx).await.is_err());
+        assert_eq!(rx.recv().await, Some(Event::Connected(true)));
+        assert_eq!(
+            rx.recv().await,
+            Some(Event::Reading(0, device::Value::Flt(71.5)))
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use drmem_api::{
+    device,
+    driver::{self, DriverConfig},
+    Error, Result,
+};
+use futures::future::{pending, select_all};
+use std::{convert::Infallible, future::Future, pin::Pin, sync::Arc};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{sleep, Duration},
+};
+use tracing::{debug, info, warn, Span};
+
+mod graphql;
+
+// How long to wait before connecting again, after the connection to
+// the other node is lost.
+
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// The base name of the device reporting the state of the connection.
+// Mirrored devices can't use it.
+
+const STATE: &str = "state";
+
+// Holds the configuration of a mirrored device.
+
+#[derive(Debug, PartialEq)]
+struct Mirror {
+    name: device::Base,
+    remote: device::Name,
+    units: Option<String>,
+    settable: bool,
+}
+
+// The local copy of a device of the other node.
+
+enum Local {
+    ReadOnly(driver::ReadOnlyDevice<device::Value>),
+    ReadWrite(driver::ReadWriteDevice<device::Value>),
+}
+
+impl Local {
+    async fn report_update(&mut self, value: device::Value) {
+        match self {
+            Local::ReadOnly(dev) => dev.report_update(value).await,
+            Local::ReadWrite(dev) => dev.report_update(value).await,
+        }
+    }
+}
+
+pub struct Instance {
+    client: graphql::Client,
+}
+
+pub struct Devices {
+    d_state: driver::ReadOnlyDevice<bool>,
+    // The mirrored devices, with the names they have on the other
+    // node.
+    d_mirrors: Vec<(String, Local)>,
+}
+
+impl Instance {
+    pub const NAME: &'static str = "remote";
+
+    pub const SUMMARY: &'static str =
+        "mirrors devices of another drmemd using its GraphQL interface";
+
+    pub const DESCRIPTION: &'static str = include_str!("../README.md");
+
+    fn get_cfg_url(cfg: &DriverConfig) -> Result<String> {
+        match cfg.get("url") {
+            Some(toml::value::Value::String(url)) => Ok(url.to_string()),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'url' config parameter should be a string",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'url' parameter in config",
+            ))),
+        }
+    }
+
+    fn get_cfg_client_id(cfg: &DriverConfig) -> Result<Option<String>> {
+        match cfg.get("client_id") {
+            Some(toml::value::Value::String(id)) => Ok(Some(id.to_string())),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'client_id' config parameter should be a string",
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Converts a TOML table into a `Mirror`.
+
+    fn to_mirror(tbl: &toml::Table) -> Result<Mirror> {
+        let name = match tbl.get("name") {
+            Some(toml::value::Value::String(name)) => name
+                .parse::<device::Base>()
+                .ok()
+                .filter(|_| name != STATE)
+                .ok_or_else(|| {
+                    Error::ConfigError(format!(
+                        "'{}' can't be used as a device name",
+                        name
+                    ))
+                })?,
+            _ => {
+                return Err(Error::ConfigError(String::from(
+                    "each device needs a 'name' string",
+                )))
+            }
+        };
+
+        let remote = match tbl.get("remote") {
+            Some(toml::value::Value::String(remote)) => {
+                remote.parse::<device::Name>().map_err(|_| {
+                    Error::ConfigError(format!(
+                        "'{}' isn't a valid device name",
+                        remote
+                    ))
+                })?
+            }
+            _ => {
+                return Err(Error::ConfigError(format!(
+                    "device '{}' needs a 'remote' string",
+                    name
+                )))
+            }
+        };
+
+        let units = match tbl.get("units") {
+            Some(toml::value::Value::String(units)) => Some(units.to_string()),
+            Some(_) => {
+                return Err(Error::ConfigError(format!(
+                    "'units' of device '{}' should be a string",
+                    name
+                )))
+            }
+            None => None,
+        };
+
+        let settable = match tbl.get("settable") {
+            Some(toml::value::Value::Boolean(v)) => *v,
+            Some(_) => {
+                return Err(Error::ConfigError(format!(
+                    "'settable' of device '{}' should be a boolean",
+                    name
+                )))
+            }
+            None => false,
+        };
+
+        Ok(Mirror {
+            name,
+            remote,
+            units,
+            settable,
+        })
+    }
+
+    // Validates the list of mirrored devices.
+
+    fn get_cfg_devices(cfg: &DriverConfig) -> Result<Vec<Mirror>> {
+        match cfg.get("devices") {
+            Some(toml::value::Value::Array(devs)) if !devs.is_empty() => {
+                let devs = devs
+                    .iter()
+                    .map(|v| match v {
+                        toml::value::Value::Table(tbl) => {
+                            Instance::to_mirror(tbl)
+                        }
+                        _ => Err(Error::ConfigError(String::from(
+                            "'devices' should be an array of tables",
+                        ))),
+                    })
+                    .collect::<Result<Vec<Mirror>>>()?;
+
+                for (idx, dev) in devs.iter().enumerate() {
+                    if devs[..idx].iter().any(|d| d.name == dev.name) {
+                        return Err(Error::ConfigError(format!(
+                            "device '{}' is defined more than once",
+                            dev.name
+                        )));
+                    }
+                }
+                Ok(devs)
+            }
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'devices' should be a non-empty array of tables",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'devices' parameter in config",
+            ))),
+        }
+    }
+
+    // Keeps a connection to the other node, connecting again whenever
+    // it's lost.
+
+    async fn watch(
+        client: &graphql::Client,
+        names: &[String],
+        tx: mpsc::Sender<graphql::Event>,
+    ) -> Infallible {
+        loop {
+            debug!("connecting");
+
+            if let Err(e) = client.monitor(names, &tx).await {
+                warn!("lost connection -- {}", e)
+            }
+
+            let _ = tx.send(graphql::Event::Connected(false)).await;
+
+            sleep(RETRY_DELAY).await
+        }
+    }
+}
+
+// Waits for a setting of one of the settable devices. Returns the
+// index of the device and the setting.
+
+async fn next_setting(
+    mirrors: &mut [(String, Local)],
+) -> (usize, (device::Value, driver::SettingReply<device::Value>)) {
+    let settings: Vec<_> = mirrors
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(idx, (_, dev))| match dev {
+            Local::ReadWrite(dev) => {
+                Some(Box::pin(async move { (idx, dev.next_setting().await) }))
+            }
+            Local::ReadOnly(_) => None,
+        })
+        .collect();
+
+    if !settings.is_empty() {
+        if let ((idx, Some(setting)), _, _) = select_all(settings).await {
+            return (idx, setting);
+        }
+    }
+
+    // Without settable devices, or once the core has closed a
+    // setting channel, there's nothing left to forward.
+
+    pending().await
+}
+
+impl driver::API for Instance {
+    type DeviceSet = Devices;
+
+    fn register_devices(
+        core: driver::RequestChan,
+        cfg: &DriverConfig,
+        max_history: Option<usize>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
+        let state_name = STATE.parse::<device::Base>().unwrap();
+        let mirrors = Instance::get_cfg_devices(cfg);
+
+        Box::pin(async move {
+            let mirrors = mirrors?;
+            let d_state =
+                core.add_ro_device(state_name, None, max_history).await?;
+            let mut d_mirrors = Vec::with_capacity(mirrors.len());
+
+            for Mirror {
+                name,
+                remote,
+                units,
+                settable,
+            } in mirrors
+            {
+                let dev = if settable {
+                    Local::ReadWrite(
+                        core.add_rw_device(name, units.as_deref(), max_history)
+                            .await?,
+                    )
+                } else {
+                    Local::ReadOnly(
+                        core.add_ro_device(name, units.as_deref(), max_history)
+                            .await?,
+                    )
+                };
+
+                d_mirrors.push((remote.to_string(), dev))
+            }
+
+            Ok(Devices { d_state, d_mirrors })
+        })
+    }
+
+    fn create_instance(
+        cfg: &DriverConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let url = Instance::get_cfg_url(cfg);
+        let client_id = Instance::get_cfg_client_id(cfg);
+
+        Box::pin(async move {
+            let client = graphql::Client::new(&url?, client_id?)?;
+
+            debug!("instance successfully created");
+            Ok(Box::new(Instance { client }))
+        })
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
+        let fut = async move {
+            let mut devices = devices.lock().await;
+
+            Span::current().record("cfg", self.client.url());
+
+            let names: Vec<String> =
+                devices.d_mirrors.iter().map(|(v, _)| v.clone()).collect();
+            let (tx, mut rx) = mpsc::channel(100);
+            let watch = Instance::watch(&self.client, &names, tx);
+
+            tokio::pin!(watch);
+            devices.d_state.report_update(false).await;
+
+            loop {
+                tokio::select! {
+                    never = &mut watch => match never {},
+
+                    Some(event) = rx.recv() => match event {
+                        graphql::Event::Connected(v) => {
+                            if v {
+                                info!("connected")
+                            }
+                            devices.d_state.report_update(v).await
+                        }
+                        graphql::Event::Reading(idx, v) => {
+                            devices.d_mirrors[idx].1.report_update(v).await
+                        }
+                    },
+
+                    // Settings are forwarded in their own task so a
+                    // slow reply doesn't hold up the readings. The
+                    // mirrored device is updated when the other node
+                    // reports the new reading.
+                    (idx, (v, reply)) =
+                        next_setting(&mut devices.d_mirrors) => {
+                        let client = self.client.clone();
+                        let name = devices.d_mirrors[idx].0.clone();
+
+                        tokio::spawn(async move {
+                            reply(client.set(&name, v).await)
+                        });
+                    }
+                }
+            }
+        };
+
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(text: &str) -> DriverConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_cfg() {
+        let good = cfg(r#"
+url = "http://barn.local:3000"
+devices = [{ name = "temp", remote = "barn:temp", units = "°F" },
+           { name = "fan", remote = "barn:fan:state", settable = true }]
+"#);
+
+        assert_eq!(
+            Instance::get_cfg_url(&good).unwrap(),
+            "http://barn.local:3000"
+        );
+        assert_eq!(Instance::get_cfg_client_id(&good).unwrap(), None);
+        assert_eq!(
+            Instance::get_cfg_devices(&good).unwrap(),
+            vec![
+                Mirror {
+                    name: "temp".parse().unwrap(),
+                    remote: "barn:temp".parse().unwrap(),
+                    units: Some("°F".into()),
+                    settable: false,
+                },
+                Mirror {
+                    name: "fan".parse().unwrap(),
+                    remote: "barn:fan:state".parse().unwrap(),
+                    units: None,
+                    settable: true,
+                },
+            ]
+        );
+
+        for bad in [
+            "devices = []",
+            "devices = [\"barn:temp\"]",
+            "devices = [{ name = \"temp\" }]",
+            "devices = [{ name = \"temp\", remote = \"temp\" }]",
+            "devices = [{ name = \"state\", remote = \"barn:state\" }]",
+            "devices = [{ name = \"fan\", remote = \"barn:fan\", \
+             settable = \"yes\" }]",
+            "devices = [{ name = \"a\", remote = \"barn:a\" }, \
+             { name = \"a\", remote = \"barn:b\" }]",
+        ] {
+            assert!(Instance::get_cfg_devices(&cfg(bad)).is_err(), "{}", bad);
+        }
+
+        assert!(Instance::get_cfg_url(&cfg("url = 3000")).is_err());
+        assert!(Instance::get_cfg_client_id(&cfg("client_id = 1")).is_err());
+    }
+}
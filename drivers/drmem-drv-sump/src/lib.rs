@@ -143,11 +143,66 @@ impl State {
     }
 }
 
+// Tracks the high-water alarm. The alarm latches when the float
+// switch detects high water. It only clears after it has been
+// acknowledged and the water has gone back down.
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+#[derive(Default)]
+struct Alarm {
+    high_water: bool,
+    latched: bool,
+    acked: bool,
+}
+
+impl Alarm {
+    // Updates the alarm with the state of the float switch. Returns
+    // `Some()` with the new alarm state, if it changed.
+
+    pub fn float_event(&mut self, high_water: bool) -> Option<bool> {
+        self.high_water = high_water;
+
+        if high_water && !self.latched {
+            self.latched = true;
+            self.acked = false;
+            Some(true)
+        } else if !high_water && self.latched && self.acked {
+            self.latched = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    // Acknowledges the alarm. Returns `Some(false)` if the alarm
+    // cleared.
+
+    pub fn ack(&mut self) -> Option<bool> {
+        self.acked = true;
+
+        if self.latched && !self.high_water {
+            self.latched = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+// The size of a packet sent by the remote process.
+
+const PACKET_SIZE: usize = 12;
+
 pub struct Instance {
     state: State,
+    alarm: Alarm,
+    pump: Option<bool>,
     gpm: f64,
+    addr: SocketAddrV4,
     rx: OwnedReadHalf,
     _tx: OwnedWriteHalf,
+    buf: [u8; PACKET_SIZE],
+    buf_len: usize,
 }
 
 pub struct Devices {
@@ -156,6 +211,8 @@ pub struct Devices {
     d_duty: driver::ReadOnlyDevice<f64>,
     d_inflow: driver::ReadOnlyDevice<f64>,
     d_duration: driver::ReadOnlyDevice<f64>,
+    d_alarm: driver::ReadOnlyDevice<bool>,
+    d_ack: driver::ReadWriteDevice<bool>,
 }
 
 impl Instance {
@@ -270,15 +327,101 @@ impl Instance {
         }
     }
 
-    // This function reads the next frame from the sump pump process.
-    // It either returns `Ok()` with the two fields' values or `Err()`
-    // if a socket error occurred.
+    // Computes the delay before the next reconnection attempt. The
+    // delay starts at one second and doubles with each failure until
+    // it reaches a minute.
+
+    fn next_delay(delay: time::Duration) -> time::Duration {
+        std::cmp::min(delay * 2, time::Duration::from_secs(60))
+    }
+
+    // Tries to re-establish the connection with the sump pump
+    // process. This function doesn't return until a new connection
+    // has been made. Since we lost track of the pump's state while
+    // disconnected, the state machine is reset so it re-syncs with
+    // the next OFF event.
+
+    async fn reconnect(&mut self) {
+        let mut delay = time::Duration::from_secs(1);
+
+        loop {
+            time::sleep(delay).await;
+
+            if let Ok(s) = Instance::connect(&self.addr) {
+                let (rx, tx) = s.into_split();
+
+                self.rx = rx;
+                self._tx = tx;
+                self.state = State::Unknown;
+                self.pump = None;
+                self.buf_len = 0;
+                break;
+            }
+
+            delay = Instance::next_delay(delay);
+            warn!("retrying connection in {:?}", delay);
+        }
+    }
+
+    // Updates the pump's state machine and reports the results of a
+    // completed cycle.
+
+    async fn pump_event(
+        &mut self,
+        devices: &mut Devices,
+        stamp: u64,
+        on: bool,
+    ) {
+        if on {
+            if self.state.on_event(stamp) {
+                devices.d_state.report_update(true).await;
+            }
+        } else if let Some((cycle, duty, in_flow)) =
+            self.state.off_event(stamp, self.gpm)
+        {
+            debug!(
+                "cycle: {}, duty: {:.1}%, inflow: {:.2} gpm",
+                Instance::elapsed(cycle),
+                duty,
+                in_flow
+            );
+
+            devices.d_state.report_update(false).await;
+            devices.d_duty.report_update(duty).await;
+            devices.d_inflow.report_update(in_flow).await;
+            devices
+                .d_duration
+                .report_update(((cycle as f64) / 600.0).round() / 100.0)
+                .await;
+        }
+    }
+
+    // Decodes a packet from the sump pump process. The first 8 bytes
+    // hold the timestamp. The last 4 bytes hold the state of the
+    // inputs: bit 0 is the pump state and bit 1 is the high-water
+    // float switch.
 
-    async fn get_reading(&mut self) -> io::Result<(u64, bool)> {
-        let stamp = self.rx.read_u64().await?;
-        let value = self.rx.read_u32().await?;
+    fn decode(buf: &[u8; PACKET_SIZE]) -> (u64, bool, bool) {
+        let stamp = u64::from_be_bytes(buf[..8].try_into().unwrap());
+        let value = u32::from_be_bytes(buf[8..].try_into().unwrap());
 
-        Ok((stamp, value != 0))
+        (stamp, value & 1 != 0, value & 2 != 0)
+    }
+
+    // This function reads the next frame from the sump pump process.
+    // It either returns `Ok()` with the fields' values or `Err()` if
+    // a socket error occurred. Partially received frames are kept in
+    // the instance, so this function can be safely canceled.
+
+    async fn get_reading(&mut self) -> io::Result<(u64, bool, bool)> {
+        while self.buf_len < PACKET_SIZE {
+            match self.rx.read(&mut self.buf[self.buf_len..]).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.buf_len += n,
+            }
+        }
+        self.buf_len = 0;
+        Ok(Instance::decode(&self.buf))
     }
 }
 
@@ -295,6 +438,8 @@ impl driver::API for Instance {
         let duty_name = "duty".parse::<device::Base>().unwrap();
         let in_flow_name = "in-flow".parse::<device::Base>().unwrap();
         let dur_name = "duration".parse::<device::Base>().unwrap();
+        let alarm_name = "alarm".parse::<device::Base>().unwrap();
+        let ack_name = "ack".parse::<device::Base>().unwrap();
 
         Box::pin(async move {
             // Define the devices managed by this driver.
@@ -312,6 +457,9 @@ impl driver::API for Instance {
             let d_duration = core
                 .add_ro_device(dur_name, Some("min"), max_history)
                 .await?;
+            let d_alarm =
+                core.add_ro_device(alarm_name, None, max_history).await?;
+            let d_ack = core.add_rw_device(ack_name, None, max_history).await?;
 
             Ok(Devices {
                 d_service,
@@ -319,6 +467,8 @@ impl driver::API for Instance {
                 d_duty,
                 d_inflow,
                 d_duration,
+                d_alarm,
+                d_ack,
             })
         })
     }
@@ -344,9 +494,14 @@ impl driver::API for Instance {
 
             Ok(Box::new(Instance {
                 state: State::Unknown,
+                alarm: Alarm::default(),
+                pump: None,
                 gpm,
+                addr,
                 rx,
                 _tx,
+                buf: [0; PACKET_SIZE],
+                buf_len: 0,
             }))
         };
 
@@ -374,44 +529,59 @@ impl driver::API for Instance {
             let mut devices = devices.lock().await;
 
             devices.d_service.report_update(true).await;
+            devices.d_alarm.report_update(self.alarm.latched).await;
 
             loop {
-                match self.get_reading().await {
-                    Ok((stamp, true)) => {
-                        if self.state.on_event(stamp) {
-                            devices.d_state.report_update(true).await;
-                        }
+                #[rustfmt::skip]
+                tokio::select! {
+                    result = self.get_reading() => {
+			match result {
+			    Ok((stamp, pump, high_water)) => {
+				// Only feed the state machine when the
+				// pump changes state; packets are also
+				// sent when the float switch changes.
+
+				if self.pump != Some(pump) {
+				    self.pump = Some(pump);
+				    self.pump_event(&mut devices, stamp, pump).await;
+				}
+
+				if let Some(v) = self.alarm.float_event(high_water) {
+				    warn!("high-water alarm -> {}", v);
+				    devices.d_alarm.report_update(v).await;
+				    if v {
+					devices.d_ack.report_update(false).await;
+				    }
+				}
+			    }
+
+			    // If the connection was lost, mark the
+			    // devices as out of service and try to
+			    // reconnect.
+
+			    Err(e) => {
+				warn!("couldn't read sump state -- {:?}", e);
+				devices.d_state.report_update(false).await;
+				devices.d_service.report_update(false).await;
+				self.reconnect().await;
+				devices.d_service.report_update(true).await;
+			    }
+			}
                     }
 
-                    Ok((stamp, false)) => {
-                        let gpm = self.gpm;
-
-                        if let Some((cycle, duty, in_flow)) =
-                            self.state.off_event(stamp, gpm)
-                        {
-                            debug!(
-                                "cycle: {}, duty: {:.1}%, inflow: {:.2} gpm",
-                                Instance::elapsed(cycle),
-                                duty,
-                                in_flow
-                            );
-
-                            devices.d_state.report_update(false).await;
-                            devices.d_duty.report_update(duty).await;
-                            devices.d_inflow.report_update(in_flow).await;
-                            devices
-                                .d_duration
-                                .report_update(
-                                    ((cycle as f64) / 600.0).round() / 100.0,
-                                )
-                                .await;
-                        }
-                    }
+		    // Handle acknowledgements of the high-water alarm.
+		    // Only `true` acknowledges the alarm.
+
+                    Some((v, reply)) = devices.d_ack.next_setting() => {
+			reply(Ok(v));
+			devices.d_ack.report_update(v).await;
 
-                    Err(e) => {
-                        devices.d_state.report_update(false).await;
-                        devices.d_service.report_update(false).await;
-                        panic!("couldn't read sump state -- {:?}", e);
+			if v {
+			    if let Some(v) = self.alarm.ack() {
+				info!("high-water alarm cleared");
+				devices.d_alarm.report_update(v).await;
+			    }
+			}
                     }
                 }
             }
@@ -503,6 +673,73 @@ mod tests {
         assert_eq!(state, State::Off { off_time: 60000 });
     }
 
+    #[test]
+    fn test_decode() {
+        let mut buf = [0u8; PACKET_SIZE];
+
+        buf[7] = 100;
+        assert_eq!(Instance::decode(&buf), (100, false, false));
+
+        buf[11] = 1;
+        assert_eq!(Instance::decode(&buf), (100, true, false));
+
+        buf[11] = 2;
+        assert_eq!(Instance::decode(&buf), (100, false, true));
+
+        buf[11] = 3;
+        assert_eq!(Instance::decode(&buf), (100, true, true));
+    }
+
+    #[test]
+    fn test_alarm() {
+        let mut alarm = Alarm::default();
+
+        // Low water doesn't affect an idle alarm, nor does an
+        // acknowledgement.
+
+        assert_eq!(alarm.float_event(false), None);
+        assert_eq!(alarm.ack(), None);
+
+        // High water latches the alarm. Going low again doesn't
+        // clear it until it's acknowledged.
+
+        assert_eq!(alarm.float_event(true), Some(true));
+        assert_eq!(alarm.float_event(true), None);
+        assert_eq!(alarm.float_event(false), None);
+        assert!(alarm.latched);
+        assert_eq!(alarm.ack(), Some(false));
+        assert!(!alarm.latched);
+
+        // Acknowledging while the water is still high clears the
+        // alarm once the water goes down.
+
+        assert_eq!(alarm.float_event(true), Some(true));
+        assert_eq!(alarm.ack(), None);
+        assert!(alarm.latched);
+        assert_eq!(alarm.float_event(false), Some(false));
+
+        // A new high-water event requires a new acknowledgement.
+
+        assert_eq!(alarm.float_event(true), Some(true));
+        assert_eq!(alarm.float_event(false), None);
+        assert!(alarm.latched);
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let mut delay = time::Duration::from_secs(1);
+
+        delay = Instance::next_delay(delay);
+        assert_eq!(delay, time::Duration::from_secs(2));
+        delay = Instance::next_delay(delay);
+        assert_eq!(delay, time::Duration::from_secs(4));
+
+        let delay = Instance::next_delay(time::Duration::from_secs(40));
+
+        assert_eq!(delay, time::Duration::from_secs(60));
+        assert_eq!(Instance::next_delay(delay), time::Duration::from_secs(60));
+    }
+
     #[test]
     fn test_elapsed() {
         assert_eq!(Instance::elapsed(0), "0s");
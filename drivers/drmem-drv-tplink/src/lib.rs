@@ -24,6 +24,11 @@
 //   Turn off:  {"system":{"set_led_off":{"off":1}}}
 //   Received:  {"system":{"set_led_off":{"err_code":0}}}
 //
+//  Reading the dimmer's configuration:
+//
+//   Sent:      {"smartlife.iot.dimmer":{"get_dimmer_parameters":{}}}
+//   Received:  {"smartlife.iot.dimmer":{"get_dimmer_parameters":{"minThreshold":11,"fadeOnTime":1000,"fadeOffTime":1000,"gentleOnTime":3000,"gentleOffTime":10000,"rampRate":30,"bulb_type":1,"err_code":0}}}
+//
 //  Error reply (example):
 //
 //   Sent:      {"system":{"set_bright":{"bright":75}}}
@@ -60,8 +65,16 @@ pub struct Devices {
     d_error: driver::ReadOnlyDevice<bool>,
     d_brightness: driver::ReadWriteDevice<f64>,
     d_led: driver::ReadWriteDevice<bool>,
+    d_fade_on: driver::ReadOnlyDevice<f64>,
+    d_fade_off: driver::ReadOnlyDevice<f64>,
+    d_min_brightness: driver::ReadOnlyDevice<f64>,
 }
 
+// The fade-on time, fade-off time (both in milliseconds), and minimum
+// brightness configured in a dimmer.
+
+type DimmerParams = (u32, u32, u8);
+
 impl Instance {
     pub const NAME: &'static str = "tplink";
 
@@ -278,6 +291,48 @@ impl Instance {
         }
     }
 
+    // Retrieves the dimmer's configuration. If the device doesn't
+    // support dimming, `None` is returned.
+
+    async fn dimmer_params_rpc(
+        &mut self,
+        s: &mut TcpStream,
+    ) -> Result<Option<DimmerParams>> {
+        use tplink_api::{dimmer_params_cmd, DimmerParamsReply, Reply};
+
+        let (mut rx, mut tx) = s.split();
+
+        match self.rpc(&mut rx, &mut tx, dimmer_params_cmd()).await? {
+            Reply::Dimmer {
+                get_dimmer_parameters:
+                    Some(DimmerParamsReply {
+                        err_code: 0,
+                        fade_on_time: Some(on),
+                        fade_off_time: Some(off),
+                        min_threshold: Some(min),
+                        ..
+                    }),
+                ..
+            } => Ok(Some((on, off, min))),
+
+            Reply::Dimmer {
+                get_dimmer_parameters: Some(DimmerParamsReply { err_msg, .. }),
+                ..
+            } => {
+                debug!(
+                    "dimmer parameters unavailable : {}",
+                    err_msg.as_deref().unwrap_or("no reason given")
+                );
+                Ok(None)
+            }
+
+            reply => Err(Error::ProtocolError(format!(
+                "unexpected reply : {:?}",
+                &reply
+            ))),
+        }
+    }
+
     // Sets the brightness between 0 and 100, depending on the
     // argument.
 
@@ -449,6 +504,8 @@ impl Instance {
             tokio::time::interval(tokio::time::Duration::from_secs(5));
         let mut current_led = false;
         let mut current_brightness = -1.0f64;
+        let mut current_params: Option<DimmerParams> = None;
+        let mut ticks = 0u32;
 
         // Main loop of the driver. This loop never ends.
 
@@ -496,6 +553,29 @@ impl Instance {
 		    } else {
 			break 'main
 		    }
+
+		    // The dimmer's configuration rarely changes so
+		    // it's only checked once a minute.
+
+		    if ticks.is_multiple_of(12) {
+			match self.dimmer_params_rpc(s).await {
+			    Ok(Some(params)) if current_params != Some(params) => {
+				let (on, off, min) = params;
+
+				debug!("dimmer parameters: {:?}", &params);
+				current_params = Some(params);
+				devices.d_fade_on
+				    .report_update(on as f64 / 1000.0).await;
+				devices.d_fade_off
+				    .report_update(off as f64 / 1000.0).await;
+				devices.d_min_brightness
+				    .report_update(min as f64).await;
+			    }
+			    Ok(_) => (),
+			    Err(_) => break 'main
+			}
+		    }
+		    ticks = ticks.wrapping_add(1);
                 }
 
 		// Handle settings to the brightness device.
@@ -560,7 +640,8 @@ impl Instance {
 impl driver::API for Instance {
     type DeviceSet = Devices;
 
-    // Registers two devices, `error` and `brightness`.
+    // Registers the devices: `error`, `brightness`, `led`, and the
+    // dimmer's configuration parameters.
 
     fn register_devices(
         core: driver::RequestChan,
@@ -576,6 +657,15 @@ impl driver::API for Instance {
         let led_name = "led"
             .parse::<device::Base>()
             .expect("parsing 'led' should never fail");
+        let fade_on_name = "fade-on"
+            .parse::<device::Base>()
+            .expect("parsing 'fade-on' should never fail");
+        let fade_off_name = "fade-off"
+            .parse::<device::Base>()
+            .expect("parsing 'fade-off' should never fail");
+        let min_brightness_name = "min-brightness"
+            .parse::<device::Base>()
+            .expect("parsing 'min-brightness' should never fail");
 
         Box::pin(async move {
             // Define the devices managed by this driver.
@@ -586,11 +676,23 @@ impl driver::API for Instance {
                 .add_rw_device(brightness_name, None, max_history)
                 .await?;
             let d_led = core.add_rw_device(led_name, None, max_history).await?;
+            let d_fade_on = core
+                .add_ro_device(fade_on_name, Some("s"), max_history)
+                .await?;
+            let d_fade_off = core
+                .add_ro_device(fade_off_name, Some("s"), max_history)
+                .await?;
+            let d_min_brightness = core
+                .add_ro_device(min_brightness_name, Some("%"), max_history)
+                .await?;
 
             Ok(Devices {
                 d_error,
                 d_brightness,
                 d_led,
+                d_fade_on,
+                d_fade_off,
+                d_min_brightness,
             })
         })
     }
@@ -91,6 +91,15 @@ pub struct BrightnessValue {
     pub brightness: u8,
 }
 
+// Defines the internal value used by the `get_dimmer_parameters`
+// command. Needs to convert to `{}`.
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct DimmerParamsValue {
+    #[serde(skip)]
+    pub nothing: PhantomData<()>,
+}
+
 #[derive(Serialize, PartialEq, Debug)]
 pub enum Cmd {
     #[serde(rename = "system")]
@@ -104,7 +113,12 @@ pub enum Cmd {
     },
 
     #[serde(rename = "smartlife.iot.dimmer")]
-    Dimmer { set_brightness: BrightnessValue },
+    Dimmer {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        set_brightness: Option<BrightnessValue>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        get_dimmer_parameters: Option<DimmerParamsValue>,
+    },
 }
 
 impl Cmd {
@@ -153,6 +167,21 @@ pub struct InfoReply {
     pub err_code: i32,
 }
 
+// Holds the configuration of a dimmer. Devices that aren't dimmers
+// return an error status, so all the parameters are optional.
+
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct DimmerParamsReply {
+    #[serde(rename = "minThreshold")]
+    pub min_threshold: Option<u8>,
+    #[serde(rename = "fadeOnTime")]
+    pub fade_on_time: Option<u32>,
+    #[serde(rename = "fadeOffTime")]
+    pub fade_off_time: Option<u32>,
+    pub err_code: i32,
+    pub err_msg: Option<String>,
+}
+
 // This type models a subset of the replies that are returned by the
 // device (only define the replies that come from commands we send.)
 
@@ -166,7 +195,10 @@ pub enum Reply {
     },
 
     #[serde(rename = "smartlife.iot.dimmer")]
-    Dimmer { set_brightness: Option<ErrorStatus> },
+    Dimmer {
+        set_brightness: Option<ErrorStatus>,
+        get_dimmer_parameters: Option<DimmerParamsReply>,
+    },
 }
 
 impl Reply {
@@ -186,7 +218,17 @@ pub fn active_cmd(v: u8) -> Cmd {
 
 pub fn brightness_cmd(v: u8) -> Cmd {
     Cmd::Dimmer {
-        set_brightness: BrightnessValue { brightness: v },
+        set_brightness: Some(BrightnessValue { brightness: v }),
+        get_dimmer_parameters: None,
+    }
+}
+
+pub fn dimmer_params_cmd() -> Cmd {
+    Cmd::Dimmer {
+        set_brightness: None,
+        get_dimmer_parameters: Some(DimmerParamsValue {
+            nothing: PhantomData,
+        }),
     }
 }
 
@@ -258,6 +300,10 @@ mod tests {
             serde_json::to_string(&brightness_cmd(100)).unwrap(),
             "{\"smartlife.iot.dimmer\":{\"set_brightness\":{\"brightness\":100}}}"
         );
+        assert_eq!(
+            serde_json::to_string(&dimmer_params_cmd()).unwrap(),
+            "{\"smartlife.iot.dimmer\":{\"get_dimmer_parameters\":{}}}"
+        );
     }
 
     #[test]
@@ -302,6 +348,39 @@ mod tests {
                     err_code: 0,
                     err_msg: None
                 }),
+                get_dimmer_parameters: None,
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Reply>(
+                r#"{"smartlife.iot.dimmer":{"get_dimmer_parameters":{"minThreshold":11,"fadeOnTime":1000,"fadeOffTime":2000,"gentleOnTime":3000,"gentleOffTime":10000,"rampRate":30,"bulb_type":1,"err_code":0}}}"#
+            )
+            .unwrap(),
+            Reply::Dimmer {
+                set_brightness: None,
+                get_dimmer_parameters: Some(DimmerParamsReply {
+                    min_threshold: Some(11),
+                    fade_on_time: Some(1000),
+                    fade_off_time: Some(2000),
+                    err_code: 0,
+                    err_msg: None
+                }),
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Reply>(
+                r#"{"smartlife.iot.dimmer":{"get_dimmer_parameters":{"err_code":-1,"err_msg":"module not support"}}}"#
+            )
+            .unwrap(),
+            Reply::Dimmer {
+                set_brightness: None,
+                get_dimmer_parameters: Some(DimmerParamsReply {
+                    min_threshold: None,
+                    fade_on_time: None,
+                    fade_off_time: None,
+                    err_code: -1,
+                    err_msg: Some("module not support".into())
+                }),
             }
         );
         assert_eq!(
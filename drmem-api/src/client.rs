@@ -38,8 +38,33 @@ use crate::{
     Result,
 };
 use chrono::*;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
 
+/// Describes a device for the people using it. Unlike the rest of a
+/// device's information, these fields aren't provided by the driver;
+/// they're edited by users and saved by the back-end.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct DeviceMeta {
+    /// A one-line description of the device.
+    pub summary: Option<String>,
+    /// A longer description of the device.
+    pub detail: Option<String>,
+    /// Where the device, or the hardware behind it, is located.
+    pub location: Option<String>,
+}
+
+/// Holds changes to a device's descriptive information. Fields that
+/// are `None` are left alone. A field set to an empty string is
+/// removed.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct MetaUpdate {
+    pub units: Option<String>,
+    pub summary: Option<String>,
+    pub detail: Option<String>,
+    pub location: Option<String>,
+}
+
 /// Holds information about a device. A back-end is free to store this
 /// information in any way it sees fit. However, it is returned for
 /// GraphQL queries, so it should be reasonably efficient to assemble
@@ -59,6 +84,28 @@ pub struct DevInfoReply {
     pub last_point: Option<device::Reading>,
     /// The name of the driver that supports this device.
     pub driver: driver::Name,
+    pub meta: DeviceMeta,
+}
+
+/// Summarizes the readings of a device that were reported during a
+/// slice of time. Empty slices aren't reported.
+///
+/// The statistics are computed from integer, floating point, and
+/// boolean readings (booleans count as 0 and 1, so `avg` is the
+/// fraction of time the device was `true`.) For other types of
+/// devices, they are `None`.
+
+#[derive(Debug, PartialEq)]
+pub struct HistoryBucket {
+    /// The start of the slice of time.
+    pub start: DateTime<Utc>,
+    /// The number of readings in the slice.
+    pub count: u32,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub avg: Option<f64>,
+    /// The last reading in the slice.
+    pub last: device::Reading,
 }
 
 // Defines the requests that can be sent to core.
@@ -87,6 +134,31 @@ pub enum Request {
         end: Option<DateTime<Utc>>,
         rpy_chan: oneshot::Sender<Result<device::DataStream<device::Reading>>>,
     },
+
+    SetDeviceMeta {
+        name: device::Name,
+        update: MetaUpdate,
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
+
+    MonitorDevices {
+        names: Vec<device::Name>,
+        rpy_chan: oneshot::Sender<
+            Result<device::DataStream<(device::Name, device::Reading)>>,
+        >,
+    },
+
+    AggregateHistory {
+        name: device::Name,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: Duration,
+        rpy_chan: oneshot::Sender<Result<Vec<HistoryBucket>>>,
+    },
+
+    Ping {
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
 }
 
 /// A handle which is used to communicate with the core of DrMem.
@@ -134,6 +206,85 @@ impl RequestChan {
         rx.await?
     }
 
+    /// Checks that the core is running and can reach its back-end.
+    ///
+    /// An `Err` from the reply means the core is running but the
+    /// back-end couldn't be reached. If the core doesn't respond, the
+    /// error comes from the channel.
+    pub async fn ping(&self) -> Result<Result<()>> {
+        let (rpy_chan, rx) = oneshot::channel();
+
+        self.req_chan.send(Request::Ping { rpy_chan }).await?;
+        Ok(rx.await?)
+    }
+
+    /// Requests that a device's descriptive information be changed.
+    pub async fn set_device_meta(
+        &self,
+        name: device::Name,
+        update: MetaUpdate,
+    ) -> Result<()> {
+        let (rpy_chan, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::SetDeviceMeta {
+                name,
+                update,
+                rpy_chan,
+            })
+            .await?;
+
+        rx.await?
+    }
+
+    /// Makes a request to monitor several devices.
+    ///
+    /// If successful, a single stream is returned which yields the
+    /// readings of all the devices, each paired with the name of its
+    /// device. Like `monitor_device`, the stream starts with the
+    /// latest reading of each device.
+    pub async fn monitor_devices(
+        &self,
+        names: Vec<device::Name>,
+    ) -> Result<device::DataStream<(device::Name, device::Reading)>> {
+        let (rpy_chan, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::MonitorDevices { names, rpy_chan })
+            .await?;
+
+        rx.await?
+    }
+
+    /// Requests a summary of a device's history.
+    ///
+    /// The readings between `start` and `end` are grouped into
+    /// slices that are `interval` long. Each slice that holds
+    /// readings is summarized by a `HistoryBucket`. This lets a
+    /// client chart a long period of time without receiving every
+    /// reading.
+    pub async fn aggregate_history(
+        &self,
+        name: device::Name,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: Duration,
+    ) -> Result<Vec<HistoryBucket>> {
+        let (rpy_chan, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::AggregateHistory {
+                name,
+                start,
+                end,
+                interval,
+                rpy_chan,
+            })
+            .await?;
+
+        rx.await?
+    }
+
     /// Requests that a device be set to a provided value.
     ///
     /// - `name` is the name of the device
@@ -174,6 +325,29 @@ impl RequestChan {
         rx.await?.and_then(T::try_from)
     }
 
+    /// Requests that a device be set to a `device::Value`.
+    ///
+    /// This is for clients that pass along values whose type they
+    /// don't know ahead of time. The value the driver used is
+    /// returned as it was reported, rather than being converted back
+    /// to the type that was sent.
+    pub async fn set_value(
+        &self,
+        name: device::Name,
+        value: device::Value,
+    ) -> Result<device::Value> {
+        let (tx, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::SetDevice {
+                name,
+                value,
+                rpy_chan: tx,
+            })
+            .await?;
+        rx.await?
+    }
+
     pub async fn get_setting_chan(
         &self,
         name: device::Name,
@@ -0,0 +1,41 @@
+//! Types used by drivers to find devices on the local network.
+//!
+//! Many devices announce themselves with mDNS (DNS-SD) or SSDP.
+//! Rather than have each driver bind its own multicast sockets,
+//! `drmemd` runs one discovery service and drivers ask it, with
+//! `RequestChan::discover()`, to report the endpoints offering a
+//! service.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+pub mod mdns;
+
+/// Selects the kind of service a driver is looking for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Service {
+    /// An mDNS service type, like `"_hue._tcp.local"`.
+    Mdns(String),
+
+    /// An SSDP search target, like
+    /// `"urn:schemas-upnp-org:device:MediaRenderer:1"`.
+    Ssdp(String),
+}
+
+/// Describes an endpoint that offers a service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    /// The service that was found.
+    pub service: Service,
+
+    /// Identifies the endpoint. For mDNS, it's the instance name.
+    /// For SSDP, it's the unique service name (the `USN` header.)
+    pub name: String,
+
+    /// The address of the endpoint.
+    pub addr: SocketAddr,
+
+    /// Extra information about the endpoint. For mDNS, these are the
+    /// "key=value" entries of the TXT record. For SSDP, they're the
+    /// headers of the reply, with lower-case names (e.g. `location`.)
+    pub info: HashMap<String, String>,
+}
@@ -0,0 +1,286 @@
+//! Builds mDNS queries and pulls the service instances out of the
+//! replies.
+//!
+//! Queries ask for unicast replies, so the replies come back to the
+//! socket that sent them and the mDNS port doesn't have to be bound.
+//! `drmemd`'s discovery service uses it to find devices and
+//! `drmemctl` uses it to find `drmemd` nodes.
+
+use super::{Endpoint, Service};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// The mDNS multicast group and port.
+pub const ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+
+// DNS record types used by services.
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+// The "IN" class with the bit that asks for a unicast reply.
+
+const CLASS_IN_QU: u16 = 0x8001;
+
+/// Builds the query for the PTR records of a service type, like
+/// `"_hue._tcp.local"`.
+pub fn query(service: &str) -> Vec<u8> {
+    let mut pkt = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    for label in service.split('.') {
+        pkt.push(label.len() as u8);
+        pkt.extend_from_slice(label.as_bytes());
+    }
+    pkt.push(0);
+    pkt.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    pkt.extend_from_slice(&CLASS_IN_QU.to_be_bytes());
+    pkt
+}
+
+fn get_u16(pkt: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*pkt.get(off)?, *pkt.get(off + 1)?]))
+}
+
+// Reads a domain name, following compression pointers. Returns the
+// name and the offset just past it.
+
+fn get_name(pkt: &[u8], mut off: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+
+    // Each pointer has to go backwards, so a packet can't send the
+    // parser around in circles.
+
+    let mut limit = off;
+
+    loop {
+        let len = *pkt.get(off)? as usize;
+
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(off + 1)));
+            }
+            0xc0.. => {
+                let ptr = (get_u16(pkt, off)? & 0x3fff) as usize;
+
+                if ptr >= limit {
+                    return None;
+                }
+                end.get_or_insert(off + 2);
+                limit = ptr;
+                off = ptr
+            }
+            0x40.. => return None,
+            _ => {
+                let label = pkt.get(off + 1..off + 1 + len)?;
+
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                off += 1 + len
+            }
+        }
+    }
+}
+
+// The records of a reply that describe a service.
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Ptr(String, String),
+    Srv(String, u16),
+    Txt(String, Vec<String>),
+}
+
+// Pulls the service records out of a reply. Other records are
+// skipped.
+
+fn parse(pkt: &[u8]) -> Option<Vec<Record>> {
+    let flags = get_u16(pkt, 2)?;
+
+    // Only look at responses.
+
+    if flags & 0x8000 == 0 {
+        return Some(vec![]);
+    }
+
+    let questions = get_u16(pkt, 4)?;
+    let records = [6, 8, 10]
+        .iter()
+        .map(|&v| get_u16(pkt, v).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut off = 12;
+    let mut result = vec![];
+
+    for _ in 0..questions {
+        off = get_name(pkt, off)?.1 + 4;
+    }
+
+    for _ in 0..records {
+        let (owner, next) = get_name(pkt, off)?;
+        let rtype = get_u16(pkt, next)?;
+        let len = get_u16(pkt, next + 8)? as usize;
+        let data = next + 10;
+
+        pkt.get(data..data + len)?;
+
+        match rtype {
+            TYPE_PTR => result.push(Record::Ptr(owner, get_name(pkt, data)?.0)),
+            TYPE_SRV => {
+                result.push(Record::Srv(owner, get_u16(pkt, data + 4)?))
+            }
+            TYPE_TXT => {
+                let mut entries = vec![];
+                let mut pos = data;
+
+                while pos < data + len {
+                    let size = pkt[pos] as usize;
+                    let entry = pkt.get(pos + 1..pos + 1 + size)?;
+
+                    entries.push(String::from_utf8_lossy(entry).into_owned());
+                    pos += 1 + size
+                }
+                result.push(Record::Txt(owner, entries))
+            }
+            _ => (),
+        }
+        off = data + len
+    }
+    Some(result)
+}
+
+/// Returns the instances of `service` described by a reply that came
+/// from `from`.
+///
+/// Instances without an SRV record are skipped since their port isn't
+/// known. A reply that can't be parsed returns no instances.
+pub fn endpoints(pkt: &[u8], service: &str, from: IpAddr) -> Vec<Endpoint> {
+    let Some(records) = parse(pkt) else {
+        return vec![];
+    };
+    let suffix = format!(".{}", service);
+
+    records
+        .iter()
+        .filter_map(|rec| match rec {
+            Record::Ptr(owner, target)
+                if owner.eq_ignore_ascii_case(service) =>
+            {
+                Some(target)
+            }
+            _ => None,
+        })
+        .filter_map(|target| {
+            let port = records.iter().find_map(|rec| match rec {
+                Record::Srv(owner, port) if owner == target => Some(*port),
+                _ => None,
+            })?;
+            let info = records
+                .iter()
+                .find_map(|rec| match rec {
+                    Record::Txt(owner, entries) if owner == target => {
+                        Some(entries)
+                    }
+                    _ => None,
+                })
+                .into_iter()
+                .flatten()
+                .filter_map(|v| v.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+            Some(Endpoint {
+                service: Service::Mdns(service.into()),
+                name: target.strip_suffix(&suffix).unwrap_or(target).into(),
+                addr: SocketAddr::new(from, port),
+                info,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE: &str = "_hue._tcp.local";
+
+    // Appends a name, without compression, to a packet.
+
+    fn put_name(pkt: &mut Vec<u8>, name: &str) {
+        for label in name.split('.') {
+            pkt.push(label.len() as u8);
+            pkt.extend_from_slice(label.as_bytes());
+        }
+        pkt.push(0)
+    }
+
+    fn put_record(pkt: &mut Vec<u8>, owner: &[u8], rtype: u16, data: &[u8]) {
+        pkt.extend_from_slice(owner);
+        pkt.extend_from_slice(&rtype.to_be_bytes());
+        pkt.extend_from_slice(&[0x80, 0x01, 0, 0, 0x11, 0x94]);
+        pkt.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        pkt.extend_from_slice(data)
+    }
+
+    #[test]
+    fn test_query() {
+        let pkt = query(SERVICE);
+
+        assert_eq!(get_name(&pkt, 12), Some((SERVICE.into(), pkt.len() - 4)));
+        assert_eq!(get_u16(&pkt, pkt.len() - 4), Some(TYPE_PTR));
+    }
+
+    #[test]
+    fn test_reply() {
+        let mut pkt = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 2];
+        let mut service = vec![];
+        let mut instance = vec![];
+
+        put_name(&mut service, SERVICE);
+
+        // The instance's name is "bridge" followed by a pointer to
+        // the service name, which starts at offset 12.
+
+        put_name(&mut instance, "bridge");
+        instance.pop();
+        instance.extend_from_slice(&[0xc0, 12]);
+
+        put_record(&mut pkt, &service, TYPE_PTR, &instance);
+
+        let ptr = pkt.len() - instance.len();
+        let owner = [0xc0, ptr as u8];
+
+        put_record(&mut pkt, &owner, TYPE_SRV, &[0, 0, 0, 0, 0x01, 0xbb, 0]);
+        put_record(
+            &mut pkt,
+            &owner,
+            TYPE_TXT,
+            b"\x0emodelid=BSB002\x0fnoequalsign-xyz",
+        );
+
+        assert_eq!(
+            parse(&pkt).unwrap()[0],
+            Record::Ptr(SERVICE.into(), format!("bridge.{}", SERVICE))
+        );
+
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let found = endpoints(&pkt, SERVICE, from);
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].service, Service::Mdns(SERVICE.into()));
+        assert_eq!(found[0].name, "bridge");
+        assert_eq!(found[0].addr, SocketAddr::new(from, 443));
+        assert_eq!(found[0].info.len(), 1);
+        assert_eq!(found[0].info["modelid"], "BSB002");
+
+        // Other services and truncated replies are ignored.
+
+        assert!(endpoints(&pkt, "_elg._tcp.local", from).is_empty());
+        assert_eq!(parse(&pkt[..pkt.len() - 3]), None);
+        assert!(endpoints(&pkt[..pkt.len() - 4], SERVICE, from).is_empty());
+
+        // Pointers that don't go backwards are refused.
+
+        assert_eq!(get_name(&[0xc0, 0], 0), None);
+    }
+}
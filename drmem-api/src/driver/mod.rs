@@ -3,7 +3,7 @@
 
 use crate::types::{device, Error};
 use std::future::Future;
-use std::{convert::Infallible, pin::Pin, sync::Arc};
+use std::{convert::Infallible, pin::Pin, sync::Arc, time::Duration};
 use tokio::sync::{mpsc, oneshot, Mutex};
 use toml::value;
 
@@ -20,6 +20,7 @@ pub type Name = Arc<str>;
 /// values.
 pub type DriverConfig = value::Table;
 
+pub mod discovery;
 mod ro_device;
 mod rw_device;
 
@@ -42,6 +43,7 @@ pub enum Request {
         dev_name: device::Name,
         dev_units: Option<String>,
         max_history: Option<usize>,
+        max_age: Option<Duration>,
         rpy_chan: oneshot::Sender<Result<ReportReading>>,
     },
 
@@ -56,10 +58,21 @@ pub enum Request {
         dev_name: device::Name,
         dev_units: Option<String>,
         max_history: Option<usize>,
+        max_age: Option<Duration>,
         rpy_chan: oneshot::Sender<
             Result<(ReportReading, RxDeviceSetting, Option<device::Value>)>,
         >,
     },
+
+    /// Asks to be told about the endpoints offering a service on the
+    /// local network.
+    ///
+    /// The reply is a channel that yields each endpoint that is
+    /// found. Endpoints found before the request are sent first.
+    Discover {
+        service: discovery::Service,
+        rpy_chan: oneshot::Sender<Result<mpsc::Receiver<discovery::Endpoint>>>,
+    },
 }
 
 /// A handle which is used to communicate with the core of DrMem.
@@ -73,6 +86,7 @@ pub struct RequestChan {
     driver_name: Name,
     prefix: device::Path,
     req_chan: mpsc::Sender<Request>,
+    max_age: Option<Duration>,
 }
 
 impl RequestChan {
@@ -85,9 +99,18 @@ impl RequestChan {
             driver_name,
             prefix: prefix.clone(),
             req_chan: req_chan.clone(),
+            max_age: None,
         }
     }
 
+    /// Sets how long the readings of the devices registered through
+    /// this channel are kept. The framework uses this to apply the
+    /// `max_age` of a driver instance's configuration; drivers don't
+    /// need to call it.
+    pub fn with_max_age(self, max_age: Option<Duration>) -> Self {
+        RequestChan { max_age, ..self }
+    }
+
     /// Registers a read-only device with the framework. `name` is the
     /// last section of the full device name. Typically a driver will
     /// register several devices, each representing a portion of the
@@ -125,6 +148,7 @@ impl RequestChan {
                 dev_name: device::Name::build(self.prefix.clone(), name),
                 dev_units: units.map(String::from),
                 max_history,
+                max_age: self.max_age,
                 rpy_chan: tx,
             })
             .await;
@@ -177,6 +201,7 @@ impl RequestChan {
                 dev_name: device::Name::build(self.prefix.clone(), name),
                 dev_units: units.map(String::from),
                 max_history,
+                max_age: self.max_age,
                 rpy_chan: tx,
             })
             .await;
@@ -197,6 +222,42 @@ impl RequestChan {
             "can't communicate with core",
         )))
     }
+
+    /// Asks DrMem to look for endpoints offering `service`. Drivers
+    /// should use this instead of binding their own multicast
+    /// sockets, so one listener serves every driver.
+    ///
+    /// If it returns `Ok()`, the value is a channel that yields the
+    /// endpoints as they're found. An endpoint is sent again if its
+    /// address or information changes. The search continues until
+    /// the channel is dropped.
+    ///
+    /// If it returns `Err()`, the discovery service isn't available
+    /// and the driver has to be configured with the endpoint's
+    /// address.
+    pub async fn discover(
+        &self,
+        service: discovery::Service,
+    ) -> Result<mpsc::Receiver<discovery::Endpoint>> {
+        let (tx, rx) = oneshot::channel();
+        let result = self
+            .req_chan
+            .send(Request::Discover {
+                service,
+                rpy_chan: tx,
+            })
+            .await;
+
+        if result.is_ok() {
+            if let Ok(v) = rx.await {
+                return v;
+            }
+        }
+
+        Err(Error::MissingPeer(String::from(
+            "can't communicate with core",
+        )))
+    }
 }
 
 /// Defines a boxed type that supports the `driver::API` trait.
@@ -264,4 +325,19 @@ pub trait API: Send {
         &'a mut self,
         devices: Arc<Mutex<Self::DeviceSet>>,
     ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>>;
+
+    /// Called when `drmemd` is shutting down.
+    ///
+    /// The future returned by `run()` has been dropped by the time
+    /// this method is called, so the driver can use its devices to
+    /// put the hardware in a safe state and report final readings.
+    /// The framework waits a limited time for the returned future to
+    /// complete. The default implementation does nothing.
+
+    fn shutdown<'a>(
+        &'a mut self,
+        _devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+        Box::pin(async {})
+    }
 }
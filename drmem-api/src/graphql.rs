@@ -0,0 +1,206 @@
+//! A client for the GraphQL interface of `drmemd`.
+//!
+//! Queries and mutations are sent over HTTP. Subscriptions use a
+//! WebSocket and the `graphql-transport-ws` protocol. `drmemctl` and
+//! the `remote` driver build on this client; each handles the
+//! messages of its subscriptions itself.
+
+use crate::{Error, Result};
+use futures::SinkExt;
+use serde_json::{json, Value as Json};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// The path `drmemd` serves queries and mutations on.
+pub const QUERY_PATH: &str = "/drmem/q";
+
+/// The path `drmemd` serves subscriptions on.
+pub const SUBSCRIBE_PATH: &str = "/drmem/s";
+
+/// The header identifying the client to servers that use a
+/// `security` section.
+pub const CLIENT_HEADER: &str = "X-DrMem-Client-Id";
+
+/// The WebSocket connection returned by `Client::connect()`.
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Returns the WebSocket URL that corresponds to an HTTP URL.
+pub fn ws_url(url: &str) -> Result<String> {
+    if let Some(rest) = url.strip_prefix("http://") {
+        Ok(format!("ws://{}", rest))
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        Ok(format!("wss://{}", rest))
+    } else {
+        Err(Error::InvArgument(format!(
+            "URL must start with http:// or https:// -- {}",
+            url
+        )))
+    }
+}
+
+/// Pulls the data out of a GraphQL response. If the server reported
+/// errors, the first one is returned.
+pub fn get_data(mut resp: Json) -> Result<Json> {
+    if let Some(msg) = resp["errors"][0]["message"].as_str() {
+        let detail = &resp["errors"][0]["extensions"]["error"];
+
+        return Err(Error::OperationError(match detail.as_str() {
+            Some(detail) => format!("{}: {}", msg, detail),
+            None => msg.into(),
+        }));
+    }
+
+    match resp.get_mut("data") {
+        Some(data) if !data.is_null() => Ok(data.take()),
+        _ => Err(Error::ProtocolError("reply has no data".into())),
+    }
+}
+
+/// Holds the address of a `drmemd` node and the ID the client
+/// identifies itself with.
+#[derive(Clone)]
+pub struct Client {
+    url: String,
+    client_id: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Creates a client of the node at `url`, which has to be an
+    /// HTTP or HTTPS URL. If `timeout` is given, queries that take
+    /// longer fail.
+    pub fn new(
+        url: &str,
+        client_id: Option<String>,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let url = url.trim_end_matches('/').to_string();
+
+        ws_url(&url)?;
+
+        let mut http = reqwest::Client::builder();
+
+        if let Some(timeout) = timeout {
+            http = http.timeout(timeout)
+        }
+
+        let http = http.build().map_err(|e| {
+            Error::OperationError(format!(
+                "couldn't build client connection -- {}",
+                e
+            ))
+        })?;
+
+        Ok(Client {
+            url,
+            client_id,
+            http,
+        })
+    }
+
+    /// Returns the URL of the node.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Sends a query, or mutation, and returns its data.
+    pub async fn query(&self, query: &str, variables: Json) -> Result<Json> {
+        let mut req = self
+            .http
+            .post(format!("{}{}", self.url, QUERY_PATH))
+            .json(&json!({ "query": query, "variables": variables }));
+
+        if let Some(id) = &self.client_id {
+            req = req.header(CLIENT_HEADER, id)
+        }
+
+        let resp = req.send().await.map_err(|e| {
+            Error::MissingPeer(format!("couldn't reach {} -- {}", self.url, e))
+        })?;
+
+        if resp.status() == reqwest::StatusCode::FORBIDDEN {
+            return Err(Error::AuthenticationError);
+        }
+
+        get_data(
+            resp.json()
+                .await
+                .map_err(|e| Error::ProtocolError(e.to_string()))?,
+        )
+    }
+
+    /// Opens the WebSocket used for subscriptions and starts the
+    /// `graphql-transport-ws` protocol. The caller waits for the
+    /// server's `connection_ack` before subscribing.
+    pub async fn connect(&self) -> Result<Socket> {
+        let url = format!("{}{}", ws_url(&self.url)?, SUBSCRIBE_PATH);
+        let mut req = url
+            .into_client_request()
+            .map_err(|e| Error::InvArgument(e.to_string()))?;
+        let headers = req.headers_mut();
+
+        headers.insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_static("graphql-transport-ws"),
+        );
+        if let Some(id) = &self.client_id {
+            headers.insert(
+                CLIENT_HEADER,
+                HeaderValue::from_str(id)
+                    .map_err(|e| Error::InvArgument(e.to_string()))?,
+            );
+        }
+
+        let lost = |e: tokio_tungstenite::tungstenite::Error| {
+            Error::MissingPeer(format!("connection failed -- {}", e))
+        };
+        let (mut ws, _) =
+            tokio_tungstenite::connect_async(req).await.map_err(lost)?;
+
+        ws.send(Message::text(
+            json!({ "type": "connection_init" }).to_string(),
+        ))
+        .await
+        .map_err(lost)?;
+        Ok(ws)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replies() {
+        assert_eq!(ws_url("http://host:3000").unwrap(), "ws://host:3000");
+        assert_eq!(ws_url("https://host").unwrap(), "wss://host");
+        assert!(ws_url("host:3000").is_err());
+        assert!(Client::new("host:3000", None, None).is_err());
+        assert_eq!(
+            Client::new("http://host:3000/", None, None).unwrap().url(),
+            "http://host:3000"
+        );
+
+        assert_eq!(
+            get_data(json!({ "data": { "a": 1 } })).unwrap(),
+            json!({ "a": 1 })
+        );
+        assert_eq!(
+            get_data(json!({
+                "data": null,
+                "errors": [{
+                    "message": "error making setting",
+                    "extensions": { "error": "bad value" },
+                }],
+            })),
+            Err(Error::OperationError(
+                "error making setting: bad value".into()
+            ))
+        );
+        assert!(get_data(json!({})).is_err());
+    }
+}
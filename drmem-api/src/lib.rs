@@ -17,3 +17,9 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub mod client;
 pub mod driver;
+
+#[cfg(feature = "graphql-client")]
+pub mod graphql;
+
+#[cfg(feature = "plugin")]
+pub mod plugin;
@@ -0,0 +1,496 @@
+// The side of the interface that runs in a plugin. These functions
+// are exported through the `Declaration` and `Driver` that
+// `export_plugin!` builds. They run the drivers on the plugin's own
+// runtime and turn the requests of its drivers into calls to
+// `drmemd`.
+
+use super::{
+    level_filter, service_parts, Done, Endpoints, Host, Owned, Ptr,
+    RawEndpoint, RawValue, Settings, Str,
+};
+use crate::driver::{
+    discovery, DriverConfig, ReportReading, Request, RequestChan,
+    SettingRequest, API,
+};
+use crate::{device, Error, Result};
+use std::{
+    any::Any,
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot, Mutex},
+};
+use tracing::{field, level_filters::LevelFilter, span, Event, Metadata};
+
+static HOST: OnceLock<&'static Host> = OnceLock::new();
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn host() -> &'static Host {
+    HOST.get().expect("plugin wasn't initialized")
+}
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get().expect("plugin wasn't initialized")
+}
+
+// Passes the plugin's log messages to `drmemd`. Spans aren't passed
+// on, so only the messages, and their fields, are logged.
+
+struct Forward(LevelFilter);
+
+struct Text(String);
+
+impl field::Visit for Text {
+    fn record_debug(&mut self, f: &field::Field, v: &dyn std::fmt::Debug) {
+        if f.name() == "message" {
+            let _ = write!(self.0, "{:?}", v);
+        } else {
+            let _ = write!(self.0, " {}={:?}", f.name(), v);
+        }
+    }
+}
+
+impl tracing::Subscriber for Forward {
+    fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        *meta.level() <= self.0
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.0)
+    }
+
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, ev: &Event<'_>) {
+        let mut text = Text(String::new());
+        let meta = ev.metadata();
+
+        ev.record(&mut text);
+        (host().log)(
+            super::level_code(LevelFilter::from_level(*meta.level())),
+            Str::new(meta.target()),
+            Str::new(&text.0),
+        )
+    }
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
+
+pub(super) extern "C" fn init(host: &'static Host, level: u8) {
+    if HOST.set(host).is_ok() {
+        let _ = RUNTIME.set(
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .thread_name("drmem-plugin")
+                .build()
+                .expect("couldn't start the plugin's runtime"),
+        );
+
+        // When `drmemd` doesn't log, no subscriber is installed.
+        // This also keeps a plugin built into a test from passing
+        // messages back to itself.
+
+        let level = level_filter(level);
+
+        if level != LevelFilter::OFF {
+            let _ = tracing::subscriber::set_global_default(Forward(level));
+        }
+    }
+}
+
+fn parse_cfg(cfg: Str) -> Result<DriverConfig> {
+    // SAFETY: `drmemd` passes a string that's valid during this call.
+
+    unsafe { cfg.get() }
+        .ok_or_else(|| Error::ConfigError("not UTF-8".into()))?
+        .parse::<DriverConfig>()
+        .map_err(|e| Error::ConfigError(e.to_string()))
+}
+
+fn history(max_history: Option<usize>) -> isize {
+    max_history.map_or(-1, |v| v as isize)
+}
+
+// Returns the function a driver uses to report readings. It passes
+// them to the reporter `drmemd` created for the device.
+
+fn reporter(ptr: Ptr) -> ReportReading {
+    let reporter = Arc::new(Owned(ptr, host().free_reporter));
+
+    Box::new(move |v| {
+        let (done, rx) = Done::channel();
+
+        (host().report)(reporter.0, &RawValue::new(&v), done);
+        Box::pin(async move {
+            let _ = rx.await;
+        })
+    })
+}
+
+// Called by `drmemd` with each setting of a writable device. They're
+// passed, in order, to the task that hands them to the driver.
+
+type Setting = (device::Value, Done);
+
+extern "C" fn send_setting(ctx: Ptr, value: &RawValue, done: Done) {
+    // SAFETY: `ctx` was created by `settings()` and the value is
+    // valid during this call.
+
+    let tx = unsafe { ctx.get::<mpsc::UnboundedSender<Setting>>() };
+
+    match unsafe { value.get() } {
+        Some(v) => {
+            let _ = tx.send((v, done));
+        }
+        None => done.finish(Err(Error::TypeError)),
+    }
+}
+
+extern "C" fn free_settings(ctx: Ptr) {
+    // SAFETY: `ctx` was created by `settings()` and `drmemd` frees it
+    // once.
+
+    drop(unsafe { ctx.take::<mpsc::UnboundedSender<Setting>>() })
+}
+
+fn settings(tx_set: mpsc::Sender<SettingRequest>) -> Settings {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Setting>();
+
+    tokio::spawn(async move {
+        while let Some((v, done)) = rx.recv().await {
+            let (tx_rpy, rx_rpy) = oneshot::channel();
+
+            if tx_set.send((v, tx_rpy)).await.is_err() {
+                done.finish(Err(Error::MissingPeer("driver".into())));
+                continue;
+            }
+            tokio::spawn(async move {
+                let reply = rx_rpy.await.unwrap_or_else(|e| Err(e.into()));
+
+                done.finish(reply.map(|v| (Ptr::NULL, Some(v))))
+            });
+        }
+    });
+
+    Settings {
+        ctx: Ptr::new(tx),
+        send: send_setting,
+        free: free_settings,
+    }
+}
+
+// Called by `drmemd` with the endpoints a driver asked for. An
+// endpoint is dropped if the driver isn't keeping up; it's sent again
+// when it changes.
+
+extern "C" fn send_endpoint(ctx: Ptr, ep: &RawEndpoint) -> bool {
+    // SAFETY: `ctx` was created by `endpoints()` and the endpoint is
+    // valid during this call.
+
+    let tx = unsafe { ctx.get::<mpsc::Sender<discovery::Endpoint>>() };
+
+    match unsafe { ep.get() } {
+        Some(ep) => !matches!(
+            tx.try_send(ep),
+            Err(mpsc::error::TrySendError::Closed(_))
+        ),
+        None => !tx.is_closed(),
+    }
+}
+
+extern "C" fn free_endpoints(ctx: Ptr) {
+    // SAFETY: `ctx` was created by `endpoints()` and `drmemd` frees
+    // it once.
+
+    drop(unsafe { ctx.take::<mpsc::Sender<discovery::Endpoint>>() })
+}
+
+fn endpoints(tx: mpsc::Sender<discovery::Endpoint>) -> Endpoints {
+    Endpoints {
+        ctx: Ptr::new(tx),
+        send: send_endpoint,
+        free: free_endpoints,
+    }
+}
+
+// Passes the requests a driver makes through its `RequestChan` to
+// `drmemd`, one at a time. The request channel of the instance is
+// freed when the driver has dropped every copy of its `RequestChan`.
+
+async fn forward(chan: Owned, mut rx: mpsc::Receiver<Request>) {
+    while let Some(req) = rx.recv().await {
+        match req {
+            Request::AddReadonlyDevice {
+                dev_name,
+                dev_units,
+                max_history,
+                rpy_chan,
+                ..
+            } => {
+                let (done, rx) = Done::channel();
+                let name = dev_name.get_name().to_string();
+
+                (host().add_ro_device)(
+                    chan.0,
+                    Str::new(&name),
+                    Str::opt(dev_units.as_deref()),
+                    history(max_history),
+                    done,
+                );
+
+                let reply = super::wait(rx).await;
+                let _ = rpy_chan.send(reply.map(|(v, _)| reporter(v)));
+            }
+
+            Request::AddReadWriteDevice {
+                dev_name,
+                dev_units,
+                max_history,
+                rpy_chan,
+                ..
+            } => {
+                let (done, rx) = Done::channel();
+                let (tx_set, rx_set) = mpsc::channel(10);
+                let name = dev_name.get_name().to_string();
+
+                (host().add_rw_device)(
+                    chan.0,
+                    Str::new(&name),
+                    Str::opt(dev_units.as_deref()),
+                    history(max_history),
+                    settings(tx_set),
+                    done,
+                );
+
+                let reply = super::wait(rx).await;
+                let _ = rpy_chan
+                    .send(reply.map(|(v, prev)| (reporter(v), rx_set, prev)));
+            }
+
+            Request::Discover { service, rpy_chan } => {
+                let (done, rx) = Done::channel();
+                let (tx_ep, rx_ep) = mpsc::channel(10);
+                let (kind, name) = service_parts(&service);
+
+                (host().discover)(
+                    chan.0,
+                    kind,
+                    Str::new(name),
+                    endpoints(tx_ep),
+                    done,
+                );
+
+                let reply = super::wait(rx).await;
+                let _ = rpy_chan.send(reply.map(|_| rx_ep));
+            }
+        }
+    }
+}
+
+// Creates the `RequestChan` a driver in the plugin uses. The name and
+// prefix are placeholders: `drmemd` registers devices with the ones
+// of the instance and only uses the last part of a device's name.
+
+fn request_chan(chan: Ptr) -> RequestChan {
+    let (tx, rx) = mpsc::channel(10);
+    let prefix = "plugin".parse().expect("placeholder prefix is valid");
+
+    tokio::spawn(forward(Owned(chan, host().free_chan), rx));
+    RequestChan::new("plugin".into(), &prefix, &tx)
+}
+
+// Holds the device set of a driver instance. It's the
+// `Arc<Mutex<T::DeviceSet>>` of the driver, hidden so it can be
+// passed to functions that don't know the driver's type.
+
+type Devices = Arc<dyn Any + Send + Sync>;
+
+// A driver instance with the parts that need to know the driver's
+// type hidden.
+
+trait Instance: Send {
+    fn run(
+        &mut self,
+        devices: &Devices,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>>;
+
+    fn shutdown(
+        &mut self,
+        devices: &Devices,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+struct Erased<T>(Box<T>);
+
+impl<T> Erased<T>
+where
+    T: API + 'static,
+{
+    // `drmemd` only passes an instance the devices that were
+    // registered by the same driver.
+
+    fn devices(devices: &Devices) -> Arc<Mutex<T::DeviceSet>> {
+        devices
+            .downcast_ref::<Arc<Mutex<T::DeviceSet>>>()
+            .expect("device set belongs to another driver")
+            .clone()
+    }
+}
+
+impl<T> Instance for Erased<T>
+where
+    T: API + 'static,
+{
+    fn run(
+        &mut self,
+        devices: &Devices,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>> {
+        self.0.run(Self::devices(devices))
+    }
+
+    fn shutdown(
+        &mut self,
+        devices: &Devices,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        self.0.shutdown(Self::devices(devices))
+    }
+}
+
+// The instance is locked while it runs, so shutting it down waits
+// for a cancelled task to end and freeing it doesn't pull it out from
+// under the task.
+
+type Shared = Arc<Mutex<Box<dyn Instance>>>;
+
+pub(super) extern "C" fn register_devices<T>(
+    chan: Ptr,
+    cfg: Str,
+    max_history: isize,
+    done: Done,
+) where
+    T: API + 'static,
+{
+    let cfg = parse_cfg(cfg);
+    let max_history = usize::try_from(max_history).ok();
+
+    runtime().spawn(async move {
+        let drc = request_chan(chan);
+        let result = match cfg {
+            Ok(cfg) => T::register_devices(drc, &cfg, max_history).await,
+            Err(e) => Err(e),
+        };
+
+        done.finish(result.map(|v| {
+            let devices: Devices = Arc::new(Arc::new(Mutex::new(v)));
+
+            (Ptr::new(devices), None)
+        }))
+    });
+}
+
+pub(super) extern "C" fn create_instance<T>(cfg: Str, done: Done)
+where
+    T: API + 'static,
+{
+    let cfg = parse_cfg(cfg);
+
+    runtime().spawn(async move {
+        let result = match cfg {
+            Ok(cfg) => T::create_instance(&cfg).await,
+            Err(e) => Err(e),
+        };
+
+        done.finish(result.map(|v| {
+            let instance: Shared =
+                Arc::new(Mutex::new(Box::new(Erased(v)) as Box<dyn Instance>));
+
+            (Ptr::new(instance), None)
+        }))
+    });
+}
+
+// SAFETY: The caller has to pass pointers that were returned by
+// `create_instance()` and `register_devices()` and not freed yet.
+
+unsafe fn parts(instance: Ptr, devices: Ptr) -> (Shared, Devices) {
+    (
+        instance.get::<Shared>().clone(),
+        devices.get::<Devices>().clone(),
+    )
+}
+
+pub(super) extern "C" fn run(instance: Ptr, devices: Ptr, done: Done) -> Ptr {
+    // SAFETY: `drmemd` passes live objects of this plugin.
+
+    let (instance, devices) = unsafe { parts(instance, devices) };
+    let task = runtime().spawn(async move {
+        let mut instance = instance.lock_owned().await;
+
+        instance.run(&devices).await
+    });
+    let abort = task.abort_handle();
+
+    runtime().spawn(async move {
+        let e = match task.await {
+            Ok(v) => match v {},
+            Err(e) if e.is_panic() => {
+                let e = e.into_panic();
+
+                e.downcast_ref::<&str>()
+                    .map(|v| v.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "driver panicked".into())
+            }
+            Err(e) => e.to_string(),
+        };
+
+        done.finish(Err(Error::OperationError(e)))
+    });
+
+    Ptr::new(abort)
+}
+
+pub(super) extern "C" fn cancel(task: Ptr) {
+    // SAFETY: `task` was returned by `run()` and `drmemd` cancels it
+    // once.
+
+    unsafe { task.take::<tokio::task::AbortHandle>() }.abort()
+}
+
+pub(super) extern "C" fn shutdown(instance: Ptr, devices: Ptr, done: Done) {
+    // SAFETY: `drmemd` passes live objects of this plugin.
+
+    let (instance, devices) = unsafe { parts(instance, devices) };
+
+    runtime().spawn(async move {
+        instance.lock_owned().await.shutdown(&devices).await;
+        done.finish(Ok((Ptr::NULL, None)))
+    });
+}
+
+pub(super) extern "C" fn free_instance(instance: Ptr) {
+    // SAFETY: `instance` was returned by `create_instance()` and
+    // `drmemd` frees it once.
+
+    drop(unsafe { instance.take::<Shared>() })
+}
+
+pub(super) extern "C" fn free_devices(devices: Ptr) {
+    // SAFETY: `devices` was returned by `register_devices()` and
+    // `drmemd` frees it once.
+
+    drop(unsafe { devices.take::<Devices>() })
+}
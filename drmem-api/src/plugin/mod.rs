@@ -0,0 +1,749 @@
+//! Defines the interface between `drmemd` and its plugins.
+//!
+//! A plugin is a shared library holding drivers that were built
+//! separately from `drmemd`. When `drmemd` starts, it loads the
+//! plugins in its plugin directory and adds their drivers to the ones
+//! built into it.
+//!
+//! `drmemd` and a plugin each link their own copies of this crate,
+//! `tokio`, and `tracing`, which may be different versions built by
+//! different compilers. So no Rust types cross between them. They
+//! only call each other's `extern "C"` functions and pass the
+//! `#[repr(C)]` types defined here:
+//!
+//! - Strings and device values are borrowed and are only valid
+//!   during the call they're passed to.
+//! - Driver configurations are passed as TOML text.
+//! - Everything else is an opaque `Ptr` that only the side that
+//!   created it looks into. It comes with the function the other
+//!   side calls to free it.
+//! - Operations that complete later take a `Done`, which gets the
+//!   result.
+//!
+//! A plugin runs its drivers on its own `tokio` runtime. Its log
+//! messages are passed to `drmemd`.
+//!
+//! A plugin crate has a `crate-type` of `cdylib`, depends on this
+//! crate with its `plugin` feature, and uses `export_plugin!` to list
+//! its drivers:
+//!
+//! ```ignore
+//! drmem_api::export_plugin!(my_driver::Instance);
+//! ```
+
+use crate::driver::{discovery, API};
+use crate::{device, Error, Result};
+use std::{ffi::c_void, net::SocketAddr};
+use tokio::sync::oneshot;
+use tracing::level_filters::LevelFilter;
+
+mod guest;
+
+/// The version of the interface. `drmemd` rejects plugins that were
+/// built for a different one.
+
+pub const ABI: u32 = 1;
+
+/// The name of the `Declaration` a plugin exports.
+
+pub const SYMBOL: &[u8] = b"DRMEM_PLUGIN\0";
+
+/// A borrowed string. A null pointer stands for `None`.
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Str {
+    ptr: *const u8,
+    len: usize,
+}
+
+// SAFETY: A `Str` only points to data that isn't modified while it's
+// used.
+
+unsafe impl Send for Str {}
+unsafe impl Sync for Str {}
+
+impl Str {
+    pub const NONE: Str = Str {
+        ptr: std::ptr::null(),
+        len: 0,
+    };
+
+    pub const fn new(s: &str) -> Self {
+        Str {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    pub fn opt(s: Option<&str>) -> Self {
+        s.map(Str::new).unwrap_or(Str::NONE)
+    }
+
+    /// Returns the string. It returns `None` if the pointer is null
+    /// or the string isn't valid UTF-8.
+    ///
+    /// # Safety
+    ///
+    /// `self` has to point to a string that is valid for `'a`.
+
+    pub unsafe fn get<'a>(self) -> Option<&'a str> {
+        if self.ptr.is_null() {
+            None
+        } else {
+            std::str::from_utf8(std::slice::from_raw_parts(self.ptr, self.len))
+                .ok()
+        }
+    }
+}
+
+/// An opaque pointer to an object owned by one side.
+
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ptr(*mut c_void);
+
+// SAFETY: `Ptr::new()` only accepts objects that are `Send` and
+// `Sync`.
+
+unsafe impl Send for Ptr {}
+unsafe impl Sync for Ptr {}
+
+impl Ptr {
+    pub const NULL: Ptr = Ptr(std::ptr::null_mut());
+
+    /// Moves `v` to the heap and returns a pointer to it.
+
+    pub fn new<T: Send + Sync>(v: T) -> Self {
+        Ptr(Box::into_raw(Box::new(v)) as *mut c_void)
+    }
+
+    /// Returns a reference to the object.
+    ///
+    /// # Safety
+    ///
+    /// `self` has to have been returned by `Ptr::new::<T>()` and the
+    /// object can't be freed while the reference is used.
+
+    pub unsafe fn get<'a, T>(self) -> &'a T {
+        &*(self.0 as *const T)
+    }
+
+    /// Takes the object back.
+    ///
+    /// # Safety
+    ///
+    /// `self` has to have been returned by `Ptr::new::<T>()` and can't
+    /// be used afterwards.
+
+    pub unsafe fn take<T>(self) -> T {
+        *Box::from_raw(self.0 as *mut T)
+    }
+}
+
+/// Frees an object, using the function of the side that owns it,
+/// when dropped.
+
+pub struct Owned(pub Ptr, pub extern "C" fn(Ptr));
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        (self.1)(self.0)
+    }
+}
+
+/// A borrowed device value. `kind` says which field holds it: 1 is a
+/// boolean and 2 an integer, both in `int`, 3 is `flt`, 4 is `text`,
+/// and 5 is `color`. 0 means there's no value.
+
+#[repr(C)]
+pub struct RawValue {
+    kind: u8,
+    int: i32,
+    flt: f64,
+    text: Str,
+    color: [u8; 4],
+}
+
+impl RawValue {
+    pub const NONE: RawValue = RawValue {
+        kind: 0,
+        int: 0,
+        flt: 0.0,
+        text: Str::NONE,
+        color: [0; 4],
+    };
+
+    /// Borrows `v`.
+
+    pub fn new(v: &device::Value) -> Self {
+        match v {
+            device::Value::Bool(v) => RawValue {
+                kind: 1,
+                int: *v as i32,
+                ..RawValue::NONE
+            },
+            device::Value::Int(v) => RawValue {
+                kind: 2,
+                int: *v,
+                ..RawValue::NONE
+            },
+            device::Value::Flt(v) => RawValue {
+                kind: 3,
+                flt: *v,
+                ..RawValue::NONE
+            },
+            device::Value::Str(v) => RawValue {
+                kind: 4,
+                text: Str::new(v),
+                ..RawValue::NONE
+            },
+            device::Value::Color(v) => RawValue {
+                kind: 5,
+                color: [v.red, v.green, v.blue, v.alpha],
+                ..RawValue::NONE
+            },
+        }
+    }
+
+    fn opt(v: Option<&device::Value>) -> Self {
+        v.map(RawValue::new).unwrap_or(RawValue::NONE)
+    }
+
+    /// Returns a copy of the value.
+    ///
+    /// # Safety
+    ///
+    /// A string value has to still be valid.
+
+    pub unsafe fn get(&self) -> Option<device::Value> {
+        match self.kind {
+            1 => Some(device::Value::Bool(self.int != 0)),
+            2 => Some(device::Value::Int(self.int)),
+            3 => Some(device::Value::Flt(self.flt)),
+            4 => self.text.get().map(device::Value::from),
+            5 => {
+                let [r, g, b, a] = self.color;
+
+                Some(device::Value::Color(palette::LinSrgba::new(r, g, b, a)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Errors are passed as a code and the text the variant holds. Code 0
+// means there's no error.
+
+fn error_code(e: &Error) -> (u32, &str) {
+    match e {
+        Error::NotFound => (1, ""),
+        Error::InUse => (2, ""),
+        Error::DeviceDefined(v) => (3, v),
+        Error::MissingPeer(v) => (4, v),
+        Error::TypeError => (5, ""),
+        Error::InvArgument(v) => (6, v),
+        Error::BackendError(v) => (7, v),
+        Error::ProtocolError(v) => (8, v),
+        Error::AuthenticationError => (9, ""),
+        Error::TimeoutError => (10, ""),
+        Error::OperationError(v) => (11, v),
+        Error::ConfigError(v) => (12, v),
+        Error::ParseError(v) => (13, v),
+    }
+}
+
+fn error_from(code: u32, text: String) -> Error {
+    match code {
+        1 => Error::NotFound,
+        2 => Error::InUse,
+        3 => Error::DeviceDefined(text),
+        4 => Error::MissingPeer(text),
+        5 => Error::TypeError,
+        6 => Error::InvArgument(text),
+        7 => Error::BackendError(text),
+        8 => Error::ProtocolError(text),
+        9 => Error::AuthenticationError,
+        10 => Error::TimeoutError,
+        12 => Error::ConfigError(text),
+        13 => Error::ParseError(text),
+        _ => Error::OperationError(text),
+    }
+}
+
+/// What an operation returns: an object of the side that did it, a
+/// device value, or both.
+
+pub type Outcome = Result<(Ptr, Option<device::Value>)>;
+
+/// The borrowed form of an `Outcome`. `code` is 0 if the operation
+/// succeeded and `handle` and `value` hold what it returned.
+/// Otherwise `code` and `text` describe the error.
+
+#[repr(C)]
+pub struct Reply {
+    code: u32,
+    text: Str,
+    handle: Ptr,
+    value: RawValue,
+}
+
+impl Reply {
+    // SAFETY: The strings in `self` have to still be valid.
+
+    unsafe fn get(&self) -> Outcome {
+        if self.code == 0 {
+            Ok((self.handle, self.value.get()))
+        } else {
+            let text = self.text.get().unwrap_or_default().to_string();
+
+            Err(error_from(self.code, text))
+        }
+    }
+}
+
+/// Receives the result of an operation that completes later. If it's
+/// dropped without being finished, the operation fails with
+/// `MissingPeer`.
+
+#[repr(C)]
+pub struct Done {
+    ctx: Ptr,
+    call: extern "C" fn(Ptr, &Reply),
+}
+
+extern "C" fn complete(ctx: Ptr, reply: &Reply) {
+    // SAFETY: `ctx` was created by `Done::channel()` and a `Done` is
+    // only completed once. The reply is valid during this call.
+
+    let tx = unsafe { ctx.take::<oneshot::Sender<Outcome>>() };
+    let _ = tx.send(unsafe { reply.get() });
+}
+
+impl Done {
+    /// Returns a `Done` and the channel that receives its result.
+
+    pub fn channel() -> (Done, oneshot::Receiver<Outcome>) {
+        let (tx, rx) = oneshot::channel();
+
+        (
+            Done {
+                ctx: Ptr::new(tx),
+                call: complete,
+            },
+            rx,
+        )
+    }
+
+    /// Passes `result` to the side that's waiting for it.
+
+    pub fn finish(mut self, result: Outcome) {
+        let reply = match &result {
+            Ok((handle, value)) => Reply {
+                code: 0,
+                text: Str::NONE,
+                handle: *handle,
+                value: RawValue::opt(value.as_ref()),
+            },
+            Err(e) => {
+                let (code, text) = error_code(e);
+
+                Reply {
+                    code,
+                    text: Str::new(text),
+                    handle: Ptr::NULL,
+                    value: RawValue::NONE,
+                }
+            }
+        };
+
+        (self.call)(std::mem::replace(&mut self.ctx, Ptr::NULL), &reply)
+    }
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        if self.ctx != Ptr::NULL {
+            let ctx = std::mem::replace(&mut self.ctx, Ptr::NULL);
+
+            Done {
+                ctx,
+                call: self.call,
+            }
+            .finish(Err(Error::MissingPeer("plugin operation".into())))
+        }
+    }
+}
+
+/// Waits for the result passed to a `Done`.
+
+pub async fn wait(rx: oneshot::Receiver<Outcome>) -> Outcome {
+    rx.await.unwrap_or_else(|e| Err(e.into()))
+}
+
+/// Where `drmemd` sends the settings of a writable device. `send`
+/// passes on a setting and the driver's reply goes to the `Done`.
+/// `free` is called once no more settings will be sent.
+
+#[repr(C)]
+pub struct Settings {
+    pub ctx: Ptr,
+    pub send: extern "C" fn(Ptr, &RawValue, Done),
+    pub free: extern "C" fn(Ptr),
+}
+
+/// A borrowed `discovery::Endpoint`. `kind` is 0 for an mDNS service
+/// and 1 for an SSDP one. `info` points to `info_len` strings: the
+/// keys and values of the endpoint's information, one after the
+/// other.
+
+#[repr(C)]
+pub struct RawEndpoint {
+    pub kind: u8,
+    pub service: Str,
+    pub name: Str,
+    pub addr: Str,
+    pub info: *const Str,
+    pub info_len: usize,
+}
+
+/// Splits a service into the `kind` and name used by `RawEndpoint`.
+
+pub fn service_parts(service: &discovery::Service) -> (u8, &str) {
+    match service {
+        discovery::Service::Mdns(v) => (0, v),
+        discovery::Service::Ssdp(v) => (1, v),
+    }
+}
+
+/// Rebuilds a service from its `kind` and name.
+
+pub fn service_from(kind: u8, name: &str) -> discovery::Service {
+    if kind == 0 {
+        discovery::Service::Mdns(name.into())
+    } else {
+        discovery::Service::Ssdp(name.into())
+    }
+}
+
+impl RawEndpoint {
+    /// Borrows `ep`. `info` has to hold the strings of `ep.info`,
+    /// which `info()` returns.
+
+    pub fn new(ep: &discovery::Endpoint, addr: &str, info: &[Str]) -> Self {
+        let (kind, service) = service_parts(&ep.service);
+
+        RawEndpoint {
+            kind,
+            service: Str::new(service),
+            name: Str::new(&ep.name),
+            addr: Str::new(addr),
+            info: info.as_ptr(),
+            info_len: info.len(),
+        }
+    }
+
+    /// Borrows the keys and values of `ep.info`.
+
+    pub fn info(ep: &discovery::Endpoint) -> Vec<Str> {
+        ep.info
+            .iter()
+            .flat_map(|(k, v)| [Str::new(k), Str::new(v)])
+            .collect()
+    }
+
+    /// Returns a copy of the endpoint, or `None` if it's malformed.
+    ///
+    /// # Safety
+    ///
+    /// The strings have to still be valid.
+
+    pub unsafe fn get(&self) -> Option<discovery::Endpoint> {
+        let info = if self.info_len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(self.info, self.info_len)
+        };
+
+        Some(discovery::Endpoint {
+            service: service_from(self.kind, self.service.get()?),
+            name: self.name.get()?.into(),
+            addr: self.addr.get()?.parse::<SocketAddr>().ok()?,
+            info: info
+                .chunks_exact(2)
+                .filter_map(|v| Some((v[0].get()?.into(), v[1].get()?.into())))
+                .collect(),
+        })
+    }
+}
+
+/// Where `drmemd` sends the endpoints a driver asked it to find.
+/// `send` returns `false` once the driver has stopped looking.
+/// `free` is called once no more endpoints will be sent.
+
+#[repr(C)]
+pub struct Endpoints {
+    pub ctx: Ptr,
+    pub send: extern "C" fn(Ptr, &RawEndpoint) -> bool,
+    pub free: extern "C" fn(Ptr),
+}
+
+/// Converts the level of log messages to the code passed to a
+/// plugin. 0 turns messages off and 1 through 5 go from `ERROR` to
+/// `TRACE`.
+
+pub fn level_code(level: LevelFilter) -> u8 {
+    match level.into_level() {
+        None => 0,
+        Some(tracing::Level::ERROR) => 1,
+        Some(tracing::Level::WARN) => 2,
+        Some(tracing::Level::INFO) => 3,
+        Some(tracing::Level::DEBUG) => 4,
+        Some(tracing::Level::TRACE) => 5,
+    }
+}
+
+/// Converts a code from `level_code()` back to the level.
+
+pub fn level_filter(code: u8) -> LevelFilter {
+    match code {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// What `drmemd` provides to a plugin.
+///
+/// `chan` is the request channel of the driver instance that
+/// registers devices. `name` is the last part of a device's name,
+/// `units` is null if the device has none, and a negative
+/// `max_history` stands for `None`. `add_ro_device` and
+/// `add_rw_device` finish with a reporter, which `report` passes
+/// readings to, and `add_rw_device` includes the device's last saved
+/// value. `discover` finishes once the search has started.
+
+#[repr(C)]
+pub struct Host {
+    pub log: extern "C" fn(level: u8, target: Str, text: Str),
+    pub add_ro_device: extern "C" fn(
+        chan: Ptr,
+        name: Str,
+        units: Str,
+        max_history: isize,
+        done: Done,
+    ),
+    pub add_rw_device: extern "C" fn(
+        chan: Ptr,
+        name: Str,
+        units: Str,
+        max_history: isize,
+        settings: Settings,
+        done: Done,
+    ),
+    pub report: extern "C" fn(reporter: Ptr, value: &RawValue, done: Done),
+    pub free_reporter: extern "C" fn(Ptr),
+    pub discover: extern "C" fn(
+        chan: Ptr,
+        kind: u8,
+        service: Str,
+        endpoints: Endpoints,
+        done: Done,
+    ),
+    pub free_chan: extern "C" fn(Ptr),
+}
+
+/// Describes a driver in a plugin. The functions match the ones in
+/// the `API` trait.
+///
+/// `register_devices` finishes with the device set and
+/// `create_instance` with the instance. Both are freed with the
+/// functions in the `Declaration`. `cfg` is the instance's
+/// configuration as TOML text and `chan` is freed with
+/// `Host::free_chan` once the driver is done with it.
+
+#[repr(C)]
+pub struct Driver {
+    pub name: Str,
+    pub summary: Str,
+    pub description: Str,
+    pub register_devices:
+        extern "C" fn(chan: Ptr, cfg: Str, max_history: isize, done: Done),
+    pub create_instance: extern "C" fn(cfg: Str, done: Done),
+}
+
+impl Driver {
+    /// Describes driver `T`. `export_plugin!` uses this function.
+
+    pub const fn new<T>(
+        name: &'static str,
+        summary: &'static str,
+        description: &'static str,
+    ) -> Self
+    where
+        T: API + 'static,
+    {
+        Driver {
+            name: Str::new(name),
+            summary: Str::new(summary),
+            description: Str::new(description),
+            register_devices: guest::register_devices::<T>,
+            create_instance: guest::create_instance::<T>,
+        }
+    }
+}
+
+/// What a plugin exports, under the name in `SYMBOL`. `abi` has to
+/// be first so `drmemd` can check it before using the rest.
+///
+/// `init` is called once, before anything else. `level` is the
+/// `level_code()` of the messages `drmemd` logs.
+///
+/// `run` starts an instance with its device set and returns the
+/// task, which `cancel` stops and frees. The `Done` is finished when
+/// the task ends, which only happens if it was cancelled or
+/// panicked. `shutdown` calls the instance's shutdown hook once its
+/// task has ended.
+
+#[repr(C)]
+pub struct Declaration {
+    pub abi: u32,
+    pub init: extern "C" fn(host: &'static Host, level: u8),
+    drivers: *const Driver,
+    count: usize,
+    pub run: extern "C" fn(instance: Ptr, devices: Ptr, done: Done) -> Ptr,
+    pub cancel: extern "C" fn(task: Ptr),
+    pub shutdown: extern "C" fn(instance: Ptr, devices: Ptr, done: Done),
+    pub free_instance: extern "C" fn(Ptr),
+    pub free_devices: extern "C" fn(Ptr),
+}
+
+// SAFETY: `drivers` points to an array that is never modified.
+
+unsafe impl Sync for Declaration {}
+
+impl Declaration {
+    /// Declares a plugin holding `drivers`. `export_plugin!` uses
+    /// this function.
+
+    pub const fn new(drivers: &'static [Driver]) -> Self {
+        Declaration {
+            abi: ABI,
+            init: guest::init,
+            drivers: drivers.as_ptr(),
+            count: drivers.len(),
+            run: guest::run,
+            cancel: guest::cancel,
+            shutdown: guest::shutdown,
+            free_instance: guest::free_instance,
+            free_devices: guest::free_devices,
+        }
+    }
+
+    /// Returns the drivers of the plugin.
+    ///
+    /// # Safety
+    ///
+    /// `abi` has to be checked first.
+
+    pub unsafe fn drivers(&self) -> &[Driver] {
+        std::slice::from_raw_parts(self.drivers, self.count)
+    }
+}
+
+/// Exports the drivers of a plugin. Each driver type needs the
+/// `NAME`, `SUMMARY`, and `DESCRIPTION` constants that the built-in
+/// drivers have.
+
+#[macro_export]
+macro_rules! export_plugin {
+    ($($drv:ty),+ $(,)?) => {
+        #[no_mangle]
+        pub static DRMEM_PLUGIN: $crate::plugin::Declaration = {
+            const DRIVERS: &[$crate::plugin::Driver] =
+                &[$($crate::plugin::Driver::new::<$drv>(
+                    <$drv>::NAME,
+                    <$drv>::SUMMARY,
+                    <$drv>::DESCRIPTION,
+                )),+];
+
+            $crate::plugin::Declaration::new(DRIVERS)
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        let values: [device::Value; 5] = [
+            true.into(),
+            (-5).into(),
+            1.5.into(),
+            "hello".into(),
+            palette::LinSrgba::new(1, 2, 3, 4).into(),
+        ];
+
+        for v in values {
+            assert_eq!(unsafe { RawValue::new(&v).get() }, Some(v));
+        }
+        assert_eq!(unsafe { RawValue::NONE.get() }, None);
+    }
+
+    #[tokio::test]
+    async fn test_done() {
+        let (done, rx) = Done::channel();
+
+        done.finish(Ok((Ptr::NULL, Some(3.into()))));
+        assert_eq!(wait(rx).await, Ok((Ptr::NULL, Some(3.into()))));
+
+        let errors = [
+            Error::NotFound,
+            Error::DeviceDefined("dev".into()),
+            Error::ConfigError("bad".into()),
+            Error::TimeoutError,
+        ];
+
+        for e in errors {
+            let (done, rx) = Done::channel();
+
+            done.finish(Err(e.clone()));
+            assert_eq!(wait(rx).await, Err(e));
+        }
+
+        // Dropping a `Done` fails the operation. Finishing one nobody
+        // waits for is harmless.
+
+        let (done, rx) = Done::channel();
+
+        drop(done);
+        assert!(matches!(wait(rx).await, Err(Error::MissingPeer(_))));
+
+        let (done, rx) = Done::channel();
+
+        drop(rx);
+        done.finish(Ok((Ptr::NULL, None)));
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let ep = discovery::Endpoint {
+            service: discovery::Service::Mdns("_hue._tcp.local".into()),
+            name: "bridge".into(),
+            addr: "192.168.1.2:80".parse().unwrap(),
+            info: [("id".to_string(), "001788".to_string())].into(),
+        };
+        let addr = ep.addr.to_string();
+        let info = RawEndpoint::info(&ep);
+
+        assert_eq!(
+            unsafe { RawEndpoint::new(&ep, &addr, &info).get() },
+            Some(ep)
+        );
+    }
+}
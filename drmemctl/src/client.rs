@@ -0,0 +1,94 @@
+// Talks to the GraphQL interface of `drmemd`. Queries and mutations
+// are sent over HTTP. Subscriptions use a WebSocket and the
+// `graphql-transport-ws` protocol.
+
+use drmem_api::{
+    graphql::{self, get_data},
+    Error, Result,
+};
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value as Json};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Clone)]
+pub struct Client(graphql::Client);
+
+impl Client {
+    pub fn new(url: &str, client_id: Option<String>) -> Result<Self> {
+        graphql::Client::new(url, client_id, None).map(Client)
+    }
+
+    // Sends a query, or mutation, and returns its data.
+
+    pub async fn query(&self, query: &str, variables: Json) -> Result<Json> {
+        self.0.query(query, variables).await
+    }
+
+    // Starts a subscription and passes the data of each update to
+    // `f`. Returns when the server ends the subscription or `f`
+    // returns an error. If `idle` is given, it also returns when no
+    // message arrives for that long.
+
+    pub async fn subscribe(
+        &self,
+        query: &str,
+        variables: Json,
+        idle: Option<Duration>,
+        mut f: impl FnMut(Json) -> Result<()>,
+    ) -> Result<()> {
+        let mut ws = self.0.connect().await?;
+        let send_err = |e: tokio_tungstenite::tungstenite::Error| {
+            Error::OperationError(e.to_string())
+        };
+
+        let mut subscribed = false;
+
+        loop {
+            let msg = match idle {
+                Some(idle) if subscribed => {
+                    match tokio::time::timeout(idle, ws.next()).await {
+                        Ok(msg) => msg,
+                        Err(_) => return Ok(()),
+                    }
+                }
+                _ => ws.next().await,
+            };
+            let msg = match msg.transpose().map_err(send_err)? {
+                Some(Message::Text(v)) => v,
+                None | Some(Message::Close(_)) => break,
+                _ => continue,
+            };
+            let mut msg: Json = serde_json::from_str(&msg)
+                .map_err(|e| Error::ProtocolError(e.to_string()))?;
+
+            match msg["type"].as_str() {
+                Some("connection_ack") if !subscribed => {
+                    subscribed = true;
+
+                    let start = json!({
+                        "id": "1",
+                        "type": "subscribe",
+                        "payload": { "query": query, "variables": variables },
+                    });
+
+                    ws.send(Message::text(start.to_string()))
+                        .await
+                        .map_err(send_err)?
+                }
+                Some("ping") => ws
+                    .send(Message::text(json!({ "type": "pong" }).to_string()))
+                    .await
+                    .map_err(send_err)?,
+                Some("next") => f(get_data(msg["payload"].take())?)?,
+                Some("error") => {
+                    return get_data(json!({ "errors": msg["payload"] }))
+                        .map(|_| ())
+                }
+                Some("complete") => return Ok(()),
+                _ => (),
+            }
+        }
+        Err(Error::OperationError("server closed the connection".into()))
+    }
+}
@@ -0,0 +1,621 @@
+// A terminal dashboard. It shows the latest reading of a set of
+// devices, along with a sparkline of their recent values, and lets
+// the settable ones be set. The keys it uses:
+//
+//     Up/Down, k/j    select a device
+//     Enter, s        edit a setting for the selected device
+//     Space           toggle the selected device, if it's a boolean
+//     Esc             cancel the setting being edited
+//     q               quit
+//
+// The set of devices can also be kept in a TOML file:
+//
+//     devices = ["garage:door", "porch:light"]
+//     pattern = "weather:*"
+
+use super::{client::Client, parse_value, send_setting, to_value, READING};
+use chrono::{Duration, Utc};
+use drmem_api::{device, Error, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    widgets::{
+        Block, Paragraph, Row as TableRow, Sparkline, Table, TableState,
+    },
+    DefaultTerminal, Frame,
+};
+use serde_json::{json, Value as Json};
+use std::collections::VecDeque;
+use tokio::sync::mpsc;
+
+// The number of values kept for the sparklines. When the dashboard
+// starts, they're filled with the averages of this many intervals of
+// `HISTORY_INTERVAL` seconds.
+
+const HISTORY_POINTS: usize = 120;
+const HISTORY_INTERVAL: i64 = 60;
+
+// The characters used to draw the sparkline in the table.
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// The number of values shown in the table's sparklines.
+
+const ROW_SPARK: usize = 20;
+
+struct Row {
+    name: String,
+    units: Option<String>,
+    settable: bool,
+    stamp: Option<String>,
+    value: Option<device::Value>,
+    history: VecDeque<f64>,
+}
+
+// Returns the value used to plot a reading. Booleans are plotted as 0
+// and 1; strings and colors aren't plotted.
+
+fn plot_value(v: &device::Value) -> Option<f64> {
+    match v {
+        device::Value::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
+        device::Value::Int(v) => Some(*v as f64),
+        device::Value::Flt(v) => Some(*v),
+        _ => None,
+    }
+}
+
+impl Row {
+    fn new(info: &Json) -> Option<Self> {
+        let mut row = Row {
+            name: info["deviceName"].as_str()?.into(),
+            units: info["units"].as_str().map(String::from),
+            settable: info["settable"] == true,
+            stamp: None,
+            value: None,
+            history: VecDeque::with_capacity(HISTORY_POINTS),
+        };
+
+        row.update(&info["history"]["lastPoint"]);
+        row.history.clear();
+        Some(row)
+    }
+
+    fn add_point(&mut self, v: f64) {
+        if self.history.len() == HISTORY_POINTS {
+            self.history.pop_front();
+        }
+        self.history.push_back(v)
+    }
+
+    fn update(&mut self, reading: &Json) {
+        if let Some(value) = to_value(reading) {
+            if let Some(v) = plot_value(&value) {
+                self.add_point(v)
+            }
+            self.stamp = reading["stamp"].as_str().map(String::from);
+            self.value = Some(value)
+        }
+    }
+}
+
+// Scales values so they fill the range of a sparkline. The smallest
+// value is drawn as 1, rather than 0, so it's still visible.
+
+fn spark_data<'a>(values: impl Iterator<Item = &'a f64> + Clone) -> Vec<u64> {
+    let min = values.clone().copied().fold(f64::INFINITY, f64::min);
+    let max = values.clone().copied().fold(f64::NEG_INFINITY, f64::max);
+
+    values
+        .map(|v| {
+            if max > min {
+                1 + ((v - min) / (max - min) * 99.0).round() as u64
+            } else {
+                1
+            }
+        })
+        .collect()
+}
+
+// Draws the last `width` values as a line of text.
+
+fn spark_text(values: &VecDeque<f64>, width: usize) -> String {
+    let start = values.len().saturating_sub(width);
+
+    spark_data(values.range(start..))
+        .into_iter()
+        .map(|v| BARS[((v - 1) as usize * (BARS.len() - 1) + 50) / 99])
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Quit,
+    Set(String, Json),
+}
+
+struct Dash {
+    rows: Vec<Row>,
+    selected: usize,
+    input: Option<String>,
+    status: String,
+}
+
+impl Dash {
+    fn new(rows: Vec<Row>) -> Self {
+        Dash {
+            rows,
+            selected: 0,
+            input: None,
+            status: String::from("q: quit, enter: set, space: toggle"),
+        }
+    }
+
+    fn update(&mut self, reading: &Json) {
+        let name = reading["device"].as_str();
+
+        if let Some(row) = self.rows.iter_mut().find(|r| Some(&*r.name) == name)
+        {
+            row.update(reading)
+        }
+    }
+
+    // Handles a key while a setting is being edited.
+
+    fn edit_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let input = self.input.as_mut()?;
+
+        match key.code {
+            KeyCode::Esc => self.input = None,
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Enter => {
+                let text = self.input.take()?;
+
+                match parse_value(text.trim(), None) {
+                    Ok(v) => {
+                        return Some(Action::Set(
+                            self.rows[self.selected].name.clone(),
+                            v,
+                        ))
+                    }
+                    Err(e) => self.status = e.to_string(),
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if self.input.is_some() {
+            return self.edit_key(key);
+        }
+
+        let row = &self.rows[self.selected];
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.saturating_sub(1)
+            }
+            KeyCode::Down | KeyCode::Char('j')
+                if self.selected + 1 < self.rows.len() =>
+            {
+                self.selected += 1
+            }
+            KeyCode::Enter | KeyCode::Char('s') if row.settable => {
+                self.input = Some(String::new())
+            }
+            KeyCode::Char(' ') if row.settable => {
+                if let Some(device::Value::Bool(v)) = row.value {
+                    return Some(Action::Set(
+                        row.name.clone(),
+                        json!({ "bool": !v }),
+                    ));
+                }
+                self.status = format!("{} isn't a boolean", row.name)
+            }
+            KeyCode::Enter | KeyCode::Char('s' | ' ') => {
+                self.status = format!("{} isn't settable", row.name)
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [table_area, spark_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(6),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let rows = self.rows.iter().map(|row| {
+            TableRow::new([
+                row.name.clone(),
+                row.value.as_ref().map_or("-".into(), |v| v.to_string()),
+                row.units.clone().unwrap_or_default(),
+                spark_text(&row.history, ROW_SPARK),
+                if row.settable { "rw" } else { "r" }.into(),
+                row.stamp.clone().unwrap_or_default(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(2),
+                Constraint::Fill(1),
+                Constraint::Length(8),
+                Constraint::Length(ROW_SPARK as u16),
+                Constraint::Length(2),
+                Constraint::Fill(2),
+            ],
+        )
+        .header(
+            TableRow::new(["Device", "Value", "Units", "", "", "Updated"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" DrMem "));
+
+        frame.render_stateful_widget(
+            table,
+            table_area,
+            &mut TableState::new().with_selected(Some(self.selected)),
+        );
+
+        let row = &self.rows[self.selected];
+        let data = spark_data(row.history.iter());
+
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(" {} ", row.name)))
+                .data(&data),
+            spark_area,
+        );
+
+        let status = match &self.input {
+            Some(input) => format!("set {} to: {}", row.name, input),
+            None => self.status.clone(),
+        };
+
+        frame.render_widget(Paragraph::new(status), status_area)
+    }
+}
+
+// Reads the set of devices from a TOML file and adds them to
+// `devices`. Returns the pattern, if the file has one.
+
+pub fn read_file(
+    path: &str,
+    devices: &mut Vec<String>,
+) -> Result<Option<String>> {
+    let bad = |e: String| Error::ConfigError(format!("{}: {}", path, e));
+    let text = std::fs::read_to_string(path).map_err(|e| bad(e.to_string()))?;
+    let table = text
+        .parse::<toml::Table>()
+        .map_err(|e| bad(e.to_string()))?;
+
+    for name in table
+        .get("devices")
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        devices.push(
+            name.as_str()
+                .ok_or_else(|| bad("devices must be strings".into()))?
+                .into(),
+        )
+    }
+
+    match table.get("pattern") {
+        Some(toml::Value::String(v)) => Ok(Some(v.clone())),
+        Some(_) => Err(bad("pattern must be a string".into())),
+        None => Ok(None),
+    }
+}
+
+// Gets the information about each device and fills its sparkline
+// with its recent history.
+
+async fn load_rows(
+    client: &Client,
+    devices: &[String],
+    pattern: Option<&str>,
+) -> Result<Vec<Row>> {
+    let mut rows: Vec<Row> = vec![];
+    let patterns = devices.iter().map(String::as_str).chain(pattern);
+
+    for pattern in patterns {
+        let data = client
+            .query(
+                &format!(
+                    "query ($pattern: String) {{ \
+                     deviceInfo(pattern: $pattern) {{ deviceName units \
+                     settable history {{ lastPoint {{ {} }} }} }} }}",
+                    READING
+                ),
+                json!({ "pattern": pattern }),
+            )
+            .await?;
+
+        for info in data["deviceInfo"].as_array().into_iter().flatten() {
+            if let Some(row) = Row::new(info) {
+                if !rows.iter().any(|r| r.name == row.name) {
+                    rows.push(row)
+                }
+            }
+        }
+    }
+
+    let start = Utc::now()
+        - Duration::try_seconds(HISTORY_INTERVAL * HISTORY_POINTS as i64)
+            .unwrap();
+
+    for row in rows.iter_mut() {
+        let data = client
+            .query(
+                "query ($name: String!, $start: DateTime!, $interval: Int!) \
+                 { deviceHistory(device: $name, start: $start, \
+                 interval: $interval) { avg } }",
+                json!({
+                    "name": row.name,
+                    "start": start.to_rfc3339(),
+                    "interval": HISTORY_INTERVAL,
+                }),
+            )
+            .await?;
+
+        for bucket in data["deviceHistory"].as_array().into_iter().flatten() {
+            if let Some(v) = bucket["avg"].as_f64() {
+                row.add_point(v)
+            }
+        }
+    }
+    Ok(rows)
+}
+
+enum Msg {
+    Key(KeyEvent),
+    Reading(Json),
+    Status(String),
+    Redraw,
+}
+
+// Reading terminal events blocks, so it's done in its own thread.
+
+fn read_keys(tx: mpsc::UnboundedSender<Msg>) {
+    std::thread::spawn(move || loop {
+        let msg = match event::read() {
+            Ok(Event::Key(k)) if k.kind == KeyEventKind::Press => Msg::Key(k),
+            Ok(Event::Resize(..)) => Msg::Redraw,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+
+        if tx.send(msg).is_err() {
+            break;
+        }
+    });
+}
+
+async fn event_loop(
+    terminal: &mut DefaultTerminal,
+    client: &Client,
+    dash: &mut Dash,
+    tx: mpsc::UnboundedSender<Msg>,
+    mut rx: mpsc::UnboundedReceiver<Msg>,
+) -> Result<()> {
+    loop {
+        terminal
+            .draw(|frame| dash.draw(frame))
+            .map_err(|e| Error::OperationError(e.to_string()))?;
+
+        match rx.recv().await {
+            Some(Msg::Key(key)) => match dash.handle_key(key) {
+                Some(Action::Quit) => return Ok(()),
+                Some(Action::Set(name, value)) => {
+                    let client = client.clone();
+                    let tx = tx.clone();
+
+                    dash.status = format!("setting {}", name);
+                    tokio::spawn(async move {
+                        let status =
+                            match send_setting(&client, &name, value).await {
+                                Ok(_) => format!("set {}", name),
+                                Err(e) => format!("{}: {}", name, e),
+                            };
+                        let _ = tx.send(Msg::Status(status));
+                    });
+                }
+                None => (),
+            },
+            Some(Msg::Reading(reading)) => dash.update(&reading),
+            Some(Msg::Status(status)) => dash.status = status,
+            Some(Msg::Redraw) => (),
+            None => return Ok(()),
+        }
+    }
+}
+
+pub async fn run(
+    client: &Client,
+    devices: &[String],
+    pattern: Option<&str>,
+) -> Result<()> {
+    let rows = load_rows(client, devices, pattern).await?;
+
+    if rows.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let names: Vec<&String> = rows.iter().map(|r| &r.name).collect();
+    let query = format!(
+        "subscription ($names: [String!]) \
+         {{ monitorDevices(devices: $names) {{ device {} }} }}",
+        READING
+    );
+    let vars = json!({ "names": names });
+    let sub_client = client.clone();
+    let sub_tx = tx.clone();
+
+    tokio::spawn(async move {
+        let result = sub_client
+            .subscribe(&query, vars, None, |mut data| {
+                let _ =
+                    sub_tx.send(Msg::Reading(data["monitorDevices"].take()));
+                Ok(())
+            })
+            .await;
+        let status = match result {
+            Ok(()) => "updates ended".into(),
+            Err(e) => format!("updates ended: {}", e),
+        };
+        let _ = sub_tx.send(Msg::Status(status));
+    });
+
+    read_keys(tx.clone());
+
+    let mut dash = Dash::new(rows);
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, client, &mut dash, tx, rx).await;
+
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::crossterm::event::KeyModifiers;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn row(name: &str, settable: bool, reading: Json) -> Row {
+        Row::new(&json!({
+            "deviceName": name,
+            "settable": settable,
+            "history": { "lastPoint": reading },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sparks() {
+        let values: VecDeque<f64> = [2.0, 4.0, 3.0, 6.0].into();
+
+        assert_eq!(spark_data(values.iter()), vec![1, 51, 26, 100]);
+        assert_eq!(spark_data([5.0, 5.0].iter()), vec![1, 1]);
+        assert!(spark_data([].iter()).is_empty());
+        assert_eq!(spark_text(&values, 10), "▁▅▃█");
+        assert_eq!(spark_text(&values, 2), "▁█");
+        assert_eq!(spark_text(&VecDeque::new(), 2), "");
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut dash = Dash::new(vec![
+            row("a:b", false, json!({ "stamp": "t0", "intValue": 3 })),
+            row("a:c", true, Json::Null),
+        ]);
+
+        assert_eq!(dash.rows[0].value, Some(device::Value::Int(3)));
+        assert!(dash.rows[0].history.is_empty());
+        assert_eq!(dash.rows[1].value, None);
+
+        dash.update(&json!({ "device": "a:b", "stamp": "t1", "intValue": 4 }));
+        dash.update(
+            &json!({ "device": "a:c", "stamp": "t1", "boolValue": true }),
+        );
+        dash.update(&json!({ "device": "a:d", "stamp": "t1", "intValue": 1 }));
+        dash.update(
+            &json!({ "device": "a:c", "stamp": "t2", "stringValue": "x" }),
+        );
+
+        assert_eq!(dash.rows[0].stamp.as_deref(), Some("t1"));
+        assert_eq!(dash.rows[0].history, [4.0]);
+        assert_eq!(dash.rows[1].value, Some(device::Value::Str("x".into())));
+        assert_eq!(dash.rows[1].history, [1.0]);
+
+        for v in 0..HISTORY_POINTS as i32 {
+            dash.update(&json!({ "device": "a:b", "intValue": v }))
+        }
+        assert_eq!(dash.rows[0].history.len(), HISTORY_POINTS);
+        assert_eq!(dash.rows[0].history[0], 0.0);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut dash = Dash::new(vec![
+            row("a:b", false, json!({ "intValue": 3 })),
+            row("a:c", true, json!({ "boolValue": true })),
+        ]);
+
+        assert_eq!(dash.handle_key(key(KeyCode::Char(' '))), None);
+        assert_eq!(dash.status, "a:b isn't settable");
+        assert_eq!(dash.handle_key(key(KeyCode::Enter)), None);
+        assert_eq!(dash.input, None);
+
+        dash.handle_key(key(KeyCode::Down));
+        dash.handle_key(key(KeyCode::Char('j')));
+        assert_eq!(dash.selected, 1);
+        assert_eq!(
+            dash.handle_key(key(KeyCode::Char(' '))),
+            Some(Action::Set("a:c".into(), json!({ "bool": false })))
+        );
+
+        // Edit a setting, including a correction and a cancel.
+
+        dash.handle_key(key(KeyCode::Char('s')));
+        for c in "12x".chars() {
+            dash.handle_key(key(KeyCode::Char(c)));
+        }
+        dash.handle_key(key(KeyCode::Backspace));
+        assert_eq!(dash.input.as_deref(), Some("12"));
+        assert_eq!(
+            dash.handle_key(key(KeyCode::Enter)),
+            Some(Action::Set("a:c".into(), json!({ "int": 12 })))
+        );
+        assert_eq!(dash.input, None);
+
+        dash.handle_key(key(KeyCode::Enter));
+        dash.handle_key(key(KeyCode::Char('q')));
+        dash.handle_key(key(KeyCode::Esc));
+        assert_eq!(dash.input, None);
+
+        dash.handle_key(key(KeyCode::Up));
+        dash.handle_key(key(KeyCode::Char('k')));
+        assert_eq!(dash.selected, 0);
+        assert_eq!(
+            dash.handle_key(key(KeyCode::Char('q'))),
+            Some(Action::Quit)
+        );
+    }
+
+    #[test]
+    fn test_file() {
+        let path = std::env::temp_dir().join("drmemctl-dash-test.toml");
+        let path = path.to_str().unwrap();
+        let mut devices = vec!["a:b".to_string()];
+
+        std::fs::write(path, "devices = [\"a:c\"]\npattern = \"x:*\"\n")
+            .unwrap();
+        assert_eq!(
+            read_file(path, &mut devices).unwrap().as_deref(),
+            Some("x:*")
+        );
+        assert_eq!(devices, ["a:b", "a:c"]);
+
+        std::fs::write(path, "devices = [1]\n").unwrap();
+        assert!(read_file(path, &mut devices).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
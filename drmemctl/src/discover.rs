@@ -0,0 +1,111 @@
+// Finds the `drmemd` nodes on the LAN. Each node advertises its
+// GraphQL server as a `_drmem._tcp` service with mDNS. A query for
+// the service is sent to the mDNS group, asking for unicast replies,
+// and the replies that arrive before the timeout are collected.
+
+use drmem_api::{
+    driver::discovery::{mdns, Endpoint},
+    Error, Result,
+};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::timeout_at};
+
+const SERVICE: &str = "_drmem._tcp.local";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub addr: SocketAddr,
+    // The "KEY=VALUE" entries of the TXT record.
+    pub info: HashMap<String, String>,
+}
+
+impl From<Endpoint> for Node {
+    fn from(ep: Endpoint) -> Self {
+        Node {
+            name: ep.name,
+            addr: ep.addr,
+            info: ep.info,
+        }
+    }
+}
+
+impl Node {
+    // Returns the URL of the node's GraphQL server. A preferred
+    // address, if the node gave one, is used instead of the address
+    // the reply came from.
+
+    pub fn url(&self) -> String {
+        let scheme = if self.info.get("tls").map(String::as_str) == Some("true")
+        {
+            "https"
+        } else {
+            "http"
+        };
+
+        match self.info.get("pref-addr") {
+            Some(addr) => format!("{}://{}", scheme, addr),
+            None => format!("{}://{}", scheme, self.addr),
+        }
+    }
+}
+
+// Returns the nodes that replied within `wait`, sorted by name.
+
+pub async fn discover(wait: Duration) -> Result<Vec<Node>> {
+    let err = |e: std::io::Error| Error::OperationError(e.to_string());
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(err)?;
+
+    sock.send_to(&mdns::query(SERVICE), mdns::ADDR)
+        .await
+        .map_err(err)?;
+
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut found: Vec<Node> = vec![];
+    let mut buf = [0u8; 9000];
+
+    while let Ok(reply) = timeout_at(deadline, sock.recv_from(&mut buf)).await {
+        let (len, from) = reply.map_err(err)?;
+
+        for node in mdns::endpoints(&buf[..len], SERVICE, from.ip())
+            .into_iter()
+            .map(Node::from)
+        {
+            if !found.iter().any(|v| v.name == node.name) {
+                found.push(node)
+            }
+        }
+    }
+
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let mut node = Node {
+            name: "main".into(),
+            addr: "10.0.0.1:3000".parse().unwrap(),
+            info: HashMap::new(),
+        };
+
+        assert_eq!(node.url(), "http://10.0.0.1:3000");
+
+        node.info.insert("tls".into(), "true".into());
+        assert_eq!(node.url(), "https://10.0.0.1:3000");
+
+        node.info
+            .insert("pref-addr".into(), "drmem.local:3000".into());
+        assert_eq!(node.url(), "https://drmem.local:3000");
+    }
+}
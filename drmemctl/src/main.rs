@@ -0,0 +1,632 @@
+// `drmemctl` uses the GraphQL interface of `drmemd` to do the common
+// operations from a shell: list devices, read and monitor them, set
+// them, and dump their history. The output is one line per item so
+// it can be used by scripts. With `--json`, the data returned by
+// `drmemd` is printed instead.
+
+use chrono::{DateTime, Duration, Utc};
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
+use drmem_api::{device, Error, Result};
+use serde_json::{json, Value as Json};
+
+mod client;
+#[cfg(feature = "tui")]
+mod dash;
+mod discover;
+
+// The fields of a reading requested from `drmemd`.
+
+const READING: &str = "stamp boolValue intValue floatValue stringValue \
+                       colorValue";
+
+// Converts a reading returned by `drmemd` to a value.
+
+fn to_value(reading: &Json) -> Option<device::Value> {
+    if let Some(v) = reading["boolValue"].as_bool() {
+        Some(v.into())
+    } else if let Some(v) = reading["intValue"].as_i64() {
+        Some((v as i32).into())
+    } else if let Some(v) = reading["floatValue"].as_f64() {
+        Some(v.into())
+    } else if let Some(v) = reading["stringValue"].as_str() {
+        Some(v.into())
+    } else {
+        let c: Vec<u8> = reading["colorValue"]
+            .as_array()?
+            .iter()
+            .map(|v| v.as_u64().map(|v| v as u8))
+            .collect::<Option<_>>()?;
+
+        match c[..] {
+            [r, g, b] => Some(palette::LinSrgba::new(r, g, b, 255).into()),
+            [r, g, b, a] => Some(palette::LinSrgba::new(r, g, b, a).into()),
+            _ => None,
+        }
+    }
+}
+
+// Formats a reading as its timestamp and value.
+
+fn format_reading(reading: &Json) -> String {
+    format!(
+        "{}\t{}",
+        reading["stamp"].as_str().unwrap_or("-"),
+        to_value(reading).map_or_else(|| "-".into(), |v| v.to_string())
+    )
+}
+
+// Converts a value given on the command line to the setting sent to
+// `drmemd`. Without a type, the value is a boolean, an integer, a
+// float, or a color ("#rrggbb" or "#rrggbbaa"), if it looks like
+// one, and a string otherwise.
+
+fn parse_value(text: &str, ty: Option<&str>) -> Result<Json> {
+    let bad = |ty: &str| {
+        Error::InvArgument(format!("'{}' isn't a valid {}", text, ty))
+    };
+    let color = || {
+        let hex = text.strip_prefix('#').filter(|v| {
+            (v.len() == 6 || v.len() == 8)
+                && v.chars().all(|c| c.is_ascii_hexdigit())
+        })?;
+
+        Some(
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    match ty {
+        Some("bool") => text
+            .parse::<bool>()
+            .map(|v| json!({ "bool": v }))
+            .map_err(|_| bad("boolean")),
+        Some("int") => text
+            .parse::<i32>()
+            .map(|v| json!({ "int": v }))
+            .map_err(|_| bad("integer")),
+        Some("flt") => text
+            .parse::<f64>()
+            .map(|v| json!({ "flt": v }))
+            .map_err(|_| bad("float")),
+        Some("str") => Ok(json!({ "str": text })),
+        Some("color") => {
+            color().map(|v| json!({ "color": v })).ok_or(bad("color"))
+        }
+        Some(ty) => Err(Error::InvArgument(format!("unknown type '{}'", ty))),
+        None => Ok(if let Ok(v) = text.parse::<bool>() {
+            json!({ "bool": v })
+        } else if let Ok(v) = text.parse::<i32>() {
+            json!({ "int": v })
+        } else if let Ok(v) = text.parse::<f64>() {
+            json!({ "flt": v })
+        } else if let Some(v) = color() {
+            json!({ "color": v })
+        } else {
+            json!({ "str": text })
+        }),
+    }
+}
+
+// Parses a time given on the command line. It's either in RFC 3339
+// format or a time before `now`, like "90s", "15m", "2h", or "7d".
+
+fn parse_time(text: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if let Ok(v) = DateTime::parse_from_rfc3339(text) {
+        return Ok(v.with_timezone(&Utc));
+    }
+
+    let (amount, unit) = text.split_at(text.len().saturating_sub(1));
+    let amount = amount.parse::<i64>().ok().filter(|v| *v >= 0);
+    let ago = match (amount, unit) {
+        (Some(v), "s") => Duration::try_seconds(v),
+        (Some(v), "m") => Duration::try_minutes(v),
+        (Some(v), "h") => Duration::try_hours(v),
+        (Some(v), "d") => Duration::try_days(v),
+        _ => None,
+    };
+
+    ago.and_then(|v| now.checked_sub_signed(v)).ok_or_else(|| {
+        Error::InvArgument(format!(
+            "'{}' isn't an RFC 3339 time or a duration like \"2h\"",
+            text
+        ))
+    })
+}
+
+fn print_json(v: &Json) {
+    println!("{}", v)
+}
+
+async fn list_devices(
+    client: &client::Client,
+    pattern: Option<&String>,
+    raw: bool,
+) -> Result<()> {
+    let data = client
+        .query(
+            "query ($pattern: String) { \
+             deviceInfo(pattern: $pattern) \
+             { deviceName units settable summary } }",
+            json!({ "pattern": pattern }),
+        )
+        .await?;
+
+    if raw {
+        print_json(&data["deviceInfo"])
+    } else {
+        for dev in data["deviceInfo"].as_array().into_iter().flatten() {
+            println!(
+                "{}\t{}\t{}\t{}",
+                dev["deviceName"].as_str().unwrap_or("-"),
+                dev["units"].as_str().unwrap_or("-"),
+                if dev["settable"] == true { "rw" } else { "r" },
+                dev["summary"].as_str().unwrap_or("")
+            )
+        }
+    }
+    Ok(())
+}
+
+async fn get_device(
+    client: &client::Client,
+    name: &str,
+    follow: bool,
+    raw: bool,
+) -> Result<()> {
+    let print = |reading: &Json| {
+        if raw {
+            print_json(reading)
+        } else {
+            println!("{}", format_reading(reading))
+        }
+    };
+
+    // Following a device subscribes to it. The first update is its
+    // latest reading.
+
+    if follow {
+        return client
+            .subscribe(
+                &format!(
+                    "subscription ($name: String!) \
+                     {{ monitorDevice(device: $name) {{ {} }} }}",
+                    READING
+                ),
+                json!({ "name": name }),
+                None,
+                |data| {
+                    print(&data["monitorDevice"]);
+                    Ok(())
+                },
+            )
+            .await;
+    }
+
+    let data = client
+        .query(
+            &format!(
+                "query ($name: String) {{ deviceInfo(pattern: $name) \
+                 {{ deviceName history {{ lastPoint {{ {} }} }} }} }}",
+                READING
+            ),
+            json!({ "name": name }),
+        )
+        .await?;
+    let dev = data["deviceInfo"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|v| v["deviceName"] == name)
+        .ok_or(Error::NotFound)?;
+
+    print(&dev["history"]["lastPoint"]);
+    Ok(())
+}
+
+// Sends a setting to a device and returns the reading it used.
+
+async fn send_setting(
+    client: &client::Client,
+    name: &str,
+    value: Json,
+) -> Result<Json> {
+    let mut data = client
+        .query(
+            &format!(
+                "mutation ($name: String!, $value: SettingData!) \
+                 {{ setDevice(name: $name, value: $value) {{ {} }} }}",
+                READING
+            ),
+            json!({ "name": name, "value": value }),
+        )
+        .await?;
+
+    Ok(data["setDevice"].take())
+}
+
+async fn set_device(
+    client: &client::Client,
+    name: &str,
+    value: Json,
+    raw: bool,
+) -> Result<()> {
+    let reading = send_setting(client, name, value).await?;
+
+    if raw {
+        print_json(&reading)
+    } else {
+        println!("{}", format_reading(&reading))
+    }
+    Ok(())
+}
+
+// The subscription of a time range only ends when the device reports
+// a reading after the range, which can take a long time. Since stored
+// readings are sent right away, the dump ends when no reading arrives
+// for this long.
+
+const HISTORY_IDLE: std::time::Duration = std::time::Duration::from_secs(1);
+
+async fn dump_history(
+    client: &client::Client,
+    name: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    raw: bool,
+) -> Result<()> {
+    client
+        .subscribe(
+            &format!(
+                "subscription ($name: String!, $range: DateRange) \
+                 {{ monitorDevice(device: $name, range: $range) {{ {} }} }}",
+                READING
+            ),
+            json!({
+                "name": name,
+                "range": {
+                    "start": start.to_rfc3339(),
+                    "end": end.to_rfc3339(),
+                },
+            }),
+            Some(HISTORY_IDLE),
+            |data| {
+                let reading = &data["monitorDevice"];
+
+                if raw {
+                    print_json(reading)
+                } else {
+                    println!("{}", format_reading(reading))
+                }
+                Ok(())
+            },
+        )
+        .await
+}
+
+// Prints the `drmemd` nodes found on the LAN.
+
+async fn discover_nodes(wait: std::time::Duration, raw: bool) -> Result<()> {
+    let nodes = discover::discover(wait).await?;
+
+    if raw {
+        print_json(&Json::Array(
+            nodes
+                .iter()
+                .map(|v| {
+                    json!({
+                        "name": v.name,
+                        "url": v.url(),
+                        "info": v.info,
+                    })
+                })
+                .collect(),
+        ))
+    } else {
+        for node in nodes {
+            println!(
+                "{}\t{}\t{}\t{}",
+                node.name,
+                node.url(),
+                node.info.get("version").map_or("-", String::as_str),
+                node.info.get("location").map_or("", String::as_str)
+            )
+        }
+    }
+    Ok(())
+}
+
+fn device_arg() -> Arg {
+    Arg::new("device")
+        .required(true)
+        .value_name("DEVICE")
+        .help("The name of the device")
+}
+
+fn command() -> Command {
+    let cmd = Command::new("drmemctl")
+        .version(crate_version!())
+        .about("Command line client of the DrMem control system.")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("url")
+                .short('u')
+                .long("url")
+                .env("DRMEM_URL")
+                .default_value("http://localhost:3000")
+                .help("The URL of drmemd's GraphQL server"),
+        )
+        .arg(
+            Arg::new("client_id")
+                .long("client-id")
+                .env("DRMEM_CLIENT_ID")
+                .help("The client fingerprint sent to secure servers"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Prints the data returned by drmemd as JSON"),
+        )
+        .subcommand(
+            Command::new("devices")
+                .about("Lists devices: name, units, access, and summary")
+                .arg(
+                    Arg::new("pattern")
+                        .value_name("PATTERN")
+                        .help("Only lists devices matching the pattern"),
+                ),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Prints the latest reading of a device")
+                .arg(device_arg())
+                .arg(
+                    Arg::new("follow")
+                        .short('f')
+                        .long("follow")
+                        .action(ArgAction::SetTrue)
+                        .help("Keeps printing readings as they arrive"),
+                ),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Sets a device and prints the value it used")
+                .arg(device_arg())
+                .arg(
+                    Arg::new("value")
+                        .required(true)
+                        .value_name("VALUE")
+                        .allow_hyphen_values(true)
+                        .help("The value to send"),
+                )
+                .arg(
+                    Arg::new("type")
+                        .short('t')
+                        .long("type")
+                        .value_parser(["bool", "int", "flt", "str", "color"])
+                        .help("The type of the value; guessed if not given"),
+                ),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("Prints the readings of a device over a time range")
+                .arg(device_arg())
+                .arg(
+                    Arg::new("start")
+                        .short('s')
+                        .long("start")
+                        .required(true)
+                        .help("The start, in RFC 3339 format or like \"2h\""),
+                )
+                .arg(
+                    Arg::new("end")
+                        .short('e')
+                        .long("end")
+                        .help("The end, like the start; defaults to now"),
+                ),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("Lists the drmemd nodes on the LAN: name, URL, version")
+                .arg(
+                    Arg::new("wait")
+                        .short('w')
+                        .long("wait")
+                        .value_name("SECS")
+                        .value_parser(clap::value_parser!(u64).range(1..=60))
+                        .default_value("2")
+                        .help("How long to wait for replies"),
+                ),
+        );
+
+    #[cfg(feature = "tui")]
+    let cmd = cmd.subcommand(
+        Command::new("dash")
+            .about("Shows a live dashboard of devices in the terminal")
+            .arg(
+                Arg::new("device")
+                    .value_name("DEVICE")
+                    .num_args(0..)
+                    .help("The devices to show"),
+            )
+            .arg(
+                Arg::new("pattern")
+                    .short('p')
+                    .long("pattern")
+                    .help("Also shows the devices matching the pattern"),
+            )
+            .arg(
+                Arg::new("file")
+                    .short('F')
+                    .long("file")
+                    .help("A TOML file with more devices and a pattern"),
+            ),
+    );
+
+    cmd
+}
+
+async fn run(matches: ArgMatches) -> Result<()> {
+    let client = client::Client::new(
+        matches.get_one::<String>("url").unwrap(),
+        matches.get_one::<String>("client_id").cloned(),
+    )?;
+    let raw = matches.get_flag("json");
+    let device = |m: &ArgMatches| -> Result<String> {
+        let name = m.get_one::<String>("device").unwrap();
+
+        name.parse::<device::Name>()
+            .map(|_| name.clone())
+            .map_err(|_| {
+                Error::InvArgument(format!("bad device name {}", name))
+            })
+    };
+
+    match matches.subcommand() {
+        Some(("devices", m)) => {
+            list_devices(&client, m.get_one("pattern"), raw).await
+        }
+        Some(("get", m)) => {
+            get_device(&client, &device(m)?, m.get_flag("follow"), raw).await
+        }
+        Some(("set", m)) => {
+            let value = parse_value(
+                m.get_one::<String>("value").unwrap(),
+                m.get_one::<String>("type").map(String::as_str),
+            )?;
+
+            set_device(&client, &device(m)?, value, raw).await
+        }
+        Some(("history", m)) => {
+            let now = Utc::now();
+            let start = parse_time(m.get_one::<String>("start").unwrap(), now)?;
+            let end = match m.get_one::<String>("end") {
+                Some(v) => parse_time(v, now)?,
+                None => now,
+            };
+
+            dump_history(&client, &device(m)?, start, end, raw).await
+        }
+        Some(("discover", m)) => {
+            let secs = *m.get_one::<u64>("wait").unwrap();
+
+            discover_nodes(std::time::Duration::from_secs(secs), raw).await
+        }
+        #[cfg(feature = "tui")]
+        Some(("dash", m)) => {
+            let mut devices: Vec<String> = m
+                .get_many("device")
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect();
+            let mut pattern = m.get_one::<String>("pattern").cloned();
+
+            if let Some(path) = m.get_one::<String>("file") {
+                let file_pattern = dash::read_file(path, &mut devices)?;
+
+                pattern = pattern.or(file_pattern)
+            }
+
+            if devices.is_empty() && pattern.is_none() {
+                return Err(Error::InvArgument(
+                    "no devices were given to show".into(),
+                ));
+            }
+
+            dash::run(&client, &devices, pattern.as_deref()).await
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    if let Err(e) = run(command().get_matches()).await {
+        eprintln!("drmemctl: {}", e);
+        std::process::exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        assert_eq!(parse_value("true", None).unwrap(), json!({"bool": true}));
+        assert_eq!(parse_value("-5", None).unwrap(), json!({"int": -5}));
+        assert_eq!(parse_value("2.5", None).unwrap(), json!({"flt": 2.5}));
+        assert_eq!(
+            parse_value("#ff8000", None).unwrap(),
+            json!({"color": [255, 128, 0]})
+        );
+        assert_eq!(
+            parse_value("#ff80", None).unwrap(),
+            json!({"str": "#ff80"})
+        );
+        assert_eq!(parse_value("on", None).unwrap(), json!({"str": "on"}));
+        assert_eq!(parse_value("5", Some("str")).unwrap(), json!({"str": "5"}));
+        assert_eq!(parse_value("5", Some("flt")).unwrap(), json!({"flt": 5.0}));
+        assert!(parse_value("x", Some("int")).is_err());
+        assert!(parse_value("#12", Some("color")).is_err());
+
+        assert_eq!(
+            format_reading(&json!({
+                "stamp": "2024-01-01T00:00:00Z",
+                "colorValue": [255, 0, 16, 128],
+            })),
+            "2024-01-01T00:00:00Z\t\"#ff001080\""
+        );
+        assert_eq!(
+            format_reading(&json!({
+                "stamp": "2024-01-01T00:00:00Z",
+                "intValue": 3,
+                "floatValue": null,
+            })),
+            "2024-01-01T00:00:00Z\t3"
+        );
+        assert_eq!(format_reading(&Json::Null), "-\t-");
+    }
+
+    #[test]
+    fn test_times() {
+        let now = DateTime::parse_from_rfc3339("2024-01-02T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let ago = |secs| now - Duration::try_seconds(secs).unwrap();
+
+        assert_eq!(parse_time("90s", now).unwrap(), ago(90));
+        assert_eq!(parse_time("15m", now).unwrap(), ago(900));
+        assert_eq!(parse_time("2h", now).unwrap(), ago(7_200));
+        assert_eq!(parse_time("1d", now).unwrap(), ago(86_400));
+        assert_eq!(
+            parse_time("2024-01-01T18:00:00-06:00", now).unwrap(),
+            ago(0)
+        );
+        assert!(parse_time("-1h", now).is_err());
+        assert!(parse_time("2w", now).is_err());
+        assert!(parse_time("", now).is_err());
+    }
+
+    #[test]
+    fn test_command() {
+        command().debug_assert();
+
+        let m = command()
+            .try_get_matches_from(["drmemctl", "set", "a:b", "-5", "-t", "int"])
+            .unwrap();
+        let (_, m) = m.subcommand().unwrap();
+
+        assert_eq!(m.get_one::<String>("value").unwrap(), "-5");
+        assert!(command()
+            .try_get_matches_from(["drmemctl", "history", "a:b"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(["drmemctl", "discover", "-w", "0"])
+            .is_err());
+    }
+}
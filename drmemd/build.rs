@@ -11,5 +11,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         })
         .lexer_in_src_dir("logic/logic.l")?
         .build()?;
+
+    // The gRPC service is generated from its protobuf definition.
+
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/drmem.proto"], &["proto"])?;
+    }
     Ok(())
 }
@@ -0,0 +1,179 @@
+// Groups the history of a device into buckets. Back-ends feed the
+// readings, in order, to an `Aggregator` and return the buckets it
+// built.
+
+use chrono::{DateTime, Utc};
+use drmem_api::{client, device, Error, Result};
+use std::time::{Duration, SystemTime};
+
+pub struct Aggregator {
+    start: SystemTime,
+    end: SystemTime,
+    interval: Duration,
+    numeric: u32,
+    buckets: Vec<client::HistoryBucket>,
+}
+
+impl Aggregator {
+    pub fn new(
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: Duration,
+    ) -> Result<Self> {
+        if interval.is_zero() {
+            return Err(Error::InvArgument("interval can't be zero".into()));
+        }
+        if end < start {
+            return Err(Error::InvArgument("end is before start".into()));
+        }
+
+        Ok(Aggregator {
+            start: start.into(),
+            end: end.into(),
+            interval,
+            numeric: 0,
+            buckets: vec![],
+        })
+    }
+
+    fn as_f64(value: &device::Value) -> Option<f64> {
+        match value {
+            device::Value::Bool(v) => Some(if *v { 1.0 } else { 0.0 }),
+            device::Value::Int(v) => Some(*v as f64),
+            device::Value::Flt(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    // Adds a reading to its bucket. Readings outside the range are
+    // ignored. Readings have to be added in the order they occurred.
+
+    pub fn add(&mut self, reading: &device::Reading) {
+        let Ok(offset) = reading.ts.duration_since(self.start) else {
+            return;
+        };
+
+        if reading.ts > self.end {
+            return;
+        }
+
+        let bucket_start = self.start
+            + self.interval
+                * (offset.as_nanos() / self.interval.as_nanos()) as u32;
+        let bucket_start = DateTime::<Utc>::from(bucket_start);
+        let value = Self::as_f64(&reading.value);
+
+        match self.buckets.last_mut() {
+            Some(b) if b.start == bucket_start => {
+                b.count += 1;
+                b.last = reading.clone();
+
+                if let Some(v) = value {
+                    self.numeric += 1;
+                    b.min = Some(b.min.map_or(v, |m| m.min(v)));
+                    b.max = Some(b.max.map_or(v, |m| m.max(v)));
+                    b.avg = Some(
+                        b.avg.map_or(v, |a| a + (v - a) / self.numeric as f64),
+                    );
+                }
+            }
+            _ => {
+                self.numeric = value.is_some() as u32;
+                self.buckets.push(client::HistoryBucket {
+                    start: bucket_start,
+                    count: 1,
+                    min: value,
+                    max: value,
+                    avg: value,
+                    last: reading.clone(),
+                })
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<client::HistoryBucket> {
+        self.buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let base = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let reading = |secs: u64, v: device::Value| device::Reading {
+            ts: base + Duration::from_secs(secs),
+            value: v,
+        };
+
+        assert!(Aggregator::new(
+            base.into(),
+            base.into(),
+            Duration::from_secs(0)
+        )
+        .is_err());
+        assert!(Aggregator::new(
+            (base + Duration::from_secs(1)).into(),
+            base.into(),
+            Duration::from_secs(10)
+        )
+        .is_err());
+
+        let mut agg = Aggregator::new(
+            (base + Duration::from_secs(10)).into(),
+            (base + Duration::from_secs(50)).into(),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+
+        for r in [
+            reading(5, 100.into()),
+            reading(10, 1.into()),
+            reading(12, 5.into()),
+            reading(19, 3.into()),
+            reading(35, true.into()),
+            reading(38, false.into()),
+            reading(39, false.into()),
+            reading(40, "on".into()),
+            reading(51, 100.into()),
+        ] {
+            agg.add(&r)
+        }
+
+        let buckets = agg.finish();
+
+        // Readings outside the range are dropped and empty buckets
+        // aren't reported.
+
+        assert_eq!(buckets.len(), 3);
+
+        assert_eq!(
+            buckets[0].start,
+            DateTime::<Utc>::from(base + Duration::from_secs(10))
+        );
+        assert_eq!(buckets[0].count, 3);
+        assert_eq!(buckets[0].min, Some(1.0));
+        assert_eq!(buckets[0].max, Some(5.0));
+        assert_eq!(buckets[0].avg, Some(3.0));
+        assert_eq!(buckets[0].last, reading(19, 3.into()));
+
+        // Booleans count as 0 and 1.
+
+        assert_eq!(
+            buckets[1].start,
+            DateTime::<Utc>::from(base + Duration::from_secs(30))
+        );
+        assert_eq!(buckets[1].count, 3);
+        assert_eq!(buckets[1].min, Some(0.0));
+        assert_eq!(buckets[1].max, Some(1.0));
+        assert!((buckets[1].avg.unwrap() - 1.0 / 3.0).abs() < 1e-9);
+
+        // Strings only report the count and last value.
+
+        assert_eq!(buckets[2].count, 1);
+        assert_eq!(buckets[2].avg, None);
+        assert_eq!(buckets[2].last, reading(40, "on".into()));
+    }
+}
@@ -0,0 +1,146 @@
+// Sizes the channels a back-end creates for each device. The
+// `[channels]` section gives the defaults and the first `[[device]]`
+// entry whose pattern matches a device, and that gives `readings` or
+// `policy`, can override the size of its readings channel and the
+// policy used when a client falls behind.
+
+use crate::{config, glob};
+use drmem_api::device;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Records readings a client missed because it fell behind.
+
+pub fn dropped(count: u64) {
+    DROPPED.fetch_add(count, Ordering::Relaxed);
+}
+
+// Returns the number of readings that clients have missed since
+// `drmemd` started.
+
+pub fn total_dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+struct Rule {
+    pattern: glob::Pattern,
+    readings: Option<usize>,
+    policy: Option<config::Policy>,
+}
+
+#[derive(Default)]
+pub struct Capacities {
+    defaults: config::Channels,
+    rules: Vec<Rule>,
+}
+
+impl Capacities {
+    pub fn new(cfg: &config::Config) -> Self {
+        Capacities {
+            defaults: cfg.channels.clone(),
+            rules: cfg
+                .device
+                .iter()
+                .filter(|v| v.readings.is_some() || v.policy.is_some())
+                .map(|v| Rule {
+                    pattern: glob::Pattern::create(&v.pattern),
+                    readings: v.readings,
+                    policy: v.policy,
+                })
+                .collect(),
+        }
+    }
+
+    // Returns the size of the readings channel for a device and what
+    // to do when a client falls behind.
+
+    pub fn readings(&self, name: &device::Name) -> (usize, config::Policy) {
+        let name = name.to_string();
+        let rule = self.rules.iter().find(|v| v.pattern.matches(&name));
+
+        (
+            rule.and_then(|v| v.readings)
+                .unwrap_or(self.defaults.readings),
+            rule.and_then(|v| v.policy).unwrap_or(self.defaults.policy),
+        )
+    }
+
+    // Returns the size of the channel holding a device's settings.
+
+    pub fn settings(&self) -> usize {
+        self.defaults.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capacities() {
+        let mut cfg = config::Config::default();
+
+        cfg.channels.readings = 50;
+        cfg.device.push(config::Device {
+            pattern: "weather:*".into(),
+            min_report_interval: None,
+            deadband: None,
+            readings: Some(500),
+            policy: Some(config::Policy::Block),
+            max_history: None,
+            max_age: None,
+        });
+        cfg.device.push(config::Device {
+            pattern: "*".into(),
+            min_report_interval: None,
+            deadband: None,
+            readings: None,
+            policy: Some(config::Policy::Block),
+            max_history: None,
+            max_age: None,
+        });
+
+        let caps = Capacities::new(&cfg);
+
+        assert_eq!(
+            caps.readings(&"weather:temp".parse().unwrap()),
+            (500, config::Policy::Block)
+        );
+        assert_eq!(
+            caps.readings(&"sump:state".parse().unwrap()),
+            (50, config::Policy::Block)
+        );
+        assert_eq!(caps.settings(), 20);
+
+        // An earlier entry that only limits reports doesn't hide the
+        // capacities of a later one.
+
+        cfg.device.insert(
+            0,
+            config::Device {
+                pattern: "weather:*".into(),
+                min_report_interval: Some(60.0),
+                deadband: None,
+                readings: None,
+                policy: None,
+                max_history: None,
+                max_age: None,
+            },
+        );
+
+        let caps = Capacities::new(&cfg);
+
+        assert_eq!(
+            caps.readings(&"weather:temp".parse().unwrap()),
+            (500, config::Policy::Block)
+        );
+
+        let caps = Capacities::default();
+
+        assert_eq!(
+            caps.readings(&"sump:state".parse().unwrap()),
+            (20, config::Policy::DropOldest)
+        );
+    }
+}
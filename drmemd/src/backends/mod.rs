@@ -1,6 +1,24 @@
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use drmem_api::{client, device, driver, Result};
+use std::{future::Future, pin::Pin, time::Duration};
+use tokio_stream::StreamMap;
+
+mod aggregate;
+pub mod channels;
+
+pub use aggregate::Aggregator;
+
+// The work of summarizing a device's history. Scanning a long history
+// can take a while, so the core runs it in its own task.
+
+pub type Summary =
+    Pin<Box<dyn Future<Output = Result<Vec<client::HistoryBucket>>> + Send>>;
+
+// A setting on its way to a driver. A driver can be slow to reply, so
+// the core waits for it in its own task.
+
+pub type Setting = Pin<Box<dyn Future<Output = Result<device::Value>> + Send>>;
 
 // Defines the trait that a back-end needs to implement to provide
 // storage for -- and access to -- the state of each driver's devices.
@@ -23,6 +41,7 @@ pub trait Store {
     //   units returned by the device.
     // - `max_history` is a hint as to how large an archive the user
     //   specifies should be used for this device.
+    // - `max_age` is a hint as to how long readings should be kept.
     //
     // On success, this function returns a pair. The first element is
     // a closure the driver uses to report updates. The second element
@@ -35,6 +54,7 @@ pub trait Store {
         name: &device::Name,
         units: Option<&String>,
         max_history: Option<usize>,
+        max_age: Option<Duration>,
     ) -> Result<driver::ReportReading>;
 
     // Called when a read-write device is to be registered with the
@@ -53,6 +73,7 @@ pub trait Store {
     //   units returned by the device.
     // - `max_history` is a hint as to how large an archive the user
     //   specifies should be used for this device.
+    // - `max_age` is a hint as to how long readings should be kept.
     //
     // On success, this function returns a 3-tuple. The first element
     // is a closure the driver uses to report updates. The second
@@ -66,6 +87,7 @@ pub trait Store {
         name: &device::Name,
         units: Option<&String>,
         max_history: Option<usize>,
+        max_age: Option<Duration>,
     ) -> Result<(
         driver::ReportReading,
         driver::RxDeviceSetting,
@@ -85,14 +107,25 @@ pub trait Store {
         pattern: Option<&str>,
     ) -> Result<Vec<client::DevInfoReply>>;
 
-    // Sends a request to a driver to set its device to the specified
-    // value.
+    // Changes the descriptive information of a device. The changes
+    // should be saved with the rest of the device's information so
+    // they aren't lost when the driver registers the device again.
+
+    async fn set_device_meta(
+        &mut self,
+        name: &device::Name,
+        update: client::MetaUpdate,
+    ) -> Result<()>;
+
+    // Returns a future that sends a request to a driver to set its
+    // device to the specified value and resolves to the driver's
+    // reply.
 
     async fn set_device(
         &self,
         name: device::Name,
         value: device::Value,
-    ) -> Result<device::Value>;
+    ) -> Result<Setting>;
 
     // Obtains the `mpsc::Sender<>` handle associated with the
     // specified device. This handle can be used to send settings to
@@ -116,6 +149,51 @@ pub trait Store {
         start: Option<DateTime<Utc>>,
         end: Option<DateTime<Utc>>,
     ) -> Result<device::DataStream<device::Reading>>;
+
+    // Creates a stream that yields the values of several devices as
+    // they update. Each reading is paired with the name of its
+    // device. Back-ends that can watch several devices more
+    // efficiently than merging the streams of `monitor_device`
+    // should override this method.
+
+    async fn monitor_devices(
+        &mut self,
+        names: Vec<device::Name>,
+    ) -> Result<device::DataStream<(device::Name, device::Reading)>>
+    where
+        Self: Send,
+    {
+        let mut streams = StreamMap::new();
+
+        for name in names {
+            let strm = self.monitor_device(name.clone(), None, None).await?;
+
+            streams.insert(name, strm);
+        }
+        Ok(Box::pin(streams))
+    }
+
+    // Checks that the back-end can be reached. Back-ends that keep
+    // their data in memory are always available.
+
+    async fn ping(&mut self) -> Result<()>
+    where
+        Self: Send,
+    {
+        Ok(())
+    }
+
+    // Returns a future that summarizes the history of a device
+    // between `start` and `end` into buckets that are `interval`
+    // long.
+
+    async fn aggregate_history(
+        &mut self,
+        name: device::Name,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: Duration,
+    ) -> Result<Summary>;
 }
 
 #[cfg(feature = "simple-backend")]
@@ -1,4 +1,6 @@
-use crate::backends::Store;
+use crate::backends::{
+    channels::Capacities, Aggregator, Setting, Store, Summary,
+};
 use async_trait::async_trait;
 use chrono::*;
 use drmem_api::{
@@ -10,9 +12,11 @@ use futures::task::{Context, Poll};
 use futures::Future;
 use redis::{
     aio,
-    streams::{StreamId, StreamInfoStreamReply},
+    streams::{
+        StreamId, StreamInfoStreamReply, StreamRangeReply, StreamReadReply,
+    },
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
 use std::pin::Pin;
 use std::time;
@@ -258,6 +262,15 @@ fn id_to_ts(id: &str) -> Result<time::SystemTime> {
     Err(Error::InvArgument(String::from("unknown timestamp format")))
 }
 
+// Returns the stream ID, in milliseconds, of the oldest reading that
+// is younger than `age` at time `now`.
+
+fn oldest_id(now: time::SystemTime, age: time::Duration) -> u64 {
+    now.checked_sub(age)
+        .and_then(|v| v.duration_since(time::UNIX_EPOCH).ok())
+        .map_or(0, |v| v.as_millis() as u64)
+}
+
 type ReadFuture = Pin<
     Box<
         dyn Future<
@@ -276,6 +289,7 @@ struct ReadingStream {
 
 impl ReadingStream {
     const TIMEOUT: usize = 5_000;
+    const MANY_COUNT: usize = 20;
 
     // Converts a `time::SystemTime` into a redis stream id.
     // Microseconds are mapping into the secondary portion of the id.
@@ -292,6 +306,18 @@ impl ReadingStream {
         format!("{}-{}", us / 1000, us % 1000)
     }
 
+    // Builds the command that waits for new readings from several
+    // history streams. Each stream's readings are read after the
+    // matching id in `ids`.
+
+    fn read_many_cmd(keys: &[String], ids: &[String]) -> redis::Cmd {
+        let opts = redis::streams::StreamReadOptions::default()
+            .block(Self::TIMEOUT)
+            .count(Self::MANY_COUNT);
+
+        redis::Cmd::xread_options(keys, ids, &opts)
+    }
+
     fn read_next_cmd(key: &str, id: &str) -> redis::Cmd {
         let opts = redis::streams::StreamReadOptions::default()
             .block(Self::TIMEOUT)
@@ -431,9 +457,15 @@ pub struct RedisStore {
     db_con: AioMplexConnection,
     table: SettingTable,
     cfg: config::Config,
+    caps: Capacities,
 }
 
 impl RedisStore {
+    // The number of readings requested at a time when scanning a
+    // device's history.
+
+    const HISTORY_PAGE: usize = 1_000;
+
     fn make_client(
         cfg: &config::Config,
         name: Option<&String>,
@@ -512,6 +544,7 @@ impl RedisStore {
             db_con,
             table: HashMap::new(),
             cfg: cfg.clone(),
+            caps: Capacities::default(),
         })
     }
 
@@ -574,6 +607,15 @@ impl RedisStore {
         redis::Cmd::xrevrange_count(name, "+", "-", 1usize)
     }
 
+    // Builds the low-level command that returns a page of the
+    // device's history, starting at the stream id `from`.
+
+    fn history_range_cmd(name: &str, from: &str, to: &str) -> redis::Cmd {
+        let name = Self::hist_key(name);
+
+        redis::Cmd::xrange_count(name, from, to, Self::HISTORY_PAGE)
+    }
+
     fn match_pattern_cmd(pattern: Option<&str>) -> redis::Cmd {
         // Take the pattern from the caller and append "#info" since
         // we only want to look at device information keys.
@@ -608,6 +650,33 @@ impl RedisStore {
     // Creates a redis command pipeline which returns the standard,
     // meta-data for a device.
 
+    // Builds the pipeline that changes the descriptive fields of the
+    // device's info record. Empty values remove the field.
+
+    fn set_meta_cmd(
+        name: &str,
+        update: &client::MetaUpdate,
+    ) -> redis::Pipeline {
+        let info_key = Self::info_key(name);
+        let mut pipe = redis::pipe();
+
+        pipe.atomic();
+
+        for (field, value) in [
+            ("units", &update.units),
+            ("summary", &update.summary),
+            ("detail", &update.detail),
+            ("location", &update.location),
+        ] {
+            match value.as_deref() {
+                Some("") => pipe.hdel(&info_key, field).ignore(),
+                Some(v) => pipe.hset(&info_key, field, v).ignore(),
+                None => &mut pipe,
+            };
+        }
+        pipe
+    }
+
     fn device_info_cmd(name: &str) -> redis::Cmd {
         let info_key = Self::info_key(name);
 
@@ -643,6 +712,18 @@ impl RedisStore {
         redis::Cmd::xadd_maxlen(key, opts, "*", &data)
     }
 
+    // Generates a command that removes the readings older than
+    // `oldest`, a stream ID in milliseconds. Like the length limit,
+    // the trimming is approximate so redis can drop whole blocks of
+    // readings. Requires redis 6.2, or later.
+
+    fn trim_old_values_cmd(key: &str, oldest: u64) -> redis::Cmd {
+        let mut cmd = redis::cmd("XTRIM");
+
+        cmd.arg(key).arg("MINID").arg("~").arg(oldest);
+        cmd
+    }
+
     fn hash_to_info(
         st: &SettingTable,
         name: &device::Name,
@@ -674,6 +755,11 @@ impl RedisStore {
                 total_points: 0,
                 first_point: None,
                 last_point: None,
+                meta: client::DeviceMeta {
+                    summary: hmap.get("summary").cloned(),
+                    detail: hmap.get("detail").cloned(),
+                    location: hmap.get("location").cloned(),
+                },
             })
         } else {
             Err(Error::NotFound)
@@ -871,48 +957,46 @@ impl RedisStore {
     }
 
     // Creates a closure for a driver to report a device's changing
-    // values.
+    // values. If `max_age` is given, each new value also trims the
+    // readings that have become too old.
 
     fn mk_report_func(
         &self,
         name: &str,
         max_history: Option<usize>,
+        max_age: Option<time::Duration>,
     ) -> ReportReading {
         let db_con = self.db_con.clone();
         let name = String::from(name);
 
-        if let Some(mh) = max_history {
-            Box::new(move |v| {
-                let mut db_con = db_con.clone();
-                let hist_key = Self::hist_key(&name);
-                let name = name.clone();
+        Box::new(move |v| {
+            let mut db_con = db_con.clone();
+            let hist_key = Self::hist_key(&name);
+            let name = name.clone();
+            let mut pipe = redis::pipe();
 
-                Box::pin(async move {
-                    if let Err(e) =
-                        Self::report_bounded_new_value_cmd(&hist_key, &v, mh)
-                            .query_async::<()>(&mut db_con)
-                            .await
-                    {
-                        warn!("couldn't save {} data to redis ... {}", &name, e)
-                    }
-                })
+            pipe.add_command(match max_history {
+                Some(mh) => {
+                    Self::report_bounded_new_value_cmd(&hist_key, &v, mh)
+                }
+                None => Self::report_new_value_cmd(&hist_key, &v),
             })
-        } else {
-            Box::new(move |v| {
-                let mut db_con = db_con.clone();
-                let hist_key = Self::hist_key(&name);
-                let name = name.clone();
-
-                Box::pin(async move {
-                    if let Err(e) = Self::report_new_value_cmd(&hist_key, &v)
-                        .query_async::<()>(&mut db_con)
-                        .await
-                    {
-                        warn!("couldn't save {} data to redis ... {}", &name, e)
-                    }
-                })
+            .ignore();
+
+            if let Some(age) = max_age {
+                pipe.add_command(Self::trim_old_values_cmd(
+                    &hist_key,
+                    oldest_id(time::SystemTime::now(), age),
+                ))
+                .ignore();
+            }
+
+            Box::pin(async move {
+                if let Err(e) = pipe.query_async::<()>(&mut db_con).await {
+                    warn!("couldn't save {} data to redis ... {}", &name, e)
+                }
             })
-        }
+        })
     }
 }
 
@@ -926,6 +1010,7 @@ impl Store for RedisStore {
         name: &device::Name,
         units: Option<&String>,
         max_history: Option<usize>,
+        max_age: Option<time::Duration>,
     ) -> Result<ReportReading> {
         let name = name.to_string();
 
@@ -936,7 +1021,7 @@ impl Store for RedisStore {
 
             info!("'{}' has been successfully created", &name);
         }
-        Ok(self.mk_report_func(&name, max_history))
+        Ok(self.mk_report_func(&name, max_history, max_age))
     }
 
     async fn register_read_write_device(
@@ -945,6 +1030,7 @@ impl Store for RedisStore {
         name: &device::Name,
         units: Option<&String>,
         max_history: Option<usize>,
+        max_age: Option<time::Duration>,
     ) -> Result<(ReportReading, RxDeviceSetting, Option<device::Value>)> {
         let sname = name.to_string();
 
@@ -956,14 +1042,14 @@ impl Store for RedisStore {
             info!("'{}' has been successfully created", &sname);
         }
 
-        let (tx, rx) = mpsc::channel(20);
+        let (tx, rx) = mpsc::channel(self.caps.settings());
 
         if self.table.insert(name.clone(), tx).is_some() {
             warn!("{} already had a setting channel", &name);
         }
 
         Ok((
-            self.mk_report_func(&sname, max_history),
+            self.mk_report_func(&sname, max_history, max_age),
             rx,
             self.last_value(&sname).await.map(|v| v.value),
         ))
@@ -1010,29 +1096,55 @@ impl Store for RedisStore {
     // This method implements the set_device mutation in the GraphQL
     // API.
 
+    async fn ping(&mut self) -> Result<()> {
+        redis::cmd("PING")
+            .query_async::<()>(&mut self.db_con)
+            .await
+            .map_err(xlat_err)
+    }
+
+    async fn set_device_meta(
+        &mut self,
+        name: &device::Name,
+        update: client::MetaUpdate,
+    ) -> Result<()> {
+        let name = name.to_string();
+
+        self.validate_device(&name).await?;
+
+        Self::set_meta_cmd(&name, &update)
+            .query_async(&mut self.db_con)
+            .await
+            .map_err(xlat_err)
+    }
+
     async fn set_device(
         &self,
         name: device::Name,
         value: device::Value,
-    ) -> Result<device::Value> {
+    ) -> Result<Setting> {
         if let Some(tx) = self.table.get(&name) {
-            let (tx_rpy, rx_rpy) = oneshot::channel();
+            let tx = tx.clone();
+
+            Ok(Box::pin(async move {
+                let (tx_rpy, rx_rpy) = oneshot::channel();
 
-            // Send the request and return from the function with the
-            // reply. If any error occurs during communication, fall
-            // through to report it.
+                // Send the request and return from the function with
+                // the reply. If any error occurs during communication,
+                // fall through to report it.
 
-            if let Ok(()) = tx.send((value, tx_rpy)).await {
-                if let Ok(reply) = rx_rpy.await {
-                    return reply;
+                if let Ok(()) = tx.send((value, tx_rpy)).await {
+                    if let Ok(reply) = rx_rpy.await {
+                        return reply;
+                    }
                 }
-            }
 
-            // Some portion of the RPC failed. Return an error.
+                // Some portion of the RPC failed. Return an error.
 
-            Err(Error::MissingPeer(
-                "cannot communicate with driver".to_string(),
-            ))
+                Err(Error::MissingPeer(
+                    "cannot communicate with driver".to_string(),
+                ))
+            }))
         } else {
             Err(Error::NotFound)
         }
@@ -1127,14 +1239,148 @@ impl Store for RedisStore {
             }
         }
     }
+
+    // Watches all the devices with one connection, which waits for
+    // new entries in any of their history streams.
+
+    async fn monitor_devices(
+        &mut self,
+        names: Vec<device::Name>,
+    ) -> Result<device::DataStream<(device::Name, device::Reading)>> {
+        let con = Self::make_connection(&self.cfg, None, None).await?;
+        let mut keys = Vec::with_capacity(names.len());
+        let mut ids = Vec::with_capacity(names.len());
+
+        // Start each stream just before its last value so the client
+        // gets the current values first. Devices without a history
+        // are read from the beginning of their stream.
+
+        for name in &names {
+            let name = name.to_string();
+
+            ids.push(
+                self.last_value(&name)
+                    .await
+                    .map(|r| ReadingStream::ts_to_id(st_minus_1us(r.ts)))
+                    .unwrap_or_else(|| String::from("0-0")),
+            );
+            keys.push(Self::hist_key(&name))
+        }
+
+        let state = (con, names, keys, ids, VecDeque::new());
+
+        Ok(Box::pin(futures::stream::unfold(
+            state,
+            |(mut con, names, keys, mut ids, mut pending)| async move {
+                loop {
+                    if let Some(item) = pending.pop_front() {
+                        break Some((item, (con, names, keys, ids, pending)));
+                    }
+
+                    let reply: redis::Value =
+                        match ReadingStream::read_many_cmd(&keys, &ids)
+                            .query_async(&mut con)
+                            .await
+                        {
+                            Ok(v) => v,
+                            Err(e) => {
+                                warn!("read error -- {}", &e);
+                                break None;
+                            }
+                        };
+
+                    // A `Nil` reply means the read timed out.
+
+                    if reply == redis::Value::Nil {
+                        continue;
+                    }
+
+                    let reply: StreamReadReply =
+                        match redis::from_redis_value(&reply) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("couldn't parse readings: {:?}", &e);
+                                break None;
+                            }
+                        };
+
+                    for skey in reply.keys {
+                        if let Some(idx) =
+                            keys.iter().position(|k| *k == skey.key)
+                        {
+                            for sid in skey.ids {
+                                if let Ok(reading) =
+                                    Self::stream_id_to_reading(&sid)
+                                {
+                                    pending.push_back((
+                                        names[idx].clone(),
+                                        reading,
+                                    ))
+                                }
+                                ids[idx] = sid.id
+                            }
+                        }
+                    }
+                }
+            },
+        )))
+    }
+
+    // Reads the history stream, a page at a time, using its own
+    // connection so other requests aren't held up by a long scan.
+
+    async fn aggregate_history(
+        &mut self,
+        name: device::Name,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: time::Duration,
+    ) -> Result<Summary> {
+        let mut agg = Aggregator::new(start, end, interval)?;
+        let mut con = Self::make_connection(&self.cfg, None, None).await?;
+        let name = name.to_string();
+        let mut from = ReadingStream::ts_to_id(start.into());
+        let to = ReadingStream::ts_to_id(end.into());
+
+        Ok(Box::pin(async move {
+            loop {
+                let page: StreamRangeReply =
+                    Self::history_range_cmd(&name, &from, &to)
+                        .query_async(&mut con)
+                        .await
+                        .map_err(xlat_err)?;
+
+                for sid in &page.ids {
+                    agg.add(&Self::stream_id_to_reading(sid)?)
+                }
+
+                match page.ids.last() {
+                    Some(sid) if page.ids.len() == Self::HISTORY_PAGE => {
+                        from = format!("({}", sid.id)
+                    }
+                    _ => break Ok(agg.finish()),
+                }
+            }
+        }))
+    }
 }
 
-pub async fn open(cfg: &config::Config) -> Result<impl Store> {
-    RedisStore::new(cfg, None, None)
+// Opens the back-end. Readings are read back from redis' streams, so
+// clients that fall behind don't miss any and only the size of the
+// settings channels is used from `caps`.
+
+pub async fn open(
+    cfg: &config::Config,
+    caps: Capacities,
+) -> Result<impl Store> {
+    let mut store = RedisStore::new(cfg, None, None)
         .instrument(
             info_span!("redis-db", addr=?cfg.get_addr(), db=cfg.get_dbn()),
         )
-        .await
+        .await?;
+
+    store.caps = caps;
+    Ok(store)
 }
 
 // This is the test module to make sure the redis backend works
@@ -1499,6 +1745,71 @@ $1\r\n1\r\n"
         );
     }
 
+    #[test]
+    fn test_read_many_cmd() {
+        let cmd = ReadingStream::read_many_cmd(
+            &["a#hist".into(), "b#hist".into()],
+            &["1-0".into(), "0-0".into()],
+        );
+
+        assert_eq!(
+            &cmd.get_packed_command(),
+            b"*10\r
+$5\r\nXREAD\r
+$5\r\nBLOCK\r
+$4\r\n5000\r
+$5\r\nCOUNT\r
+$2\r\n20\r
+$7\r\nSTREAMS\r
+$6\r\na#hist\r
+$6\r\nb#hist\r
+$3\r\n1-0\r
+$3\r\n0-0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_set_meta_cmd() {
+        let update = client::MetaUpdate {
+            summary: Some("Sump pump".into()),
+            location: Some("".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            &RedisStore::set_meta_cmd("device", &update).get_packed_pipeline(),
+            b"*1\r
+$5\r\nMULTI\r
+*4\r
+$4\r\nHSET\r
+$11\r\ndevice#info\r
+$7\r\nsummary\r
+$9\r\nSump pump\r
+*3\r
+$4\r\nHDEL\r
+$11\r\ndevice#info\r
+$8\r\nlocation\r
+*1\r
+$4\r\nEXEC\r\n"
+        );
+    }
+
+    #[test]
+    fn test_history_range_cmd() {
+        let cmd = RedisStore::history_range_cmd("device", "(1-0", "5-0");
+
+        assert_eq!(
+            &cmd.get_packed_command(),
+            b"*6\r
+$6\r\nXRANGE\r
+$11\r\ndevice#hist\r
+$4\r\n(1-0\r
+$3\r\n5-0\r
+$5\r\nCOUNT\r
+$4\r\n1000\r\n"
+        );
+    }
+
     #[test]
     fn test_parsing_last_value() {
         const NAME: &str = "device";
@@ -1688,6 +1999,25 @@ $10\r\nS\x00\x00\x00\x05hello\r\n"
         );
     }
 
+    #[test]
+    fn test_trim_cmd() {
+        let now = time::UNIX_EPOCH + time::Duration::from_millis(90_000);
+
+        assert_eq!(oldest_id(now, time::Duration::from_secs(60)), 30_000);
+        assert_eq!(oldest_id(now, time::Duration::from_secs(600)), 0);
+
+        assert_eq!(
+            &RedisStore::trim_old_values_cmd("key", 30_000)
+                .get_packed_command(),
+            b"*5\r
+$5\r\nXTRIM\r
+$3\r\nkey\r
+$5\r\nMINID\r
+$1\r\n~\r
+$5\r\n30000\r\n"
+        );
+    }
+
     #[test]
     fn test_init_dev() {
         assert_eq!(
@@ -1937,6 +2267,7 @@ $4\r\nEXEC\r\n"
                 total_points: 0,
                 first_point: None,
                 last_point: None,
+                meta: Default::default(),
             })
         );
 
@@ -1952,6 +2283,7 @@ $4\r\nEXEC\r\n"
                 total_points: 0,
                 first_point: None,
                 last_point: None,
+                meta: Default::default(),
             })
         );
 
@@ -1968,6 +2300,7 @@ $4\r\nEXEC\r\n"
                 total_points: 0,
                 first_point: None,
                 last_point: None,
+                meta: Default::default(),
             })
         );
     }
@@ -9,7 +9,14 @@
 //! historical information but, instead, are doing real-time control
 //! with current values.
 
-use crate::backends::Store;
+use crate::{
+    backends::{
+        channels::{self, Capacities},
+        Aggregator, Setting, Store, Summary,
+    },
+    config::Policy,
+    glob,
+};
 use async_trait::async_trait;
 use chrono::*;
 use drmem_api::{
@@ -29,7 +36,10 @@ use tokio_stream::{
 };
 use tracing::{error, warn};
 
-const CHAN_SIZE: usize = 20;
+// How often a driver, blocked by a slow client, checks whether the
+// client has caught up.
+
+const BLOCK_POLL: time::Duration = time::Duration::from_millis(10);
 
 type ReadingState = (
     broadcast::Sender<device::Reading>,
@@ -38,13 +48,15 @@ type ReadingState = (
 );
 
 pub mod config;
-mod glob;
 
 struct DeviceInfo {
     owner: driver::Name,
     units: Option<String>,
+    meta: client::DeviceMeta,
     tx_setting: Option<TxDeviceSetting>,
     reading: Arc<Mutex<ReadingState>>,
+    capacity: usize,
+    policy: Policy,
 }
 
 impl DeviceInfo {
@@ -52,24 +64,54 @@ impl DeviceInfo {
         owner: String,
         units: Option<&String>,
         tx_setting: Option<TxDeviceSetting>,
+        (capacity, policy): (usize, Policy),
     ) -> DeviceInfo {
-        let (tx, _) = broadcast::channel(CHAN_SIZE);
+        let (tx, _) = broadcast::channel(capacity);
 
         // Build the entry and insert it in the table.
 
         DeviceInfo {
             owner: owner.into(),
             units: units.cloned(),
+            meta: client::DeviceMeta::default(),
             tx_setting,
             reading: Arc::new(Mutex::new((tx, None, time::UNIX_EPOCH))),
+            capacity,
+            policy,
         }
     }
 }
 
-struct SimpleStore(HashMap<device::Name, DeviceInfo>);
+#[derive(Default)]
+struct SimpleStore(HashMap<device::Name, DeviceInfo>, Capacities);
+
+pub async fn open(
+    _cfg: &config::Config,
+    caps: Capacities,
+) -> Result<impl Store> {
+    Ok(SimpleStore(HashMap::new(), caps))
+}
 
-pub async fn open(_cfg: &config::Config) -> Result<impl Store> {
-    Ok(SimpleStore(HashMap::new()))
+// Waits until the slowest client of a device has room for another
+// reading and then sends it. Used by devices with the `block` policy.
+
+async fn send_blocked(
+    reading: Arc<Mutex<ReadingState>>,
+    capacity: usize,
+    value: device::Reading,
+) {
+    loop {
+        tokio::time::sleep(BLOCK_POLL).await;
+
+        match reading.lock() {
+            Ok(data) if data.0.len() < capacity => {
+                let _ = data.0.send(value);
+                break;
+            }
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
 }
 
 // Builds the `ReportReading` function. Drivers will call specialized
@@ -78,13 +120,16 @@ pub async fn open(_cfg: &config::Config) -> Result<impl Store> {
 fn mk_report_func(di: &DeviceInfo, name: &device::Name) -> ReportReading {
     let reading = di.reading.clone();
     let name = name.to_string();
+    let capacity = di.capacity;
+    let policy = di.policy;
 
     Box::new(move |v| {
         // Determine the timestamp *before* we take the mutex. The
         // timing shouldn't pay the price of waiting for the mutex so
-        // we grab it right away.
+        // we grab it right away. The DrMem clock is used so a step
+        // of the system clock doesn't make timestamps go backwards.
 
-        let mut ts = time::SystemTime::now();
+        let mut ts = crate::clock::now();
 
         // If a lock is obtained, update the current value. The only
         // way a lock can fail is if it's "poisoned", which means
@@ -118,13 +163,21 @@ fn mk_report_func(di: &DeviceInfo, name: &device::Name) -> ReportReading {
                 }
             }
 
-            let reading = device::Reading { ts, value: v };
-            let _ = data.0.send(reading.clone());
+            let rdg = device::Reading { ts, value: v };
 
             // Update the device's state.
 
-            data.1 = Some(reading);
-            data.2 = ts
+            data.1 = Some(rdg.clone());
+            data.2 = ts;
+
+            // With the `block` policy, a full channel makes the
+            // driver wait for the slowest client. Otherwise the
+            // oldest reading is pushed out.
+
+            if policy == Policy::Block && data.0.len() >= capacity {
+                return Box::pin(send_blocked(reading.clone(), capacity, rdg));
+            }
+            let _ = data.0.send(rdg);
         } else {
             error!("couldn't set current value of {}", &name)
         }
@@ -145,6 +198,7 @@ impl Store for SimpleStore {
         name: &device::Name,
         units: Option<&String>,
         _max_history: Option<usize>,
+        _max_age: Option<time::Duration>,
     ) -> Result<ReportReading> {
         // Check to see if the device name already exists.
 
@@ -158,6 +212,7 @@ impl Store for SimpleStore {
                     String::from(driver),
                     units,
                     None,
+                    self.1.readings(name),
                 ));
 
                 // Create and return the closure that the driver will
@@ -193,6 +248,7 @@ impl Store for SimpleStore {
         name: &device::Name,
         units: Option<&String>,
         _max_history: Option<usize>,
+        _max_age: Option<time::Duration>,
     ) -> Result<(ReportReading, RxDeviceSetting, Option<device::Value>)> {
         // Check to see if the device name already exists.
 
@@ -202,7 +258,7 @@ impl Store for SimpleStore {
             hash_map::Entry::Vacant(e) => {
                 // Create a channel with which to send settings.
 
-                let (tx_sets, rx_sets) = mpsc::channel(CHAN_SIZE);
+                let (tx_sets, rx_sets) = mpsc::channel(self.1.settings());
 
                 // Build the entry and insert it in the table.
 
@@ -210,6 +266,7 @@ impl Store for SimpleStore {
                     String::from(driver),
                     units,
                     Some(tx_sets),
+                    self.1.readings(name),
                 ));
 
                 // Create and return the closure that the driver will
@@ -227,7 +284,7 @@ impl Store for SimpleStore {
                 if dev_info.owner.as_ref() == driver {
                     // Create a channel with which to send settings.
 
-                    let (tx_sets, rx_sets) = mpsc::channel(CHAN_SIZE);
+                    let (tx_sets, rx_sets) = mpsc::channel(self.1.settings());
 
                     dev_info.tx_setting = Some(tx_sets);
 
@@ -291,6 +348,7 @@ impl Store for SimpleStore {
                     total_points: tot,
                     first_point: rdg.clone(),
                     last_point: rdg,
+                    meta: v.meta.clone(),
                 }
             })
             .collect();
@@ -298,26 +356,53 @@ impl Store for SimpleStore {
         Ok(res)
     }
 
+    async fn set_device_meta(
+        &mut self,
+        name: &device::Name,
+        update: client::MetaUpdate,
+    ) -> Result<()> {
+        // Replaces a field with its new value. Empty strings clear
+        // the field.
+
+        fn apply(field: &mut Option<String>, value: Option<String>) {
+            if let Some(value) = value {
+                *field = Some(value).filter(|v| !v.is_empty())
+            }
+        }
+
+        let di = self.0.get_mut(name).ok_or(Error::NotFound)?;
+
+        apply(&mut di.units, update.units);
+        apply(&mut di.meta.summary, update.summary);
+        apply(&mut di.meta.detail, update.detail);
+        apply(&mut di.meta.location, update.location);
+        Ok(())
+    }
+
     async fn set_device(
         &self,
         name: device::Name,
         value: device::Value,
-    ) -> Result<device::Value> {
+    ) -> Result<Setting> {
         if let Some(di) = self.0.get(&name) {
             if let Some(tx) = &di.tx_setting {
-                let (tx_rpy, rx_rpy) = oneshot::channel();
+                let tx = tx.clone();
+
+                Ok(Box::pin(async move {
+                    let (tx_rpy, rx_rpy) = oneshot::channel();
 
-                match tx.send((value, tx_rpy)).await {
-                    Ok(()) => match rx_rpy.await {
-                        Ok(reply) => reply,
+                    match tx.send((value, tx_rpy)).await {
+                        Ok(()) => match rx_rpy.await {
+                            Ok(reply) => reply,
+                            Err(_) => Err(Error::MissingPeer(
+                                "driver broke connection".to_string(),
+                            )),
+                        },
                         Err(_) => Err(Error::MissingPeer(
-                            "driver broke connection".to_string(),
+                            "driver is ignoring settings".to_string(),
                         )),
-                    },
-                    Err(_) => Err(Error::MissingPeer(
-                        "driver is ignoring settings".to_string(),
-                    )),
-                }
+                    }
+                }))
             } else {
                 Err(Error::OperationError(format!("{} is read-only", &name)))
             }
@@ -373,6 +458,7 @@ impl Store for SimpleStore {
                             Ok(v) => Some(v),
                             Err(BroadcastStreamRecvError::Lagged(count)) => {
                                 warn!("missed {} readings of {}", count, &name);
+                                channels::dropped(count);
                                 None
                             }
                         }
@@ -449,14 +535,42 @@ impl Store for SimpleStore {
             Err(Error::NotFound)
         }
     }
+
+    // This back-end only keeps the last reading of a device, so the
+    // summary holds, at most, one bucket.
+
+    async fn aggregate_history(
+        &mut self,
+        name: device::Name,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: time::Duration,
+    ) -> Result<Summary> {
+        let mut agg = Aggregator::new(start, end, interval)?;
+
+        if let Some(di) = self.0.get(&name) {
+            if let Ok(guard) = di.reading.lock() {
+                if let Some(reading) = &guard.1 {
+                    agg.add(reading)
+                }
+                Ok(Box::pin(async move { Ok(agg.finish()) }))
+            } else {
+                Err(Error::OperationError(
+                    "unable to lock reading channel".to_owned(),
+                ))
+            }
+        } else {
+            Err(Error::NotFound)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{mk_report_func, DeviceInfo, SimpleStore};
+    use super::{mk_report_func, DeviceInfo, Policy, SimpleStore};
     use crate::backends::Store;
     use drmem_api::device;
-    use std::{collections::HashMap, time};
+    use std::time;
     use tokio::sync::{mpsc::error::TryRecvError, oneshot};
     use tokio::time::interval;
     use tokio_stream::StreamExt;
@@ -468,13 +582,146 @@ mod tests {
             .is_some())
     }
 
+    #[tokio::test]
+    async fn test_set_device_meta() {
+        use drmem_api::client;
+
+        let mut db = SimpleStore::default();
+        let name = "test:device".parse::<device::Name>().unwrap();
+        let units = String::from("V");
+
+        let _ = db
+            .register_read_only_device("test", &name, Some(&units), None, None)
+            .await
+            .unwrap();
+
+        db.set_device_meta(
+            &name,
+            client::MetaUpdate {
+                summary: Some("Battery voltage".into()),
+                location: Some("garage".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        // Empty strings clear a field and missing ones are left
+        // alone.
+
+        db.set_device_meta(
+            &name,
+            client::MetaUpdate {
+                units: Some("".into()),
+                detail: Some("12V lead-acid".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let info = db.get_device_info(Some("test:device")).await.unwrap();
+
+        assert_eq!(info[0].units, None);
+        assert_eq!(
+            info[0].meta,
+            client::DeviceMeta {
+                summary: Some("Battery voltage".into()),
+                detail: Some("12V lead-acid".into()),
+                location: Some("garage".into()),
+            }
+        );
+
+        // The information is kept when the driver registers the
+        // device again.
+
+        let _ = db
+            .register_read_only_device("test", &name, Some(&units), None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get_device_info(Some("test:device")).await.unwrap()[0].meta,
+            info[0].meta
+        );
+
+        assert!(db
+            .set_device_meta(
+                &"test:other".parse().unwrap(),
+                client::MetaUpdate::default()
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_monitor_devices() {
+        let mut db = SimpleStore::default();
+        let a = "test:a".parse::<device::Name>().unwrap();
+        let b = "test:b".parse::<device::Name>().unwrap();
+        let fa = db
+            .register_read_only_device("test", &a, None, None, None)
+            .await
+            .unwrap();
+        let fb = db
+            .register_read_only_device("test", &b, None, None, None)
+            .await
+            .unwrap();
+
+        fa(device::Value::Int(1)).await;
+
+        let s = db
+            .monitor_devices(vec![a.clone(), b.clone()])
+            .await
+            .unwrap()
+            .timeout(time::Duration::from_millis(100));
+
+        tokio::pin!(s);
+
+        // The stream starts with the last value of each device and
+        // tags each reading with its device.
+
+        let (name, reading) = s.try_next().await.unwrap().unwrap();
+
+        assert_eq!((name, reading.value), (a.clone(), device::Value::Int(1)));
+
+        // Readings of different devices aren't ordered.
+
+        fb(device::Value::Int(2)).await;
+        fa(device::Value::Int(3)).await;
+
+        let mut got = vec![];
+
+        for _ in 0..2 {
+            let (name, reading) = s.try_next().await.unwrap().unwrap();
+
+            got.push((name.to_string(), reading.value))
+        }
+        got.sort_by_key(|(name, _)| name.clone());
+
+        assert_eq!(
+            got,
+            [
+                (a.to_string(), device::Value::Int(3)),
+                (b.to_string(), device::Value::Int(2))
+            ]
+        );
+        assert!(s.try_next().await.is_err());
+
+        // Unknown devices are an error.
+
+        assert!(db
+            .monitor_devices(vec!["test:c".parse().unwrap()])
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_read_live_stream() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "test:device".parse::<device::Name>().unwrap();
 
         if let Ok(f) = db
-            .register_read_only_device("test", &name, None, None)
+            .register_read_only_device("test", &name, None, None, None)
             .await
         {
             // Test that priming the history with one value returns
@@ -554,11 +801,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_start_stream() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "test:device".parse::<device::Name>().unwrap();
 
         if let Ok(f) = db
-            .register_read_only_device("test", &name, None, None)
+            .register_read_only_device("test", &name, None, None, None)
             .await
         {
             // Verify that monitoring device, starting now, picks up
@@ -641,11 +888,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_end_stream() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "test:device".parse::<device::Name>().unwrap();
 
         if let Ok(f) = db
-            .register_read_only_device("test", &name, None, None)
+            .register_read_only_device("test", &name, None, None, None)
             .await
         {
             // Verify that, if the latest point is before the starting
@@ -685,11 +932,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_read_start_end_stream() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "test:device".parse::<device::Name>().unwrap();
 
         if let Ok(f) = db
-            .register_read_only_device("test", &name, None, None)
+            .register_read_only_device("test", &name, None, None, None)
             .await
         {
             // Verify that, if both times are before the data, nothing
@@ -871,14 +1118,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_ro_registration() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "misc:junk".parse::<device::Name>().unwrap();
 
         // Register a device named "junk" and associate it with the
         // driver named "test". We don't define units for this device.
 
         if let Ok(f) = db
-            .register_read_only_device("test", &name, None, None)
+            .register_read_only_device("test", &name, None, None, None)
             .await
         {
             // Make sure the device was defined and the setting
@@ -905,7 +1152,7 @@ mod tests {
             // driver name results in an error.
 
             assert!(db
-                .register_read_only_device("test2", &name, None, None)
+                .register_read_only_device("test2", &name, None, None, None)
                 .await
                 .is_err());
 
@@ -913,7 +1160,7 @@ mod tests {
             // driver name is successful.
 
             if let Ok(f) = db
-                .register_read_only_device("test", &name, None, None)
+                .register_read_only_device("test", &name, None, None, None)
                 .await
             {
                 // Also, verify that the device update channel wasn't
@@ -932,14 +1179,14 @@ mod tests {
 
     #[tokio::test]
     async fn test_rw_registration() {
-        let mut db = SimpleStore(HashMap::new());
+        let mut db = SimpleStore::default();
         let name = "misc:junk".parse::<device::Name>().unwrap();
 
         // Register a device named "junk" and associate it with the
         // driver named "test". We don't define units for this device.
 
         if let Ok((f, mut set_chan, None)) = db
-            .register_read_write_device("test", &name, None, None)
+            .register_read_write_device("test", &name, None, None, None)
             .await
         {
             // Make sure the device was defined and a setting channel
@@ -987,7 +1234,7 @@ mod tests {
             // didn't affect the setting channel.
 
             assert!(db
-                .register_read_only_device("test2", &name, None, None)
+                .register_read_only_device("test2", &name, None, None, None)
                 .await
                 .is_err());
             assert_eq!(
@@ -999,7 +1246,7 @@ mod tests {
             // driver name is successful.
 
             if let Ok((f, _, Some(device::Value::Int(1)))) = db
-                .register_read_write_device("test", &name, None, None)
+                .register_read_write_device("test", &name, None, None, None)
                 .await
             {
                 assert_eq!(
@@ -1023,7 +1270,12 @@ mod tests {
 
     #[tokio::test]
     async fn test_closure() {
-        let di = DeviceInfo::create(String::from("test"), None, None);
+        let di = DeviceInfo::create(
+            String::from("test"),
+            None,
+            None,
+            (20, Policy::DropOldest),
+        );
         let name = "misc:junk".parse::<device::Name>().unwrap();
         let f = mk_report_func(&di, &name);
 
@@ -1066,4 +1318,55 @@ mod tests {
             );
         }
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_policy() {
+        let name = "misc:junk".parse::<device::Name>().unwrap();
+
+        // A slow client makes the oldest readings get dropped.
+
+        let di = DeviceInfo::create(
+            String::from("test"),
+            None,
+            None,
+            (2, Policy::DropOldest),
+        );
+        let f = mk_report_func(&di, &name);
+        let mut rx = di.reading.lock().unwrap().0.subscribe();
+
+        for ii in 1..=3 {
+            f(device::Value::Int(ii)).await
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(rx.try_recv().unwrap().value, device::Value::Int(2));
+
+        // With the `block` policy, the driver waits until the client
+        // has room.
+
+        let di = DeviceInfo::create(
+            String::from("test"),
+            None,
+            None,
+            (2, Policy::Block),
+        );
+        let f = mk_report_func(&di, &name);
+        let mut rx = di.reading.lock().unwrap().0.subscribe();
+
+        f(device::Value::Int(1)).await;
+        f(device::Value::Int(2)).await;
+
+        let blocked = tokio::spawn(f(device::Value::Int(3)));
+
+        tokio::time::sleep(super::BLOCK_POLL * 3).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(
+            di.reading.lock().unwrap().1.as_ref().unwrap().value,
+            device::Value::Int(3)
+        );
+
+        assert_eq!(rx.try_recv().unwrap().value, device::Value::Int(1));
+        blocked.await.unwrap();
+        assert_eq!(rx.try_recv().unwrap().value, device::Value::Int(2));
+        assert_eq!(rx.try_recv().unwrap().value, device::Value::Int(3));
+    }
 }
@@ -0,0 +1,156 @@
+// Provides the wall-clock time used to stamp readings. The system
+// clock can be stepped, by NTP or by hand, which would make
+// timestamps go backwards or let scheduled times happen twice. This
+// clock follows the monotonic clock from the last time it returned,
+// so its times never decrease:
+//
+// - If the system clock moves backwards by up to `STEP_LIMIT`, the
+//   clock runs at half speed until the system clock catches up.
+//
+// - Larger corrections, either way, are steps. They're accepted, so
+//   the clock doesn't drift from the system clock for long, and
+//   they're logged and published as a `ClockStepped` event.
+
+use crate::events;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+
+const STEP_LIMIT: Duration = Duration::from_secs(60);
+
+const TICK: Duration = Duration::from_micros(1);
+
+// Describes a correction larger than `STEP_LIMIT`.
+
+#[derive(Debug, PartialEq)]
+enum Step {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+#[derive(Default)]
+struct Clock {
+    // The last time returned and the monotonic time it was returned.
+    last: Option<(SystemTime, Instant)>,
+}
+
+impl Clock {
+    // Returns the time to use, given the system clock and the
+    // monotonic clock, and whether the system clock was stepped.
+
+    fn next(
+        &mut self,
+        sys: SystemTime,
+        mono: Instant,
+    ) -> (SystemTime, Option<Step>) {
+        let Some((last, last_mono)) = self.last else {
+            self.last = Some((sys, mono));
+            return (sys, None);
+        };
+        let elapsed = mono.saturating_duration_since(last_mono);
+        let expected = last + elapsed;
+
+        let (now, step) = match sys.duration_since(expected) {
+            Ok(ahead) if ahead > STEP_LIMIT => {
+                (sys, Some(Step::Forward(ahead)))
+            }
+            _ => match last.duration_since(sys) {
+                Ok(behind) if behind > STEP_LIMIT => {
+                    (sys, Some(Step::Backward(behind)))
+                }
+
+                // The system clock is behind the last time returned,
+                // so advance at half speed until it catches up.
+                Ok(_) => (last + (elapsed / 2).max(TICK), None),
+                Err(_) => (sys, None),
+            },
+        };
+
+        self.last = Some((now, mono));
+        (now, step)
+    }
+}
+
+static CLOCK: Mutex<Clock> = Mutex::new(Clock { last: None });
+
+// Returns the current time. Successive calls never return a time
+// earlier than a previous one, unless the system clock was stepped
+// back by more than `STEP_LIMIT`.
+
+pub fn now() -> SystemTime {
+    let sys = SystemTime::now();
+    let mono = Instant::now();
+    let (now, step) = match CLOCK.lock() {
+        Ok(mut clock) => clock.next(sys, mono),
+        Err(_) => return sys,
+    };
+
+    if let Some(step) = step {
+        let message = match step {
+            Step::Forward(v) => {
+                format!(
+                    "system clock jumped ahead {:.3} seconds",
+                    v.as_secs_f64()
+                )
+            }
+            Step::Backward(v) => {
+                format!(
+                    "system clock jumped back {:.3} seconds",
+                    v.as_secs_f64()
+                )
+            }
+        };
+
+        warn!("{}", &message);
+        events::publish(events::Kind::ClockStepped, "drmemd", message)
+    }
+    now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let mut clock = Clock::default();
+        let sys = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mono = Instant::now();
+        let secs = Duration::from_secs;
+
+        assert_eq!(clock.next(sys, mono), (sys, None));
+        assert_eq!(
+            clock.next(sys + secs(1), mono + secs(1)),
+            (sys + secs(1), None)
+        );
+
+        // A small step back makes the clock run at half speed until
+        // the system clock catches up.
+
+        assert_eq!(
+            clock.next(sys - secs(3), mono + secs(2)),
+            (sys + Duration::from_millis(1500), None)
+        );
+        assert_eq!(
+            clock.next(sys - secs(3), mono + secs(2)),
+            (sys + Duration::from_micros(1_500_001), None)
+        );
+        assert_eq!(
+            clock.next(sys + secs(3), mono + secs(8)),
+            (sys + secs(3), None)
+        );
+
+        // Large corrections are accepted and reported.
+
+        assert_eq!(
+            clock.next(sys - secs(3597), mono + secs(9)),
+            (sys - secs(3597), Some(Step::Backward(secs(3600))))
+        );
+        assert_eq!(
+            clock.next(sys + secs(4), mono + secs(10)),
+            (sys + secs(4), Some(Step::Forward(secs(3600))))
+        );
+    }
+}
@@ -3,6 +3,7 @@ use drmem_api::{Error, Result};
 use serde_derive::Deserialize;
 use std::collections::HashMap;
 use std::env;
+use std::path::{Path, PathBuf};
 use toml::{self, value};
 use tracing::Level;
 
@@ -12,30 +13,104 @@ fn def_log_level() -> String {
     String::from("warn")
 }
 
+fn def_setting_timeout() -> f64 {
+    10.0
+}
+
+// Converts the name of a log level. Only the levels that can be
+// given on the command line are accepted.
+
+pub fn parse_level(v: &str) -> Option<Level> {
+    match v {
+        "warn" => Some(Level::WARN),
+        "info" => Some(Level::INFO),
+        "debug" => Some(Level::DEBUG),
+        "trace" => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(default = "def_log_level")]
     log_level: String,
+    #[serde(default)]
+    pub log: Log,
     pub latitude: f64,
     pub longitude: f64,
+    pub timezone: Option<String>,
+    #[serde(default = "def_setting_timeout")]
+    pub setting_timeout: f64,
+    // The history kept for devices that don't set their own, in
+    // readings and seconds.
+    pub max_history: Option<usize>,
+    pub max_age: Option<f64>,
+    #[serde(default)]
+    pub holidays: Vec<crate::logic::tod::Holiday>,
     #[cfg(feature = "graphql")]
     #[serde(default)]
     pub graphql: super::graphql::config::Config,
+    #[cfg(feature = "grpc")]
+    #[serde(default)]
+    pub grpc: super::grpc::config::Config,
+    #[cfg(feature = "mqtt")]
+    #[serde(default)]
+    pub mqtt: super::mqtt::config::Config,
+    #[cfg(feature = "webhooks")]
+    #[serde(default)]
+    pub webhook: Vec<super::webhooks::config::Config>,
+    #[cfg(feature = "homekit")]
+    pub homekit: Option<super::homekit::config::Config>,
+    #[cfg(feature = "federation")]
+    pub federation: Option<super::federation::config::Config>,
     pub backend: Option<store::config::Config>,
     #[serde(default)]
     pub driver: Vec<Driver>,
     #[serde(default)]
+    pub device: Vec<Device>,
+    #[serde(default)]
+    pub channels: Channels,
+    #[serde(default)]
+    pub clients: Clients,
+    #[serde(default)]
     pub logic: Vec<Logic>,
+    #[serde(default)]
+    pub startup: Settings,
+    #[serde(default)]
+    pub shutdown: Settings,
+    #[serde(default)]
+    pub secrets: Secrets,
+    #[cfg(feature = "plugins")]
+    pub plugin_dir: Option<PathBuf>,
+    // Set by `--simulate`. The drivers that use hardware are
+    // simulated.
+    #[serde(skip)]
+    pub simulate: bool,
 }
 
 impl<'a> Config {
     pub fn get_log_level(&self) -> Level {
-        match self.log_level.as_str() {
-            "info" => Level::INFO,
-            "debug" => Level::DEBUG,
-            "trace" => Level::TRACE,
-            _ => Level::WARN,
-        }
+        parse_level(&self.log_level).unwrap_or(Level::WARN)
+    }
+
+    // Returns the IANA time zone used for local time, if one was
+    // configured.
+
+    pub fn get_timezone(&self) -> Option<chrono_tz::Tz> {
+        self.timezone.as_deref().and_then(|v| v.parse().ok())
+    }
+
+    // Returns how long a setting waits for the driver to reply.
+
+    pub fn get_setting_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.setting_timeout)
+    }
+
+    // Returns how long readings are kept, if the configuration
+    // limits it.
+
+    pub fn get_max_age(&self) -> Option<std::time::Duration> {
+        self.max_age.map(std::time::Duration::from_secs_f64)
     }
 
     pub fn get_backend(&'a self) -> &'a store::config::Config {
@@ -57,26 +132,221 @@ impl Default for Config {
     fn default() -> Self {
         Config {
             log_level: String::from("warn"),
+            log: Log::default(),
             latitude: 0.0,
             longitude: 0.0,
+            timezone: None,
+            setting_timeout: def_setting_timeout(),
+            max_history: None,
+            max_age: None,
+            holidays: vec![],
             #[cfg(feature = "graphql")]
             graphql: super::graphql::config::Config::default(),
+            #[cfg(feature = "grpc")]
+            grpc: super::grpc::config::Config::default(),
+            #[cfg(feature = "mqtt")]
+            mqtt: super::mqtt::config::Config::default(),
+            #[cfg(feature = "webhooks")]
+            webhook: vec![],
+            #[cfg(feature = "homekit")]
+            homekit: None,
+            #[cfg(feature = "federation")]
+            federation: None,
             backend: Some(store::config::Config::new()),
             driver: vec![],
+            device: vec![],
+            channels: Channels::default(),
+            clients: Clients::default(),
             logic: vec![],
+            startup: Settings::default(),
+            shutdown: Settings::default(),
+            secrets: Secrets::default(),
+            #[cfg(feature = "plugins")]
+            plugin_dir: None,
+            simulate: false,
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct Driver {
     pub name: String,
     pub prefix: device::Path,
     pub max_history: Option<usize>,
+    pub max_age: Option<f64>,
+    pub log_level: Option<String>,
     pub cfg: Option<DriverConfig>,
+    // The starting values of the devices when the instance is
+    // simulated.
+    pub simulate: Option<DriverConfig>,
+    // The parameters as written in the file, before references to
+    // secrets were expanded. `--print-config` shows these.
+    #[serde(skip)]
+    pub raw_cfg: Option<DriverConfig>,
+}
+
+impl Driver {
+    // Returns `true` if an instance started with `other` can keep
+    // running with this configuration. Only the log level may differ.
+
+    pub fn same_instance(&self, other: &Driver) -> bool {
+        self.name == other.name
+            && self.prefix == other.prefix
+            && self.max_history == other.max_history
+            && self.max_age == other.max_age
+            && self.cfg == other.cfg
+            && self.simulate == other.simulate
+    }
+
+    pub fn get_max_age(&self) -> Option<std::time::Duration> {
+        self.max_age.map(std::time::Duration::from_secs_f64)
+    }
 }
 
+// Limits how often the devices whose names match `pattern` have
+// their readings saved. `min_report_interval` is in seconds.
+// `readings` and `policy` override the `[channels]` section for the
+// devices. `max_history` and `max_age` override the history kept by
+// their driver instance, or the top-level defaults.
+
 #[derive(Deserialize)]
+pub struct Device {
+    pub pattern: String,
+    pub min_report_interval: Option<f64>,
+    pub deadband: Option<f64>,
+    pub readings: Option<usize>,
+    pub policy: Option<Policy>,
+    pub max_history: Option<usize>,
+    pub max_age: Option<f64>,
+}
+
+impl Device {
+    fn validate(&self) -> Result<()> {
+        for (key, v) in [
+            ("min_report_interval", self.min_report_interval),
+            ("deadband", self.deadband),
+        ] {
+            if v.is_some_and(|v| !(v.is_finite() && v >= 0.0)) {
+                return Err(Error::ConfigError(format!(
+                    "device '{}' has a bad value for '{}'",
+                    &self.pattern, key
+                )));
+            }
+        }
+
+        if self.readings.is_some_and(|v| !valid_capacity(v)) {
+            return Err(Error::ConfigError(format!(
+                "device '{}' has a bad value for 'readings'",
+                &self.pattern
+            )));
+        }
+
+        if !valid_max_age(self.max_age) {
+            return Err(Error::ConfigError(format!(
+                "device '{}' has a bad value for 'max_age'",
+                &self.pattern
+            )));
+        }
+        Ok(())
+    }
+}
+
+// An age limit has to leave some history to keep.
+
+fn valid_max_age(v: Option<f64>) -> bool {
+    v.is_none_or(|v| v.is_finite() && v > 0.0)
+}
+
+// Says what happens to a device's readings when a client can't keep
+// up with them. With `drop-oldest`, the oldest unread readings are
+// discarded and counted. With `block`, the driver waits until the
+// slowest client has room.
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    #[default]
+    DropOldest,
+    Block,
+}
+
+// Sizes the channels that connect the tasks of `drmemd`:
+//
+//     readings  readings of a device that a client can fall behind
+//     settings  settings of a device waiting for its driver
+//     requests  requests waiting for the core, logic manager, and
+//               driver instances
+//     events    system events a subscriber can fall behind
+
+fn def_readings() -> usize {
+    20
+}
+
+fn def_settings() -> usize {
+    20
+}
+
+fn def_requests() -> usize {
+    10
+}
+
+fn def_events() -> usize {
+    100
+}
+
+// Channels can't be empty and tokio limits broadcast channels to
+// half the address space.
+
+fn valid_capacity(v: usize) -> bool {
+    v > 0 && v <= usize::MAX / 2
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Channels {
+    #[serde(default = "def_readings")]
+    pub readings: usize,
+    #[serde(default = "def_settings")]
+    pub settings: usize,
+    #[serde(default = "def_requests")]
+    pub requests: usize,
+    #[serde(default = "def_events")]
+    pub events: usize,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Channels {
+            readings: def_readings(),
+            settings: def_settings(),
+            requests: def_requests(),
+            events: def_events(),
+            policy: Policy::default(),
+        }
+    }
+}
+
+impl Channels {
+    fn validate(&self) -> Result<()> {
+        for (key, v) in [
+            ("readings", self.readings),
+            ("settings", self.settings),
+            ("requests", self.requests),
+            ("events", self.events),
+        ] {
+            if !valid_capacity(v) {
+                return Err(Error::ConfigError(format!(
+                    "channels '{}' must be positive",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
 pub struct Logic {
     pub name: String,
     pub summary: Option<String>,
@@ -85,7 +355,298 @@ pub struct Logic {
     pub exprs: Vec<String>,
     #[serde(default)]
     pub inputs: HashMap<String, device::Name>,
+    #[serde(default)]
+    pub init: HashMap<String, toml::value::Value>,
+    #[serde(default)]
+    pub units: HashMap<String, String>,
+    #[serde(default)]
+    pub timeout: HashMap<String, f64>,
+    #[serde(default)]
+    pub fallback: HashMap<String, toml::value::Value>,
     pub outputs: HashMap<String, device::Name>,
+    #[serde(default)]
+    pub deadband: HashMap<String, f64>,
+    #[serde(default)]
+    pub min_interval: HashMap<String, f64>,
+    pub state: Option<device::Name>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub dry_run: bool,
+    pub prefix: Option<device::Path>,
+    pub machine: Option<Machine>,
+    #[serde(default)]
+    pub alarms: Vec<Alarm>,
+}
+
+// Picks the devices that the client APIs (GraphQL, REST, gRPC, MQTT,
+// webhooks, and HomeKit) can use, whoever the client is. A device is
+// visible if it matches a `visible` pattern and no `hidden` pattern.
+// Visible devices matching a `read_only` pattern can't be set. Logic
+// blocks aren't affected.
+
+fn def_visible() -> Vec<String> {
+    vec![String::from("*")]
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Clients {
+    #[serde(default = "def_visible")]
+    pub visible: Vec<String>,
+    #[serde(default)]
+    pub hidden: Vec<String>,
+    #[serde(default)]
+    pub read_only: Vec<String>,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Clients {
+            visible: def_visible(),
+            hidden: vec![],
+            read_only: vec![],
+        }
+    }
+}
+
+// Describes the settings `drmemd` makes when it starts (`[startup]`)
+// and when it's asked to stop (`[shutdown]`.) `timeout` is how many
+// seconds to wait for each setting. When stopping, it's also how
+// long the drivers get to shut down.
+
+fn def_settings_timeout() -> f64 {
+    10.0
+}
+
+#[derive(Deserialize)]
+pub struct Settings {
+    #[serde(default = "def_settings_timeout")]
+    pub timeout: f64,
+    #[serde(default)]
+    pub setting: Vec<Setting>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            timeout: def_settings_timeout(),
+            setting: vec![],
+        }
+    }
+}
+
+impl Settings {
+    pub fn get_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.timeout)
+    }
+
+    // Checks the section. `section` names it in error messages.
+
+    fn validate(&self, section: &str) -> Result<()> {
+        if !(self.timeout.is_finite() && self.timeout > 0.0) {
+            return Err(Error::ConfigError(format!(
+                "{} 'timeout' must be positive",
+                section
+            )));
+        }
+
+        for s in &self.setting {
+            if device::Value::try_from(&s.value).is_err() {
+                return Err(Error::ConfigError(format!(
+                    "{} setting of '{}' has an unsupported value",
+                    section, &s.device
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Says where the log is written. Without `file` or `syslog`, it goes
+// to stdout. The file is rotated when it grows past `max_size` MiB,
+// or at the start of each hour or day, and the `keep` newest rotated
+// files are kept. `modules` overrides the log level for the messages
+// of a module (e.g. `drmem_db_redis = "debug"`.)
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotate {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+fn def_log_keep() -> usize {
+    5
+}
+
+#[derive(Deserialize)]
+pub struct Log {
+    pub file: Option<PathBuf>,
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub rotate: Rotate,
+    #[serde(default = "def_log_keep")]
+    pub keep: usize,
+    pub syslog: Option<Syslog>,
+    #[serde(default)]
+    pub modules: HashMap<String, String>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            file: None,
+            max_size: None,
+            rotate: Rotate::default(),
+            keep: def_log_keep(),
+            syslog: None,
+            modules: HashMap::new(),
+        }
+    }
+}
+
+impl Log {
+    fn validate(&self) -> Result<()> {
+        if self.max_size == Some(0) {
+            return Err(Error::ConfigError(
+                "log 'max_size' must be positive".into(),
+            ));
+        }
+
+        if let Some(syslog) = &self.syslog {
+            syslog.validate()?
+        }
+
+        for (module, level) in &self.modules {
+            if parse_level(level).is_none() {
+                return Err(Error::ConfigError(format!(
+                    "log level '{}' of module '{}' isn't valid",
+                    level, module
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sends the log to a syslog server. Without an `address`, the local
+// server is used. Remote servers are given as "udp://host:port" or
+// "tcp://host:port".
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    #[default]
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Syslog {
+    pub address: Option<String>,
+    #[serde(default)]
+    pub facility: Facility,
+}
+
+impl Syslog {
+    fn validate(&self) -> Result<()> {
+        match self.address.as_deref() {
+            None => Ok(()),
+            Some(addr)
+                if addr.starts_with("udp://") || addr.starts_with("tcp://") =>
+            {
+                Ok(())
+            }
+            Some(addr) => Err(Error::ConfigError(format!(
+                "syslog address '{}' must start with 'udp://' or 'tcp://'",
+                addr
+            ))),
+        }
+    }
+}
+
+// A setting applied when `drmemd` starts or stops.
+
+#[derive(Deserialize)]
+pub struct Setting {
+    pub device: device::Name,
+    pub value: toml::value::Value,
+}
+
+// Says where the values of secrets, which driver parameters refer to
+// as "@name", are found. `keyring` is the service name the secrets
+// are stored under in the OS keyring.
+
+#[derive(Clone, Default, Deserialize)]
+pub struct Secrets {
+    pub file: Option<PathBuf>,
+    pub keyring: Option<String>,
+}
+
+// Describes the state machine of a logic block.
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct Machine {
+    pub initial: String,
+    pub states: HashMap<String, MachineState>,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct MachineState {
+    #[serde(default)]
+    pub outputs: HashMap<String, toml::value::Value>,
+    #[serde(default)]
+    pub transitions: Vec<Transition>,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct Transition {
+    pub when: String,
+    pub to: String,
+}
+
+// Describes an alarm of a logic block.
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    #[default]
+    Warning,
+    Critical,
+}
+
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct Alarm {
+    pub name: String,
+    pub when: String,
+    pub sink: String,
+    pub message: String,
+    #[serde(default)]
+    pub severity: Severity,
+    pub delay: Option<f64>,
+    pub repeat: Option<f64>,
+    pub ack: Option<String>,
 }
 
 fn from_cmdline(mut cfg: Config) -> (bool, Config) {
@@ -117,6 +678,12 @@ fn from_cmdline(mut cfg: Config) -> (bool, Config) {
                 .action(ArgAction::SetTrue)
                 .help("Displays the configuration and exits"),
         )
+        .arg(
+            Arg::new("simulate")
+                .long("simulate")
+                .action(ArgAction::SetTrue)
+                .help("Simulates the drivers that use hardware"),
+        )
         .get_matches();
 
     // The number of '-v' options determines the log level.
@@ -128,95 +695,587 @@ fn from_cmdline(mut cfg: Config) -> (bool, Config) {
         _ => cfg.log_level = String::from("trace"),
     };
 
+    cfg.simulate = matches.get_flag("simulate");
+
     // Return the config built from the command line and a flag
     // indicating the user wants the final configuration displayed.
 
     (matches.get_flag("print_cfg"), cfg)
 }
 
-fn parse_config(contents: &str) -> Result<Config> {
-    toml::from_str(contents)
-        .map_err(|e| Error::ConfigError(format!("{}", e)))
-        .and_then(|cfg: Config| {
-            // Make sure latitude is between -90 and 90 degrees.
+// Expands the references in a value holding a secret so it doesn't
+// have to be written in the file. `${NAME}` is replaced
+// by the value of the environment variable and `$${` is a literal
+// `${`. A value of the form `file:/path` is replaced by the contents of
+// the file, without the trailing newline.
+
+fn expand(v: &str) -> Result<String> {
+    if let Some(path) = v.strip_prefix("file:") {
+        return std::fs::read_to_string(path)
+            .map(|v| v.trim_end_matches(['\r', '\n']).into())
+            .map_err(|e| {
+                Error::ConfigError(format!("couldn't read '{}' -- {}", path, e))
+            });
+    }
 
-            if !(-90.0..=90.0).contains(&cfg.latitude) {
-                return Err(Error::ConfigError(
-                    "'latitude' is out of range".into(),
-                ));
-            }
+    let mut result = String::with_capacity(v.len());
+    let mut rest = v;
 
-            // Make sure longitude is between -180 and 180 degrees.
+    while let Some(idx) = rest.find("${") {
+        if rest[..idx].ends_with('$') {
+            result.push_str(&rest[..idx - 1]);
+            result.push_str("${");
+            rest = &rest[idx + 2..];
+            continue;
+        }
 
-            if !(-180.0..=180.0).contains(&cfg.longitude) {
-                return Err(Error::ConfigError(
-                    "'longitude' is out of range".into(),
-                ));
-            }
-            Ok(cfg)
-        })
+        let Some(end) = rest[idx..].find('}') else {
+            return Err(Error::ConfigError(format!("missing '}}' in '{}'", v)));
+        };
+        let name = &rest[idx + 2..idx + end];
+
+        result.push_str(&rest[..idx]);
+        result.push_str(&env::var(name).map_err(|_| {
+            Error::ConfigError(format!(
+                "environment variable '{}' isn't set",
+                name
+            ))
+        })?);
+        rest = &rest[idx + end + 1..]
+    }
+    result.push_str(rest);
+    Ok(result)
 }
 
-async fn from_file(path: &str) -> Option<Result<Config>> {
-    use tokio::fs;
+fn interpolate(v: value::Value) -> Result<value::Value> {
+    Ok(match v {
+        value::Value::String(v) => value::Value::String(expand(&v)?),
+        value::Value::Array(v) => value::Value::Array(
+            v.into_iter().map(interpolate).collect::<Result<_>>()?,
+        ),
+        value::Value::Table(v) => value::Value::Table(
+            v.into_iter()
+                .map(|(k, v)| interpolate(v).map(|v| (k, v)))
+                .collect::<Result<_>>()?,
+        ),
+        v => v,
+    })
+}
 
-    if let Ok(contents) = fs::read(path).await {
-        let contents = String::from_utf8_lossy(&contents);
+// Expands the value of `key` in the table `v`, if it has one.
 
-        Some(parse_config(&contents))
-    } else {
-        None
+fn interpolate_key(v: &mut value::Value, key: &str) -> Result<()> {
+    if let Some(v) = v.get_mut(key) {
+        *v = interpolate(std::mem::replace(v, value::Value::Boolean(false)))?
     }
+    Ok(())
 }
 
-async fn find_cfg() -> Result<Config> {
-    const CFG_FILE: &str = "drmem.toml";
+// Expands the references in the values that can hold secrets: the
+// parameters of drivers and the credentials of the client APIs. Other
+// strings, like the expressions of logic blocks, are used as written.
 
-    // Create a vector of directories that could contain a
-    // configuration file. The directories will be searched in their
-    // order within the vector.
+fn expand_secrets(v: value::Value) -> Result<value::Value> {
+    let value::Value::Table(mut cfg) = v else {
+        return Ok(v);
+    };
 
-    let mut dirs = vec![String::from("./")];
+    if let Some(value::Value::Array(drivers)) = cfg.get_mut("driver") {
+        for drv in drivers {
+            interpolate_key(drv, "cfg")?
+        }
+    }
 
-    // If the user has `HOME` defined, append their home directory to
-    // the search path. Note the end of the path has a period. This is
-    // done so the file will be named `.drmem.toml` in the home
-    // directory. (Kind of hack-y, I know.)
+    if let Some(value::Value::Array(hooks)) = cfg.get_mut("webhook") {
+        for hook in hooks {
+            interpolate_key(hook, "headers")?
+        }
+    }
 
-    if let Ok(home) = env::var("HOME") {
-        dirs.push(format!("{}/.", home))
+    if let Some(v) = cfg.get_mut("federation") {
+        interpolate_key(v, "token")?
     }
 
-    // Add other, common configuration areas.
+    if let Some(v) = cfg.get_mut("homekit") {
+        interpolate_key(v, "code")?
+    }
 
-    dirs.push(String::from("/usr/local/etc/"));
-    dirs.push(String::from("/usr/pkg/etc/"));
-    dirs.push(String::from("/etc/"));
+    if let Some(v) = cfg.get_mut("mqtt") {
+        interpolate_key(v, "password")?
+    }
 
-    // Iterate through the directories. The first file that is found
-    // and can be parsed is used as the configuration.
+    if let Some(v) = cfg.get_mut("graphql").and_then(|v| v.get_mut("smarthome"))
+    {
+        interpolate_key(v, "token")?
+    }
+    Ok(value::Value::Table(cfg))
+}
 
-    for dir in dirs {
-        let file = format!("{}{}", &dir, CFG_FILE);
+// Returns the `cfg` tables of the drivers, as written.
 
-        if let Some(cfg) = from_file(&file).await {
-            return cfg;
+fn driver_cfgs(v: &value::Value) -> Vec<Option<DriverConfig>> {
+    v.get("driver")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .map(|v| v.get("cfg").and_then(|v| v.as_table()).cloned())
+        .collect()
+}
+
+// Adds the contents of a fragment to the configuration. Arrays, like
+// the `[[driver]]` and `[[logic]]` sections, are appended and tables
+// are merged. Any other value can only be defined once.
+
+fn merge(
+    into: &mut value::Table,
+    from: value::Table,
+    file: &Path,
+) -> Result<()> {
+    for (k, v) in from {
+        match (into.get_mut(&k), v) {
+            (None, v) => {
+                into.insert(k, v);
+            }
+            (Some(value::Value::Array(a)), value::Value::Array(b)) => {
+                a.extend(b)
+            }
+            (Some(value::Value::Table(a)), value::Value::Table(b)) => {
+                merge(a, b, file)?
+            }
+            _ => {
+                return Err(Error::ConfigError(format!(
+                    "'{}' in {} is already defined",
+                    k,
+                    file.display()
+                )))
+            }
         }
     }
-    Ok(Config::default())
+    Ok(())
 }
 
-fn dump_config(cfg: &Config) {
-    println!("Configuration:");
-    println!("    log level: {}\n", cfg.get_log_level());
+// Merges the fragments in the directories named by the `include` key.
+// The `.toml` files in each directory are read in name order.
+// Relative directories are found from `dir`, the directory holding
+// the main file. Fragments can't include other directories.
 
-    #[cfg(feature = "simple-backend")]
-    {
-        println!("Using SIMPLE backend -- no configuration for it.\n");
-    }
+fn include(v: value::Value, dir: &Path) -> Result<value::Value> {
+    let value::Value::Table(mut cfg) = v else {
+        return Ok(v);
+    };
+    let dirs = match cfg.remove("include") {
+        None => vec![],
+        Some(value::Value::String(v)) => vec![v],
+        Some(value::Value::Array(v)) => v
+            .into_iter()
+            .map(|v| match v {
+                value::Value::String(v) => Ok(v),
+                _ => Err(Error::ConfigError(
+                    "'include' must hold directory names".into(),
+                )),
+            })
+            .collect::<Result<_>>()?,
+        Some(_) => {
+            return Err(Error::ConfigError(
+                "'include' must hold directory names".into(),
+            ))
+        }
+    };
 
-    #[cfg(feature = "redis-backend")]
-    {
+    for inc in dirs {
+        let inc = dir.join(inc);
+        let mut files: Vec<PathBuf> = std::fs::read_dir(&inc)
+            .map_err(|e| {
+                Error::ConfigError(format!(
+                    "couldn't read {} -- {}",
+                    inc.display(),
+                    e
+                ))
+            })?
+            .filter_map(|v| v.ok().map(|v| v.path()))
+            .filter(|v| v.extension().is_some_and(|v| v == "toml"))
+            .collect();
+
+        files.sort();
+
+        for file in files {
+            let fragment = std::fs::read_to_string(&file)
+                .map_err(|e| format!("{}", e))
+                .and_then(|v| {
+                    toml::from_str::<value::Table>(&v)
+                        .map_err(|e| format!("{}", e))
+                })
+                .map_err(|e| {
+                    Error::ConfigError(format!("{} -- {}", file.display(), e))
+                })?;
+
+            if fragment.contains_key("include") {
+                return Err(Error::ConfigError(format!(
+                    "{} can't include other files",
+                    file.display()
+                )));
+            }
+            merge(&mut cfg, fragment, &file)?
+        }
+    }
+    Ok(value::Value::Table(cfg))
+}
+
+// Copies the values of `from` into `into`. Values in `from` win,
+// except tables, which are combined.
+
+fn overlay(into: &mut value::Table, from: value::Table) {
+    for (k, v) in from {
+        match (into.get_mut(&k), v) {
+            (Some(value::Value::Table(a)), value::Value::Table(b)) => {
+                overlay(a, b)
+            }
+            (_, v) => {
+                into.insert(k, v);
+            }
+        }
+    }
+}
+
+// Builds the final list of drivers. A `[[driver]]` entry with an
+// `instances` array is a template; each element is a table that's
+// laid over the entry to make one instance, so repeated instances
+// only need to give what's different (e.g. the prefix and address.)
+// Then the `[driver-defaults.<name>]` parameters are added to each
+// instance of driver `name` that doesn't set them.
+
+fn expand_drivers(v: value::Value) -> Result<value::Value> {
+    let value::Value::Table(mut cfg) = v else {
+        return Ok(v);
+    };
+    let defaults = match cfg.remove("driver-defaults") {
+        None => value::Table::new(),
+        Some(value::Value::Table(v)) => v,
+        Some(_) => {
+            return Err(Error::ConfigError(
+                "'driver-defaults' must be a table".into(),
+            ))
+        }
+    };
+    let Some(value::Value::Array(drivers)) = cfg.remove("driver") else {
+        return Ok(value::Value::Table(cfg));
+    };
+    let mut result = Vec::with_capacity(drivers.len());
+
+    for drv in drivers {
+        let value::Value::Table(mut drv) = drv else {
+            return Err(Error::ConfigError(
+                "'driver' entries must be tables".into(),
+            ));
+        };
+
+        let instances = match drv.remove("instances") {
+            None => vec![value::Table::new()],
+            Some(value::Value::Array(v)) => v
+                .into_iter()
+                .map(|v| match v {
+                    value::Value::Table(v) => Ok(v),
+                    _ => Err(Error::ConfigError(
+                        "'instances' must hold tables".into(),
+                    )),
+                })
+                .collect::<Result<_>>()?,
+            Some(_) => {
+                return Err(Error::ConfigError(
+                    "'instances' must be an array of tables".into(),
+                ))
+            }
+        };
+
+        for inst in instances {
+            let mut drv = drv.clone();
+
+            overlay(&mut drv, inst);
+
+            let defs = drv
+                .get("name")
+                .and_then(|v| v.as_str())
+                .and_then(|v| defaults.get(v));
+
+            match defs {
+                None => (),
+                Some(value::Value::Table(defs)) => {
+                    let mut params = defs.clone();
+
+                    match drv.remove("cfg") {
+                        None => (),
+                        Some(value::Value::Table(v)) => overlay(&mut params, v),
+                        Some(_) => {
+                            return Err(Error::ConfigError(
+                                "driver 'cfg' must be a table".into(),
+                            ))
+                        }
+                    }
+                    drv.insert("cfg".into(), value::Value::Table(params));
+                }
+                Some(_) => {
+                    return Err(Error::ConfigError(
+                        "'driver-defaults' entries must be tables".into(),
+                    ))
+                }
+            }
+            result.push(value::Value::Table(drv))
+        }
+    }
+    cfg.insert("driver".into(), value::Value::Array(result));
+    Ok(value::Value::Table(cfg))
+}
+
+#[cfg(test)]
+fn parse_config(contents: &str) -> Result<Config> {
+    parse_config_in(contents, Path::new(""))
+}
+
+// Parses a configuration. `dir` is the directory holding the file,
+// which is used to find included fragments.
+
+fn parse_config_in(contents: &str, dir: &Path) -> Result<Config> {
+    toml::from_str(contents)
+        .map_err(|e| Error::ConfigError(format!("{}", e)))
+        .and_then(|v| include(v, dir))
+        .and_then(expand_drivers)
+        .and_then(|v| {
+            let raw = driver_cfgs(&v);
+
+            expand_secrets(v).map(|v| (v, raw))
+        })
+        .and_then(|(v, raw)| {
+            let mut cfg: Config = v
+                .try_into()
+                .map_err(|e| Error::ConfigError(format!("{}", e)))?;
+
+            for (drv, raw) in cfg.driver.iter_mut().zip(raw) {
+                drv.raw_cfg = raw
+            }
+            Ok(cfg)
+        })
+        .and_then(|cfg: Config| {
+            // Make sure latitude is between -90 and 90 degrees.
+
+            if !(-90.0..=90.0).contains(&cfg.latitude) {
+                return Err(Error::ConfigError(
+                    "'latitude' is out of range".into(),
+                ));
+            }
+
+            // Make sure longitude is between -180 and 180 degrees.
+
+            if !(-180.0..=180.0).contains(&cfg.longitude) {
+                return Err(Error::ConfigError(
+                    "'longitude' is out of range".into(),
+                ));
+            }
+
+            if !(cfg.setting_timeout.is_finite() && cfg.setting_timeout > 0.0) {
+                return Err(Error::ConfigError(
+                    "'setting_timeout' must be positive".into(),
+                ));
+            }
+
+            if !valid_max_age(cfg.max_age) {
+                return Err(Error::ConfigError(
+                    "'max_age' must be positive".into(),
+                ));
+            }
+
+            if let Some(tz) = &cfg.timezone {
+                if tz.parse::<chrono_tz::Tz>().is_err() {
+                    return Err(Error::ConfigError(format!(
+                        "'{}' isn't a known time zone",
+                        tz
+                    )));
+                }
+            }
+
+            #[cfg(feature = "graphql")]
+            cfg.graphql.validate()?;
+
+            #[cfg(feature = "webhooks")]
+            for hook in &cfg.webhook {
+                hook.validate()?
+            }
+
+            #[cfg(feature = "homekit")]
+            if let Some(homekit) = &cfg.homekit {
+                homekit.validate()?
+            }
+
+            #[cfg(feature = "federation")]
+            if let Some(federation) = &cfg.federation {
+                federation.validate()?
+            }
+
+            #[cfg(feature = "mqtt")]
+            cfg.mqtt.validate()?;
+
+            for drv in &cfg.driver {
+                if let Some(level) = &drv.log_level {
+                    if parse_level(level).is_none() {
+                        return Err(Error::ConfigError(format!(
+                            "log level '{}' of driver '{}' isn't valid",
+                            level, &drv.prefix
+                        )));
+                    }
+                }
+
+                if !valid_max_age(drv.max_age) {
+                    return Err(Error::ConfigError(format!(
+                        "'max_age' of driver '{}' must be positive",
+                        &drv.prefix
+                    )));
+                }
+
+                for (name, v) in drv.simulate.iter().flatten() {
+                    if device::Value::try_from(v).is_err() {
+                        return Err(Error::ConfigError(format!(
+                            "simulated value of '{}' in driver '{}' has an \
+                             unsupported type",
+                            name, &drv.prefix
+                        )));
+                    }
+                }
+            }
+
+            for dev in &cfg.device {
+                dev.validate()?
+            }
+
+            cfg.channels.validate()?;
+            cfg.log.validate()?;
+            cfg.startup.validate("startup")?;
+            cfg.shutdown.validate("shutdown")?;
+            Ok(cfg)
+        })
+}
+
+async fn from_file(path: &str) -> Option<Result<Config>> {
+    use tokio::fs;
+
+    if let Ok(contents) = fs::read(path).await {
+        let contents = String::from_utf8_lossy(&contents);
+
+        Some(parse_config_in(
+            &contents,
+            Path::new(path).parent().unwrap_or(Path::new("")),
+        ))
+    } else {
+        None
+    }
+}
+
+async fn search_cfg() -> Option<Result<Config>> {
+    const CFG_FILE: &str = "drmem.toml";
+
+    // Create a vector of directories that could contain a
+    // configuration file. The directories will be searched in their
+    // order within the vector.
+
+    let mut dirs = vec![String::from("./")];
+
+    // If the user has `HOME` defined, append their home directory to
+    // the search path. Note the end of the path has a period. This is
+    // done so the file will be named `.drmem.toml` in the home
+    // directory. (Kind of hack-y, I know.)
+
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(format!("{}/.", home))
+    }
+
+    // Add other, common configuration areas.
+
+    dirs.push(String::from("/usr/local/etc/"));
+    dirs.push(String::from("/usr/pkg/etc/"));
+    dirs.push(String::from("/etc/"));
+
+    // Iterate through the directories. The first file that is found
+    // and can be parsed is used as the configuration.
+
+    for dir in dirs {
+        let file = format!("{}{}", &dir, CFG_FILE);
+
+        if let Some(cfg) = from_file(&file).await {
+            return Some(cfg);
+        }
+    }
+    None
+}
+
+// Returns the first configuration file that's found or, if there
+// isn't one, the default configuration.
+
+async fn find_cfg() -> Result<Config> {
+    search_cfg().await.unwrap_or_else(|| Ok(Config::default()))
+}
+
+fn dump_config(cfg: &Config) {
+    println!("Configuration:");
+    println!("    log level: {}", cfg.get_log_level());
+
+    match &cfg.log.file {
+        Some(file) => {
+            println!("    log file: {}", file.display());
+            if let Some(size) = cfg.log.max_size {
+                println!("    rotate at: {} MiB", size);
+            }
+            if cfg.log.rotate != Rotate::Never {
+                println!("    rotate: {:?}", cfg.log.rotate);
+            }
+            println!("    keep: {}\n", cfg.log.keep);
+        }
+        None if cfg.log.syslog.is_some() => println!(),
+        None => println!("    log file: stdout\n"),
+    }
+
+    if let Some(syslog) = &cfg.log.syslog {
+        println!("Using syslog:");
+        println!(
+            "    server: {}",
+            syslog.address.as_deref().unwrap_or("local")
+        );
+        println!("    facility: {:?}\n", syslog.facility);
+    }
+
+    if !cfg.log.modules.is_empty() {
+        println!("Module log levels:");
+        for (module, level) in &cfg.log.modules {
+            println!("    {}: {}", module, level);
+        }
+        println!();
+    }
+
+    if let Some(tz) = &cfg.timezone {
+        println!("    time zone: {}\n", tz);
+    }
+
+    if !cfg.holidays.is_empty() {
+        println!("    holidays: {:?}\n", &cfg.holidays);
+    }
+
+    println!("    setting timeout: {} seconds", cfg.setting_timeout);
+    if let Some(mh) = cfg.max_history {
+        println!("    max history: {} readings", mh);
+    }
+    if let Some(age) = cfg.max_age {
+        println!("    max age: {} seconds", age);
+    }
+    println!();
+
+    if cfg.simulate {
+        println!("Simulating the drivers that use hardware.\n");
+    }
+
+    #[cfg(feature = "simple-backend")]
+    {
+        println!("Using SIMPLE backend -- no configuration for it.\n");
+    }
+
+    #[cfg(feature = "redis-backend")]
+    {
         println!("Using REDIS for storage:");
         println!("    address: {}", &cfg.get_backend().get_addr());
         println!("    db #: {}\n", cfg.get_backend().get_dbn());
@@ -226,17 +1285,168 @@ fn dump_config(cfg: &Config) {
     {
         println!("Using GraphQL:");
         println!("    instance name: {}", cfg.get_name());
-        println!("    address: {}\n", cfg.get_graphql_addr());
+        println!("    address: {}", cfg.get_graphql_addr());
+        if let Some(smarthome) = &cfg.graphql.smarthome {
+            println!("    smart-home devices: {}", smarthome.device.len());
+        }
+        println!(
+            "    TLS: {}\n",
+            if cfg.graphql.tls().is_some() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        println!("Using gRPC:");
+        println!("    address: {}\n", cfg.grpc.addr);
+    }
+
+    #[cfg(feature = "mqtt")]
+    {
+        println!("Using MQTT:");
+        println!("    broker: {}:{}", cfg.mqtt.host, cfg.mqtt.port);
+        println!("    client ID: {}", cfg.mqtt.client_id);
+        println!("    topic: {}", cfg.mqtt.topic);
+        if let Some(username) = &cfg.mqtt.username {
+            println!("    username: {}", username);
+        }
+        println!("    TLS: {}\n", cfg.mqtt.uses_tls());
+    }
+
+    #[cfg(feature = "webhooks")]
+    if !cfg.webhook.is_empty() {
+        println!("Using webhooks:");
+        for hook in &cfg.webhook {
+            println!("    {} <- {}", hook.url, hook.devices.join(", "));
+        }
+        println!();
+    }
+
+    #[cfg(feature = "homekit")]
+    if let Some(homekit) = &cfg.homekit {
+        println!("Using HomeKit:");
+        println!("    name: {}", homekit.name);
+        println!("    port: {}", homekit.port);
+        println!("    accessories: {}\n", homekit.accessory.len());
+    }
+
+    #[cfg(feature = "federation")]
+    if let Some(federation) = &cfg.federation {
+        println!("Using federation:");
+        println!("    node: {}", federation.name);
+        if let Some(addr) = federation.addr {
+            println!("    address: {}", addr);
+            println!("    export: {}", federation.export.join(", "));
+        }
+        for peer in &federation.peer {
+            println!(
+                "    peer {} at {}: {}",
+                peer.name,
+                peer.addr,
+                peer.import.join(", ")
+            );
+        }
+        println!();
+    }
+
+    #[cfg(feature = "plugins")]
+    if let Some(dir) = &cfg.plugin_dir {
+        println!("Plugin directory: {}\n", dir.display());
+    }
+
+    if let Some(file) = &cfg.secrets.file {
+        println!("Secrets file: {}\n", file.display());
+    }
+
+    if !cfg.startup.setting.is_empty() {
+        println!("Settings at startup:");
+        for s in &cfg.startup.setting {
+            if let Ok(v) = device::Value::try_from(&s.value) {
+                println!("    {} = {}", &s.device, v);
+            }
+        }
+        println!();
+    }
+
+    if !cfg.shutdown.setting.is_empty() {
+        println!("Safe state on shutdown:");
+        for s in &cfg.shutdown.setting {
+            if let Ok(v) = device::Value::try_from(&s.value) {
+                println!("    {} = {}", &s.device, v);
+            }
+        }
+        println!();
     }
 
+    if !cfg.device.is_empty() {
+        println!("Device limits:");
+        for dev in &cfg.device {
+            println!(
+                "    {}: interval {:?}, deadband {:?}",
+                &dev.pattern, dev.min_report_interval, dev.deadband
+            );
+            if dev.readings.is_some() || dev.policy.is_some() {
+                println!(
+                    "        readings {:?}, policy {:?}",
+                    dev.readings, dev.policy
+                );
+            }
+            if dev.max_history.is_some() || dev.max_age.is_some() {
+                println!(
+                    "        max history {:?}, max age {:?}",
+                    dev.max_history, dev.max_age
+                );
+            }
+        }
+        println!();
+    }
+
+    if cfg.clients != Clients::default() {
+        println!(
+            "Client devices:\n    visible: {}\n    hidden: {}\n    \
+             read-only: {}\n",
+            cfg.clients.visible.join(", "),
+            cfg.clients.hidden.join(", "),
+            cfg.clients.read_only.join(", ")
+        );
+    }
+
+    println!(
+        "Channels:\n    readings: {}, settings: {}, requests: {}, \
+         events: {}\n    policy: {:?}\n",
+        cfg.channels.readings,
+        cfg.channels.settings,
+        cfg.channels.requests,
+        cfg.channels.events,
+        cfg.channels.policy
+    );
+
     println!("Driver configuration:");
     if !cfg.driver.is_empty() {
         for ii in &cfg.driver {
+            println!("    name: {}\n    prefix: '{}'", &ii.name, &ii.prefix);
+            if let Some(level) = &ii.log_level {
+                println!("    log level: {}", level);
+            }
+            if let Some(mh) = ii.max_history {
+                println!("    max history: {} readings", mh);
+            }
+            if let Some(age) = ii.max_age {
+                println!("    max age: {} seconds", age);
+            }
+            if let Some(values) = &ii.simulate {
+                println!("    simulate: {:?}", values);
+            }
             println!(
-                "    name: {}\n    prefix: '{}'\n    cfg: {:?}\n",
-                &ii.name,
-                &ii.prefix,
-                ii.cfg.as_ref().unwrap_or(&value::Table::new())
+                "    cfg: {:?}\n",
+                ii.raw_cfg
+                    .as_ref()
+                    .or(ii.cfg.as_ref())
+                    .unwrap_or(&value::Table::new())
             )
         }
     } else {
@@ -244,249 +1454,1137 @@ fn dump_config(cfg: &Config) {
     }
 }
 
-#[tracing::instrument(name = "loading config")]
-pub async fn get() -> Option<Config> {
-    match find_cfg().await {
-        Ok(cfg) => {
-            let (print_cfg, cfg) = from_cmdline(cfg);
+// Reads the configuration file again. This is used to reload the
+// logic blocks and drivers while drmemd is running. Command line
+// options still override the file.
+//
+// Unlike at start-up, a missing file is an error. Otherwise the
+// default configuration, which has no drivers or logic blocks, would
+// stop everything.
+
+pub async fn reload() -> Result<Config> {
+    match search_cfg().await {
+        Some(cfg) => cfg.map(|cfg| from_cmdline(cfg).1),
+        None => Err(Error::ConfigError("no configuration file found".into())),
+    }
+}
+
+#[tracing::instrument(name = "loading config")]
+pub async fn get() -> Option<Config> {
+    match find_cfg().await {
+        Ok(cfg) => {
+            let (print_cfg, cfg) = from_cmdline(cfg);
+
+            if print_cfg {
+                dump_config(&cfg);
+                None
+            } else {
+                Some(cfg)
+            }
+        }
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        // Verify the defaults.
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::WARN),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        // Verify the log_level can be set.
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+log_level = "trace"
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::TRACE),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+log_level = "debug"
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::DEBUG),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+log_level = "info"
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::INFO),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+log_level = "warn"
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::WARN),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_holidays() {
+        use crate::logic::tod::Holiday;
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+"#,
+        ) {
+            Ok(cfg) => assert!(cfg.holidays.is_empty()),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+holidays = ["12-25", "2024-11-28"]
+"#,
+        ) {
+            Ok(cfg) => assert_eq!(
+                cfg.holidays,
+                vec![
+                    Holiday::Annual(12, 25),
+                    Holiday::Once(
+                        chrono::NaiveDate::from_ymd_opt(2024, 11, 28).unwrap()
+                    )
+                ]
+            ),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+holidays = ["12-32"]
+"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_clients_section() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => {
+                assert_eq!(cfg.clients.visible, vec!["*"]);
+                assert!(cfg.clients.hidden.is_empty());
+                assert!(cfg.clients.read_only.is_empty());
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        const CLIENTS: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[clients]
+hidden = ["logic:*", "*:latch"]
+read_only = ["drmem:*"]
+"#;
+
+        match parse_config(CLIENTS) {
+            Ok(cfg) => {
+                assert_eq!(cfg.clients.visible, vec!["*"]);
+                assert_eq!(cfg.clients.hidden, vec!["logic:*", "*:latch"]);
+                assert_eq!(cfg.clients.read_only, vec!["drmem:*"]);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&CLIENTS.replace("[\"drmem:*\"]", "1")).is_err());
+    }
+
+    #[test]
+    fn test_setting_timeout() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => assert_eq!(
+                cfg.get_setting_timeout(),
+                std::time::Duration::from_secs(10)
+            ),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        const TIMEOUT: &str = "latitude = 0.0
+longitude = 0.0
+setting_timeout = 2.5
+";
+
+        match parse_config(TIMEOUT) {
+            Ok(cfg) => assert_eq!(
+                cfg.get_setting_timeout(),
+                std::time::Duration::from_millis(2500)
+            ),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&TIMEOUT.replace("2.5", "0.0")).is_err());
+        assert!(parse_config(&TIMEOUT.replace("2.5", "inf")).is_err());
+    }
+
+    #[test]
+    fn test_history() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => {
+                assert_eq!(cfg.max_history, None);
+                assert_eq!(cfg.get_max_age(), None);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        const HISTORY: &str = r#"
+latitude = 0.0
+longitude = 0.0
+max_history = 1000
+max_age = 86400.0
+
+[[driver]]
+name = "memory"
+prefix = "test"
+max_age = 3600.0
+
+[[device]]
+pattern = "weather:*"
+max_history = 10000
+"#;
+
+        match parse_config(HISTORY) {
+            Ok(cfg) => {
+                assert_eq!(cfg.max_history, Some(1000));
+                assert_eq!(
+                    cfg.get_max_age(),
+                    Some(std::time::Duration::from_secs(86_400))
+                );
+                assert_eq!(cfg.driver[0].max_history, None);
+                assert_eq!(
+                    cfg.driver[0].get_max_age(),
+                    Some(std::time::Duration::from_secs(3_600))
+                );
+                assert_eq!(cfg.device[0].max_history, Some(10000));
+                assert_eq!(cfg.device[0].max_age, None);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&HISTORY.replace("86400.0", "0.0")).is_err());
+        assert!(parse_config(&HISTORY.replace("3600.0", "-1.0")).is_err());
+        assert!(parse_config(
+            &HISTORY.replace("= 10000", "= 10000\nmax_age = inf")
+        )
+        .is_err());
+        assert!(parse_config(&HISTORY.replace("1000\n", "-1\n")).is_err());
+    }
+
+    #[test]
+    fn test_timezone() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => assert_eq!(cfg.get_timezone(), None),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        const TZ: &str = r#"
+latitude = 41.9
+longitude = -87.6
+timezone = "America/Chicago"
+"#;
+
+        match parse_config(TZ) {
+            Ok(cfg) => {
+                assert_eq!(
+                    cfg.get_timezone(),
+                    Some(chrono_tz::America::Chicago)
+                )
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&TZ.replace("Chicago", "Springfield")).is_err());
+    }
+
+    #[cfg(feature = "graphql")]
+    #[test]
+    fn test_graphql_config() {
+        use std::net::Ipv4Addr;
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+                assert_eq!(cfg.graphql.pref_host, None);
+                assert_eq!(cfg.graphql.pref_port, 3000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+                assert_eq!(cfg.graphql.pref_host, None);
+                assert_eq!(cfg.graphql.pref_port, 3000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+name = "primary-node"
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "primary-node");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+                assert_eq!(cfg.graphql.pref_host, None);
+                assert_eq!(cfg.graphql.pref_port, 3000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+location = "basement"
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "basement".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+addr = "10.1.1.0:1234"
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(10, 1, 1, 0), 1234).into()
+                );
+                assert_eq!(cfg.graphql.pref_host, None);
+                assert_eq!(cfg.graphql.pref_port, 3000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+pref_host = "www.google.com"
+pref_port = 4000
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+                assert_eq!(
+                    cfg.graphql.pref_host,
+                    Some("www.google.com".into())
+                );
+                assert_eq!(cfg.graphql.pref_port, 4000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+pref_host = "www.google.com"
+"#,
+        ) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.name, "unknown name");
+                assert_eq!(cfg.graphql.location, "unknown location".into());
+                assert_eq!(
+                    cfg.graphql.addr,
+                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                );
+                assert_eq!(
+                    cfg.graphql.pref_host,
+                    Some("www.google.com".into())
+                );
+                assert_eq!(cfg.graphql.pref_port, 3000)
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql.security]
+clients = ["00:11:22:33"]
+cert_file = "cert.pem"
+key_file = "key.pem"
+
+[[graphql.security.access]]
+clients = ["00:11:22:33"]
+devices = ["hallway:*"]
+allow = ["monitor", "set"]
+"#,
+        ) {
+            Ok(cfg) => {
+                use crate::graphql::config::Operation;
+
+                let security = cfg.graphql.security.unwrap();
+
+                assert_eq!(security.access.len(), 1);
+                assert_eq!(security.access[0].devices, vec!["hallway:*"]);
+                assert_eq!(
+                    security.access[0].allow,
+                    vec![Operation::Monitor, Operation::Set]
+                );
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        // The server can use TLS without the `security` section. The
+        // certificate and key have to be given together.
+
+        const TLS: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+cert = "cert.pem"
+key = "key.pem"
+"#;
+
+        match parse_config(TLS) {
+            Ok(cfg) => {
+                let (cert, key) = cfg.graphql.tls().unwrap();
+
+                assert_eq!(&*cert, Path::new("cert.pem"));
+                assert_eq!(&*key, Path::new("key.pem"));
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&TLS.replace("key = \"key.pem\"", "")).is_err());
+        assert!(parse_config(&format!(
+            "{}\n[graphql.security]\nclients = []\n\
+             cert_file = \"a.pem\"\nkey_file = \"b.pem\"\n",
+            TLS
+        ))
+        .is_err());
+
+        // Access rules only accept known operations.
+
+        assert!(toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql.security]
+clients = []
+cert_file = "cert.pem"
+key_file = "key.pem"
+
+[[graphql.security.access]]
+clients = []
+allow = ["delete"]
+"#,
+        )
+        .is_err());
+
+        // WebSocket settings have defaults.
+
+        const WS: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql.websocket]
+keepalive = 0
+"#;
+
+        match parse_config(WS) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.websocket.keepalive, 0);
+                assert_eq!(cfg.graphql.websocket.init_timeout, 10);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&format!("{}init_timeout = 0\n", WS)).is_err());
+
+        // Client limits have defaults and can be turned off.
+
+        const LIMITS: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql.limits]
+requests = 0
+connections = 5
+"#;
+
+        match parse_config(LIMITS) {
+            Ok(cfg) => {
+                assert_eq!(cfg.graphql.limits.requests, 0);
+                assert_eq!(cfg.graphql.limits.burst, 40);
+                assert_eq!(cfg.graphql.limits.subscriptions, 50);
+                assert_eq!(cfg.graphql.limits.connections, 5);
+                assert_eq!(cfg.graphql.limits.depth, 15);
+                assert_eq!(cfg.graphql.limits.complexity, 1_000);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(
+            "latitude = -45.0\nlongitude = 45.0\n[graphql.limits]\nburst = 0\n"
+        )
+        .is_err());
+
+        // Smart-home devices map patterns to device types. The token
+        // can't be empty.
+
+        const SMART: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql.smarthome]
+token = "secret"
+
+[[graphql.smarthome.device]]
+pattern = "porch:*"
+type = "light"
+"#;
+
+        match parse_config(SMART) {
+            Ok(cfg) => {
+                use crate::graphql::config::DeviceType;
+
+                let smarthome = cfg.graphql.smarthome.unwrap();
+
+                assert_eq!(&*smarthome.token, "secret");
+                assert_eq!(smarthome.device[0].pattern, "porch:*");
+                assert_eq!(smarthome.device[0].kind, DeviceType::Light);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&SMART.replace("\"secret\"", "\"\"")).is_err());
+        assert!(parse_config(&SMART.replace("light", "toaster")).is_err());
+
+        // Allowed origins need a scheme and a host.
+
+        const ORIGINS: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[graphql]
+allowed_origins = ["https://dash.example.com", "http://localhost:8080"]
+"#;
+
+        match parse_config(ORIGINS) {
+            Ok(cfg) => assert_eq!(
+                cfg.graphql.allowed_origins,
+                Some(vec![
+                    "https://dash.example.com".into(),
+                    "http://localhost:8080".into()
+                ])
+            ),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&ORIGINS.replace("https://", "")).is_err());
+        assert!(parse_config(&ORIGINS.replace(".com", ".com/")).is_err());
+
+        // The service is advertised unless it's turned off.
+
+        match parse_config(ORIGINS) {
+            Ok(cfg) => assert!(cfg.graphql.advertise),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        match parse_config(
+            &ORIGINS.replace("allowed", "advertise = false\nallowed"),
+        ) {
+            Ok(cfg) => assert!(!cfg.graphql.advertise),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        // Persisted queries are read from the named file.
+
+        let path = std::env::temp_dir().join("drmem-test-queries.toml");
+
+        std::fs::write(&path, "devices = \"{ deviceInfo { deviceName } }\"\n")
+            .unwrap();
+
+        let queries = format!(
+            "latitude = -45.0\nlongitude = 45.0\n[graphql]\n\
+             persisted_queries = {:?}\n",
+            path
+        );
+
+        match parse_config(&queries) {
+            Ok(cfg) => assert_eq!(
+                cfg.graphql
+                    .persisted_queries
+                    .unwrap()
+                    .get("devices")
+                    .map(String::as_str),
+                Some("{ deviceInfo { deviceName } }")
+            ),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_config(&queries).is_err());
+    }
+
+    #[cfg(feature = "webhooks")]
+    #[test]
+    fn test_webhook_config() {
+        const HOOKS: &str = r#"
+latitude = -45.0
+longitude = 45.0
+
+[[webhook]]
+url = "https://example.com/hook"
+devices = ["sump:*"]
+debounce = 500
+headers = { Authorization = "Bearer abc" }
+payload = { text = "{device} is {value}" }
+
+[[webhook]]
+url = "http://10.0.0.5/alert"
+devices = ["porch:light", "garage:door"]
+"#;
+
+        match parse_config(HOOKS) {
+            Ok(cfg) => {
+                assert_eq!(cfg.webhook.len(), 2);
+                assert_eq!(cfg.webhook[0].debounce, 500);
+                assert_eq!(cfg.webhook[0].timeout, 10);
+                assert_eq!(
+                    cfg.webhook[0].payload,
+                    Some(serde_json::json!({ "text": "{device} is {value}" }))
+                );
+                assert_eq!(cfg.webhook[1].debounce, 0);
+                assert!(cfg.webhook[1].headers.is_empty());
+                assert_eq!(cfg.webhook[1].payload, None);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&HOOKS.replace("https://", "ftp://")).is_err());
+        assert!(parse_config(&HOOKS.replace("[\"sump:*\"]", "[]")).is_err());
+        assert!(
+            parse_config(&HOOKS.replace("Authorization", "\"bad name\""))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = env::temp_dir().join("drmem-test-includes");
+        let frags = dir.join("drmem.d");
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&frags).unwrap();
+        std::fs::write(
+            frags.join("20-lamp.toml"),
+            "[[driver]]\nname = \"memory\"\nprefix = \"lamp\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            frags.join("10-sump.toml"),
+            "[[driver]]\nname = \"memory\"\nprefix = \"sump\"\n\n\
+             [shutdown]\ntimeout = 3.0\n",
+        )
+        .unwrap();
+        std::fs::write(frags.join("README"), "not a fragment").unwrap();
+
+        const MAIN: &str = r#"
+latitude = 0.0
+longitude = 0.0
+include = "drmem.d"
+
+[[driver]]
+name = "memory"
+prefix = "main"
+"#;
+
+        // Fragments are read in name order, after the main file.
+
+        let cfg = parse_config_in(MAIN, &dir).unwrap();
+        let prefixes: Vec<String> =
+            cfg.driver.iter().map(|v| v.prefix.to_string()).collect();
+
+        assert_eq!(prefixes, ["main", "sump", "lamp"]);
+        assert_eq!(cfg.shutdown.timeout, 3.0);
+
+        // Values can't be defined twice.
+
+        std::fs::write(frags.join("30-bad.toml"), "latitude = 1.0\n").unwrap();
+        assert!(parse_config_in(MAIN, &dir).is_err());
+
+        // Fragments can't include other directories.
+
+        std::fs::write(frags.join("30-bad.toml"), "include = \".\"\n").unwrap();
+        assert!(parse_config_in(MAIN, &dir).is_err());
+
+        std::fs::remove_file(frags.join("30-bad.toml")).unwrap();
+        assert!(parse_config_in(MAIN, &dir.join("missing")).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_driver_templates() {
+        const CFG: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[driver-defaults.tplink]
+poll = 5
+led = true
+
+[[driver]]
+name = "tplink"
+cfg = { led = false }
+instances = [
+    { prefix = "plug-kitchen", cfg = { addr = "10.0.0.31" } },
+    { prefix = "plug-office", cfg = { addr = "10.0.0.32", poll = 1 } },
+]
+
+[[driver]]
+name = "tplink"
+prefix = "plug-porch"
+cfg = { addr = "10.0.0.33" }
+
+[[driver]]
+name = "memory"
+prefix = "var"
+"#;
+
+        let cfg = parse_config(CFG).unwrap();
+        let prefixes: Vec<String> =
+            cfg.driver.iter().map(|v| v.prefix.to_string()).collect();
+
+        assert_eq!(
+            prefixes,
+            ["plug-kitchen", "plug-office", "plug-porch", "var"]
+        );
+
+        // Instances override the template, which overrides the
+        // defaults.
+
+        let params = |idx: usize| cfg.driver[idx].cfg.clone().unwrap();
+
+        assert_eq!(params(0)["addr"].as_str(), Some("10.0.0.31"));
+        assert_eq!(params(0)["poll"].as_integer(), Some(5));
+        assert_eq!(params(0)["led"].as_bool(), Some(false));
+        assert_eq!(params(1)["addr"].as_str(), Some("10.0.0.32"));
+        assert_eq!(params(1)["poll"].as_integer(), Some(1));
+        assert_eq!(params(2)["led"].as_bool(), Some(true));
+        assert!(cfg.driver[3].cfg.is_none());
 
-            if print_cfg {
-                dump_config(&cfg);
-                None
-            } else {
-                Some(cfg)
-            }
-        }
-        Err(e) => {
-            println!("{}", e);
-            None
-        }
-    }
-}
+        // Templates need tables.
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert!(parse_config(
+            "latitude = 0.0\nlongitude = 0.0\n\
+             [[driver]]\nname = \"memory\"\ninstances = [\"a\"]\n"
+        )
+        .is_err());
+    }
 
     #[test]
-    fn test_config() {
-        // Verify the defaults.
+    fn test_interpolation() {
+        env::set_var("DRMEM_TEST_KEY", "abc123");
+        env::remove_var("DRMEM_TEST_UNSET");
+
+        assert_eq!(expand("plain").unwrap(), "plain");
+        assert_eq!(expand("${DRMEM_TEST_KEY}").unwrap(), "abc123");
+        assert_eq!(
+            expand("key=${DRMEM_TEST_KEY}&id=${DRMEM_TEST_KEY}").unwrap(),
+            "key=abc123&id=abc123"
+        );
+        assert_eq!(expand("$${DRMEM_TEST_KEY}").unwrap(), "${DRMEM_TEST_KEY}");
+        assert_eq!(expand("cost: $5").unwrap(), "cost: $5");
+        assert!(expand("${DRMEM_TEST_UNSET}").is_err());
+        assert!(expand("${DRMEM_TEST_KEY").is_err());
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
-"#,
-        ) {
-            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::WARN),
-            Err(e) => panic!("TOML parse error: {}", e),
-        }
+        let path = env::temp_dir().join("drmem-test-secret");
 
-        // Verify the log_level can be set.
+        std::fs::write(&path, "s3cret\n").unwrap();
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
-log_level = "trace"
-"#,
-        ) {
-            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::TRACE),
-            Err(e) => panic!("TOML parse error: {}", e),
-        }
+        let file = format!("file:{}", path.display());
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
-log_level = "debug"
-"#,
-        ) {
-            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::DEBUG),
-            Err(e) => panic!("TOML parse error: {}", e),
-        }
+        assert_eq!(expand(&file).unwrap(), "s3cret");
+        assert!(expand("file:/nonexistent/drmem-secret").is_err());
 
-        match toml::from_str::<Config>(
+        // Driver parameters are expanded, but the parameters as
+        // written are kept for `--print-config`. Other strings, like
+        // logic expressions, aren't expanded.
+
+        let cfg = parse_config(&format!(
             r#"
-latitude = -45.0
-longitude = 45.0
-log_level = "info"
+latitude = 0.0
+longitude = 0.0
+
+[[driver]]
+name = "memory"
+prefix = "test"
+cfg = {{ name = "${{DRMEM_TEST_KEY}}", keys = ["{}"] }}
+
+[[logic]]
+name = "copy"
+summary = "${{DRMEM_TEST_KEY}}"
+exprs = ["{{a}} -> {{b}}"]
+outputs = {{ b = "test:out" }}
+inputs = {{ a = "test:in" }}
 "#,
-        ) {
-            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::INFO),
-            Err(e) => panic!("TOML parse error: {}", e),
-        }
+            file
+        ))
+        .unwrap();
+        let drv_cfg = cfg.driver[0].cfg.as_ref().unwrap();
+        let raw_cfg = cfg.driver[0].raw_cfg.as_ref().unwrap();
+
+        assert_eq!(drv_cfg["name"].as_str(), Some("abc123"));
+        assert_eq!(drv_cfg["keys"][0].as_str(), Some("s3cret"));
+        assert_eq!(raw_cfg["name"].as_str(), Some("${DRMEM_TEST_KEY}"));
+        assert_eq!(raw_cfg["keys"][0].as_str(), Some(file.as_str()));
+        assert_eq!(cfg.logic[0].summary.as_deref(), Some("${DRMEM_TEST_KEY}"));
+
+        // The MQTT password is a credential, so it's expanded. The
+        // username isn't.
+
+        #[cfg(feature = "mqtt")]
+        {
+            let cfg = parse_config(
+                r#"
+latitude = 0.0
+longitude = 0.0
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
-log_level = "warn"
+[mqtt]
+username = "${DRMEM_TEST_KEY}"
+password = "${DRMEM_TEST_KEY}"
 "#,
-        ) {
-            Ok(cfg) => assert_eq!(cfg.get_log_level(), Level::WARN),
-            Err(e) => panic!("TOML parse error: {}", e),
+            )
+            .unwrap();
+
+            assert_eq!(cfg.mqtt.username.as_deref(), Some("${DRMEM_TEST_KEY}"));
+            assert_eq!(cfg.mqtt.password.as_deref(), Some("abc123"));
         }
+
+        let _ = std::fs::remove_file(&path);
     }
 
-    #[cfg(feature = "graphql")]
     #[test]
-    fn test_graphql_config() {
-        use std::net::Ipv4Addr;
+    fn test_shutdown_config() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => {
+                assert_eq!(cfg.shutdown.timeout, 10.0);
+                assert!(cfg.shutdown.setting.is_empty());
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
 
-        match toml::from_str::<Config>(
-            r#"
+        const SHUTDOWN: &str = r##"
 latitude = -45.0
 longitude = 45.0
-"#,
-        ) {
+
+[shutdown]
+timeout = 2.5
+
+[[shutdown.setting]]
+device = "basement:heater:enable"
+value = false
+
+[[shutdown.setting]]
+device = "porch:light:color"
+value = "#000000"
+"##;
+
+        match parse_config(SHUTDOWN) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
                 assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                    cfg.shutdown.get_timeout(),
+                    std::time::Duration::from_millis(2500)
+                );
+                assert_eq!(cfg.shutdown.setting.len(), 2);
+                assert_eq!(
+                    cfg.shutdown.setting[0].device.to_string(),
+                    "basement:heater:enable"
+                );
+                assert_eq!(
+                    cfg.shutdown.setting[1].value,
+                    toml::Value::String("#000000".into())
                 );
-                assert_eq!(cfg.graphql.pref_host, None);
-                assert_eq!(cfg.graphql.pref_port, 3000)
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        assert!(parse_config(&SHUTDOWN.replace("2.5", "0.0")).is_err());
+        assert!(parse_config(&SHUTDOWN.replace("false", "[1, 2]")).is_err());
+        assert!(parse_config(&SHUTDOWN.replace("basement:", "bad!:")).is_err());
 
-[graphql]"#,
-        ) {
+        // The `[startup]` section has the same form.
+
+        let startup = SHUTDOWN.replace("shutdown", "startup");
+
+        match parse_config(&startup) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
-                assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
-                );
-                assert_eq!(cfg.graphql.pref_host, None);
-                assert_eq!(cfg.graphql.pref_port, 3000)
+                assert_eq!(cfg.startup.setting.len(), 2);
+                assert!(cfg.shutdown.setting.is_empty());
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        assert!(parse_config(&startup.replace("false", "[1, 2]")).is_err());
+    }
 
-[graphql]
-name = "primary-node"
-"#,
-        ) {
+    #[test]
+    fn test_device_section() {
+        const DEVICE: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[[device]]
+pattern = "weather:*"
+min_report_interval = 60.0
+deadband = 0.5
+
+[[device]]
+pattern = "sump:state"
+deadband = 1.0
+"#;
+
+        match parse_config(DEVICE) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "primary-node");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
-                assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
-                );
-                assert_eq!(cfg.graphql.pref_host, None);
-                assert_eq!(cfg.graphql.pref_port, 3000)
+                assert_eq!(cfg.device.len(), 2);
+                assert_eq!(cfg.device[0].pattern, "weather:*");
+                assert_eq!(cfg.device[0].min_report_interval, Some(60.0));
+                assert_eq!(cfg.device[0].deadband, Some(0.5));
+                assert_eq!(cfg.device[1].min_report_interval, None);
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        assert!(parse_config(&DEVICE.replace("60.0", "-1.0")).is_err());
+        assert!(parse_config(&DEVICE.replace("= 1.0", "= nan")).is_err());
+    }
 
-[graphql]
-location = "basement"
-"#,
-        ) {
+    #[test]
+    fn test_channels_section() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
+            Ok(cfg) => assert_eq!(cfg.channels, Channels::default()),
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        const CHANNELS: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[channels]
+readings = 100
+requests = 50
+policy = "block"
+
+[[device]]
+pattern = "power:*"
+readings = 500
+policy = "drop-oldest"
+"#;
+
+        match parse_config(CHANNELS) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "basement".into());
-                assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
-                );
+                assert_eq!(cfg.channels.readings, 100);
+                assert_eq!(cfg.channels.settings, 20);
+                assert_eq!(cfg.channels.requests, 50);
+                assert_eq!(cfg.channels.events, 100);
+                assert_eq!(cfg.channels.policy, Policy::Block);
+                assert_eq!(cfg.device[0].readings, Some(500));
+                assert_eq!(cfg.device[0].policy, Some(Policy::DropOldest));
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        assert!(parse_config(&CHANNELS.replace("= 100", "= 0")).is_err());
+        assert!(parse_config(&CHANNELS.replace("= 500", "= 0")).is_err());
+        assert!(
+            parse_config(&CHANNELS.replace("\"block\"", "\"wait\"")).is_err()
+        );
+    }
 
-[graphql]
-addr = "10.1.1.0:1234"
-"#,
-        ) {
+    #[test]
+    fn test_log_config() {
+        match parse_config("latitude = 0.0\nlongitude = 0.0\n") {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
-                assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(10, 1, 1, 0), 1234).into()
-                );
-                assert_eq!(cfg.graphql.pref_host, None);
-                assert_eq!(cfg.graphql.pref_port, 3000)
+                assert!(cfg.log.file.is_none());
+                assert_eq!(cfg.log.rotate, Rotate::Never);
+                assert_eq!(cfg.log.keep, 5);
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        const LOG: &str = r#"
+latitude = 0.0
+longitude = 0.0
 
-[graphql]
-pref_host = "www.google.com"
-pref_port = 4000
-"#,
-        ) {
+[log]
+file = "/var/log/drmemd.log"
+max_size = 10
+rotate = "daily"
+keep = 3
+"#;
+
+        match parse_config(LOG) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
-                assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
-                );
                 assert_eq!(
-                    cfg.graphql.pref_host,
-                    Some("www.google.com".into())
+                    cfg.log.file,
+                    Some(PathBuf::from("/var/log/drmemd.log"))
                 );
-                assert_eq!(cfg.graphql.pref_port, 4000)
+                assert_eq!(cfg.log.max_size, Some(10));
+                assert_eq!(cfg.log.rotate, Rotate::Daily);
+                assert_eq!(cfg.log.keep, 3);
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
 
-        match toml::from_str::<Config>(
-            r#"
-latitude = -45.0
-longitude = 45.0
+        assert!(parse_config(&LOG.replace("= 10", "= 0")).is_err());
+        assert!(parse_config(&LOG.replace("daily", "weekly")).is_err());
 
-[graphql]
-pref_host = "www.google.com"
-"#,
-        ) {
+        const SYSLOG: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[log.syslog]
+address = "udp://10.0.0.2:514"
+facility = "local3"
+"#;
+
+        match parse_config(SYSLOG) {
             Ok(cfg) => {
-                assert_eq!(cfg.graphql.name, "unknown name");
-                assert_eq!(cfg.graphql.location, "unknown location".into());
+                let syslog = cfg.log.syslog.unwrap();
+
                 assert_eq!(
-                    cfg.graphql.addr,
-                    (Ipv4Addr::new(0, 0, 0, 0), 3000).into()
+                    syslog.address.as_deref(),
+                    Some("udp://10.0.0.2:514")
                 );
+                assert_eq!(syslog.facility, Facility::Local3);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(&SYSLOG.replace("udp:", "http:")).is_err());
+        assert!(parse_config(&SYSLOG.replace("local3", "local8")).is_err());
+
+        const LEVELS: &str = r#"
+latitude = 0.0
+longitude = 0.0
+
+[log.modules]
+drmem_db_redis = "debug"
+
+[[driver]]
+name = "tplink"
+prefix = "plug-porch"
+log_level = "trace"
+"#;
+
+        match parse_config(LEVELS) {
+            Ok(cfg) => {
                 assert_eq!(
-                    cfg.graphql.pref_host,
-                    Some("www.google.com".into())
+                    cfg.log.modules.get("drmem_db_redis").map(String::as_str),
+                    Some("debug")
                 );
-                assert_eq!(cfg.graphql.pref_port, 3000)
+                assert_eq!(cfg.driver[0].log_level.as_deref(), Some("trace"));
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
+
+        assert!(parse_config(&LEVELS.replace("\"debug", "\"loud")).is_err());
+        assert!(parse_config(&LEVELS.replace("\"trace", "\"loud")).is_err());
     }
 
     #[test]
@@ -597,6 +2695,45 @@ max_history = 10000
             }
             Err(e) => panic!("TOML parse error: {}", e),
         }
+
+        // Simulated values have to be device values.
+
+        match toml::from_str::<Config>(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[[driver]]
+name = "tplink"
+prefix = "porch:light"
+
+[driver.simulate]
+brightness = 50.0
+"#,
+        ) {
+            Ok(cfg) => {
+                let values = cfg.driver[0].simulate.as_ref().unwrap();
+
+                assert_eq!(values["brightness"], toml::Value::Float(50.0));
+                assert!(!cfg.simulate);
+            }
+            Err(e) => panic!("TOML parse error: {}", e),
+        }
+
+        assert!(parse_config(
+            r#"
+latitude = -45.0
+longitude = 45.0
+
+[[driver]]
+name = "tplink"
+prefix = "porch:light"
+
+[driver.simulate]
+brightness = [1, 2]
+"#,
+        )
+        .is_err());
     }
 
     #[test]
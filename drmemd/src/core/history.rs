@@ -0,0 +1,131 @@
+// Decides how much history the backend keeps for each device. The
+// top-level `max_history` and `max_age` apply to every device. A
+// `[[driver]]` entry overrides them for the devices of its instance;
+// its values arrive with the requests that register the devices. The
+// first `[[device]]` entry that matches a device, and gives either
+// value, has the last word; what it leaves out comes from the
+// instance or the defaults.
+
+use crate::{config, glob};
+use drmem_api::device;
+use std::time::Duration;
+
+struct Rule {
+    pattern: glob::Pattern,
+    max_history: Option<usize>,
+    max_age: Option<Duration>,
+}
+
+#[derive(Default)]
+pub struct Limits {
+    max_history: Option<usize>,
+    max_age: Option<Duration>,
+    rules: Vec<Rule>,
+}
+
+impl Limits {
+    pub fn new(cfg: &config::Config) -> Self {
+        Limits {
+            max_history: cfg.max_history,
+            max_age: cfg.get_max_age(),
+            rules: cfg
+                .device
+                .iter()
+                .filter(|v| v.max_history.is_some() || v.max_age.is_some())
+                .map(|v| Rule {
+                    pattern: glob::Pattern::create(&v.pattern),
+                    max_history: v.max_history,
+                    max_age: v.max_age.map(Duration::from_secs_f64),
+                })
+                .collect(),
+        }
+    }
+
+    // Returns the number of readings, and how long, device `name`
+    // keeps. `max_history` and `max_age` are the values its driver
+    // instance asked for.
+
+    pub fn get(
+        &self,
+        name: &device::Name,
+        max_history: Option<usize>,
+        max_age: Option<Duration>,
+    ) -> (Option<usize>, Option<Duration>) {
+        let name = name.to_string();
+        let rule = self.rules.iter().find(|v| v.pattern.matches(&name));
+
+        (
+            rule.and_then(|v| v.max_history)
+                .or(max_history)
+                .or(self.max_history),
+            rule.and_then(|v| v.max_age).or(max_age).or(self.max_age),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let name = |v: &str| v.parse::<device::Name>().unwrap();
+        let hour = Some(Duration::from_secs(3_600));
+        let day = Some(Duration::from_secs(86_400));
+        let mut cfg = config::Config::default();
+
+        cfg.max_history = Some(100);
+        cfg.max_age = Some(86_400.0);
+
+        for (pattern, max_history, max_age) in [
+            ("sump:*", None, None),
+            ("weather:*", Some(5_000), None),
+            ("*:temp", None, Some(3_600.0)),
+        ] {
+            cfg.device.push(config::Device {
+                pattern: pattern.into(),
+                min_report_interval: Some(1.0),
+                deadband: None,
+                readings: None,
+                policy: None,
+                max_history,
+                max_age,
+            });
+        }
+
+        let limits = Limits::new(&cfg);
+
+        // The top-level values are used when nothing overrides them.
+
+        assert_eq!(
+            limits.get(&name("room:light"), None, None),
+            (Some(100), day)
+        );
+
+        // The driver instance's values override them.
+
+        assert_eq!(
+            limits.get(&name("room:light"), Some(10), hour),
+            (Some(10), hour)
+        );
+
+        // A `[[device]]` entry without history values doesn't hide
+        // the ones after it.
+
+        assert_eq!(
+            limits.get(&name("sump:temp"), None, None),
+            (Some(100), hour)
+        );
+
+        // The first matching entry overrides only what it gives.
+
+        assert_eq!(
+            limits.get(&name("weather:temp"), Some(10), None),
+            (Some(5_000), day)
+        );
+
+        let limits = Limits::default();
+
+        assert_eq!(limits.get(&name("room:light"), None, None), (None, None));
+    }
+}
@@ -1,10 +1,18 @@
-use crate::backends::{store, Store};
-use drmem_api::{client, driver, Error, Result};
+use crate::{
+    backends::{channels::Capacities, store, Store},
+    discovery, events,
+};
+use drmem_api::{client, device, driver, Error, Result};
 use std::convert::Infallible;
 use tokio::{sync::mpsc, task::JoinHandle};
 use tracing::{info, info_span, warn};
 use tracing_futures::Instrument;
 
+mod history;
+mod throttle;
+pub mod unresponsive;
+mod view;
+
 /// Holds the state of the core task in the framework.
 ///
 /// The core task starts-up the necessary drivers and maintains a
@@ -12,14 +20,31 @@ use tracing_futures::Instrument;
 /// core task through channels.
 struct State {
     backend: Box<dyn Store + Send>,
+    limits: history::Limits,
+    throttles: throttle::Throttles,
+    discovery: discovery::RequestChan,
+    view: view::View,
 }
 
 impl State {
     /// Creates an initialized state for the core task.
-    async fn create(cfg: store::config::Config) -> Result<Self> {
-        let backend = Box::new(store::open(&cfg).await?);
+    async fn create(
+        cfg: store::config::Config,
+        caps: Capacities,
+        limits: history::Limits,
+        throttles: throttle::Throttles,
+        discovery: discovery::RequestChan,
+        view: view::View,
+    ) -> Result<Self> {
+        let backend = Box::new(store::open(&cfg, caps).await?);
 
-        Ok(State { backend })
+        Ok(State {
+            backend,
+            limits,
+            throttles,
+            discovery,
+            view,
+        })
     }
 
     /// Handles incoming requests and returns a reply.
@@ -30,8 +55,11 @@ impl State {
                 ref dev_name,
                 ref dev_units,
                 max_history,
+                max_age,
                 rpy_chan,
             } => {
+                let (max_history, max_age) =
+                    self.limits.get(dev_name, max_history, max_age);
                 let result = self
                     .backend
                     .register_read_only_device(
@@ -39,10 +67,16 @@ impl State {
                         dev_name,
                         dev_units.as_ref(),
                         max_history,
+                        max_age,
                     )
                     .await
+                    .map(|v| self.throttles.wrap(dev_name, v))
                     .map_err(|_| Error::DeviceDefined(format!("{}", dev_name)));
 
+                if result.is_ok() {
+                    Self::registered(driver_name, dev_name)
+                }
+
                 if rpy_chan.send(result).is_err() {
                     warn!("driver exited before a reply could be sent")
                 }
@@ -53,8 +87,11 @@ impl State {
                 ref dev_name,
                 ref dev_units,
                 max_history,
+                max_age,
                 rpy_chan,
             } => {
+                let (max_history, max_age) =
+                    self.limits.get(dev_name, max_history, max_age);
                 let result = self
                     .backend
                     .register_read_write_device(
@@ -62,17 +99,39 @@ impl State {
                         dev_name,
                         dev_units.as_ref(),
                         max_history,
+                        max_age,
                     )
                     .await
+                    .map(|(v, rx, prev)| {
+                        (self.throttles.wrap(dev_name, v), rx, prev)
+                    })
                     .map_err(|_| Error::DeviceDefined(format!("{}", dev_name)));
 
+                if result.is_ok() {
+                    Self::registered(driver_name, dev_name)
+                }
+
                 if rpy_chan.send(result).is_err() {
                     warn!("driver exited before a reply could be sent")
                 }
             }
+
+            driver::Request::Discover { service, rpy_chan } => {
+                self.discovery.discover(service, rpy_chan).await
+            }
         }
     }
 
+    // Announces a device that was registered by a driver.
+
+    fn registered(driver_name: &str, dev_name: &device::Name) {
+        events::publish(
+            events::Kind::DeviceRegistered,
+            dev_name.to_string(),
+            format!("registered by the {} driver", driver_name),
+        )
+    }
+
     async fn handle_client_request(&mut self, req: client::Request) {
         match req {
             client::Request::QueryDeviceInfo { pattern, rpy_chan } => {
@@ -92,13 +151,26 @@ impl State {
                 name,
                 value,
                 rpy_chan,
-            } => {
-                let fut = self.backend.set_device(name, value);
+            } => match self.backend.set_device(name.clone(), value).await {
+                // The reply is awaited in its own task, so a slow
+                // driver doesn't hold up the core. A wedged driver is
+                // given a limited time.
+                Ok(setting) => {
+                    tokio::spawn(async move {
+                        let result =
+                            unresponsive::wait(Some(&name), setting).await;
 
-                if rpy_chan.send(fut.await).is_err() {
-                    warn!("client exited before a reply could be sent")
+                        if rpy_chan.send(result).is_err() {
+                            warn!("client exited before a reply could be sent")
+                        }
+                    });
                 }
-            }
+                Err(e) => {
+                    if rpy_chan.send(Err(e)).is_err() {
+                        warn!("client exited before a reply could be sent")
+                    }
+                }
+            },
 
             client::Request::GetSettingChan {
                 name,
@@ -124,6 +196,85 @@ impl State {
                     warn!("client exited before a reply could be sent")
                 }
             }
+
+            client::Request::SetDeviceMeta {
+                name,
+                update,
+                rpy_chan,
+            } => {
+                let fut = self.backend.set_device_meta(&name, update);
+
+                if rpy_chan.send(fut.await).is_err() {
+                    warn!("client exited before a reply could be sent")
+                }
+            }
+
+            client::Request::Ping { rpy_chan } => {
+                if rpy_chan.send(self.backend.ping().await).is_err() {
+                    warn!("client exited before a reply could be sent")
+                }
+            }
+
+            client::Request::MonitorDevices { names, rpy_chan } => {
+                let fut = self.backend.monitor_devices(names);
+
+                if rpy_chan.send(fut.await).is_err() {
+                    warn!("client exited before a reply could be sent")
+                }
+            }
+
+            client::Request::AggregateHistory {
+                name,
+                start,
+                end,
+                interval,
+                rpy_chan,
+            } => {
+                match self
+                    .backend
+                    .aggregate_history(name, start, end, interval)
+                    .await
+                {
+                    Ok(summary) => {
+                        tokio::spawn(async move {
+                            if rpy_chan.send(summary.await).is_err() {
+                                warn!(
+                                    "client exited before a reply could be sent"
+                                )
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        if rpy_chan.send(Err(e)).is_err() {
+                            warn!("client exited before a reply could be sent")
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Handles a request from one of the client APIs. Only the
+    // devices allowed by the `[clients]` section can be used.
+
+    async fn handle_external_request(&mut self, req: client::Request) {
+        match req {
+            client::Request::QueryDeviceInfo { pattern, rpy_chan } => {
+                let result = self
+                    .backend
+                    .get_device_info(pattern.as_deref())
+                    .await
+                    .map(|v| self.view.filter(v));
+
+                if rpy_chan.send(result).is_err() {
+                    warn!("client exited before a reply could be sent")
+                }
+            }
+            req => {
+                if let Some(req) = self.view.screen(req) {
+                    self.handle_client_request(req).await
+                }
+            }
         }
     }
 
@@ -134,7 +285,10 @@ impl State {
         mut self,
         mut rx_drv_req: mpsc::Receiver<driver::Request>,
         mut rx_clnt_req: mpsc::Receiver<client::Request>,
+        mut rx_ext_req: mpsc::Receiver<client::Request>,
     ) -> Result<Infallible> {
+        let mut watchdog = crate::systemd::Watchdog::new();
+
         info!("starting");
         loop {
             #[rustfmt::skip]
@@ -149,6 +303,12 @@ impl State {
 		    .handle_client_request(req)
 		    .instrument(info_span!("client_req"))
 		    .await,
+		Some(req) = rx_ext_req.recv() =>
+                    self
+		    .handle_external_request(req)
+		    .instrument(info_span!("client_req"))
+		    .await,
+		_ = watchdog.tick() => watchdog.pet(),
 		else => break
             }
         }
@@ -162,32 +322,101 @@ impl State {
 
 /// Starts the core task. Returns an `mpsc::Sender<>` handle so other
 /// tasks can send requests to it.
+///
+/// Two client handles are returned. The first is for `drmemd`'s own
+/// tasks, like the logic blocks, and can use every device. The second
+/// is for the client APIs and is limited by the `[clients]` section.
 
 pub async fn start(
     cfg: &super::config::Config,
 ) -> Result<(
     mpsc::Sender<driver::Request>,
     client::RequestChan,
+    client::RequestChan,
     JoinHandle<Result<Infallible>>,
 )> {
     // Create a channel that drivers can use to make requests to the
     // framework. This task will hang onto the Receiver end and each
     // driver will get a .clone() of the transmit handle.
 
-    let (tx_drv_req, rx_drv_req) = mpsc::channel(10);
-    let (tx_clnt_req, rx_clnt_req) = mpsc::channel(10);
+    let (tx_drv_req, rx_drv_req) = mpsc::channel(cfg.channels.requests);
+    let (tx_clnt_req, rx_clnt_req) = mpsc::channel(cfg.channels.requests);
+    let (tx_ext_req, rx_ext_req) = mpsc::channel(cfg.channels.requests);
+    let view = view::View::new(&cfg.clients);
     let be_cfg = cfg.get_backend().clone();
+    let caps = Capacities::new(cfg);
+    let limits = history::Limits::new(cfg);
+    let throttles = throttle::Throttles::new(&cfg.device);
 
     Ok((
         tx_drv_req,
         client::RequestChan::new(tx_clnt_req),
+        client::RequestChan::new(tx_ext_req),
         tokio::spawn(async {
-            let state = State::create(be_cfg).await?;
+            let state = State::create(
+                be_cfg,
+                caps,
+                limits,
+                throttles,
+                discovery::start(),
+                view,
+            )
+            .await?;
 
             state
-                .run(rx_drv_req, rx_clnt_req)
+                .run(rx_drv_req, rx_clnt_req, rx_ext_req)
                 .instrument(info_span!("drmem"))
                 .await
         }),
     ))
 }
+
+// The tests use the simple backend, which doesn't need a server.
+
+#[cfg(all(test, feature = "simple-backend"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A driver that doesn't reply to a setting mustn't hold up the
+    // requests that come after it.
+
+    #[tokio::test]
+    async fn test_slow_driver() {
+        let (tx_drv, clnt, _, _core) =
+            start(&crate::config::Config::default()).await.unwrap();
+        let drv = driver::RequestChan::new(
+            "test".into(),
+            &"test".parse().unwrap(),
+            &tx_drv,
+        );
+        let _slow = drv
+            .add_rw_device::<bool>("slow".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+
+        tokio::spawn({
+            let clnt = clnt.clone();
+
+            async move { clnt.set_device("test:slow".parse().unwrap(), true).await }
+        });
+
+        let fast = tokio::time::timeout(
+            Duration::from_secs(1),
+            drv.add_ro_device::<bool>("fast".parse().unwrap(), None, None),
+        )
+        .await;
+
+        assert!(matches!(fast, Ok(Ok(_))));
+
+        let info = tokio::time::timeout(
+            Duration::from_secs(1),
+            clnt.get_device_info(Some("test:*".into())),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(info.len(), 2);
+    }
+}
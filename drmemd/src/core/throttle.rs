@@ -0,0 +1,299 @@
+// Tames devices whose drivers report more often than needed. A
+// `[[device]]` entry in the configuration matches devices by name
+// pattern and can give:
+//
+//     min_report_interval  seconds that have to pass between readings
+//     deadband             how much a numeric reading has to change
+//                          before it's saved
+//
+// The limits are applied to the function the core hands a driver for
+// reporting readings, so they work the same for every driver and
+// backend. A reading that arrives before the interval has passed is
+// held and saved when it ends, so the latest value isn't lost. The
+// first entry that matches a device, and gives either limit, is used.
+
+use crate::{config, glob};
+use drmem_api::{device, driver::ReportReading};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+#[derive(Clone)]
+struct Rule {
+    pattern: glob::Pattern,
+    interval: Option<Duration>,
+    deadband: Option<f64>,
+}
+
+#[derive(Default)]
+struct State {
+    last: Option<(Instant, device::Value)>,
+    pending: Option<device::Value>,
+    scheduled: bool,
+}
+
+enum Action {
+    Report,
+    Drop,
+    // Hold the reading until the given time.
+    Hold(Instant),
+}
+
+impl Rule {
+    // Returns `true` if the value is close enough to the last reading
+    // that it shouldn't be saved.
+
+    fn within_deadband(
+        &self,
+        prev: &device::Value,
+        value: &device::Value,
+    ) -> bool {
+        let as_flt = |v: &device::Value| match v {
+            device::Value::Int(v) => Some(*v as f64),
+            device::Value::Flt(v) => Some(*v),
+            _ => None,
+        };
+
+        match (self.deadband, as_flt(prev), as_flt(value)) {
+            (Some(db), Some(prev), Some(v)) => (v - prev).abs() < db,
+            _ => false,
+        }
+    }
+
+    fn action(
+        &self,
+        state: &State,
+        value: &device::Value,
+        now: Instant,
+    ) -> Action {
+        let Some((stamp, prev)) = &state.last else {
+            return Action::Report;
+        };
+
+        if self.within_deadband(prev, value) {
+            return Action::Drop;
+        }
+
+        match self.interval {
+            Some(interval) if now < *stamp + interval => {
+                Action::Hold(*stamp + interval)
+            }
+            _ => Action::Report,
+        }
+    }
+}
+
+type ReportFut = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// Saves the held reading, if there's still one, once the interval
+// has passed.
+
+async fn flush(
+    report: Arc<ReportReading>,
+    state: Arc<Mutex<State>>,
+    deadline: Instant,
+) {
+    tokio::time::sleep_until(deadline).await;
+
+    let value = {
+        let mut state = state.lock().unwrap();
+
+        let value = state.pending.take();
+
+        state.scheduled = false;
+        if let Some(v) = &value {
+            state.last = Some((Instant::now(), v.clone()))
+        }
+        value
+    };
+
+    if let Some(v) = value {
+        report(v).await
+    }
+}
+
+#[derive(Default)]
+pub struct Throttles(Vec<Rule>);
+
+impl Throttles {
+    pub fn new(cfg: &[config::Device]) -> Self {
+        Throttles(
+            cfg.iter()
+                .filter(|v| {
+                    v.min_report_interval.is_some() || v.deadband.is_some()
+                })
+                .map(|v| Rule {
+                    pattern: glob::Pattern::create(&v.pattern),
+                    interval: v
+                        .min_report_interval
+                        .map(Duration::from_secs_f64),
+                    deadband: v.deadband,
+                })
+                .collect(),
+        )
+    }
+
+    // Wraps the function that reports the readings of device `name`
+    // so its limits are applied. Devices without limits get `report`
+    // back.
+
+    pub fn wrap(
+        &self,
+        name: &device::Name,
+        report: ReportReading,
+    ) -> ReportReading {
+        let name = name.to_string();
+        let Some(rule) = self.0.iter().find(|v| v.pattern.matches(&name))
+        else {
+            return report;
+        };
+        let rule = rule.clone();
+        let report = Arc::new(report);
+        let state = Arc::new(Mutex::new(State::default()));
+
+        Box::new(move |value: device::Value| -> ReportFut {
+            let now = Instant::now();
+            let mut guard = state.lock().unwrap();
+
+            match rule.action(&guard, &value, now) {
+                Action::Report => {
+                    guard.pending = None;
+                    guard.last = Some((now, value.clone()));
+                    report(value)
+                }
+                Action::Drop => {
+                    guard.pending = None;
+                    Box::pin(async {})
+                }
+                Action::Hold(deadline) => {
+                    guard.pending = Some(value);
+                    if !guard.scheduled {
+                        guard.scheduled = true;
+                        tokio::spawn(flush(
+                            report.clone(),
+                            state.clone(),
+                            deadline,
+                        ));
+                    }
+                    Box::pin(async {})
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttles(interval: Option<f64>, deadband: Option<f64>) -> Throttles {
+        Throttles::new(&[config::Device {
+            pattern: "weather:*".into(),
+            min_report_interval: interval,
+            deadband,
+            readings: None,
+            policy: None,
+            max_history: None,
+            max_age: None,
+        }])
+    }
+
+    // Returns a report function that saves the readings in `log`.
+
+    fn recorder(log: &Arc<Mutex<Vec<device::Value>>>) -> ReportReading {
+        let log = log.clone();
+
+        Box::new(move |v| {
+            log.lock().unwrap().push(v);
+            Box::pin(async {})
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let report = throttles(Some(10.0), None)
+            .wrap(&"weather:temp".parse().unwrap(), recorder(&log));
+
+        report(1.0.into()).await;
+        report(2.0.into()).await;
+        report(3.0.into()).await;
+        assert_eq!(*log.lock().unwrap(), vec![device::Value::Flt(1.0)]);
+
+        // The latest held reading is saved when the interval ends.
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![device::Value::Flt(1.0), device::Value::Flt(3.0)]
+        );
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        report(4.0.into()).await;
+        assert_eq!(log.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_deadband() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let report = throttles(None, Some(0.5))
+            .wrap(&"weather:temp".parse().unwrap(), recorder(&log));
+
+        report(20.0.into()).await;
+        report(20.4.into()).await;
+        report(19.6.into()).await;
+        report(20.5.into()).await;
+        report(true.into()).await;
+        report(true.into()).await;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                device::Value::Flt(20.0),
+                device::Value::Flt(20.5),
+                device::Value::Bool(true),
+                device::Value::Bool(true)
+            ]
+        );
+
+        // Devices that don't match aren't limited.
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let report = throttles(None, Some(0.5))
+            .wrap(&"porch:temp".parse().unwrap(), recorder(&log));
+
+        report(20.0.into()).await;
+        report(20.1.into()).await;
+        assert_eq!(log.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_first_match() {
+        let device = |pattern: &str, readings, deadband| config::Device {
+            pattern: pattern.into(),
+            min_report_interval: None,
+            deadband,
+            readings,
+            policy: None,
+            max_history: None,
+            max_age: None,
+        };
+
+        // An earlier entry without limits doesn't hide a later one.
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let report = Throttles::new(&[
+            device("*", Some(500), None),
+            device("weather:*", None, Some(0.5)),
+        ])
+        .wrap(&"weather:temp".parse().unwrap(), recorder(&log));
+
+        report(20.0.into()).await;
+        report(20.1.into()).await;
+        assert_eq!(*log.lock().unwrap(), vec![device::Value::Flt(20.0)]);
+    }
+}
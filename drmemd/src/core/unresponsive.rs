@@ -0,0 +1,138 @@
+// Limits how long a setting waits for a driver to reply. A driver
+// that's wedged would otherwise stall the client, or logic block,
+// making the setting forever. When a driver doesn't reply in time,
+// its device is remembered as unresponsive, which is logged and
+// published as a `DriverUnresponsive` event. The device is forgotten
+// when its driver replies to a later setting.
+
+use crate::events;
+use drmem_api::{device, Error, Result};
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{info, warn};
+
+// The timeout, in milliseconds. It's set by the `setting_timeout`
+// entry of the configuration.
+
+static TIMEOUT: AtomicU64 = AtomicU64::new(10_000);
+
+static DEVICES: LazyLock<Mutex<HashSet<device::Name>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+pub fn set_timeout(v: Duration) {
+    TIMEOUT.store(
+        u64::try_from(v.as_millis()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    )
+}
+
+fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT.load(Ordering::Relaxed))
+}
+
+// Returns the number of devices whose driver didn't reply to the last
+// setting.
+
+pub fn count() -> usize {
+    DEVICES.lock().map(|v| v.len()).unwrap_or(0)
+}
+
+// Records whether the driver of `dev` replied. Returns `true` if
+// this changed the device's state.
+
+fn update(dev: &device::Name, replied: bool) -> bool {
+    match DEVICES.lock() {
+        Ok(mut devs) if replied => devs.remove(dev),
+        Ok(mut devs) => devs.insert(dev.clone()),
+        Err(_) => false,
+    }
+}
+
+// Waits for `fut`, which makes a setting of `dev`, to complete. If
+// it takes longer than the timeout, `Error::TimeoutError` is
+// returned. Settings that aren't for a known device pass `None` and
+// aren't tracked.
+
+pub async fn wait<T>(
+    dev: Option<&device::Name>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let limit = timeout();
+    let result = tokio::time::timeout(limit, fut).await;
+
+    if let Some(dev) = dev {
+        let replied = result.is_ok();
+
+        if update(dev, replied) {
+            if replied {
+                info!("driver of {} is responding again", dev)
+            } else {
+                let message = format!(
+                    "driver of {} didn't reply within {:.1} seconds",
+                    dev,
+                    limit.as_secs_f64()
+                );
+
+                warn!("{}", &message);
+                events::publish(
+                    events::Kind::DriverUnresponsive,
+                    dev.to_string(),
+                    message,
+                )
+            }
+        }
+    }
+    result.unwrap_or(Err(Error::TimeoutError))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait() {
+        let dev: device::Name = "test:unresponsive".parse().unwrap();
+        let mut events = events::subscribe();
+
+        assert_eq!(wait(Some(&dev), async { Ok(1) }).await, Ok(1));
+        assert!(!DEVICES.lock().unwrap().contains(&dev));
+
+        // A driver that doesn't reply gets reported once.
+
+        for _ in 0..2 {
+            assert_eq!(
+                wait(Some(&dev), std::future::pending::<Result<()>>()).await,
+                Err(Error::TimeoutError)
+            );
+        }
+        assert!(DEVICES.lock().unwrap().contains(&dev));
+
+        // Other tests can publish events, so only look at the ones
+        // about this device.
+
+        let mut reports = 0;
+
+        while let Ok(ev) = events.try_recv() {
+            if ev.source == "test:unresponsive" {
+                assert_eq!(ev.kind, events::Kind::DriverUnresponsive);
+                reports += 1
+            }
+        }
+        assert_eq!(reports, 1);
+
+        // Replying, even with an error, makes it responsive again.
+
+        assert_eq!(
+            wait(Some(&dev), async { Err::<(), _>(Error::TypeError) }).await,
+            Err(Error::TypeError)
+        );
+        assert!(!DEVICES.lock().unwrap().contains(&dev));
+    }
+}
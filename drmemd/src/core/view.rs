@@ -0,0 +1,217 @@
+// Limits the client APIs to the devices picked by the `[clients]`
+// section. Their requests arrive on their own channel, so the core
+// can screen them before handling them like any other client
+// request. Hidden devices aren't listed and look like they don't
+// exist. Read-only devices are listed, as not settable, and refuse
+// settings.
+
+use crate::{config, glob};
+use drmem_api::{client, device, Error, Result};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+pub struct View {
+    visible: Vec<glob::Pattern>,
+    hidden: Vec<glob::Pattern>,
+    read_only: Vec<glob::Pattern>,
+}
+
+fn patterns(v: &[String]) -> Vec<glob::Pattern> {
+    v.iter().map(|v| glob::Pattern::create(v)).collect()
+}
+
+fn matches(patterns: &[glob::Pattern], name: &str) -> bool {
+    patterns.iter().any(|p| p.matches(name))
+}
+
+// Sends an error to a client.
+
+fn refuse<T>(rpy_chan: oneshot::Sender<Result<T>>, e: Error) {
+    if rpy_chan.send(Err(e)).is_err() {
+        warn!("client exited before a reply could be sent")
+    }
+}
+
+impl View {
+    pub fn new(cfg: &config::Clients) -> Self {
+        View {
+            visible: patterns(&cfg.visible),
+            hidden: patterns(&cfg.hidden),
+            read_only: patterns(&cfg.read_only),
+        }
+    }
+
+    fn shows(&self, name: &device::Name) -> bool {
+        let name = name.to_string();
+
+        matches(&self.visible, &name) && !matches(&self.hidden, &name)
+    }
+
+    fn settable(&self, name: &device::Name) -> bool {
+        self.shows(name) && !matches(&self.read_only, &name.to_string())
+    }
+
+    // Removes the hidden devices from a list and marks the read-only
+    // ones as not settable.
+
+    pub fn filter(
+        &self,
+        devs: Vec<client::DevInfoReply>,
+    ) -> Vec<client::DevInfoReply> {
+        devs.into_iter()
+            .filter(|v| self.shows(&v.name))
+            .map(|v| client::DevInfoReply {
+                settable: v.settable && self.settable(&v.name),
+                ..v
+            })
+            .collect()
+    }
+
+    // Checks a request. Requests that aren't allowed are answered
+    // with an error and `None` is returned. Otherwise the request is
+    // returned so it can be handled.
+
+    pub fn screen(&self, req: client::Request) -> Option<client::Request> {
+        use client::Request;
+
+        match req {
+            Request::SetDevice { name, rpy_chan, .. }
+                if !self.settable(&name) =>
+            {
+                refuse(rpy_chan, self.read_only_error(&name))
+            }
+            Request::GetSettingChan { name, rpy_chan, .. }
+                if !self.settable(&name) =>
+            {
+                refuse(rpy_chan, self.read_only_error(&name))
+            }
+            Request::MonitorDevice { name, rpy_chan, .. }
+                if !self.shows(&name) =>
+            {
+                refuse(rpy_chan, Error::NotFound)
+            }
+            Request::SetDeviceMeta { name, rpy_chan, .. }
+                if !self.shows(&name) =>
+            {
+                refuse(rpy_chan, Error::NotFound)
+            }
+            Request::AggregateHistory { name, rpy_chan, .. }
+                if !self.shows(&name) =>
+            {
+                refuse(rpy_chan, Error::NotFound)
+            }
+            Request::MonitorDevices { names, rpy_chan }
+                if !names.iter().all(|v| self.shows(v)) =>
+            {
+                refuse(rpy_chan, Error::NotFound)
+            }
+            req => return Some(req),
+        }
+        None
+    }
+
+    // Hidden devices have to look like they don't exist.
+
+    fn read_only_error(&self, name: &device::Name) -> Error {
+        if self.shows(name) {
+            Error::OperationError(format!("{} is read-only", name))
+        } else {
+            Error::NotFound
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(visible: &[&str], hidden: &[&str], read_only: &[&str]) -> View {
+        let strs = |v: &[&str]| v.iter().map(|v| v.to_string()).collect();
+
+        View::new(&config::Clients {
+            visible: strs(visible),
+            hidden: strs(hidden),
+            read_only: strs(read_only),
+        })
+    }
+
+    fn name(v: &str) -> device::Name {
+        v.parse().unwrap()
+    }
+
+    #[test]
+    fn test_view() {
+        let all = View::new(&config::Clients::default());
+
+        assert!(all.shows(&name("logic:diag:count")));
+        assert!(all.settable(&name("logic:diag:count")));
+
+        let v = view(&["*"], &["logic:*", "*:latch"], &["drmem:*"]);
+
+        assert!(v.shows(&name("room:light:state")));
+        assert!(v.settable(&name("room:light:state")));
+        assert!(!v.shows(&name("logic:diag:count")));
+        assert!(!v.shows(&name("garage:door:latch")));
+        assert!(v.shows(&name("drmem:daemon:uptime")));
+        assert!(!v.settable(&name("drmem:daemon:uptime")));
+        assert!(!v.settable(&name("garage:door:latch")));
+
+        let v = view(&["room:*"], &[], &[]);
+
+        assert!(v.shows(&name("room:light:state")));
+        assert!(!v.shows(&name("garage:door:state")));
+    }
+
+    #[tokio::test]
+    async fn test_screen() {
+        let v = view(&["*"], &["logic:*"], &["drmem:*"]);
+
+        // Settings of hidden and read-only devices are refused.
+
+        for (dev, err) in [
+            ("logic:diag:count", Error::NotFound),
+            (
+                "drmem:daemon:uptime",
+                Error::OperationError(
+                    "drmem:daemon:uptime is read-only".into(),
+                ),
+            ),
+        ] {
+            let (tx, rx) = oneshot::channel();
+
+            assert!(v
+                .screen(client::Request::SetDevice {
+                    name: name(dev),
+                    value: device::Value::Int(1),
+                    rpy_chan: tx,
+                })
+                .is_none());
+            assert_eq!(rx.await.unwrap(), Err(err));
+        }
+
+        // Hidden devices can't be monitored, even in a group.
+
+        let (tx, rx) = oneshot::channel();
+
+        assert!(v
+            .screen(client::Request::MonitorDevices {
+                names: vec![name("room:light:state"), name("logic:x:y")],
+                rpy_chan: tx,
+            })
+            .is_none());
+        assert!(matches!(rx.await.unwrap(), Err(Error::NotFound)));
+
+        // Other requests are passed along.
+
+        let (tx, _rx) = oneshot::channel();
+
+        assert!(v
+            .screen(client::Request::MonitorDevice {
+                name: name("drmem:daemon:uptime"),
+                start: None,
+                end: None,
+                rpy_chan: tx,
+            })
+            .is_some());
+    }
+}
@@ -0,0 +1,288 @@
+// Finds devices on the local network for the drivers. One task owns
+// an mDNS socket and an SSDP socket and sends the searches for every
+// service a driver asked about, so drivers don't each bind their own
+// multicast sockets.
+//
+// The searches are sent when a driver first asks about a service and
+// then every `QUERY_INTERVAL`. The endpoints that are found are
+// remembered, so a driver that asks later, or is restarted, gets
+// them right away. An endpoint is only sent again when its address
+// or information changes.
+
+use drmem_api::{
+    driver::discovery::{mdns, Endpoint, Service},
+    Error, Result,
+};
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    time::{self, Duration},
+};
+use tracing::{debug, info_span, warn};
+use tracing_futures::Instrument;
+
+mod ssdp;
+
+const QUERY_INTERVAL: Duration = Duration::from_secs(300);
+
+// How many endpoints can wait in a driver's channel.
+
+const CHAN_SIZE: usize = 32;
+
+type Reply = oneshot::Sender<Result<mpsc::Receiver<Endpoint>>>;
+
+// A handle used to send requests to the discovery task.
+
+#[derive(Clone)]
+pub struct RequestChan(mpsc::Sender<(Service, Reply)>);
+
+impl RequestChan {
+    // Asks for the endpoints offering `service`. The reply, sent to
+    // `rpy_chan`, is the channel they're sent on.
+
+    pub async fn discover(&self, service: Service, rpy_chan: Reply) {
+        if let Err(e) = self.0.send((service, rpy_chan)).await {
+            let (_, rpy_chan) = e.0;
+
+            let _ = rpy_chan.send(Err(Error::MissingPeer(
+                "discovery service isn't running".into(),
+            )));
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    found: HashMap<Service, HashMap<String, Endpoint>>,
+    subscribers: HashMap<Service, Vec<mpsc::Sender<Endpoint>>>,
+}
+
+impl State {
+    // Adds a subscriber to a service and sends it the endpoints that
+    // have already been found.
+
+    fn subscribe(&mut self, service: &Service) -> mpsc::Receiver<Endpoint> {
+        let (tx, rx) = mpsc::channel(CHAN_SIZE);
+
+        for ep in self.found.get(service).into_iter().flat_map(|v| v.values()) {
+            let _ = tx.try_send(ep.clone());
+        }
+        self.subscribers
+            .entry(service.clone())
+            .or_default()
+            .push(tx);
+        rx
+    }
+
+    // Forgets the subscribers that dropped their channel and the
+    // services nobody is interested in.
+
+    fn prune(&mut self) {
+        self.subscribers.retain(|_, v| {
+            v.retain(|tx| !tx.is_closed());
+            !v.is_empty()
+        });
+        self.found.retain(|k, _| self.subscribers.contains_key(k));
+    }
+
+    // Saves an endpoint and, if it's new or changed, sends it to the
+    // subscribers of its service.
+
+    fn add(&mut self, ep: Endpoint) {
+        let Some(subs) = self.subscribers.get(&ep.service) else {
+            return;
+        };
+        let known = self.found.entry(ep.service.clone()).or_default();
+
+        if known.get(&ep.name) != Some(&ep) {
+            debug!("found {:?} at {}", &ep.name, &ep.addr);
+            for tx in subs {
+                if let Err(mpsc::error::TrySendError::Full(_)) =
+                    tx.try_send(ep.clone())
+                {
+                    warn!("a driver isn't reading discovered endpoints")
+                }
+            }
+            known.insert(ep.name.clone(), ep);
+        }
+    }
+
+    fn mdns_services(&self) -> impl Iterator<Item = &str> {
+        self.subscribers.keys().filter_map(|v| match v {
+            Service::Mdns(v) => Some(v.as_str()),
+            Service::Ssdp(_) => None,
+        })
+    }
+}
+
+async fn bind(proto: &str) -> Option<UdpSocket> {
+    match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(sock) => Some(sock),
+        Err(e) => {
+            warn!("couldn't create {} socket -- {}", proto, e);
+            None
+        }
+    }
+}
+
+// Sends the search for a service.
+
+async fn search(
+    mdns: &Option<UdpSocket>,
+    ssdp: &Option<UdpSocket>,
+    service: &Service,
+) {
+    let result = match (service, mdns, ssdp) {
+        (Service::Mdns(v), Some(sock), _) => {
+            sock.send_to(&mdns::query(v), mdns::ADDR).await
+        }
+        (Service::Ssdp(v), _, Some(sock)) => {
+            sock.send_to(&ssdp::query(v), ssdp::ADDR).await
+        }
+        _ => return,
+    };
+
+    if let Err(e) = result {
+        warn!("couldn't search for {:?} -- {}", service, e)
+    }
+}
+
+// Waits for a packet on a socket. If the socket couldn't be created,
+// it waits forever.
+
+async fn recv(
+    sock: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match sock {
+        Some(sock) => sock.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn run(mut rx: mpsc::Receiver<(Service, Reply)>) {
+    let mdns = bind("mDNS").await;
+    let ssdp = bind("SSDP").await;
+    let mut state = State::default();
+    let mut interval = time::interval(QUERY_INTERVAL);
+    let mut mdns_buf = [0u8; 9000];
+    let mut ssdp_buf = [0u8; 2048];
+
+    loop {
+        tokio::select! {
+            req = rx.recv() => {
+                let Some((service, rpy_chan)) = req else {
+                    break;
+                };
+                let available = match service {
+                    Service::Mdns(_) => mdns.is_some(),
+                    Service::Ssdp(_) => ssdp.is_some(),
+                };
+
+                if available {
+                    let first = !state.subscribers.contains_key(&service);
+                    let _ = rpy_chan.send(Ok(state.subscribe(&service)));
+
+                    if first {
+                        search(&mdns, &ssdp, &service).await
+                    }
+                } else {
+                    let _ = rpy_chan.send(Err(Error::OperationError(
+                        "discovery isn't available".into(),
+                    )));
+                }
+            }
+
+            _ = interval.tick() => {
+                state.prune();
+                for service in state.subscribers.keys() {
+                    search(&mdns, &ssdp, service).await
+                }
+            }
+
+            Ok((len, from)) = recv(&mdns, &mut mdns_buf) => {
+                let found: Vec<Endpoint> = state
+                    .mdns_services()
+                    .flat_map(|v| {
+                        mdns::endpoints(&mdns_buf[..len], v, from.ip())
+                    })
+                    .collect();
+
+                for ep in found {
+                    state.add(ep)
+                }
+            }
+
+            Ok((len, from)) = recv(&ssdp, &mut ssdp_buf) => {
+                if let Some(ep) = ssdp::endpoint(&ssdp_buf[..len], from) {
+                    state.add(ep)
+                }
+            }
+        }
+    }
+}
+
+// Starts the discovery task and returns the handle used to make
+// requests of it.
+
+pub fn start() -> RequestChan {
+    let (tx, rx) = mpsc::channel(10);
+
+    tokio::spawn(run(rx).instrument(info_span!("discovery")));
+    RequestChan(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(name: &str, port: u16) -> Endpoint {
+        Endpoint {
+            service: Service::Mdns("_hue._tcp.local".into()),
+            name: name.into(),
+            addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            info: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let service = Service::Mdns("_hue._tcp.local".into());
+        let mut state = State::default();
+
+        // Endpoints nobody asked for are dropped.
+
+        state.add(endpoint("a", 80));
+
+        let mut rx = state.subscribe(&service);
+
+        assert!(rx.try_recv().is_err());
+
+        // Only new or changed endpoints are sent.
+
+        state.add(endpoint("a", 80));
+        state.add(endpoint("a", 80));
+        state.add(endpoint("a", 81));
+        assert_eq!(rx.try_recv().unwrap(), endpoint("a", 80));
+        assert_eq!(rx.try_recv().unwrap(), endpoint("a", 81));
+        assert!(rx.try_recv().is_err());
+
+        // Later subscribers get the endpoints that were found.
+
+        let mut rx2 = state.subscribe(&service);
+
+        assert_eq!(rx2.try_recv().unwrap(), endpoint("a", 81));
+
+        // Services are forgotten once their subscribers are gone.
+
+        drop(rx);
+        drop(rx2);
+        state.prune();
+        assert!(state.subscribers.is_empty());
+        assert!(state.found.is_empty());
+    }
+}
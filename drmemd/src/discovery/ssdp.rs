@@ -0,0 +1,125 @@
+// Builds SSDP searches and reads the replies. Devices reply, with
+// unicast, to the socket that sent the search.
+
+use drmem_api::driver::discovery::{Endpoint, Service};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+pub const ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+// How many seconds devices can wait before replying.
+
+const MX: u32 = 2;
+
+// Builds the search for a target.
+
+pub fn query(target: &str) -> Vec<u8> {
+    format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: {}\r\n\
+         ST: {}\r\n\r\n",
+        ADDR, MX, target
+    )
+    .into_bytes()
+}
+
+// Returns the address in a `LOCATION` URL, like
+// "http://10.0.0.5:49152/desc.xml".
+
+fn location_addr(url: &str) -> Option<SocketAddr> {
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest.split('/').next()?;
+
+    host.parse().ok().or_else(|| {
+        let port = if scheme.eq_ignore_ascii_case("https") {
+            443
+        } else {
+            80
+        };
+
+        host.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, port))
+    })
+}
+
+// Returns the endpoint described by a reply that came from `from`.
+// The address comes from the `LOCATION` header, if it has one.
+
+pub fn endpoint(pkt: &[u8], from: SocketAddr) -> Option<Endpoint> {
+    let text = std::str::from_utf8(pkt).ok()?;
+    let mut lines = text.split("\r\n");
+
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+
+    let info: HashMap<String, String> = lines
+        .take_while(|v| !v.is_empty())
+        .filter_map(|v| v.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+
+    Some(Endpoint {
+        service: Service::Ssdp(info.get("st")?.clone()),
+        name: info.get("usn")?.clone(),
+        addr: info
+            .get("location")
+            .and_then(|v| location_addr(v))
+            .unwrap_or(from),
+        info,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply() {
+        const TARGET: &str = "urn:dial-multiscreen-org:service:dial:1";
+
+        assert!(String::from_utf8(query(TARGET))
+            .unwrap()
+            .contains("\r\nST: urn:dial-multiscreen-org:service:dial:1\r\n"));
+
+        let from: SocketAddr = "10.0.0.5:1900".parse().unwrap();
+        let reply = b"HTTP/1.1 200 OK\r\n\
+                      CACHE-CONTROL: max-age=1800\r\n\
+                      Location: http://10.0.0.5:8008/ssdp/device-desc.xml\r\n\
+                      ST: urn:dial-multiscreen-org:service:dial:1\r\n\
+                      USN: uuid:1234::urn:dial-multiscreen-org:service:dial:1\r\n\
+                      \r\n";
+        let found = endpoint(reply, from).unwrap();
+
+        assert_eq!(found.service, Service::Ssdp(TARGET.into()));
+        assert_eq!(
+            found.name,
+            "uuid:1234::urn:dial-multiscreen-org:service:dial:1"
+        );
+        assert_eq!(found.addr, "10.0.0.5:8008".parse().unwrap());
+        assert_eq!(found.info["cache-control"], "max-age=1800");
+
+        // Without a usable location, the sender's address is used.
+
+        let reply = b"HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n\
+                      USN: uuid:5678\r\nLOCATION: http://hue.local/\r\n\r\n";
+
+        assert_eq!(endpoint(reply, from).unwrap().addr, from);
+        assert_eq!(
+            location_addr("https://10.0.0.6/desc.xml"),
+            Some("10.0.0.6:443".parse().unwrap())
+        );
+
+        // Searches from other clients and incomplete replies are
+        // ignored.
+
+        assert!(endpoint(&query(TARGET), from).is_none());
+        assert!(endpoint(b"HTTP/1.1 200 OK\r\nST: x\r\n\r\n", from).is_none());
+    }
+}
@@ -23,7 +23,10 @@ pub struct Instance {
     enabled: Vec<device::Value>,
     state: CycleState,
     index: usize,
-    millis: time::Duration,
+    high_millis: time::Duration,
+    low_millis: time::Duration,
+    count: Option<u32>,
+    cycles: u32,
 }
 
 pub struct Devices {
@@ -39,13 +42,19 @@ impl Instance {
 
     pub const DESCRIPTION: &'static str = include_str!("drv_cycle.md");
 
-    /// Creates a new, idle `Instance`.
+    /// Creates a new, idle `Instance`. Values at even positions of
+    /// `enabled` are held for `high_millis` and values at odd
+    /// positions are held for `low_millis`. If `count` isn't `None`,
+    /// the instance returns to idle after cycling through `enabled`
+    /// that many times.
 
     pub fn new(
         enabled_at_boot: bool,
-        millis: time::Duration,
+        high_millis: time::Duration,
+        low_millis: time::Duration,
         disabled: device::Value,
         enabled: Vec<device::Value>,
+        count: Option<u32>,
     ) -> Instance {
         Instance {
             enabled_at_boot,
@@ -53,14 +62,21 @@ impl Instance {
             disabled,
             enabled,
             index: 0,
-            millis,
+            high_millis,
+            low_millis,
+            count,
+            cycles: 0,
         }
     }
 
-    // Validates the time duration from the driver configuration.
+    // Validates a time duration from the driver configuration. If
+    // the parameter isn't specified, `None` is returned.
 
-    fn get_cfg_millis(cfg: &DriverConfig) -> Result<time::Duration> {
-        match cfg.get("millis") {
+    fn get_cfg_duration(
+        cfg: &DriverConfig,
+        key: &str,
+    ) -> Result<Option<time::Duration>> {
+        match cfg.get(key) {
             Some(toml::value::Value::Integer(millis)) => {
                 // DrMem's official sample rate is 20 Hz, so the cycle
                 // shouldn't change faster than that. Limit the
@@ -71,19 +87,62 @@ impl Instance {
                 // drmem-api crate indicating the max sample rate?
 
                 if (50..=3_600_000).contains(millis) {
-                    Ok(time::Duration::from_millis(*millis as u64))
+                    Ok(Some(time::Duration::from_millis(*millis as u64)))
+                } else {
+                    Err(Error::ConfigError(format!("'{}' out of range", key)))
+                }
+            }
+            Some(_) => Err(Error::ConfigError(format!(
+                "'{}' config parameter should be an integer",
+                key
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Determines the high and low durations. The configuration can
+    // either specify `millis`, which is used for both, or it can
+    // specify both `high_millis` and `low_millis`.
+
+    fn get_cfg_millis(
+        cfg: &DriverConfig,
+    ) -> Result<(time::Duration, time::Duration)> {
+        let millis = Instance::get_cfg_duration(cfg, "millis")?;
+        let high = Instance::get_cfg_duration(cfg, "high_millis")?;
+        let low = Instance::get_cfg_duration(cfg, "low_millis")?;
+
+        match (millis, high, low) {
+            (Some(millis), None, None) => Ok((millis, millis)),
+            (None, Some(high), Some(low)) => Ok((high, low)),
+            (None, None, None) => Err(Error::ConfigError(String::from(
+                "missing 'millis' parameter in config",
+            ))),
+            (Some(_), _, _) => Err(Error::ConfigError(String::from(
+                "'millis' can't be used with 'high_millis' or 'low_millis'",
+            ))),
+            (None, _, _) => Err(Error::ConfigError(String::from(
+                "'high_millis' and 'low_millis' must both be specified",
+            ))),
+        }
+    }
+
+    // Validates the optional cycle count parameter.
+
+    fn get_cfg_count(cfg: &DriverConfig) -> Result<Option<u32>> {
+        match cfg.get("count") {
+            Some(toml::value::Value::Integer(count)) => {
+                if (1..=i64::from(u32::MAX)).contains(count) {
+                    Ok(Some(*count as u32))
                 } else {
                     Err(Error::ConfigError(String::from(
-                        "'millis' out of range",
+                        "'count' must be a positive integer",
                     )))
                 }
             }
             Some(_) => Err(Error::ConfigError(String::from(
-                "'millis' config parameter should be an integer",
-            ))),
-            None => Err(Error::ConfigError(String::from(
-                "missing 'millis' parameter in config",
+                "'count' config parameter should be an integer",
             ))),
+            None => Ok(None),
         }
     }
 
@@ -130,16 +189,46 @@ impl Instance {
         }
     }
 
+    // Returns how long the current output value should be held.
+
+    fn duration(&self) -> time::Duration {
+        if self.index.is_multiple_of(2) {
+            self.high_millis
+        } else {
+            self.low_millis
+        }
+    }
+
+    // Advances to the next value in the cycle. Returns the value with
+    // which to set the output or `None` if it remains unchanged. If
+    // the configured number of cycles has completed, the instance
+    // goes back to the Idle state and the output gets the disabled
+    // value.
+
     fn time_expired(&mut self) -> Option<device::Value> {
         match self.state {
             CycleState::Idle => None,
 
             CycleState::Cycling => {
-                let current = &self.enabled[self.index];
+                let current = self.index;
 
                 self.index = (self.index + 1) % self.enabled.len();
 
-                if &self.enabled[self.index] != current {
+                if self.index == 0 {
+                    self.cycles = self.cycles.saturating_add(1);
+
+                    if Some(self.cycles) == self.count {
+                        self.state = CycleState::Idle;
+
+                        return if self.enabled[current] != self.disabled {
+                            Some(self.disabled.clone())
+                        } else {
+                            None
+                        };
+                    }
+                }
+
+                if self.enabled[self.index] != self.enabled[current] {
                     Some(self.enabled[self.index].clone())
                 } else {
                     None
@@ -160,6 +249,7 @@ impl Instance {
                 if val {
                     self.state = CycleState::Cycling;
                     self.index = 0;
+                    self.cycles = 0;
 
                     let value = &self.enabled[self.index];
 
@@ -213,9 +303,10 @@ impl driver::API for Instance {
         Box::pin(async move {
             // Define the devices managed by this driver.
             //
-            // This first device is the output signal. It toggles
-            // between `false` and `true` at a rate determined by
-            // the `interval` config option.
+            // This first device is the output signal. It steps
+            // through the `enabled` values at a rate determined by
+            // the `millis` (or `high_millis` and `low_millis`)
+            // config options.
 
             let d_output =
                 core.add_ro_device(output_name, None, max_history).await?;
@@ -239,13 +330,18 @@ impl driver::API for Instance {
         let enabled_at_boot = Instance::get_cfg_enabled(cfg);
         let disabled = Instance::get_inactive_value(cfg);
         let enabled = Instance::get_active_values(cfg);
+        let count = Instance::get_cfg_count(cfg);
 
         let fut = async move {
+            let (high_millis, low_millis) = millis?;
+
             Ok(Box::new(Instance::new(
                 enabled_at_boot?,
-                millis?,
+                high_millis,
+                low_millis,
                 disabled?,
                 enabled?,
+                count?,
             )))
         };
 
@@ -257,9 +353,11 @@ impl driver::API for Instance {
         devices: Arc<Mutex<Self::DeviceSet>>,
     ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
         let fut = async move {
-            let mut timer = time::interval(self.millis);
+            let timer = time::sleep(self.duration());
             let mut devices = devices.lock().await;
 
+            tokio::pin!(timer);
+
             if self.enabled_at_boot {
                 self.state = CycleState::Cycling;
                 devices.d_enable.report_update(true).await;
@@ -280,16 +378,28 @@ impl driver::API for Instance {
                     // If the driver is in a timing cycle, add the
                     // sleep future to the list of futures to await.
 
-                    _ = timer.tick() => {
+                    _ = &mut timer => {
+			let was_cycling = self.state == CycleState::Cycling;
 
 			// If the timeout occurs, update the state and
-			// set the output to the inactive value.
+			// set the output to the next value.
 
 			if let Some(v) = self.time_expired() {
 			    debug!("state {:?} : timeout occurred -- output {}",
 				   &self.state, v);
 			    devices.d_output.report_update(v).await;
 			}
+
+			// If the cycle count was reached, the `enable`
+			// device reports that we're no longer enabled.
+
+			if was_cycling && self.state == CycleState::Idle {
+			    devices.d_enable.report_update(false).await;
+			}
+
+			let next = timer.deadline() + self.duration();
+
+			timer.as_mut().reset(next);
                     }
 
                     // Always look for settings. We're pattern
@@ -303,7 +413,9 @@ impl driver::API for Instance {
                         let (reset, out) = self.update_state(b);
 
                         if reset {
-			    timer.reset()
+			    timer.as_mut().reset(
+				time::Instant::now() + self.duration()
+			    )
                         }
 
                         reply(Ok(b));
@@ -356,7 +468,8 @@ mod tests {
             let inst = Instance::create_instance(&cfg).await.unwrap();
 
             assert_eq!(inst.enabled_at_boot, true);
-            assert_eq!(inst.millis, Duration::from_millis(500));
+            assert_eq!(inst.high_millis, Duration::from_millis(500));
+            assert_eq!(inst.low_millis, Duration::from_millis(500));
             assert_eq!(inst.disabled, device::Value::Bool(false));
             assert_eq!(
                 inst.enabled,
@@ -383,7 +496,8 @@ mod tests {
             let inst = Instance::create_instance(&cfg).await.unwrap();
 
             assert_eq!(inst.enabled_at_boot, false);
-            assert_eq!(inst.millis, Duration::from_millis(500));
+            assert_eq!(inst.high_millis, Duration::from_millis(500));
+            assert_eq!(inst.low_millis, Duration::from_millis(500));
             assert_eq!(inst.disabled, device::Value::Bool(false));
             assert_eq!(
                 inst.enabled,
@@ -453,6 +567,138 @@ mod tests {
 
             assert!(Instance::create_instance(&cfg).await.is_err());
         }
+
+        {
+            let mut cfg = DriverConfig::new();
+
+            cfg.insert(
+                "high_millis".to_owned(),
+                toml::value::Value::Integer(100),
+            );
+            cfg.insert(
+                "low_millis".to_owned(),
+                toml::value::Value::Integer(900),
+            );
+            cfg.insert("count".to_owned(), toml::value::Value::Integer(3));
+            cfg.insert(
+                "disabled".to_owned(),
+                toml::value::Value::Boolean(false),
+            );
+            cfg.insert(
+                "enabled".to_owned(),
+                toml::value::Value::Array(vec![
+                    toml::value::Value::Boolean(true),
+                    toml::value::Value::Boolean(false),
+                ]),
+            );
+
+            let inst = Instance::create_instance(&cfg).await.unwrap();
+
+            assert_eq!(inst.high_millis, Duration::from_millis(100));
+            assert_eq!(inst.low_millis, Duration::from_millis(900));
+            assert_eq!(inst.count, Some(3));
+
+            // Mixing `millis` with the high/low parameters is an
+            // error.
+
+            cfg.insert("millis".to_owned(), toml::value::Value::Integer(500));
+            assert!(Instance::create_instance(&cfg).await.is_err());
+
+            // Only specifying one of the high/low parameters is an
+            // error.
+
+            cfg.remove("millis");
+            cfg.remove("low_millis");
+            assert!(Instance::create_instance(&cfg).await.is_err());
+
+            // The count must be positive.
+
+            cfg.insert(
+                "low_millis".to_owned(),
+                toml::value::Value::Integer(900),
+            );
+            cfg.insert("count".to_owned(), toml::value::Value::Integer(0));
+            assert!(Instance::create_instance(&cfg).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_durations() {
+        let mut timer = Instance::new(
+            false,
+            time::Duration::from_millis(100),
+            time::Duration::from_millis(900),
+            device::Value::Int(0),
+            vec![
+                device::Value::Int(1),
+                device::Value::Int(2),
+                device::Value::Int(3),
+            ],
+            None,
+        );
+
+        // Values at even positions use the high duration while values
+        // at odd positions use the low duration.
+
+        assert_eq!((true, Some((1).into())), timer.update_state(true));
+        assert_eq!(timer.duration(), time::Duration::from_millis(100));
+        assert_eq!(Some((2).into()), timer.time_expired());
+        assert_eq!(timer.duration(), time::Duration::from_millis(900));
+        assert_eq!(Some((3).into()), timer.time_expired());
+        assert_eq!(timer.duration(), time::Duration::from_millis(100));
+        assert_eq!(Some((1).into()), timer.time_expired());
+        assert_eq!(timer.duration(), time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_count() {
+        let mut timer = Instance::new(
+            false,
+            time::Duration::from_millis(100),
+            time::Duration::from_millis(900),
+            device::Value::Bool(false),
+            vec![device::Value::Bool(true), device::Value::Bool(false)],
+            Some(2),
+        );
+
+        // Verify the output cycles twice and then returns to the Idle
+        // state. Since the last value in the cycle equals the
+        // disabled value, no final output is emitted.
+
+        assert_eq!((true, Some(true.into())), timer.update_state(true));
+        assert_eq!(Some(false.into()), timer.time_expired());
+        assert_eq!(Some(true.into()), timer.time_expired());
+        assert_eq!(Some(false.into()), timer.time_expired());
+        assert_eq!(None, timer.time_expired());
+        assert_eq!(timer.state, CycleState::Idle);
+        assert_eq!(None, timer.time_expired());
+
+        // Re-enabling restarts the count.
+
+        assert_eq!((true, Some(true.into())), timer.update_state(true));
+        assert_eq!(Some(false.into()), timer.time_expired());
+        assert_eq!(Some(true.into()), timer.time_expired());
+        assert_eq!(Some(false.into()), timer.time_expired());
+        assert_eq!(None, timer.time_expired());
+        assert_eq!(timer.state, CycleState::Idle);
+
+        // If the last value of the cycle differs from the disabled
+        // value, returning to the Idle state emits the disabled
+        // value.
+
+        let mut timer = Instance::new(
+            false,
+            time::Duration::from_millis(100),
+            time::Duration::from_millis(100),
+            device::Value::Int(0),
+            vec![device::Value::Int(1), device::Value::Int(2)],
+            Some(1),
+        );
+
+        assert_eq!((true, Some((1).into())), timer.update_state(true));
+        assert_eq!(Some((2).into()), timer.time_expired());
+        assert_eq!(Some((0).into()), timer.time_expired());
+        assert_eq!(timer.state, CycleState::Idle);
     }
 
     #[test]
@@ -460,8 +706,10 @@ mod tests {
         let mut timer = Instance::new(
             false,
             time::Duration::from_millis(1000),
+            time::Duration::from_millis(1000),
             device::Value::Bool(false),
             vec![device::Value::Bool(true), device::Value::Bool(false)],
+            None,
         );
 
         // Verify that, when in the Idle state, an input of `false` or
@@ -498,12 +746,14 @@ mod tests {
         let mut timer = Instance::new(
             false,
             time::Duration::from_millis(1000),
+            time::Duration::from_millis(1000),
             device::Value::Int(0),
             vec![
                 device::Value::Int(1),
                 device::Value::Int(2),
                 device::Value::Int(3),
             ],
+            None,
         );
 
         // Verify that, when in the Idle state, an input of `false` or
@@ -545,12 +795,14 @@ mod tests {
         let mut timer = Instance::new(
             false,
             time::Duration::from_millis(1000),
+            time::Duration::from_millis(1000),
             device::Value::Bool(false),
             vec![
                 device::Value::Bool(true),
                 device::Value::Bool(false),
                 device::Value::Bool(false),
             ],
+            None,
         );
 
         // Verify that, when in the Idle state, an input of `false` or
@@ -589,12 +841,14 @@ mod tests {
         let mut timer = Instance::new(
             false,
             time::Duration::from_millis(1000),
+            time::Duration::from_millis(1000),
             device::Value::Bool(false),
             vec![
                 device::Value::Bool(false),
                 device::Value::Bool(true),
                 device::Value::Bool(false),
             ],
+            None,
         );
 
         // Verify that, when in the Idle state, an input of `false` or
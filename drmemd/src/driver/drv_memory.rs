@@ -6,7 +6,35 @@ use drmem_api::{
 use std::{convert::Infallible, future::Future, pin::Pin, sync::Arc};
 use tokio::sync::Mutex;
 
-pub struct Instance;
+// The types of values a memory device can be restricted to hold.
+
+#[derive(Debug, PartialEq)]
+enum MemoryType {
+    Bool,
+    Int,
+    Flt,
+    Str,
+    Color,
+}
+
+impl MemoryType {
+    // Returns `true` if the value is of this type.
+
+    fn accepts(&self, v: &device::Value) -> bool {
+        matches!(
+            (self, v),
+            (MemoryType::Bool, device::Value::Bool(_))
+                | (MemoryType::Int, device::Value::Int(_))
+                | (MemoryType::Flt, device::Value::Flt(_))
+                | (MemoryType::Str, device::Value::Str(_))
+                | (MemoryType::Color, device::Value::Color(_))
+        )
+    }
+}
+
+pub struct Instance {
+    mem_type: Option<MemoryType>,
+}
 
 pub struct Devices {
     d_memory: driver::ReadWriteDevice<device::Value>,
@@ -21,8 +49,15 @@ impl Instance {
 
     /// Creates a new `Instance` instance.
 
-    pub fn new() -> Instance {
-        Instance {}
+    fn new(mem_type: Option<MemoryType>) -> Instance {
+        Instance { mem_type }
+    }
+
+    // Returns `true` if the value can be stored in the memory
+    // device. If no type was configured, all values are accepted.
+
+    fn accepts(mem_type: &Option<MemoryType>, v: &device::Value) -> bool {
+        mem_type.as_ref().map(|t| t.accepts(v)).unwrap_or(true)
     }
 
     // Gets the name of the device from the configuration.
@@ -47,12 +82,49 @@ impl Instance {
         }
     }
 
+    // Gets the optional type of the device from the configuration.
+
+    fn get_cfg_type(cfg: &DriverConfig) -> Result<Option<MemoryType>> {
+        match cfg.get("type") {
+            Some(toml::value::Value::String(t)) => match t.as_str() {
+                "bool" => Ok(Some(MemoryType::Bool)),
+                "int" => Ok(Some(MemoryType::Int)),
+                "float" => Ok(Some(MemoryType::Flt)),
+                "string" => Ok(Some(MemoryType::Str)),
+                "color" => Ok(Some(MemoryType::Color)),
+                _ => Err(Error::ConfigError(format!(
+                    "'type' has an unknown value: {}",
+                    t
+                ))),
+            },
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'type' config parameter should be a string",
+            ))),
+            None => Ok(None),
+        }
+    }
+
     // Gets the initial value of the device from the configuration.
 
     fn get_cfg_init_val(cfg: &DriverConfig) -> Option<device::Value> {
         cfg.get("initial")
             .and_then(|v| device::Value::try_from(v).ok())
     }
+
+    // Determines the value the memory device should have at startup.
+    // The last value saved in the backend is preferred. If there is
+    // none, or it doesn't match the configured type, the initial
+    // value is used.
+
+    fn startup_value(
+        mem_type: &Option<MemoryType>,
+        last: Option<&device::Value>,
+        init_value: Option<device::Value>,
+    ) -> Option<device::Value> {
+        last.filter(|v| Instance::accepts(mem_type, v))
+            .cloned()
+            .or(init_value)
+    }
 }
 
 impl driver::API for Instance {
@@ -64,10 +136,23 @@ impl driver::API for Instance {
         max_history: Option<usize>,
     ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
         let name = Instance::get_cfg_name(cfg);
+        let mem_type = Instance::get_cfg_type(cfg);
         let init_value = Instance::get_cfg_init_val(cfg);
 
         Box::pin(async move {
             let name = name?;
+            let mem_type = mem_type?;
+
+            // If a type was specified, the initial value has to be of
+            // that type.
+
+            if let Some(v) = &init_value {
+                if !Instance::accepts(&mem_type, v) {
+                    return Err(Error::ConfigError(String::from(
+                        "'initial' doesn't match the 'type' parameter",
+                    )));
+                }
+            }
 
             // This device is settable. Any setting is forwarded to
             // the backend.
@@ -75,13 +160,16 @@ impl driver::API for Instance {
             let mut d_memory =
                 core.add_rw_device(name, None, max_history).await?;
 
-            // If the user configured an initial value and there was
-            // no previous value, immediately set it.
+            // Report the value the device has at startup. This
+            // re-reports the previously saved value so clients see
+            // the memory device is active.
 
-            if d_memory.get_last().is_none() {
-                if let Some(v) = init_value {
-                    d_memory.report_update(v).await
-                }
+            if let Some(v) = Instance::startup_value(
+                &mem_type,
+                d_memory.get_last(),
+                init_value,
+            ) {
+                d_memory.report_update(v).await
             }
 
             Ok(Devices { d_memory })
@@ -89,12 +177,14 @@ impl driver::API for Instance {
     }
 
     fn create_instance(
-        _cfg: &DriverConfig,
+        cfg: &DriverConfig,
     ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let mem_type = Instance::get_cfg_type(cfg);
+
         let fut = async move {
             // Build and return the future.
 
-            Ok(Box::new(Instance::new()))
+            Ok(Box::new(Instance::new(mem_type?)))
         };
 
         Box::pin(fut)
@@ -108,8 +198,14 @@ impl driver::API for Instance {
             let mut devices = devices.lock().await;
 
             while let Some((v, reply)) = devices.d_memory.next_setting().await {
-                reply(Ok(v.clone()));
-                devices.d_memory.report_update(v).await
+                // Reject values that don't match the configured type.
+
+                if Instance::accepts(&self.mem_type, &v) {
+                    reply(Ok(v.clone()));
+                    devices.d_memory.report_update(v).await
+                } else {
+                    reply(Err(Error::TypeError))
+                }
             }
             panic!("can no longer receive settings");
         };
@@ -117,3 +213,71 @@ impl driver::API for Instance {
         Box::pin(fut)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cfg_type() {
+        let mut cfg = DriverConfig::new();
+
+        assert_eq!(Instance::get_cfg_type(&cfg).unwrap(), None);
+
+        cfg.insert(
+            "type".to_owned(),
+            toml::value::Value::String("bool".to_owned()),
+        );
+        assert_eq!(
+            Instance::get_cfg_type(&cfg).unwrap(),
+            Some(MemoryType::Bool)
+        );
+
+        cfg.insert(
+            "type".to_owned(),
+            toml::value::Value::String("float".to_owned()),
+        );
+        assert_eq!(
+            Instance::get_cfg_type(&cfg).unwrap(),
+            Some(MemoryType::Flt)
+        );
+
+        cfg.insert(
+            "type".to_owned(),
+            toml::value::Value::String("double".to_owned()),
+        );
+        assert!(Instance::get_cfg_type(&cfg).is_err());
+
+        cfg.insert("type".to_owned(), toml::value::Value::Integer(1));
+        assert!(Instance::get_cfg_type(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_startup_value() {
+        let t = Some(MemoryType::Bool);
+
+        // With no type, any previous value is used.
+
+        assert_eq!(
+            Instance::startup_value(&None, Some(&(1).into()), None),
+            Some((1).into())
+        );
+
+        // The previous value is preferred over the initial value.
+
+        assert_eq!(
+            Instance::startup_value(&t, Some(&true.into()), Some(false.into())),
+            Some(true.into())
+        );
+
+        // A previous value of the wrong type is replaced by the
+        // initial value.
+
+        assert_eq!(
+            Instance::startup_value(&t, Some(&(1).into()), Some(false.into())),
+            Some(false.into())
+        );
+        assert_eq!(Instance::startup_value(&t, Some(&(1).into()), None), None);
+        assert_eq!(Instance::startup_value(&t, None, None), None);
+    }
+}
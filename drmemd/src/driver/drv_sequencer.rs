@@ -0,0 +1,530 @@
+use drmem_api::{
+    device,
+    driver::{self, DriverConfig},
+    Error, Result,
+};
+use std::{
+    convert::Infallible, future::Future, pin::Pin, sync::Arc, time::Duration,
+};
+use tokio::{sync::Mutex, time};
+use tracing::{debug, info};
+
+// Base names used by the driver's fixed devices. Zone names can't
+// use these.
+
+const RESERVED: [&str; 5] = ["enable", "rain", "run", "zone", "remaining"];
+
+// Holds the configuration of a zone.
+
+#[derive(Debug, PartialEq)]
+struct Zone {
+    name: device::Base,
+    duration: Duration,
+}
+
+pub struct Instance {
+    zones: Vec<Zone>,
+    enabled: bool,
+    raining: bool,
+    current: Option<(usize, time::Instant)>,
+}
+
+pub struct Devices {
+    d_enable: driver::ReadWriteDevice<bool>,
+    d_rain: driver::ReadWriteDevice<bool>,
+    d_run: driver::ReadWriteDevice<bool>,
+    d_zone: driver::ReadOnlyDevice<String>,
+    d_remaining: driver::ReadOnlyDevice<f64>,
+    d_zones: Vec<driver::ReadOnlyDevice<bool>>,
+}
+
+impl Instance {
+    pub const NAME: &'static str = "sequencer";
+
+    pub const SUMMARY: &'static str =
+        "Runs a set of outputs, one at a time, for configured durations.";
+
+    pub const DESCRIPTION: &'static str = include_str!("drv_sequencer.md");
+
+    /// Creates a new, idle `Instance`.
+
+    fn new(zones: Vec<Zone>) -> Instance {
+        Instance {
+            zones,
+            enabled: true,
+            raining: false,
+            current: None,
+        }
+    }
+
+    // Converts a TOML table into a `Zone`.
+
+    fn to_zone(tbl: &toml::Table) -> Result<Zone> {
+        let name = match tbl.get("name") {
+            Some(toml::value::Value::String(name)) => name
+                .parse::<device::Base>()
+                .ok()
+                .filter(|_| !RESERVED.contains(&name.as_str()))
+                .ok_or_else(|| {
+                    Error::ConfigError(format!(
+                        "'{}' can't be used as a zone name",
+                        name
+                    ))
+                })?,
+            _ => {
+                return Err(Error::ConfigError(String::from(
+                    "each zone needs a 'name' string",
+                )))
+            }
+        };
+
+        match tbl.get("minutes") {
+            Some(toml::value::Value::Integer(mins))
+                if (1..=1440).contains(mins) =>
+            {
+                Ok(Zone {
+                    name,
+                    duration: Duration::from_secs(*mins as u64 * 60),
+                })
+            }
+            _ => Err(Error::ConfigError(String::from(
+                "each zone needs 'minutes' in the range 1 - 1440",
+            ))),
+        }
+    }
+
+    // Validates the list of zones.
+
+    fn get_cfg_zones(cfg: &DriverConfig) -> Result<Vec<Zone>> {
+        match cfg.get("zones") {
+            Some(toml::value::Value::Array(zones)) if !zones.is_empty() => {
+                let zones = zones
+                    .iter()
+                    .map(|v| match v {
+                        toml::value::Value::Table(tbl) => {
+                            Instance::to_zone(tbl)
+                        }
+                        _ => Err(Error::ConfigError(String::from(
+                            "'zones' should be an array of tables",
+                        ))),
+                    })
+                    .collect::<Result<Vec<Zone>>>()?;
+
+                // Each zone creates a device, so the names have to be
+                // unique.
+
+                for (idx, zone) in zones.iter().enumerate() {
+                    if zones[..idx].iter().any(|z| z.name == zone.name) {
+                        return Err(Error::ConfigError(format!(
+                            "zone '{}' is defined more than once",
+                            zone.name
+                        )));
+                    }
+                }
+                Ok(zones)
+            }
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'zones' should be a non-empty array of tables",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'zones' parameter in config",
+            ))),
+        }
+    }
+
+    // Starts a sequence, if one isn't running and the master enable
+    // and rain-skip inputs allow it. Returns the index of the zone
+    // to turn on.
+
+    fn start(&mut self, now: time::Instant) -> Option<usize> {
+        if self.current.is_none() && self.enabled && !self.raining {
+            self.current = Some((0, now + self.zones[0].duration));
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    // Stops the sequence. Returns the index of the zone to turn off,
+    // if one was running.
+
+    fn stop(&mut self) -> Option<usize> {
+        self.current.take().map(|(idx, _)| idx)
+    }
+
+    // Called when the active zone's time has expired. Returns a
+    // 2-tuple containing the index of the zone to turn off and the
+    // index of the next zone to turn on (or `None`, if the sequence
+    // is complete.)
+
+    fn time_expired(
+        &mut self,
+        now: time::Instant,
+    ) -> (Option<usize>, Option<usize>) {
+        match self.current {
+            Some((idx, _)) if idx + 1 < self.zones.len() => {
+                self.current =
+                    Some((idx + 1, now + self.zones[idx + 1].duration));
+                (Some(idx), Some(idx + 1))
+            }
+            Some((idx, _)) => {
+                self.current = None;
+                (Some(idx), None)
+            }
+            None => (None, None),
+        }
+    }
+
+    // Returns the time at which the active zone finishes.
+
+    fn deadline(&self) -> Option<time::Instant> {
+        self.current.map(|(_, end)| end)
+    }
+
+    // Returns the number of minutes remaining in the sequence,
+    // rounded to a tenth of a minute.
+
+    fn remaining(&self, now: time::Instant) -> f64 {
+        match self.current {
+            Some((idx, end)) => {
+                let total = self.zones[idx + 1..]
+                    .iter()
+                    .fold(end.saturating_duration_since(now), |acc, z| {
+                        acc + z.duration
+                    });
+
+                (total.as_secs_f64() / 6.0).round() / 10.0
+            }
+            None => 0.0,
+        }
+    }
+
+    // Reports the state of the sequence to the progress devices.
+
+    async fn report_progress(&self, devices: &mut Devices) {
+        let now = time::Instant::now();
+        let zone = self
+            .current
+            .map(|(idx, _)| self.zones[idx].name.to_string())
+            .unwrap_or_default();
+
+        devices.d_zone.report_update(zone).await;
+        devices.d_remaining.report_update(self.remaining(now)).await;
+    }
+}
+
+impl driver::API for Instance {
+    type DeviceSet = Devices;
+
+    fn register_devices(
+        core: driver::RequestChan,
+        cfg: &DriverConfig,
+        max_history: Option<usize>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
+        let enable_name = "enable".parse::<device::Base>().unwrap();
+        let rain_name = "rain".parse::<device::Base>().unwrap();
+        let run_name = "run".parse::<device::Base>().unwrap();
+        let zone_name = "zone".parse::<device::Base>().unwrap();
+        let remaining_name = "remaining".parse::<device::Base>().unwrap();
+        let zones = Instance::get_cfg_zones(cfg);
+
+        Box::pin(async move {
+            let zones = zones?;
+
+            // Define the devices managed by this driver.
+            //
+            // The master enable and the rain-skip input are settable
+            // and keep their values across restarts.
+
+            let d_enable =
+                core.add_rw_device(enable_name, None, max_history).await?;
+            let d_rain =
+                core.add_rw_device(rain_name, None, max_history).await?;
+
+            // Setting this device to `true` starts the sequence.
+
+            let d_run = core.add_rw_device(run_name, None, max_history).await?;
+
+            // These devices show the progress of the sequence.
+
+            let d_zone =
+                core.add_ro_device(zone_name, None, max_history).await?;
+            let d_remaining = core
+                .add_ro_device(remaining_name, Some("min"), max_history)
+                .await?;
+
+            // Each zone gets an output device.
+
+            let mut d_zones = Vec::with_capacity(zones.len());
+
+            for zone in zones {
+                d_zones.push(
+                    core.add_ro_device(zone.name, None, max_history).await?,
+                );
+            }
+
+            Ok(Devices {
+                d_enable,
+                d_rain,
+                d_run,
+                d_zone,
+                d_remaining,
+                d_zones,
+            })
+        })
+    }
+
+    fn create_instance(
+        cfg: &DriverConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let zones = Instance::get_cfg_zones(cfg);
+
+        let fut = async move { Ok(Box::new(Instance::new(zones?))) };
+
+        Box::pin(fut)
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
+        let fut = async move {
+            let mut devices = devices.lock().await;
+            let devices = &mut *devices;
+            let mut timer = time::interval(Duration::from_secs(60));
+
+            // Restore the inputs and initialize the outputs. If the
+            // driver was restarted in the middle of a sequence, the
+            // sequence isn't resumed.
+
+            self.enabled = devices.d_enable.get_last().cloned().unwrap_or(true);
+            self.raining = devices.d_rain.get_last().cloned().unwrap_or(false);
+            self.current = None;
+
+            devices.d_enable.report_update(self.enabled).await;
+            devices.d_rain.report_update(self.raining).await;
+            devices.d_run.report_update(false).await;
+
+            for d in devices.d_zones.iter_mut() {
+                d.report_update(false).await;
+            }
+
+            self.report_progress(devices).await;
+
+            loop {
+                let deadline = self.deadline();
+                let mut stop = false;
+
+                #[rustfmt::skip]
+                tokio::select! {
+                    // When the active zone's time expires, move to
+                    // the next zone.
+
+                    _ = time::sleep_until(deadline.unwrap_or_else(time::Instant::now)),
+			if deadline.is_some() => {
+			let (off, on) = self.time_expired(time::Instant::now());
+
+			if let Some(idx) = off {
+			    devices.d_zones[idx].report_update(false).await;
+			}
+
+			if let Some(idx) = on {
+			    info!("starting zone {}", &self.zones[idx].name);
+			    devices.d_zones[idx].report_update(true).await;
+			} else {
+			    info!("sequence complete");
+			    devices.d_run.report_update(false).await;
+			}
+			self.report_progress(devices).await;
+                    }
+
+		    // Periodically update the remaining time while
+		    // running.
+
+                    _ = timer.tick(), if deadline.is_some() => {
+			self.report_progress(devices).await;
+                    }
+
+		    // Handle the master enable. Disabling the driver
+		    // stops any active sequence.
+
+                    Some((v, reply)) = devices.d_enable.next_setting() => {
+			reply(Ok(v));
+			self.enabled = v;
+			devices.d_enable.report_update(v).await;
+			stop = !v;
+                    }
+
+		    // Handle the rain-skip input. Rain stops any active
+		    // sequence.
+
+                    Some((v, reply)) = devices.d_rain.next_setting() => {
+			reply(Ok(v));
+			self.raining = v;
+			devices.d_rain.report_update(v).await;
+			stop = v;
+                    }
+
+		    // Handle requests to start the sequence. The reply
+		    // holds whether the sequence is running. Setting
+		    // `false` doesn't stop the sequence so a logic
+		    // block can trigger it with a momentary `true`.
+
+                    Some((v, reply)) = devices.d_run.next_setting() => {
+			if let Some(idx) = v.then(|| self.start(time::Instant::now())).flatten() {
+			    info!("starting zone {}", &self.zones[idx].name);
+			    reply(Ok(true));
+			    devices.d_run.report_update(true).await;
+			    devices.d_zones[idx].report_update(true).await;
+			    self.report_progress(devices).await;
+			} else {
+			    debug!("sequence not started");
+			    reply(Ok(self.current.is_some()));
+			}
+                    }
+                }
+
+                // If the sequence was stopped early by the enable or
+                // rain inputs, turn off the active zone.
+
+                if stop {
+                    if let Some(idx) = self.stop() {
+                        info!("sequence stopped");
+                        devices.d_zones[idx].report_update(false).await;
+                        devices.d_run.report_update(false).await;
+                        self.report_progress(devices).await;
+                    }
+                }
+            }
+        };
+
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(name: &str, minutes: i64) -> toml::value::Value {
+        let mut tbl = toml::Table::new();
+
+        tbl.insert(
+            "name".to_owned(),
+            toml::value::Value::String(name.to_owned()),
+        );
+        tbl.insert("minutes".to_owned(), toml::value::Value::Integer(minutes));
+        toml::value::Value::Table(tbl)
+    }
+
+    fn make_instance() -> Instance {
+        Instance::new(vec![
+            Zone {
+                name: "front".parse().unwrap(),
+                duration: Duration::from_secs(600),
+            },
+            Zone {
+                name: "back".parse().unwrap(),
+                duration: Duration::from_secs(300),
+            },
+        ])
+    }
+
+    #[test]
+    fn test_cfg() {
+        let mut cfg = DriverConfig::new();
+
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+
+        cfg.insert("zones".to_owned(), toml::value::Value::Array(vec![]));
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+
+        cfg.insert(
+            "zones".to_owned(),
+            toml::value::Value::Array(vec![zone("front", 10), zone("back", 5)]),
+        );
+        assert_eq!(
+            Instance::get_cfg_zones(&cfg).unwrap(),
+            make_instance().zones
+        );
+
+        cfg.insert(
+            "zones".to_owned(),
+            toml::value::Value::Array(vec![
+                zone("front", 10),
+                zone("front", 5),
+            ]),
+        );
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+
+        cfg.insert(
+            "zones".to_owned(),
+            toml::value::Value::Array(vec![zone("run", 10)]),
+        );
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+
+        cfg.insert(
+            "zones".to_owned(),
+            toml::value::Value::Array(vec![zone("front", 0)]),
+        );
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+
+        cfg.insert(
+            "zones".to_owned(),
+            toml::value::Value::Array(vec![zone("bad:name", 10)]),
+        );
+        assert!(Instance::get_cfg_zones(&cfg).is_err());
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut inst = make_instance();
+        let now = time::Instant::now();
+
+        assert_eq!(inst.deadline(), None);
+        assert_eq!(inst.remaining(now), 0.0);
+        assert_eq!(inst.time_expired(now), (None, None));
+
+        // Starting the sequence turns on the first zone. Starting it
+        // again has no effect.
+
+        assert_eq!(inst.start(now), Some(0));
+        assert_eq!(inst.deadline(), Some(now + Duration::from_secs(600)));
+        assert_eq!(inst.remaining(now), 15.0);
+        assert_eq!(inst.start(now), None);
+
+        let now = now + Duration::from_secs(600);
+
+        assert_eq!(inst.time_expired(now), (Some(0), Some(1)));
+        assert_eq!(inst.deadline(), Some(now + Duration::from_secs(300)));
+        assert_eq!(inst.remaining(now + Duration::from_secs(30)), 4.5);
+
+        let now = now + Duration::from_secs(300);
+
+        assert_eq!(inst.time_expired(now), (Some(1), None));
+        assert_eq!(inst.deadline(), None);
+        assert_eq!(inst.stop(), None);
+    }
+
+    #[test]
+    fn test_inhibit() {
+        let mut inst = make_instance();
+        let now = time::Instant::now();
+
+        // The sequence can't start if disabled or if it's raining.
+
+        inst.enabled = false;
+        assert_eq!(inst.start(now), None);
+
+        inst.enabled = true;
+        inst.raining = true;
+        assert_eq!(inst.start(now), None);
+
+        // Stopping the sequence returns the active zone.
+
+        inst.raining = false;
+        assert_eq!(inst.start(now), Some(0));
+        assert_eq!(inst.stop(), Some(0));
+        assert_eq!(inst.deadline(), None);
+    }
+}
@@ -0,0 +1,582 @@
+use drmem_api::{
+    device,
+    driver::{self, DriverConfig},
+    Error, Result,
+};
+use std::{convert::Infallible, future::Future, pin::Pin, sync::Arc};
+use tokio::{sync::Mutex, time};
+use tracing::{debug, warn};
+
+// Determines whether the output drives a heating or a cooling
+// appliance.
+
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Heat,
+    Cool,
+}
+
+pub struct Instance {
+    mode: Mode,
+    min_on: time::Duration,
+    min_off: time::Duration,
+    min_temp: Option<f64>,
+    max_temp: Option<f64>,
+    temperature: Option<f64>,
+    setpoint: Option<f64>,
+    hysteresis: f64,
+    output: bool,
+    changed_at: Option<time::Instant>,
+}
+
+pub struct Devices {
+    d_output: driver::ReadOnlyDevice<bool>,
+    d_temperature: driver::ReadWriteDevice<f64>,
+    d_setpoint: driver::ReadWriteDevice<f64>,
+    d_hysteresis: driver::ReadWriteDevice<f64>,
+}
+
+impl Instance {
+    pub const NAME: &'static str = "thermostat";
+
+    pub const SUMMARY: &'static str =
+        "Controls a heating or cooling appliance.";
+
+    pub const DESCRIPTION: &'static str = include_str!("drv_thermostat.md");
+
+    /// Creates a new `Instance` instance. The output is initially
+    /// off and no temperature has been received.
+
+    fn new(
+        mode: Mode,
+        min_on: time::Duration,
+        min_off: time::Duration,
+        min_temp: Option<f64>,
+        max_temp: Option<f64>,
+    ) -> Instance {
+        Instance {
+            mode,
+            min_on,
+            min_off,
+            min_temp,
+            max_temp,
+            temperature: None,
+            setpoint: None,
+            hysteresis: 1.0,
+            output: false,
+            changed_at: None,
+        }
+    }
+
+    // Validates the mode parameter.
+
+    fn get_cfg_mode(cfg: &DriverConfig) -> Result<Mode> {
+        match cfg.get("mode") {
+            Some(toml::value::Value::String(mode)) => match mode.as_str() {
+                "heat" => Ok(Mode::Heat),
+                "cool" => Ok(Mode::Cool),
+                _ => Err(Error::ConfigError(String::from(
+                    "'mode' must be \"heat\" or \"cool\"",
+                ))),
+            },
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'mode' config parameter should be a string",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'mode' parameter in config",
+            ))),
+        }
+    }
+
+    // Validates an optional duration, specified in seconds. If it's
+    // missing, a duration of 0 is returned.
+
+    fn get_cfg_secs(cfg: &DriverConfig, key: &str) -> Result<time::Duration> {
+        match cfg.get(key) {
+            Some(toml::value::Value::Integer(secs)) => {
+                if (0..=86_400).contains(secs) {
+                    Ok(time::Duration::from_secs(*secs as u64))
+                } else {
+                    Err(Error::ConfigError(format!("'{}' out of range", key)))
+                }
+            }
+            Some(_) => Err(Error::ConfigError(format!(
+                "'{}' config parameter should be an integer",
+                key
+            ))),
+            None => Ok(time::Duration::ZERO),
+        }
+    }
+
+    // Validates an optional temperature. The value can be specified
+    // as an integer or floating point.
+
+    fn get_cfg_temp(cfg: &DriverConfig, key: &str) -> Result<Option<f64>> {
+        match cfg.get(key) {
+            Some(toml::value::Value::Integer(v)) => Ok(Some(*v as f64)),
+            Some(toml::value::Value::Float(v)) if v.is_finite() => Ok(Some(*v)),
+            Some(_) => Err(Error::ConfigError(format!(
+                "'{}' config parameter should be a number",
+                key
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Validates the optional engineering units of the temperature
+    // devices.
+
+    fn get_cfg_units(cfg: &DriverConfig) -> Result<Option<String>> {
+        match cfg.get("units") {
+            Some(toml::value::Value::String(units)) => Ok(Some(units.clone())),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'units' config parameter should be a string",
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Returns `true` if the temperature is within the safety limits.
+
+    fn within_limits(&self, temp: f64) -> bool {
+        self.min_temp.map(|v| temp >= v).unwrap_or(true)
+            && self.max_temp.map(|v| temp <= v).unwrap_or(true)
+    }
+
+    // Determines whether the appliance should be running. The output
+    // turns on when the temperature moves more than half the
+    // hysteresis away from the setpoint (below it when heating, above
+    // it when cooling) and turns off when it moves half the
+    // hysteresis past the setpoint in the other direction. In between,
+    // the output keeps its current state.
+
+    fn demand(&self) -> bool {
+        match (self.temperature, self.setpoint) {
+            (Some(temp), Some(sp)) if self.within_limits(temp) => {
+                let half = self.hysteresis / 2.0;
+                let (on, off) = match self.mode {
+                    Mode::Heat => (temp < sp - half, temp > sp + half),
+                    Mode::Cool => (temp > sp + half, temp < sp - half),
+                };
+
+                on || (self.output && !off)
+            }
+            _ => false,
+        }
+    }
+
+    // Decides whether the output should change. Returns a 2-tuple
+    // where the first element, if not `None`, is the new value of
+    // the output. The second element, if not `None`, is the time at
+    // which the state needs to be re-evaluated because a minimum
+    // on/off time is delaying a change.
+
+    fn evaluate(
+        &mut self,
+        now: time::Instant,
+    ) -> (Option<bool>, Option<time::Instant>) {
+        let demand = self.demand();
+
+        if demand == self.output {
+            return (None, None);
+        }
+
+        // A temperature outside the safety limits turns the
+        // appliance off immediately, regardless of the minimum on
+        // time.
+
+        let unsafe_temp = self
+            .temperature
+            .map(|t| !self.within_limits(t))
+            .unwrap_or(false);
+        let min = if self.output {
+            self.min_on
+        } else {
+            self.min_off
+        };
+
+        match self.changed_at {
+            Some(t) if !unsafe_temp && now < t + min => (None, Some(t + min)),
+            _ => {
+                self.output = demand;
+                self.changed_at = Some(now);
+                (Some(demand), None)
+            }
+        }
+    }
+
+    // Returns `true` if the setpoint is finite and lies within the
+    // safety limits.
+
+    fn valid_setpoint(&self, v: f64) -> bool {
+        v.is_finite() && self.within_limits(v)
+    }
+}
+
+impl driver::API for Instance {
+    type DeviceSet = Devices;
+
+    fn register_devices(
+        core: driver::RequestChan,
+        cfg: &DriverConfig,
+        max_history: Option<usize>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
+        let output_name = "output".parse::<device::Base>().unwrap();
+        let temperature_name = "temperature".parse::<device::Base>().unwrap();
+        let setpoint_name = "setpoint".parse::<device::Base>().unwrap();
+        let hysteresis_name = "hysteresis".parse::<device::Base>().unwrap();
+        let units = Instance::get_cfg_units(cfg);
+        let init_setpoint = Instance::get_cfg_temp(cfg, "setpoint");
+        let init_hysteresis = Instance::get_cfg_temp(cfg, "hysteresis");
+
+        Box::pin(async move {
+            let units = units?;
+            let units = units.as_deref();
+            let init_setpoint = init_setpoint?;
+            let init_hysteresis = init_hysteresis?;
+
+            // Define the devices managed by this driver.
+            //
+            // This first device is the output which controls the
+            // appliance.
+
+            let d_output =
+                core.add_ro_device(output_name, None, max_history).await?;
+
+            // This device receives the current temperature. It's
+            // typically set by a logic block that forwards the value
+            // of a temperature sensor.
+
+            let d_temperature = core
+                .add_rw_device(temperature_name, units, max_history)
+                .await?;
+
+            // These devices hold the control parameters. If they
+            // don't have a previous value, the values from the
+            // configuration are used.
+
+            let mut d_setpoint = core
+                .add_rw_device(setpoint_name, units, max_history)
+                .await?;
+            let mut d_hysteresis = core
+                .add_rw_device(hysteresis_name, units, max_history)
+                .await?;
+
+            if d_setpoint.get_last().is_none() {
+                if let Some(v) = init_setpoint {
+                    d_setpoint.report_update(v).await
+                }
+            }
+
+            if d_hysteresis.get_last().is_none() {
+                d_hysteresis
+                    .report_update(init_hysteresis.unwrap_or(1.0))
+                    .await
+            }
+
+            Ok(Devices {
+                d_output,
+                d_temperature,
+                d_setpoint,
+                d_hysteresis,
+            })
+        })
+    }
+
+    fn create_instance(
+        cfg: &DriverConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let mode = Instance::get_cfg_mode(cfg);
+        let min_on = Instance::get_cfg_secs(cfg, "min_on_secs");
+        let min_off = Instance::get_cfg_secs(cfg, "min_off_secs");
+        let min_temp = Instance::get_cfg_temp(cfg, "min_temp");
+        let max_temp = Instance::get_cfg_temp(cfg, "max_temp");
+
+        let fut = async move {
+            // Validate the configuration.
+
+            let min_temp = min_temp?;
+            let max_temp = max_temp?;
+
+            if let (Some(lo), Some(hi)) = (min_temp, max_temp) {
+                if lo >= hi {
+                    return Err(Error::ConfigError(String::from(
+                        "'min_temp' must be less than 'max_temp'",
+                    )));
+                }
+            }
+
+            Ok(Box::new(Instance::new(
+                mode?, min_on?, min_off?, min_temp, max_temp,
+            )))
+        };
+
+        Box::pin(fut)
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
+        let fut = async move {
+            let mut devices = devices.lock().await;
+            let Devices {
+                d_output,
+                d_temperature,
+                d_setpoint,
+                d_hysteresis,
+            } = &mut *devices;
+            let mut recheck: Option<time::Instant> = None;
+
+            // Restore the control parameters. The temperature isn't
+            // restored because an old reading could be stale.
+
+            self.setpoint = d_setpoint.get_last().cloned();
+            self.hysteresis = d_hysteresis.get_last().cloned().unwrap_or(1.0);
+            self.output = false;
+            self.changed_at = None;
+
+            d_output.report_update(false).await;
+
+            loop {
+                #[rustfmt::skip]
+                tokio::select! {
+                    // If a minimum on/off time delayed a change in
+                    // the output, re-evaluate it when the delay
+                    // expires.
+
+                    _ = time::sleep_until(recheck.unwrap_or_else(time::Instant::now)),
+			if recheck.is_some() => {
+			debug!("minimum on/off time expired");
+                    }
+
+		    // Handle new temperature readings.
+
+                    Some((v, reply)) = d_temperature.next_setting() => {
+			if v.is_finite() {
+			    self.temperature = Some(v);
+			    reply(Ok(v));
+			    d_temperature.report_update(v).await;
+			} else {
+			    reply(Err(Error::InvArgument(
+				"temperature must be a finite number".into()
+			    )));
+			    continue
+			}
+                    }
+
+		    // Handle changes to the setpoint.
+
+                    Some((v, reply)) = d_setpoint.next_setting() => {
+			if self.valid_setpoint(v) {
+			    self.setpoint = Some(v);
+			    reply(Ok(v));
+			    d_setpoint.report_update(v).await;
+			} else {
+			    reply(Err(Error::InvArgument(
+				"setpoint outside of the safety limits".into()
+			    )));
+			    continue
+			}
+                    }
+
+		    // Handle changes to the hysteresis.
+
+                    Some((v, reply)) = d_hysteresis.next_setting() => {
+			if v.is_finite() && v >= 0.0 {
+			    self.hysteresis = v;
+			    reply(Ok(v));
+			    d_hysteresis.report_update(v).await;
+			} else {
+			    reply(Err(Error::InvArgument(
+				"hysteresis must be a non-negative number".into()
+			    )));
+			    continue
+			}
+                    }
+                }
+
+                let (out, tmo) = self.evaluate(time::Instant::now());
+
+                if let Some(out) = out {
+                    if let Some(t) = self.temperature {
+                        if !self.within_limits(t) {
+                            warn!("temperature {} outside safety limits", t)
+                        }
+                    }
+                    debug!("output -> {}", out);
+                    d_output.report_update(out).await;
+                }
+                recheck = tmo;
+            }
+        };
+
+        Box::pin(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drmem_api::driver::API;
+
+    fn make_instance(mode: Mode) -> Instance {
+        let mut inst = Instance::new(
+            mode,
+            time::Duration::from_secs(60),
+            time::Duration::from_secs(120),
+            Some(40.0),
+            Some(90.0),
+        );
+
+        inst.setpoint = Some(70.0);
+        inst.hysteresis = 2.0;
+        inst
+    }
+
+    #[tokio::test]
+    async fn test_cfg() {
+        let mut cfg = DriverConfig::new();
+
+        assert!(Instance::create_instance(&cfg).await.is_err());
+
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("heat".to_owned()),
+        );
+
+        let inst = Instance::create_instance(&cfg).await.unwrap();
+
+        assert_eq!(inst.mode, Mode::Heat);
+        assert_eq!(inst.min_on, time::Duration::ZERO);
+        assert_eq!(inst.min_off, time::Duration::ZERO);
+        assert_eq!(inst.min_temp, None);
+        assert_eq!(inst.max_temp, None);
+
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("cool".to_owned()),
+        );
+        cfg.insert("min_on_secs".to_owned(), toml::value::Value::Integer(300));
+        cfg.insert("min_off_secs".to_owned(), toml::value::Value::Integer(600));
+        cfg.insert("min_temp".to_owned(), toml::value::Value::Integer(50));
+        cfg.insert("max_temp".to_owned(), toml::value::Value::Float(95.5));
+
+        let inst = Instance::create_instance(&cfg).await.unwrap();
+
+        assert_eq!(inst.mode, Mode::Cool);
+        assert_eq!(inst.min_on, time::Duration::from_secs(300));
+        assert_eq!(inst.min_off, time::Duration::from_secs(600));
+        assert_eq!(inst.min_temp, Some(50.0));
+        assert_eq!(inst.max_temp, Some(95.5));
+
+        cfg.insert("min_temp".to_owned(), toml::value::Value::Integer(100));
+        assert!(Instance::create_instance(&cfg).await.is_err());
+
+        cfg.remove("min_temp");
+        cfg.insert("min_on_secs".to_owned(), toml::value::Value::Integer(-1));
+        assert!(Instance::create_instance(&cfg).await.is_err());
+
+        cfg.remove("min_on_secs");
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("fan".to_owned()),
+        );
+        assert!(Instance::create_instance(&cfg).await.is_err());
+    }
+
+    #[test]
+    fn test_heating() {
+        let mut inst = make_instance(Mode::Heat);
+        let now = time::Instant::now();
+
+        // Without a temperature, the output stays off.
+
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        // Within the hysteresis band, the output stays off.
+
+        inst.temperature = Some(69.5);
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        // Below the band, the output turns on.
+
+        inst.temperature = Some(68.5);
+        assert_eq!(inst.evaluate(now), (Some(true), None));
+
+        // It stays on through the band.
+
+        inst.temperature = Some(70.5);
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        // Above the band, it wants to turn off but the minimum on
+        // time delays it.
+
+        let later = now + time::Duration::from_secs(10);
+
+        inst.temperature = Some(71.5);
+        assert_eq!(
+            inst.evaluate(later),
+            (None, Some(now + time::Duration::from_secs(60)))
+        );
+
+        let later = now + time::Duration::from_secs(60);
+
+        assert_eq!(inst.evaluate(later), (Some(false), None));
+
+        // The minimum off time delays turning on again.
+
+        inst.temperature = Some(60.0);
+        assert_eq!(
+            inst.evaluate(later),
+            (None, Some(later + time::Duration::from_secs(120)))
+        );
+    }
+
+    #[test]
+    fn test_cooling() {
+        let mut inst = make_instance(Mode::Cool);
+        let now = time::Instant::now();
+
+        inst.temperature = Some(70.5);
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        inst.temperature = Some(71.5);
+        assert_eq!(inst.evaluate(now), (Some(true), None));
+
+        inst.temperature = Some(69.5);
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        let later = now + time::Duration::from_secs(61);
+
+        inst.temperature = Some(68.5);
+        assert_eq!(inst.evaluate(later), (Some(false), None));
+    }
+
+    #[test]
+    fn test_safety_limits() {
+        let mut inst = make_instance(Mode::Heat);
+        let now = time::Instant::now();
+
+        // A temperature below the safety limit doesn't turn on the
+        // appliance.
+
+        inst.temperature = Some(30.0);
+        assert_eq!(inst.evaluate(now), (None, None));
+
+        inst.temperature = Some(60.0);
+        assert_eq!(inst.evaluate(now), (Some(true), None));
+
+        // A temperature outside the limits turns off the appliance,
+        // even if the minimum on time hasn't expired.
+
+        inst.temperature = Some(95.0);
+        assert_eq!(inst.evaluate(now), (Some(false), None));
+
+        // Setpoints have to be within the limits.
+
+        assert!(inst.valid_setpoint(70.0));
+        assert!(!inst.valid_setpoint(100.0));
+        assert!(!inst.valid_setpoint(f64::NAN));
+    }
+}
@@ -19,11 +19,23 @@ enum TimerState {
     TimedOut,       // Not timing, input is true
 }
 
+// Determines how the timer responds to a `true` setting while it's
+// timing.
+
+#[derive(Debug, PartialEq)]
+enum TimerMode {
+    Normal,    // Only a `false` to `true` transition restarts the timer
+    Retrigger, // Any `true` restarts the timer
+    Extend,    // Any `true` adds another interval to the timer
+}
+
 pub struct Instance {
     state: TimerState,
+    mode: TimerMode,
     active_value: device::Value,
     inactive_value: device::Value,
     millis: time::Duration,
+    timeout: time::Instant,
 }
 
 pub struct Devices {
@@ -42,16 +54,19 @@ impl Instance {
     /// Creates a new `Instance` instance. It is assumed the external
     /// input is `false` so the initial timer state is `Armed`.
 
-    pub fn new(
+    fn new(
         active_value: device::Value,
         inactive_value: device::Value,
         millis: time::Duration,
+        mode: TimerMode,
     ) -> Instance {
         Instance {
             state: TimerState::Armed,
+            mode,
             active_value,
             inactive_value,
             millis,
+            timeout: time::Instant::now(),
         }
     }
 
@@ -95,6 +110,38 @@ impl Instance {
         }
     }
 
+    // Validates the optional timer mode parameter.
+
+    fn get_cfg_mode(cfg: &DriverConfig) -> Result<TimerMode> {
+        match cfg.get("mode") {
+            Some(toml::value::Value::String(mode)) => match mode.as_str() {
+                "normal" => Ok(TimerMode::Normal),
+                "retrigger" => Ok(TimerMode::Retrigger),
+                "extend" => Ok(TimerMode::Extend),
+                _ => Err(Error::ConfigError(String::from(
+                    "'mode' must be \"normal\", \"retrigger\", or \"extend\"",
+                ))),
+            },
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'mode' config parameter should be a string",
+            ))),
+            None => Ok(TimerMode::Normal),
+        }
+    }
+
+    // Computes a new timeout when the timer is restarted while it's
+    // still timing. In "extend" mode, another interval is added to
+    // the current timeout. Otherwise the timer starts over.
+
+    fn restart(&mut self) -> time::Instant {
+        self.timeout = if self.mode == TimerMode::Extend {
+            self.timeout + self.millis
+        } else {
+            time::Instant::now() + self.millis
+        };
+        self.timeout
+    }
+
     // Validates the active value parameter.
 
     fn get_active_value(cfg: &DriverConfig) -> Result<device::Value> {
@@ -136,7 +183,7 @@ impl Instance {
                     None,
                     if val {
                         self.state = TimerState::Timing;
-                        Some(time::Instant::now() + self.millis)
+                        Some(self.restart())
                     } else {
                         None
                     },
@@ -150,10 +197,8 @@ impl Instance {
 
                 if val {
                     self.state = TimerState::Timing;
-                    (
-                        Some(self.active_value.clone()),
-                        Some(time::Instant::now() + self.millis),
-                    )
+                    self.timeout = time::Instant::now() + self.millis;
+                    (Some(self.active_value.clone()), Some(self.timeout))
                 } else {
                     (None, None)
                 }
@@ -163,13 +208,18 @@ impl Instance {
             TimerState::Timing => {
                 // If the input is `false`, continue with the current
                 // timing cycle but enter `TimingAndArmed` because a
-                // `true` can restart the timer.
+                // `true` can restart the timer. If the input is
+                // `true`, only the "retrigger" and "extend" modes
+                // adjust the timeout.
 
                 if !val {
                     self.state = TimerState::TimingAndArmed;
+                    (None, None)
+                } else if self.mode != TimerMode::Normal {
+                    (None, Some(self.restart()))
+                } else {
+                    (None, None)
                 }
-
-                (None, None)
             }
 
             // Not timing, input is `true`.
@@ -222,6 +272,7 @@ impl driver::API for Instance {
         cfg: &DriverConfig,
     ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
         let millis = Instance::get_cfg_millis(cfg);
+        let mode = Instance::get_cfg_mode(cfg);
         let active_value = Instance::get_active_value(cfg);
         let inactive_value = Instance::get_inactive_value(cfg);
 
@@ -229,6 +280,7 @@ impl driver::API for Instance {
             // Validate the configuration.
 
             let millis = millis?;
+            let mode = mode?;
             let active_value = active_value?;
             let inactive_value = inactive_value?;
 
@@ -238,6 +290,7 @@ impl driver::API for Instance {
                 active_value,
                 inactive_value,
                 millis,
+                mode,
             )))
         };
 
@@ -321,6 +374,7 @@ mod tests {
             device::Value::Bool(true),
             device::Value::Bool(false),
             time::Duration::from_millis(1000),
+            TimerMode::Normal,
         );
 
         assert_eq!(timer.state, TimerState::Armed);
@@ -368,4 +422,110 @@ mod tests {
         timer.time_expired();
         assert_eq!(timer.state, TimerState::Armed);
     }
+
+    #[test]
+    fn test_retrigger() {
+        let mut timer = Instance::new(
+            device::Value::Bool(true),
+            device::Value::Bool(false),
+            time::Duration::from_millis(1000),
+            TimerMode::Retrigger,
+        );
+
+        let (a, b) = timer.update_state(true);
+
+        assert_eq!(timer.state, TimerState::Timing);
+        assert_eq!(Some(device::Value::Bool(true)), a);
+
+        // A duplicate `true` restarts the timer from the current
+        // time.
+
+        let b = b.unwrap();
+        let (a, c) = timer.update_state(true);
+
+        assert_eq!(timer.state, TimerState::Timing);
+        assert!(a.is_none());
+
+        let c = c.unwrap();
+
+        assert!(c >= b);
+        assert!(c <= time::Instant::now() + time::Duration::from_millis(1000));
+
+        assert_eq!((None, None), timer.update_state(false));
+        assert_eq!(timer.state, TimerState::TimingAndArmed);
+
+        timer.time_expired();
+        assert_eq!(timer.state, TimerState::Armed);
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut timer = Instance::new(
+            device::Value::Bool(true),
+            device::Value::Bool(false),
+            time::Duration::from_millis(1000),
+            TimerMode::Extend,
+        );
+
+        let (a, b) = timer.update_state(true);
+
+        assert_eq!(timer.state, TimerState::Timing);
+        assert_eq!(Some(device::Value::Bool(true)), a);
+
+        // Each additional `true` adds another interval to the
+        // timeout.
+
+        let b = b.unwrap();
+
+        assert_eq!(
+            (None, Some(b + time::Duration::from_millis(1000))),
+            timer.update_state(true)
+        );
+        assert_eq!(
+            (None, Some(b + time::Duration::from_millis(2000))),
+            timer.update_state(true)
+        );
+
+        assert_eq!((None, None), timer.update_state(false));
+        assert_eq!(timer.state, TimerState::TimingAndArmed);
+        assert_eq!(
+            (None, Some(b + time::Duration::from_millis(3000))),
+            timer.update_state(true)
+        );
+        assert_eq!(timer.state, TimerState::Timing);
+
+        // Once the timer expires, a `true` doesn't restart it.
+
+        timer.time_expired();
+        assert_eq!(timer.state, TimerState::TimedOut);
+        assert_eq!((None, None), timer.update_state(true));
+    }
+
+    #[test]
+    fn test_cfg_mode() {
+        let mut cfg = DriverConfig::new();
+
+        assert_eq!(Instance::get_cfg_mode(&cfg).unwrap(), TimerMode::Normal);
+
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("extend".to_owned()),
+        );
+        assert_eq!(Instance::get_cfg_mode(&cfg).unwrap(), TimerMode::Extend);
+
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("retrigger".to_owned()),
+        );
+        assert_eq!(Instance::get_cfg_mode(&cfg).unwrap(), TimerMode::Retrigger);
+
+        cfg.insert(
+            "mode".to_owned(),
+            toml::value::Value::String("bogus".to_owned()),
+        );
+        assert!(Instance::get_cfg_mode(&cfg).is_err());
+
+        cfg.insert("mode".to_owned(), toml::value::Value::Integer(1));
+        assert!(Instance::get_cfg_mode(&cfg).is_err());
+    }
 }
@@ -0,0 +1,846 @@
+use drmem_api::{
+    device,
+    driver::{self, DriverConfig},
+    Error, Result,
+};
+use std::{
+    convert::Infallible,
+    future::Future,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::{sync::Mutex, time};
+use tracing::{info, warn};
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc,
+};
+
+// How long a network request made by a module can take.
+
+const NET_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// The values `request()` returns when it fails.
+
+const ERR_ENDPOINT: i32 = -1;
+const ERR_CONNECT: i32 = -2;
+const ERR_IO: i32 = -3;
+
+// The types of values a device can hold.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DevType {
+    Bool,
+    Int,
+    Flt,
+    Str,
+}
+
+impl DevType {
+    fn accepts(&self, v: &device::Value) -> bool {
+        matches!(
+            (self, v),
+            (DevType::Bool, device::Value::Bool(_))
+                | (DevType::Int, device::Value::Int(_))
+                | (DevType::Flt, device::Value::Flt(_))
+                | (DevType::Str, device::Value::Str(_))
+        )
+    }
+}
+
+// A device declared in the configuration.
+
+#[derive(Debug, PartialEq)]
+struct DevCfg {
+    name: device::Base,
+    dev_type: DevType,
+    units: Option<String>,
+    settable: bool,
+}
+
+enum Device {
+    Ro(driver::ReadOnlyDevice<device::Value>),
+    Rw(driver::ReadWriteDevice<device::Value>),
+}
+
+impl Device {
+    async fn report_update(&mut self, v: device::Value) {
+        match self {
+            Device::Ro(d) => d.report_update(v).await,
+            Device::Rw(d) => d.report_update(v).await,
+        }
+    }
+}
+
+pub struct Devices {
+    devices: Vec<Device>,
+}
+
+// The state the host functions share. It's the only thing a module
+// can reach outside of its own memory.
+
+struct Host {
+    types: Vec<DevType>,
+    endpoints: Vec<String>,
+    params: String,
+    reports: Vec<(usize, device::Value)>,
+    limits: StoreLimits,
+}
+
+// An instantiated module and the functions it exports.
+
+struct Guest {
+    store: Store<Host>,
+    fuel: u64,
+    tick: TypedFunc<(), ()>,
+    set_bool: Option<TypedFunc<(i32, i32), i32>>,
+    set_int: Option<TypedFunc<(i32, i64), i32>>,
+    set_flt: Option<TypedFunc<(i32, f64), i32>>,
+}
+
+impl Guest {
+    // Calls a function of the module with a full tank of fuel, so a
+    // module that loops forever is stopped.
+
+    fn call<P, R>(
+        &mut self,
+        func: &TypedFunc<P, R>,
+        params: P,
+    ) -> std::result::Result<R, wasmi::Error>
+    where
+        P: wasmi::WasmParams,
+        R: wasmi::WasmResults,
+    {
+        self.store.set_fuel(self.fuel)?;
+        func.call(&mut self.store, params)
+    }
+}
+
+pub struct Instance {
+    guest: Option<Guest>,
+    interval: time::Duration,
+}
+
+// Returns the module's memory. Every host function that takes a
+// pointer needs it.
+
+fn memory(
+    caller: &Caller<'_, Host>,
+) -> std::result::Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("module doesn't export 'memory'"))
+}
+
+fn read_bytes(
+    caller: &Caller<'_, Host>,
+    ptr: i32,
+    len: i32,
+) -> std::result::Result<Vec<u8>, wasmi::Error> {
+    let mut buf = vec![0; len.max(0) as usize];
+
+    memory(caller)?.read(caller, ptr as u32 as usize, &mut buf)?;
+    Ok(buf)
+}
+
+// Saves a reading reported by the module. It's sent to the device
+// once the call into the module returns.
+
+fn report(
+    caller: &mut Caller<'_, Host>,
+    dev: i32,
+    v: device::Value,
+) -> std::result::Result<(), wasmi::Error> {
+    let host = caller.data_mut();
+
+    match host.types.get(dev as usize) {
+        Some(t) if t.accepts(&v) => {
+            host.reports.push((dev as usize, v));
+            Ok(())
+        }
+        Some(_) => Err(wasmi::Error::new(format!(
+            "device {} can't hold {}",
+            dev, v
+        ))),
+        None => Err(wasmi::Error::new(format!("no device {}", dev))),
+    }
+}
+
+// Sends a request to an endpoint from the configuration and reads the
+// reply until the peer closes the connection or `cap` bytes arrive.
+
+fn request(
+    endpoint: &str,
+    req: &[u8],
+    cap: usize,
+) -> std::result::Result<Vec<u8>, i32> {
+    let addr = endpoint
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut v| v.next())
+        .ok_or(ERR_CONNECT)?;
+    let mut sock = TcpStream::connect_timeout(&addr, NET_TIMEOUT)
+        .map_err(|_| ERR_CONNECT)?;
+
+    sock.set_read_timeout(Some(NET_TIMEOUT))
+        .map_err(|_| ERR_IO)?;
+    sock.set_write_timeout(Some(NET_TIMEOUT))
+        .map_err(|_| ERR_IO)?;
+    sock.write_all(req).map_err(|_| ERR_IO)?;
+
+    let mut rsp = vec![];
+
+    sock.take(cap as u64)
+        .read_to_end(&mut rsp)
+        .map_err(|_| ERR_IO)?;
+    Ok(rsp)
+}
+
+// Builds the functions a module can import. They're in the "drmem"
+// namespace.
+
+fn linker(engine: &Engine) -> std::result::Result<Linker<Host>, wasmi::Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "drmem",
+        "report_bool",
+        |mut caller: Caller<'_, Host>, dev: i32, v: i32| {
+            report(&mut caller, dev, device::Value::Bool(v != 0))
+        },
+    )?;
+    linker.func_wrap(
+        "drmem",
+        "report_int",
+        |mut caller: Caller<'_, Host>, dev: i32, v: i64| {
+            report(&mut caller, dev, device::Value::Int(v as i32))
+        },
+    )?;
+    linker.func_wrap(
+        "drmem",
+        "report_flt",
+        |mut caller: Caller<'_, Host>, dev: i32, v: f64| {
+            report(&mut caller, dev, device::Value::Flt(v))
+        },
+    )?;
+    linker.func_wrap(
+        "drmem",
+        "report_str",
+        |mut caller: Caller<'_, Host>, dev: i32, ptr: i32, len: i32| {
+            let v = String::from_utf8_lossy(&read_bytes(&caller, ptr, len)?)
+                .to_string();
+
+            report(&mut caller, dev, device::Value::Str(v.into()))
+        },
+    )?;
+
+    // Copies the `params` table, as JSON, into the module's memory.
+    // Returns the length of the JSON so a module can retry with a
+    // larger buffer.
+
+    linker.func_wrap(
+        "drmem",
+        "params",
+        |mut caller: Caller<'_, Host>, ptr: i32, cap: i32| {
+            let params = caller.data().params.clone().into_bytes();
+            let len = params.len().min(cap.max(0) as usize);
+
+            memory(&caller)?.write(
+                &mut caller,
+                ptr as u32 as usize,
+                &params[..len],
+            )?;
+            Ok(params.len() as i32)
+        },
+    )?;
+    linker.func_wrap(
+        "drmem",
+        "log",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            info!(
+                "{}",
+                String::from_utf8_lossy(&read_bytes(&caller, ptr, len)?)
+            );
+            Ok(())
+        },
+    )?;
+
+    // Sends a request to one of the configured endpoints and copies
+    // the reply into the module's memory. Returns the length of the
+    // reply or a negative error code.
+
+    linker.func_wrap(
+        "drmem",
+        "request",
+        |mut caller: Caller<'_, Host>,
+         ep: i32,
+         req_ptr: i32,
+         req_len: i32,
+         rsp_ptr: i32,
+         rsp_cap: i32| {
+            let Some(endpoint) =
+                caller.data().endpoints.get(ep as usize).cloned()
+            else {
+                return Ok(ERR_ENDPOINT);
+            };
+            let req = read_bytes(&caller, req_ptr, req_len)?;
+
+            match request(&endpoint, &req, rsp_cap.max(0) as usize) {
+                Ok(rsp) => {
+                    memory(&caller)?.write(
+                        &mut caller,
+                        rsp_ptr as u32 as usize,
+                        &rsp,
+                    )?;
+                    Ok(rsp.len() as i32)
+                }
+                Err(e) => {
+                    warn!("request to {} failed", &endpoint);
+                    Ok(e)
+                }
+            }
+        },
+    )?;
+    Ok(linker)
+}
+
+impl Instance {
+    pub const NAME: &'static str = "wasm";
+
+    pub const SUMMARY: &'static str =
+        "Runs a driver, compiled to WebAssembly, in a sandbox.";
+
+    pub const DESCRIPTION: &'static str = include_str!("drv_wasm.md");
+
+    fn get_cfg_module(cfg: &DriverConfig) -> Result<PathBuf> {
+        match cfg.get("module") {
+            Some(toml::value::Value::String(v)) => Ok(v.into()),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'module' config parameter should be a string",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'module' parameter in config",
+            ))),
+        }
+    }
+
+    fn get_cfg_interval(cfg: &DriverConfig) -> Result<time::Duration> {
+        match cfg.get("interval") {
+            Some(toml::value::Value::Integer(v))
+                if (1..=86_400).contains(v) =>
+            {
+                Ok(time::Duration::from_secs(*v as u64))
+            }
+            Some(toml::value::Value::Integer(_)) => {
+                Err(Error::ConfigError(String::from("'interval' out of range")))
+            }
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'interval' config parameter should be an integer",
+            ))),
+            None => Ok(time::Duration::from_secs(10)),
+        }
+    }
+
+    // Gets a positive integer parameter, or its default.
+
+    fn get_cfg_limit(cfg: &DriverConfig, key: &str, def: u64) -> Result<u64> {
+        match cfg.get(key) {
+            Some(toml::value::Value::Integer(v)) if *v > 0 => Ok(*v as u64),
+            Some(_) => Err(Error::ConfigError(format!(
+                "'{}' config parameter should be a positive integer",
+                key
+            ))),
+            None => Ok(def),
+        }
+    }
+
+    fn get_cfg_endpoints(cfg: &DriverConfig) -> Result<Vec<String>> {
+        match cfg.get("endpoints") {
+            Some(toml::value::Value::Array(v)) => v
+                .iter()
+                .map(|v| match v {
+                    toml::value::Value::String(v) if v.contains(':') => {
+                        Ok(v.clone())
+                    }
+                    _ => Err(Error::ConfigError(String::from(
+                        "'endpoints' should hold \"host:port\" strings",
+                    ))),
+                })
+                .collect(),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'endpoints' config parameter should be an array",
+            ))),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn get_cfg_params(cfg: &DriverConfig) -> Result<String> {
+        match cfg.get("params") {
+            Some(v @ toml::value::Value::Table(_)) => serde_json::to_string(v)
+                .map_err(|e| Error::ConfigError(format!("'params' -- {}", e))),
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'params' config parameter should be a table",
+            ))),
+            None => Ok(String::from("{}")),
+        }
+    }
+
+    fn get_cfg_device(v: &toml::value::Value) -> Result<DevCfg> {
+        let err =
+            |msg: &str| Error::ConfigError(format!("'devices' -- {}", msg));
+        let toml::value::Value::Table(v) = v else {
+            return Err(err("entries should be tables"));
+        };
+        let name = v
+            .get("name")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| err("missing 'name'"))?
+            .parse::<device::Base>()
+            .map_err(|_| {
+                err("'name' isn't a proper, base name for a device")
+            })?;
+        let dev_type = match v.get("type").and_then(|v| v.as_str()) {
+            Some("bool") => DevType::Bool,
+            Some("int") => DevType::Int,
+            Some("float") => DevType::Flt,
+            Some("string") => DevType::Str,
+            _ => {
+                return Err(err("'type' should be bool, int, float, or string"))
+            }
+        };
+        let settable =
+            v.get("settable").and_then(|v| v.as_bool()).unwrap_or(false);
+
+        if settable && dev_type == DevType::Str {
+            return Err(err("string devices can't be settable"));
+        }
+
+        Ok(DevCfg {
+            name,
+            dev_type,
+            units: v.get("units").and_then(|v| v.as_str()).map(String::from),
+            settable,
+        })
+    }
+
+    fn get_cfg_devices(cfg: &DriverConfig) -> Result<Vec<DevCfg>> {
+        match cfg.get("devices") {
+            Some(toml::value::Value::Array(v)) => {
+                v.iter().map(Instance::get_cfg_device).collect()
+            }
+            Some(_) => Err(Error::ConfigError(String::from(
+                "'devices' config parameter should be an array",
+            ))),
+            None => Err(Error::ConfigError(String::from(
+                "missing 'devices' parameter in config",
+            ))),
+        }
+    }
+
+    // Compiles and instantiates the module. If it exports `init`,
+    // it's called before anything else.
+
+    fn load(cfg: &DriverConfig, wasm: &[u8]) -> Result<Guest> {
+        let err =
+            |e: wasmi::Error| Error::ConfigError(format!("module -- {}", e));
+        let memory = Instance::get_cfg_limit(cfg, "memory", 16)?;
+        let mut config = Config::default();
+
+        config.consume_fuel(true);
+
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(err)?;
+        let host = Host {
+            types: Instance::get_cfg_devices(cfg)?
+                .iter()
+                .map(|v| v.dev_type)
+                .collect(),
+            endpoints: Instance::get_cfg_endpoints(cfg)?,
+            params: Instance::get_cfg_params(cfg)?,
+            reports: vec![],
+            limits: StoreLimitsBuilder::new()
+                .memory_size((memory as usize) << 20)
+                .build(),
+        };
+        let mut store = Store::new(&engine, host);
+
+        store.limiter(|host| &mut host.limits);
+
+        let fuel = Instance::get_cfg_limit(cfg, "fuel", 10_000_000)?;
+
+        store.set_fuel(fuel).map_err(|e| err(e.into()))?;
+
+        let instance = linker(&engine)
+            .and_then(|v| v.instantiate(&mut store, &module))
+            .and_then(|v| v.start(&mut store))
+            .map_err(err)?;
+
+        let mut guest = Guest {
+            tick: instance.get_typed_func(&store, "tick").map_err(err)?,
+            set_bool: instance.get_typed_func(&store, "set_bool").ok(),
+            set_int: instance.get_typed_func(&store, "set_int").ok(),
+            set_flt: instance.get_typed_func(&store, "set_flt").ok(),
+            store,
+            fuel,
+        };
+
+        if let Ok(init) =
+            instance.get_typed_func::<(), ()>(&guest.store, "init")
+        {
+            guest.call(&init, ()).map_err(err)?;
+        }
+        Ok(guest)
+    }
+
+    // Runs `f`, which calls into the module, on a thread that's
+    // allowed to block, since modules make network requests. A
+    // module that traps is a fatal error; the instance gets
+    // restarted.
+
+    async fn call<R, F>(&mut self, f: F) -> (R, Vec<(usize, device::Value)>)
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Guest) -> std::result::Result<R, wasmi::Error>
+            + Send
+            + 'static,
+    {
+        let mut guest = self.guest.take().expect("module was lost");
+        let (mut guest, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut guest);
+
+            (guest, result)
+        })
+        .await
+        .expect("module call was aborted");
+        let reports = std::mem::take(&mut guest.store.data_mut().reports);
+
+        self.guest = Some(guest);
+        match result {
+            Ok(v) => (v, reports),
+            Err(e) => panic!("module failed -- {}", e),
+        }
+    }
+
+    // Passes a setting to the module. A result of 0 means the module
+    // accepted it.
+
+    async fn apply(
+        &mut self,
+        dev: usize,
+        v: &device::Value,
+    ) -> (Option<bool>, Vec<(usize, device::Value)>) {
+        let dev = dev as i32;
+
+        match v.clone() {
+            device::Value::Bool(v) => {
+                self.call(move |g| {
+                    g.set_bool
+                        .map(|f| g.call(&f, (dev, v as i32)).map(|v| v == 0))
+                        .transpose()
+                })
+                .await
+            }
+            device::Value::Int(v) => {
+                self.call(move |g| {
+                    g.set_int
+                        .map(|f| g.call(&f, (dev, v as i64)).map(|v| v == 0))
+                        .transpose()
+                })
+                .await
+            }
+            device::Value::Flt(v) => {
+                self.call(move |g| {
+                    g.set_flt
+                        .map(|f| g.call(&f, (dev, v)).map(|v| v == 0))
+                        .transpose()
+                })
+                .await
+            }
+            _ => (None, vec![]),
+        }
+    }
+}
+
+impl driver::API for Instance {
+    type DeviceSet = Devices;
+
+    fn register_devices(
+        core: driver::RequestChan,
+        cfg: &DriverConfig,
+        max_history: Option<usize>,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::DeviceSet>> + Send>> {
+        let devices = Instance::get_cfg_devices(cfg);
+
+        Box::pin(async move {
+            let mut result = vec![];
+
+            for dev in devices? {
+                let units = dev.units.as_deref();
+
+                result.push(if dev.settable {
+                    Device::Rw(
+                        core.add_rw_device(dev.name, units, max_history)
+                            .await?,
+                    )
+                } else {
+                    Device::Ro(
+                        core.add_ro_device(dev.name, units, max_history)
+                            .await?,
+                    )
+                })
+            }
+            Ok(Devices { devices: result })
+        })
+    }
+
+    fn create_instance(
+        cfg: &DriverConfig,
+    ) -> Pin<Box<dyn Future<Output = Result<Box<Self>>> + Send>> {
+        let cfg = cfg.clone();
+
+        Box::pin(async move {
+            let path = Instance::get_cfg_module(&cfg)?;
+            let interval = Instance::get_cfg_interval(&cfg)?;
+            let wasm = tokio::fs::read(&path).await.map_err(|e| {
+                Error::ConfigError(format!(
+                    "couldn't read {} -- {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            let guest = tokio::task::spawn_blocking(move || {
+                Instance::load(&cfg, &wasm)
+            })
+            .await
+            .map_err(|e| Error::OperationError(e.to_string()))??;
+
+            Ok(Box::new(Instance {
+                guest: Some(guest),
+                interval,
+            }))
+        })
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        devices: Arc<Mutex<Devices>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + 'a>> {
+        Box::pin(async move {
+            let mut devices = devices.lock().await;
+            let mut interval = time::interval(self.interval);
+
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+            loop {
+                // Wait for the next tick or a setting to one of the
+                // settable devices.
+
+                let setting = {
+                    let settings: Vec<_> = devices
+                        .devices
+                        .iter_mut()
+                        .enumerate()
+                        .filter_map(|(idx, dev)| match dev {
+                            Device::Rw(d) => Some(Box::pin(async move {
+                                (idx, d.next_setting().await)
+                            })),
+                            Device::Ro(_) => None,
+                        })
+                        .collect();
+                    let next = async {
+                        if settings.is_empty() {
+                            std::future::pending().await
+                        } else {
+                            futures::future::select_all(settings).await.0
+                        }
+                    };
+
+                    tokio::select! {
+                        _ = interval.tick() => None,
+                        v = next => Some(v)
+                    }
+                };
+
+                let reports = match setting {
+                    None => {
+                        self.call(|g| {
+                            let tick = g.tick;
+
+                            g.call(&tick, ())
+                        })
+                        .await
+                        .1
+                    }
+                    Some((idx, Some((v, reply)))) => {
+                        let (accepted, reports) = self.apply(idx, &v).await;
+
+                        match accepted {
+                            Some(true) => reply(Ok(v)),
+                            Some(false) => reply(Err(Error::InvArgument(
+                                String::from("setting rejected by module"),
+                            ))),
+                            None => reply(Err(Error::OperationError(
+                                String::from("module doesn't accept settings"),
+                            ))),
+                        }
+                        reports
+                    }
+                    Some((_, None)) => panic!("can't receive new settings"),
+                };
+
+                for (idx, v) in reports {
+                    devices.devices[idx].report_update(v).await
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module that counts the ticks, reports a parameter, and
+    // accepts settings to its second device between 0 and 100.
+
+    const WAT: &str = r#"
+(module
+  (import "drmem" "report_int" (func $report_int (param i32 i64)))
+  (import "drmem" "report_flt" (func $report_flt (param i32 f64)))
+  (import "drmem" "params" (func $params (param i32 i32) (result i32)))
+  (import "drmem" "request" (func $request (param i32 i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (global $count (mut i64) (i64.const 0))
+  (func (export "init")
+    (drop (call $params (i32.const 0) (i32.const 64))))
+  (func (export "tick")
+    (global.set $count (i64.add (global.get $count) (i64.const 1)))
+    (call $report_int (i32.const 0) (global.get $count))
+    ;; Reports the first byte of the JSON parameters.
+    (call $report_int (i32.const 2) (i64.load8_u (i32.const 0)))
+    ;; Reports the result of using an undeclared endpoint.
+    (call $report_int (i32.const 3)
+      (i64.extend_i32_s
+        (call $request (i32.const 5) (i32.const 0) (i32.const 0)
+                       (i32.const 0) (i32.const 0)))))
+  (func (export "set_flt") (param $dev i32) (param $v f64) (result i32)
+    (if (result i32)
+        (i32.and (f64.ge (local.get $v) (f64.const 0))
+                 (f64.le (local.get $v) (f64.const 100)))
+      (then (call $report_flt (local.get $dev) (local.get $v))
+            (i32.const 0))
+      (else (i32.const 1)))))
+"#;
+
+    fn config() -> DriverConfig {
+        toml::from_str(
+            r#"
+module = "test.wasm"
+interval = 1
+fuel = 100000
+params = { key = 1 }
+devices = [
+    { name = "count", type = "int" },
+    { name = "level", type = "float", settable = true, units = "%" },
+    { name = "first", type = "int" },
+    { name = "error", type = "int" },
+]
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_config() {
+        let cfg = config();
+        let devices = Instance::get_cfg_devices(&cfg).unwrap();
+
+        assert_eq!(devices.len(), 4);
+        assert_eq!(
+            devices[1],
+            DevCfg {
+                name: "level".parse().unwrap(),
+                dev_type: DevType::Flt,
+                units: Some("%".into()),
+                settable: true,
+            }
+        );
+        assert_eq!(Instance::get_cfg_params(&cfg).unwrap(), "{\"key\":1}");
+        assert!(Instance::get_cfg_endpoints(&cfg).unwrap().is_empty());
+
+        let bad = |s: &str| {
+            let cfg: DriverConfig = toml::from_str(s).unwrap();
+
+            Instance::get_cfg_devices(&cfg).is_err()
+        };
+
+        assert!(bad("devices = [{ name = \"a\", type = \"color\" }]"));
+        assert!(bad("devices = [{ name = \"a:b\", type = \"int\" }]"));
+        assert!(bad(
+            "devices = [{ name = \"a\", type = \"string\", settable = true }]"
+        ));
+        assert!(Instance::get_cfg_endpoints(
+            &toml::from_str("endpoints = [\"localhost\"]").unwrap()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_module() {
+        let cfg = config();
+        let wasm = wat::parse_str(WAT).unwrap();
+        let mut inst = Instance {
+            guest: Some(Instance::load(&cfg, &wasm).unwrap()),
+            interval: time::Duration::from_secs(1),
+        };
+
+        // Each tick is counted. The module was given the parameters
+        // and can't reach endpoints that weren't configured.
+
+        for n in 1..=2 {
+            let ((), reports) = inst
+                .call(|g| {
+                    let tick = g.tick;
+
+                    g.call(&tick, ())
+                })
+                .await;
+
+            assert_eq!(
+                reports,
+                [
+                    (0, device::Value::Int(n)),
+                    (2, device::Value::Int(b'{' as i32)),
+                    (3, device::Value::Int(ERR_ENDPOINT)),
+                ]
+            );
+        }
+
+        // Settings are checked by the module.
+
+        assert_eq!(
+            inst.apply(1, &device::Value::Flt(50.0)).await,
+            (Some(true), vec![(1, device::Value::Flt(50.0))])
+        );
+        assert_eq!(
+            inst.apply(1, &device::Value::Flt(150.0)).await,
+            (Some(false), vec![])
+        );
+        assert_eq!(inst.apply(1, &device::Value::Int(5)).await, (None, vec![]));
+
+        // A module that doesn't return runs out of fuel.
+
+        let wasm = wat::parse_str(
+            r#"(module (func (export "tick") (loop $l (br $l))))"#,
+        )
+        .unwrap();
+        let mut guest = Instance::load(&cfg, &wasm).unwrap();
+        let tick = guest.tick;
+
+        assert!(guest.call(&tick, ()).is_err());
+    }
+}
@@ -0,0 +1,625 @@
+// Keeps track of the running driver instances so the set can be
+// changed, when the configuration is reloaded, without restarting
+// `drmemd`. Instances are identified by their device prefix.
+//
+// A removed instance gets to run its shutdown hook. Its devices stay
+// registered, but they no longer get updated and settings to them
+// fail.
+//
+// Each instance runs in an "instance" span holding its prefix. The
+// log filter uses it to apply the instance's log level.
+//
+// Clients can also stop, start, and restart instances, and add new
+// ones, through a `RequestChan`. Those changes last until the
+// configuration is reloaded, which makes the instances match the
+// file again.
+
+use super::{simulate, status, DriverDb};
+use crate::{config, secrets, shutdown};
+use drmem_api::{device, driver, Error, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+};
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument;
+
+struct Running {
+    cfg: config::Driver,
+    // The parameters with the secrets filled in.
+    resolved: driver::DriverConfig,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+// Reports, by prefix, what a reload did to the instances.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+// Requests from clients to change the set of running instances.
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+pub enum Request {
+    Stop {
+        prefix: device::Path,
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
+    Start {
+        prefix: device::Path,
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
+    Restart {
+        prefix: device::Path,
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
+    Add {
+        cfg: config::Driver,
+        rpy_chan: oneshot::Sender<Result<()>>,
+    },
+}
+
+// A handle used to send requests to the task that owns the instances.
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+#[derive(Clone)]
+pub struct RequestChan(mpsc::Sender<Request>);
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+impl RequestChan {
+    pub fn new(req_chan: mpsc::Sender<Request>) -> Self {
+        RequestChan(req_chan)
+    }
+
+    async fn send(
+        &self,
+        f: impl FnOnce(oneshot::Sender<Result<()>>) -> Request,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+
+        self.0.send(f(tx)).await?;
+        rx.await?
+    }
+
+    // Stops a running instance. It can be started again with
+    // `start()`.
+
+    pub async fn stop(&self, prefix: device::Path) -> Result<()> {
+        self.send(|rpy_chan| Request::Stop { prefix, rpy_chan })
+            .await
+    }
+
+    // Starts an instance that was stopped by `stop()`.
+
+    pub async fn start(&self, prefix: device::Path) -> Result<()> {
+        self.send(|rpy_chan| Request::Start { prefix, rpy_chan })
+            .await
+    }
+
+    // Stops an instance and starts it again with the same
+    // configuration.
+
+    pub async fn restart(&self, prefix: device::Path) -> Result<()> {
+        self.send(|rpy_chan| Request::Restart { prefix, rpy_chan })
+            .await
+    }
+
+    // Starts a new instance of a driver. Its prefix can't already be
+    // in use.
+
+    pub async fn add(&self, cfg: config::Driver) -> Result<()> {
+        self.send(|rpy_chan| Request::Add { cfg, rpy_chan }).await
+    }
+}
+
+pub struct Instances {
+    db: DriverDb,
+    req_chan: mpsc::Sender<driver::Request>,
+    secrets: secrets::Store,
+    running: HashMap<device::Path, Running>,
+    // Instances stopped by a client, so they can be started again.
+    stopped: HashMap<device::Path, config::Driver>,
+}
+
+impl Instances {
+    pub fn new(
+        db: DriverDb,
+        req_chan: mpsc::Sender<driver::Request>,
+        secrets: secrets::Store,
+    ) -> Self {
+        Instances {
+            db,
+            req_chan,
+            secrets,
+            running: HashMap::new(),
+            stopped: HashMap::new(),
+        }
+    }
+
+    // Replaces the secrets used by instances started from now on.
+
+    pub fn set_secrets(&mut self, secrets: secrets::Store) {
+        self.secrets = secrets
+    }
+
+    fn resolve(&self, cfg: &config::Driver) -> Result<driver::DriverConfig> {
+        self.secrets
+            .resolve(cfg.cfg.as_ref().unwrap_or(&Default::default()))
+    }
+
+    // Starts an instance. An error is returned if the driver doesn't
+    // exist or its devices couldn't be registered.
+
+    pub async fn start(&mut self, cfg: config::Driver) -> Result<()> {
+        let driver_name: driver::Name = cfg.name.clone().into();
+
+        // If the driver exists in the driver table, an instance can
+        // be started.
+
+        let Some(driver_info) = self.db.get_driver(&driver_name) else {
+            error!("no driver named {}", &cfg.name);
+            return Err(Error::NotFound);
+        };
+        let drv_cfg = self.resolve(&cfg)?;
+
+        // A simulated instance registers its devices through a
+        // channel that simulates them.
+
+        let chan = if self.db.is_simulated(&driver_name) {
+            simulate::request_chan(
+                driver_name.clone(),
+                &cfg.prefix,
+                &self.req_chan,
+                simulate::values(cfg.simulate.as_ref())?,
+            )
+        } else {
+            driver::RequestChan::new(
+                driver_name.clone(),
+                &cfg.prefix,
+                &self.req_chan,
+            )
+        }
+        .with_max_age(cfg.get_max_age());
+        let mut status = self.db.status().add(
+            driver_name.clone(),
+            cfg.prefix.to_string(),
+            &drv_cfg,
+        );
+
+        // Register the devices that report whether the instance is
+        // alive. Not having them isn't fatal.
+
+        let wd_chan = driver::RequestChan::new(
+            "drmemd".into(),
+            &format!("drmem:driver:{}", cfg.prefix).parse()?,
+            &self.req_chan,
+        );
+
+        match status::Watchdog::register(&wd_chan).await {
+            Ok(wd) => {
+                status = status.with_watchdog(wd);
+                status.publish().await
+            }
+            Err(e) => warn!(
+                "couldn't add watchdog devices for {} -- {}",
+                &cfg.prefix, e
+            ),
+        }
+
+        // Call the function that manages instances of this driver.
+        // If it returns `Ok()`, the value is a Future that implements
+        // the driver. If `Err()` is returned, then the devices
+        // couldn't be registered or some other serious error
+        // occurred.
+
+        let (stop, rx_stop) = shutdown::Stop::new();
+        let span = info_span!("instance", prefix = %cfg.prefix);
+        let instance = (driver_info.2)(
+            driver_name,
+            drv_cfg.clone(),
+            chan,
+            cfg.max_history,
+            status,
+            rx_stop,
+        )
+        .instrument(span.clone())
+        .await?;
+
+        // Driver managers only return after shutting down their
+        // instance.
+
+        self.running.insert(
+            cfg.prefix.clone(),
+            Running {
+                cfg,
+                resolved: drv_cfg,
+                stop,
+                task: tokio::spawn(instance.instrument(span)),
+            },
+        );
+        Ok(())
+    }
+
+    // Stops an instance and waits, up to `limit`, for it to run its
+    // shutdown hook.
+
+    async fn stop(prefix: &device::Path, running: Running, limit: Duration) {
+        info!("stopping driver instance {}", prefix);
+        running.stop.send_replace(true);
+
+        let abort = running.task.abort_handle();
+
+        if tokio::time::timeout(limit, running.task).await.is_err() {
+            warn!("driver instance {} didn't stop in time", prefix);
+            abort.abort()
+        }
+    }
+
+    // Stops a running instance and remembers its configuration so it
+    // can be started again.
+
+    async fn stop_instance(
+        &mut self,
+        prefix: &device::Path,
+        limit: Duration,
+    ) -> Result<()> {
+        let running = self.running.remove(prefix).ok_or(Error::NotFound)?;
+        let cfg = running.cfg.clone();
+
+        Self::stop(prefix, running, limit).await;
+        self.stopped.insert(prefix.clone(), cfg);
+        Ok(())
+    }
+
+    // Starts an instance that was stopped by a client. If it can't be
+    // started, it stays in the stopped set.
+
+    async fn start_instance(&mut self, prefix: &device::Path) -> Result<()> {
+        if self.running.contains_key(prefix) {
+            return Err(Error::InUse);
+        }
+
+        let cfg = self.stopped.remove(prefix).ok_or(Error::NotFound)?;
+
+        self.start(cfg.clone()).await.inspect_err(|_| {
+            self.stopped.insert(prefix.clone(), cfg);
+        })
+    }
+
+    // Handles a request from a client. `limit` is how long an
+    // instance has to stop.
+
+    pub async fn handle(&mut self, req: Request, limit: Duration) {
+        match req {
+            Request::Stop { prefix, rpy_chan } => {
+                let _ = rpy_chan.send(self.stop_instance(&prefix, limit).await);
+            }
+            Request::Start { prefix, rpy_chan } => {
+                let _ = rpy_chan.send(self.start_instance(&prefix).await);
+            }
+            Request::Restart { prefix, rpy_chan } => {
+                let result = match self.stop_instance(&prefix, limit).await {
+                    Ok(()) => self.start_instance(&prefix).await,
+                    Err(e) => Err(e),
+                };
+
+                let _ = rpy_chan.send(result);
+            }
+            Request::Add { cfg, rpy_chan } => {
+                let result = if self.running.contains_key(&cfg.prefix)
+                    || self.stopped.contains_key(&cfg.prefix)
+                {
+                    Err(Error::InUse)
+                } else {
+                    self.start(cfg).await
+                };
+
+                let _ = rpy_chan.send(result);
+            }
+        }
+    }
+
+    // Makes the running instances match the configuration. Instances
+    // whose configuration changed are restarted. An instance that
+    // can't be started is logged and skipped. Changing only the log
+    // level doesn't restart an instance; the log filter picks it up.
+
+    pub async fn update(
+        &mut self,
+        cfg: Vec<config::Driver>,
+        limit: Duration,
+    ) -> Result<Summary> {
+        let mut prefixes = HashSet::with_capacity(cfg.len());
+
+        for drv in &cfg {
+            if !prefixes.insert(&drv.prefix) {
+                return Err(Error::ConfigError(format!(
+                    "driver prefix '{}' is used more than once",
+                    &drv.prefix
+                )));
+            }
+        }
+
+        let mut summary = Summary::default();
+
+        // Instances stopped by a client are forgotten. The ones still
+        // in the configuration get started below.
+
+        self.stopped.clear();
+
+        // Stop the instances that were removed or changed. Changing
+        // the value of a secret an instance uses is a change, too.
+
+        let stale: Vec<device::Path> = self
+            .running
+            .iter()
+            .filter(|(k, v)| {
+                !cfg.iter().any(|c| {
+                    c.prefix == **k
+                        && c.same_instance(&v.cfg)
+                        && self.resolve(c).is_ok_and(|c| c == v.resolved)
+                })
+            })
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for prefix in stale {
+            if let Some(running) = self.running.remove(&prefix) {
+                Self::stop(&prefix, running, limit).await;
+                summary.stopped.push(prefix.to_string())
+            }
+        }
+
+        // Start the new ones.
+
+        for drv in cfg {
+            let prefix = drv.prefix.to_string();
+
+            if self.running.contains_key(&drv.prefix) {
+                summary.unchanged.push(prefix)
+            } else {
+                match self.start(drv).await {
+                    Ok(()) => summary.started.push(prefix),
+                    Err(e) => error!(
+                        "couldn't start driver instance {} -- {}",
+                        &prefix, e
+                    ),
+                }
+            }
+        }
+
+        summary.started.sort();
+        summary.stopped.sort();
+        summary.unchanged.sort();
+        Ok(summary)
+    }
+
+    // Waits, up to `limit`, for the instances to stop once a shutdown
+    // has been requested.
+
+    pub async fn join(self, limit: Duration) {
+        let tasks = self.running.into_values().map(|v| v.task);
+
+        if tokio::time::timeout(limit, futures::future::join_all(tasks))
+            .await
+            .is_err()
+        {
+            warn!("some drivers didn't shut down in time")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(prefix: &str, initial: bool) -> config::Driver {
+        let mut cfg = driver::DriverConfig::new();
+
+        cfg.insert("name".into(), "var".into());
+        cfg.insert("initial".into(), initial.into());
+        config::Driver {
+            name: "memory".into(),
+            prefix: prefix.parse().unwrap(),
+            max_history: None,
+            max_age: None,
+            log_level: None,
+            cfg: Some(cfg),
+            simulate: None,
+            raw_cfg: None,
+        }
+    }
+
+    // Fakes the core. Registrations succeed; the devices' readings
+    // are dropped.
+
+    fn fake_core() -> mpsc::Sender<driver::Request> {
+        let (tx, mut rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            let mut settings = vec![];
+
+            while let Some(req) = rx.recv().await {
+                match req {
+                    driver::Request::AddReadonlyDevice { rpy_chan, .. } => {
+                        let _ =
+                            rpy_chan.send(Ok(Box::new(|_| Box::pin(async {}))));
+                    }
+                    driver::Request::AddReadWriteDevice {
+                        rpy_chan, ..
+                    } => {
+                        let (tx, rx) = mpsc::channel(10);
+
+                        settings.push(tx);
+                        let _ = rpy_chan.send(Ok((
+                            Box::new(|_| Box::pin(async {})),
+                            rx,
+                            None,
+                        )));
+                    }
+                    driver::Request::Discover { .. } => (),
+                }
+            }
+        });
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let db = DriverDb::create();
+        let mut inst =
+            Instances::new(db.clone(), fake_core(), secrets::Store::default());
+        let limit = Duration::from_secs(1);
+
+        let summary = inst
+            .update(vec![memory("a", false), memory("b", false)], limit)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.started, ["a", "b"]);
+        assert_eq!(db.status().get_all().len(), 2);
+
+        // Removing one, changing one, and adding one.
+
+        let summary = inst
+            .update(vec![memory("b", true), memory("c", false)], limit)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            Summary {
+                started: vec!["b".into(), "c".into()],
+                stopped: vec!["a".into(), "b".into()],
+                unchanged: vec![],
+            }
+        );
+
+        let summary = inst
+            .update(vec![memory("b", true), memory("c", false)], limit)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.unchanged, ["b", "c"]);
+
+        // Changing the log level doesn't restart the instance.
+
+        let mut debug = memory("b", true);
+
+        debug.log_level = Some("debug".into());
+
+        let summary = inst
+            .update(vec![debug, memory("c", false)], limit)
+            .await
+            .unwrap();
+
+        assert_eq!(summary.unchanged, ["b", "c"]);
+
+        // The stopped instances are removed from the status table.
+
+        let mut prefixes: Vec<String> = db
+            .status()
+            .get_all()
+            .into_iter()
+            .map(|v| v.prefix)
+            .collect();
+
+        prefixes.sort();
+        assert_eq!(prefixes, ["b", "c"]);
+
+        // Unknown drivers are skipped and prefixes can't be reused.
+
+        let mut bad = memory("d", false);
+
+        bad.name = "nonexistent".into();
+
+        let summary = inst
+            .update(vec![memory("b", true), bad], limit)
+            .await
+            .unwrap();
+
+        assert!(summary.started.is_empty());
+        assert!(inst
+            .update(vec![memory("b", true), memory("b", false)], limit)
+            .await
+            .is_err());
+    }
+    #[tokio::test]
+    async fn test_requests() {
+        let db = DriverDb::create();
+        let mut inst =
+            Instances::new(db.clone(), fake_core(), secrets::Store::default());
+        let limit = Duration::from_secs(1);
+        let (tx, mut rx) = mpsc::channel(10);
+        let chan = RequestChan::new(tx);
+
+        // Handle the requests the way `drmemd` does.
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                inst.handle(req, limit).await
+            }
+        });
+
+        let prefixes = || {
+            let mut v: Vec<String> = db
+                .status()
+                .get_all()
+                .into_iter()
+                .map(|v| v.prefix)
+                .collect();
+
+            v.sort();
+            v
+        };
+
+        assert!(chan.add(memory("a", false)).await.is_ok());
+        assert!(chan.add(memory("b", false)).await.is_ok());
+        assert_eq!(prefixes(), ["a", "b"]);
+
+        // Prefixes can't be reused.
+
+        assert!(matches!(
+            chan.add(memory("a", true)).await,
+            Err(Error::InUse)
+        ));
+
+        // A stopped instance keeps its prefix and can be started
+        // again.
+
+        let a: device::Path = "a".parse().unwrap();
+
+        assert!(chan.stop(a.clone()).await.is_ok());
+        assert_eq!(prefixes(), ["b"]);
+        assert!(matches!(chan.stop(a.clone()).await, Err(Error::NotFound)));
+        assert!(matches!(
+            chan.add(memory("a", true)).await,
+            Err(Error::InUse)
+        ));
+        assert!(chan.start(a.clone()).await.is_ok());
+        assert_eq!(prefixes(), ["a", "b"]);
+        assert!(matches!(chan.start(a.clone()).await, Err(Error::InUse)));
+
+        assert!(chan.restart(a.clone()).await.is_ok());
+        assert_eq!(prefixes(), ["a", "b"]);
+        assert!(matches!(
+            chan.restart("c".parse().unwrap()).await,
+            Err(Error::NotFound)
+        ));
+
+        // Unknown drivers can't be added.
+
+        let mut bad = memory("d", false);
+
+        bad.name = "nonexistent".into();
+        assert!(chan.add(bad).await.is_err());
+    }
+}
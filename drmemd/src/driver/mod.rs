@@ -1,87 +1,291 @@
+use crate::shutdown;
 use drmem_api::{driver, Result};
 use futures::future::Future;
 use std::collections::HashMap;
-use std::{convert::Infallible, pin::Pin, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    convert::Infallible, marker::PhantomData, pin::Pin, sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, time::Instant};
 use tracing::{error, field, info, info_span, warn};
 use tracing_futures::Instrument;
 
 mod drv_cycle;
 mod drv_map;
 mod drv_memory;
+mod drv_sequencer;
+mod drv_thermostat;
 mod drv_timer;
+#[cfg(feature = "wasm")]
+mod drv_wasm;
+pub mod instances;
+#[cfg(feature = "plugins")]
+mod plugins;
+pub mod simulate;
+pub mod status;
 
 pub type Fut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
-pub type MgrTask = Fut<Infallible>;
+pub type MgrTask = Fut<()>;
 pub type MgrFuncRet = Fut<Result<MgrTask>>;
 
-pub type Launcher = fn(
-    driver::Name,
-    driver::DriverConfig,
-    driver::RequestChan,
-    Option<usize>,
-) -> MgrFuncRet;
+pub type Launcher = Arc<
+    dyn Fn(
+            driver::Name,
+            driver::DriverConfig,
+            driver::RequestChan,
+            Option<usize>,
+            status::Reporter,
+            shutdown::Stop,
+        ) -> MgrFuncRet
+        + Send
+        + Sync,
+>;
 
 pub type DriverInfo = (&'static str, &'static str, Launcher);
 
-// This is the main loop of the driver manager. It only returns if the
-// driver panics.
+// The parts of a driver instance the manager uses once the instance
+// has been created. Every `driver::API` implementation has them; so
+// do the instances of drivers loaded from plugins.
 
-fn mgr_body<T>(
-    name: driver::Name,
-    devices: T::DeviceSet,
+trait Run: Send + 'static {
+    type DeviceSet: Send + Sync + 'static;
+
+    fn run(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>>;
+
+    fn shutdown(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+impl<T> Run for T
+where
+    T: driver::API + 'static,
+{
+    type DeviceSet = T::DeviceSet;
+
+    fn run(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>> {
+        driver::API::run(self, devices)
+    }
+
+    fn shutdown(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        driver::API::shutdown(self, devices)
+    }
+}
+
+// Registers the devices of, and creates, driver instances. Built-in
+// drivers use the functions of their `driver::API` implementation.
+// Drivers from plugins use the ones the plugin exported.
+
+trait Factory: Send + Sync + 'static {
+    type Driver: Run;
+
+    fn register_devices(
+        &self,
+        drc: driver::RequestChan,
+        cfg: &driver::DriverConfig,
+        max_history: Option<usize>,
+    ) -> Fut<Result<<Self::Driver as Run>::DeviceSet>>;
+
+    fn create_instance(
+        &self,
+        cfg: &driver::DriverConfig,
+    ) -> Fut<Result<Box<Self::Driver>>>;
+}
+
+struct BuiltIn<T>(PhantomData<fn() -> T>);
+
+impl<T> Factory for BuiltIn<T>
+where
+    T: driver::API + 'static,
+{
+    type Driver = T;
+
+    fn register_devices(
+        &self,
+        drc: driver::RequestChan,
+        cfg: &driver::DriverConfig,
+        max_history: Option<usize>,
+    ) -> Fut<Result<T::DeviceSet>> {
+        T::register_devices(drc, cfg, max_history)
+    }
+
+    fn create_instance(
+        &self,
+        cfg: &driver::DriverConfig,
+    ) -> Fut<Result<Box<T>>> {
+        T::create_instance(cfg)
+    }
+}
+
+// Computes how long to wait before restarting a driver instance. The
+// delay doubles after each failure, up to a limit. A random amount is
+// added to each delay so instances that failed together, because a
+// shared resource went away, don't all restart at the same moment.
+
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const START: Duration = Duration::from_secs(5);
+    const MAX: Duration = Duration::from_secs(600);
+
+    // An instance that runs this long is considered healthy, so its
+    // next failure starts over with the shortest delay.
+
+    const STABLE: Duration = Duration::from_secs(600);
+
+    fn new() -> Self {
+        Backoff {
+            delay: Backoff::START,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay = Backoff::START
+    }
+
+    // Returns the delay to use now and lengthens the next one.
+    // `jitter` is the fraction of the delay to add to it.
+
+    fn next(&mut self, jitter: f64) -> Duration {
+        let delay = self.delay.mul_f64(1.0 + jitter);
+
+        self.delay = std::cmp::min(self.delay * 2, Backoff::MAX);
+        delay
+    }
+}
+
+// What the driver manager needs to register the devices of, and
+// create, an instance again when it's restarted.
+
+struct Setup<F> {
+    factory: Arc<F>,
     cfg: driver::DriverConfig,
+    req_chan: driver::RequestChan,
+    max_history: Option<usize>,
+}
+
+// This is the main loop of the driver manager. An instance that stops
+// is restarted. It only returns when `drmemd` shuts down, after the
+// instance's shutdown hook has run.
+
+fn mgr_body<F>(
+    setup: Setup<F>,
+    name: driver::Name,
+    devices: <F::Driver as Run>::DeviceSet,
+    mut status: status::Reporter,
+    stop: shutdown::Stop,
 ) -> MgrTask
 where
-    T: driver::API + Send + 'static,
+    F: Factory,
 {
-    Box::pin(async move {
-        const START_DELAY: u64 = 5;
-        const MAX_DELAY: u64 = 600;
+    let Setup {
+        factory,
+        cfg,
+        req_chan,
+        max_history,
+    } = setup;
 
-        let mut restart_delay = START_DELAY;
-        let devices = Arc::new(Mutex::new(devices));
+    Box::pin(async move {
+        let mut backoff = Backoff::new();
+        let mut devices = Some(devices);
 
         info!("starting instance of driver");
 
         loop {
+            // A restarted instance registers its devices again. This
+            // gives it fresh channels, so settings that were queued
+            // for the failed instance aren't handed to the new one.
+
+            let registered = match devices.take() {
+                Some(v) => Ok(v),
+                None => factory
+                    .register_devices(req_chan.clone(), &cfg, max_history)
+                    .instrument(info_span!("re-init", name = name.as_ref()))
+                    .await
+                    .map_err(|e| format!("couldn't register devices -- {}", e)),
+            };
+
             // Create a Future that creates an instance of the driver
             // using the provided configuration parameters.
 
-            let result = T::create_instance(&cfg)
-                .instrument(info_span!("init", cfg = field::Empty));
-
-            if let Ok(mut instance) = result.await {
-                let name = name.clone();
-                let devices = devices.clone();
-
-                restart_delay = START_DELAY;
-
-                // Start the driver instance as a background task and
-                // monitor the return value.
-
-                let task = tokio::spawn(async move {
-                    instance
-                        .run(devices)
+            let result = match registered {
+                Ok(devices) => factory
+                    .create_instance(&cfg)
+                    .instrument(info_span!("init", cfg = field::Empty))
+                    .await
+                    .map(|v| (v, Arc::new(Mutex::new(devices))))
+                    .map_err(|e| format!("couldn't start -- {}", e)),
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok((mut instance, devices)) => {
+                    let name = name.clone();
+                    let stop = stop.clone();
+                    let started = Instant::now();
+
+                    status.running();
+                    status.publish().await;
+
+                    // Start the driver instance as a background task
+                    // and monitor the return value. When a shutdown
+                    // is requested, the `run()` future is dropped and
+                    // the instance's shutdown hook is called.
+
+                    let task = tokio::spawn(
+                        async move {
+                            tokio::select! {
+                                v = instance.run(devices.clone()) => match v {},
+                                _ = stop.requested() => ()
+                            }
+                            info!("shutting down");
+                            instance.shutdown(devices).await
+                        }
                         .instrument(info_span!(
                             "driver",
                             name = name.as_ref(),
                             cfg = field::Empty
-                        ))
-                        .await
-                });
-
-                // Drivers are never supposed to exit so the JoinHandle
-                // will never return an `Ok()` value. We can't stop
-                // drivers from panicking, however, so we have to look for
-                // an `Err()` value.
-                //
-                // (When Rust officially supports the `!` type, we will be
-                // able to convert this from an `if-statement` to a simple
-                // assignment.)
-
-                if let Err(e) = task.await {
-                    error!("driver exited unexpectedly -- {}", e)
+                        )),
+                    );
+
+                    // Drivers are never supposed to exit on their own
+                    // so the JoinHandle only returns `Ok()` after the
+                    // instance was shut down. We can't stop drivers
+                    // from panicking, however, so we have to look for
+                    // an `Err()` value.
+
+                    match task.await {
+                        Ok(()) => return,
+                        Err(e) => {
+                            error!("driver exited unexpectedly -- {}", e);
+                            status.failed(e.to_string());
+                            status.publish().await
+                        }
+                    }
+
+                    // Only an instance that ran for a while gets to
+                    // restart quickly. One that keeps failing right
+                    // after it starts waits longer each time.
+
+                    if started.elapsed() >= Backoff::STABLE {
+                        backoff.reset()
+                    }
+                }
+                Err(e) => {
+                    status.failed(e);
+                    status.publish().await
                 }
             }
 
@@ -89,14 +293,21 @@ where
             // system from being compute-bound if the driver panics right
             // away.
 
-            warn!("delay before restarting driver ...");
-            tokio::time::sleep(tokio::time::Duration::from_secs(restart_delay))
-                .await;
+            let delay = backoff.next(rand::random::<f64>() * 0.25);
 
-            // Stretch the timeout each time we have to restart. Set the
-            // max timeout to 10 minutes.
+            warn!(
+                "delay of {:.1}s before restarting driver ...",
+                delay.as_secs_f64()
+            );
+            status.delayed(delay);
 
-            restart_delay = std::cmp::min(restart_delay * 2, MAX_DELAY);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => (),
+                _ = stop.requested() => return
+            }
+
+            status.restarting();
+            status.publish().await;
             info!("restarting instance of driver");
         }
     })
@@ -107,14 +318,17 @@ where
 // (T::DeviceSet), so one function wouldn't be able to handle every
 // type.
 
-fn manage_instance<T>(
+fn manage_instance<F>(
+    factory: Arc<F>,
     name: driver::Name,
     cfg: driver::DriverConfig,
     req_chan: driver::RequestChan,
     max_history: Option<usize>,
+    status: status::Reporter,
+    stop: shutdown::Stop,
 ) -> MgrFuncRet
 where
-    T: driver::API + Send + 'static,
+    F: Factory,
 {
     // Return a future that returns an error if the devices couldn't
     // be registered, or returns a future that manages the running
@@ -123,7 +337,8 @@ where
     Box::pin(async move {
         // Let the driver API register the necessary devices.
 
-        let devices = T::register_devices(req_chan, &cfg, max_history)
+        let devices = factory
+            .register_devices(req_chan.clone(), &cfg, max_history)
             .instrument(info_span!("one-time-init", name = name.as_ref()))
             .await?;
 
@@ -132,18 +347,89 @@ where
         Ok(Box::pin(async move {
             let drv_name = name.clone();
 
-            mgr_body::<T>(name, devices, cfg)
+            let setup = Setup {
+                factory,
+                cfg,
+                req_chan,
+                max_history,
+            };
+
+            mgr_body(setup, name, devices, status, stop)
                 .instrument(info_span!("mngr", drvr = drv_name.as_ref()))
                 .await
         }) as MgrTask)
     }) as MgrFuncRet
 }
 
+// Returns the function that starts instances of the drivers `factory`
+// creates.
+
+fn launcher<F: Factory>(factory: F) -> Launcher {
+    let factory = Arc::new(factory);
+
+    Arc::new(move |name, cfg, req_chan, max_history, status, stop| {
+        manage_instance(
+            factory.clone(),
+            name,
+            cfg,
+            req_chan,
+            max_history,
+            status,
+            stop,
+        )
+    })
+}
+
+fn builtin<T: driver::API + 'static>() -> Launcher {
+    launcher(BuiltIn::<T>(PhantomData))
+}
+
+// Returns the launcher of a driver that uses hardware, or the
+// network. When simulating, its instances are simulated.
+
+#[cfg_attr(
+    not(any(
+        feature = "wasm",
+        feature = "drmem-drv-ntp",
+        feature = "drmem-drv-sump",
+        feature = "drmem-drv-weather-wu",
+        feature = "drmem-drv-tplink",
+        feature = "drmem-drv-notify",
+        feature = "drmem-drv-remote"
+    )),
+    allow(dead_code)
+)]
+fn hardware<T: driver::API + 'static>(simulate: bool) -> Launcher {
+    if simulate {
+        launcher(simulate::Simulated(BuiltIn::<T>(PhantomData)))
+    } else {
+        builtin::<T>()
+    }
+}
+
+// Holds the drivers built into `drmemd` and the status of the
+// instances that were started. The last field is `true` if the
+// drivers that use hardware are simulated.
+
 #[derive(Clone)]
-pub struct DriverDb(Arc<HashMap<driver::Name, DriverInfo>>);
+pub struct DriverDb(
+    Arc<HashMap<driver::Name, DriverInfo>>,
+    status::Table,
+    bool,
+);
 
 impl DriverDb {
     pub fn create() -> DriverDb {
+        DriverDb::build(false)
+    }
+
+    // Creates the table used by `--simulate`.
+
+    pub fn simulated() -> DriverDb {
+        DriverDb::build(true)
+    }
+
+    fn build(simulate: bool) -> DriverDb {
         let mut table: HashMap<driver::Name, DriverInfo> = HashMap::new();
 
         {
@@ -154,7 +440,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    builtin::<Instance>(),
                 ),
             );
         }
@@ -167,7 +453,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    builtin::<Instance>(),
                 ),
             );
         }
@@ -180,7 +466,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    builtin::<Instance>(),
                 ),
             );
         }
@@ -193,7 +479,49 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    builtin::<Instance>(),
+                ),
+            );
+        }
+
+        {
+            use drv_thermostat::Instance;
+
+            table.insert(
+                Instance::NAME.into(),
+                (
+                    Instance::SUMMARY,
+                    Instance::DESCRIPTION,
+                    builtin::<Instance>(),
+                ),
+            );
+        }
+
+        {
+            use drv_sequencer::Instance;
+
+            table.insert(
+                Instance::NAME.into(),
+                (
+                    Instance::SUMMARY,
+                    Instance::DESCRIPTION,
+                    builtin::<Instance>(),
+                ),
+            );
+        }
+
+        // Load the set-up for the WebAssembly driver host.
+
+        #[cfg(feature = "wasm")]
+        {
+            use drv_wasm::Instance;
+
+            table.insert(
+                Instance::NAME.into(),
+                (
+                    Instance::SUMMARY,
+                    Instance::DESCRIPTION,
+                    hardware::<Instance>(simulate),
                 ),
             );
         }
@@ -209,7 +537,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    hardware::<Instance>(simulate),
                 ),
             );
         }
@@ -225,7 +553,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    hardware::<Instance>(simulate),
                 ),
             );
         }
@@ -241,7 +569,7 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    hardware::<Instance>(simulate),
                 ),
             );
         }
@@ -257,12 +585,77 @@ impl DriverDb {
                 (
                     Instance::SUMMARY,
                     Instance::DESCRIPTION,
-                    manage_instance::<Instance>,
+                    hardware::<Instance>(simulate),
+                ),
+            );
+        }
+
+        // Load the set-up for the notification driver.
+
+        #[cfg(feature = "drmem-drv-notify")]
+        {
+            use drmem_drv_notify::Instance;
+
+            table.insert(
+                Instance::NAME.into(),
+                (
+                    Instance::SUMMARY,
+                    Instance::DESCRIPTION,
+                    hardware::<Instance>(simulate),
+                ),
+            );
+        }
+
+        // Load the set-up for the driver that mirrors devices of
+        // another node.
+
+        #[cfg(feature = "drmem-drv-remote")]
+        {
+            use drmem_drv_remote::Instance;
+
+            table.insert(
+                Instance::NAME.into(),
+                (
+                    Instance::SUMMARY,
+                    Instance::DESCRIPTION,
+                    hardware::<Instance>(simulate),
                 ),
             );
         }
 
-        DriverDb(Arc::new(table))
+        DriverDb(Arc::new(table), status::Table::default(), simulate)
+    }
+
+    /// Adds the drivers of plugins. A driver with the name of one
+    /// that's already in the table is skipped.
+
+    #[cfg(feature = "plugins")]
+    fn add_drivers(mut self, drivers: Vec<plugins::Plugin>) -> Self {
+        let table = Arc::make_mut(&mut self.0);
+
+        for drv in drivers {
+            if table.contains_key(drv.name) {
+                warn!("plugin driver {} is already defined", drv.name);
+                continue;
+            }
+
+            let info = (drv.name, drv.summary, drv.description);
+            let launch = if self.2 {
+                launcher(simulate::Simulated(drv))
+            } else {
+                launcher(drv)
+            };
+
+            table.insert(info.0.into(), (info.1, info.2, launch));
+        }
+        self
+    }
+
+    /// Adds the drivers of the plugins in `dir`.
+
+    #[cfg(feature = "plugins")]
+    pub fn with_plugins(self, dir: &std::path::Path) -> Result<Self> {
+        Ok(self.add_drivers(plugins::load(dir)?))
     }
 
     /// Searches the map for a driver with the specified name. If
@@ -272,6 +665,17 @@ impl DriverDb {
         self.0.get(key)
     }
 
+    /// Returns `true` if instances of the driver are simulated.
+
+    pub fn is_simulated(&self, key: &str) -> bool {
+        self.2 && simulate::replaces(key)
+    }
+
+    /// Returns the table holding the status of the driver instances.
+    pub fn status(&self) -> &status::Table {
+        &self.1
+    }
+
     /// Searches the map for a driver with the specified name. If
     /// found, it extracts the information needed for the GraphQL
     /// query and returns it.
@@ -298,3 +702,125 @@ impl DriverDb {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new();
+        let secs = Duration::from_secs;
+
+        // The delay doubles after each failure.
+
+        assert_eq!(backoff.next(0.0), secs(5));
+        assert_eq!(backoff.next(0.0), secs(10));
+        assert_eq!(backoff.next(0.5), secs(30));
+        assert_eq!(backoff.next(0.0), secs(40));
+
+        // It stops growing at the limit, but jitter is still added.
+
+        for _ in 0..10 {
+            backoff.next(0.0);
+        }
+        assert_eq!(backoff.next(0.0), secs(600));
+        assert_eq!(backoff.next(0.1), secs(660));
+
+        // Resetting it starts over.
+
+        backoff.reset();
+        assert_eq!(backoff.next(0.0), secs(5));
+    }
+
+    // A driver that reports `true` when it runs and `false` when it's
+    // shut down.
+
+    static LOG: std::sync::Mutex<Vec<bool>> = std::sync::Mutex::new(vec![]);
+
+    struct Probe;
+
+    impl driver::API for Probe {
+        type DeviceSet = driver::ReadOnlyDevice<bool>;
+
+        fn register_devices(
+            _: driver::RequestChan,
+            _: &driver::DriverConfig,
+            _: Option<usize>,
+        ) -> Fut<Result<Self::DeviceSet>> {
+            Box::pin(async {
+                Ok(driver::ReadOnlyDevice::new(Box::new(|v| {
+                    if let drmem_api::device::Value::Bool(v) = v {
+                        LOG.lock().unwrap().push(v)
+                    }
+                    Box::pin(async {})
+                })))
+            })
+        }
+
+        fn create_instance(_: &driver::DriverConfig) -> Fut<Result<Box<Self>>> {
+            Box::pin(async { Ok(Box::new(Probe)) })
+        }
+
+        fn run<'a>(
+            &'a mut self,
+            devices: Arc<Mutex<Self::DeviceSet>>,
+        ) -> Pin<Box<dyn Future<Output = std::convert::Infallible> + Send + 'a>>
+        {
+            Box::pin(async move {
+                devices.lock().await.report_update(true).await;
+                std::future::pending().await
+            })
+        }
+
+        fn shutdown<'a>(
+            &'a mut self,
+            devices: Arc<Mutex<Self::DeviceSet>>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+            Box::pin(
+                async move { devices.lock().await.report_update(false).await },
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(10);
+        let cfg = driver::DriverConfig::new();
+        let req_chan = driver::RequestChan::new(
+            "probe".into(),
+            &"test".parse().unwrap(),
+            &tx,
+        );
+        let status =
+            status::Table::default().add("probe".into(), "test".into(), &cfg);
+        let (tx_stop, stop) = shutdown::Stop::new();
+        let mgr = tokio::spawn(
+            manage_instance(
+                Arc::new(BuiltIn::<Probe>(PhantomData)),
+                "probe".into(),
+                cfg,
+                req_chan,
+                None,
+                status,
+                stop,
+            )
+            .await
+            .unwrap(),
+        );
+
+        while LOG.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await
+        }
+
+        // The manager returns once the instance has run its shutdown
+        // hook.
+
+        tx_stop.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(1), mgr)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*LOG.lock().unwrap(), [true, false]);
+    }
+}
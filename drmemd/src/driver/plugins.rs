@@ -0,0 +1,714 @@
+// Loads drivers from plugins, which are shared libraries built
+// separately from `drmemd`. Every library in the plugin directory is
+// loaded when `drmemd` starts. A library that can't be loaded, or was
+// built for a different version of the plugin interface, is logged
+// and skipped.
+//
+// `drmemd` and a plugin only call each other through the `extern "C"`
+// functions of `drmem_api::plugin`. This module is the `drmemd` side:
+// it registers the devices of plugin drivers with the core and runs
+// their instances with the functions the plugin exports. Calling into
+// a library `drmemd` didn't build can't be checked by the compiler,
+// so this is the only module that uses unsafe code.
+//
+// Loaded libraries are never unloaded, since their code runs until
+// `drmemd` exits.
+
+#![allow(unsafe_code)]
+
+use super::{Factory, Fut, Run};
+use drmem_api::{
+    device, driver,
+    plugin::{
+        self, Declaration, Done, Endpoints, Owned, Ptr, RawEndpoint, RawValue,
+        Settings, Str,
+    },
+    Error, Result,
+};
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, Mutex},
+};
+use tracing::{debug, error, info, level_filters::LevelFilter, trace, warn};
+
+fn history(max_history: isize) -> Option<usize> {
+    usize::try_from(max_history).ok()
+}
+
+// The request channel of a driver instance. It's given to the plugin
+// when the instance registers its devices. The plugin's requests are
+// handled on the `drmemd` runtime.
+
+struct Chan {
+    drc: driver::RequestChan,
+    runtime: Handle,
+}
+
+extern "C" fn log(level: u8, target: Str, text: Str) {
+    // SAFETY: The plugin passes strings that are valid during this
+    // call.
+
+    let target = unsafe { target.get() }.unwrap_or("plugin");
+    let text = unsafe { text.get() }.unwrap_or_default();
+
+    match level {
+        1 => error!(plugin = target, "{}", text),
+        2 => warn!(plugin = target, "{}", text),
+        3 => info!(plugin = target, "{}", text),
+        4 => debug!(plugin = target, "{}", text),
+        _ => trace!(plugin = target, "{}", text),
+    }
+}
+
+// A device registered by a driver in a plugin. A writable device
+// holds where its settings are sent and the plugin function that
+// sends them.
+
+enum Dev {
+    Ro(driver::ReadOnlyDevice<device::Value>),
+    Rw(
+        driver::ReadWriteDevice<device::Value>,
+        Owned,
+        extern "C" fn(Ptr, &RawValue, Done),
+    ),
+}
+
+type Reading = (device::Value, Done);
+
+impl Dev {
+    async fn report(&mut self, v: device::Value) {
+        match self {
+            Dev::Ro(dev) => dev.report_update(v).await,
+            Dev::Rw(dev, ..) => dev.report_update(v).await,
+        }
+    }
+
+    async fn next_setting(
+        &mut self,
+    ) -> Option<(device::Value, driver::SettingReply<device::Value>)> {
+        match self {
+            Dev::Ro(_) => std::future::pending().await,
+            Dev::Rw(dev, ..) => dev.next_setting().await,
+        }
+    }
+
+    // Passes a setting to the plugin. The reply is sent to the client
+    // once the driver has handled it.
+
+    fn send(
+        &self,
+        v: device::Value,
+        reply: driver::SettingReply<device::Value>,
+    ) {
+        if let Dev::Rw(_, settings, send) = self {
+            let (done, rx) = Done::channel();
+
+            send(settings.0, &RawValue::new(&v), done);
+            tokio::spawn(async move {
+                reply(
+                    plugin::wait(rx)
+                        .await
+                        .and_then(|(_, v)| v.ok_or(Error::TypeError)),
+                )
+            });
+        }
+    }
+}
+
+// Reports the readings of a device, in the order the plugin sent
+// them, and passes its settings to the plugin. It ends when the
+// plugin frees the reporter.
+
+async fn serve(mut dev: Dev, mut rx: mpsc::UnboundedReceiver<Reading>) {
+    loop {
+        tokio::select! {
+            v = rx.recv() => match v {
+                Some((v, done)) => {
+                    dev.report(v).await;
+                    done.finish(Ok((Ptr::NULL, None)))
+                }
+                None => break,
+            },
+            Some((v, reply)) = dev.next_setting() => dev.send(v, reply)
+        }
+    }
+}
+
+// Starts the task that serves a device and returns the reporter the
+// plugin passes readings to.
+
+fn reporter(dev: Dev) -> Ptr {
+    let (tx, rx) = mpsc::unbounded_channel::<Reading>();
+
+    tokio::spawn(serve(dev, rx));
+    Ptr::new(tx)
+}
+
+extern "C" fn add_ro_device(
+    chan: Ptr,
+    name: Str,
+    units: Str,
+    max_history: isize,
+    done: Done,
+) {
+    // SAFETY: `chan` was created by `Plugin::register_devices()` and
+    // the plugin hasn't freed it. The strings are valid during this
+    // call.
+
+    let chan = unsafe { chan.get::<Chan>() };
+    let name = unsafe { name.get() }.map(str::parse::<device::Base>);
+    let units = unsafe { units.get() }.map(String::from);
+    let drc = chan.drc.clone();
+
+    chan.runtime.spawn(async move {
+        let result = match name {
+            Some(Ok(name)) => {
+                drc.add_ro_device(name, units.as_deref(), history(max_history))
+                    .await
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(Error::ParseError("device name isn't UTF-8".into())),
+        };
+
+        done.finish(result.map(|dev| (reporter(Dev::Ro(dev)), None)))
+    });
+}
+
+extern "C" fn add_rw_device(
+    chan: Ptr,
+    name: Str,
+    units: Str,
+    max_history: isize,
+    settings: Settings,
+    done: Done,
+) {
+    // SAFETY: `chan` was created by `Plugin::register_devices()` and
+    // the plugin hasn't freed it. The strings are valid during this
+    // call.
+
+    let chan = unsafe { chan.get::<Chan>() };
+    let name = unsafe { name.get() }.map(str::parse::<device::Base>);
+    let units = unsafe { units.get() }.map(String::from);
+    let drc = chan.drc.clone();
+    let Settings { ctx, send, free } = settings;
+    let settings = Owned(ctx, free);
+
+    chan.runtime.spawn(async move {
+        let result = match name {
+            Some(Ok(name)) => {
+                drc.add_rw_device(name, units.as_deref(), history(max_history))
+                    .await
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(Error::ParseError("device name isn't UTF-8".into())),
+        };
+
+        done.finish(result.map(|dev| {
+            let prev = dev.get_last().cloned();
+
+            (reporter(Dev::Rw(dev, settings, send)), prev)
+        }))
+    });
+}
+
+extern "C" fn report(reporter: Ptr, value: &RawValue, done: Done) {
+    // SAFETY: `reporter` was created by `reporter()` and the plugin
+    // hasn't freed it. The value is valid during this call.
+
+    let tx = unsafe { reporter.get::<mpsc::UnboundedSender<Reading>>() };
+
+    match unsafe { value.get() } {
+        Some(v) => {
+            let _ = tx.send((v, done));
+        }
+        None => done.finish(Err(Error::TypeError)),
+    }
+}
+
+extern "C" fn free_reporter(reporter: Ptr) {
+    // SAFETY: `reporter` was created by `reporter()` and the plugin
+    // frees it once.
+
+    drop(unsafe { reporter.take::<mpsc::UnboundedSender<Reading>>() })
+}
+
+extern "C" fn discover(
+    chan: Ptr,
+    kind: u8,
+    service: Str,
+    endpoints: Endpoints,
+    done: Done,
+) {
+    // SAFETY: `chan` was created by `Plugin::register_devices()` and
+    // the plugin hasn't freed it. The string is valid during this
+    // call.
+
+    let chan = unsafe { chan.get::<Chan>() };
+    let service =
+        unsafe { service.get() }.map(|v| plugin::service_from(kind, v));
+    let drc = chan.drc.clone();
+    let Endpoints { ctx, send, free } = endpoints;
+    let endpoints = Owned(ctx, free);
+
+    chan.runtime.spawn(async move {
+        let Some(service) = service else {
+            return done
+                .finish(Err(Error::ParseError("service isn't UTF-8".into())));
+        };
+
+        match drc.discover(service).await {
+            Ok(mut rx) => {
+                done.finish(Ok((Ptr::NULL, None)));
+
+                while let Some(ep) = rx.recv().await {
+                    let addr = ep.addr.to_string();
+                    let info = RawEndpoint::info(&ep);
+
+                    if !send(endpoints.0, &RawEndpoint::new(&ep, &addr, &info))
+                    {
+                        break;
+                    }
+                }
+            }
+            Err(e) => done.finish(Err(e)),
+        }
+    });
+}
+
+extern "C" fn free_chan(chan: Ptr) {
+    // SAFETY: `chan` was created by `Plugin::register_devices()` and
+    // the plugin frees it once.
+
+    drop(unsafe { chan.take::<Chan>() })
+}
+
+static HOST: plugin::Host = plugin::Host {
+    log,
+    add_ro_device,
+    add_rw_device,
+    report,
+    free_reporter,
+    discover,
+    free_chan,
+};
+
+// A driver in a plugin. It creates the instances of the driver.
+
+pub struct Plugin {
+    pub name: &'static str,
+    pub summary: &'static str,
+    pub description: &'static str,
+    decl: &'static Declaration,
+    drv: &'static plugin::Driver,
+}
+
+// The device set of an instance of a driver in a plugin. Only the
+// plugin looks into it.
+
+pub struct Devices(Owned);
+
+// An instance of a driver in a plugin.
+
+pub struct Plugged {
+    instance: Owned,
+    decl: &'static Declaration,
+}
+
+impl Run for Plugged {
+    type DeviceSet = Devices;
+
+    // The instance runs in a task of the plugin. If this future is
+    // dropped, the task is cancelled. The task only ends on its own
+    // if the driver panicked, which is passed on so the instance is
+    // restarted.
+
+    fn run(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>> {
+        Box::pin(async move {
+            let (done, rx) = Done::channel();
+            let devices = devices.lock().await.0 .0;
+            let task = (self.decl.run)(self.instance.0, devices, done);
+            let _task = Owned(task, self.decl.cancel);
+
+            match plugin::wait(rx).await {
+                Ok(_) => panic!("driver exited"),
+                Err(e) => panic!("{}", e),
+            }
+        })
+    }
+
+    fn shutdown(
+        &mut self,
+        devices: Arc<Mutex<Self::DeviceSet>>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let (done, rx) = Done::channel();
+            let devices = devices.lock().await.0 .0;
+
+            (self.decl.shutdown)(self.instance.0, devices, done);
+            let _ = rx.await;
+        })
+    }
+}
+
+impl Factory for Plugin {
+    type Driver = Plugged;
+
+    fn register_devices(
+        &self,
+        drc: driver::RequestChan,
+        cfg: &driver::DriverConfig,
+        max_history: Option<usize>,
+    ) -> Fut<Result<Devices>> {
+        let decl = self.decl;
+        let rx = toml::to_string(cfg)
+            .map_err(|e| Error::ConfigError(e.to_string()))
+            .map(|cfg| {
+                let (done, rx) = Done::channel();
+                let chan = Ptr::new(Chan {
+                    drc,
+                    runtime: Handle::current(),
+                });
+
+                (self.drv.register_devices)(
+                    chan,
+                    Str::new(&cfg),
+                    max_history.map_or(-1, |v| v as isize),
+                    done,
+                );
+                rx
+            });
+
+        Box::pin(async move {
+            let (devices, _) = plugin::wait(rx?).await?;
+
+            Ok(Devices(Owned(devices, decl.free_devices)))
+        })
+    }
+
+    fn create_instance(
+        &self,
+        cfg: &driver::DriverConfig,
+    ) -> Fut<Result<Box<Plugged>>> {
+        let decl = self.decl;
+        let rx = toml::to_string(cfg)
+            .map_err(|e| Error::ConfigError(e.to_string()))
+            .map(|cfg| {
+                let (done, rx) = Done::channel();
+
+                (self.drv.create_instance)(Str::new(&cfg), done);
+                rx
+            });
+
+        Box::pin(async move {
+            let (instance, _) = plugin::wait(rx?).await?;
+
+            Ok(Box::new(Plugged {
+                instance: Owned(instance, decl.free_instance),
+                decl,
+            }))
+        })
+    }
+}
+
+// Initializes a plugin and returns its drivers.
+
+fn drivers(
+    decl: &'static Declaration,
+) -> std::result::Result<Vec<Plugin>, String> {
+    (decl.init)(&HOST, plugin::level_code(LevelFilter::current()));
+
+    // SAFETY: The interface version of the plugin was checked. The
+    // strings of its drivers are static.
+
+    let text = |v: Str| -> std::result::Result<&'static str, String> {
+        unsafe { v.get() }.ok_or_else(|| "a driver's name isn't UTF-8".into())
+    };
+
+    unsafe { decl.drivers() }
+        .iter()
+        .map(|drv| {
+            Ok(Plugin {
+                name: text(drv.name)?,
+                summary: text(drv.summary)?,
+                description: text(drv.description)?,
+                decl,
+                drv,
+            })
+        })
+        .collect()
+}
+
+fn is_library(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|v| v == std::env::consts::DLL_EXTENSION)
+}
+
+// Loads one plugin and returns its drivers.
+
+fn load_one(path: &Path) -> std::result::Result<Vec<Plugin>, String> {
+    // SAFETY: Loading a library runs its initialization code. The
+    // plugin directory should only hold trusted plugins.
+
+    let lib =
+        unsafe { libloading::Library::new(path) }.map_err(|e| e.to_string())?;
+
+    // SAFETY: The symbol is the `Declaration` that `export_plugin!`
+    // exports. Its interface version, which is first, is checked
+    // before anything else is used.
+
+    let decl: &'static Declaration = unsafe {
+        let sym = lib
+            .get::<*const Declaration>(plugin::SYMBOL)
+            .map_err(|e| e.to_string())?;
+
+        &**sym
+    };
+
+    if decl.abi != plugin::ABI {
+        return Err(format!(
+            "built for plugin interface {}, but drmemd uses {}",
+            decl.abi,
+            plugin::ABI
+        ));
+    }
+
+    // The plugin's code has to stay loaded once it's initialized.
+
+    std::mem::forget(lib);
+    drivers(decl)
+}
+
+// Loads the plugins in `dir`, in name order, and returns their
+// drivers.
+
+pub fn load(dir: &Path) -> Result<Vec<Plugin>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| {
+            Error::ConfigError(format!(
+                "couldn't read plugin directory {} -- {}",
+                dir.display(),
+                e
+            ))
+        })?
+        .filter_map(|v| v.ok().map(|v| v.path()))
+        .filter(|v| is_library(v))
+        .collect();
+
+    files.sort();
+
+    let mut drivers = vec![];
+
+    for file in files {
+        match load_one(&file) {
+            Ok(v) => {
+                info!(
+                    "loaded plugin {} -- drivers: {:?}",
+                    file.display(),
+                    v.iter().map(|v| v.name).collect::<Vec<_>>()
+                );
+                drivers.extend(v)
+            }
+            Err(e) => error!("skipping plugin {} -- {}", file.display(), e),
+        }
+    }
+    Ok(drivers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{driver::DriverDb, shutdown};
+    use tokio::sync::oneshot;
+
+    // A driver built like the ones in plugins. It's exported from the
+    // test instead of a library, so it goes through the same
+    // interface.
+
+    struct Counter(i32);
+
+    impl Counter {
+        pub const NAME: &'static str = "counter";
+        pub const SUMMARY: &'static str = "counts";
+        pub const DESCRIPTION: &'static str = "Counts up once.";
+    }
+
+    impl driver::API for Counter {
+        type DeviceSet =
+            (driver::ReadOnlyDevice<i32>, driver::ReadWriteDevice<bool>);
+
+        fn register_devices(
+            drc: driver::RequestChan,
+            _: &driver::DriverConfig,
+            max_history: Option<usize>,
+        ) -> Fut<Result<Self::DeviceSet>> {
+            Box::pin(async move {
+                Ok((
+                    drc.add_ro_device("count".parse()?, None, max_history)
+                        .await?,
+                    drc.add_rw_device("enable".parse()?, None, max_history)
+                        .await?,
+                ))
+            })
+        }
+
+        fn create_instance(
+            cfg: &driver::DriverConfig,
+        ) -> Fut<Result<Box<Self>>> {
+            let start =
+                cfg.get("start").and_then(|v| v.as_integer()).unwrap_or(0)
+                    as i32;
+
+            Box::pin(async move { Ok(Box::new(Counter(start))) })
+        }
+
+        // Counts, reports the saved value of the writable device,
+        // and then accepts its settings.
+
+        fn run(
+            &mut self,
+            devices: Arc<Mutex<Self::DeviceSet>>,
+        ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>> {
+            Box::pin(async move {
+                let mut devices = devices.lock().await;
+                let (count, enable) = &mut *devices;
+                let last = enable.get_last().copied().unwrap_or_default();
+
+                self.0 += 1;
+                count.report_update(self.0).await;
+                enable.report_update(last).await;
+
+                while let Some((v, reply)) = enable.next_setting().await {
+                    reply(Ok(v))
+                }
+                std::future::pending().await
+            })
+        }
+
+        fn shutdown(
+            &mut self,
+            devices: Arc<Mutex<Self::DeviceSet>>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+            Box::pin(
+                async move { devices.lock().await.0.report_update(-1).await },
+            )
+        }
+    }
+
+    drmem_api::export_plugin!(Counter);
+
+    #[tokio::test]
+    async fn test_plugin_driver() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (tx_val, mut rx_val) = mpsc::unbounded_channel();
+        let (tx_set, rx_set) = mpsc::channel(10);
+        let db =
+            DriverDb::create().add_drivers(drivers(&DRMEM_PLUGIN).unwrap());
+
+        // Fake the core. The readings are sent to the test and the
+        // writable device gets its settings from the test.
+
+        tokio::spawn(async move {
+            let mut rx_set = Some(rx_set);
+
+            while let Some(req) = rx.recv().await {
+                let tx_val = tx_val.clone();
+                let report: driver::ReportReading = Box::new(move |v| {
+                    let _ = tx_val.send(v);
+                    Box::pin(async {})
+                });
+
+                match req {
+                    driver::Request::AddReadonlyDevice { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Ok(report));
+                    }
+                    driver::Request::AddReadWriteDevice {
+                        rpy_chan, ..
+                    } => {
+                        let _ = rpy_chan.send(Ok((
+                            report,
+                            rx_set.take().unwrap(),
+                            Some(true.into()),
+                        )));
+                    }
+                    driver::Request::Discover { .. } => (),
+                }
+            }
+        });
+
+        let info = db.get_driver(Counter::NAME).unwrap();
+        let mut cfg = driver::DriverConfig::new();
+
+        cfg.insert("start".into(), 41.into());
+
+        let (tx_stop, stop) = shutdown::Stop::new();
+        let status = db.status().add(Counter::NAME.into(), "test".into(), &cfg);
+        let mgr = (info.2)(
+            Counter::NAME.into(),
+            cfg,
+            driver::RequestChan::new(
+                Counter::NAME.into(),
+                &"test".parse().unwrap(),
+                &tx,
+            ),
+            None,
+            status,
+            stop,
+        )
+        .await
+        .unwrap();
+        let mgr = tokio::spawn(mgr);
+
+        assert_eq!(info.0, "counts");
+        assert_eq!(rx_val.recv().await, Some(42.into()));
+        assert_eq!(rx_val.recv().await, Some(true.into()));
+
+        // Settings reach the driver and its replies come back. A
+        // setting of the wrong type is rejected.
+
+        let (tx_rpy, rx_rpy) = oneshot::channel();
+
+        tx_set.send((false.into(), tx_rpy)).await.unwrap();
+        assert_eq!(rx_rpy.await.unwrap(), Ok(false.into()));
+
+        let (tx_rpy, rx_rpy) = oneshot::channel();
+
+        tx_set.send((1.into(), tx_rpy)).await.unwrap();
+        assert_eq!(rx_rpy.await.unwrap(), Err(Error::TypeError));
+
+        tx_stop.send_replace(true);
+        mgr.await.unwrap();
+        assert_eq!(rx_val.recv().await, Some((-1).into()));
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join("drmem-test-plugins");
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README"), "not a plugin").unwrap();
+
+        // Files that aren't libraries are skipped, too.
+
+        std::fs::write(
+            dir.join(format!("bad.{}", std::env::consts::DLL_EXTENSION)),
+            "not a library",
+        )
+        .unwrap();
+
+        assert!(load(&dir).unwrap().is_empty());
+        assert!(load(&dir.join("missing")).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
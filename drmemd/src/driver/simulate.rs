@@ -0,0 +1,411 @@
+// Replaces the drivers that talk to hardware, or the network, when
+// `drmemd` is started with `--simulate`. This lets a configuration be
+// tried on a machine that can't reach the devices.
+//
+// A simulated instance still runs its driver's `register_devices()`,
+// so it gets the same devices the real driver would have, but it
+// registers them through a channel that intercepts the requests. The
+// driver is given devices that go nowhere and the simulation keeps
+// the real ones:
+//
+// - Read-only devices report a value every few seconds. Numbers
+//   wander around their starting value and booleans flip now and
+//   then.
+//
+// - Settable devices accept every setting and report it as their
+//   reading.
+//
+// The driver instance is never created, so nothing is opened or
+// contacted. Starting values can be given, by device name, in the
+// `simulate` table of the instance's configuration.
+
+use super::{drv_cycle, drv_map, drv_memory, drv_sequencer, drv_thermostat};
+use super::{drv_timer, Factory, Fut, Run};
+use drmem_api::{device, driver, Error, Result};
+use futures::future::Future;
+use std::{collections::HashMap, convert::Infallible, pin::Pin, sync::Arc};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinSet,
+    time::{interval, Duration},
+};
+use tracing::info;
+
+const PERIOD: Duration = Duration::from_secs(5);
+
+// The built-in drivers that only compute values. They run normally
+// when simulating.
+
+const SOFTWARE: [&str; 6] = [
+    drv_memory::Instance::NAME,
+    drv_map::Instance::NAME,
+    drv_timer::Instance::NAME,
+    drv_cycle::Instance::NAME,
+    drv_thermostat::Instance::NAME,
+    drv_sequencer::Instance::NAME,
+];
+
+// Returns `true` if instances of the driver are simulated.
+
+pub fn replaces(name: &str) -> bool {
+    !SOFTWARE.contains(&name)
+}
+
+// Starting values of the devices, keyed by the last section of their
+// names.
+
+pub type Values = HashMap<String, device::Value>;
+
+// Converts the `simulate` table of an instance's configuration.
+
+pub fn values(cfg: Option<&driver::DriverConfig>) -> Result<Values> {
+    cfg.into_iter()
+        .flatten()
+        .map(|(k, v)| {
+            device::Value::try_from(v)
+                .map(|v| (k.clone(), v))
+                .map_err(|_| {
+                    Error::ConfigError(format!(
+                        "simulated value of '{}' has an unsupported type",
+                        k
+                    ))
+                })
+        })
+        .collect()
+}
+
+// Returns the value a device starts with. Without one in the
+// configuration, devices with units are assumed to be measurements
+// and the others to be on/off states.
+
+fn initial(
+    values: &Values,
+    name: &device::Name,
+    units: Option<&str>,
+) -> device::Value {
+    values.get(&name.get_name().to_string()).cloned().unwrap_or(
+        if units.is_some() {
+            device::Value::Flt(50.0)
+        } else {
+            device::Value::Bool(false)
+        },
+    )
+}
+
+// Computes the next reading of a read-only device. `noise` is a
+// random number from -1.0 to 1.0. Numbers take a step and are pulled
+// back towards `center`, so they don't drift away.
+
+fn step(
+    value: &device::Value,
+    center: &device::Value,
+    noise: f64,
+) -> device::Value {
+    match (value, center) {
+        (device::Value::Flt(v), device::Value::Flt(c)) => device::Value::Flt(
+            v + (c - v) * 0.1 + noise * (c.abs() * 0.02).max(0.1),
+        ),
+        (device::Value::Int(v), device::Value::Int(c)) => {
+            let delta = (f64::from(c - v) * 0.1 + noise * 1.5).round();
+
+            device::Value::Int(v.saturating_add(delta as i32))
+        }
+        (device::Value::Bool(v), _) if noise.abs() > 0.9 => {
+            device::Value::Bool(!v)
+        }
+        (v, _) => v.clone(),
+    }
+}
+
+async fn read_only(report: driver::ReportReading, center: device::Value) {
+    let mut timer = interval(PERIOD);
+    let mut value = center.clone();
+
+    loop {
+        timer.tick().await;
+        report(value.clone()).await;
+        value = step(&value, &center, rand::random::<f64>() * 2.0 - 1.0)
+    }
+}
+
+async fn settable(
+    report: driver::ReportReading,
+    mut rx_set: driver::RxDeviceSetting,
+    value: Option<device::Value>,
+) {
+    if let Some(v) = value {
+        report(v).await
+    }
+
+    while let Some((v, rpy_chan)) = rx_set.recv().await {
+        report(v.clone()).await;
+        let _ = rpy_chan.send(Ok(v));
+    }
+}
+
+// A reading channel that throws away the readings of the driver.
+
+fn discard() -> driver::ReportReading {
+    Box::new(|_| Box::pin(async {}))
+}
+
+// Handles the requests of a simulated instance. The devices are
+// simulated until the instance's `RequestChan` is dropped.
+
+async fn forward(
+    mut rx: mpsc::Receiver<driver::Request>,
+    core: mpsc::Sender<driver::Request>,
+    values: Values,
+) {
+    let mut devices = JoinSet::new();
+
+    while let Some(req) = rx.recv().await {
+        match req {
+            driver::Request::AddReadonlyDevice {
+                driver_name,
+                dev_name,
+                dev_units,
+                max_history,
+                max_age,
+                rpy_chan,
+            } => {
+                let value = initial(&values, &dev_name, dev_units.as_deref());
+                let (tx, rx) = oneshot::channel();
+                let req = driver::Request::AddReadonlyDevice {
+                    driver_name,
+                    dev_name,
+                    dev_units,
+                    max_history,
+                    max_age,
+                    rpy_chan: tx,
+                };
+
+                if core.send(req).await.is_err() {
+                    break;
+                }
+
+                let Ok(result) = rx.await else { break };
+
+                let _ = rpy_chan.send(result.map(|report| {
+                    devices.spawn(read_only(report, value));
+                    discard()
+                }));
+            }
+            driver::Request::AddReadWriteDevice {
+                driver_name,
+                dev_name,
+                dev_units,
+                max_history,
+                max_age,
+                rpy_chan,
+            } => {
+                let value =
+                    values.get(&dev_name.get_name().to_string()).cloned();
+                let (tx, rx) = oneshot::channel();
+                let req = driver::Request::AddReadWriteDevice {
+                    driver_name,
+                    dev_name,
+                    dev_units,
+                    max_history,
+                    max_age,
+                    rpy_chan: tx,
+                };
+
+                if core.send(req).await.is_err() {
+                    break;
+                }
+
+                let Ok(result) = rx.await else { break };
+
+                let _ = rpy_chan.send(result.map(|(report, rx_set, prev)| {
+                    devices.spawn(settable(
+                        report,
+                        rx_set,
+                        prev.clone().or(value),
+                    ));
+                    (discard(), mpsc::channel(1).1, prev)
+                }));
+            }
+
+            // The network isn't searched, so a simulated instance
+            // doesn't find anything.
+            driver::Request::Discover { rpy_chan, .. } => {
+                let _ = rpy_chan.send(Ok(mpsc::channel(1).1));
+            }
+        }
+    }
+}
+
+// Returns the channel a simulated instance uses to register its
+// devices.
+
+pub fn request_chan(
+    driver_name: driver::Name,
+    prefix: &device::Path,
+    core: &mpsc::Sender<driver::Request>,
+    values: Values,
+) -> driver::RequestChan {
+    let (tx, rx) = mpsc::channel(10);
+
+    tokio::spawn(forward(rx, core.clone(), values));
+    driver::RequestChan::new(driver_name, prefix, &tx)
+}
+
+// Stands in for the instance of a simulated driver. It does nothing;
+// the devices are updated by the task handling its `RequestChan`.
+
+pub struct Idle;
+
+impl Run for Idle {
+    type DeviceSet = ();
+
+    fn run(
+        &mut self,
+        _devices: Arc<Mutex<()>>,
+    ) -> Pin<Box<dyn Future<Output = Infallible> + Send + '_>> {
+        Box::pin(std::future::pending())
+    }
+
+    fn shutdown(
+        &mut self,
+        _devices: Arc<Mutex<()>>,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async {})
+    }
+}
+
+// Creates simulated instances of the drivers `F` creates.
+
+pub struct Simulated<F>(pub F);
+
+impl<F: Factory> Factory for Simulated<F> {
+    type Driver = Idle;
+
+    fn register_devices(
+        &self,
+        drc: driver::RequestChan,
+        cfg: &driver::DriverConfig,
+        max_history: Option<usize>,
+    ) -> Fut<Result<()>> {
+        let fut = self.0.register_devices(drc, cfg, max_history);
+
+        Box::pin(async move { fut.await.map(|_| ()) })
+    }
+
+    fn create_instance(
+        &self,
+        _cfg: &driver::DriverConfig,
+    ) -> Fut<Result<Box<Idle>>> {
+        info!("simulating driver");
+        Box::pin(async { Ok(Box::new(Idle)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step() {
+        let flt = device::Value::Flt(20.0);
+
+        assert_eq!(step(&flt, &flt, 0.0), flt);
+        assert_eq!(step(&flt, &flt, 1.0), device::Value::Flt(20.4));
+        assert_eq!(
+            step(&device::Value::Flt(30.0), &flt, 0.0),
+            device::Value::Flt(29.0)
+        );
+        assert_eq!(
+            step(&device::Value::Int(10), &device::Value::Int(0), 0.0),
+            device::Value::Int(9)
+        );
+        assert_eq!(
+            step(&device::Value::Bool(true), &flt, 0.5),
+            device::Value::Bool(true)
+        );
+        assert_eq!(
+            step(&device::Value::Bool(true), &flt, -0.95),
+            device::Value::Bool(false)
+        );
+        assert!(replaces("tplink"));
+        assert!(!replaces("memory"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_forward() {
+        let (tx_core, mut rx_core) = mpsc::channel(10);
+        let (tx_read, mut rx_read) = mpsc::unbounded_channel();
+        let mut cfg = driver::DriverConfig::new();
+
+        cfg.insert("temp".into(), 70.0.into());
+
+        let drc = request_chan(
+            "test".into(),
+            &"porch".parse().unwrap(),
+            &tx_core,
+            values(Some(&cfg)).unwrap(),
+        );
+
+        // Fake the core. It registers the devices and sends their
+        // readings to the test.
+
+        let (tx_set, rx_set) = mpsc::channel(10);
+        let mut rx_set = Some(rx_set);
+
+        tokio::spawn(async move {
+            while let Some(req) = rx_core.recv().await {
+                let tx_read = tx_read.clone();
+                let report: driver::ReportReading = Box::new(move |v| {
+                    let _ = tx_read.send(v);
+                    Box::pin(async {})
+                });
+
+                match req {
+                    driver::Request::AddReadonlyDevice { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Ok(report));
+                    }
+                    driver::Request::AddReadWriteDevice {
+                        rpy_chan, ..
+                    } => {
+                        let _ = rpy_chan.send(Ok((
+                            report,
+                            rx_set.take().unwrap(),
+                            None,
+                        )));
+                    }
+                    driver::Request::Discover { .. } => (),
+                }
+            }
+        });
+
+        // The driver's devices don't reach the core.
+
+        let mut temp = drc
+            .add_ro_device::<f64>("temp".parse().unwrap(), Some("°F"), None)
+            .await
+            .unwrap();
+
+        temp.report_update(0.0).await;
+        assert_eq!(rx_read.recv().await, Some(device::Value::Flt(70.0)));
+
+        let _state = drc
+            .add_rw_device::<bool>("state".parse().unwrap(), None, None)
+            .await
+            .unwrap();
+
+        // Settings are accepted and reported.
+
+        let (tx, rx) = oneshot::channel();
+
+        tx_set.send((true.into(), tx)).await.unwrap();
+        assert_eq!(rx.await.unwrap(), Ok(device::Value::Bool(true)));
+        assert_eq!(rx_read.recv().await, Some(device::Value::Bool(true)));
+
+        // The read-only device keeps reporting.
+
+        match rx_read.recv().await {
+            Some(device::Value::Flt(v)) => assert!((v - 70.0).abs() < 2.0),
+            v => panic!("unexpected reading {:?}", v),
+        }
+    }
+}
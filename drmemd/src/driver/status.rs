@@ -0,0 +1,334 @@
+// Keeps track of how each driver instance is doing. The driver
+// manager reports when an instance starts running and when it has to
+// be restarted. The table is shared with the GraphQL server, which
+// reports it to operators.
+//
+// Each instance can also have a pair of read-only devices, under
+// `drmem:driver:<prefix>`, so logic blocks can react to it:
+//
+//     alive     true while the instance is running
+//     restarts  the number of times the instance was restarted
+
+use crate::events;
+use crate::monitor::MAX_HISTORY;
+use drmem_api::{driver, Result};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    // The instance is being created.
+    Starting,
+    Running,
+    // The instance stopped and will be restarted after a delay.
+    Restarting,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+pub struct Instance {
+    pub driver: driver::Name,
+    pub prefix: String,
+    // The names of the instance's configuration parameters. The
+    // values aren't kept since they may hold passwords or keys.
+    pub config: Vec<String>,
+    pub state: State,
+    // When the instance entered its current state.
+    pub since: SystemTime,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    // When a stopped instance will be restarted.
+    pub restart_at: Option<SystemTime>,
+}
+
+// Entries of instances that were removed are left empty so the
+// indices of the other entries don't change.
+
+#[derive(Clone, Default)]
+pub struct Table(Arc<Mutex<Vec<Option<Instance>>>>);
+
+impl Table {
+    // Adds a driver instance to the table and returns the handle used
+    // to update its entry.
+
+    pub fn add(
+        &self,
+        driver: driver::Name,
+        prefix: String,
+        cfg: &driver::DriverConfig,
+    ) -> Reporter {
+        let mut table = self.0.lock().unwrap();
+
+        table.push(Some(Instance {
+            driver,
+            prefix,
+            config: cfg.keys().cloned().collect(),
+            state: State::Starting,
+            since: SystemTime::now(),
+            restarts: 0,
+            last_error: None,
+            restart_at: None,
+        }));
+
+        Reporter {
+            table: self.clone(),
+            idx: table.len() - 1,
+            watchdog: None,
+        }
+    }
+
+    #[cfg(any(feature = "graphql", test))]
+    pub fn get_all(&self) -> Vec<Instance> {
+        self.0.lock().unwrap().iter().flatten().cloned().collect()
+    }
+
+    // Returns the number of instances that are running.
+
+    pub fn running(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .filter(|v| v.state == State::Running)
+            .count()
+    }
+}
+
+// The devices that report the state of an instance.
+
+pub struct Watchdog {
+    alive: driver::ReadOnlyDevice<bool>,
+    restarts: driver::ReadOnlyDevice<i32>,
+}
+
+impl Watchdog {
+    // Registers the devices. `reg` has to use the instance's path
+    // under `drmem:driver`.
+
+    pub async fn register(reg: &driver::RequestChan) -> Result<Self> {
+        Ok(Watchdog {
+            alive: reg
+                .add_ro_device("alive".parse()?, None, MAX_HISTORY)
+                .await?,
+            restarts: reg
+                .add_ro_device("restarts".parse()?, None, MAX_HISTORY)
+                .await?,
+        })
+    }
+}
+
+pub struct Reporter {
+    table: Table,
+    idx: usize,
+    watchdog: Option<Watchdog>,
+}
+
+impl Reporter {
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Instance)) {
+        if let Some(Some(entry)) =
+            self.table.0.lock().unwrap().get_mut(self.idx)
+        {
+            f(entry)
+        }
+    }
+
+    pub fn running(&self) {
+        self.update(|e| {
+            e.state = State::Running;
+            e.since = SystemTime::now()
+        })
+    }
+
+    // Records that the instance stopped. `error` describes why.
+
+    pub fn failed(&self, error: String) {
+        self.update(|e| {
+            events::publish(
+                events::Kind::DriverFailed,
+                Self::source(e),
+                error.as_str(),
+            );
+            e.state = State::Restarting;
+            e.since = SystemTime::now();
+            e.last_error = Some(error)
+        })
+    }
+
+    // Records how long the manager waits before restarting the
+    // instance.
+
+    pub fn delayed(&self, delay: Duration) {
+        self.update(|e| {
+            e.state = State::Restarting;
+            e.restart_at = Some(SystemTime::now() + delay)
+        })
+    }
+
+    pub fn restarting(&self) {
+        self.update(|e| {
+            e.state = State::Starting;
+            e.since = SystemTime::now();
+            e.restart_at = None;
+            e.restarts += 1;
+            events::publish(
+                events::Kind::DriverRestarted,
+                Self::source(e),
+                format!("restart #{}", e.restarts),
+            )
+        })
+    }
+
+    // Updates the watchdog devices, if there are any, with the
+    // current state of the instance.
+
+    pub async fn publish(&mut self) {
+        if let Some(wd) = &mut self.watchdog {
+            let entry = self.table.0.lock().unwrap()[self.idx].clone();
+
+            if let Some(e) = entry {
+                wd.alive.report_update(e.state == State::Running).await;
+                wd.restarts
+                    .report_update(
+                        i32::try_from(e.restarts).unwrap_or(i32::MAX),
+                    )
+                    .await
+            }
+        }
+    }
+
+    // Names the instance in events.
+
+    fn source(e: &Instance) -> String {
+        format!("{} ({})", e.driver, e.prefix)
+    }
+}
+
+// An instance that's no longer managed is removed from the table.
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if let Some(entry) = self.table.0.lock().unwrap().get_mut(self.idx) {
+            *entry = None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drmem_api::device;
+
+    fn recorder<T: Into<device::Value> + Clone>(
+        log: &Arc<Mutex<Vec<device::Value>>>,
+    ) -> driver::ReadOnlyDevice<T> {
+        let log = log.clone();
+
+        driver::ReadOnlyDevice::new(Box::new(move |v| {
+            log.lock().unwrap().push(v);
+            Box::pin(async {})
+        }))
+    }
+
+    #[test]
+    fn test_status() {
+        let table = Table::default();
+        let mut cfg = driver::DriverConfig::new();
+
+        cfg.insert("addr".into(), "10.0.0.1".into());
+
+        let rpt = table.add("tplink".into(), "room:lamp".into(), &cfg);
+        let ntp = table.add("ntp".into(), "net:ntp".into(), &cfg);
+
+        assert_eq!(table.get_all()[0].state, State::Starting);
+        assert_eq!(table.get_all()[0].config, ["addr"]);
+
+        rpt.running();
+        assert_eq!(table.get_all()[0].state, State::Running);
+
+        rpt.failed("panicked".into());
+        rpt.delayed(Duration::from_secs(5));
+
+        let entry = &table.get_all()[0];
+
+        assert_eq!(entry.state, State::Restarting);
+        assert!(entry.restart_at.unwrap() > entry.since);
+
+        rpt.restarting();
+
+        let entry = &table.get_all()[0];
+
+        assert_eq!(entry.state, State::Starting);
+        assert_eq!(entry.restart_at, None);
+        assert_eq!(entry.restarts, 1);
+        assert_eq!(entry.last_error.as_deref(), Some("panicked"));
+
+        // Other entries aren't affected.
+
+        assert_eq!(table.get_all()[1].restarts, 0);
+
+        // An instance that's no longer managed is removed.
+
+        drop(rpt);
+        assert_eq!(table.get_all().len(), 1);
+        assert_eq!(table.get_all()[0].prefix, "net:ntp");
+
+        ntp.running();
+        assert_eq!(table.get_all()[0].state, State::Running);
+    }
+
+    #[tokio::test]
+    async fn test_watchdog() {
+        use device::Value::{Bool, Int};
+
+        let table = Table::default();
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut rpt = table
+            .add("ntp".into(), "net:ntp".into(), &driver::DriverConfig::new())
+            .with_watchdog(Watchdog {
+                alive: recorder(&log),
+                restarts: recorder(&log),
+            });
+
+        rpt.publish().await;
+        rpt.running();
+        rpt.publish().await;
+        rpt.failed("timeout".into());
+        rpt.publish().await;
+        rpt.restarting();
+        rpt.running();
+        rpt.publish().await;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                Bool(false),
+                Int(0),
+                Bool(true),
+                Int(0),
+                Bool(false),
+                Int(0),
+                Bool(true),
+                Int(1)
+            ]
+        );
+
+        // Without a watchdog, publishing does nothing.
+
+        let mut rpt = table.add(
+            "ntp".into(),
+            "net:ntp2".into(),
+            &driver::DriverConfig::new(),
+        );
+
+        rpt.publish().await;
+        assert_eq!(log.lock().unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,111 @@
+// Reports events about `drmemd` itself, like drivers failing or
+// devices being registered, so external monitoring can watch the
+// health of the daemon without reading its logs. Any part of `drmemd`
+// can publish an event. Events published while nobody is listening
+// are dropped.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock,
+    },
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+
+// The number of events kept for a slow subscriber before it starts
+// missing them. It's set by the `events` entry of the `[channels]`
+// section.
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(100);
+
+static BUS: LazyLock<broadcast::Sender<Event>> =
+    LazyLock::new(|| broadcast::channel(CAPACITY.load(Ordering::Relaxed)).0);
+
+// Sets the number of events kept for a slow subscriber. It has no
+// effect once an event has been published or a subscriber added.
+
+pub fn set_capacity(size: usize) {
+    CAPACITY.store(size, Ordering::Relaxed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    // A driver instance stopped (usually a panic) or couldn't be
+    // created.
+    DriverFailed,
+    // A driver instance is being restarted.
+    DriverRestarted,
+    // A logic block stopped because of an error.
+    LogicStopped,
+    // A driver registered a device.
+    DeviceRegistered,
+    // The system clock was stepped by more than a minute.
+    ClockStepped,
+    // A driver didn't reply to a setting in time.
+    DriverUnresponsive,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+pub struct Event {
+    pub stamp: SystemTime,
+    pub kind: Kind,
+    // What the event is about: a driver, logic block, or device.
+    pub source: String,
+    pub message: String,
+}
+
+pub fn publish(
+    kind: Kind,
+    source: impl Into<String>,
+    message: impl Into<String>,
+) {
+    let _ = BUS.send(Event {
+        stamp: SystemTime::now(),
+        kind,
+        source: source.into(),
+        message: message.into(),
+    });
+}
+
+#[cfg(any(
+    feature = "graphql",
+    feature = "mqtt",
+    feature = "webhooks",
+    feature = "federation",
+    test
+))]
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events() {
+        // Events published before subscribing aren't received.
+
+        publish(Kind::DeviceRegistered, "test:early", "");
+
+        let mut rx = subscribe();
+
+        publish(Kind::DriverFailed, "test-driver", "panicked");
+
+        // Other tests may publish events, so skip them.
+
+        loop {
+            let ev = rx.recv().await.unwrap();
+
+            assert_ne!(ev.source, "test:early");
+
+            if ev.source == "test-driver" {
+                assert_eq!(ev.kind, Kind::DriverFailed);
+                assert_eq!(ev.message, "panicked");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use drmem_api::{Error, Result};
+use serde_derive::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+
+fn def_name() -> String {
+    String::from("drmem")
+}
+
+fn def_import() -> Vec<String> {
+    vec![String::from("*")]
+}
+
+// Another node whose devices are used by this one.
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Peer {
+    pub name: String,
+    // The "host:port" address of the peer's federation listener.
+    pub addr: String,
+    // The patterns of the peer's devices that are used.
+    #[serde(default = "def_import")]
+    pub import: Vec<String>,
+    // Connect with TLS. The peer's certificate has to be signed by a
+    // public authority or, if it's given, the one in `ca`.
+    #[serde(default)]
+    pub tls: bool,
+    pub ca: Option<PathBuf>,
+}
+
+impl Peer {
+    pub fn uses_tls(&self) -> bool {
+        self.tls || self.ca.is_some()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    // The name peers use for this node in their logs.
+    #[serde(default = "def_name")]
+    pub name: String,
+    // Where peers connect to use this node's devices. Without it,
+    // this node doesn't offer any.
+    pub addr: Option<SocketAddr>,
+    // Every node of a federation shares it. A peer that doesn't send
+    // it is turned away.
+    pub token: Option<String>,
+    // The certificate chain, and its key, used to offer TLS
+    // connections to peers.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // The patterns of the devices offered to peers.
+    #[serde(default)]
+    pub export: Vec<String>,
+    #[serde(default)]
+    pub peer: Vec<Peer>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        match self.token.as_deref() {
+            Some("") => {
+                return Err(Error::ConfigError(
+                    "federation token can't be empty".into(),
+                ))
+            }
+            None if self.addr.is_some() || !self.peer.is_empty() => {
+                return Err(Error::ConfigError(
+                    "federation needs a 'token'".into(),
+                ))
+            }
+            _ => (),
+        }
+
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(Error::ConfigError(
+                "federation 'cert' and 'key' have to be given together".into(),
+            ));
+        }
+
+        for (idx, peer) in self.peer.iter().enumerate() {
+            if self.peer[..idx].iter().any(|v| v.name == peer.name) {
+                return Err(Error::ConfigError(format!(
+                    "federation peer '{}' is defined more than once",
+                    &peer.name
+                )));
+            }
+
+            if !peer.addr.contains(':') {
+                return Err(Error::ConfigError(format!(
+                    "address of federation peer '{}' needs a port",
+                    &peer.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let cfg: Config = toml::from_str(
+            r#"
+addr = "0.0.0.0:3400"
+token = "shared"
+export = ["garage:*"]
+
+[[peer]]
+name = "barn"
+addr = "barn.local:3400"
+
+[[peer]]
+name = "shop"
+addr = "10.0.0.7:3400"
+import = ["shop:temp"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.name, "drmem");
+        assert_eq!(cfg.addr, Some("0.0.0.0:3400".parse().unwrap()));
+        assert_eq!(cfg.peer[0].import, ["*"]);
+        assert_eq!(cfg.peer[1].import, ["shop:temp"]);
+        assert!(cfg.validate().is_ok());
+
+        assert!(!cfg.peer[0].uses_tls());
+
+        let mut bad = cfg;
+
+        bad.peer[1].name = "barn".into();
+        assert!(bad.validate().is_err());
+
+        bad.peer.truncate(1);
+        bad.peer[0].addr = "barn.local".into();
+        assert!(bad.validate().is_err());
+
+        bad.peer.clear();
+        bad.token = Some("".into());
+        assert!(bad.validate().is_err());
+
+        // A node that offers devices, or uses a peer's, needs a
+        // token.
+
+        bad.token = None;
+        assert!(bad.validate().is_err());
+
+        bad.addr = None;
+        assert!(bad.validate().is_ok());
+
+        bad.peer.push(Peer {
+            name: "barn".into(),
+            addr: "barn.local:3400".into(),
+            import: vec![],
+            tls: false,
+            ca: Some("/etc/drmem/ca.pem".into()),
+        });
+        assert!(bad.peer[0].uses_tls());
+        assert!(bad.validate().is_err());
+
+        // The certificate needs its key.
+
+        bad.token = Some("shared".into());
+        bad.cert = Some("/etc/drmem/node.pem".into());
+        assert!(bad.validate().is_err());
+
+        bad.key = Some("/etc/drmem/node.key".into());
+        assert!(bad.validate().is_ok());
+    }
+}
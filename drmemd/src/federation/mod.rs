@@ -0,0 +1,827 @@
+// Lets `drmemd` nodes use each other's devices. A node offers the
+// devices matching `export` to the peers that connect to `addr`.
+// Each node listed as a `peer` is connected to and its devices that
+// match `import` are registered here, under the same names, by the
+// "federation" driver. Logic blocks and clients use them like local
+// devices: readings are mirrored from the peer and settings are
+// forwarded to it.
+//
+// Devices registered by federation aren't offered to peers, so
+// devices aren't passed around in circles. Only the devices that the
+// `[clients]` section lets clients use are offered. A peer's device
+// that has the name of a local device isn't registered.
+//
+// When the connection to a peer is lost, its devices stop updating
+// and settings to them fail until it's made again.
+//
+// Connections use TLS when the node has a certificate and the peer
+// entry asks for it. Either way, a peer has to send the token shared
+// by the federation.
+
+use crate::{events, glob};
+use drmem_api::{client, device, driver, Error, Result};
+use futures::Future;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt, StreamMap};
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument;
+
+pub mod config;
+mod protocol;
+mod tls;
+
+use protocol::Msg;
+
+// The devices of a peer are registered by a driver named
+// "federation:" followed by the peer's name. Each peer gets its own
+// name so a peer can't take over a device registered for another
+// one.
+
+pub const DRIVER: &str = "federation";
+
+fn owner(peer: &str) -> driver::Name {
+    format!("{}:{}", DRIVER, peer).into()
+}
+
+// Returns whether a device was registered for a peer.
+
+fn is_mirror(driver: &str) -> bool {
+    driver
+        .strip_prefix(DRIVER)
+        .is_some_and(|v| v.starts_with(':'))
+}
+
+// How long to wait before connecting to a peer again.
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The number of replies to settings that can wait to be sent.
+
+const CHAN_SIZE: usize = 100;
+
+fn patterns(v: &[String]) -> Vec<glob::Pattern> {
+    v.iter().map(|v| glob::Pattern::create(v)).collect()
+}
+
+// Offers this node's devices to the peers that connect to it.
+
+#[derive(Clone)]
+struct Server {
+    token: String,
+    export: Arc<Vec<glob::Pattern>>,
+    cchan: client::RequestChan,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Server {
+    // Returns `true` if a peer sent the federation's token. The
+    // comparison takes the same time wherever the tokens differ, so
+    // it doesn't give away how much of a guess was right.
+
+    fn accepts(&self, token: Option<&str>) -> bool {
+        token.is_some_and(|v| v.as_bytes().ct_eq(self.token.as_bytes()).into())
+    }
+
+    // Returns how a device is described to peers, if it's offered.
+
+    fn offer(&self, dev: &client::DevInfoReply) -> Option<protocol::Device> {
+        let name = dev.name.to_string();
+
+        (!is_mirror(&dev.driver)
+            && self.export.iter().any(|v| v.matches(&name)))
+        .then(|| protocol::Device {
+            name,
+            units: dev.units.clone(),
+            settable: dev.settable,
+        })
+    }
+
+    // Returns the offered devices matching `pattern`.
+
+    async fn catalog(
+        &self,
+        pattern: Option<String>,
+    ) -> Result<Vec<protocol::Device>> {
+        Ok(self
+            .cchan
+            .get_device_info(pattern)
+            .await?
+            .iter()
+            .filter_map(|v| self.offer(v))
+            .collect())
+    }
+
+    // Handles a connection from a peer. It returns when the peer
+    // disconnects or breaks the protocol.
+
+    async fn session<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut rd = protocol::Reader::new(rd);
+
+        // The peer has to introduce itself first.
+
+        let node = match rd.recv().await? {
+            Some(Msg::Hello { node, token })
+                if self.accepts(token.as_deref()) =>
+            {
+                node
+            }
+            Some(Msg::Hello { node, .. }) => {
+                let reason = String::from("bad token");
+
+                protocol::send(&mut wr, &Msg::Refused { reason }).await?;
+                warn!("refused peer {} -- bad token", node);
+                return Err(Error::AuthenticationError);
+            }
+            _ => {
+                return Err(Error::ProtocolError(
+                    "peer didn't introduce itself".into(),
+                ))
+            }
+        };
+
+        info!("peer {} connected", &node);
+
+        // Listen for new devices before getting the current ones so
+        // none are missed.
+
+        let mut new_devices = events::subscribe();
+        let devices = self.catalog(None).await?;
+        let mut offered: HashSet<String> =
+            devices.iter().map(|v| v.name.clone()).collect();
+
+        for chunk in devices.chunks(protocol::CATALOG_CHUNK) {
+            let devices = chunk.to_vec();
+
+            protocol::send(&mut wr, &Msg::Catalog { devices }).await?
+        }
+
+        let mut streams = StreamMap::new();
+        let (tx_rpy, mut rx_rpy) = mpsc::channel(CHAN_SIZE);
+
+        loop {
+            tokio::select! {
+                msg = rd.recv() => match msg? {
+                    None => {
+                        info!("peer {} disconnected", &node);
+                        return Ok(());
+                    }
+                    Some(Msg::Monitor { name }) => {
+                        if !offered.contains(&name)
+                            || streams.contains_key(&name)
+                        {
+                            continue;
+                        }
+
+                        match self
+                            .cchan
+                            .monitor_device(name.parse()?, None, None)
+                            .await
+                        {
+                            Ok(s) => {
+                                streams.insert(name, s);
+                            }
+                            Err(e) => {
+                                warn!("couldn't monitor '{}' -- {}", &name, e)
+                            }
+                        }
+                    }
+                    Some(Msg::Set { id, name, value }) => {
+                        let cchan = self.cchan.clone();
+                        let tx_rpy = tx_rpy.clone();
+                        let allowed = offered.contains(&name);
+
+                        tokio::spawn(async move {
+                            let result = match name.parse() {
+                                Ok(name) if allowed => {
+                                    cchan.set_value(name, value.into()).await
+                                }
+                                Ok(_) => Err(Error::NotFound),
+                                Err(e) => Err(e),
+                            };
+                            let result = result
+                                .map(Into::into)
+                                .map_err(|e| e.to_string());
+                            let msg = Msg::Reply { id, result };
+
+                            let _ = tx_rpy.send(msg).await;
+                        });
+                    }
+                    Some(_) => {
+                        return Err(Error::ProtocolError(
+                            "unexpected message from peer".into(),
+                        ))
+                    }
+                },
+
+                Some((name, reading)) = streams.next() => {
+                    let value = reading.value.into();
+
+                    protocol::send(&mut wr, &Msg::Reading { name, value })
+                        .await?
+                }
+
+                Some(msg) = rx_rpy.recv() => {
+                    protocol::send(&mut wr, &msg).await?
+                }
+
+                ev = new_devices.recv() => match ev {
+                    Ok(ev) if ev.kind == events::Kind::DeviceRegistered => {
+                        let devices = self.catalog(Some(ev.source)).await?;
+
+                        if !devices.is_empty() {
+                            for dev in &devices {
+                                offered.insert(dev.name.clone());
+                            }
+                            protocol::send(&mut wr, &Msg::Catalog { devices })
+                                .await?
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => {
+                        return Err(Error::OperationError(
+                            "event bus closed".into(),
+                        ))
+                    }
+                },
+            }
+        }
+    }
+
+    async fn run(self, addr: std::net::SocketAddr) {
+        let listener = match TcpListener::bind(addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("couldn't listen on {} -- {}", addr, e);
+                return;
+            }
+        };
+
+        info!("offering devices to peers on {}", addr);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let server = self.clone();
+
+                    tokio::spawn(
+                        async move {
+                            let result = match &server.tls {
+                                Some(tls) => match tls.accept(stream).await {
+                                    Ok(stream) => server.session(stream).await,
+                                    Err(e) => {
+                                        Err(Error::ProtocolError(format!(
+                                            "TLS handshake failed -- {}",
+                                            e
+                                        )))
+                                    }
+                                },
+                                None => server.session(stream).await,
+                            };
+
+                            if let Err(e) = result {
+                                warn!("peer connection closed -- {}", e)
+                            }
+                        }
+                        .instrument(info_span!("peer", addr = %peer)),
+                    );
+                }
+                Err(e) => warn!("couldn't accept a peer -- {}", e),
+            }
+        }
+    }
+}
+
+// Asks a peer for the readings of devices.
+
+async fn monitor<W>(wr: &mut W, names: Vec<String>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    for name in names {
+        protocol::send(wr, &Msg::Monitor { name }).await?
+    }
+    Ok(())
+}
+
+// Mirrors the devices of a peer.
+
+struct Link {
+    peer: config::Peer,
+    // The driver name the peer's devices are registered under.
+    owner: driver::Name,
+    node: String,
+    token: Option<String>,
+    import: Vec<glob::Pattern>,
+    core: mpsc::Sender<driver::Request>,
+    // The devices registered for the peer, by name.
+    mirrors: HashMap<String, driver::ReportReading>,
+    // The devices that couldn't be registered, so they're only
+    // reported once.
+    refused: HashSet<String>,
+    settings: StreamMap<String, ReceiverStream<driver::SettingRequest>>,
+    tls: Option<TlsConnector>,
+}
+
+impl Link {
+    fn new(
+        cfg: &config::Config,
+        peer: &config::Peer,
+        core: mpsc::Sender<driver::Request>,
+    ) -> Self {
+        Link {
+            peer: peer.clone(),
+            owner: owner(&peer.name),
+            node: cfg.name.clone(),
+            token: cfg.token.clone(),
+            import: patterns(&peer.import),
+            core,
+            mirrors: HashMap::new(),
+            refused: HashSet::new(),
+            settings: StreamMap::new(),
+            tls: None,
+        }
+    }
+
+    // Registers a device of the peer with the core.
+
+    async fn register(&mut self, dev: protocol::Device) -> Result<()> {
+        let dev_name: device::Name = dev.name.parse()?;
+        let driver_name = self.owner.clone();
+
+        if dev.settable {
+            let (rpy_chan, rx) = oneshot::channel();
+
+            self.core
+                .send(driver::Request::AddReadWriteDevice {
+                    driver_name,
+                    dev_name,
+                    dev_units: dev.units,
+                    max_history: None,
+                    max_age: None,
+                    rpy_chan,
+                })
+                .await?;
+
+            let (report, rx_set, _) = rx.await??;
+
+            self.settings
+                .insert(dev.name.clone(), ReceiverStream::new(rx_set));
+            self.mirrors.insert(dev.name, report);
+        } else {
+            let (rpy_chan, rx) = oneshot::channel();
+
+            self.core
+                .send(driver::Request::AddReadonlyDevice {
+                    driver_name,
+                    dev_name,
+                    dev_units: dev.units,
+                    max_history: None,
+                    max_age: None,
+                    rpy_chan,
+                })
+                .await?;
+            self.mirrors.insert(dev.name, rx.await??);
+        }
+        Ok(())
+    }
+
+    // Registers the devices of a catalog that are used and haven't
+    // been registered yet. Returns the names of the new devices.
+
+    async fn add(&mut self, devices: Vec<protocol::Device>) -> Vec<String> {
+        let mut added = vec![];
+
+        for dev in devices {
+            let name = dev.name.clone();
+
+            if self.mirrors.contains_key(&name)
+                || self.refused.contains(&name)
+                || !self.import.iter().any(|v| v.matches(&name))
+            {
+                continue;
+            }
+
+            match self.register(dev).await {
+                Ok(()) => added.push(name),
+                Err(e) => {
+                    warn!("couldn't add '{}' -- {}", &name, e);
+                    self.refused.insert(name);
+                }
+            }
+        }
+        added
+    }
+
+    // Handles a connection to the peer. It only returns if the
+    // connection fails.
+
+    async fn session<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let (rd, mut wr) = tokio::io::split(stream);
+        let mut rd = protocol::Reader::new(rd);
+        let mut pending: HashMap<u64, oneshot::Sender<Result<device::Value>>> =
+            HashMap::new();
+        let mut next_id: u64 = 0;
+
+        protocol::send(
+            &mut wr,
+            &Msg::Hello {
+                node: self.node.clone(),
+                token: self.token.clone(),
+            },
+        )
+        .await?;
+
+        // Ask for the readings of the devices registered during an
+        // earlier connection.
+
+        monitor(&mut wr, self.mirrors.keys().cloned().collect()).await?;
+
+        let result = loop {
+            tokio::select! {
+                msg = rd.recv() => match msg {
+                    Ok(Some(Msg::Catalog { devices })) => {
+                        let names = self.add(devices).await;
+
+                        if let Err(e) = monitor(&mut wr, names).await {
+                            break Err(e);
+                        }
+                    }
+                    Ok(Some(Msg::Reading { name, value })) => {
+                        if let Some(report) = self.mirrors.get(&name) {
+                            report(value.into()).await
+                        }
+                    }
+                    Ok(Some(Msg::Reply { id, result })) => {
+                        if let Some(rpy_chan) = pending.remove(&id) {
+                            let result = result
+                                .map(Into::into)
+                                .map_err(Error::OperationError);
+
+                            let _ = rpy_chan.send(result);
+                        }
+                    }
+                    Ok(Some(Msg::Refused { reason })) => {
+                        break Err(Error::OperationError(format!(
+                            "peer refused the connection -- {}",
+                            reason
+                        )))
+                    }
+                    Ok(Some(_)) => {
+                        break Err(Error::ProtocolError(
+                            "unexpected message from peer".into(),
+                        ))
+                    }
+                    Ok(None) => {
+                        break Err(Error::MissingPeer(
+                            "peer closed the connection".into(),
+                        ))
+                    }
+                    Err(e) => break Err(e),
+                },
+
+                Some((name, (value, rpy_chan))) = self.settings.next() => {
+                    let id = next_id;
+                    let value = value.into();
+
+                    next_id += 1;
+                    pending.insert(id, rpy_chan);
+
+                    if let Err(e) =
+                        protocol::send(&mut wr, &Msg::Set { id, name, value })
+                            .await
+                    {
+                        break Err(e);
+                    }
+                }
+            }
+        };
+
+        // Settings that weren't answered won't be.
+
+        for (_, rpy_chan) in pending {
+            let _ = rpy_chan.send(Err(Error::MissingPeer(
+                "lost connection to peer".into(),
+            )));
+        }
+        result
+    }
+
+    // Refuses the settings made while the peer can't be reached.
+
+    async fn wait(&mut self, delay: Duration) {
+        let pause = tokio::time::sleep(delay);
+
+        tokio::pin!(pause);
+
+        loop {
+            tokio::select! {
+                _ = &mut pause => break,
+
+                Some((_, (_, rpy_chan))) = self.settings.next() => {
+                    let _ = rpy_chan.send(Err(Error::MissingPeer(
+                        "peer isn't connected".into(),
+                    )));
+                }
+            }
+        }
+    }
+
+    // Connects to the peer, using TLS if it's configured.
+
+    async fn connect(&mut self) -> Result<()> {
+        let stream =
+            TcpStream::connect(&self.peer.addr).await.map_err(|e| {
+                Error::MissingPeer(format!("couldn't connect -- {}", e))
+            })?;
+
+        match self.tls.clone() {
+            Some(tls) => {
+                let name = tls::server_name(&self.peer.addr)?;
+                let stream = tls.connect(name, stream).await.map_err(|e| {
+                    Error::ProtocolError(format!(
+                        "TLS handshake failed -- {}",
+                        e
+                    ))
+                })?;
+
+                info!("connected with TLS");
+                self.session(stream).await
+            }
+            None => {
+                info!("connected");
+                self.session(stream).await
+            }
+        }
+    }
+
+    async fn run(mut self) {
+        if self.peer.uses_tls() {
+            match tls::connector(self.peer.ca.as_deref()) {
+                Ok(v) => self.tls = Some(v),
+                Err(e) => {
+                    error!("can't use peer -- {}", e);
+                    return;
+                }
+            }
+        }
+
+        loop {
+            if let Err(e) = self.connect().await {
+                warn!("connection lost -- {}", e)
+            }
+            self.wait(RETRY_DELAY).await
+        }
+    }
+}
+
+// Returns a future that connects to the peers and, if an address is
+// configured, offers this node's devices.
+
+pub fn start(
+    cfg: &config::Config,
+    cchan: client::RequestChan,
+    core: mpsc::Sender<driver::Request>,
+) -> impl Future<Output = ()> {
+    for peer in &cfg.peer {
+        let span = info_span!("federation", peer = peer.name.as_str());
+
+        tokio::spawn(Link::new(cfg, peer, core.clone()).run().instrument(span));
+    }
+
+    // The configuration was checked, so there's a token when
+    // there's an address.
+
+    let tls = match (&cfg.cert, &cfg.key) {
+        (Some(cert), Some(key)) => tls::acceptor(cert, key).map(Some),
+        _ => Ok(None),
+    };
+    let server = Server {
+        token: cfg.token.clone().unwrap_or_default(),
+        export: Arc::new(patterns(&cfg.export)),
+        cchan,
+        tls: None,
+    };
+    let addr = cfg.addr;
+
+    async move {
+        match (addr, tls) {
+            (Some(addr), Ok(tls)) => Server { tls, ..server }.run(addr).await,
+            (Some(_), Err(e)) => {
+                error!("can't offer devices to peers -- {}", e)
+            }
+            (None, _) => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn info(name: &str, driver: &str, settable: bool) -> client::DevInfoReply {
+        client::DevInfoReply {
+            name: name.parse().unwrap(),
+            units: None,
+            settable,
+            total_points: 0,
+            first_point: None,
+            last_point: None,
+            driver: driver.into(),
+            meta: client::DeviceMeta::default(),
+        }
+    }
+
+    // Fakes the client API of the node offering devices. Monitored
+    // devices report 1.5 and settings are accepted.
+
+    fn fake_clients() -> client::RequestChan {
+        let (tx, mut rx) = mpsc::channel(10);
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                match req {
+                    client::Request::QueryDeviceInfo { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Ok(vec![
+                            info("barn:temp", "weather", false),
+                            info("barn:fan", "memory", true),
+                            info("barn:copy", "federation:shop", false),
+                            info("shop:temp", "weather", false),
+                        ]));
+                    }
+                    client::Request::MonitorDevice { rpy_chan, .. } => {
+                        let reading = device::Reading {
+                            ts: SystemTime::now(),
+                            value: device::Value::Flt(1.5),
+                        };
+                        let s = tokio_stream::iter([reading])
+                            .chain(tokio_stream::pending());
+
+                        let _ = rpy_chan.send(Ok(Box::pin(s)));
+                    }
+                    client::Request::SetDevice {
+                        value, rpy_chan, ..
+                    } => {
+                        let _ = rpy_chan.send(Ok(value));
+                    }
+                    _ => (),
+                }
+            }
+        });
+        client::RequestChan::new(tx)
+    }
+
+    #[test]
+    fn test_owner() {
+        assert_eq!(owner("barn").as_ref(), "federation:barn");
+        assert!(is_mirror("federation:barn"));
+        assert!(!is_mirror("federation"));
+        assert!(!is_mirror("federations:barn"));
+        assert!(!is_mirror("memory"));
+    }
+
+    #[tokio::test]
+    async fn test_federation() {
+        let cfg: config::Config = toml::from_str(
+            r#"
+token = "shared"
+export = ["barn:*"]
+
+[[peer]]
+name = "barn"
+addr = "barn.local:3400"
+"#,
+        )
+        .unwrap();
+        let server = Server {
+            token: cfg.token.clone().unwrap(),
+            export: Arc::new(patterns(&cfg.export)),
+            cchan: fake_clients(),
+            tls: None,
+        };
+
+        // Fake the core of the node using the devices. It keeps the
+        // readings and the setting channels of the devices.
+
+        let (tx_core, mut rx_core) = mpsc::channel(10);
+        let mut link = Link::new(&cfg, &cfg.peer[0], tx_core);
+        let (here, there) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move { server.session(there).await });
+        tokio::spawn(async move { link.session(here).await });
+
+        let (tx_read, mut rx_read) = mpsc::unbounded_channel();
+        let mut registered = vec![];
+        let mut tx_set = None;
+
+        while registered.len() < 2 {
+            let tx_read = tx_read.clone();
+
+            match rx_core.recv().await.unwrap() {
+                driver::Request::AddReadonlyDevice {
+                    driver_name,
+                    dev_name,
+                    rpy_chan,
+                    ..
+                } => {
+                    assert_eq!(driver_name.as_ref(), "federation:barn");
+
+                    let name = dev_name.to_string();
+
+                    registered.push(name.clone());
+                    let _ = rpy_chan.send(Ok(Box::new(move |v| {
+                        let _ = tx_read.send((name.clone(), v));
+                        Box::pin(async {})
+                    })));
+                }
+                driver::Request::AddReadWriteDevice {
+                    dev_name,
+                    rpy_chan,
+                    ..
+                } => {
+                    let (tx, rx) = mpsc::channel(10);
+
+                    registered.push(dev_name.to_string());
+                    tx_set = Some(tx);
+                    let _ = rpy_chan.send(Ok((
+                        Box::new(|_| Box::pin(async {})),
+                        rx,
+                        None,
+                    )));
+                }
+                driver::Request::Discover { .. } => panic!("unexpected"),
+            }
+        }
+
+        // Devices that aren't exported, or that came from another
+        // peer, aren't offered.
+
+        assert_eq!(registered, ["barn:temp", "barn:fan"]);
+
+        // Readings are mirrored.
+
+        assert_eq!(
+            rx_read.recv().await,
+            Some(("barn:temp".into(), device::Value::Flt(1.5)))
+        );
+
+        // Settings are forwarded and the peer's reply is returned.
+
+        let (tx, rx) = oneshot::channel();
+
+        tx_set.unwrap().send((true.into(), tx)).await.unwrap();
+        assert_eq!(rx.await.unwrap(), Ok(device::Value::Bool(true)));
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let server = Server {
+            token: "shared".into(),
+            export: Arc::new(vec![]),
+            cchan: fake_clients(),
+            tls: None,
+        };
+        let cfg = config::Config {
+            name: "house".into(),
+            addr: None,
+            token: Some("guess".into()),
+            cert: None,
+            key: None,
+            export: vec![],
+            peer: vec![config::Peer {
+                name: "barn".into(),
+                addr: "barn.local:3400".into(),
+                import: vec![],
+                tls: false,
+                ca: None,
+            }],
+        };
+        let (tx_core, _rx_core) = mpsc::channel(10);
+        let mut link = Link::new(&cfg, &cfg.peer[0], tx_core);
+        let (here, there) = tokio::io::duplex(4096);
+
+        tokio::spawn(async move { server.session(there).await });
+        assert!(matches!(
+            link.session(here).await,
+            Err(Error::OperationError(_))
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+// Defines the messages nodes exchange. Each message is a line of
+// JSON. The connecting node sends `Hello` and the other node replies
+// with its `Catalog`, which it adds to when devices are registered
+// later. After that, the connecting node asks for readings with
+// `Monitor` and makes settings with `Set`.
+
+use drmem_api::{device, Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// The longest message that's accepted. Catalogs are sent in pieces
+// so they stay under it.
+
+pub const MAX_LINE: usize = 64 * 1024;
+
+// The most devices sent in one `Catalog` message.
+
+pub const CATALOG_CHUNK: usize = 100;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Value {
+    Bool(bool),
+    Int(i32),
+    Flt(f64),
+    Str(String),
+    Color([u8; 4]),
+}
+
+impl From<device::Value> for Value {
+    fn from(v: device::Value) -> Self {
+        match v {
+            device::Value::Bool(v) => Value::Bool(v),
+            device::Value::Int(v) => Value::Int(v),
+            device::Value::Flt(v) => Value::Flt(v),
+            device::Value::Str(v) => Value::Str(v.to_string()),
+            device::Value::Color(v) => {
+                Value::Color([v.red, v.green, v.blue, v.alpha])
+            }
+        }
+    }
+}
+
+impl From<Value> for device::Value {
+    fn from(v: Value) -> Self {
+        match v {
+            Value::Bool(v) => device::Value::Bool(v),
+            Value::Int(v) => device::Value::Int(v),
+            Value::Flt(v) => device::Value::Flt(v),
+            Value::Str(v) => device::Value::Str(v.into()),
+            Value::Color([r, g, b, a]) => {
+                device::Value::Color(palette::LinSrgba::new(r, g, b, a))
+            }
+        }
+    }
+}
+
+// A device offered by a node.
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Device {
+    pub name: String,
+    pub units: Option<String>,
+    pub settable: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Msg {
+    Hello {
+        node: String,
+        token: Option<String>,
+    },
+    Refused {
+        reason: String,
+    },
+    Catalog {
+        devices: Vec<Device>,
+    },
+    Monitor {
+        name: String,
+    },
+    Reading {
+        name: String,
+        value: Value,
+    },
+    Set {
+        id: u64,
+        name: String,
+        value: Value,
+    },
+    Reply {
+        id: u64,
+        result: std::result::Result<Value, String>,
+    },
+}
+
+fn lost(e: std::io::Error) -> Error {
+    Error::MissingPeer(format!("federation connection failed -- {}", e))
+}
+
+// Reads messages from one side of a connection. The bytes read so
+// far are kept in the `Reader`, rather than in the future returned by
+// `recv`, so `recv` can be used in a `select!` without losing part of
+// a message when another branch wins.
+
+pub struct Reader<R> {
+    rd: R,
+    buf: Vec<u8>,
+}
+
+impl<R> Reader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(rd: R) -> Self {
+        Reader {
+            rd,
+            buf: Vec::new(),
+        }
+    }
+
+    // Reads the next message. `None` is returned when the other node
+    // closes the connection.
+
+    pub async fn recv(&mut self) -> Result<Option<Msg>> {
+        let mut scanned = 0;
+
+        loop {
+            if let Some(pos) =
+                self.buf[scanned..].iter().position(|b| *b == b'\n')
+            {
+                let line: Vec<u8> = self.buf.drain(..=scanned + pos).collect();
+
+                return serde_json::from_slice(&line).map(Some).map_err(|e| {
+                    Error::ProtocolError(format!(
+                        "bad federation message -- {}",
+                        e
+                    ))
+                });
+            }
+
+            scanned = self.buf.len();
+
+            if scanned >= MAX_LINE {
+                return Err(Error::ProtocolError(
+                    "federation message too long".into(),
+                ));
+            }
+
+            self.buf.reserve(MAX_LINE - scanned);
+
+            let size = self.rd.read_buf(&mut self.buf).await.map_err(lost)?;
+
+            if size == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(Error::ProtocolError(
+                        "federation connection closed mid-message".into(),
+                    ))
+                };
+            }
+        }
+    }
+}
+
+pub async fn send<W>(wr: &mut W, msg: &Msg) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut line = serde_json::to_string(msg).map_err(|e| {
+        Error::OperationError(format!("couldn't encode message -- {}", e))
+    })?;
+
+    line.push('\n');
+    wr.write_all(line.as_bytes()).await.map_err(lost)?;
+    wr.flush().await.map_err(lost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        for v in [
+            device::Value::Bool(true),
+            device::Value::Int(-3),
+            device::Value::Flt(1.0),
+            device::Value::Str("on".into()),
+            device::Value::Color(palette::LinSrgba::new(1, 2, 3, 4)),
+        ] {
+            assert_eq!(device::Value::from(Value::from(v.clone())), v);
+        }
+
+        // Floats stay floats, even when they hold whole numbers.
+
+        assert_eq!(
+            serde_json::to_string(&Value::from(device::Value::Flt(1.0)))
+                .unwrap(),
+            "{\"flt\":1.0}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let (mut wr, rd) = tokio::io::duplex(1024);
+        let mut rd = Reader::new(rd);
+        let msgs = [
+            Msg::Hello {
+                node: "barn".into(),
+                token: None,
+            },
+            Msg::Set {
+                id: 7,
+                name: "barn:fan".into(),
+                value: Value::Bool(true),
+            },
+            Msg::Reply {
+                id: 7,
+                result: Err("device not found".into()),
+            },
+        ];
+
+        for msg in &msgs {
+            send(&mut wr, msg).await.unwrap();
+        }
+        drop(wr);
+
+        for msg in msgs {
+            assert_eq!(rd.recv().await.unwrap(), Some(msg));
+        }
+        assert_eq!(rd.recv().await.unwrap(), None);
+
+        // Junk, overly long lines and partial lines are refused.
+
+        let mut rd = Reader::new(&b"{\"monitor\":{}}\n"[..]);
+
+        assert!(rd.recv().await.is_err());
+
+        let long = vec![b' '; MAX_LINE + 1];
+        let mut rd = Reader::new(&long[..]);
+
+        assert!(rd.recv().await.is_err());
+
+        let mut rd = Reader::new(&b"{\"refused\""[..]);
+
+        assert!(rd.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let (mut wr, rd) = tokio::io::duplex(1024);
+        let mut rd = Reader::new(rd);
+        let msg = Msg::Reply {
+            id: 3,
+            result: Ok(Value::Int(42)),
+        };
+        let line = format!("{}\n", serde_json::to_string(&msg).unwrap());
+        let (head, tail) = line.as_bytes().split_at(line.len() / 2);
+
+        // Cancel a `recv` after it has read half of the message. The
+        // next `recv` has to pick up where it left off.
+
+        wr.write_all(head).await.unwrap();
+        tokio::select! {
+            biased;
+
+            _ = rd.recv() => panic!("recv returned a partial message"),
+            _ = tokio::task::yield_now() => ()
+        }
+
+        wr.write_all(tail).await.unwrap();
+        assert_eq!(rd.recv().await.unwrap(), Some(msg));
+    }
+}
@@ -0,0 +1,122 @@
+// Builds the TLS configurations of the federation connections. The
+// `ring` provider is named explicitly, rather than installed as the
+// process default, so it doesn't matter which providers the other
+// crates enable.
+
+use drmem_api::{Error, Result};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        self,
+        crypto::ring,
+        pki_types::{CertificateDer, ServerName},
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path).map(BufReader::new).map_err(|e| {
+        Error::ConfigError(format!("couldn't open {} -- {}", path.display(), e))
+    })
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::io::Result<_>>()
+        .map_err(|e| {
+            Error::ConfigError(format!(
+                "bad certificate in {} -- {}",
+                path.display(),
+                e
+            ))
+        })
+}
+
+fn tls_err(e: rustls::Error) -> Error {
+    Error::ConfigError(format!("TLS configuration -- {}", e))
+}
+
+// Returns the acceptor used for the connections of peers. `cert`
+// holds the certificate chain and `key` its private key.
+
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let key = rustls_pemfile::private_key(&mut open(key)?)
+        .ok()
+        .flatten()
+        .ok_or_else(|| {
+            Error::ConfigError(format!("no private key in {}", key.display()))
+        })?;
+    let cfg = rustls::ServerConfig::builder_with_provider(Arc::new(
+        ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(tls_err)?
+    .with_no_client_auth()
+    .with_single_cert(certs(cert)?, key)
+    .map_err(tls_err)?;
+
+    Ok(TlsAcceptor::from(Arc::new(cfg)))
+}
+
+// Returns the connector used to reach a peer. The peer's certificate
+// has to be signed by the authority in `ca` or, if it isn't given, by
+// one of the usual public authorities.
+
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    match ca {
+        Some(ca) => {
+            for cert in certs(ca)? {
+                roots.add(cert).map_err(tls_err)?
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let cfg = rustls::ClientConfig::builder_with_provider(Arc::new(
+        ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(tls_err)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(cfg)))
+}
+
+// Returns the name a peer's certificate has to hold: the host part
+// of its "host:port" address.
+
+pub fn server_name(addr: &str) -> Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |v| v.0);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    ServerName::try_from(host.to_string()).map_err(|_| {
+        Error::ConfigError(format!("'{}' isn't a valid host name", host))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(
+            server_name("barn.local:3400").unwrap(),
+            ServerName::try_from("barn.local").unwrap()
+        );
+        assert_eq!(
+            server_name("10.0.0.7:3400").unwrap(),
+            ServerName::try_from("10.0.0.7").unwrap()
+        );
+        assert_eq!(
+            server_name("[::1]:3400").unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+        assert!(server_name("bad host:3400").is_err());
+        assert!(connector(None).is_ok());
+        assert!(acceptor(Path::new("/nonexistent"), Path::new("/x")).is_err());
+    }
+}
@@ -121,6 +121,7 @@ impl Fsm<'_> {
     }
 }
 
+#[derive(Clone)]
 pub struct Pattern {
     data: String,
 }
@@ -0,0 +1,159 @@
+// Decides what a GraphQL client is allowed to do. A client that
+// passed authentication is given the operations of the access rules
+// listing its fingerprint. If the configuration doesn't have any
+// access rules, every client can do everything, which is how
+// `drmemd` behaved before rules were added.
+
+use super::{cmp_fprints, config};
+use crate::glob;
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct Grant {
+    devices: Vec<glob::Pattern>,
+    allow: Vec<config::Operation>,
+}
+
+#[derive(Clone)]
+pub struct Access {
+    grants: Option<Arc<[Grant]>>,
+    client: Option<Arc<str>>,
+}
+
+impl Access {
+    // Returns the access given to clients when the server doesn't
+    // use access rules.
+
+    pub fn unrestricted() -> Self {
+        Access {
+            grants: None,
+            client: None,
+        }
+    }
+
+    // Collects the rules that apply to `client`.
+
+    pub fn for_client(rules: &[config::Rule], client: &str) -> Self {
+        let client_id = Some(Arc::from(client));
+
+        if rules.is_empty() {
+            return Access {
+                grants: None,
+                client: client_id,
+            };
+        }
+
+        Access {
+            grants: Some(
+                rules
+                    .iter()
+                    .filter(|r| {
+                        r.clients.iter().any(|fp| cmp_fprints(fp, client))
+                    })
+                    .map(|r| Grant {
+                        devices: r
+                            .devices
+                            .iter()
+                            .map(|p| glob::Pattern::create(p))
+                            .collect(),
+                        allow: r.allow.clone(),
+                    })
+                    .collect(),
+            ),
+            client: client_id,
+        }
+    }
+
+    // Returns the fingerprint of the client, if the server
+    // authenticates clients.
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    // Returns `true` if the client can perform `op` on `device`.
+
+    pub fn allows(&self, op: config::Operation, device: &str) -> bool {
+        self.grants.as_ref().is_none_or(|grants| {
+            grants.iter().any(|g| {
+                g.allow.contains(&op)
+                    && g.devices.iter().any(|p| p.matches(device))
+            })
+        })
+    }
+
+    // Returns `true` if the client can manage `drmemd`. Administration
+    // isn't tied to devices, so the rule's patterns aren't used.
+
+    pub fn allows_admin(&self) -> bool {
+        self.grants.as_ref().is_none_or(|grants| {
+            grants
+                .iter()
+                .any(|g| g.allow.contains(&config::Operation::Admin))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Operation;
+
+    fn rule(
+        clients: &[&str],
+        devices: &[&str],
+        allow: &[Operation],
+    ) -> config::Rule {
+        config::Rule {
+            clients: clients.iter().map(|s| s.to_string()).collect(),
+            devices: devices.iter().map(|s| s.to_string()).collect(),
+            allow: allow.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_access() {
+        // Without rules, everything is allowed.
+
+        let access = Access::for_client(&[], "00:11");
+
+        assert!(access.allows(Operation::Set, "room:thermostat:limit"));
+        assert!(access.allows_admin());
+        assert_eq!(access.client(), Some("00:11"));
+
+        let rules = [
+            rule(&["00:11", "22:33"], &["hallway:*"], &[Operation::Monitor]),
+            rule(&["00:11"], &["hallway:light:*"], &[Operation::Set]),
+            rule(&["44:55"], &[], &[Operation::Admin]),
+        ];
+
+        // A wall panel can watch the hallway, but can only set its
+        // lights.
+
+        let panel = Access::for_client(&rules, "0011");
+
+        assert!(panel.allows(Operation::Monitor, "hallway:thermostat:limit"));
+        assert!(!panel.allows(Operation::Set, "hallway:thermostat:limit"));
+        assert!(panel.allows(Operation::Set, "hallway:light:state"));
+        assert!(!panel.allows(Operation::Monitor, "garage:door:state"));
+        assert!(!panel.allows_admin());
+
+        // Rules only apply to the clients they list.
+
+        let other = Access::for_client(&rules, "22:33");
+
+        assert!(!other.allows(Operation::Set, "hallway:light:state"));
+
+        let admin = Access::for_client(&rules, "44:55");
+
+        assert!(admin.allows_admin());
+        assert!(!admin.allows(Operation::Monitor, "hallway:light:state"));
+
+        // Clients without any rules can't do anything.
+
+        let unknown = Access::for_client(&rules, "66:77");
+
+        assert!(!unknown.allows(Operation::Monitor, "hallway:light:state"));
+        assert!(!unknown.allows_admin());
+    }
+}
@@ -0,0 +1,146 @@
+// Keeps a trail of the settings made by GraphQL clients so operators
+// can find out who changed a device, and when. The trail is kept in
+// memory, so it's lost when `drmemd` restarts, and only the latest
+// `MAX_RECORDS` settings of each device are kept.
+
+use drmem_api::device;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+const MAX_RECORDS: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    // Identifies the record. Later records have larger ids, so they
+    // are used as cursors when paging through a trail.
+    pub id: u64,
+    pub stamp: SystemTime,
+    // The fingerprint of the client's certificate, if the server
+    // authenticates clients.
+    pub client: Option<String>,
+    // The device's latest reading before the setting.
+    pub old: Option<device::Reading>,
+    pub new: device::Value,
+    // The value used by the driver or the reason the setting failed.
+    pub result: Result<device::Value, String>,
+}
+
+#[derive(Default)]
+struct Trails {
+    next_id: u64,
+    devices: HashMap<String, VecDeque<Record>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Log(Arc<Mutex<Trails>>);
+
+impl Log {
+    // Adds a setting to the trail of `device`. The record's id is
+    // assigned by the log.
+
+    pub fn add(&self, device: &str, mut rec: Record) {
+        let mut trails = self.0.lock().unwrap();
+
+        rec.id = trails.next_id;
+        trails.next_id += 1;
+
+        let trail = trails.devices.entry(device.into()).or_default();
+
+        if trail.len() >= MAX_RECORDS {
+            trail.pop_front();
+        }
+        trail.push_back(rec)
+    }
+
+    // Returns up to `count` records of `device`, most recent first.
+    // If `before` is given, only records older than it are returned.
+
+    pub fn get(
+        &self,
+        device: &str,
+        before: Option<u64>,
+        count: usize,
+    ) -> Vec<Record> {
+        self.0
+            .lock()
+            .unwrap()
+            .devices
+            .get(device)
+            .map(|trail| {
+                trail
+                    .iter()
+                    .rev()
+                    .filter(|r| before.is_none_or(|id| r.id < id))
+                    .take(count)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(v: i32) -> Record {
+        Record {
+            id: 0,
+            stamp: SystemTime::now(),
+            client: None,
+            old: None,
+            new: device::Value::Int(v),
+            result: Ok(device::Value::Int(v)),
+        }
+    }
+
+    fn values(recs: &[Record]) -> Vec<device::Value> {
+        recs.iter().map(|r| r.new.clone()).collect()
+    }
+
+    #[test]
+    fn test_log() {
+        let log = Log::default();
+
+        assert!(log.get("dev:a", None, 10).is_empty());
+
+        for v in 0..5 {
+            log.add("dev:a", rec(v));
+            log.add("dev:b", rec(v + 100));
+        }
+
+        // Each device has its own trail, newest first.
+
+        let page = log.get("dev:a", None, 2);
+
+        assert_eq!(
+            values(&page),
+            vec![device::Value::Int(4), device::Value::Int(3)]
+        );
+
+        let page = log.get("dev:a", Some(page[1].id), 10);
+
+        assert_eq!(
+            values(&page),
+            vec![
+                device::Value::Int(2),
+                device::Value::Int(1),
+                device::Value::Int(0)
+            ]
+        );
+
+        // Old records are dropped.
+
+        for v in 0..MAX_RECORDS as i32 {
+            log.add("dev:b", rec(v));
+        }
+
+        let page = log.get("dev:b", None, usize::MAX);
+
+        assert_eq!(page.len(), MAX_RECORDS);
+        assert_eq!(page.last().unwrap().new, device::Value::Int(0));
+    }
+}
@@ -1,7 +1,10 @@
+use drmem_api::{Error, Result};
+use serde::{de, Deserialize as _, Deserializer};
 use serde_derive::Deserialize;
 use std::{
+    collections::HashMap,
     net::{Ipv4Addr, SocketAddr},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 
@@ -21,11 +24,170 @@ fn def_pref_port() -> u16 {
     3000
 }
 
+fn def_advertise() -> bool {
+    true
+}
+
+fn def_keepalive() -> u64 {
+    15
+}
+
+fn def_init_timeout() -> u64 {
+    10
+}
+
+fn def_requests() -> u32 {
+    20
+}
+
+fn def_burst() -> u32 {
+    40
+}
+
+fn def_subscriptions() -> usize {
+    50
+}
+
+fn def_connections() -> usize {
+    20
+}
+
+fn def_depth() -> usize {
+    15
+}
+
+fn def_complexity() -> usize {
+    1_000
+}
+
+// Controls the WebSocket connections used by subscriptions. Times are
+// in seconds. Keep-alive messages stop NAT devices and proxies from
+// closing idle connections; a `keepalive` of 0 turns them off.
+// Clients that don't initialize their connection within
+// `init_timeout` are disconnected.
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Websocket {
+    #[serde(default = "def_keepalive")]
+    pub keepalive: u64,
+    #[serde(default = "def_init_timeout")]
+    pub init_timeout: u64,
+}
+
+impl Default for Websocket {
+    fn default() -> Self {
+        Websocket {
+            keepalive: def_keepalive(),
+            init_timeout: def_init_timeout(),
+        }
+    }
+}
+
+// Limits what clients can use so a misbehaving one can't fill the
+// core's request channel and delay the settings from logic blocks.
+// Each client address can make `requests` HTTP requests per second
+// and up to `burst` of them at once. `subscriptions` limits the active
+// subscriptions of a WebSocket connection and `connections` limits the
+// number of open WebSocket connections. Queries and mutations whose
+// fields are nested deeper than `depth`, or that select more than
+// `complexity` fields, are refused. A limit of 0 turns it off.
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    #[serde(default = "def_requests")]
+    pub requests: u32,
+    #[serde(default = "def_burst")]
+    pub burst: u32,
+    #[serde(default = "def_subscriptions")]
+    pub subscriptions: usize,
+    #[serde(default = "def_connections")]
+    pub connections: usize,
+    #[serde(default = "def_depth")]
+    pub depth: usize,
+    #[serde(default = "def_complexity")]
+    pub complexity: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            requests: def_requests(),
+            burst: def_burst(),
+            subscriptions: def_subscriptions(),
+            connections: def_connections(),
+            depth: def_depth(),
+            complexity: def_complexity(),
+        }
+    }
+}
+
+// The operations a client can be allowed to perform. `Monitor`
+// covers reading a device's information and subscribing to its
+// readings. `Admin` covers managing `drmemd`, like reloading the
+// logic blocks.
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Monitor,
+    Set,
+    Admin,
+}
+
+// An access rule gives the clients, listed by fingerprint, the
+// operations in `allow` on the devices matching any of the patterns
+// in `devices`.
+
+#[derive(Deserialize, Clone)]
+pub struct Rule {
+    pub clients: Vec<String>,
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub allow: Vec<Operation>,
+}
+
 #[derive(Deserialize)]
 pub struct Security {
     pub clients: Arc<[String]>,
     pub cert_file: Arc<Path>,
     pub key_file: Arc<Path>,
+    #[serde(default)]
+    pub access: Arc<[Rule]>,
+}
+
+// The kinds of devices voice assistants are told about. Lights,
+// switches, outlets, and fans are boolean devices. A dimmer holds a
+// brightness from 0 to 100. A thermometer is a read-only temperature.
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    Light,
+    Switch,
+    Outlet,
+    Fan,
+    Dimmer,
+    Thermometer,
+}
+
+// Shows the devices matching `pattern` to voice assistants as
+// `DeviceType`s.
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SmartDevice {
+    pub pattern: String,
+    #[serde(rename = "type")]
+    pub kind: DeviceType,
+}
+
+// Configures the smart-home endpoints used by voice assistants. The
+// assistant's account linking has to give them `token`.
+
+#[derive(Deserialize, Clone)]
+pub struct SmartHome {
+    pub token: Arc<str>,
+    #[serde(default)]
+    pub device: Arc<[SmartDevice]>,
 }
 
 #[derive(Deserialize)]
@@ -39,7 +201,111 @@ pub struct Config {
     pub pref_host: Option<Arc<str>>,
     #[serde(default = "def_pref_port")]
     pub pref_port: u16,
+    pub cert: Option<Arc<Path>>,
+    pub key: Option<Arc<Path>>,
     pub security: Option<Security>,
+    #[serde(default)]
+    pub websocket: Websocket,
+    #[serde(default)]
+    pub limits: Limits,
+    pub smarthome: Option<SmartHome>,
+    #[serde(default = "def_advertise")]
+    pub advertise: bool,
+    pub allowed_origins: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "load_queries")]
+    pub persisted_queries: Option<Arc<HashMap<String, String>>>,
+}
+
+// Reads the persisted queries from the file named in the
+// configuration. The file is in TOML format and maps each query's id
+// to its text.
+
+fn load_queries<'de, D>(
+    de: D,
+) -> std::result::Result<Option<Arc<HashMap<String, String>>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let path = PathBuf::deserialize(de)?;
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        de::Error::custom(format!("can't read {}: {}", path.display(), e))
+    })?;
+
+    toml::from_str(&text)
+        .map(|v| Some(Arc::new(v)))
+        .map_err(|e| de::Error::custom(format!("{}: {}", path.display(), e)))
+}
+
+// Returns `true` if `origin` looks like the origin of a web page:
+// a scheme and a host, with an optional port, like
+// "https://example.com:8080".
+
+fn valid_origin(origin: &str) -> bool {
+    origin.split_once("://").is_some_and(|(scheme, host)| {
+        !scheme.is_empty()
+            && !host.is_empty()
+            && !host.contains(|c: char| c == '/' || c.is_whitespace())
+    })
+}
+
+impl Config {
+    // Returns the certificate and key files used to serve TLS
+    // connections, if the server uses TLS. The `security` section
+    // has its own files, since it always uses TLS.
+
+    pub fn tls(&self) -> Option<(Arc<Path>, Arc<Path>)> {
+        match (&self.security, &self.cert, &self.key) {
+            (Some(s), _, _) => Some((s.cert_file.clone(), s.key_file.clone())),
+            (None, Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.websocket.init_timeout == 0 {
+            return Err(Error::ConfigError(
+                "'init_timeout' must be greater than 0".into(),
+            ));
+        }
+
+        if self.limits.requests > 0 && self.limits.burst == 0 {
+            return Err(Error::ConfigError(
+                "'burst' must be greater than 0 when requests are limited"
+                    .into(),
+            ));
+        }
+
+        if let Some(origin) = self
+            .allowed_origins
+            .iter()
+            .flatten()
+            .find(|v| !valid_origin(v))
+        {
+            return Err(Error::ConfigError(format!(
+                "'{}' isn't a valid origin",
+                origin
+            )));
+        }
+
+        if self.smarthome.as_ref().is_some_and(|v| v.token.is_empty()) {
+            return Err(Error::ConfigError(
+                "the smart-home 'token' can't be empty".into(),
+            ));
+        }
+
+        match (&self.security, &self.cert, &self.key) {
+            (Some(_), None, None) | (None, None, None) => Ok(()),
+            (None, Some(_), Some(_)) => Ok(()),
+            (Some(_), _, _) => Err(Error::ConfigError(
+                "'cert' and 'key' can't be used with the 'security' section; \
+                 use its 'cert_file' and 'key_file'"
+                    .into(),
+            )),
+            _ => Err(Error::ConfigError(
+                "'cert' and 'key' have to be given together".into(),
+            )),
+        }
+    }
 }
 
 impl Default for Config {
@@ -50,7 +316,15 @@ impl Default for Config {
             addr: def_address(),
             pref_host: None,
             pref_port: def_pref_port(),
+            cert: None,
+            key: None,
             security: None,
+            websocket: Websocket::default(),
+            limits: Limits::default(),
+            smarthome: None,
+            advertise: def_advertise(),
+            allowed_origins: None,
+            persisted_queries: None,
         }
     }
 }
@@ -0,0 +1,779 @@
+// Protects `drmemd`, which is also doing real-time control, from
+// pathological or abusive queries. Before a query is executed, its
+// selections are measured: the depth is how deeply its fields are
+// nested and the complexity is the number of fields it selects, with
+// fragments expanded. Queries that go past the limits are refused.
+// If the configuration has a list of persisted queries, only those
+// queries are executed. Clients can send a persisted query's id
+// instead of its text. Operations started over a subscription's
+// WebSocket are checked the same way.
+
+use super::config;
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, sync::Arc};
+use tracing::info;
+use warp::{filters::BoxedFilter, hyper::body::Bytes, reject, ws, Filter};
+
+// Selection sets nested deeper than this aren't parsed, no matter
+// what the configured limit is, so a query can't overflow the stack.
+
+const MAX_NESTING: usize = 100;
+
+#[derive(Debug)]
+pub struct BadRequest;
+
+impl reject::Reject for BadRequest {}
+
+// The tokens of a query. Values, like strings and numbers, only need
+// to be skipped, so their contents aren't kept.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    Punct(char),
+    Spread,
+    Name(&'a str),
+    Value,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token<'_>>, String> {
+    let bytes = text.as_bytes();
+    let mut tokens = vec![];
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b' ' | b'\t' | b'\r' | b'\n' | b',' => idx += 1,
+            b'#' => {
+                while idx < bytes.len() && bytes[idx] != b'\n' {
+                    idx += 1
+                }
+            }
+            b'.' if bytes[idx..].starts_with(b"...") => {
+                tokens.push(Token::Spread);
+                idx += 3
+            }
+            b'"' if bytes[idx..].starts_with(b"\"\"\"") => {
+                idx += 3;
+                loop {
+                    if idx >= bytes.len() {
+                        return Err("unterminated string".into());
+                    } else if bytes[idx..].starts_with(b"\\\"\"\"") {
+                        idx += 4
+                    } else if bytes[idx..].starts_with(b"\"\"\"") {
+                        idx += 3;
+                        break;
+                    } else {
+                        idx += 1
+                    }
+                }
+                tokens.push(Token::Value)
+            }
+            b'"' => {
+                idx += 1;
+                loop {
+                    match bytes.get(idx) {
+                        None | Some(b'\n') => {
+                            return Err("unterminated string".into())
+                        }
+                        Some(b'\\') => idx += 2,
+                        Some(b'"') => {
+                            idx += 1;
+                            break;
+                        }
+                        Some(_) => idx += 1,
+                    }
+                }
+                tokens.push(Token::Value)
+            }
+            b'-' | b'0'..=b'9' => {
+                idx += 1;
+                while idx < bytes.len()
+                    && (bytes[idx].is_ascii_alphanumeric()
+                        || matches!(bytes[idx], b'.' | b'+' | b'-'))
+                {
+                    idx += 1
+                }
+                tokens.push(Token::Value)
+            }
+            b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                let start = idx;
+
+                while idx < bytes.len()
+                    && (bytes[idx].is_ascii_alphanumeric()
+                        || bytes[idx] == b'_')
+                {
+                    idx += 1
+                }
+                tokens.push(Token::Name(&text[start..idx]))
+            }
+            b'!' | b'$' | b'&' | b'(' | b')' | b':' | b'=' | b'@' | b'['
+            | b']' | b'{' | b'|' | b'}' => {
+                tokens.push(Token::Punct(bytes[idx] as char));
+                idx += 1
+            }
+            _ => {
+                return Err(format!(
+                    "unexpected character '{}'",
+                    text.get(idx..)
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or_default()
+                ))
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// The parts of a selection set that matter when measuring a query.
+
+enum Selection {
+    Field(Vec<Selection>),
+    Inline(Vec<Selection>),
+    Spread(String),
+}
+
+#[derive(Default)]
+struct Document {
+    operations: Vec<Vec<Selection>>,
+    fragments: HashMap<String, Vec<Selection>>,
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>, String> {
+        let token = self.peek().ok_or("unexpected end of query")?;
+
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        match self.next()? {
+            Token::Name(name) => Ok(name),
+            _ => Err("expected a name".into()),
+        }
+    }
+
+    // Skips a parenthesized list, like arguments or variable
+    // definitions, if one is next.
+
+    fn skip_parens(&mut self) -> Result<(), String> {
+        if self.peek() == Some(Token::Punct('(')) {
+            let mut level = 0;
+
+            loop {
+                match self.next()? {
+                    Token::Punct('(') => level += 1,
+                    Token::Punct(')') => {
+                        level -= 1;
+                        if level == 0 {
+                            break;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_directives(&mut self) -> Result<(), String> {
+        while self.peek() == Some(Token::Punct('@')) {
+            self.pos += 1;
+            self.name()?;
+            self.skip_parens()?
+        }
+        Ok(())
+    }
+
+    fn selection_set(
+        &mut self,
+        nesting: usize,
+    ) -> Result<Vec<Selection>, String> {
+        if nesting > MAX_NESTING {
+            return Err("query is nested too deeply".into());
+        }
+
+        if self.next()? != Token::Punct('{') {
+            return Err("expected a selection set".into());
+        }
+
+        let mut set = vec![];
+
+        loop {
+            match self.next()? {
+                Token::Punct('}') => break Ok(set),
+                Token::Spread => match self.peek() {
+                    Some(Token::Name(name)) if name != "on" => {
+                        self.pos += 1;
+                        self.skip_directives()?;
+                        set.push(Selection::Spread(name.into()))
+                    }
+                    _ => {
+                        if self.peek() == Some(Token::Name("on")) {
+                            self.pos += 1;
+                            self.name()?;
+                        }
+                        self.skip_directives()?;
+                        set.push(Selection::Inline(
+                            self.selection_set(nesting + 1)?,
+                        ))
+                    }
+                },
+                Token::Name(_) => {
+                    if self.peek() == Some(Token::Punct(':')) {
+                        self.pos += 1;
+                        self.name()?;
+                    }
+                    self.skip_parens()?;
+                    self.skip_directives()?;
+
+                    let sub = if self.peek() == Some(Token::Punct('{')) {
+                        self.selection_set(nesting + 1)?
+                    } else {
+                        vec![]
+                    };
+
+                    set.push(Selection::Field(sub))
+                }
+                _ => break Err("expected a selection".into()),
+            }
+        }
+    }
+
+    fn document(mut self) -> Result<Document, String> {
+        let mut doc = Document::default();
+
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Punct('{') => {
+                    doc.operations.push(self.selection_set(0)?);
+                }
+                Token::Name("query" | "mutation" | "subscription") => {
+                    self.pos += 1;
+                    if let Some(Token::Name(_)) = self.peek() {
+                        self.pos += 1
+                    }
+                    self.skip_parens()?;
+                    self.skip_directives()?;
+                    doc.operations.push(self.selection_set(0)?)
+                }
+                Token::Name("fragment") => {
+                    self.pos += 1;
+
+                    let name = self.name()?;
+
+                    if self.name()? != "on" {
+                        return Err("expected 'on'".into());
+                    }
+                    self.name()?;
+                    self.skip_directives()?;
+
+                    let set = self.selection_set(0)?;
+
+                    doc.fragments.insert(name.into(), set);
+                }
+                _ => return Err("expected an operation or fragment".into()),
+            }
+        }
+        Ok(doc)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Cost {
+    depth: usize,
+    complexity: usize,
+}
+
+// Measures a selection set. The cost of each fragment is saved, so a
+// fragment used many times is only measured once.
+
+fn measure(
+    set: &[Selection],
+    doc: &Document,
+    saved: &mut HashMap<String, Cost>,
+    active: &mut Vec<String>,
+) -> Result<Cost, String> {
+    let mut cost = Cost::default();
+
+    for sel in set {
+        let (sub, is_field) = match sel {
+            Selection::Field(sub) => (measure(sub, doc, saved, active)?, true),
+            Selection::Inline(sub) => {
+                (measure(sub, doc, saved, active)?, false)
+            }
+            Selection::Spread(name) => {
+                if let Some(sub) = saved.get(name) {
+                    (*sub, false)
+                } else if active.len() >= MAX_NESTING {
+                    return Err("fragments are nested too deeply".into());
+                } else if active.contains(name) {
+                    return Err(format!("fragment '{}' uses itself", name));
+                } else {
+                    let frag = doc.fragments.get(name).ok_or_else(|| {
+                        format!("unknown fragment '{}'", name)
+                    })?;
+
+                    active.push(name.clone());
+
+                    let sub = measure(frag, doc, saved, active)?;
+
+                    active.pop();
+                    saved.insert(name.clone(), sub);
+                    (sub, false)
+                }
+            }
+        };
+
+        if is_field {
+            cost.depth = cost.depth.max(sub.depth + 1);
+            cost.complexity = cost
+                .complexity
+                .saturating_add(sub.complexity)
+                .saturating_add(1);
+        } else {
+            cost.depth = cost.depth.max(sub.depth);
+            cost.complexity = cost.complexity.saturating_add(sub.complexity);
+        }
+    }
+    Ok(cost)
+}
+
+// Returns the cost of the most expensive operation in the query.
+
+fn cost(text: &str) -> Result<Cost, String> {
+    let doc = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    }
+    .document()?;
+    let mut saved = HashMap::new();
+    let mut result = Cost::default();
+
+    for op in &doc.operations {
+        let op = measure(op, &doc, &mut saved, &mut vec![])?;
+
+        result.depth = result.depth.max(op.depth);
+        result.complexity = result.complexity.max(op.complexity);
+    }
+    Ok(result)
+}
+
+#[derive(Clone)]
+pub struct Guard {
+    max_depth: usize,
+    max_complexity: usize,
+    queries: Option<Arc<HashMap<String, String>>>,
+}
+
+impl Guard {
+    pub fn new(cfg: &config::Config) -> Self {
+        Guard {
+            max_depth: cfg.limits.depth,
+            max_complexity: cfg.limits.complexity,
+            queries: cfg.persisted_queries.clone(),
+        }
+    }
+
+    // Checks one request of a batch. If it holds the id of a
+    // persisted query, the id is replaced with the query.
+
+    fn check_one(&self, req: &mut Map<String, Value>) -> Result<(), String> {
+        let id = req.remove("id");
+
+        let text = match (&self.queries, id) {
+            (Some(queries), Some(Value::String(id))) => {
+                let text = queries.get(&id).ok_or_else(|| {
+                    format!("unknown persisted query '{}'", id)
+                })?;
+
+                req.insert("query".into(), text.clone().into());
+                text.clone()
+            }
+            (None, Some(_)) => {
+                return Err("persisted queries aren't used".into())
+            }
+            (Some(_), Some(_)) => return Err("badly formed query id".into()),
+            (queries, None) => {
+                let text = req
+                    .get("query")
+                    .and_then(Value::as_str)
+                    .ok_or("missing query")?;
+
+                if queries.as_ref().is_some_and(|v| {
+                    !v.values().any(|q| q.trim() == text.trim())
+                }) {
+                    return Err("only persisted queries are allowed".into());
+                }
+                text.into()
+            }
+        };
+
+        let measured = cost(&text)?;
+
+        if self.max_depth > 0 && measured.depth > self.max_depth {
+            return Err(format!(
+                "query depth of {} is more than the limit of {}",
+                measured.depth, self.max_depth
+            ));
+        }
+
+        if self.max_complexity > 0 && measured.complexity > self.max_complexity
+        {
+            return Err(format!(
+                "query complexity of {} is more than the limit of {}",
+                measured.complexity, self.max_complexity
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks a request, or a batch of requests, before it's executed.
+
+    pub fn check(&self, mut req: Value) -> Result<Value, String> {
+        match &mut req {
+            Value::Object(req) => self.check_one(req)?,
+            Value::Array(batch) if !batch.is_empty() => {
+                for req in batch {
+                    self.check_one(
+                        req.as_object_mut().ok_or("badly formed request")?,
+                    )?
+                }
+            }
+            _ => return Err("badly formed request".into()),
+        }
+        Ok(req)
+    }
+
+    // Checks a message a client sent over a subscription's
+    // WebSocket. Messages that start an operation, "subscribe" in
+    // the `graphql-transport-ws` protocol and "start" in
+    // `graphql-ws`, have their payload checked like a request. Other
+    // messages are passed on. If the operation is refused, the error
+    // message to send back to the client is returned instead.
+
+    pub fn check_ws(
+        &self,
+        msg: ws::Message,
+    ) -> Result<ws::Message, ws::Message> {
+        let Ok(mut value) = serde_json::from_slice::<Value>(msg.as_bytes())
+        else {
+            return Ok(msg);
+        };
+        let legacy = match value.get("type").and_then(Value::as_str) {
+            Some("subscribe") => false,
+            Some("start") => true,
+            _ => return Ok(msg),
+        };
+        let payload = value
+            .get_mut("payload")
+            .map(Value::take)
+            .unwrap_or_default();
+        let checked = if payload.is_object() {
+            self.check(payload)
+        } else {
+            Err("badly formed request".into())
+        };
+
+        match checked {
+            Ok(payload) => {
+                value["payload"] = payload;
+                Ok(ws::Message::text(value.to_string()))
+            }
+            Err(msg) => {
+                info!("refused request: {}", msg);
+
+                let payload = if legacy {
+                    json!({ "message": msg })
+                } else {
+                    json!([{ "message": msg }])
+                };
+
+                let reply = json!({
+                    "type": "error",
+                    "id": value["id"],
+                    "payload": payload
+                });
+
+                Err(ws::Message::text(reply.to_string()))
+            }
+        }
+    }
+}
+
+// Builds a request from the parameters of a GET request.
+
+fn from_params(
+    mut params: HashMap<String, String>,
+) -> Result<Value, reject::Rejection> {
+    let mut req = Map::new();
+
+    for key in ["query", "id", "operationName"] {
+        if let Some(v) = params.remove(key) {
+            req.insert(key.into(), v.into());
+        }
+    }
+
+    if let Some(v) = params.remove("variables") {
+        let v =
+            serde_json::from_str(&v).map_err(|_| reject::custom(BadRequest))?;
+
+        req.insert("variables".into(), v);
+    }
+    Ok(Value::Object(req))
+}
+
+// Builds a request from the body of a POST request. If the body's
+// type is "application/graphql", it holds the text of the query.
+// Otherwise it has to be JSON.
+
+fn from_body(
+    content_type: Option<String>,
+    body: Bytes,
+) -> Result<Value, reject::Rejection> {
+    let is_graphql = content_type.is_some_and(|v| {
+        v.split(';').next().is_some_and(|v| {
+            v.trim().eq_ignore_ascii_case("application/graphql")
+        })
+    });
+
+    if is_graphql {
+        String::from_utf8(body.to_vec())
+            .map(|query| json!({ "query": query }))
+            .map_err(|_| reject::custom(BadRequest))
+    } else {
+        serde_json::from_slice(&body).map_err(|_| reject::custom(BadRequest))
+    }
+}
+
+// Returns a filter that extracts a GraphQL request from the
+// parameters of a GET request or from the body of a POST request.
+
+pub fn request() -> BoxedFilter<(Value,)> {
+    let get = warp::get()
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|params: HashMap<String, String>| {
+            futures::future::ready(from_params(params))
+        });
+    let post = warp::post()
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(|content_type: Option<String>, body: Bytes| {
+            futures::future::ready(from_body(content_type, body))
+        });
+
+    get.or(post).unify().boxed()
+}
+
+// The reply sent when a request is refused. It uses the format of a
+// GraphQL response, so clients report the error like any other.
+
+pub fn refusal(msg: &str) -> Value {
+    json!({ "errors": [{ "message": msg }] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(
+        depth: usize,
+        complexity: usize,
+        queries: Option<&[(&str, &str)]>,
+    ) -> Guard {
+        Guard {
+            max_depth: depth,
+            max_complexity: complexity,
+            queries: queries.map(|v| {
+                Arc::new(
+                    v.iter()
+                        .map(|(id, q)| (id.to_string(), q.to_string()))
+                        .collect(),
+                )
+            }),
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let c = |depth, complexity| Ok(Cost { depth, complexity });
+
+        assert_eq!(cost("{ a }"), c(1, 1));
+        assert_eq!(cost("query { a b c }"), c(1, 3));
+        assert_eq!(cost("query Q($x: Int = 3) { a(x: $x) { b c } }"), c(2, 3));
+        assert_eq!(cost("{ x: a { b { c } } y: a { b { c } } }"), c(3, 6));
+
+        // Arguments, strings, comments, and directives are skipped.
+
+        assert_eq!(
+            cost(
+                "# { not { a { field } } }
+                 { a(s: \"}{\", o: {k: [1, 2.5e3]}) @include(if: true) {
+                     b(s: \"\"\"{ \\\"\"\" }\"\"\")
+                 } }"
+            ),
+            c(2, 2)
+        );
+
+        // Fragments are expanded, but aren't fields.
+
+        assert_eq!(
+            cost(
+                "{ a { ...F ... on T { d } } }
+                 fragment F on T { b { c } }"
+            ),
+            c(3, 4)
+        );
+
+        // The most expensive operation is used.
+
+        assert_eq!(cost("query A { a } query B { a { b } }"), c(2, 2));
+    }
+
+    #[test]
+    fn test_bad_queries() {
+        assert!(cost("{ a").is_err());
+        assert!(cost("{ a(x: 1 }").is_err());
+        assert!(cost("{ a(x: \"1) }").is_err());
+        assert!(cost("{ ...F }").is_err());
+        assert!(cost("type T { a: Int }").is_err());
+        assert!(cost("{ a } fragment F on T { ...F }").is_ok());
+        assert!(cost("{ ...F } fragment F on T { ...F }").is_err());
+        assert!(cost(&format!(
+            "{}{}",
+            "{ a ".repeat(1_000),
+            "}".repeat(1_000)
+        ))
+        .is_err());
+
+        // Fragments that use each other many times don't take long to
+        // measure and the result doesn't overflow.
+
+        let mut bomb = String::from("{ ...F0 }");
+
+        for ii in 0..80 {
+            bomb.push_str(&format!(
+                " fragment F{} on T {{ ...F{} ...F{} }}",
+                ii,
+                ii + 1,
+                ii + 1
+            ));
+        }
+        bomb.push_str(" fragment F80 on T { a }");
+        assert_eq!(cost(&bomb).map(|c| c.complexity), Ok(usize::MAX));
+    }
+
+    #[test]
+    fn test_limits() {
+        let g = guard(2, 4, None);
+
+        assert!(g.check(json!({ "query": "{ a { b c } }" })).is_ok());
+        assert!(g.check(json!({ "query": "{ a { b { c } } }" })).is_err());
+        assert!(g.check(json!({ "query": "{ a b c d e }" })).is_err());
+        assert!(g.check(json!({})).is_err());
+        assert!(g.check(json!([])).is_err());
+
+        // Every request in a batch is checked.
+
+        assert!(g
+            .check(json!([{ "query": "{ a }" }, { "query": "{ a }" }]))
+            .is_ok());
+        assert!(g
+            .check(json!([{ "query": "{ a }" }, { "query": "{ a b c d e }" }]))
+            .is_err());
+
+        // Limits of 0 are turned off.
+
+        let g = guard(0, 0, None);
+
+        assert!(g.check(json!({ "query": "{ a { b { c d e } } }" })).is_ok());
+        assert!(g.check(json!({ "id": "q" })).is_err());
+    }
+
+    #[test]
+    fn test_persisted() {
+        let g = guard(0, 0, Some(&[("devices", "{ deviceInfo { name } }")]));
+
+        // A persisted query's id is replaced with its text.
+
+        assert_eq!(
+            g.check(json!({ "id": "devices", "variables": {} })),
+            Ok(json!({ "query": "{ deviceInfo { name } }", "variables": {} }))
+        );
+        assert!(g.check(json!({ "id": "other" })).is_err());
+        assert!(g.check(json!({ "id": 5 })).is_err());
+
+        // The text of a persisted query is also allowed, but nothing
+        // else is.
+
+        assert!(g
+            .check(json!({ "query": " { deviceInfo { name } }\n" }))
+            .is_ok());
+        assert!(g
+            .check(json!({ "query": "{ driverInfo { name } }" }))
+            .is_err());
+    }
+
+    #[test]
+    fn test_ws() {
+        let g = guard(0, 0, Some(&[("devices", "{ deviceInfo { name } }")]));
+        let check = |v: Value| {
+            g.check_ws(ws::Message::text(v.to_string()))
+                .map(|v| serde_json::from_slice::<Value>(v.as_bytes()).unwrap())
+                .map_err(|v| {
+                    serde_json::from_slice::<Value>(v.as_bytes()).unwrap()
+                })
+        };
+
+        // Messages that don't start an operation are passed on.
+
+        assert_eq!(
+            check(json!({ "type": "connection_init" })),
+            Ok(json!({ "type": "connection_init" }))
+        );
+
+        // Operations are checked in both protocols.
+
+        assert_eq!(
+            check(json!({
+                "id": "1",
+                "type": "subscribe",
+                "payload": { "id": "devices" }
+            })),
+            Ok(json!({
+                "id": "1",
+                "type": "subscribe",
+                "payload": { "query": "{ deviceInfo { name } }" }
+            }))
+        );
+        assert_eq!(
+            check(json!({
+                "id": "2",
+                "type": "subscribe",
+                "payload": { "query": "{ driverInfo { name } }" }
+            })),
+            Err(json!({
+                "id": "2",
+                "type": "error",
+                "payload": [{ "message": "only persisted queries are allowed" }]
+            }))
+        );
+        assert_eq!(
+            check(json!({ "id": "3", "type": "start", "payload": [] })),
+            Err(json!({
+                "id": "3",
+                "type": "error",
+                "payload": { "message": "badly formed request" }
+            }))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+// Provides the endpoints used by service managers and container
+// orchestrators to check on `drmemd`:
+//
+//     GET /healthz   `drmemd` is alive: the core and the logic manager
+//                    are answering requests
+//     GET /readyz    `drmemd` is working: the back-end can be reached,
+//                    every driver instance is running, and no logic
+//                    block has stopped
+//
+// Both return 200 when the check passes and 503 when it doesn't. The
+// body is a JSON object with the details. The endpoints don't require
+// authentication since probes can't provide it.
+
+use crate::driver::{status, DriverDb};
+use drmem_api::client;
+use serde_json::{json, Value as Json};
+use std::{convert::Infallible, future::Future, time::Duration};
+use warp::{http::StatusCode, reply, Filter, Rejection};
+
+// How long a check waits for an answer.
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Response = reply::WithStatus<reply::Json>;
+
+fn respond(ok: bool, body: Json) -> Response {
+    reply::with_status(
+        reply::json(&body),
+        if ok {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )
+}
+
+// Runs a check, giving up after `CHECK_TIMEOUT`.
+
+async fn check<T, E: std::fmt::Display>(
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+        Ok(Ok(v)) => Ok(v),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("no response".into()),
+    }
+}
+
+async fn healthz(
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> Result<Response, Infallible> {
+    // A reply to the ping shows the core is running, even if it
+    // can't reach the back-end.
+
+    let core = check(cchan.ping()).await.map(|_| ());
+    let logic = check(lchan.get_health()).await.map(|_| ());
+
+    Ok(respond(
+        core.is_ok() && logic.is_ok(),
+        json!({
+            "core": core.err().unwrap_or_else(|| "ok".into()),
+            "logic": logic.err().unwrap_or_else(|| "ok".into()),
+        }),
+    ))
+}
+
+async fn readyz(
+    db: DriverDb,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> Result<Response, Infallible> {
+    let backend = check(cchan.ping())
+        .await
+        .and_then(|v| v.map_err(|e| e.to_string()));
+    let instances = db.status().get_all();
+    let problems: Vec<String> = instances
+        .iter()
+        .filter(|e| e.state != status::State::Running)
+        .map(|e| format!("{} ({})", e.driver, e.prefix))
+        .collect();
+    let logic = check(lchan.get_health()).await;
+    let ready = backend.is_ok()
+        && problems.is_empty()
+        && logic.as_ref().is_ok_and(|v| v.stopped.is_empty());
+
+    Ok(respond(
+        ready,
+        json!({
+            "backend": backend.err().unwrap_or_else(|| "ok".into()),
+            "drivers": {
+                "running": instances.len() - problems.len(),
+                "total": instances.len(),
+                "notRunning": problems,
+            },
+            "logic": match logic {
+                Ok(v) => json!({
+                    "running": v.running,
+                    "stopped": v.stopped,
+                }),
+                Err(e) => json!(e),
+            },
+        }),
+    ))
+}
+
+// Builds the filter that handles the health checks.
+
+pub fn routes(
+    db: DriverDb,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let healthz = {
+        let cchan = cchan.clone();
+        let lchan = lchan.clone();
+
+        warp::path!("healthz")
+            .and(warp::get())
+            .and_then(move || healthz(cchan.clone(), lchan.clone()))
+    };
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .and_then(move || readyz(db.clone(), cchan.clone(), lchan.clone()));
+
+    healthz.or(readyz).unify()
+}
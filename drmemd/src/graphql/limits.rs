@@ -0,0 +1,268 @@
+// Keeps clients from using more than their share of `drmemd`. Each
+// client address gets a bucket of tokens that refills at the allowed
+// request rate. A request takes a token and is refused when the
+// bucket is empty. WebSocket connections are counted while they're
+// open so new ones can be refused once there are too many.
+
+use super::config;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use warp::{filters::BoxedFilter, reject, Filter};
+
+// When the table of clients grows this large, the clients that
+// haven't made requests lately are dropped from it.
+
+const MAX_CLIENTS: usize = 1_000;
+
+#[derive(Debug)]
+pub struct TooManyRequests;
+
+impl reject::Reject for TooManyRequests {}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Refills the bucket for the time since it was last used. The
+    // bucket never holds more than `burst` tokens.
+
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.updated);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        self.updated = now
+    }
+
+    // Takes a token, if there's one.
+
+    fn take(&mut self, now: Instant, rate: f64, burst: f64) -> bool {
+        self.refill(now, rate, burst);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Holds a WebSocket connection's place in the count of open
+// connections. The place is given up when this is dropped. It's also
+// counted in the `drmem:daemon:clients` device.
+
+pub struct Connection(Arc<AtomicUsize>, crate::monitor::Client);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct Limiter {
+    cfg: config::Limits,
+    clients: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Limiter {
+    pub fn new(cfg: config::Limits) -> Self {
+        Limiter {
+            cfg,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // The number of subscriptions a WebSocket connection can have
+    // active. 0 means there's no limit.
+
+    pub fn subscriptions(&self) -> usize {
+        self.cfg.subscriptions
+    }
+
+    // Returns `true` if the client at `addr` can make another
+    // request.
+
+    fn allow_request(&self, addr: IpAddr, now: Instant) -> bool {
+        if self.cfg.requests == 0 {
+            return true;
+        }
+
+        let rate = self.cfg.requests as f64;
+        let burst = self.cfg.burst as f64;
+        let mut clients = self.clients.lock().unwrap();
+
+        // A bucket that has filled back up is the same as a new one,
+        // so it can be dropped.
+
+        if clients.len() >= MAX_CLIENTS {
+            clients.retain(|_, b| {
+                b.refill(now, rate, burst);
+                b.tokens < burst
+            })
+        }
+
+        clients
+            .entry(addr)
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            })
+            .take(now, rate, burst)
+    }
+
+    // Returns a place in the count of open WebSocket connections, if
+    // there's room for another one.
+
+    fn connect(&self) -> Option<Connection> {
+        let max = self.cfg.connections;
+
+        self.connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (max == 0 || n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| {
+                Connection(
+                    self.connections.clone(),
+                    crate::monitor::Client::connect(),
+                )
+            })
+    }
+
+    // Returns a filter that refuses requests from clients that have
+    // used up their request rate. Clients with an unknown address
+    // share one bucket.
+
+    pub fn requests(&self) -> BoxedFilter<()> {
+        let limiter = self.clone();
+
+        warp::addr::remote()
+            .and_then(move |addr: Option<SocketAddr>| {
+                let addr = addr
+                    .map(|v| v.ip())
+                    .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+                futures::future::ready(
+                    if limiter.allow_request(addr, Instant::now()) {
+                        Ok(())
+                    } else {
+                        Err(reject::custom(TooManyRequests))
+                    },
+                )
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    // Returns a filter that gives each WebSocket connection a place in
+    // the count of open connections, or refuses it if there are too
+    // many.
+
+    pub fn connection(&self) -> BoxedFilter<(Connection,)> {
+        let limiter = self.clone();
+
+        warp::any()
+            .and_then(move || {
+                futures::future::ready(
+                    limiter
+                        .connect()
+                        .ok_or_else(|| reject::custom(TooManyRequests)),
+                )
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(requests: u32, burst: u32, connections: usize) -> Limiter {
+        Limiter::new(config::Limits {
+            requests,
+            burst,
+            subscriptions: 0,
+            connections,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_requests() {
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+        let lim = limiter(2, 3, 0);
+
+        // A client can use its burst right away.
+
+        assert!(lim.allow_request(a, now));
+        assert!(lim.allow_request(a, now));
+        assert!(lim.allow_request(a, now));
+        assert!(!lim.allow_request(a, now));
+
+        // Other clients have their own bucket.
+
+        assert!(lim.allow_request(b, now));
+
+        // The bucket refills at the request rate.
+
+        let later = now + Duration::from_millis(500);
+
+        assert!(lim.allow_request(a, later));
+        assert!(!lim.allow_request(a, later));
+
+        // Waiting a long time doesn't save up more than the burst.
+
+        let much_later = later + Duration::from_secs(60);
+
+        for _ in 0..3 {
+            assert!(lim.allow_request(a, much_later));
+        }
+        assert!(!lim.allow_request(a, much_later));
+
+        // A rate of 0 doesn't limit requests.
+
+        let lim = limiter(0, 0, 0);
+
+        for _ in 0..100 {
+            assert!(lim.allow_request(a, now));
+        }
+    }
+
+    #[test]
+    fn test_connections() {
+        let lim = limiter(0, 0, 2);
+        let c1 = lim.connect();
+        let c2 = lim.connect();
+
+        assert!(c1.is_some());
+        assert!(c2.is_some());
+        assert!(lim.connect().is_none());
+
+        // Closing a connection makes room for another.
+
+        drop(c1);
+        assert!(lim.connect().is_some());
+
+        // A limit of 0 doesn't limit connections.
+
+        let lim = limiter(0, 0, 0);
+        let conns: Vec<_> = (0..100).map(|_| lim.connect()).collect();
+
+        assert!(conns.iter().all(Option::is_some));
+    }
+}
@@ -1,29 +1,52 @@
 use chrono::prelude::*;
 use drmem_api::{client, device, driver, Error};
-use futures::Future;
+use futures::{future::join_all, Future};
 use juniper::{
     executor::FieldError, graphql_object, graphql_subscription, graphql_value,
-    FieldResult, GraphQLInputObject, GraphQLObject, RootNode, Value,
+    http::GraphQLBatchRequest, FieldResult, GraphQLEnum, GraphQLInputObject,
+    GraphQLObject, RootNode, Value,
+};
+use juniper_graphql_ws::{
+    graphql_transport_ws, graphql_ws, ArcSchema, ConnectionConfig,
 };
-use juniper_graphql_ws::ConnectionConfig;
-use juniper_warp::subscriptions::serve_graphql_ws;
 use libmdns::Responder;
 use std::{pin::Pin, result, sync::Arc, time::Duration};
-use tracing::{error, info, info_span};
+use tracing::{error, info, info_span, warn};
 use tracing_futures::Instrument;
-use warp::{http::StatusCode, reject, reply, Filter, Rejection, Reply};
+use warp::{
+    filters::BoxedFilter, http::StatusCode, reject, reply, Filter, Rejection,
+    Reply,
+};
 
+mod access;
+mod audit;
 pub mod config;
+mod guard;
+mod health;
+mod limits;
+mod rest;
+mod smarthome;
+mod throttle;
 
 #[derive(Debug)]
 struct NoAuthorization;
 
 impl reject::Reject for NoAuthorization {}
 
-// The Context parameter for Queries.
+// The Context parameter for Queries. `access` holds what the client
+// making the request is allowed to do. `audit` holds the trail of
+// settings, which is shared by all clients. `instances` is used to
+// manage the driver instances.
 
 #[derive(Clone)]
-struct ConfigDb(crate::driver::DriverDb, client::RequestChan);
+struct ConfigDb {
+    drivers: crate::driver::DriverDb,
+    client: client::RequestChan,
+    logic: crate::logic::manager::RequestChan,
+    access: access::Access,
+    audit: audit::Log,
+    instances: crate::driver::instances::RequestChan,
+}
 
 impl juniper::Context for ConfigDb {}
 
@@ -67,23 +90,193 @@ impl DriverInfo {
     }
 }
 
-#[derive(GraphQLInputObject)]
+// The REST interface accepts the same data, as a JSON object.
+
+#[derive(GraphQLInputObject, serde_derive::Deserialize)]
 #[graphql(description = "Describes data that can be sent to devices. When \
 			 specifying data, one -- and only one -- field \
 			 must be set.")]
+#[serde(deny_unknown_fields)]
 struct SettingData {
     #[graphql(name = "int", description = "Placeholder for integer values.")]
+    #[serde(rename = "int")]
     f_int: Option<i32>,
     #[graphql(name = "flt", description = "Placeholder for float values.")]
+    #[serde(rename = "flt")]
     f_float: Option<f64>,
     #[graphql(name = "bool", description = "Placeholder for boolean values.")]
+    #[serde(rename = "bool")]
     f_bool: Option<bool>,
     #[graphql(name = "str", description = "Placeholder for string values.")]
+    #[serde(rename = "str")]
     f_string: Option<String>,
     #[graphql(name = "color", description = "Placeholder for color values.")]
+    #[serde(rename = "color")]
     f_color: Option<Vec<i32>>,
 }
 
+impl SettingData {
+    // Converts the data to a `device::Value`, making sure only one
+    // field was given.
+
+    fn into_value(self) -> FieldResult<device::Value> {
+        match self {
+            SettingData {
+                f_int: None,
+                f_float: None,
+                f_bool: None,
+                f_string: None,
+                f_color: None,
+            } => Err(FieldError::new("no data provided", Value::null())),
+
+            SettingData {
+                f_int: Some(v),
+                f_float: None,
+                f_bool: None,
+                f_string: None,
+                f_color: None,
+            } => Ok(v.into()),
+
+            SettingData {
+                f_int: None,
+                f_float: Some(v),
+                f_bool: None,
+                f_string: None,
+                f_color: None,
+            } => Ok(v.into()),
+
+            SettingData {
+                f_int: None,
+                f_float: None,
+                f_bool: Some(v),
+                f_string: None,
+                f_color: None,
+            } => Ok(v.into()),
+
+            SettingData {
+                f_int: None,
+                f_float: None,
+                f_bool: None,
+                f_string: Some(v),
+                f_color: None,
+            } => Ok(v.into()),
+
+            SettingData {
+                f_int: None,
+                f_float: None,
+                f_bool: None,
+                f_string: None,
+                f_color: Some(v),
+            } => {
+                let comp = |c: i32| {
+                    u8::try_from(c).map_err(|_| {
+                        FieldError::new(
+                            "color component is out of range",
+                            Value::null(),
+                        )
+                    })
+                };
+
+                match v[..] {
+                    [r, g, b] => Ok(palette::LinSrgba::<u8>::new(
+                        comp(r)?,
+                        comp(g)?,
+                        comp(b)?,
+                        255,
+                    )
+                    .into()),
+                    [r, g, b, a] => Ok(palette::LinSrgba::<u8>::new(
+                        comp(r)?,
+                        comp(g)?,
+                        comp(b)?,
+                        comp(a)?,
+                    )
+                    .into()),
+                    _ => Err(FieldError::new(
+                        "color values have three or four components",
+                        Value::null(),
+                    )),
+                }
+            }
+
+            SettingData { .. } => Err(FieldError::new(
+                "must only specify one item of data",
+                Value::null(),
+            )),
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A value to send to a device.")]
+struct DeviceSetting {
+    #[graphql(description = "The name of the device.")]
+    name: String,
+    value: SettingData,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "The result of one setting of a `setDevices` \
+			 request. Either `reading` or `error` is set.")]
+struct SettingResult {
+    #[graphql(description = "The name of the device.")]
+    device: String,
+    #[graphql(description = "The value the driver used.")]
+    reading: Option<Reading>,
+    #[graphql(description = "Why the setting wasn't made.")]
+    error: Option<String>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Records a setting made by a client. Either \
+			 `reading` or `error` is set.")]
+struct SettingRecord {
+    #[graphql(description = "Identifies the record. Pass it as the `after` \
+			     argument of `settingAudit` to get older \
+			     records.")]
+    id: String,
+    #[graphql(description = "When the setting was made.")]
+    stamp: DateTime<Utc>,
+    #[graphql(description = "The fingerprint of the client that made the \
+			     setting. It's `null` if the server doesn't \
+			     authenticate clients.")]
+    client: Option<String>,
+    #[graphql(description = "The device's latest reading before the \
+			     setting, if it had one.")]
+    old: Option<Reading>,
+    #[graphql(description = "The value requested by the client.")]
+    requested: Reading,
+    #[graphql(description = "The value the driver used.")]
+    reading: Option<Reading>,
+    #[graphql(description = "Why the setting wasn't made.")]
+    error: Option<String>,
+}
+
+impl SettingRecord {
+    fn new(device: &str, rec: audit::Record) -> Self {
+        let reading = |r: &device::Reading| Reading {
+            device: device.into(),
+            ..r.into()
+        };
+        let at_stamp = |value| {
+            reading(&device::Reading {
+                ts: rec.stamp,
+                value,
+            })
+        };
+
+        SettingRecord {
+            id: rec.id.to_string(),
+            stamp: rec.stamp.into(),
+            client: rec.client,
+            old: rec.old.as_ref().map(reading),
+            requested: at_stamp(rec.new),
+            reading: rec.result.as_ref().ok().cloned().map(at_stamp),
+            error: rec.result.err(),
+        }
+    }
+}
+
 // Contains information about a device's history in the backend.
 
 #[derive(GraphQLObject)]
@@ -117,10 +310,35 @@ struct DeviceInfo {
     units: Option<String>,
     settable: bool,
     driver_name: driver::Name,
+    meta: client::DeviceMeta,
     history: DeviceHistory,
     db: crate::driver::DriverDb,
 }
 
+impl DeviceInfo {
+    fn new(db: &ConfigDb, e: &client::DevInfoReply) -> Self {
+        DeviceInfo {
+            device_name: e.name.to_string(),
+            units: e.units.clone(),
+            settable: e.settable,
+            driver_name: e.driver.clone(),
+            meta: e.meta.clone(),
+            history: DeviceHistory {
+                total_points: e.total_points as i32,
+                first_point: e.first_point.as_ref().map(|v| Reading {
+                    device: e.name.to_string(),
+                    ..v.into()
+                }),
+                last_point: e.last_point.as_ref().map(|v| Reading {
+                    device: e.name.to_string(),
+                    ..v.into()
+                }),
+            },
+            db: db.drivers.clone(),
+        }
+    }
+}
+
 #[graphql_object(
     Context = ConfigDb,
     description = "Information about a registered device in the running \
@@ -156,11 +374,265 @@ impl DeviceInfo {
             .unwrap()
     }
 
+    #[graphql(description = "A one-line description of the device.")]
+    fn summary(&self) -> Option<&String> {
+        self.meta.summary.as_ref()
+    }
+
+    #[graphql(description = "A longer description of the device.")]
+    fn detail(&self) -> Option<&String> {
+        self.meta.detail.as_ref()
+    }
+
+    #[graphql(description = "Where the device, or the hardware behind it, \
+			     is located.")]
+    fn location(&self) -> Option<&String> {
+        self.meta.location.as_ref()
+    }
+
     fn history(&self) -> &DeviceHistory {
         &self.history
     }
 }
 
+// These GraphQL objects report the activity of a logic block.
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Describes an input, or definition, of a logic \
+			 block.")]
+struct LogicInput {
+    #[graphql(description = "The name used by the expressions.")]
+    name: String,
+    #[graphql(description = "The device providing the value. This is \
+			     `null` for definitions.")]
+    device: Option<String>,
+    #[graphql(description = "The latest value and when it last changed. \
+			     This is `null` if there's no value yet.")]
+    value: Option<Reading>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Describes an expression of a logic block.")]
+struct LogicExpr {
+    #[graphql(description = "The text of the expression.")]
+    source: String,
+    #[graphql(description = "The name of the output variable.")]
+    output: String,
+    #[graphql(description = "The device controlled by the output.")]
+    device: String,
+    #[graphql(description = "The result of the latest evaluation and \
+			     when it occurred. This is `null` if the \
+			     expression couldn't compute a value.")]
+    result: Option<Reading>,
+    #[graphql(description = "The last time the output device accepted a \
+			     setting from this expression.")]
+    last_set: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Information about a running logic block.")]
+struct LogicInfo {
+    name: String,
+    summary: Option<String>,
+    inputs: Vec<LogicInput>,
+    exprs: Vec<LogicExpr>,
+}
+
+impl From<crate::logic::manager::BlockInfo> for LogicInfo {
+    fn from(info: crate::logic::manager::BlockInfo) -> Self {
+        let cfg = info.cfg;
+        let to_reading = |dev: String| {
+            move |r: device::Reading| Reading {
+                device: dev,
+                ..(&r).into()
+            }
+        };
+
+        LogicInfo {
+            inputs: info
+                .status
+                .inputs
+                .into_iter()
+                .map(|(name, value)| {
+                    let device = cfg.inputs.get(&name).map(|v| v.to_string());
+
+                    LogicInput {
+                        value: value.map(to_reading(
+                            device.clone().unwrap_or_default(),
+                        )),
+                        name,
+                        device,
+                    }
+                })
+                .collect(),
+            exprs: info
+                .status
+                .exprs
+                .into_iter()
+                .map(|e| {
+                    let device = cfg
+                        .outputs
+                        .get(&e.output)
+                        .map(|v| v.to_string())
+                        .unwrap_or_default();
+
+                    LogicExpr {
+                        result: e.result.map(to_reading(device.clone())),
+                        last_set: e.last_set.map(DateTime::<Utc>::from),
+                        source: e.source,
+                        output: e.output,
+                        device,
+                    }
+                })
+                .collect(),
+            name: cfg.name,
+            summary: cfg.summary,
+        }
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Reports which source controls a device that is \
+			 set by logic blocks or clients.")]
+struct DeviceOwner {
+    #[graphql(description = "The name of the device.")]
+    device: String,
+    #[graphql(description = "The logic block controlling the device. If \
+			     `null`, the device was last set by a client.")]
+    logic_block: Option<String>,
+    #[graphql(description = "The priority of the controlling source.")]
+    priority: i32,
+    #[graphql(description = "When the controlling source last set the \
+			     device.")]
+    stamp: DateTime<Utc>,
+}
+
+#[derive(GraphQLEnum)]
+#[graphql(description = "The states of a driver instance.")]
+enum DriverState {
+    #[graphql(description = "The instance is being created.")]
+    Starting,
+    #[graphql(description = "The instance is running.")]
+    Running,
+    #[graphql(description = "The instance stopped and will be restarted \
+			     after a delay.")]
+    Restarting,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Reports how an instance of a driver is doing.")]
+struct DriverStatus {
+    #[graphql(description = "The name of the driver.")]
+    driver: String,
+    #[graphql(description = "The prefix of the instance's devices.")]
+    prefix: String,
+    #[graphql(description = "The names of the instance's configuration \
+			     parameters. Values aren't reported since they \
+			     may hold passwords or keys.")]
+    config: Vec<String>,
+    state: DriverState,
+    #[graphql(description = "When the instance entered its current state.")]
+    since: DateTime<Utc>,
+    #[graphql(description = "The number of seconds the instance has been \
+			     running, if it is running.")]
+    uptime: Option<f64>,
+    #[graphql(description = "The number of times the instance was \
+			     restarted.")]
+    restarts: i32,
+    #[graphql(description = "Why the instance last stopped, or `null` if \
+			     it never stopped.")]
+    last_error: Option<String>,
+    #[graphql(description = "When a stopped instance will be restarted. \
+			     The delay doubles with each failure in a row, \
+			     up to 10 minutes.")]
+    restart_at: Option<DateTime<Utc>>,
+}
+
+impl From<crate::driver::status::Instance> for DriverStatus {
+    fn from(v: crate::driver::status::Instance) -> Self {
+        use crate::driver::status::State;
+
+        DriverStatus {
+            driver: v.driver.to_string(),
+            prefix: v.prefix,
+            config: v.config,
+            state: match v.state {
+                State::Starting => DriverState::Starting,
+                State::Running => DriverState::Running,
+                State::Restarting => DriverState::Restarting,
+            },
+            since: v.since.into(),
+            uptime: (v.state == State::Running)
+                .then(|| v.since.elapsed().unwrap_or_default().as_secs_f64()),
+            restarts: v.restarts as i32,
+            last_error: v.last_error,
+            restart_at: v.restart_at.map(Into::into),
+        }
+    }
+}
+
+#[derive(GraphQLEnum)]
+#[graphql(description = "The kinds of events reported by `systemEvents`.")]
+enum SystemEventKind {
+    #[graphql(description = "A driver instance stopped or couldn't be \
+			     created.")]
+    DriverFailed,
+    #[graphql(description = "A driver instance is being restarted.")]
+    DriverRestarted,
+    #[graphql(description = "A logic block stopped because of an error.")]
+    LogicStopped,
+    #[graphql(description = "A driver registered a device.")]
+    DeviceRegistered,
+    #[graphql(description = "The system clock was stepped by more than a \
+			     minute.")]
+    ClockStepped,
+    #[graphql(description = "A driver didn't reply to a setting in time.")]
+    DriverUnresponsive,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Something that happened inside `drmemd`.")]
+struct SystemEvent {
+    #[graphql(description = "When the event occurred.")]
+    stamp: DateTime<Utc>,
+    kind: SystemEventKind,
+    #[graphql(description = "What the event is about: a driver instance, \
+			     logic block, or device.")]
+    source: String,
+    #[graphql(description = "Details of the event.")]
+    message: String,
+}
+
+impl From<crate::events::Event> for SystemEvent {
+    fn from(v: crate::events::Event) -> Self {
+        use crate::events::Kind;
+
+        SystemEvent {
+            stamp: v.stamp.into(),
+            kind: match v.kind {
+                Kind::DriverFailed => SystemEventKind::DriverFailed,
+                Kind::DriverRestarted => SystemEventKind::DriverRestarted,
+                Kind::LogicStopped => SystemEventKind::LogicStopped,
+                Kind::DeviceRegistered => SystemEventKind::DeviceRegistered,
+                Kind::ClockStepped => SystemEventKind::ClockStepped,
+                Kind::DriverUnresponsive => SystemEventKind::DriverUnresponsive,
+            },
+            source: v.source,
+            message: v.message,
+        }
+    }
+}
+
+#[derive(GraphQLEnum, Clone, Copy, PartialEq, Debug)]
+#[graphql(description = "The orders in which devices can be listed.")]
+enum DeviceOrder {
+    #[graphql(description = "Sorted by device name.")]
+    Name,
+    #[graphql(description = "The most recently updated devices first. \
+			     Devices without readings are listed last.")]
+    LastUpdate,
+}
+
 // This defines the top-level Query API.
 
 struct Config;
@@ -180,6 +652,44 @@ impl Config {
     fn is_true(_e: &&client::DevInfoReply) -> bool {
         true
     }
+
+    // Sorts the devices and returns the page of them that follows
+    // the device named `after`. Names are unique, so they're used as
+    // the cursors.
+
+    fn paginate<'a>(
+        mut devs: Vec<&'a client::DevInfoReply>,
+        order: DeviceOrder,
+        after: Option<&str>,
+        first: Option<usize>,
+    ) -> result::Result<Vec<&'a client::DevInfoReply>, &'static str> {
+        match order {
+            DeviceOrder::Name => {
+                devs.sort_by_cached_key(|d| d.name.to_string())
+            }
+            DeviceOrder::LastUpdate => devs.sort_by_cached_key(|d| {
+                (
+                    std::cmp::Reverse(d.last_point.as_ref().map(|r| r.ts)),
+                    d.name.to_string(),
+                )
+            }),
+        }
+
+        let skip = if let Some(after) = after {
+            devs.iter()
+                .position(|d| d.name.to_string() == after)
+                .ok_or("unknown cursor")?
+                + 1
+        } else {
+            0
+        };
+
+        Ok(devs
+            .into_iter()
+            .skip(skip)
+            .take(first.unwrap_or(usize::MAX))
+            .collect())
+    }
 }
 
 #[graphql_object(
@@ -203,7 +713,7 @@ impl Config {
         name: Option<String>,
     ) -> result::Result<Vec<DriverInfo>, FieldError> {
         if let Some(name) = name {
-            if let Some((n, s, d)) = db.0.find(&name) {
+            if let Some((n, s, d)) = db.drivers.find(&name) {
                 Ok(vec![DriverInfo {
                     name: n,
                     summary: s,
@@ -216,19 +726,87 @@ impl Config {
                 ))
             }
         } else {
-            let result =
-                db.0.get_all()
-                    .map(|(n, s, d)| DriverInfo {
-                        name: n,
-                        summary: s,
-                        description: d,
-                    })
-                    .collect();
+            let result = db
+                .drivers
+                .get_all()
+                .map(|(n, s, d)| DriverInfo {
+                    name: n,
+                    summary: s,
+                    description: d,
+                })
+                .collect();
 
             Ok(result)
         }
     }
 
+    #[graphql(description = "Returns the status of each driver instance \
+			     started by `drmemd`. This shows which hardware \
+			     integrations are having problems.")]
+    fn driver_status(
+        #[graphql(context)] db: &ConfigDb,
+    ) -> result::Result<Vec<DriverStatus>, FieldError> {
+        if !db.access.allows_admin() {
+            return Err(FieldError::new(
+                "not authorized to see driver status",
+                Value::null(),
+            ));
+        }
+
+        Ok(db
+            .drivers
+            .status()
+            .get_all()
+            .into_iter()
+            .map(Into::into)
+            .collect())
+    }
+
+    #[graphql(description = "Returns the audit trail of the settings made \
+			     to a device by clients, most recent first. \
+			     Each record shows who made the setting, when, \
+			     the device's previous value, and the result. \
+			     To get the next page, pass the `id` of the last \
+			     record as `after`. The trail is kept in memory, \
+			     so it starts empty when `drmemd` restarts, and \
+			     only the latest 100 settings of each device are \
+			     kept. Requires the `admin` operation.")]
+    fn setting_audit(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "The name of the device.")] device: String,
+        #[graphql(description = "If provided, the results start after the \
+				 record with this id.")]
+        after: Option<String>,
+        #[graphql(description = "If provided, at most this many records \
+				 are returned.")]
+        first: Option<i32>,
+    ) -> result::Result<Vec<SettingRecord>, FieldError> {
+        if !db.access.allows_admin() {
+            return Err(FieldError::new(
+                "not authorized to see the audit trail",
+                Value::null(),
+            ));
+        }
+
+        if first.is_some_and(|v| v < 0) {
+            return Err(FieldError::new(
+                "`first` can't be negative",
+                Value::null(),
+            ));
+        }
+
+        let Ok(after) = after.map(|v| v.parse::<u64>()).transpose() else {
+            return Err(FieldError::new("unknown cursor", Value::null()));
+        };
+
+        Ok(db
+            .audit
+            .get(&device, after, first.map_or(usize::MAX, |v| v as usize))
+            .into_iter()
+            .map(|rec| SettingRecord::new(&device, rec))
+            .collect())
+    }
+
     #[graphql(
         description = "Returns information associated with the devices that \
 		       are active in the running system. Arguments to the \
@@ -239,7 +817,10 @@ impl Config {
 		       shell \"glob\" style.\n\n\
 		       If the argument `settable` is provided, it returns \
 		       devices that are or aren't settable, depending on the \
-		       value of the agument."
+		       value of the agument.\n\n\
+		       Large installations can be browsed a page at a time \
+		       by using `first` and passing the name of the last \
+		       device of a page as `after` to get the next page."
     )]
     async fn device_info(
         #[graphql(context)] db: &ConfigDb,
@@ -260,8 +841,27 @@ impl Config {
 			   or not."
         )]
         settable: Option<bool>,
+        #[graphql(description = "If this argument is provided, only devices \
+				 of the named driver are returned.")]
+        driver: Option<String>,
+        #[graphql(description = "The order of the results. Defaults to \
+				 `NAME`.")]
+        sort: Option<DeviceOrder>,
+        #[graphql(description = "If provided, the results start after the \
+				 device with this name.")]
+        after: Option<String>,
+        #[graphql(description = "If provided, at most this many devices \
+				 are returned.")]
+        first: Option<i32>,
     ) -> result::Result<Vec<DeviceInfo>, FieldError> {
-        let tx = db.1.clone();
+        if first.is_some_and(|v| v < 0) {
+            return Err(FieldError::new(
+                "`first` can't be negative",
+                Value::null(),
+            ));
+        }
+
+        let tx = db.client.clone();
         let filt = settable
             .map(|v| {
                 if v {
@@ -272,41 +872,155 @@ impl Config {
             })
             .unwrap_or(Config::is_true);
 
-        tx.get_device_info(pattern)
+        let devs = tx.get_device_info(pattern).await.map_err(|_| {
+            FieldError::new("error looking-up device", Value::null())
+        })?;
+        let devs = devs
+            .iter()
+            .filter(filt)
+            .filter(|e| driver.as_ref().is_none_or(|d| **d == *e.driver))
+            .filter(|e| {
+                db.access
+                    .allows(config::Operation::Monitor, &e.name.to_string())
+            })
+            .collect();
+
+        Config::paginate(
+            devs,
+            sort.unwrap_or(DeviceOrder::Name),
+            after.as_deref(),
+            first.map(|v| v as usize),
+        )
+        .map(|v| v.into_iter().map(|e| DeviceInfo::new(db, e)).collect())
+        .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Returns a summary of a device's history. The \
+			     readings between `start` and `end` are grouped \
+			     into buckets that are `interval` seconds long. \
+			     Buckets without readings are left out. This \
+			     lets a dashboard chart a long period of time \
+			     without receiving every reading.")]
+    async fn device_history(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "The name of the device.")] device: String,
+        #[graphql(description = "The start of the time range (in UTC.)")]
+        start: DateTime<Utc>,
+        #[graphql(description = "The end of the time range (in UTC.) If \
+				 not provided, it means \"now\".")]
+        end: Option<DateTime<Utc>>,
+        #[graphql(description = "The length, in seconds, of each bucket.")]
+        interval: i32,
+    ) -> result::Result<Vec<HistoryBucket>, FieldError> {
+        let name = device.parse::<device::Name>().map_err(|_| {
+            FieldError::new("badly formed device name", Value::null())
+        })?;
+
+        if !db.access.allows(config::Operation::Monitor, &device) {
+            return Err(FieldError::new(
+                "not authorized to monitor device",
+                Value::null(),
+            ));
+        }
+
+        if interval <= 0 {
+            return Err(FieldError::new(
+                "interval must be positive",
+                Value::null(),
+            ));
+        }
+
+        db.client
+            .aggregate_history(
+                name,
+                start,
+                end.unwrap_or_else(Utc::now),
+                Duration::from_secs(interval as u64),
+            )
             .await
             .map(|v| {
                 v.iter()
-                    .filter(filt)
-                    .map(|e| DeviceInfo {
-                        device_name: e.name.to_string(),
-                        units: e.units.clone(),
-                        settable: e.settable,
-                        driver_name: e.driver.clone(),
-                        history: DeviceHistory {
-                            total_points: e.total_points as i32,
-                            first_point: e.first_point.as_ref().map(|v| {
-                                Reading {
-                                    device: e.name.to_string(),
-                                    ..v.into()
-                                }
-                            }),
-                            last_point: e.last_point.as_ref().map(|v| {
-                                Reading {
-                                    device: e.name.to_string(),
-                                    ..v.into()
-                                }
-                            }),
+                    .map(|b| HistoryBucket {
+                        start: b.start,
+                        count: b.count as i32,
+                        min: b.min,
+                        max: b.max,
+                        avg: b.avg,
+                        last: Reading {
+                            device: device.clone(),
+                            ..(&b.last).into()
                         },
-                        db: db.0.clone(),
                     })
                     .collect()
             })
-            .map_err(|_| {
-                FieldError::new("error looking-up device", Value::null())
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Returns the running logic blocks. Each entry \
+			     shows the block's inputs with their latest \
+			     values, its expressions with their latest \
+			     results, and when each output was last set. \
+			     This is useful to find out why a logic block \
+			     didn't do what was expected.")]
+    async fn logic_info(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "If provided, only the logic block with \
+				 this name is returned.")]
+        name: Option<String>,
+    ) -> result::Result<Vec<LogicInfo>, FieldError> {
+        db.logic
+            .get_blocks()
+            .await
+            .map(|v| {
+                v.into_iter()
+                    .filter(|b| name.as_ref().is_none_or(|n| *n == b.cfg.name))
+                    .map(LogicInfo::from)
+                    .collect()
+            })
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Returns the source controlling each device \
+			     that has been set by a logic block or a \
+			     client. When several sources set a device, \
+			     the one with the highest priority wins. Within \
+			     a priority, the last one to set the device \
+			     wins.")]
+    fn device_owners(#[graphql(context)] db: &ConfigDb) -> Vec<DeviceOwner> {
+        use crate::logic::arbiter::Source;
+
+        db.logic
+            .get_owners()
+            .into_iter()
+            .map(|(dev, claim)| DeviceOwner {
+                device: dev.to_string(),
+                logic_block: match claim.source {
+                    Source::Logic(name) => Some(name.to_string()),
+                    Source::Manual => None,
+                },
+                priority: claim.priority,
+                stamp: DateTime::<Utc>::from(claim.stamp),
             })
+            .collect()
     }
 }
 
+// Reports the outcome of reloading the logic blocks.
+
+#[derive(GraphQLObject)]
+#[graphql(description = "Reports which logic blocks were affected when \
+			 the logic configuration was reloaded.")]
+struct LogicReload {
+    #[graphql(description = "Logic blocks that were started or restarted.")]
+    started: Vec<String>,
+    #[graphql(description = "Logic blocks that were stopped because they \
+			     were removed from the configuration.")]
+    stopped: Vec<String>,
+    #[graphql(description = "Logic blocks that kept running because their \
+			     configuration didn't change.")]
+    unchanged: Vec<String>,
+}
+
 // The `Control` mutation is used to group queries that attempt to
 // control devices by sending them settings.
 
@@ -325,7 +1039,19 @@ impl Control {
         // Make sure the device name is properly formed.
 
         if let Ok(name) = device.parse::<device::Name>() {
-            let tx = db.1.clone();
+            if !db.access.allows(config::Operation::Set, device) {
+                return Err(FieldError::new(
+                    "not authorized to set device",
+                    Value::null(),
+                ));
+            }
+
+            let tx = db.client.clone();
+
+            // Manual settings take control of the device away from
+            // logic blocks with the same, or lower, priority.
+
+            db.logic.manual_setting(&name);
 
             // Send the setting to the driver. Map the error, if any,
             // to a `FieldError` type.
@@ -343,96 +1069,173 @@ impl Control {
         }
     }
 
-    // Helper function which returns a closure that converts a
-    // boolean value to a `Reading` type.
+    // Sends a value to a device and returns the value the driver
+    // used.
 
-    fn bool_to_reading(name: String) -> impl FnOnce(bool) -> Reading {
-        |v| Reading {
-            device: name,
-            stamp: Utc::now(),
-            int_value: None,
-            float_value: None,
-            bool_value: Some(v),
-            string_value: None,
-            color_value: None,
-        }
+    async fn send(
+        db: &ConfigDb,
+        device: &str,
+        value: device::Value,
+    ) -> FieldResult<device::Value> {
+        Ok(match value {
+            device::Value::Bool(v) => {
+                Control::perform_setting(db, device, v).await?.into()
+            }
+            device::Value::Int(v) => {
+                Control::perform_setting(db, device, v).await?.into()
+            }
+            device::Value::Flt(v) => {
+                Control::perform_setting(db, device, v).await?.into()
+            }
+            device::Value::Str(v) => {
+                Control::perform_setting(db, device, v).await?.into()
+            }
+            device::Value::Color(v) => {
+                Control::perform_setting(db, device, v).await?.into()
+            }
+        })
     }
 
-    // Helper function which returns a closure that converts an
-    // integer to a `Reading` type.
+    // Returns the latest reading of `device`, or `None` if the device
+    // doesn't exist. The inner `Option` is `None` if the device
+    // hasn't reported a value.
 
-    fn int_to_reading(name: String) -> impl FnOnce(i32) -> Reading {
-        |v| Reading {
-            device: name,
-            stamp: Utc::now(),
-            int_value: Some(v),
-            float_value: None,
-            bool_value: None,
-            string_value: None,
-            color_value: None,
-        }
+    async fn latest_reading(
+        db: &ConfigDb,
+        device: &str,
+    ) -> Option<Option<device::Reading>> {
+        db.client
+            .get_device_info(Some(device.into()))
+            .await
+            .ok()?
+            .into_iter()
+            .find(|e| e.name.to_string() == device)
+            .map(|e| e.last_point)
     }
 
-    // Helper function which returns a closure that converts a
-    // floating point value to a `Reading` type.
+    // Sends a value to a device and returns the value the driver
+    // used. Settings of existing devices are added to the audit
+    // trail, whether they succeed or not. Settings of unknown devices
+    // aren't, so clients can't fill the trail with made-up names.
 
-    fn flt_to_reading(name: String) -> impl FnOnce(f64) -> Reading {
-        |v| Reading {
-            device: name,
-            stamp: Utc::now(),
-            int_value: None,
-            float_value: Some(v),
-            bool_value: None,
-            string_value: None,
-            color_value: None,
+    async fn set_value(
+        db: &ConfigDb,
+        device: &str,
+        value: device::Value,
+    ) -> FieldResult<device::Value> {
+        let old = Control::latest_reading(db, device).await;
+        let result = Control::send(db, device, value.clone()).await;
+
+        if let Some(old) = old {
+            db.audit.add(
+                device,
+                audit::Record {
+                    id: 0,
+                    stamp: std::time::SystemTime::now(),
+                    client: db.access.client().map(String::from),
+                    old,
+                    new: value,
+                    result: result.as_ref().cloned().map_err(describe),
+                },
+            )
         }
+        result
     }
 
-    // Helper function which returns a closure that converts a
-    // string value to a `Reading` type.
+    // Like `set_value`, but returns the value as a `Reading`.
 
-    fn str_to_reading(name: String) -> impl FnOnce(String) -> Reading {
-        |v| Reading {
-            device: name,
-            stamp: Utc::now(),
-            int_value: None,
-            float_value: None,
-            bool_value: None,
-            string_value: Some(v.into()),
-            color_value: None,
-        }
+    async fn apply(
+        db: &ConfigDb,
+        device: &str,
+        value: device::Value,
+    ) -> FieldResult<Reading> {
+        let value = Control::set_value(db, device, value).await?;
+
+        Ok(Reading {
+            device: device.into(),
+            ..(&device::Reading {
+                ts: crate::clock::now(),
+                value,
+            })
+                .into()
+        })
     }
 
-    // Helper function which returns a closure that converts a color
-    // value to a `Reading` type.
+    // Checks the settings of a `setDevices` request. Returns the
+    // reason each one can't be made, or `None` if it can.
 
-    fn color_to_reading(
-        name: String,
-    ) -> impl FnOnce(palette::LinSrgba<u8>) -> Reading {
-        |v| Reading {
-            device: name,
-            stamp: Utc::now(),
-            int_value: None,
-            float_value: None,
-            bool_value: None,
-            string_value: None,
-            color_value: Some(if v.alpha == 255 {
-                vec![v.red as i32, v.green as i32, v.blue as i32]
-            } else {
-                vec![
-                    v.red as i32,
-                    v.green as i32,
-                    v.blue as i32,
-                    v.alpha as i32,
-                ]
-            }),
-        }
+    async fn check_settings(
+        db: &ConfigDb,
+        settings: &[(String, FieldResult<device::Value>)],
+    ) -> Vec<Option<String>> {
+        let settable: Vec<String> = db
+            .client
+            .get_device_info(None)
+            .await
+            .map(|v| {
+                v.iter()
+                    .filter(|e| e.settable)
+                    .map(|e| e.name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        settings
+            .iter()
+            .map(|(name, value)| {
+                if let Err(e) = value {
+                    Some(describe(e))
+                } else if name.parse::<device::Name>().is_err() {
+                    Some("badly formed device name".into())
+                } else if !db.access.allows(config::Operation::Set, name) {
+                    Some("not authorized to set device".into())
+                } else if !settable.contains(name) {
+                    Some("device isn't settable".into())
+                } else {
+                    None
+                }
+            })
+            .collect()
     }
-}
 
-#[graphql_object(
-    context = ConfigDb,
-    description = "This group of queries perform modifications to devices."
+    // Checks that the client may manage driver instances and parses
+    // the prefix of the instance.
+
+    fn instance_prefix(
+        db: &ConfigDb,
+        prefix: &str,
+    ) -> FieldResult<device::Path> {
+        if !db.access.allows_admin() {
+            return Err(FieldError::new(
+                "not authorized to manage driver instances",
+                Value::null(),
+            ));
+        }
+
+        prefix
+            .parse()
+            .map_err(|_| FieldError::new("badly formed prefix", Value::null()))
+    }
+}
+
+// Returns the text of a `FieldError`, including the error reported
+// by the driver, if there was one.
+
+fn describe(e: &FieldError) -> String {
+    match e
+        .extensions()
+        .as_object_value()
+        .and_then(|o| o.get_field_value("error"))
+        .and_then(|v| v.as_string_value())
+    {
+        Some(detail) => format!("{}: {}", e.message(), detail),
+        None => e.message().into(),
+    }
+}
+
+#[graphql_object(
+    context = ConfigDb,
+    description = "This group of queries perform modifications to devices."
 )]
 impl Control {
     #[graphql(description = "Submits `value` to be applied to the device \
@@ -447,112 +1250,249 @@ impl Control {
         name: String,
         value: SettingData,
     ) -> FieldResult<Reading> {
-        match value {
-            SettingData {
-                f_int: None,
-                f_float: None,
-                f_bool: None,
-                f_string: None,
-                f_color: None,
-            } => Err(FieldError::new("no data provided", Value::null())),
+        Control::apply(db, &name, value.into_value()?).await
+    }
 
-            SettingData {
-                f_int: Some(v),
-                f_float: None,
-                f_bool: None,
-                f_string: None,
-                f_color: None,
-            } => Control::perform_setting(db, &name, v)
-                .await
-                .map(Control::int_to_reading(name)),
+    #[graphql(description = "Submits a group of settings, like the ones \
+			     of a scene, in one request. The settings \
+			     don't wait for each other, so a slow driver \
+			     doesn't delay the rest, and the result of \
+			     each is returned, in order. If \
+			     `allOrNothing` is `true`, every setting is \
+			     checked first (the device name, the data, and \
+			     whether the device exists, is settable, and may \
+			     be set by the client) and none are sent if any \
+			     check fails. Drivers can still reject a value, \
+			     and settings that were accepted aren't undone.")]
+    async fn set_devices(
+        #[graphql(context)] db: &ConfigDb,
+        settings: Vec<DeviceSetting>,
+        all_or_nothing: Option<bool>,
+    ) -> Vec<SettingResult> {
+        let settings: Vec<_> = settings
+            .into_iter()
+            .map(|s| (s.name, s.value.into_value()))
+            .collect();
+
+        if all_or_nothing.unwrap_or(false) {
+            let problems = Control::check_settings(db, &settings).await;
+
+            if problems.iter().any(Option::is_some) {
+                return settings
+                    .into_iter()
+                    .zip(problems)
+                    .map(|((device, _), problem)| SettingResult {
+                        device,
+                        reading: None,
+                        error: Some(problem.unwrap_or_else(|| {
+                            "not sent because another setting failed".into()
+                        })),
+                    })
+                    .collect();
+            }
+        }
 
-            SettingData {
-                f_int: None,
-                f_float: Some(v),
-                f_bool: None,
-                f_string: None,
-                f_color: None,
-            } => Control::perform_setting(db, &name, v)
-                .await
-                .map(Control::flt_to_reading(name)),
+        join_all(settings.into_iter().map(|(device, value)| async move {
+            let result = match value {
+                Ok(value) => Control::apply(db, &device, value).await,
+                Err(e) => Err(e),
+            };
 
-            SettingData {
-                f_int: None,
-                f_float: None,
-                f_bool: Some(v),
-                f_string: None,
-                f_color: None,
-            } => Control::perform_setting(db, &name, v)
-                .await
-                .map(Control::bool_to_reading(name)),
+            match result {
+                Ok(reading) => SettingResult {
+                    device,
+                    reading: Some(reading),
+                    error: None,
+                },
+                Err(e) => SettingResult {
+                    device,
+                    reading: None,
+                    error: Some(describe(&e)),
+                },
+            }
+        }))
+        .await
+    }
 
-            SettingData {
-                f_int: None,
-                f_float: None,
-                f_bool: None,
-                f_string: Some(v),
-                f_color: None,
-            } => Control::perform_setting(db, &name, v)
-                .await
-                .map(Control::str_to_reading(name)),
+    #[graphql(description = "Changes the descriptive information of a \
+			     device. Arguments that aren't provided are left \
+			     alone. An empty string removes the field. The \
+			     back-end saves the changes with the device's \
+			     information, so they're kept when its driver \
+			     restarts. Returns the updated device \
+			     information.")]
+    async fn set_device_meta(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "The name of the device.")] name: String,
+        #[graphql(description = "The engineering units of the device's \
+				 value.")]
+        units: Option<String>,
+        #[graphql(description = "A one-line description of the device.")]
+        summary: Option<String>,
+        #[graphql(description = "A longer description of the device.")]
+        detail: Option<String>,
+        #[graphql(description = "Where the device is located.")]
+        location: Option<String>,
+    ) -> FieldResult<DeviceInfo> {
+        let dev = name.parse::<device::Name>().map_err(|_| {
+            FieldError::new("badly formed device name", Value::null())
+        })?;
+
+        if !db.access.allows_admin() {
+            return Err(FieldError::new(
+                "not authorized to edit device information",
+                Value::null(),
+            ));
+        }
 
-            SettingData {
-                f_int: None,
-                f_float: None,
-                f_bool: None,
-                f_string: None,
-                f_color: Some(v),
-            } => match v[..] {
-                [r, g, b] => {
-                    if let (Ok(r), Ok(g), Ok(b)) =
-                        (u8::try_from(r), u8::try_from(g), u8::try_from(b))
-                    {
-                        Control::perform_setting(
-                            db,
-                            &name,
-                            palette::LinSrgba::<u8>::new(r, g, b, 255),
-                        )
-                        .await
-                        .map(Control::color_to_reading(name))
-                    } else {
-                        Err(FieldError::new(
-                            "color component is out of range",
-                            Value::null(),
-                        ))
-                    }
-                }
-                [r, g, b, a] => {
-                    if let (Ok(r), Ok(g), Ok(b), Ok(a)) = (
-                        u8::try_from(r),
-                        u8::try_from(g),
-                        u8::try_from(b),
-                        u8::try_from(a),
-                    ) {
-                        Control::perform_setting(
-                            db,
-                            &name,
-                            palette::LinSrgba::<u8>::new(r, g, b, a),
-                        )
-                        .await
-                        .map(Control::color_to_reading(name))
-                    } else {
-                        Err(FieldError::new(
-                            "color component is out of range",
-                            Value::null(),
-                        ))
-                    }
-                }
-                _ => Err(FieldError::new(
-                    "color values have three or four components",
-                    Value::null(),
-                )),
-            },
+        let update = client::MetaUpdate {
+            units,
+            summary,
+            detail,
+            location,
+        };
 
-            SettingData { .. } => Err(FieldError::new(
-                "must only specify one item of data",
+        db.client
+            .set_device_meta(dev.clone(), update)
+            .await
+            .map_err(|e| FieldError::new(e, Value::null()))?;
+
+        db.client
+            .get_device_info(Some(name))
+            .await
+            .ok()
+            .and_then(|v| v.into_iter().find(|e| e.name == dev))
+            .map(|e| DeviceInfo::new(db, &e))
+            .ok_or_else(|| FieldError::new("device not found", Value::null()))
+    }
+
+    #[graphql(description = "Reads the configuration file and updates the \
+			     logic blocks to match its `[[logic]]` \
+			     sections. Only blocks that were added, removed, \
+			     or changed are affected. Drivers aren't \
+			     reloaded. If the configuration has an error, \
+			     the running blocks are left alone.")]
+    async fn reload_logic(
+        #[graphql(context)] db: &ConfigDb,
+    ) -> FieldResult<LogicReload> {
+        if !db.access.allows_admin() {
+            return Err(FieldError::new(
+                "not authorized to reload logic blocks",
                 Value::null(),
-            )),
+            ));
         }
+
+        let cfg = crate::config::reload()
+            .await
+            .map_err(|e| FieldError::new(e, Value::null()))?;
+
+        db.logic
+            .reload(cfg.logic)
+            .await
+            .map(|summary| LogicReload {
+                started: summary.started,
+                stopped: summary.stopped,
+                unchanged: summary.unchanged,
+            })
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Stops the driver instance using `prefix`. Its \
+			     devices stay registered but aren't updated \
+			     until the instance is started again with \
+			     `startDriver`. Reloading the configuration \
+			     starts it again, too, if it's still in the \
+			     file.")]
+    async fn stop_driver(
+        #[graphql(context)] db: &ConfigDb,
+        prefix: String,
+    ) -> FieldResult<bool> {
+        let prefix = Control::instance_prefix(db, &prefix)?;
+
+        db.instances
+            .stop(prefix)
+            .await
+            .map(|_| true)
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Starts the driver instance using `prefix` \
+			     after it was stopped by `stopDriver`.")]
+    async fn start_driver(
+        #[graphql(context)] db: &ConfigDb,
+        prefix: String,
+    ) -> FieldResult<bool> {
+        let prefix = Control::instance_prefix(db, &prefix)?;
+
+        db.instances
+            .start(prefix)
+            .await
+            .map(|_| true)
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Stops the driver instance using `prefix` and \
+			     starts it again with the same configuration.")]
+    async fn restart_driver(
+        #[graphql(context)] db: &ConfigDb,
+        prefix: String,
+    ) -> FieldResult<bool> {
+        let prefix = Control::instance_prefix(db, &prefix)?;
+
+        db.instances
+            .restart(prefix)
+            .await
+            .map(|_| true)
+            .map_err(|e| FieldError::new(e, Value::null()))
+    }
+
+    #[graphql(description = "Starts a new instance of a driver, without \
+			     editing the configuration file. The instance \
+			     runs until the configuration is reloaded, so \
+			     add it to the file to keep it.")]
+    async fn add_driver(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "The name of the driver.")] name: String,
+        #[graphql(description = "The prefix of the instance's devices. It \
+				 can't already be used.")]
+        prefix: String,
+        #[graphql(description = "The instance's parameters, written as the \
+				 body of a TOML table (e.g. \
+				 `addr = \"10.0.0.31\"`.)")]
+        cfg: Option<String>,
+        #[graphql(description = "The number of readings to keep for each \
+				 device.")]
+        max_history: Option<i32>,
+    ) -> FieldResult<bool> {
+        let prefix = Control::instance_prefix(db, &prefix)?;
+        let cfg = cfg
+            .map(|v| v.parse::<driver::DriverConfig>())
+            .transpose()
+            .map_err(|e| {
+                FieldError::new(
+                    format!("bad driver configuration -- {}", e),
+                    Value::null(),
+                )
+            })?;
+        let max_history =
+            max_history.map(usize::try_from).transpose().map_err(|_| {
+                FieldError::new("maxHistory can't be negative", Value::null())
+            })?;
+
+        db.instances
+            .add(crate::config::Driver {
+                name,
+                prefix,
+                max_history,
+                max_age: None,
+                log_level: None,
+                cfg,
+                simulate: None,
+                raw_cfg: None,
+            })
+            .await
+            .map(|_| true)
+            .map_err(|e| FieldError::new(e, Value::null()))
     }
 }
 
@@ -593,6 +1533,27 @@ struct Reading {
     color_value: Option<Vec<i32>>,
 }
 
+#[derive(GraphQLObject)]
+#[graphql(description = "Summarizes the readings of a device over a slice \
+			 of time. The statistics are computed from \
+			 integer, float, and boolean readings (booleans \
+			 count as 0 and 1.) For other types of devices, \
+			 they are `null`.")]
+struct HistoryBucket {
+    #[graphql(description = "The start of the slice of time.")]
+    start: DateTime<Utc>,
+    #[graphql(description = "The number of readings in the slice.")]
+    count: i32,
+    #[graphql(description = "The smallest value in the slice.")]
+    min: Option<f64>,
+    #[graphql(description = "The largest value in the slice.")]
+    max: Option<f64>,
+    #[graphql(description = "The average of the values in the slice.")]
+    avg: Option<f64>,
+    #[graphql(description = "The last reading in the slice.")]
+    last: Reading,
+}
+
 impl From<&device::Reading> for Reading {
     fn from(value: &device::Reading) -> Self {
         match &value.value {
@@ -713,17 +1674,58 @@ impl Subscription {
         #[graphql(context)] db: &ConfigDb,
         device: String,
         range: Option<DateRange>,
+        #[graphql(description = "If provided, updates are sent at most \
+				 once every `minInterval` seconds. When \
+				 updates arrive faster, the latest one is \
+				 sent once the interval has passed.")]
+        min_interval: Option<f64>,
+        #[graphql(description = "If `true`, updates that don't change \
+				 the device's value aren't sent.")]
+        on_change_only: Option<bool>,
     ) -> device::DataStream<FieldResult<Reading>> {
         use tokio_stream::StreamExt;
 
         if let Ok(name) = device.parse::<device::Name>() {
+            if !db.access.allows(config::Operation::Monitor, &device) {
+                let stream = tokio_stream::once(Err(FieldError::new(
+                    "not authorized to monitor device",
+                    Value::null(),
+                )));
+
+                return Box::pin(stream)
+                    as device::DataStream<FieldResult<Reading>>;
+            }
+
             info!("setting monitor for '{}'", &name);
 
             let start = range.as_ref().and_then(|v| v.start);
             let end = range.as_ref().and_then(|v| v.end);
 
-            if let Ok(rx) = db.1.monitor_device(name.clone(), start, end).await
+            let min_interval = match min_interval
+                .map(Duration::try_from_secs_f64)
+                .unwrap_or(Ok(Duration::ZERO))
             {
+                Ok(v) => v,
+                Err(_) => {
+                    let stream = tokio_stream::once(Err(FieldError::new(
+                        "minInterval must be a positive number of seconds",
+                        Value::null(),
+                    )));
+
+                    return Box::pin(stream)
+                        as device::DataStream<FieldResult<Reading>>;
+                }
+            };
+            let on_change_only = on_change_only.unwrap_or(false);
+
+            if let Ok(rx) =
+                db.client.monitor_device(name.clone(), start, end).await
+            {
+                let rx = if min_interval.is_zero() && !on_change_only {
+                    rx
+                } else {
+                    throttle::throttle(rx, min_interval, on_change_only)
+                };
                 let stream = StreamExt::map(rx, Subscription::xlat(device));
 
                 Box::pin(stream) as device::DataStream<FieldResult<Reading>>
@@ -744,6 +1746,150 @@ impl Subscription {
             Box::pin(stream) as device::DataStream<FieldResult<Reading>>
         }
     }
+
+    #[graphql(description = "Sets up one connection to receive the updates \
+			     of several devices. The devices are the ones \
+			     named in `devices` plus the ones whose names \
+			     match `pattern`. Each reply holds the name of \
+			     its device. Like `monitorDevice`, the stream \
+			     starts with the latest value of each device.")]
+    async fn monitor_devices(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "The names of the devices to monitor.")]
+        devices: Option<Vec<String>>,
+        #[graphql(description = "A pattern, using the grammar of the \
+				 `deviceInfo` query, selecting devices to \
+				 monitor. Devices the client isn't \
+				 authorized to monitor are skipped.")]
+        pattern: Option<String>,
+    ) -> device::DataStream<FieldResult<Reading>> {
+        use tokio_stream::StreamExt;
+
+        let failed = |msg| {
+            Box::pin(tokio_stream::once(Err(FieldError::new(
+                msg,
+                Value::null(),
+            )))) as device::DataStream<FieldResult<Reading>>
+        };
+        let mut names: Vec<device::Name> = vec![];
+
+        for device in devices.unwrap_or_default() {
+            let Ok(name) = device.parse::<device::Name>() else {
+                return failed("badly formed device name");
+            };
+
+            if !db.access.allows(config::Operation::Monitor, &device) {
+                return failed("not authorized to monitor device");
+            }
+            names.push(name)
+        }
+
+        if pattern.is_some() {
+            let Ok(found) = db.client.get_device_info(pattern).await else {
+                return failed("error looking-up device");
+            };
+
+            names.extend(found.into_iter().map(|e| e.name).filter(|name| {
+                db.access
+                    .allows(config::Operation::Monitor, &name.to_string())
+            }))
+        }
+
+        names.sort_by_cached_key(|n| n.to_string());
+        names.dedup();
+
+        if names.is_empty() {
+            return failed("no devices to monitor");
+        }
+
+        info!("setting monitor for {} devices", names.len());
+
+        if let Ok(rx) = db.client.monitor_devices(names).await {
+            Box::pin(StreamExt::map(rx, |(name, reading)| {
+                Subscription::xlat(name.to_string())(reading)
+            })) as device::DataStream<FieldResult<Reading>>
+        } else {
+            failed("device not found")
+        }
+    }
+
+    #[graphql(description = "Reports devices as drivers register them, so \
+			     clients can pick up devices added while \
+			     `drmemd` is running, like ones found by drivers \
+			     that discover hardware. Only devices the client \
+			     is authorized to monitor are sent. A driver \
+			     instance that restarts registers its devices \
+			     again, so they're sent again.")]
+    async fn new_devices(
+        #[graphql(context)] db: &ConfigDb,
+        #[graphql(description = "A pattern, using the grammar of the \
+				 `deviceInfo` query. If provided, only \
+				 matching devices are sent.")]
+        pattern: Option<String>,
+    ) -> device::DataStream<FieldResult<DeviceInfo>> {
+        use crate::events::{self, Kind};
+        use futures::StreamExt;
+        use tokio_stream::wrappers::BroadcastStream;
+
+        let db = db.clone();
+        let pattern = pattern.map(|v| crate::glob::Pattern::create(&v));
+
+        info!("setting monitor for new devices");
+
+        Box::pin(BroadcastStream::new(events::subscribe()).filter_map(
+            move |ev| {
+                let db = db.clone();
+                let name = ev
+                    .ok()
+                    .filter(|ev| ev.kind == Kind::DeviceRegistered)
+                    .map(|ev| ev.source)
+                    .filter(|name| {
+                        pattern.as_ref().is_none_or(|p| p.matches(name))
+                            && db
+                                .access
+                                .allows(config::Operation::Monitor, name)
+                    });
+
+                async move {
+                    let name = name?;
+                    let devs =
+                        db.client.get_device_info(Some(name.clone())).await;
+
+                    devs.ok()?
+                        .iter()
+                        .find(|e| e.name.to_string() == name)
+                        .map(|e| Ok(DeviceInfo::new(&db, e)))
+                }
+            },
+        ))
+    }
+
+    #[graphql(description = "Reports events about `drmemd` itself, like \
+			     drivers failing and restarting, logic blocks \
+			     stopping, and devices being registered. Only \
+			     events occurring after the subscription starts \
+			     are sent. A client that falls too far behind \
+			     misses some events. Requires the `admin` \
+			     operation.")]
+    async fn system_events(
+        #[graphql(context)] db: &ConfigDb,
+    ) -> device::DataStream<FieldResult<SystemEvent>> {
+        use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+        if !db.access.allows_admin() {
+            return Box::pin(tokio_stream::once(Err(FieldError::new(
+                "not authorized to watch system events",
+                Value::null(),
+            ))));
+        }
+
+        info!("setting monitor for system events");
+
+        Box::pin(
+            BroadcastStream::new(crate::events::subscribe())
+                .filter_map(|v| v.ok().map(|e| Ok(SystemEvent::from(e)))),
+        )
+    }
 }
 
 type Schema = RootNode<'static, Config, Control, Subscription>;
@@ -769,22 +1915,241 @@ mod paths {
     }
 }
 
+// The names of the WebSocket protocols used by subscriptions. Current
+// clients, like Apollo and urql, use `graphql-transport-ws`. Older
+// ones use `graphql-ws`.
+
+const TRANSPORT_WS: &str = "graphql-transport-ws";
+const LEGACY_WS: &str = "graphql-ws";
+
+// Picks the subscription protocol from the ones a client requested.
+// Clients that don't ask for one get the legacy protocol, which is
+// the only one `drmemd` used to support, and `None` is returned.
+
+fn ws_protocol(requested: Option<&str>) -> Option<&'static str> {
+    let requested: Vec<&str> = requested
+        .map(|v| v.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+
+    if requested.contains(&TRANSPORT_WS) {
+        Some(TRANSPORT_WS)
+    } else if requested.contains(&LEGACY_WS) {
+        Some(LEGACY_WS)
+    } else {
+        None
+    }
+}
+
+// A message from a client that the guard has accepted. Like the
+// messages `juniper_warp` relays, parsing it is left to the protocol,
+// which reports badly formed messages to the client.
+
+struct Checked(warp::ws::Message);
+
+impl<S: juniper::ScalarValue> TryFrom<Checked>
+    for graphql_ws::ClientMessage<S>
+{
+    type Error = serde_json::Error;
+
+    fn try_from(msg: Checked) -> serde_json::Result<Self> {
+        if msg.0.is_close() {
+            Ok(Self::ConnectionTerminate)
+        } else {
+            serde_json::from_slice(msg.0.as_bytes())
+        }
+    }
+}
+
+impl<S: juniper::ScalarValue> TryFrom<Checked>
+    for graphql_transport_ws::Input<S>
+{
+    type Error = serde_json::Error;
+
+    fn try_from(msg: Checked) -> serde_json::Result<Self> {
+        if msg.0.is_close() {
+            Ok(Self::Close)
+        } else {
+            serde_json::from_slice(msg.0.as_bytes()).map(Self::Message)
+        }
+    }
+}
+
+// Relays the messages of a WebSocket to a subscription connection and
+// back. Queries and mutations can be sent over the WebSocket, too, so
+// every message from the client is checked by the guard. The replies
+// to refused operations are sent straight back to the client.
+
+async fn relay<C, O>(
+    websocket: warp::ws::WebSocket,
+    conn: C,
+    guard: guard::Guard,
+    encode: impl Fn(O) -> result::Result<warp::ws::Message, WsError>,
+) -> result::Result<(), WsError>
+where
+    C: futures::Stream<Item = O>
+        + futures::Sink<Checked, Error = std::convert::Infallible>,
+{
+    use futures::{SinkExt, StreamExt};
+
+    let (mut ws_tx, mut ws_rx) = websocket.split();
+    let (mut s_tx, mut s_rx) = conn.split();
+
+    loop {
+        tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(msg)) => match guard.check_ws(msg) {
+                    Ok(msg) => {
+                        let _ = s_tx.send(Checked(msg)).await;
+                    }
+                    Err(reply) => ws_tx.send(reply).await?,
+                },
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+            out = s_rx.next() => match out {
+                Some(out) => ws_tx.send(encode(out)?).await?,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+type WsError = juniper_warp::subscriptions::Error;
+
+// Serves the subscriptions of a WebSocket connection. If the client
+// doesn't initialize the connection in time, it's dropped.
+
+async fn serve_subscriptions(
+    websocket: warp::ws::WebSocket,
+    transport: bool,
+    ctxt: ConfigDb,
+    cfg: config::Websocket,
+    max_subscriptions: usize,
+    guard: guard::Guard,
+) {
+    use futures::future::{select, Either};
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let keepalive = Duration::from_secs(cfg.keepalive);
+    let init = move |_: juniper::Variables| {
+        let _ = tx.send(());
+
+        futures::future::ready(Ok::<_, std::convert::Infallible>(
+            ConnectionConfig::new(ctxt)
+                .with_keep_alive_interval(keepalive)
+                .with_max_in_flight_operations(max_subscriptions),
+        ))
+    };
+    let schema = ArcSchema(Arc::new(schema()));
+    let conn = std::pin::pin!(async move {
+        if transport {
+            let conn = graphql_transport_ws::Connection::new(schema, init);
+
+            relay(websocket, conn, guard, |out| match out {
+                graphql_transport_ws::Output::Message(msg) => {
+                    serde_json::to_string(&msg)
+                        .map(warp::ws::Message::text)
+                        .map_err(WsError::Serde)
+                }
+                graphql_transport_ws::Output::Close { code, message } => {
+                    Ok(warp::ws::Message::close_with(code, message))
+                }
+            })
+            .await
+        } else {
+            let conn = graphql_ws::Connection::new(schema, init);
+
+            relay(websocket, conn, guard, |msg| {
+                serde_json::to_string(&msg)
+                    .map(warp::ws::Message::text)
+                    .map_err(WsError::Serde)
+            })
+            .await
+        }
+    });
+    let init_timeout = std::pin::pin!(tokio::time::timeout(
+        Duration::from_secs(cfg.init_timeout),
+        rx
+    ));
+
+    match select(conn, init_timeout).await {
+        Either::Left(_) => (),
+        Either::Right((Err(_), _)) => {
+            warn!("client didn't initialize its connection in time")
+        }
+        Either::Right((Ok(_), conn)) => {
+            let _ = conn.await;
+        }
+    }
+}
+
 // Build `warp::Filter`s that define the entire webspace.
 
 fn build_base_site(
+    cfg: &config::Config,
     db: crate::driver::DriverDb,
     cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
+    access: BoxedFilter<(access::Access,)>,
+    log: audit::Log,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    let context = ConfigDb(db, cchan);
-    let ctxt = context.clone();
+    // Each request gets a context holding what its client is allowed
+    // to do.
+
+    let context = access.map(move |access| ConfigDb {
+        drivers: db.clone(),
+        client: cchan.clone(),
+        logic: lchan.clone(),
+        access,
+        audit: log.clone(),
+        instances: ichan.clone(),
+    });
 
-    // Create filter that handles GraphQL queries and mutations.
+    let ws_cfg = cfg.websocket;
+    let limiter = limits::Limiter::new(cfg.limits);
 
-    let state = warp::any().map(move || ctxt.clone());
+    // Create filter that handles GraphQL queries and mutations. Each
+    // request is checked by the guard before it's executed.
+
+    let guard = guard::Guard::new(cfg);
+    let ws_guard = guard.clone();
+    let root_node = Arc::new(schema());
     let query_filter = warp::path(paths::QUERY)
         .and(warp::path::end())
-        .and(warp::post().or(warp::get()).unify())
-        .and(juniper_warp::make_graphql_filter(schema(), state.boxed()));
+        .and(guard::request())
+        .and(context.clone())
+        .and_then(move |req: serde_json::Value, ctxt: ConfigDb| {
+            let guard = guard.clone();
+            let root_node = root_node.clone();
+
+            async move {
+                let req = guard.check(req).and_then(|v| {
+                    serde_json::from_value::<GraphQLBatchRequest>(v)
+                        .map_err(|e| e.to_string())
+                });
+
+                Ok::<_, Rejection>(match req {
+                    Ok(req) => {
+                        let resp = req.execute(&root_node, &ctxt).await;
+                        let status = if resp.is_ok() {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::BAD_REQUEST
+                        };
+
+                        reply::with_status(reply::json(&resp), status)
+                    }
+                    Err(msg) => {
+                        info!("refused request: {}", msg);
+                        reply::with_status(
+                            reply::json(&guard::refusal(&msg)),
+                            StatusCode::BAD_REQUEST,
+                        )
+                    }
+                })
+            }
+        });
 
     // Create filter that handle the interactive GraphQL app. This
     // service is found at the BASE path.
@@ -796,25 +2161,41 @@ fn build_base_site(
             Some(&*paths::FULL_SUBSCRIBE),
         ));
 
-    // Create the filter that handles subscriptions.
+    // Create the filter that handles subscriptions. Operations sent
+    // over the WebSocket are checked by the guard, too.
 
+    let max_subscriptions = limiter.subscriptions();
     let sub_filter = warp::path(paths::SUBSCRIBE)
         .and(warp::path::end())
         .and(warp::ws())
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
         .and(warp::addr::remote())
+        .and(context.clone())
+        .and(limiter.connection())
         .map(
-            move |ws: warp::ws::Ws, addr: Option<std::net::SocketAddr>| {
-                let ctxt = context.clone();
-                let root_node = schema();
+            move |ws: warp::ws::Ws,
+                  requested: Option<String>,
+                  addr: Option<std::net::SocketAddr>,
+                  ctxt: ConfigDb,
+                  conn: limits::Connection| {
+                let protocol = ws_protocol(requested.as_deref());
+                let transport = protocol == Some(TRANSPORT_WS);
+                let guard = ws_guard.clone();
+                let reply = ws.on_upgrade(move |websocket| {
+                    // The connection is counted until the client
+                    // closes it.
 
-                ws.on_upgrade(move |websocket| {
                     async move {
-                        let _ = serve_graphql_ws(
+                        serve_subscriptions(
                             websocket,
-                            Arc::new(root_node),
-                            ConnectionConfig::new(ctxt.clone()),
+                            transport,
+                            ctxt,
+                            ws_cfg,
+                            max_subscriptions,
+                            guard,
                         )
                         .await;
+                        drop(conn)
                     }
                     .instrument(info_span!(
                         "graphql",
@@ -823,51 +2204,109 @@ fn build_base_site(
                             .unwrap_or_else(|| String::from("*unknown*"))
                             .as_str()
                     ))
-                })
+                });
+
+                // The chosen protocol is only returned to clients that
+                // asked for one.
+
+                match protocol {
+                    Some(name) => Box::new(reply::with_header(
+                        reply,
+                        "sec-websocket-protocol",
+                        name,
+                    )) as Box<dyn Reply>,
+                    None => Box::new(reply) as Box<dyn Reply>,
+                }
             },
         );
 
+    // Create the filter that handles the REST interface.
+
+    let rest_filter = rest::routes(context.boxed());
+
     #[cfg(feature = "graphiql")]
-    let site = query_filter.or(graphiql_filter).or(sub_filter);
+    let site = query_filter
+        .or(graphiql_filter)
+        .or(sub_filter)
+        .or(rest_filter);
 
     #[cfg(not(feature = "graphiql"))]
-    let site = query_filter.or(sub_filter);
+    let site = query_filter.or(sub_filter).or(rest_filter);
 
     // Stitch the filters together to build the map of the web
-    // interface.
+    // interface. Clients making too many requests are refused before
+    // they reach the core.
 
     warp::path(paths::BASE)
+        .and(limiter.requests())
         .and(site)
         .with(warp::log("gql::drmem"))
-        .with(
-            warp::cors()
-                .allow_any_origin()
-                .allow_headers(vec![
-                    "content-type",
-                    "Access-Control-Allow-Origin",
-                ])
-                .allow_methods(vec!["OPTIONS", "GET", "POST"])
-                .max_age(Duration::from_secs(3_600)),
-        )
+}
+
+// Builds the CORS policy of the site. Browsers send a "preflight"
+// request before calling the API from a page loaded from another
+// origin. The policy answers it, so it has to be checked before
+// anything that would reject the request, like a missing client id.
+// If the configuration doesn't list the allowed origins, pages from
+// any origin can use the API.
+
+fn cors(cfg: &config::Config) -> warp::filters::cors::Builder {
+    let builder = warp::cors()
+        .allow_headers(vec![
+            "content-type",
+            "x-drmem-client-id",
+            "Access-Control-Allow-Origin",
+        ])
+        .allow_methods(vec!["OPTIONS", "GET", "POST", "PUT"])
+        .max_age(Duration::from_secs(3_600));
+
+    match &cfg.allowed_origins {
+        Some(origins) => {
+            builder.allow_origins(origins.iter().map(String::as_str))
+        }
+        None => builder.allow_any_origin(),
+    }
 }
 
 fn build_site(
+    cfg: &config::Config,
     db: crate::driver::DriverDb,
     cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
 ) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone
 {
-    build_base_site(db, cchan).recover(handle_rejection)
+    let access = warp::any().map(access::Access::unrestricted).boxed();
+    let log = audit::Log::default();
+    let health = health::routes(db.clone(), cchan.clone(), lchan.clone());
+    let smarthome = smarthome::routes(
+        cfg.smarthome.clone(),
+        db.clone(),
+        cchan.clone(),
+        lchan.clone(),
+        ichan.clone(),
+        log.clone(),
+    );
+
+    health
+        .or(smarthome)
+        .or(build_base_site(cfg, db, cchan, lchan, ichan, access, log)
+            .with(cors(cfg)))
+        .recover(handle_rejection)
 }
 
 fn build_secure_site(
-    cfg: &config::Security,
+    cfg: &config::Config,
+    security: &config::Security,
     db: crate::driver::DriverDb,
     cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
 ) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone
 {
     // Clone the table of clients that are allowed in to the system.
 
-    let clients: Arc<[String]> = Arc::clone(&cfg.clients);
+    let clients: Arc<[String]> = Arc::clone(&security.clients);
 
     // Create a closure that validates the client. It takes a client
     // fingerprint as an argument and checks to see if it exists in
@@ -884,12 +2323,38 @@ fn build_secure_site(
         ready(Err(reject::custom(NoAuthorization)))
     };
 
+    // Once a client is authenticated, the access rules determine
+    // what it can do.
+
+    let rules: Arc<[config::Rule]> = Arc::clone(&security.access);
+    let access = warp::header::<String>("X-DrMem-Client-Id")
+        .map(move |client: String| access::Access::for_client(&rules, &client))
+        .boxed();
+
+    // The health checks and the smart-home endpoints are handled
+    // before the client is checked since the probes and voice
+    // assistants using them can't provide client certificates.
+
+    let log = audit::Log::default();
+    let health = health::routes(db.clone(), cchan.clone(), lchan.clone());
+    let smarthome = smarthome::routes(
+        cfg.smarthome.clone(),
+        db.clone(),
+        cchan.clone(),
+        lchan.clone(),
+        ichan.clone(),
+        log.clone(),
+    );
+
     // Build the TLS server.
 
-    warp::header::<String>("X-DrMem-Client-Id")
-        .and_then(check_client)
-        .untuple_one()
-        .and(build_base_site(db, cchan))
+    health
+        .or(smarthome)
+        .or(warp::header::<String>("X-DrMem-Client-Id")
+            .and_then(check_client)
+            .untuple_one()
+            .and(build_base_site(cfg, db, cchan, lchan, ichan, access, log))
+            .with(cors(cfg)))
         .recover(handle_rejection)
 }
 
@@ -921,26 +2386,41 @@ fn cmp_fprints(a: &str, b: &str) -> bool {
 
 // Builds the server object that will handle GraphQL requests. If the
 // configuration contains the `security` key, the server will require
-// TLS connections.
+// TLS connections from known clients. If it only gives a certificate
+// and key, the server uses TLS but accepts any client.
 
 fn build_server(
     cfg: &config::Config,
     db: crate::driver::DriverDb,
     cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
 ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
     if let Some(security) = &cfg.security {
         Box::pin(
-            warp::serve(build_secure_site(security, db, cchan))
+            warp::serve(build_secure_site(
+                cfg, security, db, cchan, lchan, ichan,
+            ))
+            .tls()
+            .key_path(security.key_file.clone())
+            .cert_path(security.cert_file.clone())
+            .bind(cfg.addr),
+        ) as Pin<Box<dyn Future<Output = ()> + Send>>
+    } else if let Some((cert, key)) = cfg.tls() {
+        Box::pin(
+            warp::serve(build_site(cfg, db, cchan, lchan, ichan))
                 .tls()
-                .key_path(security.key_file.clone())
-                .cert_path(security.cert_file.clone())
+                .key_path(key)
+                .cert_path(cert)
                 .bind(cfg.addr),
         ) as Pin<Box<dyn Future<Output = ()> + Send>>
     } else {
-        Box::pin(warp::serve(build_site(db, cchan)).bind(cfg.addr))
-            as Pin<Box<dyn Future<Output = ()> + Send>>
-    }
-}
+        Box::pin(
+            warp::serve(build_site(cfg, db, cchan, lchan, ichan))
+                .bind(cfg.addr),
+        ) as Pin<Box<dyn Future<Output = ()> + Send>>
+    }
+}
 
 async fn handle_rejection(
     err: Rejection,
@@ -949,8 +2429,31 @@ async fn handle_rejection(
         Ok(reply::with_status("NOT_FOUND", StatusCode::NOT_FOUND))
     } else if err.find::<NoAuthorization>().is_some()
         || err.find::<reject::MissingHeader>().is_some()
+        || err.find::<warp::filters::cors::CorsForbidden>().is_some()
     {
         Ok(reply::with_status("FORBIDDEN", StatusCode::FORBIDDEN))
+    } else if err.find::<reject::InvalidQuery>().is_some()
+        || err.find::<guard::BadRequest>().is_some()
+        || err
+            .find::<warp::filters::body::BodyDeserializeError>()
+            .is_some()
+    {
+        Ok(reply::with_status("BAD_REQUEST", StatusCode::BAD_REQUEST))
+    } else if err.find::<limits::TooManyRequests>().is_some() {
+        Ok(reply::with_status(
+            "TOO_MANY_REQUESTS",
+            StatusCode::TOO_MANY_REQUESTS,
+        ))
+    } else if err.find::<reject::PayloadTooLarge>().is_some() {
+        Ok(reply::with_status(
+            "PAYLOAD_TOO_LARGE",
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ))
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        Ok(reply::with_status(
+            "METHOD_NOT_ALLOWED",
+            StatusCode::METHOD_NOT_ALLOWED,
+        ))
     } else {
         error!("unhandled rejection: {:?}", err);
         Ok(reply::with_status(
@@ -960,18 +2463,22 @@ async fn handle_rejection(
     }
 }
 
-pub fn server(
-    cfg: &config::Config,
-    db: crate::driver::DriverDb,
-    cchan: client::RequestChan,
-) -> impl Future<Output = ()> {
-    // Create the background mDNS task.
+// Advertises the GraphQL server as a `_drmem._tcp` service so
+// clients on the LAN can find it. The instance name is the `name` in
+// the configuration.
 
-    let (resp, task) = Responder::with_default_handle().unwrap();
+fn advertise(cfg: &config::Config) {
+    // Create the background mDNS task. Failing to advertise the
+    // service doesn't keep clients that know the address from using
+    // it, so it's only reported.
 
-    // Create the http task.
-
-    let http_task = build_server(cfg, db, cchan);
+    let (resp, task) = match Responder::with_default_handle() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("can't advertise the service with mDNS -- {}", e);
+            return;
+        }
+    };
 
     // Get the boot-time and store it in the mDNS payload.
 
@@ -991,6 +2498,7 @@ pub fn server(
         format!("queries={}", &*paths::FULL_QUERY),
         format!("mutations={}", &*paths::FULL_QUERY),
         format!("subscriptions={}", &*paths::FULL_SUBSCRIBE),
+        format!("tls={}", cfg.tls().is_some()),
     ];
 
     // If the configuration specifies a preferred address to use, add
@@ -1020,14 +2528,110 @@ pub fn server(
     });
 
     std::mem::drop(jh);
+}
+
+pub fn server(
+    cfg: &config::Config,
+    db: crate::driver::DriverDb,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
+) -> impl Future<Output = ()> {
+    if cfg.advertise {
+        advertise(cfg)
+    }
 
-    http_task.instrument(info_span!("http"))
+    build_server(cfg, db, cchan, lchan, ichan).instrument(info_span!("http"))
 }
 
 #[cfg(test)]
 mod test {
     use super::{cmp_fprints, sanitize};
 
+    // Returns a logic manager channel for tests that don't use it.
+
+    fn logic_chan() -> crate::logic::manager::RequestChan {
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+
+        crate::logic::manager::RequestChan::new(tx, Default::default())
+    }
+
+    // Returns a driver instance channel for tests that don't use it.
+
+    fn instance_chan() -> crate::driver::instances::RequestChan {
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+
+        crate::driver::instances::RequestChan::new(tx)
+    }
+
+    #[test]
+    fn test_paginate() {
+        use super::{Config, DeviceOrder};
+        use drmem_api::{client::DevInfoReply, device};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let dev = |name: &str, ts: Option<u64>| DevInfoReply {
+            name: name.parse().unwrap(),
+            units: None,
+            settable: false,
+            total_points: 0,
+            first_point: None,
+            last_point: ts.map(|secs| device::Reading {
+                ts: UNIX_EPOCH + Duration::from_secs(secs),
+                value: device::Value::Bool(true),
+            }),
+            driver: "memory".into(),
+            meta: Default::default(),
+        };
+        let devs = [
+            dev("room:b:state", Some(10)),
+            dev("room:d:state", None),
+            dev("room:a:state", Some(5)),
+            dev("room:c:state", Some(20)),
+        ];
+        let page = |order, after, first| {
+            Config::paginate(devs.iter().collect(), order, after, first).map(
+                |v| v.iter().map(|d| d.name.to_string()).collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            page(DeviceOrder::Name, None, None).unwrap(),
+            [
+                "room:a:state",
+                "room:b:state",
+                "room:c:state",
+                "room:d:state"
+            ]
+        );
+        assert_eq!(
+            page(DeviceOrder::LastUpdate, None, None).unwrap(),
+            [
+                "room:c:state",
+                "room:b:state",
+                "room:a:state",
+                "room:d:state"
+            ]
+        );
+
+        // Pages continue after the cursor.
+
+        assert_eq!(
+            page(DeviceOrder::Name, None, Some(2)).unwrap(),
+            ["room:a:state", "room:b:state"]
+        );
+        assert_eq!(
+            page(DeviceOrder::Name, Some("room:b:state"), Some(2)).unwrap(),
+            ["room:c:state", "room:d:state"]
+        );
+        assert_eq!(
+            page(DeviceOrder::LastUpdate, Some("room:a:state"), Some(2))
+                .unwrap(),
+            ["room:d:state"]
+        );
+        assert!(page(DeviceOrder::Name, Some("room:z:state"), None).is_err());
+    }
+
     #[test]
     fn test_sanitizer() {
         assert_eq!(sanitize("1234".chars()).collect::<String>(), "1234");
@@ -1063,7 +2667,13 @@ mod test {
         use tokio::sync::mpsc;
 
         let (tx, _) = mpsc::channel(100);
-        let filter = build_site(DriverDb::create(), RequestChan::new(tx));
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
 
         #[cfg(not(feature = "graphiql"))]
         {
@@ -1124,12 +2734,284 @@ mod test {
 
         {
             let (tx, _) = mpsc::channel(100);
-            let filter = build_site(DriverDb::create(), RequestChan::new(tx));
+            let filter = build_site(
+                &Default::default(),
+                DriverDb::create(),
+                RequestChan::new(tx),
+                logic_chan(),
+                instance_chan(),
+            );
             let client =
                 warp::test::ws().path("/drmem/s").handshake(filter).await;
 
             assert!(client.is_ok());
         }
+
+        // Clients can ask for the `graphql-transport-ws` protocol.
+
+        {
+            let (tx, _) = mpsc::channel(100);
+            let filter = build_site(
+                &Default::default(),
+                DriverDb::create(),
+                RequestChan::new(tx),
+                logic_chan(),
+                instance_chan(),
+            );
+            let mut client = warp::test::ws()
+                .path("/drmem/s")
+                .header("sec-websocket-protocol", "graphql-transport-ws")
+                .handshake(filter)
+                .await
+                .unwrap();
+
+            client
+                .send(warp::ws::Message::text("{\"type\":\"connection_init\"}"))
+                .await;
+
+            let reply = client.recv().await.unwrap();
+
+            assert_eq!(reply.to_str(), Ok("{\"type\":\"connection_ack\"}"));
+        }
+    }
+
+    #[test]
+    fn test_ws_protocol() {
+        use super::ws_protocol;
+
+        assert_eq!(ws_protocol(None), None);
+        assert_eq!(ws_protocol(Some("mqtt")), None);
+        assert_eq!(ws_protocol(Some("graphql-ws")), Some("graphql-ws"));
+        assert_eq!(
+            ws_protocol(Some("graphql-ws, graphql-transport-ws")),
+            Some("graphql-transport-ws")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        use super::{
+            build_site,
+            config::{Config, Limits},
+        };
+        use crate::driver::DriverDb;
+        use drmem_api::client::RequestChan;
+        use tokio::sync::mpsc;
+
+        let (tx, _) = mpsc::channel(100);
+        let filter = build_site(
+            &Config {
+                limits: Limits {
+                    requests: 1,
+                    burst: 2,
+                    subscriptions: 1,
+                    connections: 1,
+                    ..Limits::default()
+                },
+                ..Config::default()
+            },
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let query = "{
+    \"query\": \"query { driverInfo { name } }\",
+    \"variables\": {},
+    \"operationName\": null
+}";
+
+        // The client can make a burst of requests before it's
+        // refused.
+
+        for _ in 0..2 {
+            let value = warp::test::request()
+                .method("POST")
+                .path("/drmem/q")
+                .body(query)
+                .reply(&filter)
+                .await;
+
+            assert_eq!(value.status(), 200);
+        }
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/drmem/q")
+            .body(query)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(value.status(), 429);
+
+        // Queries nested too deeply are refused.
+
+        let (tx, _) = mpsc::channel(100);
+        let filter = build_site(
+            &Config {
+                limits: Limits {
+                    requests: 0,
+                    depth: 1,
+                    ..Limits::default()
+                },
+                ..Config::default()
+            },
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let value = warp::test::request()
+            .method("POST")
+            .path("/drmem/q")
+            .body(query)
+            .reply(&filter)
+            .await;
+
+        assert_eq!(value.status(), 400);
+        assert!(String::from_utf8_lossy(value.body()).contains("depth"));
+
+        // The limits also apply to operations sent over a WebSocket.
+
+        let mut client = warp::test::ws()
+            .path("/drmem/s")
+            .header("sec-websocket-protocol", "graphql-transport-ws")
+            .handshake(filter)
+            .await
+            .unwrap();
+
+        client
+            .send(warp::ws::Message::text("{\"type\":\"connection_init\"}"))
+            .await;
+        assert!(client.recv().await.is_ok());
+
+        client
+            .send(warp::ws::Message::text(
+                "{\"id\":\"1\",\"type\":\"subscribe\",\"payload\":\
+                 {\"query\":\"query { driverInfo { name } }\"}}",
+            ))
+            .await;
+
+        // Skip the keep-alive messages.
+
+        let reply = loop {
+            let reply = client.recv().await.unwrap();
+            let reply = reply.to_str().unwrap().to_string();
+
+            if reply != "{\"type\":\"pong\"}" {
+                break reply;
+            }
+        };
+
+        assert!(reply.contains("\"type\":\"error\""));
+        assert!(reply.contains("depth"));
+
+        // Only one WebSocket connection can be open.
+
+        let (tx, _) = mpsc::channel(100);
+        let filter = build_site(
+            &Config {
+                limits: Limits {
+                    requests: 0,
+                    connections: 1,
+                    ..Limits::default()
+                },
+                ..Config::default()
+            },
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let client = warp::test::ws()
+            .path("/drmem/s")
+            .handshake(filter.clone())
+            .await;
+
+        assert!(client.is_ok());
+        assert!(warp::test::ws()
+            .path("/drmem/s")
+            .handshake(filter)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        use super::{
+            build_secure_site, build_site,
+            config::{Config, Security},
+        };
+        use crate::driver::DriverDb;
+        use drmem_api::client::RequestChan;
+        use std::{path::Path, sync::Arc};
+        use tokio::sync::mpsc;
+
+        let cfg = Config {
+            allowed_origins: Some(vec!["https://dash.example.com".into()]),
+            ..Config::default()
+        };
+        let preflight = |origin: &str, headers: &str| {
+            warp::test::request()
+                .method("OPTIONS")
+                .path("/drmem/q")
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", headers)
+        };
+
+        let (tx, _) = mpsc::channel(100);
+        let filter = build_site(
+            &cfg,
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+
+        // Preflight requests from allowed origins are accepted.
+
+        let value = preflight("https://dash.example.com", "content-type")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(value.status(), 200);
+        assert_eq!(
+            value.headers()["access-control-allow-origin"],
+            "https://dash.example.com"
+        );
+
+        // Other origins are refused.
+
+        let value = preflight("https://evil.example.com", "content-type")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(value.status(), 403);
+
+        // Browsers don't send the client id in preflight requests, so
+        // secure sites have to accept them without one.
+
+        let security = Security {
+            clients: Arc::new(["00:11:22:33:44:55:66:77".into()]),
+            cert_file: Path::new("").into(),
+            key_file: Path::new("").into(),
+            access: Arc::new([]),
+        };
+        let (tx, _) = mpsc::channel(100);
+        let filter = build_secure_site(
+            &cfg,
+            &security,
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let value = preflight("https://dash.example.com", "x-drmem-client-id")
+            .reply(&filter)
+            .await;
+
+        assert_eq!(value.status(), 200);
     }
 
     #[tokio::test]
@@ -1148,9 +3030,16 @@ mod test {
             ]),
             cert_file: Path::new("").into(),
             key_file: Path::new("").into(),
+            access: Arc::new([]),
         };
-        let filter =
-            build_secure_site(&cfg, DriverDb::create(), RequestChan::new(tx));
+        let filter = build_secure_site(
+            &Default::default(),
+            &cfg,
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
 
         // Test a client that didn't define the Client ID
         // header. Should generate a FORBIDDEN status.
@@ -1246,9 +3135,12 @@ mod test {
         {
             let (tx, _) = mpsc::channel(100);
             let filter = build_secure_site(
+                &Default::default(),
                 &cfg,
                 DriverDb::create(),
                 RequestChan::new(tx),
+                logic_chan(),
+                instance_chan(),
             );
             let client =
                 warp::test::ws().path("/drmem/s").handshake(filter).await;
@@ -1259,9 +3151,12 @@ mod test {
         {
             let (tx, _) = mpsc::channel(100);
             let filter = build_secure_site(
+                &Default::default(),
                 &cfg,
                 DriverDb::create(),
                 RequestChan::new(tx),
+                logic_chan(),
+                instance_chan(),
             );
             let client = warp::test::ws()
                 .header("X-DrMem-Client-Id", "77:66:55:44:33:22:11:00")
@@ -1275,9 +3170,12 @@ mod test {
         {
             let (tx, _) = mpsc::channel(100);
             let filter = build_secure_site(
+                &Default::default(),
                 &cfg,
                 DriverDb::create(),
                 RequestChan::new(tx),
+                logic_chan(),
+                instance_chan(),
             );
             let client = warp::test::ws()
                 .header("X-DrMem-Client-Id", "00:11:22:33:44:55:66:77")
@@ -1288,4 +3186,730 @@ mod test {
             assert!(client.is_ok());
         }
     }
+
+    #[tokio::test]
+    async fn test_site_access() {
+        use super::{
+            build_secure_site,
+            config::{Operation, Rule, Security},
+        };
+        use crate::driver::DriverDb;
+        use drmem_api::client::RequestChan;
+        use std::{path::Path, sync::Arc};
+        use tokio::sync::mpsc;
+
+        let (tx, _) = mpsc::channel(100);
+        let cfg = Security {
+            clients: Arc::new(["00:11".into(), "22:33".into()]),
+            cert_file: Path::new("").into(),
+            key_file: Path::new("").into(),
+            access: Arc::new([Rule {
+                clients: vec!["00:11".into()],
+                devices: vec!["hallway:*".into()],
+                allow: vec![Operation::Monitor],
+            }]),
+        };
+        let filter = build_secure_site(
+            &Default::default(),
+            &cfg,
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let request = |client: &'static str, query: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .header("X-DrMem-Client-Id", client)
+                .path("/drmem/q")
+                .body(format!(
+                    "{{\"query\": \"{}\", \"variables\": {{}}}}",
+                    query
+                ))
+        };
+
+        // Settings and reloads are refused before they reach the
+        // core, which this test doesn't run.
+
+        for client in ["00:11", "22:33"] {
+            let value = request(
+                client,
+                "mutation { setDevice(name: \\\"hallway:light\\\", \
+                 value: { bool: true }) { device } }",
+            )
+            .reply(&filter)
+            .await;
+
+            assert_eq!(value.status(), 200);
+            assert!(String::from_utf8_lossy(value.body())
+                .contains("not authorized to set device"));
+
+            let value = request(client, "mutation { reloadLogic { started } }")
+                .reply(&filter)
+                .await;
+
+            assert!(String::from_utf8_lossy(value.body())
+                .contains("not authorized to reload logic blocks"));
+        }
+    }
+    #[tokio::test]
+    async fn test_set_devices() {
+        use super::build_site;
+        use crate::driver::DriverDb;
+        use drmem_api::{
+            client::{DevInfoReply, Request, RequestChan},
+            Error,
+        };
+        use tokio::sync::mpsc;
+
+        // Acts as the core. "room:fan" rejects every setting and
+        // "room:temp" can't be set.
+
+        let (tx, mut rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                match req {
+                    Request::QueryDeviceInfo { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Ok(["fan", "light", "temp"]
+                            .iter()
+                            .map(|n| DevInfoReply {
+                                name: format!("room:{}", n).parse().unwrap(),
+                                units: None,
+                                settable: *n != "temp",
+                                total_points: 0,
+                                first_point: None,
+                                last_point: None,
+                                driver: "memory".into(),
+                                meta: Default::default(),
+                            })
+                            .collect()));
+                    }
+                    Request::SetDevice {
+                        name,
+                        value,
+                        rpy_chan,
+                    } => {
+                        let _ =
+                            rpy_chan.send(if name.to_string() == "room:fan" {
+                                Err(Error::InvArgument("fan is broken".into()))
+                            } else {
+                                Ok(value)
+                            });
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let request = |query: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/drmem/q")
+                .body(format!(
+                    "{{\"query\": \"{}\", \"variables\": {{}}}}",
+                    query
+                ))
+        };
+
+        // Each setting reports its own result.
+
+        let value = request(
+            "mutation { setDevices(settings: [\
+             { name: \\\"room:light\\\", value: { bool: true } }, \
+             { name: \\\"room:fan\\\", value: { int: 2 } }]) \
+             { device reading { boolValue } error } }",
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains(
+            "{\"device\":\"room:light\",\"reading\":{\"boolValue\":true},\
+             \"error\":null}"
+        ));
+        assert!(body.contains("fan is broken"));
+
+        // In all-or-nothing mode, one bad setting stops all of them.
+
+        let value = request(
+            "mutation { setDevices(allOrNothing: true, settings: [\
+             { name: \\\"room:light\\\", value: { bool: true } }, \
+             { name: \\\"room:temp\\\", value: { flt: 20.0 } }]) \
+             { device reading { boolValue } error } }",
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(!body.contains("\"boolValue\":true"));
+        assert!(body.contains("not sent because another setting failed"));
+        assert!(body.contains("device isn't settable"));
+
+        // The settings that were sent are in the audit trail, even
+        // the ones that failed.
+
+        let value = request(
+            "{ light: settingAudit(device: \\\"room:light\\\") \
+             { requested { boolValue } error } \
+             fan: settingAudit(device: \\\"room:fan\\\") { error } }",
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains(
+            "\"light\":[{\"requested\":{\"boolValue\":true},\"error\":null}]"
+        ));
+        assert!(body.contains(
+            "\"fan\":[{\"error\":\"error making setting: \
+             fan is broken\"}]"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_drivers() {
+        use super::build_site;
+        use crate::driver::{instances, DriverDb};
+        use drmem_api::{client::RequestChan, Error};
+        use tokio::sync::mpsc;
+
+        // Acts as the owner of the driver instances. Only "room" is
+        // running.
+
+        let (tx, mut rx) = mpsc::channel(10);
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                match req {
+                    instances::Request::Stop { prefix, rpy_chan }
+                    | instances::Request::Restart { prefix, rpy_chan } => {
+                        let _ =
+                            rpy_chan.send(if prefix.to_string() == "room" {
+                                Ok(())
+                            } else {
+                                Err(Error::NotFound)
+                            });
+                    }
+                    instances::Request::Start { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Err(Error::InUse));
+                    }
+                    instances::Request::Add { cfg, rpy_chan } => {
+                        let _ = rpy_chan.send(
+                            if cfg.name == "memory"
+                                && cfg.max_history == Some(10)
+                                && cfg
+                                    .cfg
+                                    .is_some_and(|v| v.contains_key("name"))
+                            {
+                                Ok(())
+                            } else {
+                                Err(Error::NotFound)
+                            },
+                        );
+                    }
+                }
+            }
+        });
+
+        let (tx_core, _) = mpsc::channel(1);
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx_core),
+            logic_chan(),
+            instances::RequestChan::new(tx),
+        );
+        let request = |query: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/drmem/q")
+                .body(format!(
+                    "{{\"query\": \"{}\", \"variables\": {{}}}}",
+                    query
+                ))
+        };
+
+        let value = request(
+            "mutation { stop: stopDriver(prefix: \\\"room\\\") \
+             restart: restartDriver(prefix: \\\"room\\\") }",
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains("\"stop\":true"));
+        assert!(body.contains("\"restart\":true"));
+
+        let value = request("mutation { stopDriver(prefix: \\\"porch\\\") }")
+            .reply(&filter)
+            .await;
+
+        assert!(
+            String::from_utf8_lossy(value.body()).contains("item not found")
+        );
+
+        let value = request("mutation { startDriver(prefix: \\\"room\\\") }")
+            .reply(&filter)
+            .await;
+
+        assert!(
+            String::from_utf8_lossy(value.body()).contains("item is in use")
+        );
+
+        let value = request(
+            "mutation { addDriver(name: \\\"memory\\\", \
+             prefix: \\\"hall\\\", maxHistory: 10, \
+             cfg: \\\"name = 'var'\\\") }",
+        )
+        .reply(&filter)
+        .await;
+
+        assert!(String::from_utf8_lossy(value.body())
+            .contains("\"addDriver\":true"));
+
+        // The parameters have to be a TOML table.
+
+        let value = request(
+            "mutation { addDriver(name: \\\"memory\\\", \
+             prefix: \\\"hall\\\", cfg: \\\"name =\\\") }",
+        )
+        .reply(&filter)
+        .await;
+
+        assert!(String::from_utf8_lossy(value.body())
+            .contains("bad driver configuration"));
+    }
+
+    #[tokio::test]
+    async fn test_rest() {
+        use super::build_site;
+        use crate::driver::DriverDb;
+        use drmem_api::{
+            client::{DevInfoReply, HistoryBucket, Request, RequestChan},
+            device,
+        };
+        use std::time::{Duration, UNIX_EPOCH};
+        use tokio::sync::mpsc;
+        use warp::http::StatusCode;
+
+        let reading = device::Reading {
+            ts: UNIX_EPOCH + Duration::from_secs(60),
+            value: device::Value::Int(7),
+        };
+        let (tx, mut rx) = mpsc::channel(100);
+
+        // Acts as the core, which has two devices.
+
+        tokio::spawn({
+            let reading = reading.clone();
+
+            async move {
+                while let Some(req) = rx.recv().await {
+                    match req {
+                        Request::QueryDeviceInfo { rpy_chan, .. } => {
+                            let _ = rpy_chan.send(Ok(["light", "temp"]
+                                .iter()
+                                .map(|n| DevInfoReply {
+                                    name: format!("room:{}", n)
+                                        .parse()
+                                        .unwrap(),
+                                    units: None,
+                                    settable: *n == "light",
+                                    total_points: 1,
+                                    first_point: Some(reading.clone()),
+                                    last_point: Some(reading.clone()),
+                                    driver: "memory".into(),
+                                    meta: Default::default(),
+                                })
+                                .collect()));
+                        }
+                        Request::SetDevice {
+                            value, rpy_chan, ..
+                        } => {
+                            let _ = rpy_chan.send(Ok(value));
+                        }
+                        Request::AggregateHistory {
+                            start, rpy_chan, ..
+                        } => {
+                            let _ = rpy_chan.send(Ok(vec![HistoryBucket {
+                                start,
+                                count: 1,
+                                min: Some(7.0),
+                                max: Some(7.0),
+                                avg: Some(7.0),
+                                last: reading.clone(),
+                            }]));
+                        }
+                        Request::MonitorDevices { names, rpy_chan } => {
+                            let readings: Vec<_> = names
+                                .into_iter()
+                                .map(|n| (n, reading.clone()))
+                                .collect();
+
+                            let _ = rpy_chan.send(Ok(Box::pin(
+                                tokio_stream::iter(readings),
+                            )
+                                as device::DataStream<_>));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        });
+
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let get =
+            |path: &'static str| warp::test::request().method("GET").path(path);
+
+        let value = get("/drmem/devices").reply(&filter).await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert!(body.contains("\"name\":\"room:light\""));
+        assert!(body.contains("\"name\":\"room:temp\""));
+
+        let value = get("/drmem/device/room:temp").reply(&filter).await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert!(body.contains(
+            "\"lastReading\":{\"stamp\":\"1970-01-01T00:01:00+00:00\",\
+             \"value\":7}"
+        ));
+
+        let value = get("/drmem/device/room:fan").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::NOT_FOUND);
+
+        let value =
+            get("/drmem/device/room:temp/history?start=1970-01-01T00:00:00Z\
+             &interval=3600")
+            .reply(&filter)
+            .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert!(body.contains("\"count\":1"));
+
+        // Settings use the same data as the `setDevice` mutation.
+
+        let put = |body: &'static str| {
+            warp::test::request()
+                .method("PUT")
+                .path("/drmem/device/room:light/value")
+                .body(body)
+        };
+
+        let value = put("{\"bool\": true}").reply(&filter).await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert!(body.contains("\"value\":true"));
+
+        let value = put("{\"bool\": true, \"int\": 1}").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::BAD_REQUEST);
+
+        let value = put("{\"float\": 1.0}").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::BAD_REQUEST);
+
+        // Readings can be streamed as server-sent events.
+
+        let value = get("/drmem/events?device=room:light&pattern=room:*")
+            .reply(&filter)
+            .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert_eq!(value.headers()["content-type"], "text/event-stream");
+        assert_eq!(body.matches("event:reading\n").count(), 2);
+        assert!(body.contains(
+            "data:{\"device\":\"room:temp\",\
+             \"stamp\":\"1970-01-01T00:01:00+00:00\",\"value\":7}\n"
+        ));
+
+        let value = get("/drmem/events?device=room").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::BAD_REQUEST);
+
+        let value = get("/drmem/events").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        use super::build_site;
+        use crate::{driver::DriverDb, logic::manager};
+        use drmem_api::client::{Request, RequestChan};
+        use tokio::sync::mpsc;
+        use warp::http::StatusCode;
+
+        let (tx, mut rx) = mpsc::channel(100);
+        let (ltx, mut lrx) = mpsc::channel(100);
+
+        // Acts as the core and as a logic manager with a stopped
+        // block.
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                if let Request::Ping { rpy_chan } = req {
+                    let _ = rpy_chan.send(Ok(()));
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(req) = lrx.recv().await {
+                if let manager::Request::GetHealth { rpy_chan } = req {
+                    let _ = rpy_chan.send(manager::Health {
+                        running: 1,
+                        stopped: vec!["broken".into()],
+                    });
+                }
+            }
+        });
+
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx.clone()),
+            manager::RequestChan::new(ltx, Default::default()),
+            instance_chan(),
+        );
+        let get =
+            |path: &'static str| warp::test::request().method("GET").path(path);
+
+        let value = get("/healthz").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::OK);
+
+        let value = get("/readyz").reply(&filter).await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("\"backend\":\"ok\""));
+        assert!(body.contains("\"stopped\":[\"broken\"]"));
+
+        // A logic manager that isn't answering makes `drmemd`
+        // unhealthy.
+
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let value = get("/healthz").reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_smarthome() {
+        use super::{build_site, config};
+        use crate::driver::DriverDb;
+        use drmem_api::{
+            client::{DevInfoReply, Request, RequestChan},
+            device,
+        };
+        use tokio::sync::mpsc;
+        use warp::http::StatusCode;
+
+        // Acts as the core, which accepts every setting. "room:fan"
+        // isn't given to the assistants.
+
+        let (tx, mut rx) = mpsc::channel(100);
+
+        tokio::spawn(async move {
+            let dev =
+                |n: &str, units: Option<&str>, v: device::Value| DevInfoReply {
+                    name: format!("room:{}", n).parse().unwrap(),
+                    units: units.map(String::from),
+                    settable: n != "temp",
+                    total_points: 1,
+                    first_point: None,
+                    last_point: Some(device::Reading {
+                        ts: std::time::SystemTime::now(),
+                        value: v,
+                    }),
+                    driver: "memory".into(),
+                    meta: Default::default(),
+                };
+
+            while let Some(req) = rx.recv().await {
+                match req {
+                    Request::QueryDeviceInfo { rpy_chan, .. } => {
+                        let _ = rpy_chan.send(Ok(vec![
+                            dev("dimmer", Some("%"), 40.into()),
+                            dev("fan", None, false.into()),
+                            dev("light", None, false.into()),
+                            dev("temp", Some("°F"), 68.0.into()),
+                        ]));
+                    }
+                    Request::SetDevice {
+                        value, rpy_chan, ..
+                    } => {
+                        let _ = rpy_chan.send(Ok(value));
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let smarthome = config::SmartHome {
+            token: "secret".into(),
+            device: [
+                ("room:light", config::DeviceType::Light),
+                ("room:dimmer", config::DeviceType::Dimmer),
+                ("room:temp", config::DeviceType::Thermometer),
+            ]
+            .iter()
+            .map(|(pattern, kind)| config::SmartDevice {
+                pattern: pattern.to_string(),
+                kind: *kind,
+            })
+            .collect(),
+        };
+        let filter = build_site(
+            &config::Config {
+                smarthome: Some(smarthome),
+                ..Default::default()
+            },
+            DriverDb::create(),
+            RequestChan::new(tx.clone()),
+            logic_chan(),
+            instance_chan(),
+        );
+        let google = |token: &str, body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/smarthome/google")
+                .header("authorization", format!("Bearer {}", token))
+                .body(body)
+        };
+        let alexa = |body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/smarthome/alexa")
+                .body(body)
+        };
+
+        // Requests need the token.
+
+        let sync = r#"{"requestId":"1",
+                       "inputs":[{"intent":"action.devices.SYNC"}]}"#;
+        let value = google("wrong", sync).reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::UNAUTHORIZED);
+
+        // Only the mapped devices are shown.
+
+        let value = google("secret", sync).reply(&filter).await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert_eq!(value.status(), StatusCode::OK);
+        assert!(body.contains("\"requestId\":\"1\""));
+        assert!(body.contains("\"name\":{\"name\":\"room light\"}"));
+        assert!(body.contains("action.devices.traits.Brightness"));
+        assert!(body.contains("\"temperatureUnitForUX\":\"F\""));
+        assert!(!body.contains("room:fan"));
+
+        // Settings report the new state. Thermometers can't be set.
+
+        let value = google(
+            "secret",
+            r#"{"requestId":"2","inputs":[{
+                 "intent":"action.devices.EXECUTE",
+                 "payload":{"commands":[{
+                   "devices":[{"id":"room:light"},{"id":"room:temp"}],
+                   "execution":[{
+                     "command":"action.devices.commands.OnOff",
+                     "params":{"on":true}}]}]}}]}"#,
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains(
+            "{\"ids\":[\"room:light\"],\"states\":{\"on\":true,\
+             \"online\":true,\"status\":\"SUCCESS\"},\"status\":\"SUCCESS\"}"
+        ));
+        assert!(body.contains("\"errorCode\":\"functionNotSupported\""));
+
+        // Temperatures are reported in Celsius.
+
+        let value = google(
+            "secret",
+            r#"{"requestId":"3","inputs":[{
+                 "intent":"action.devices.QUERY",
+                 "payload":{"devices":[{"id":"room:temp"},{"id":"x:y"}]}}]}"#,
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains("\"temperatureAmbientCelsius\":20.0"));
+        assert!(body.contains("\"errorCode\":\"deviceNotFound\""));
+
+        // Alexa puts the token in the directive.
+
+        let value = alexa(
+            r#"{"directive":{
+                 "header":{"namespace":"Alexa.Discovery","name":"Discover"},
+                 "payload":{"scope":{"type":"BearerToken","token":"x"}}}}"#,
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains("INVALID_AUTHORIZATION_CREDENTIAL"));
+
+        let value = alexa(
+            r#"{"directive":{
+                 "header":{"namespace":"Alexa.BrightnessController",
+                           "name":"AdjustBrightness",
+                           "correlationToken":"abc"},
+                 "endpoint":{"endpointId":"room:dimmer",
+                             "scope":{"type":"BearerToken",
+                                      "token":"secret"}},
+                 "payload":{"brightnessDelta":25}}}"#,
+        )
+        .reply(&filter)
+        .await;
+        let body = String::from_utf8_lossy(value.body());
+
+        assert!(body.contains("\"correlationToken\":\"abc\""));
+        assert!(body.contains("\"name\":\"Response\""));
+        assert!(body.contains("\"name\":\"brightness\""));
+        assert!(body.contains("\"value\":65"));
+
+        // Without the configuration, the endpoints don't exist.
+
+        let filter = build_site(
+            &Default::default(),
+            DriverDb::create(),
+            RequestChan::new(tx),
+            logic_chan(),
+            instance_chan(),
+        );
+        let value = google("secret", sync).reply(&filter).await;
+
+        assert_eq!(value.status(), StatusCode::NOT_FOUND);
+    }
 }
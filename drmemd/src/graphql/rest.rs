@@ -0,0 +1,377 @@
+// A small REST interface for clients that can't easily use GraphQL,
+// like shell scripts and embedded devices. It's served next to the
+// GraphQL interface and uses the same channels and access rules.
+//
+//     GET /drmem/devices                 information about the devices
+//     GET /drmem/device/{name}           information about one device
+//     PUT /drmem/device/{name}/value     sets a device
+//     GET /drmem/device/{name}/history   summarizes a device's history
+//     GET /drmem/events                  streams readings as server-sent
+//                                        events
+//
+// Replies are JSON. Failed requests return an object with an `error`
+// field describing the problem.
+
+use super::{config, describe, ConfigDb, Control, SettingData};
+use chrono::{DateTime, Utc};
+use drmem_api::{client, device, Error};
+use serde_derive::Deserialize;
+use serde_json::{json, Value as Json};
+use std::{convert::Infallible, time::Duration};
+use tokio_stream::StreamExt;
+use warp::{
+    filters::BoxedFilter, http::StatusCode, reply, sse, Filter, Rejection,
+    Reply,
+};
+
+// The largest body accepted by the `PUT` request.
+
+const MAX_BODY: u64 = 4_096;
+
+// How often an idle event stream sends a comment. It keeps proxies
+// from closing the connection and lets clients notice a dead server.
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+type Response = reply::WithStatus<reply::Json>;
+
+#[derive(Deserialize)]
+struct DevicesQuery {
+    pattern: Option<String>,
+}
+
+// `device` holds a comma-separated list of device names.
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    device: Option<String>,
+    pattern: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    start: String,
+    end: Option<String>,
+    interval: u64,
+}
+
+fn respond(status: StatusCode, body: Json) -> Response {
+    reply::with_status(reply::json(&body), status)
+}
+
+fn failed(status: StatusCode, msg: impl std::fmt::Display) -> Response {
+    respond(status, json!({ "error": msg.to_string() }))
+}
+
+// Picks the HTTP status that best describes an error from the core.
+
+fn status_of(e: &Error) -> StatusCode {
+    match e {
+        Error::NotFound => StatusCode::NOT_FOUND,
+        Error::InvArgument(_) | Error::TypeError | Error::ParseError(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Converts a value to JSON. Colors are arrays holding their red,
+// green, blue, and alpha components.
+
+fn value_to_json(v: &device::Value) -> Json {
+    match v {
+        device::Value::Bool(v) => json!(v),
+        device::Value::Int(v) => json!(v),
+        device::Value::Flt(v) => json!(v),
+        device::Value::Str(v) => json!(v.as_ref()),
+        device::Value::Color(v) => json!([v.red, v.green, v.blue, v.alpha]),
+    }
+}
+
+fn reading_to_json(r: &device::Reading) -> Json {
+    json!({
+        "stamp": DateTime::<Utc>::from(r.ts).to_rfc3339(),
+        "value": value_to_json(&r.value),
+    })
+}
+
+fn info_to_json(e: &client::DevInfoReply) -> Json {
+    json!({
+        "name": e.name.to_string(),
+        "units": e.units,
+        "settable": e.settable,
+        "driver": e.driver.as_ref(),
+        "summary": e.meta.summary,
+        "detail": e.meta.detail,
+        "location": e.meta.location,
+        "totalPoints": e.total_points,
+        "lastReading": e.last_point.as_ref().map(reading_to_json),
+    })
+}
+
+fn bucket_to_json(b: &client::HistoryBucket) -> Json {
+    json!({
+        "start": b.start.to_rfc3339(),
+        "count": b.count,
+        "min": b.min,
+        "max": b.max,
+        "avg": b.avg,
+        "last": reading_to_json(&b.last),
+    })
+}
+
+fn parse_time(v: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(v)
+        .ok()
+        .map(|v| v.with_timezone(&Utc))
+}
+
+async fn get_devices(
+    query: DevicesQuery,
+    db: ConfigDb,
+) -> Result<Response, Infallible> {
+    Ok(match db.client.get_device_info(query.pattern).await {
+        Ok(devs) => respond(
+            StatusCode::OK,
+            devs.iter()
+                .filter(|e| {
+                    db.access
+                        .allows(config::Operation::Monitor, &e.name.to_string())
+                })
+                .map(info_to_json)
+                .collect(),
+        ),
+        Err(e) => failed(status_of(&e), e),
+    })
+}
+
+async fn get_device(
+    device: String,
+    db: ConfigDb,
+) -> Result<Response, Infallible> {
+    if device.parse::<device::Name>().is_err() {
+        return Ok(failed(StatusCode::BAD_REQUEST, "badly formed device name"));
+    }
+
+    if !db.access.allows(config::Operation::Monitor, &device) {
+        return Ok(failed(
+            StatusCode::FORBIDDEN,
+            "not authorized to monitor device",
+        ));
+    }
+
+    Ok(
+        match db.client.get_device_info(Some(device.clone())).await {
+            Ok(devs) => {
+                match devs.iter().find(|e| e.name.to_string() == device) {
+                    Some(e) => respond(StatusCode::OK, info_to_json(e)),
+                    None => failed(StatusCode::NOT_FOUND, "device not found"),
+                }
+            }
+            Err(e) => failed(status_of(&e), e),
+        },
+    )
+}
+
+async fn put_value(
+    device: String,
+    data: SettingData,
+    db: ConfigDb,
+) -> Result<Response, Infallible> {
+    if device.parse::<device::Name>().is_err() {
+        return Ok(failed(StatusCode::BAD_REQUEST, "badly formed device name"));
+    }
+
+    if !db.access.allows(config::Operation::Set, &device) {
+        return Ok(failed(
+            StatusCode::FORBIDDEN,
+            "not authorized to set device",
+        ));
+    }
+
+    let value = match data.into_value() {
+        Ok(v) => v,
+        Err(e) => return Ok(failed(StatusCode::BAD_REQUEST, describe(&e))),
+    };
+
+    if Control::latest_reading(&db, &device).await.is_none() {
+        return Ok(failed(StatusCode::NOT_FOUND, "device not found"));
+    }
+
+    Ok(match Control::set_value(&db, &device, value).await {
+        Ok(value) => respond(
+            StatusCode::OK,
+            reading_to_json(&device::Reading {
+                ts: crate::clock::now(),
+                value,
+            }),
+        ),
+        Err(e) => failed(StatusCode::BAD_REQUEST, describe(&e)),
+    })
+}
+
+async fn get_history(
+    device: String,
+    query: HistoryQuery,
+    db: ConfigDb,
+) -> Result<Response, Infallible> {
+    let Ok(name) = device.parse::<device::Name>() else {
+        return Ok(failed(StatusCode::BAD_REQUEST, "badly formed device name"));
+    };
+
+    if !db.access.allows(config::Operation::Monitor, &device) {
+        return Ok(failed(
+            StatusCode::FORBIDDEN,
+            "not authorized to monitor device",
+        ));
+    }
+
+    let (Some(start), Some(end)) = (
+        parse_time(&query.start),
+        query.end.as_deref().map_or(Some(Utc::now()), parse_time),
+    ) else {
+        return Ok(failed(
+            StatusCode::BAD_REQUEST,
+            "times must be in RFC 3339 format",
+        ));
+    };
+
+    if query.interval == 0 {
+        return Ok(failed(
+            StatusCode::BAD_REQUEST,
+            "interval must be positive",
+        ));
+    }
+
+    let interval = Duration::from_secs(query.interval);
+
+    Ok(
+        match db
+            .client
+            .aggregate_history(name, start, end, interval)
+            .await
+        {
+            Ok(buckets) => respond(
+                StatusCode::OK,
+                buckets.iter().map(bucket_to_json).collect(),
+            ),
+            Err(e) => failed(status_of(&e), e),
+        },
+    )
+}
+
+// Streams the readings of the devices named in the query, and the
+// ones matching its pattern, as server-sent events. Like the
+// `monitorDevices` subscription, the stream starts with the latest
+// reading of each device.
+
+async fn get_events(
+    query: EventsQuery,
+    db: ConfigDb,
+) -> Result<Box<dyn Reply>, Infallible> {
+    let mut names: Vec<device::Name> = vec![];
+
+    for device in query.device.iter().flat_map(|v| v.split(',')) {
+        let Ok(name) = device.trim().parse::<device::Name>() else {
+            return Ok(Box::new(failed(
+                StatusCode::BAD_REQUEST,
+                "badly formed device name",
+            )));
+        };
+
+        if !db
+            .access
+            .allows(config::Operation::Monitor, &name.to_string())
+        {
+            return Ok(Box::new(failed(
+                StatusCode::FORBIDDEN,
+                "not authorized to monitor device",
+            )));
+        }
+        names.push(name)
+    }
+
+    if query.pattern.is_some() {
+        match db.client.get_device_info(query.pattern).await {
+            Ok(found) => {
+                names.extend(found.into_iter().map(|e| e.name).filter(|name| {
+                    db.access
+                        .allows(config::Operation::Monitor, &name.to_string())
+                }))
+            }
+            Err(e) => return Ok(Box::new(failed(status_of(&e), e))),
+        }
+    }
+
+    names.sort_by_cached_key(|n| n.to_string());
+    names.dedup();
+
+    if names.is_empty() {
+        return Ok(Box::new(failed(
+            StatusCode::NOT_FOUND,
+            "no devices to monitor",
+        )));
+    }
+
+    Ok(match db.client.monitor_devices(names).await {
+        Ok(rx) => {
+            let events = rx.map(|(name, reading)| {
+                let mut data = reading_to_json(&reading);
+
+                data["device"] = json!(name.to_string());
+                Ok::<_, Infallible>(
+                    sse::Event::default()
+                        .event("reading")
+                        .data(data.to_string()),
+                )
+            });
+
+            Box::new(sse::reply(
+                sse::keep_alive().interval(KEEP_ALIVE).stream(events),
+            ))
+        }
+        Err(e) => Box::new(failed(status_of(&e), e)),
+    })
+}
+
+// Builds the filter that handles the REST requests. `context` gives
+// each request the channels and the access of its client.
+
+pub fn routes(
+    context: BoxedFilter<(ConfigDb,)>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let devices = warp::path!("devices")
+        .and(warp::get())
+        .and(warp::query::<DevicesQuery>())
+        .and(context.clone())
+        .and_then(get_devices);
+    let device = warp::path!("device" / String)
+        .and(warp::get())
+        .and(context.clone())
+        .and_then(get_device);
+    let value = warp::path!("device" / String / "value")
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY))
+        .and(warp::body::json::<SettingData>())
+        .and(context.clone())
+        .and_then(put_value);
+    let history = warp::path!("device" / String / "history")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(context.clone())
+        .and_then(get_history);
+    let events = warp::path!("events")
+        .and(warp::get())
+        .and(warp::query::<EventsQuery>())
+        .and(context)
+        .and_then(get_events);
+
+    devices
+        .or(device)
+        .unify()
+        .or(value)
+        .unify()
+        .or(history)
+        .unify()
+        .or(events)
+}
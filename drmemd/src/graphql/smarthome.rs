@@ -0,0 +1,711 @@
+// Lets voice assistants control devices, so "turn on the porch light"
+// works without another home-automation system in the middle. The
+// endpoints implement the fulfillment side of the assistants'
+// smart-home integrations:
+//
+//     POST /smarthome/google   Google Home's SYNC, QUERY, EXECUTE, and
+//                              DISCONNECT intents
+//     POST /smarthome/alexa    Alexa's Discover and ReportState
+//                              directives, plus the power and
+//                              brightness controllers
+//
+// Only devices matching a `[[graphql.smarthome.device]]` pattern are
+// shown to the assistants. Google sends the configured token as a
+// bearer token and Alexa puts it in the directive's scope. Settings
+// are made like those of other clients: they take control of the
+// device away from logic blocks and are added to the audit trail.
+
+use super::{access, config, describe, ConfigDb, Control};
+use crate::glob;
+use chrono::{DateTime, Utc};
+use drmem_api::{device, Result};
+use serde_json::{json, Value as Json};
+use std::convert::Infallible;
+use subtle::ConstantTimeEq;
+use warp::{http::StatusCode, reply, Filter, Rejection};
+
+// The largest request accepted from an assistant.
+
+const MAX_BODY: u64 = 65_536;
+
+// The name used in the audit trail for settings made by assistants.
+
+const CLIENT: &str = "smarthome";
+
+type Response = reply::WithStatus<reply::Json>;
+
+fn respond(status: StatusCode, body: Json) -> Response {
+    reply::with_status(reply::json(&body), status)
+}
+
+// The commands the assistants can send.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Power(bool),
+    Brightness(u8),
+}
+
+// Why a command failed. Each assistant has its own codes for these.
+
+#[derive(Debug, PartialEq)]
+enum Failure {
+    Unauthorized,
+    NotFound,
+    NotSupported,
+    Failed(String),
+}
+
+impl Failure {
+    fn google_code(&self) -> &'static str {
+        match self {
+            Failure::Unauthorized => "authFailure",
+            Failure::NotFound => "deviceNotFound",
+            Failure::NotSupported => "functionNotSupported",
+            Failure::Failed(_) => "hardError",
+        }
+    }
+
+    fn alexa_type(&self) -> &'static str {
+        match self {
+            Failure::Unauthorized => "INVALID_AUTHORIZATION_CREDENTIAL",
+            Failure::NotFound => "NO_SUCH_ENDPOINT",
+            Failure::NotSupported => "INVALID_DIRECTIVE",
+            Failure::Failed(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Failure::Unauthorized => "bad token".into(),
+            Failure::NotFound => "device not found".into(),
+            Failure::NotSupported => "not supported by the device".into(),
+            Failure::Failed(e) => e.clone(),
+        }
+    }
+}
+
+// A device shown to the assistants.
+
+#[derive(Debug)]
+struct Device {
+    name: String,
+    kind: config::DeviceType,
+    settable: bool,
+    units: Option<String>,
+    last: Option<device::Reading>,
+}
+
+impl Device {
+    // The name used to speak of the device. Separators are replaced
+    // with spaces, so "porch:light" is the "porch light".
+
+    fn spoken_name(&self) -> String {
+        self.name.replace([':', '-', '_'], " ")
+    }
+
+    fn value(&self) -> Option<&device::Value> {
+        self.last.as_ref().map(|v| &v.value)
+    }
+
+    fn is_on(&self) -> bool {
+        match self.value() {
+            Some(device::Value::Bool(v)) => *v,
+            Some(device::Value::Int(v)) => *v > 0,
+            Some(device::Value::Flt(v)) => *v > 0.0,
+            _ => false,
+        }
+    }
+
+    fn brightness(&self) -> u8 {
+        match self.value() {
+            Some(device::Value::Int(v)) => (*v).clamp(0, 100) as u8,
+            Some(device::Value::Flt(v)) => v.round().clamp(0.0, 100.0) as u8,
+            _ => 0,
+        }
+    }
+
+    fn fahrenheit(&self) -> bool {
+        self.units
+            .as_deref()
+            .is_some_and(|u| matches!(u.trim_start_matches('°'), "F" | "degF"))
+    }
+
+    // Returns the temperature in the device's units.
+
+    fn temperature(&self) -> Option<f64> {
+        match self.value()? {
+            device::Value::Int(v) => Some(*v as f64),
+            device::Value::Flt(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn celsius(&self) -> Option<f64> {
+        self.temperature().map(|t| {
+            if self.fahrenheit() {
+                (t - 32.0) * 5.0 / 9.0
+            } else {
+                t
+            }
+        })
+    }
+
+    // Dimmers are set with the type of value they report.
+
+    fn level(&self, v: u8) -> device::Value {
+        match self.value() {
+            Some(device::Value::Flt(_)) => (v as f64).into(),
+            _ => (v as i32).into(),
+        }
+    }
+
+    // Converts a command to the value sent to the device. Turning on
+    // a dimmer that's off sets it to full brightness.
+
+    fn setting(&self, cmd: Command) -> Option<device::Value> {
+        use config::DeviceType::*;
+
+        match (self.kind, cmd) {
+            (Thermometer, _) => None,
+            (Dimmer, Command::Power(false)) => Some(self.level(0)),
+            (Dimmer, Command::Power(true)) if self.is_on() => {
+                Some(self.level(self.brightness()))
+            }
+            (Dimmer, Command::Power(true)) => Some(self.level(100)),
+            (Dimmer, Command::Brightness(v)) => Some(self.level(v)),
+            (_, Command::Power(v)) => Some(v.into()),
+            (_, Command::Brightness(_)) => None,
+        }
+    }
+}
+
+// Finds the devices shown to the assistants. A device has the type
+// of the first pattern it matches.
+
+async fn devices(
+    db: &ConfigDb,
+    cfg: &config::SmartHome,
+) -> Result<Vec<Device>> {
+    let patterns: Vec<_> = cfg
+        .device
+        .iter()
+        .map(|v| (glob::Pattern::create(&v.pattern), v.kind))
+        .collect();
+
+    Ok(db
+        .client
+        .get_device_info(None)
+        .await?
+        .into_iter()
+        .filter_map(|e| {
+            let name = e.name.to_string();
+            let kind = patterns.iter().find(|(p, _)| p.matches(&name))?.1;
+
+            Some(Device {
+                name,
+                kind,
+                settable: e.settable,
+                units: e.units,
+                last: e.last_point,
+            })
+        })
+        .collect())
+}
+
+// Sends a command to a device. The device's reading is updated with
+// the value the driver used.
+
+async fn execute(
+    db: &ConfigDb,
+    dev: &mut Device,
+    cmd: Command,
+) -> std::result::Result<(), Failure> {
+    let value = dev
+        .setting(cmd)
+        .filter(|_| dev.settable)
+        .ok_or(Failure::NotSupported)?;
+    let value = Control::set_value(db, &dev.name, value)
+        .await
+        .map_err(|e| Failure::Failed(describe(&e)))?;
+
+    dev.last = Some(device::Reading {
+        ts: crate::clock::now(),
+        value,
+    });
+    Ok(())
+}
+
+fn google_device(dev: &Device) -> Json {
+    use config::DeviceType::*;
+
+    let (ty, traits) = match dev.kind {
+        Light => ("LIGHT", &["OnOff"][..]),
+        Switch => ("SWITCH", &["OnOff"][..]),
+        Outlet => ("OUTLET", &["OnOff"][..]),
+        Fan => ("FAN", &["OnOff"][..]),
+        Dimmer => ("LIGHT", &["OnOff", "Brightness"][..]),
+        Thermometer => ("SENSOR", &["TemperatureControl"][..]),
+    };
+    let attributes = match dev.kind {
+        Thermometer => json!({
+            "queryOnlyTemperatureControl": true,
+            "temperatureUnitForUX": if dev.fahrenheit() { "F" } else { "C" },
+            "temperatureRange": {
+                "minThresholdCelsius": -100,
+                "maxThresholdCelsius": 100,
+            },
+        }),
+        _ => json!({ "queryOnlyOnOff": !dev.settable }),
+    };
+
+    json!({
+        "id": dev.name,
+        "type": format!("action.devices.types.{}", ty),
+        "traits": traits
+            .iter()
+            .map(|t| format!("action.devices.traits.{}", t))
+            .collect::<Vec<_>>(),
+        "name": { "name": dev.spoken_name() },
+        "willReportState": false,
+        "attributes": attributes,
+        "deviceInfo": { "manufacturer": "DrMem" },
+    })
+}
+
+fn google_state(dev: &Device) -> Json {
+    let mut state = json!({
+        "online": dev.last.is_some(),
+        "status": "SUCCESS",
+    });
+
+    match dev.kind {
+        config::DeviceType::Thermometer => {
+            if let Some(t) = dev.celsius() {
+                state["temperatureAmbientCelsius"] = json!(t)
+            }
+        }
+        config::DeviceType::Dimmer => {
+            state["on"] = json!(dev.is_on());
+            state["brightness"] = json!(dev.brightness())
+        }
+        _ => state["on"] = json!(dev.is_on()),
+    }
+    state
+}
+
+fn google_command(step: &Json) -> Option<Command> {
+    let params = &step["params"];
+
+    match step["command"].as_str()? {
+        "action.devices.commands.OnOff" => {
+            Some(Command::Power(params["on"].as_bool()?))
+        }
+        "action.devices.commands.BrightnessAbsolute" => Some(
+            Command::Brightness(params["brightness"].as_u64()?.min(100) as u8),
+        ),
+        _ => None,
+    }
+}
+
+// Runs the steps of an execution on a device and returns its new
+// state.
+
+async fn google_run(
+    db: &ConfigDb,
+    dev: &mut Device,
+    steps: &Json,
+) -> std::result::Result<Json, Failure> {
+    for step in steps.as_array().into_iter().flatten() {
+        let cmd = google_command(step).ok_or(Failure::NotSupported)?;
+
+        execute(db, dev, cmd).await?
+    }
+    Ok(google_state(dev))
+}
+
+async fn google_execute(
+    db: &ConfigDb,
+    mut devs: Vec<Device>,
+    payload: &Json,
+) -> Json {
+    let mut results = vec![];
+
+    for cmd in payload["commands"].as_array().into_iter().flatten() {
+        let ids = cmd["devices"].as_array().into_iter().flatten();
+
+        for id in ids.filter_map(|v| v["id"].as_str()) {
+            let result = match devs.iter_mut().find(|d| d.name == id) {
+                Some(dev) => google_run(db, dev, &cmd["execution"]).await,
+                None => Err(Failure::NotFound),
+            };
+
+            results.push(match result {
+                Ok(states) => json!({
+                    "ids": [id],
+                    "status": "SUCCESS",
+                    "states": states,
+                }),
+                Err(e) => json!({
+                    "ids": [id],
+                    "status": "ERROR",
+                    "errorCode": e.google_code(),
+                }),
+            })
+        }
+    }
+    json!({ "commands": results })
+}
+
+fn google_query(devs: &[Device], payload: &Json) -> Json {
+    let ids = payload["devices"].as_array().into_iter().flatten();
+
+    json!({
+        "devices": ids
+            .filter_map(|v| v["id"].as_str())
+            .map(|id| {
+                let state = match devs.iter().find(|d| d.name == id) {
+                    Some(dev) => google_state(dev),
+                    None => json!({
+                        "online": false,
+                        "status": "ERROR",
+                        "errorCode": Failure::NotFound.google_code(),
+                    }),
+                };
+
+                (id.to_string(), state)
+            })
+            .collect::<serde_json::Map<_, _>>(),
+    })
+}
+
+// Returns whether a request holds the configured token. The
+// comparison takes the same time however much of the token matches,
+// so it doesn't give away how much of a guess was right.
+
+fn authorized(token: Option<&str>, cfg: &config::SmartHome) -> bool {
+    token.is_some_and(|v| v.as_bytes().ct_eq(cfg.token.as_bytes()).into())
+}
+
+async fn google(
+    auth: Option<String>,
+    body: Json,
+    cfg: config::SmartHome,
+    db: ConfigDb,
+) -> std::result::Result<Response, Infallible> {
+    if !authorized(
+        auth.as_deref().and_then(|v| v.strip_prefix("Bearer ")),
+        &cfg,
+    ) {
+        return Ok(respond(
+            StatusCode::UNAUTHORIZED,
+            json!({ "errorCode": Failure::Unauthorized.google_code() }),
+        ));
+    }
+
+    let input = &body["inputs"][0];
+    let devs = match devices(&db, &cfg).await {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(respond(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": e.to_string() }),
+            ))
+        }
+    };
+    let payload = match input["intent"].as_str() {
+        Some("action.devices.SYNC") => json!({
+            "agentUserId": CLIENT,
+            "devices": devs.iter().map(google_device).collect::<Vec<_>>(),
+        }),
+        Some("action.devices.QUERY") => google_query(&devs, &input["payload"]),
+        Some("action.devices.EXECUTE") => {
+            google_execute(&db, devs, &input["payload"]).await
+        }
+        Some("action.devices.DISCONNECT") => {
+            return Ok(respond(StatusCode::OK, json!({})))
+        }
+        _ => {
+            return Ok(respond(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "unknown intent" }),
+            ))
+        }
+    };
+
+    Ok(respond(
+        StatusCode::OK,
+        json!({ "requestId": body["requestId"], "payload": payload }),
+    ))
+}
+
+fn alexa_header(directive: &Json, namespace: &str, name: &str) -> Json {
+    let mut header = json!({
+        "namespace": namespace,
+        "name": name,
+        "payloadVersion": "3",
+        "messageId": format!("{:032x}", rand::random::<u128>()),
+    });
+
+    if let Some(token) = directive["header"]["correlationToken"].as_str() {
+        header["correlationToken"] = json!(token)
+    }
+    header
+}
+
+fn alexa_error(directive: &Json, failure: Failure) -> Response {
+    respond(
+        StatusCode::OK,
+        json!({
+            "event": {
+                "header": alexa_header(directive, "Alexa", "ErrorResponse"),
+                "endpoint": {
+                    "endpointId": directive["endpoint"]["endpointId"],
+                },
+                "payload": {
+                    "type": failure.alexa_type(),
+                    "message": failure.message(),
+                },
+            },
+        }),
+    )
+}
+
+// Returns the interfaces of a device and the property each one
+// reports.
+
+fn alexa_interfaces(dev: &Device) -> &'static [(&'static str, &'static str)] {
+    match dev.kind {
+        config::DeviceType::Thermometer => {
+            &[("Alexa.TemperatureSensor", "temperature")]
+        }
+        config::DeviceType::Dimmer => &[
+            ("Alexa.PowerController", "powerState"),
+            ("Alexa.BrightnessController", "brightness"),
+        ],
+        _ => &[("Alexa.PowerController", "powerState")],
+    }
+}
+
+fn alexa_endpoint(dev: &Device) -> Json {
+    use config::DeviceType::*;
+
+    let category = match dev.kind {
+        Light | Dimmer => "LIGHT",
+        Switch => "SWITCH",
+        Outlet => "SMARTPLUG",
+        Fan => "FAN",
+        Thermometer => "TEMPERATURE_SENSOR",
+    };
+    let mut caps = vec![json!({
+        "type": "AlexaInterface",
+        "interface": "Alexa",
+        "version": "3",
+    })];
+
+    caps.extend(alexa_interfaces(dev).iter().map(|(interface, prop)| {
+        json!({
+            "type": "AlexaInterface",
+            "interface": interface,
+            "version": "3",
+            "properties": {
+                "supported": [{ "name": prop }],
+                "retrievable": true,
+                "proactivelyReported": false,
+            },
+        })
+    }));
+
+    json!({
+        "endpointId": dev.name,
+        "manufacturerName": "DrMem",
+        "friendlyName": dev.spoken_name(),
+        "description": format!("DrMem device {}", dev.name),
+        "displayCategories": [category],
+        "capabilities": caps,
+    })
+}
+
+fn alexa_properties(dev: &Device) -> Vec<Json> {
+    let Some(last) = &dev.last else {
+        return vec![];
+    };
+    let stamp = DateTime::<Utc>::from(last.ts).to_rfc3339();
+
+    alexa_interfaces(dev)
+        .iter()
+        .filter_map(|(interface, prop)| {
+            let value = match *prop {
+                "powerState" => json!(if dev.is_on() { "ON" } else { "OFF" }),
+                "brightness" => json!(dev.brightness()),
+                _ => json!({
+                    "value": dev.temperature()?,
+                    "scale": if dev.fahrenheit() {
+                        "FAHRENHEIT"
+                    } else {
+                        "CELSIUS"
+                    },
+                }),
+            };
+
+            Some(json!({
+                "namespace": interface,
+                "name": prop,
+                "value": value,
+                "timeOfSample": stamp,
+                "uncertaintyInMilliseconds": 0,
+            }))
+        })
+        .collect()
+}
+
+fn alexa_command(dev: &Device, directive: &Json) -> Option<Option<Command>> {
+    let header = &directive["header"];
+    let payload = &directive["payload"];
+
+    match (header["namespace"].as_str()?, header["name"].as_str()?) {
+        ("Alexa", "ReportState") => Some(None),
+        ("Alexa.PowerController", "TurnOn") => Some(Some(Command::Power(true))),
+        ("Alexa.PowerController", "TurnOff") => {
+            Some(Some(Command::Power(false)))
+        }
+        ("Alexa.BrightnessController", "SetBrightness") => {
+            let v = payload["brightness"].as_u64()?.min(100);
+
+            Some(Some(Command::Brightness(v as u8)))
+        }
+        ("Alexa.BrightnessController", "AdjustBrightness") => {
+            let v = dev.brightness() as i64
+                + payload["brightnessDelta"].as_i64()?;
+
+            Some(Some(Command::Brightness(v.clamp(0, 100) as u8)))
+        }
+        _ => None,
+    }
+}
+
+async fn alexa(
+    body: Json,
+    cfg: config::SmartHome,
+    db: ConfigDb,
+) -> std::result::Result<Response, Infallible> {
+    let directive = &body["directive"];
+    let header = &directive["header"];
+
+    // Discovery directives keep the token in the payload. The others
+    // keep it with the endpoint.
+
+    let token = directive["endpoint"]["scope"]["token"]
+        .as_str()
+        .or(directive["payload"]["scope"]["token"].as_str());
+
+    if !authorized(token, &cfg) {
+        return Ok(alexa_error(directive, Failure::Unauthorized));
+    }
+
+    let devs = match devices(&db, &cfg).await {
+        Ok(v) => v,
+        Err(e) => {
+            return Ok(alexa_error(directive, Failure::Failed(e.to_string())))
+        }
+    };
+
+    if header["namespace"] == "Alexa.Discovery" && header["name"] == "Discover"
+    {
+        return Ok(respond(
+            StatusCode::OK,
+            json!({
+                "event": {
+                    "header": alexa_header(
+                        directive,
+                        "Alexa.Discovery",
+                        "Discover.Response"
+                    ),
+                    "payload": {
+                        "endpoints": devs
+                            .iter()
+                            .map(alexa_endpoint)
+                            .collect::<Vec<_>>(),
+                    },
+                },
+            }),
+        ));
+    }
+
+    let id = directive["endpoint"]["endpointId"].as_str();
+    let Some(mut dev) = devs.into_iter().find(|d| Some(d.name.as_str()) == id)
+    else {
+        return Ok(alexa_error(directive, Failure::NotFound));
+    };
+    let Some(cmd) = alexa_command(&dev, directive) else {
+        return Ok(alexa_error(directive, Failure::NotSupported));
+    };
+    let name = match cmd {
+        Some(cmd) => {
+            if let Err(e) = execute(&db, &mut dev, cmd).await {
+                return Ok(alexa_error(directive, e));
+            }
+            "Response"
+        }
+        None => "StateReport",
+    };
+
+    Ok(respond(
+        StatusCode::OK,
+        json!({
+            "event": {
+                "header": alexa_header(directive, "Alexa", name),
+                "endpoint": { "endpointId": dev.name },
+                "payload": {},
+            },
+            "context": { "properties": alexa_properties(&dev) },
+        }),
+    ))
+}
+
+// Builds the filter that handles the smart-home requests. If the
+// configuration doesn't have a `smarthome` section, the endpoints
+// don't exist.
+
+pub fn routes(
+    cfg: Option<config::SmartHome>,
+    db: crate::driver::DriverDb,
+    cchan: drmem_api::client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    ichan: crate::driver::instances::RequestChan,
+    log: super::audit::Log,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let context = warp::any().and_then(move || {
+        let cfg = cfg.clone();
+        let db = ConfigDb {
+            drivers: db.clone(),
+            client: cchan.clone(),
+            logic: lchan.clone(),
+            access: access::Access::for_client(&[], CLIENT),
+            audit: log.clone(),
+            instances: ichan.clone(),
+        };
+
+        async move {
+            match cfg {
+                Some(cfg) => Ok((cfg, db)),
+                None => Err(warp::reject::not_found()),
+            }
+        }
+    });
+    let body = warp::body::content_length_limit(MAX_BODY)
+        .and(warp::body::json::<Json>());
+    let google = warp::path!("smarthome" / "google")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(body)
+        .and(context.clone())
+        .and_then(|auth, body, (cfg, db)| google(auth, body, cfg, db));
+    let alexa = warp::path!("smarthome" / "alexa")
+        .and(warp::post())
+        .and(body)
+        .and(context)
+        .and_then(|body, (cfg, db)| alexa(body, cfg, db));
+
+    google.or(alexa).unify()
+}
@@ -0,0 +1,189 @@
+// Down-samples the readings of a device for clients that can't use
+// every update. Readings are spaced using their timestamps, so
+// history is thinned the same way as live updates. When a reading
+// arrives too soon after the last one sent, it's held and sent once
+// the interval has passed, unless a newer reading replaces it. This
+// way, a client always ends up with the device's latest value.
+
+use drmem_api::device;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tokio_stream::StreamExt;
+
+struct State {
+    input: device::DataStream<device::Reading>,
+    sent: Option<device::Reading>,
+    pending: Option<device::Reading>,
+    deadline: Instant,
+}
+
+impl State {
+    // Decides what to do with a new reading. Returns the reading if
+    // it should be sent now.
+
+    fn accept(
+        &mut self,
+        reading: device::Reading,
+        min_interval: Duration,
+        on_change_only: bool,
+    ) -> Option<device::Reading> {
+        // A value that matches the one the client has isn't a change,
+        // and cancels any held change.
+
+        if on_change_only
+            && self.sent.as_ref().is_some_and(|s| s.value == reading.value)
+        {
+            self.pending = None;
+            return None;
+        }
+
+        let wait = self.sent.as_ref().and_then(|s| {
+            (s.ts + min_interval).duration_since(reading.ts).ok()
+        });
+
+        match wait {
+            Some(wait) if !wait.is_zero() => {
+                if self.pending.is_none() {
+                    self.deadline = Instant::now() + wait
+                }
+                self.pending = Some(reading);
+                None
+            }
+            _ => {
+                self.pending = None;
+                self.sent = Some(reading.clone());
+                Some(reading)
+            }
+        }
+    }
+
+    // Sends the held reading.
+
+    fn release(&mut self) -> Option<device::Reading> {
+        let reading = self.pending.take()?;
+
+        self.sent = Some(reading.clone());
+        Some(reading)
+    }
+}
+
+pub fn throttle(
+    input: device::DataStream<device::Reading>,
+    min_interval: Duration,
+    on_change_only: bool,
+) -> device::DataStream<device::Reading> {
+    let state = State {
+        input,
+        sent: None,
+        pending: None,
+        deadline: Instant::now(),
+    };
+
+    Box::pin(futures::stream::unfold(state, move |mut st| async move {
+        loop {
+            let reading = tokio::select! {
+                item = st.input.next() => match item {
+                    Some(r) => st.accept(r, min_interval, on_change_only),
+                    None => break st.release().map(|r| (r, st)),
+                },
+                _ = sleep_until(st.deadline), if st.pending.is_some() => {
+                    st.release()
+                }
+            };
+
+            if let Some(reading) = reading {
+                break Some((reading, st));
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    fn reading(ms: u64, v: i32) -> device::Reading {
+        device::Reading {
+            ts: UNIX_EPOCH + Duration::from_millis(ms),
+            value: v.into(),
+        }
+    }
+
+    fn values(v: &[device::Reading]) -> Vec<device::Value> {
+        v.iter().map(|r| r.value.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        // Readings closer than the interval are dropped, unless
+        // they're the last one.
+
+        let input = tokio_stream::iter(
+            [
+                (0, 1),
+                (100, 2),
+                (200, 3),
+                (1000, 4),
+                (1500, 5),
+                (2500, 6),
+                (2600, 7),
+            ]
+            .map(|(ms, v)| reading(ms, v)),
+        );
+        let out: Vec<_> =
+            throttle(Box::pin(input), Duration::from_secs(1), false)
+                .collect()
+                .await;
+
+        assert_eq!(values(&out), [1, 4, 6, 7].map(device::Value::from));
+
+        // Repeated values are dropped.
+
+        let input = tokio_stream::iter(
+            [(0, 1), (10, 1), (20, 2), (30, 2), (40, 1)]
+                .map(|(ms, v)| reading(ms, v)),
+        );
+        let out: Vec<_> = throttle(Box::pin(input), Duration::ZERO, true)
+            .collect()
+            .await;
+
+        assert_eq!(values(&out), [1, 2, 1].map(device::Value::from));
+    }
+
+    #[tokio::test]
+    async fn test_held_reading() {
+        let (tx, rx) = mpsc::channel(10);
+        let mut out = throttle(
+            Box::pin(ReceiverStream::new(rx)),
+            Duration::from_millis(100),
+            false,
+        );
+        let now = SystemTime::now();
+
+        tx.send(device::Reading {
+            ts: now,
+            value: 1.into(),
+        })
+        .await
+        .unwrap();
+        assert_eq!(out.next().await.unwrap().value, 1.into());
+
+        // A reading that's too early is sent once the interval has
+        // passed, even though no other readings arrive.
+
+        tx.send(device::Reading {
+            ts: now + Duration::from_millis(10),
+            value: 2.into(),
+        })
+        .await
+        .unwrap();
+
+        let start = Instant::now();
+
+        assert_eq!(out.next().await.unwrap().value, 2.into());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,23 @@
+use serde_derive::Deserialize;
+use std::net::{Ipv4Addr, SocketAddr};
+
+// The gRPC interface doesn't authenticate its clients, so it only
+// listens on the loopback interface unless configured otherwise.
+
+fn def_address() -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, 50051).into()
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    #[serde(default = "def_address")]
+    pub addr: SocketAddr,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: def_address(),
+        }
+    }
+}
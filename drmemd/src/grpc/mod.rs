@@ -0,0 +1,271 @@
+// Provides a gRPC interface to DrMem. It's meant for other services
+// that integrate with DrMem and would rather use a compact,
+// strongly-typed protocol than GraphQL. The service is defined in
+// `proto/drmem.proto`.
+//
+// The interface doesn't authenticate clients, so, by default, it
+// only accepts connections from the local host.
+
+use drmem_api::{client, device, Error};
+use futures::Future;
+use std::{
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+pub mod config;
+
+// The generated code names the variants of `Value` after the fields
+// of the protobuf definition (`BoolValue`, `IntValue`, ...), which
+// clippy doesn't like.
+
+#[allow(clippy::enum_variant_names)]
+mod proto {
+    tonic::include_proto!("drmem");
+}
+
+use proto::{dr_mem_server, value::Value as Data};
+
+// Converts a timestamp to milliseconds since the epoch, which is how
+// the protobuf definition stores them.
+
+fn to_millis(ts: SystemTime) -> i64 {
+    match ts.duration_since(UNIX_EPOCH) {
+        Ok(v) => v.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+impl From<device::Value> for proto::Value {
+    fn from(v: device::Value) -> Self {
+        proto::Value {
+            value: Some(match v {
+                device::Value::Bool(v) => Data::BoolValue(v),
+                device::Value::Int(v) => Data::IntValue(v),
+                device::Value::Flt(v) => Data::FloatValue(v),
+                device::Value::Str(v) => Data::StringValue(v.to_string()),
+                device::Value::Color(v) => Data::ColorValue(proto::Color {
+                    red: v.red.into(),
+                    green: v.green.into(),
+                    blue: v.blue.into(),
+                    alpha: v.alpha.into(),
+                }),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::Value> for device::Value {
+    type Error = Status;
+
+    fn try_from(v: proto::Value) -> Result<Self, Status> {
+        let comp = |c: u32| {
+            u8::try_from(c).map_err(|_| {
+                Status::invalid_argument("color component is out of range")
+            })
+        };
+
+        match v.value {
+            Some(Data::BoolValue(v)) => Ok(v.into()),
+            Some(Data::IntValue(v)) => Ok(v.into()),
+            Some(Data::FloatValue(v)) => Ok(v.into()),
+            Some(Data::StringValue(v)) => Ok(v.into()),
+            Some(Data::ColorValue(c)) => Ok(palette::LinSrgba::<u8>::new(
+                comp(c.red)?,
+                comp(c.green)?,
+                comp(c.blue)?,
+                comp(c.alpha)?,
+            )
+            .into()),
+            None => Err(Status::invalid_argument("no data provided")),
+        }
+    }
+}
+
+impl From<device::Reading> for proto::Reading {
+    fn from(v: device::Reading) -> Self {
+        proto::Reading {
+            stamp_ms: to_millis(v.ts),
+            value: Some(v.value.into()),
+        }
+    }
+}
+
+impl From<client::DevInfoReply> for proto::Device {
+    fn from(v: client::DevInfoReply) -> Self {
+        proto::Device {
+            name: v.name.to_string(),
+            units: v.units,
+            settable: v.settable,
+            driver: v.driver.to_string(),
+            summary: v.meta.summary,
+            detail: v.meta.detail,
+            location: v.meta.location,
+            total_points: v.total_points,
+            last_reading: v.last_point.map(Into::into),
+        }
+    }
+}
+
+// Maps errors from the core to gRPC status codes.
+
+fn to_status(e: Error) -> Status {
+    match e {
+        Error::NotFound => Status::not_found(e.to_string()),
+        Error::InvArgument(_) | Error::TypeError | Error::ParseError(_) => {
+            Status::invalid_argument(e.to_string())
+        }
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+fn parse_name(name: &str) -> Result<device::Name, Status> {
+    name.parse()
+        .map_err(|_| Status::invalid_argument("badly formed device name"))
+}
+
+struct Service {
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+}
+
+type ReadingStream =
+    Pin<Box<dyn Stream<Item = Result<proto::Reading, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl dr_mem_server::DrMem for Service {
+    async fn device_info(
+        &self,
+        request: Request<proto::DeviceInfoRequest>,
+    ) -> Result<Response<proto::DeviceInfoReply>, Status> {
+        let devices = self
+            .cchan
+            .get_device_info(request.into_inner().pattern)
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(proto::DeviceInfoReply {
+            devices: devices.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    type MonitorDeviceStream = ReadingStream;
+
+    async fn monitor_device(
+        &self,
+        request: Request<proto::MonitorRequest>,
+    ) -> Result<Response<Self::MonitorDeviceStream>, Status> {
+        let name = parse_name(&request.into_inner().name)?;
+
+        info!("setting gRPC monitor for '{}'", &name);
+
+        let rx = self
+            .cchan
+            .monitor_device(name, None, None)
+            .await
+            .map_err(to_status)?;
+
+        Ok(Response::new(
+            Box::pin(rx.map(|v| Ok(v.into()))) as ReadingStream
+        ))
+    }
+
+    async fn set_device(
+        &self,
+        request: Request<proto::SetRequest>,
+    ) -> Result<Response<proto::Reading>, Status> {
+        let request = request.into_inner();
+        let name = parse_name(&request.name)?;
+        let value = request
+            .value
+            .ok_or_else(|| Status::invalid_argument("no data provided"))?
+            .try_into()?;
+
+        // Like GraphQL settings, these take control of the device
+        // away from logic blocks with the same, or lower, priority.
+
+        self.lchan.manual_setting(&name);
+
+        let value =
+            self.cchan.set_value(name, value).await.map_err(to_status)?;
+
+        Ok(Response::new(
+            device::Reading {
+                ts: crate::clock::now(),
+                value,
+            }
+            .into(),
+        ))
+    }
+}
+
+// Returns a future that runs the gRPC server.
+
+pub fn server(
+    cfg: &config::Config,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> impl Future<Output = ()> {
+    let addr = cfg.addr;
+    let svc = dr_mem_server::DrMemServer::new(Service { cchan, lchan });
+
+    async move {
+        info!("gRPC server listening on {}", addr);
+
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(svc)
+            .serve(addr)
+            .await
+        {
+            error!("gRPC server failed -- {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn from_millis(ms: i64) -> SystemTime {
+        if ms >= 0 {
+            UNIX_EPOCH + Duration::from_millis(ms as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_millis(ms.unsigned_abs())
+        }
+    }
+
+    #[test]
+    fn test_conversions() {
+        for v in [
+            device::Value::Bool(true),
+            device::Value::Int(-5),
+            device::Value::Flt(2.5),
+            device::Value::Str("hello".into()),
+            device::Value::Color(palette::LinSrgba::new(1, 2, 3, 4)),
+        ] {
+            assert_eq!(
+                device::Value::try_from(proto::Value::from(v.clone())).unwrap(),
+                v
+            );
+        }
+
+        assert!(device::Value::try_from(proto::Value { value: None }).is_err());
+        assert!(device::Value::try_from(proto::Value {
+            value: Some(Data::ColorValue(proto::Color {
+                red: 256,
+                green: 0,
+                blue: 0,
+                alpha: 255,
+            }))
+        })
+        .is_err());
+
+        for ms in [0, 1_700_000_000_123, -1_500] {
+            assert_eq!(to_millis(from_millis(ms)), ms);
+        }
+    }
+}
@@ -0,0 +1,666 @@
+// Describes the accessories shown to HomeKit. The bridge itself is
+// accessory 1. Each configured accessory gets an ID derived from its
+// name, so it stays the same when the configuration is reordered.
+// Accessories are made of services, which are made of
+// characteristics. Apple-defined types are given by their short
+// UUIDs.
+
+use super::config::{self, Kind};
+use drmem_api::device;
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+
+// The latest value of each device used by the bridge.
+
+pub type Values = HashMap<device::Name, device::Value>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Bool,
+    UInt8,
+    Int,
+    Float,
+    String,
+}
+
+impl Format {
+    fn name(&self) -> &'static str {
+        match self {
+            Format::Bool => "bool",
+            Format::UInt8 => "uint8",
+            Format::Int => "int",
+            Format::Float => "float",
+            Format::String => "string",
+        }
+    }
+
+    // The value reported before a device has a reading.
+
+    fn default_value(&self) -> Json {
+        match self {
+            Format::Bool => json!(false),
+            Format::UInt8 | Format::Int => json!(0),
+            Format::Float => json!(0.0),
+            Format::String => json!(""),
+        }
+    }
+}
+
+// How a characteristic's value relates to a device's value.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Conv {
+    // The device's boolean value.
+    Bool,
+    // A brightness, from 0 to 100.
+    Brightness,
+    // On if the brightness is above 0.
+    OnFromBrightness,
+    Celsius,
+    Fahrenheit,
+    // The current heating state: 1 when heating, 0 when off.
+    Heating,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    // A fixed value. Writes are accepted and ignored.
+    Const(Json),
+    Device(device::Name, Conv),
+}
+
+#[derive(Clone, Debug)]
+pub struct Characteristic {
+    pub iid: u64,
+    ty: &'static str,
+    pub format: Format,
+    pub readable: bool,
+    pub writable: bool,
+    pub events: bool,
+    pub source: Source,
+    meta: Json,
+}
+
+impl Characteristic {
+    fn fixed(ty: &'static str, format: Format, value: Json) -> Self {
+        Characteristic {
+            iid: 0,
+            ty,
+            format,
+            readable: true,
+            writable: false,
+            events: false,
+            source: Source::Const(value),
+            meta: json!({}),
+        }
+    }
+
+    fn device(
+        ty: &'static str,
+        format: Format,
+        name: &device::Name,
+        conv: Conv,
+    ) -> Self {
+        Characteristic {
+            iid: 0,
+            ty,
+            format,
+            readable: true,
+            writable: false,
+            events: true,
+            source: Source::Device(name.clone(), conv),
+            meta: json!({}),
+        }
+    }
+
+    fn writable(self) -> Self {
+        Characteristic {
+            writable: true,
+            ..self
+        }
+    }
+
+    fn with_meta(self, meta: Json) -> Self {
+        Characteristic { meta, ..self }
+    }
+
+    // Returns the characteristic's current value.
+
+    pub fn read(&self, values: &Values) -> Json {
+        match &self.source {
+            Source::Const(v) => v.clone(),
+            Source::Device(name, conv) => values
+                .get(name)
+                .and_then(|v| to_json(*conv, v))
+                .unwrap_or_else(|| self.format.default_value()),
+        }
+    }
+
+    fn to_json(&self, values: &Values) -> Json {
+        let mut perms = vec![];
+
+        if self.readable {
+            perms.push("pr")
+        }
+        if self.writable {
+            perms.push("pw")
+        }
+        if self.events {
+            perms.push("ev")
+        }
+
+        let mut v = json!({
+            "iid": self.iid,
+            "type": self.ty,
+            "perms": perms,
+            "format": self.format.name(),
+        });
+
+        if self.readable {
+            v["value"] = self.read(values)
+        }
+        if let (Json::Object(v), Json::Object(meta)) = (&mut v, &self.meta) {
+            v.extend(meta.clone())
+        }
+        v
+    }
+}
+
+// Converts a device value to the value of a characteristic.
+
+fn to_json(conv: Conv, v: &device::Value) -> Option<Json> {
+    let num = match v {
+        device::Value::Int(v) => Some(*v as f64),
+        device::Value::Flt(v) => Some(*v),
+        _ => None,
+    };
+    let round = |v: f64| (v * 10.0).round() / 10.0;
+
+    match (conv, v) {
+        (Conv::Bool, device::Value::Bool(v)) => Some(json!(v)),
+        (Conv::Heating, device::Value::Bool(v)) => Some(json!(*v as u8)),
+        (Conv::Brightness, _) => {
+            num.map(|v| json!(v.round().clamp(0.0, 100.0) as i64))
+        }
+        (Conv::OnFromBrightness, _) => num.map(|v| json!(v > 0.0)),
+        (Conv::Celsius, _) => num.map(|v| json!(round(v))),
+        (Conv::Fahrenheit, _) => {
+            num.map(|v| json!(round((v - 32.0) * 5.0 / 9.0)))
+        }
+        _ => None,
+    }
+}
+
+// Converts a value written by a controller to a device value. Numbers
+// are sent using the type of the device's latest value. Returns
+// `None` if the value isn't valid.
+
+pub fn to_device(
+    conv: Conv,
+    v: &Json,
+    current: Option<&device::Value>,
+) -> Option<device::Value> {
+    // HomeKit controllers sometimes send booleans as numbers.
+
+    let flag = || v.as_bool().or_else(|| v.as_u64().map(|v| v != 0));
+    let num = |v: f64| match current {
+        Some(device::Value::Int(_)) => device::Value::Int(v.round() as i32),
+        _ => device::Value::Flt(v),
+    };
+
+    match conv {
+        Conv::Bool => flag().map(device::Value::Bool),
+        Conv::Brightness => v.as_f64().map(|v| num(v.clamp(0.0, 100.0))),
+
+        // Turning on a dimmer that's already on leaves its
+        // brightness alone.
+        Conv::OnFromBrightness => match flag()? {
+            false => Some(num(0.0)),
+            true => match current {
+                Some(v) if to_json(conv, v) == Some(json!(true)) => {
+                    Some(v.clone())
+                }
+                _ => Some(num(100.0)),
+            },
+        },
+        Conv::Celsius => v.as_f64().map(num),
+        Conv::Fahrenheit => v.as_f64().map(|v| num(v * 9.0 / 5.0 + 32.0)),
+        Conv::Heating => None,
+    }
+}
+
+struct Service {
+    iid: u64,
+    ty: &'static str,
+    chars: Vec<Characteristic>,
+}
+
+pub struct Accessory {
+    pub aid: u64,
+    services: Vec<Service>,
+}
+
+impl Accessory {
+    // Builds an accessory, assigning instance IDs to its services
+    // and characteristics.
+
+    fn new(
+        aid: u64,
+        services: Vec<(&'static str, Vec<Characteristic>)>,
+    ) -> Self {
+        let mut iid = 0;
+        let mut next = || {
+            iid += 1;
+            iid
+        };
+
+        Accessory {
+            aid,
+            services: services
+                .into_iter()
+                .map(|(ty, chars)| Service {
+                    iid: next(),
+                    ty,
+                    chars: chars
+                        .into_iter()
+                        .map(|c| Characteristic { iid: next(), ..c })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_json(&self, values: &Values) -> Json {
+        json!({
+            "aid": self.aid,
+            "services": self.services.iter().map(|s| json!({
+                "iid": s.iid,
+                "type": s.ty,
+                "characteristics": s.chars
+                    .iter()
+                    .map(|c| c.to_json(values))
+                    .collect::<Vec<_>>(),
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+// Builds the Accessory Information service, which every accessory
+// needs.
+
+fn info(
+    name: &str,
+    model: &str,
+    serial: &str,
+) -> (&'static str, Vec<Characteristic>) {
+    (
+        "3E",
+        vec![
+            Characteristic {
+                readable: false,
+                writable: true,
+                ..Characteristic::fixed("14", Format::Bool, json!(null))
+            },
+            Characteristic::fixed("20", Format::String, json!("DrMem")),
+            Characteristic::fixed("21", Format::String, json!(model)),
+            Characteristic::fixed("23", Format::String, json!(name)),
+            Characteristic::fixed("30", Format::String, json!(serial)),
+            Characteristic::fixed(
+                "52",
+                Format::String,
+                json!(env!("CARGO_PKG_VERSION")),
+            ),
+        ],
+    )
+}
+
+fn temperature(name: &device::Name, fahrenheit: bool) -> Characteristic {
+    Characteristic::device(
+        "11",
+        Format::Float,
+        name,
+        if fahrenheit {
+            Conv::Fahrenheit
+        } else {
+            Conv::Celsius
+        },
+    )
+    .with_meta(json!({
+        "unit": "celsius",
+        "minValue": -100,
+        "maxValue": 100,
+        "minStep": 0.1,
+    }))
+}
+
+// Returns the services that show a configured accessory.
+
+fn services(
+    cfg: &config::Accessory,
+) -> Vec<(&'static str, Vec<Characteristic>)> {
+    let dev = &cfg.device;
+
+    match cfg.kind {
+        Kind::Switch => vec![(
+            "49",
+            vec![Characteristic::device("25", Format::Bool, dev, Conv::Bool)
+                .writable()],
+        )],
+        Kind::Dimmer => vec![(
+            "43",
+            vec![
+                Characteristic::device(
+                    "25",
+                    Format::Bool,
+                    dev,
+                    Conv::OnFromBrightness,
+                )
+                .writable(),
+                Characteristic::device("8", Format::Int, dev, Conv::Brightness)
+                    .writable()
+                    .with_meta(json!({
+                        "unit": "percentage",
+                        "minValue": 0,
+                        "maxValue": 100,
+                        "minStep": 1,
+                    })),
+            ],
+        )],
+        Kind::Temperature => {
+            vec![("8A", vec![temperature(dev, cfg.fahrenheit)])]
+        }
+        Kind::Thermostat => {
+            let target = cfg.target.as_ref().unwrap_or(dev);
+            let heating = match &cfg.heating {
+                Some(name) => Characteristic::device(
+                    "F",
+                    Format::UInt8,
+                    name,
+                    Conv::Heating,
+                ),
+                None => Characteristic::fixed("F", Format::UInt8, json!(0)),
+            };
+
+            vec![(
+                "4A",
+                vec![
+                    heating.with_meta(json!({ "validValues": [0, 1] })),
+                    // The thermostats only heat.
+                    Characteristic::fixed("33", Format::UInt8, json!(1))
+                        .writable()
+                        .with_meta(json!({ "validValues": [1] })),
+                    temperature(dev, cfg.fahrenheit),
+                    Characteristic {
+                        ty: "35",
+                        ..temperature(target, cfg.fahrenheit)
+                    }
+                    .writable()
+                    .with_meta(json!({
+                        "unit": "celsius",
+                        "minValue": 10,
+                        "maxValue": 38,
+                        "minStep": 0.1,
+                    })),
+                    Characteristic::fixed(
+                        "36",
+                        Format::UInt8,
+                        json!(cfg.fahrenheit as u8),
+                    )
+                    .writable(),
+                ],
+            )]
+        }
+    }
+}
+
+// A 32-bit FNV-1a hash. Unlike the standard library's hasher, its
+// results don't change between releases of Rust, so it can be used
+// for values that are saved.
+
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0x811c9dc5, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+pub struct Database {
+    accessories: Vec<Accessory>,
+}
+
+impl Database {
+    pub fn new(name: &str, cfg: &[config::Accessory]) -> Self {
+        let mut accessories = vec![Accessory::new(
+            1,
+            vec![
+                info(name, "drmemd", "drmemd"),
+                (
+                    "A2",
+                    vec![Characteristic::fixed(
+                        "37",
+                        Format::String,
+                        json!("1.1.0"),
+                    )],
+                ),
+            ],
+        )];
+
+        for acc in cfg {
+            // Pick an ID that isn't used yet, starting with the hash
+            // of the name.
+
+            let mut aid = fnv1a(acc.name.as_bytes()) as u64;
+
+            while aid < 2 || accessories.iter().any(|a| a.aid == aid) {
+                aid = (aid + 1) & 0xffff_ffff
+            }
+
+            let mut svcs = vec![info(
+                &acc.name,
+                &format!("{:?}", acc.kind),
+                &acc.device.to_string(),
+            )];
+
+            svcs.extend(services(acc));
+            accessories.push(Accessory::new(aid, svcs))
+        }
+
+        Database { accessories }
+    }
+
+    pub fn find(&self, aid: u64, iid: u64) -> Option<&Characteristic> {
+        self.accessories
+            .iter()
+            .find(|a| a.aid == aid)?
+            .services
+            .iter()
+            .flat_map(|s| &s.chars)
+            .find(|c| c.iid == iid)
+    }
+
+    // Returns every characteristic along with the ID of its
+    // accessory.
+
+    pub fn characteristics(
+        &self,
+    ) -> impl Iterator<Item = (u64, &Characteristic)> {
+        self.accessories.iter().flat_map(|a| {
+            a.services
+                .iter()
+                .flat_map(move |s| s.chars.iter().map(move |c| (a.aid, c)))
+        })
+    }
+
+    // Returns the devices used by the accessories.
+
+    pub fn devices(&self) -> Vec<device::Name> {
+        let mut names: Vec<device::Name> = vec![];
+
+        for (_, c) in self.characteristics() {
+            if let Source::Device(name, _) = &c.source {
+                if !names.contains(name) {
+                    names.push(name.clone())
+                }
+            }
+        }
+        names
+    }
+
+    // Returns a hash of the layout of the accessories. Controllers
+    // need to be told when it changes.
+
+    pub fn layout_hash(&self) -> u32 {
+        fnv1a(self.to_json(&Values::new()).to_string().as_bytes())
+    }
+
+    pub fn to_json(&self, values: &Values) -> Json {
+        json!({
+            "accessories": self.accessories
+                .iter()
+                .map(|a| a.to_json(values))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accessory(name: &str, kind: Kind) -> config::Accessory {
+        config::Accessory {
+            name: name.into(),
+            kind,
+            device: "room:dev".parse().unwrap(),
+            target: Some("room:target".parse().unwrap()),
+            heating: None,
+            fahrenheit: false,
+        }
+    }
+
+    #[test]
+    fn test_conversions() {
+        let int = device::Value::Int(40);
+        let flt = device::Value::Flt(40.0);
+
+        assert_eq!(to_json(Conv::Bool, &true.into()), Some(json!(true)));
+        assert_eq!(to_json(Conv::Bool, &int), None);
+        assert_eq!(to_json(Conv::Heating, &true.into()), Some(json!(1)));
+        assert_eq!(to_json(Conv::Brightness, &flt), Some(json!(40)));
+        assert_eq!(
+            to_json(Conv::Brightness, &device::Value::Int(150)),
+            Some(json!(100))
+        );
+        assert_eq!(to_json(Conv::OnFromBrightness, &int), Some(json!(true)));
+        assert_eq!(
+            to_json(Conv::Fahrenheit, &device::Value::Flt(212.0)),
+            Some(json!(100.0))
+        );
+        assert_eq!(to_json(Conv::Celsius, &"hot".into()), None);
+
+        assert_eq!(
+            to_device(Conv::Bool, &json!(1), None),
+            Some(device::Value::Bool(true))
+        );
+        assert_eq!(
+            to_device(Conv::Brightness, &json!(55), Some(&int)),
+            Some(device::Value::Int(55))
+        );
+        assert_eq!(
+            to_device(Conv::Brightness, &json!(55), Some(&flt)),
+            Some(device::Value::Flt(55.0))
+        );
+        assert_eq!(
+            to_device(Conv::OnFromBrightness, &json!(true), Some(&int)),
+            Some(int.clone())
+        );
+        assert_eq!(
+            to_device(
+                Conv::OnFromBrightness,
+                &json!(true),
+                Some(&device::Value::Int(0))
+            ),
+            Some(device::Value::Int(100))
+        );
+        assert_eq!(
+            to_device(Conv::OnFromBrightness, &json!(false), Some(&int)),
+            Some(device::Value::Int(0))
+        );
+        assert_eq!(
+            to_device(Conv::Fahrenheit, &json!(20.0), None),
+            Some(device::Value::Flt(68.0))
+        );
+        assert_eq!(to_device(Conv::Heating, &json!(1), None), None);
+        assert_eq!(to_device(Conv::Celsius, &json!("warm"), None), None);
+    }
+
+    #[test]
+    fn test_database() {
+        let cfg = [
+            accessory("Light", Kind::Switch),
+            accessory("Den", Kind::Thermostat),
+        ];
+        let db = Database::new("DrMem", &cfg);
+        let aid = fnv1a(b"Light") as u64;
+
+        // IDs don't depend on the order of the accessories.
+
+        let other = Database::new("DrMem", &[cfg[1].clone(), cfg[0].clone()]);
+
+        assert!(other.find(aid, 9).is_some());
+        assert_ne!(other.layout_hash(), db.layout_hash());
+
+        // The switch's "On" characteristic follows the first service.
+
+        let on = db.find(aid, 9).unwrap();
+
+        assert_eq!(on.ty, "25");
+        assert!(on.writable && on.events);
+        assert_eq!(
+            on.source,
+            Source::Device("room:dev".parse().unwrap(), Conv::Bool)
+        );
+
+        let mut values = Values::new();
+
+        assert_eq!(on.read(&values), json!(false));
+        values.insert("room:dev".parse().unwrap(), true.into());
+        assert_eq!(on.read(&values), json!(true));
+
+        assert_eq!(
+            db.devices(),
+            vec![
+                "room:dev".parse::<device::Name>().unwrap(),
+                "room:target".parse().unwrap()
+            ]
+        );
+
+        let v = db.to_json(&values);
+        let light = v["accessories"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|a| a["aid"] == json!(aid))
+            .unwrap();
+
+        assert_eq!(light["services"][0]["type"], json!("3E"));
+        assert_eq!(
+            light["services"][0]["characteristics"][3]["value"],
+            json!("Light")
+        );
+        assert_eq!(
+            light["services"][1]["characteristics"][0],
+            json!({
+                "iid": 9,
+                "type": "25",
+                "perms": ["pr", "pw", "ev"],
+                "format": "bool",
+                "value": true,
+            })
+        );
+
+        // Write-only characteristics don't have a value.
+
+        assert!(light["services"][0]["characteristics"][0]
+            .get("value")
+            .is_none());
+    }
+}
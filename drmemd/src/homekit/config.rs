@@ -0,0 +1,164 @@
+use drmem_api::{device, Error, Result};
+use serde_derive::Deserialize;
+use std::path::PathBuf;
+
+fn def_name() -> String {
+    String::from("DrMem")
+}
+
+fn def_port() -> u16 {
+    51826
+}
+
+fn def_state_file() -> PathBuf {
+    PathBuf::from("homekit.json")
+}
+
+// The HomeKit services a device can be shown as.
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    // A boolean device that can be turned on and off.
+    Switch,
+    // A device holding a brightness, from 0 to 100. A brightness of
+    // 0 is shown as off.
+    Dimmer,
+    // A read-only device holding a temperature.
+    Temperature,
+    // A temperature, a settable target temperature, and, optionally,
+    // a boolean device which is true while heating.
+    Thermostat,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Accessory {
+    pub name: String,
+    pub kind: Kind,
+    pub device: device::Name,
+    // The target temperature of a thermostat.
+    pub target: Option<device::Name>,
+    // Whether a thermostat is heating.
+    pub heating: Option<device::Name>,
+    // HomeKit uses Celsius. Temperatures in Fahrenheit are converted.
+    #[serde(default)]
+    pub fahrenheit: bool,
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    // The name of the bridge shown by the Home app.
+    #[serde(default = "def_name")]
+    pub name: String,
+    // The setup code entered when adding the bridge, as "XXX-XX-XXX".
+    pub code: String,
+    #[serde(default = "def_port")]
+    pub port: u16,
+    // Where the bridge's identity and pairings are kept.
+    #[serde(default = "def_state_file")]
+    pub state_file: PathBuf,
+    #[serde(default)]
+    pub accessory: Vec<Accessory>,
+}
+
+// Setup codes that HomeKit refuses because they're easy to guess.
+
+const TRIVIAL_CODES: [&str; 12] = [
+    "000-00-000",
+    "111-11-111",
+    "222-22-222",
+    "333-33-333",
+    "444-44-444",
+    "555-55-555",
+    "666-66-666",
+    "777-77-777",
+    "888-88-888",
+    "999-99-999",
+    "123-45-678",
+    "876-54-321",
+];
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        let well_formed = self.code.len() == 10
+            && self.code.chars().enumerate().all(|(idx, c)| {
+                if idx == 3 || idx == 6 {
+                    c == '-'
+                } else {
+                    c.is_ascii_digit()
+                }
+            });
+
+        if !well_formed {
+            return Err(Error::ConfigError(
+                "HomeKit setup code must look like 123-45-679".into(),
+            ));
+        }
+
+        if TRIVIAL_CODES.contains(&self.code.as_str()) {
+            return Err(Error::ConfigError(
+                "HomeKit setup code is too easy to guess".into(),
+            ));
+        }
+
+        for acc in &self.accessory {
+            if acc.kind == Kind::Thermostat && acc.target.is_none() {
+                return Err(Error::ConfigError(format!(
+                    "HomeKit thermostat '{}' needs a target device",
+                    acc.name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(code: &str) -> Config {
+        toml::from_str(&format!(
+            "
+code = \"{}\"
+
+[[accessory]]
+name = \"Porch Light\"
+kind = \"switch\"
+device = \"porch:light\"
+
+[[accessory]]
+name = \"Den\"
+kind = \"thermostat\"
+device = \"den:temp\"
+target = \"den:setpoint\"
+fahrenheit = true
+",
+            code
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_config() {
+        let cfg = parse("031-45-154");
+
+        assert_eq!(cfg.name, "DrMem");
+        assert_eq!(cfg.port, 51826);
+        assert_eq!(cfg.accessory.len(), 2);
+        assert_eq!(cfg.accessory[1].kind, Kind::Thermostat);
+        assert!(cfg.accessory[1].fahrenheit);
+        assert!(cfg.validate().is_ok());
+
+        for code in ["031-45-15", "031-45-15a", "03145-154-", "123-45-678"] {
+            assert!(parse(code).validate().is_err(), "{} was accepted", code);
+        }
+
+        // Thermostats need a target.
+
+        let mut cfg = parse("031-45-154");
+
+        cfg.accessory[1].target = None;
+        assert!(cfg.validate().is_err());
+    }
+}
@@ -0,0 +1,393 @@
+// The cryptography used by the HomeKit Accessory Protocol: SRP-6a for
+// pairing with the setup code, HKDF-SHA512 to derive keys, and
+// ChaCha20-Poly1305 to encrypt pairing messages and sessions.
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use std::sync::LazyLock;
+use subtle::ConstantTimeEq;
+
+// The 3072-bit group from RFC 5054, which HomeKit uses with a
+// generator of 5.
+
+const N_3072: &str = "\
+    FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+    EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+    98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+    9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+    E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+    3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33\
+    A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7\
+    ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864\
+    D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2\
+    08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF";
+
+const N_LEN: usize = 384;
+
+static N: LazyLock<BigUint> =
+    LazyLock::new(|| BigUint::parse_bytes(N_3072.as_bytes(), 16).unwrap());
+static G: LazyLock<BigUint> = LazyLock::new(|| BigUint::from(5u32));
+
+// The SRP user name used by pair-setup.
+
+const USERNAME: &[u8] = b"Pair-Setup";
+
+// The most data held in one frame of an encrypted session.
+
+const MAX_FRAME: usize = 1024;
+
+const TAG_LEN: usize = 16;
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut d = Sha512::new();
+
+    for p in parts {
+        d.update(p)
+    }
+    d.finalize().to_vec()
+}
+
+// Left-pads a number with zeroes to the length of N.
+
+fn pad(v: &BigUint) -> Vec<u8> {
+    let v = v.to_bytes_be();
+    let mut buf = vec![0; N_LEN.saturating_sub(v.len())];
+
+    buf.extend(v);
+    buf
+}
+
+// x = H(s | H(I | ":" | P))
+
+pub(super) fn srp_x(salt: &[u8], code: &str) -> BigUint {
+    let inner = hash(&[USERNAME, b":", code.as_bytes()]);
+
+    BigUint::from_bytes_be(&hash(&[salt, &inner]))
+}
+
+// k = H(N | PAD(g))
+
+fn srp_k() -> BigUint {
+    BigUint::from_bytes_be(&hash(&[&N.to_bytes_be(), &pad(&G)]))
+}
+
+// u = H(PAD(A) | PAD(B))
+
+pub(super) fn srp_u(a_pub: &BigUint, b_pub: &BigUint) -> BigUint {
+    BigUint::from_bytes_be(&hash(&[&pad(a_pub), &pad(b_pub)]))
+}
+
+// M1 = H(H(N) xor H(g) | H(I) | s | A | B | K)
+
+pub(super) fn srp_m1(
+    salt: &[u8],
+    a_pub: &BigUint,
+    b_pub: &BigUint,
+    key: &[u8],
+) -> Vec<u8> {
+    let hng: Vec<u8> = hash(&[&N.to_bytes_be()])
+        .iter()
+        .zip(hash(&[&G.to_bytes_be()]))
+        .map(|(a, b)| a ^ b)
+        .collect();
+
+    hash(&[
+        &hng,
+        &hash(&[USERNAME]),
+        salt,
+        &a_pub.to_bytes_be(),
+        &b_pub.to_bytes_be(),
+        key,
+    ])
+}
+
+// The accessory's side of an SRP exchange, which proves the
+// controller knows the setup code.
+
+pub struct SrpServer {
+    salt: [u8; 16],
+    v: BigUint,
+    b: BigUint,
+    b_pub: BigUint,
+}
+
+impl SrpServer {
+    pub fn new(code: &str) -> Self {
+        let mut rng = rand::rngs::OsRng;
+        let mut salt = [0; 16];
+        let mut b = [0; 32];
+
+        rng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut b);
+
+        let v = G.modpow(&srp_x(&salt, code), &N);
+        let b = BigUint::from_bytes_be(&b);
+        let b_pub = (srp_k() * &v + G.modpow(&b, &N)) % &*N;
+
+        SrpServer { salt, v, b, b_pub }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.b_pub.to_bytes_be()
+    }
+
+    // Checks the controller's proof. If it's correct, returns the
+    // shared key and the accessory's proof. The proofs are compared
+    // in constant time so the time taken doesn't hint at the code.
+
+    pub fn verify(
+        &self,
+        a_pub: &[u8],
+        proof: &[u8],
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        let a_pub = BigUint::from_bytes_be(a_pub);
+
+        if (&a_pub % &*N) == BigUint::ZERO {
+            return None;
+        }
+
+        let u = srp_u(&a_pub, &self.b_pub);
+        let s = (&a_pub * self.v.modpow(&u, &N)).modpow(&self.b, &N);
+        let key = hash(&[&s.to_bytes_be()]);
+        let m1 = srp_m1(&self.salt, &a_pub, &self.b_pub, &key);
+
+        bool::from(m1.ct_eq(proof)).then(|| {
+            let m2 = hash(&[&a_pub.to_bytes_be(), &m1, &key]);
+
+            (key, m2)
+        })
+    }
+}
+
+pub fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+
+    Hkdf::<Sha512>::new(Some(salt), ikm)
+        .expand(info, &mut key)
+        .unwrap();
+    key
+}
+
+// Builds a nonce. HomeKit nonces are 4 bytes of zeroes followed by an
+// 8 byte label or counter.
+
+fn nonce(label: [u8; 8]) -> Nonce {
+    let mut n = [0; 12];
+
+    n[4..].copy_from_slice(&label);
+    n.into()
+}
+
+// Encrypts a pairing message. The label is the message's name, like
+// `PS-Msg06`.
+
+pub fn seal(key: &[u8; 32], label: &[u8; 8], data: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce(*label), data)
+        .unwrap()
+}
+
+pub fn open(key: &[u8; 32], label: &[u8; 8], data: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&nonce(*label), data)
+        .ok()
+}
+
+// Once a controller is verified, everything sent over the connection
+// is encrypted. Data is sent in frames holding up to 1024 bytes. Each
+// frame starts with its length, as two little-endian bytes, which is
+// authenticated with the data. Each direction has its own key and
+// counts its frames to build the nonces.
+
+pub struct Session {
+    read_key: [u8; 32],
+    write_key: [u8; 32],
+    read_count: u64,
+    write_count: u64,
+}
+
+impl Session {
+    // Creates the session keys from the secret shared during
+    // pair-verify.
+
+    pub fn new(shared: &[u8]) -> Self {
+        Session {
+            read_key: hkdf(
+                b"Control-Salt",
+                shared,
+                b"Control-Write-Encryption-Key",
+            ),
+            write_key: hkdf(
+                b"Control-Salt",
+                shared,
+                b"Control-Read-Encryption-Key",
+            ),
+            read_count: 0,
+            write_count: 0,
+        }
+    }
+
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let cipher = ChaCha20Poly1305::new(&self.write_key.into());
+        let mut buf = vec![];
+
+        for chunk in data.chunks(MAX_FRAME) {
+            let aad = (chunk.len() as u16).to_le_bytes();
+            let payload = chacha20poly1305::aead::Payload {
+                msg: chunk,
+                aad: &aad,
+            };
+            let nonce = nonce(self.write_count.to_le_bytes());
+
+            self.write_count += 1;
+            buf.extend(aad);
+            buf.extend(cipher.encrypt(&nonce, payload).unwrap());
+        }
+        buf
+    }
+
+    // Decrypts the complete frames at the start of `buf`, removing
+    // them from it. Returns `None` if a frame can't be authenticated,
+    // in which case the connection has to be closed.
+
+    pub fn decrypt(&mut self, buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(&self.read_key.into());
+        let mut data = vec![];
+        let mut used = 0;
+
+        while let Some(hdr) = buf.get(used..used + 2) {
+            let len = u16::from_le_bytes([hdr[0], hdr[1]]) as usize;
+
+            if len > MAX_FRAME {
+                return None;
+            }
+
+            let Some(frame) = buf.get(used + 2..used + 2 + len + TAG_LEN)
+            else {
+                break;
+            };
+            let payload = chacha20poly1305::aead::Payload {
+                msg: frame,
+                aad: hdr,
+            };
+            let nonce = nonce(self.read_count.to_le_bytes());
+
+            data.extend(cipher.decrypt(&nonce, payload).ok()?);
+            self.read_count += 1;
+            used += 2 + len + TAG_LEN;
+        }
+
+        buf.drain(..used);
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    // Plays the controller's side of SRP. Returns the controller's
+    // public key, its proof, and the shared key.
+
+    pub fn srp_client(
+        code: &str,
+        salt: &[u8],
+        b_pub: &[u8],
+    ) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let a = BigUint::from_bytes_be(&[0x5a; 32]);
+        let a_pub = G.modpow(&a, &N);
+        let b_pub = BigUint::from_bytes_be(b_pub);
+        let x = srp_x(salt, code);
+        let u = srp_u(&a_pub, &b_pub);
+        let kv = srp_k() * G.modpow(&x, &N) % &*N;
+        let base = (&b_pub + &*N - kv) % &*N;
+        let s = base.modpow(&(a + u * x), &N);
+        let key = hash(&[&s.to_bytes_be()]);
+        let m1 = srp_m1(salt, &a_pub, &b_pub, &key);
+
+        (a_pub.to_bytes_be(), m1, key)
+    }
+
+    // Returns the session used by the controller, whose keys are
+    // swapped.
+
+    pub fn client_session(shared: &[u8]) -> Session {
+        let mut s = Session::new(shared);
+
+        std::mem::swap(&mut s.read_key, &mut s.write_key);
+        s
+    }
+
+    #[test]
+    fn test_srp() {
+        let srv = SrpServer::new("031-45-154");
+        let (a_pub, m1, key) =
+            srp_client("031-45-154", srv.salt(), &srv.public_key());
+        let (srv_key, m2) = srv.verify(&a_pub, &m1).unwrap();
+
+        assert_eq!(srv_key, key);
+        assert_eq!(m2, hash(&[&a_pub, &m1, &key]));
+
+        // The wrong setup code doesn't produce the right proof.
+
+        let (a_pub, m1, _) =
+            srp_client("031-45-155", srv.salt(), &srv.public_key());
+
+        assert!(srv.verify(&a_pub, &m1).is_none());
+        assert!(srv.verify(&N.to_bytes_be(), &m1).is_none());
+    }
+
+    #[test]
+    fn test_messages() {
+        let key = [7; 32];
+        let msg = seal(&key, b"PS-Msg06", b"hello");
+
+        assert_eq!(msg.len(), 5 + TAG_LEN);
+        assert_eq!(open(&key, b"PS-Msg06", &msg), Some(b"hello".to_vec()));
+        assert_eq!(open(&key, b"PS-Msg05", &msg), None);
+    }
+
+    #[test]
+    fn test_session() {
+        let mut accessory = Session::new(b"shared");
+        let mut controller = client_session(b"shared");
+        let data: Vec<u8> = (0..2500).map(|v| v as u8).collect();
+        let mut buf = controller.encrypt(&data);
+
+        assert_eq!(buf.len(), data.len() + 3 * (2 + TAG_LEN));
+
+        // Partial frames are left in the buffer until the rest
+        // arrives.
+
+        let mut partial = buf.split_off(1500);
+
+        assert_eq!(accessory.decrypt(&mut buf), Some(data[..1024].to_vec()));
+        assert_eq!(buf.len(), 1500 - 1024 - 2 - TAG_LEN);
+
+        buf.append(&mut partial);
+        assert_eq!(accessory.decrypt(&mut buf), Some(data[1024..].to_vec()));
+        assert!(buf.is_empty());
+
+        // Replies use the other key.
+
+        let mut reply = accessory.encrypt(b"reply");
+
+        assert_eq!(controller.decrypt(&mut reply), Some(b"reply".to_vec()));
+
+        // Tampered frames are rejected.
+
+        let mut buf = controller.encrypt(b"data");
+
+        buf[3] ^= 1;
+        assert_eq!(accessory.decrypt(&mut buf), None);
+    }
+}
@@ -0,0 +1,834 @@
+// Bridges DrMem devices to Apple's HomeKit so they can be used from
+// the Home app. The bridge implements the HomeKit Accessory Protocol
+// over IP: it's announced with mDNS, paired using a setup code, and
+// serves the accessories over encrypted connections. Each configured
+// accessory shows one or more devices. DrMem still does the control;
+// HomeKit is another client.
+//
+// Like other clients, settings made from the Home app take control of
+// their devices away from logic blocks.
+
+use drmem_api::{client, device};
+use futures::Future;
+use libmdns::{Responder, Service};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_stream::StreamExt;
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod accessory;
+pub mod config;
+mod crypto;
+mod pairing;
+mod state;
+mod tlv;
+
+use accessory::Source;
+
+// The largest request accepted from a controller.
+
+const MAX_REQUEST: usize = 64 * 1024;
+
+// How long a setting can take before it's reported as failed.
+
+const SETTING_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TLV_TYPE: &str = "application/pairing+tlv8";
+const JSON_TYPE: &str = "application/hap+json";
+
+// The status codes HomeKit uses to report errors with
+// characteristics.
+
+const ST_NOT_AUTHORIZED: i32 = -70401;
+const ST_COMM_FAILURE: i32 = -70402;
+const ST_READ_ONLY: i32 = -70404;
+const ST_WRITE_ONLY: i32 = -70405;
+const ST_NO_NOTIFY: i32 = -70406;
+const ST_NOT_FOUND: i32 = -70409;
+const ST_INVALID_VALUE: i32 = -70410;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+// Removes a complete request from the start of `buf`. Returns
+// `Ok(None)` if more data is needed and `Err` if the request is
+// malformed or too big.
+
+fn parse_request(buf: &mut Vec<u8>) -> Result<Option<Request>, ()> {
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return if buf.len() > MAX_REQUEST {
+            Err(())
+        } else {
+            Ok(None)
+        };
+    };
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| ())?;
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().ok_or(())?.split(' ');
+    let method = parts.next().ok_or(())?.to_string();
+    let target = parts.next().ok_or(())?;
+    let mut length = 0;
+
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| ())?
+            }
+        }
+    }
+
+    if length > MAX_REQUEST {
+        return Err(());
+    }
+    if buf.len() < end + 4 + length {
+        return Ok(None);
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let req = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        body: buf[end + 4..end + 4 + length].to_vec(),
+    };
+
+    buf.drain(..end + 4 + length);
+    Ok(Some(req))
+}
+
+fn response(status: u16, ctype: &str, body: &[u8]) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        404 => "Not Found",
+        470 => "Connection Authorization Required",
+        _ => "Internal Server Error",
+    };
+    let mut buf = format!("HTTP/1.1 {} {}\r\n", status, reason).into_bytes();
+
+    if !body.is_empty() {
+        buf.extend(format!("Content-Type: {}\r\n", ctype).bytes())
+    }
+    buf.extend(format!("Content-Length: {}\r\n\r\n", body.len()).bytes());
+    buf.extend(body);
+    buf
+}
+
+fn json_response(status: u16, body: Json) -> Vec<u8> {
+    response(status, JSON_TYPE, body.to_string().as_bytes())
+}
+
+// Builds the reply to a request that reads or writes several
+// characteristics. If they all succeeded, `ok` is returned. Otherwise
+// each result includes its status.
+
+fn multi_status(ok: Vec<u8>, results: Vec<Json>) -> Vec<u8> {
+    if results.iter().all(|v| v["status"] == json!(0)) {
+        ok
+    } else {
+        json_response(207, json!({ "characteristics": results }))
+    }
+}
+
+// Parses the `id` parameter of a read, which lists characteristics as
+// "aid.iid" pairs.
+
+fn parse_ids(query: &str) -> Option<Vec<(u64, u64)>> {
+    let ids = query
+        .split('&')
+        .find_map(|v| v.strip_prefix("id="))?
+        .split(',')
+        .map(|v| {
+            let (aid, iid) = v.split_once('.')?;
+
+            Some((aid.parse().ok()?, iid.parse().ok()?))
+        });
+
+    ids.collect()
+}
+
+struct Bridge {
+    code: String,
+    name: String,
+    port: u16,
+    db: accessory::Database,
+    state: Mutex<state::State>,
+    values: Mutex<accessory::Values>,
+    changes: broadcast::Sender<device::Name>,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+    mdns: Option<Mutex<(Responder, Option<Service>)>>,
+}
+
+impl Bridge {
+    // (Re)announces the bridge. The announcement says whether the
+    // bridge is paired and the configuration number, so it's updated
+    // when they change.
+
+    fn announce(&self) {
+        let Some(mdns) = &self.mdns else {
+            return;
+        };
+        let state = self.state.lock().unwrap();
+        let txt = [
+            format!("c#={}", state.config_number()),
+            "ff=0".into(),
+            format!("id={}", state.device_id()),
+            format!("md={}", self.name),
+            "pv=1.1".into(),
+            "s#=1".into(),
+            format!("sf={}", !state.is_paired() as u8),
+            "ci=2".into(),
+        ];
+        let (responder, service) = &mut *mdns.lock().unwrap();
+
+        // The old announcement has to be dropped before making the
+        // new one.
+
+        *service = None;
+        *service = Some(responder.register(
+            "_hap._tcp".into(),
+            self.name.clone(),
+            self.port,
+            &txt.iter().map(String::as_str).collect::<Vec<_>>(),
+        ));
+    }
+
+    // Keeps the latest value of a device and tells the connections
+    // when it changes.
+
+    async fn watch(self: Arc<Self>, name: device::Name) {
+        let mut rx =
+            match self.cchan.monitor_device(name.clone(), None, None).await {
+                Ok(rx) => rx,
+                Err(e) => {
+                    warn!("couldn't monitor '{}' -- {}", &name, e);
+                    return;
+                }
+            };
+
+        while let Some(reading) = rx.next().await {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(name.clone(), reading.value);
+            let _ = self.changes.send(name.clone());
+        }
+    }
+}
+
+// The state of a connection from a controller.
+
+struct Connection {
+    bridge: Arc<Bridge>,
+    pairing: pairing::Pairing,
+    session: Option<crypto::Session>,
+    controller: Option<String>,
+    // The characteristics the controller wants to be told about.
+    events: HashSet<(u64, u64)>,
+    // Values set by the controller. It isn't told when they change
+    // to the value it set.
+    written: HashMap<(u64, u64), Json>,
+    // Received data that hasn't been decrypted yet and decrypted data
+    // that hasn't been parsed yet.
+    raw: Vec<u8>,
+    plain: Vec<u8>,
+}
+
+impl Connection {
+    fn new(bridge: Arc<Bridge>) -> Self {
+        Connection {
+            bridge,
+            pairing: pairing::Pairing::default(),
+            session: None,
+            controller: None,
+            events: HashSet::new(),
+            written: HashMap::new(),
+            raw: vec![],
+            plain: vec![],
+        }
+    }
+
+    async fn run(mut self, mut stream: TcpStream) {
+        let mut changes = self.bridge.changes.subscribe();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let ok = tokio::select! {
+                n = stream.read(&mut buf) => match n {
+                    Ok(0) | Err(_) => false,
+                    Ok(n) => self.received(&buf[..n], &mut stream).await,
+                },
+                dev = changes.recv() => match dev {
+                    Ok(dev) => self.notify(&dev, &mut stream).await,
+                    Err(RecvError::Lagged(_)) => true,
+                    Err(RecvError::Closed) => false,
+                },
+            };
+
+            if !ok {
+                break;
+            }
+        }
+    }
+
+    async fn send(&mut self, stream: &mut TcpStream, data: &[u8]) -> bool {
+        let data = match &mut self.session {
+            Some(session) => session.encrypt(data),
+            None => data.to_vec(),
+        };
+
+        stream.write_all(&data).await.is_ok()
+    }
+
+    // Handles data from the controller. Returns `false` if the
+    // connection should be closed.
+
+    async fn received(&mut self, data: &[u8], stream: &mut TcpStream) -> bool {
+        match &mut self.session {
+            Some(session) => {
+                self.raw.extend(data);
+
+                match session.decrypt(&mut self.raw) {
+                    Some(data) => self.plain.extend(data),
+                    None => return false,
+                }
+            }
+            None => self.plain.extend(data),
+        }
+
+        loop {
+            let req = match parse_request(&mut self.plain) {
+                Ok(Some(req)) => req,
+                Ok(None) => return true,
+                Err(()) => return false,
+            };
+            let (rpy, verified) = self.handle(req).await;
+
+            if !self.send(stream, &rpy).await {
+                return false;
+            }
+
+            // The reply to pair-verify is the last unencrypted
+            // message.
+
+            if let Some((mut session, id)) = verified {
+                info!("controller {} connected", &id);
+                self.raw.append(&mut self.plain);
+
+                match session.decrypt(&mut self.raw) {
+                    Some(data) => self.plain.extend(data),
+                    None => return false,
+                }
+                self.session = Some(session);
+                self.controller = Some(id);
+            }
+
+            // Connections from controllers that were removed are
+            // closed.
+
+            if let Some(id) = &self.controller {
+                if self.bridge.state.lock().unwrap().pairing(id).is_none() {
+                    return false;
+                }
+            }
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        req: Request,
+    ) -> (Vec<u8>, Option<(crypto::Session, String)>) {
+        let bridge = self.bridge.clone();
+
+        match (req.method.as_str(), req.path.as_str()) {
+            ("POST", "/pair-setup") => {
+                let mut state = bridge.state.lock().unwrap();
+                let was_paired = state.is_paired();
+                let rpy =
+                    self.pairing.setup(&mut state, &bridge.code, &req.body);
+                let paired = state.is_paired();
+
+                drop(state);
+                if paired != was_paired {
+                    bridge.announce()
+                }
+                (response(200, TLV_TYPE, &rpy.encode()), None)
+            }
+            ("POST", "/pair-verify") => {
+                let state = bridge.state.lock().unwrap();
+                let (rpy, verified) = self.pairing.verify(&state, &req.body);
+
+                (response(200, TLV_TYPE, &rpy.encode()), verified)
+            }
+            ("POST", "/identify") => {
+                if bridge.state.lock().unwrap().is_paired() {
+                    let body = json!({ "status": ST_NOT_AUTHORIZED });
+
+                    (json_response(400, body), None)
+                } else {
+                    info!("identify requested");
+                    (response(204, "", &[]), None)
+                }
+            }
+            _ if self.session.is_none() => {
+                let body = json!({ "status": ST_NOT_AUTHORIZED });
+
+                (json_response(470, body), None)
+            }
+            ("GET", "/accessories") => {
+                let values = bridge.values.lock().unwrap();
+
+                (json_response(200, bridge.db.to_json(&values)), None)
+            }
+            ("GET", "/characteristics") => (self.read(&req.query), None),
+            ("PUT", "/characteristics") => (self.write(&req.body).await, None),
+            ("POST", "/pairings") => (self.manage(&req.body), None),
+            _ => (response(404, "", &[]), None),
+        }
+    }
+
+    fn read(&self, query: &str) -> Vec<u8> {
+        let Some(ids) = parse_ids(query) else {
+            return response(400, "", &[]);
+        };
+        let values = self.bridge.values.lock().unwrap();
+        let results = ids
+            .into_iter()
+            .map(|(aid, iid)| match self.bridge.db.find(aid, iid) {
+                Some(c) if c.readable => json!({
+                    "aid": aid,
+                    "iid": iid,
+                    "value": c.read(&values),
+                    "status": 0,
+                }),
+                Some(_) => {
+                    json!({ "aid": aid, "iid": iid, "status": ST_WRITE_ONLY })
+                }
+                None => {
+                    json!({ "aid": aid, "iid": iid, "status": ST_NOT_FOUND })
+                }
+            })
+            .collect::<Vec<_>>();
+        let ok = json!({
+            "characteristics": results.iter().map(|v| json!({
+                "aid": v["aid"],
+                "iid": v["iid"],
+                "value": v["value"],
+            })).collect::<Vec<_>>(),
+        });
+
+        multi_status(json_response(200, ok), results)
+    }
+
+    async fn write(&mut self, body: &[u8]) -> Vec<u8> {
+        let Some(items) = serde_json::from_slice::<Json>(body)
+            .ok()
+            .and_then(|v| v["characteristics"].as_array().cloned())
+        else {
+            return response(400, "", &[]);
+        };
+        let mut results = vec![];
+
+        for item in items {
+            let (Some(aid), Some(iid)) =
+                (item["aid"].as_u64(), item["iid"].as_u64())
+            else {
+                return response(400, "", &[]);
+            };
+            let status = self.write_one(aid, iid, &item).await;
+
+            results.push(json!({ "aid": aid, "iid": iid, "status": status }))
+        }
+
+        multi_status(response(204, "", &[]), results)
+    }
+
+    // Handles a request to change a characteristic's value or
+    // whether the controller is told about its changes. Returns the
+    // HomeKit status.
+
+    async fn write_one(&mut self, aid: u64, iid: u64, item: &Json) -> i32 {
+        let Some(c) = self.bridge.db.find(aid, iid).cloned() else {
+            return ST_NOT_FOUND;
+        };
+
+        if let Some(ev) = item["ev"].as_bool() {
+            if !c.events {
+                return ST_NO_NOTIFY;
+            }
+            if ev {
+                self.events.insert((aid, iid));
+            } else {
+                self.events.remove(&(aid, iid));
+            }
+        }
+
+        let value = &item["value"];
+
+        if value.is_null() {
+            return 0;
+        }
+        if !c.writable {
+            return ST_READ_ONLY;
+        }
+
+        let Source::Device(name, conv) = &c.source else {
+            return 0;
+        };
+        let current = self.bridge.values.lock().unwrap().get(name).cloned();
+        let Some(setting) =
+            accessory::to_device(*conv, value, current.as_ref())
+        else {
+            return ST_INVALID_VALUE;
+        };
+
+        self.bridge.lchan.manual_setting(name);
+
+        match tokio::time::timeout(
+            SETTING_TIMEOUT,
+            self.bridge.cchan.set_value(name.clone(), setting),
+        )
+        .await
+        {
+            Ok(Ok(_)) => {
+                self.written.insert((aid, iid), value.clone());
+                0
+            }
+            Ok(Err(e)) => {
+                warn!("couldn't set '{}' -- {}", name, e);
+                ST_COMM_FAILURE
+            }
+            Err(_) => {
+                warn!("setting '{}' timed out", name);
+                ST_COMM_FAILURE
+            }
+        }
+    }
+
+    fn manage(&mut self, body: &[u8]) -> Vec<u8> {
+        let mut state = self.bridge.state.lock().unwrap();
+        let admin = self
+            .controller
+            .as_ref()
+            .and_then(|id| state.pairing(id))
+            .is_some_and(|(_, admin)| admin);
+        let was_paired = state.is_paired();
+        let rpy = pairing::manage(&mut state, admin, body);
+        let paired = state.is_paired();
+
+        drop(state);
+        if paired != was_paired {
+            self.bridge.announce()
+        }
+        response(200, TLV_TYPE, &rpy.encode())
+    }
+
+    // Tells the controller about a device's new value. Returns
+    // `false` if the connection should be closed.
+
+    async fn notify(
+        &mut self,
+        dev: &device::Name,
+        stream: &mut TcpStream,
+    ) -> bool {
+        if self.session.is_none() {
+            return true;
+        }
+
+        let mut changed = vec![];
+
+        {
+            let values = self.bridge.values.lock().unwrap();
+
+            for (aid, c) in self.bridge.db.characteristics() {
+                let key = (aid, c.iid);
+
+                if matches!(&c.source, Source::Device(name, _) if name == dev)
+                    && self.events.contains(&key)
+                {
+                    let value = c.read(&values);
+
+                    if self.written.remove(&key).as_ref() != Some(&value) {
+                        changed.push(json!({
+                            "aid": aid,
+                            "iid": c.iid,
+                            "value": value,
+                        }))
+                    }
+                }
+            }
+        }
+
+        if changed.is_empty() {
+            return true;
+        }
+
+        let body = json!({ "characteristics": changed }).to_string();
+        let msg = format!(
+            "EVENT/1.0 200 OK\r\nContent-Type: {}\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            JSON_TYPE,
+            body.len(),
+            body
+        );
+
+        self.send(stream, msg.as_bytes()).await
+    }
+}
+
+// Returns a future that runs the HomeKit bridge.
+
+pub fn bridge(
+    cfg: &config::Config,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> impl Future<Output = ()> {
+    let db = accessory::Database::new(&cfg.name, &cfg.accessory);
+    let state_file = cfg.state_file.clone();
+    let code = cfg.code.clone();
+    let name = cfg.name.clone();
+    let port = cfg.port;
+
+    async move {
+        let mut state = match state::State::load(state_file) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("couldn't load HomeKit state -- {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = state.set_layout(db.layout_hash()) {
+            error!("couldn't save HomeKit state -- {}", e);
+            return;
+        }
+
+        let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("couldn't listen on port {} -- {}", port, e);
+                return;
+            }
+        };
+        let mdns = match Responder::new() {
+            Ok(v) => Some(Mutex::new((v, None))),
+            Err(e) => {
+                warn!("couldn't start mDNS -- {}", e);
+                None
+            }
+        };
+        let devices = db.devices();
+        let bridge = Arc::new(Bridge {
+            code,
+            name,
+            port,
+            db,
+            state: Mutex::new(state),
+            values: Mutex::new(accessory::Values::new()),
+            changes: broadcast::channel(100).0,
+            cchan,
+            lchan,
+            mdns,
+        });
+
+        for dev in devices {
+            tokio::spawn(bridge.clone().watch(dev));
+        }
+
+        bridge.announce();
+        info!("HomeKit bridge listening on port {}", port);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let client = crate::monitor::Client::connect();
+                    let conn = Connection::new(bridge.clone());
+
+                    tokio::spawn(
+                        async move {
+                            conn.run(stream).await;
+                            drop(client)
+                        }
+                        .instrument(info_span!("homekit", client = %addr)),
+                    );
+                }
+                Err(e) => warn!("couldn't accept connection -- {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drmem_api::client::Request as CoreRequest;
+    use ed25519_dalek::SigningKey;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_parse_request() {
+        let mut buf = b"GET /characteristics?id=1.9,2.3 HTTP/1.1\r\n\
+                        Host: x\r\n\r\n\
+                        PUT /characteristics HTTP/1.1\r\n\
+                        content-length: 4\r\n\r\n{}"
+            .to_vec();
+        let req = parse_request(&mut buf).unwrap().unwrap();
+
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/characteristics");
+        assert_eq!(parse_ids(&req.query), Some(vec![(1, 9), (2, 3)]));
+
+        // The second request isn't complete.
+
+        assert!(parse_request(&mut buf).unwrap().is_none());
+        buf.extend(b"\r\n");
+
+        let req = parse_request(&mut buf).unwrap().unwrap();
+
+        assert_eq!(req.method, "PUT");
+        assert_eq!(req.body, b"{}\r\n");
+        assert!(buf.is_empty());
+
+        assert_eq!(parse_ids("id=1.x"), None);
+        assert_eq!(parse_ids("ev=1"), None);
+        assert!(parse_request(&mut b"\r\n\r\n".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let (tx, mut rx) = mpsc::channel(100);
+        let (ltx, _) = mpsc::channel(1);
+
+        // Acts as the core, which echoes settings.
+
+        tokio::spawn(async move {
+            while let Some(req) = rx.recv().await {
+                if let CoreRequest::SetDevice {
+                    value, rpy_chan, ..
+                } = req
+                {
+                    let _ = rpy_chan.send(Ok(value));
+                }
+            }
+        });
+
+        let cfg = [config::Accessory {
+            name: "Light".into(),
+            kind: config::Kind::Switch,
+            device: "room:light".parse().unwrap(),
+            target: None,
+            heating: None,
+            fahrenheit: false,
+        }];
+        let bridge = Arc::new(Bridge {
+            code: pairing::tests::CODE.into(),
+            name: "DrMem".into(),
+            port: 0,
+            db: accessory::Database::new("DrMem", &cfg),
+            state: Mutex::new(pairing::tests::temp_state("conn")),
+            values: Mutex::new(accessory::Values::new()),
+            changes: broadcast::channel(10).0,
+            cchan: client::RequestChan::new(tx),
+            lchan: crate::logic::manager::RequestChan::new(
+                ltx,
+                Default::default(),
+            ),
+            mdns: None,
+        });
+        let aid = accessory::fnv1a(b"Light") as u64;
+        let mut conn = Connection::new(bridge.clone());
+        let req = |method: &str, path: &str, body: &str| {
+            let (path, query) = path.split_once('?').unwrap_or((path, ""));
+
+            Request {
+                method: method.into(),
+                path: path.into(),
+                query: query.into(),
+                body: body.into(),
+            }
+        };
+
+        // Accessories can't be used until the controller is verified.
+
+        let (rpy, _) = conn.handle(req("GET", "/accessories", "")).await;
+
+        assert!(rpy.starts_with(b"HTTP/1.1 470 "));
+
+        let key = SigningKey::from_bytes(&[4; 32]);
+
+        {
+            let mut state = bridge.state.lock().unwrap();
+
+            pairing::tests::pair(&mut conn.pairing, &mut state, "ctl", &key);
+
+            let ((_, verified), _) =
+                pairing::tests::verify(&mut conn.pairing, &state, "ctl", &key);
+            let (session, id) = verified.unwrap();
+
+            conn.session = Some(session);
+            conn.controller = Some(id);
+        }
+
+        let (rpy, _) = conn.handle(req("GET", "/accessories", "")).await;
+
+        assert!(rpy.starts_with(b"HTTP/1.1 200 "));
+
+        // Turn the light on and ask to hear about its changes.
+
+        let body = json!({
+            "characteristics": [
+                { "aid": aid, "iid": 9, "value": true, "ev": true }
+            ]
+        })
+        .to_string();
+        let (rpy, _) = conn.handle(req("PUT", "/characteristics", &body)).await;
+
+        assert!(rpy.starts_with(b"HTTP/1.1 204 "));
+        assert!(conn.events.contains(&(aid, 9)));
+
+        // Writing a read-only characteristic or a missing one fails.
+
+        let body = format!(
+            "{{\"characteristics\":[{{\"aid\":1,\"iid\":3,\"value\":\"x\"}},\
+             {{\"aid\":{},\"iid\":99,\"value\":1}}]}}",
+            aid
+        );
+        let (rpy, _) = conn.handle(req("PUT", "/characteristics", &body)).await;
+        let text = String::from_utf8_lossy(&rpy);
+
+        assert!(text.starts_with("HTTP/1.1 207 "));
+        assert!(text.contains("-70404"));
+        assert!(text.contains("-70409"));
+
+        // Reads use the latest reading.
+
+        bridge
+            .values
+            .lock()
+            .unwrap()
+            .insert("room:light".parse().unwrap(), true.into());
+
+        let path = format!("/characteristics?id={}.9", aid);
+        let (rpy, _) = conn.handle(req("GET", &path, "")).await;
+        let text = String::from_utf8_lossy(&rpy);
+
+        assert!(text.starts_with("HTTP/1.1 200 "));
+        assert!(text.contains("\"value\":true"));
+    }
+}
@@ -0,0 +1,654 @@
+// Handles the pairing requests. Pair-setup adds a controller using
+// the setup code. Pair-verify starts an encrypted session with a
+// paired controller. Once a session is started, admin controllers can
+// add, remove, and list pairings.
+
+use super::{
+    crypto::{self, Session, SrpServer},
+    state::State,
+    tlv::{self, Tlv},
+};
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use tracing::{info, warn};
+
+const METHOD_ADD: u8 = 3;
+const METHOD_REMOVE: u8 = 4;
+const METHOD_LIST: u8 = 5;
+
+// After this many failed attempts, pair-setup is refused until the
+// state file is removed.
+
+const MAX_TRIES: u32 = 100;
+
+fn failed(state: u8, err: u8) -> Tlv {
+    Tlv::new().add(tlv::STATE, [state]).add(tlv::ERROR, [err])
+}
+
+fn verify_signature(key: &VerifyingKey, msg: &[u8], sig: &[u8]) -> bool {
+    Signature::from_slice(sig).is_ok_and(|sig| key.verify(msg, &sig).is_ok())
+}
+
+// The values pair-verify keeps between its two requests.
+
+struct Verify {
+    shared: [u8; 32],
+    accessory_pk: [u8; 32],
+    controller_pk: [u8; 32],
+    key: [u8; 32],
+}
+
+// Tracks the pairing requests made on a connection.
+
+#[derive(Default)]
+pub struct Pairing {
+    srp: Option<SrpServer>,
+    setup_key: Option<Vec<u8>>,
+    verify: Option<Verify>,
+}
+
+impl Pairing {
+    // Handles a pair-setup request. Only one controller can be added
+    // this way. Others are added by an admin controller. A wrong
+    // setup code makes the controller wait before it can try again
+    // and, after `MAX_TRIES` wrong codes, pair-setup is refused.
+
+    pub fn setup(&mut self, state: &mut State, code: &str, body: &[u8]) -> Tlv {
+        let Some(req) = Tlv::decode(body) else {
+            return failed(2, tlv::ERR_UNKNOWN);
+        };
+
+        match req.get_u8(tlv::STATE) {
+            Some(1) => {
+                if state.is_paired() {
+                    return failed(2, tlv::ERR_UNAVAILABLE);
+                }
+
+                if state.failed_setups() >= MAX_TRIES {
+                    return failed(2, tlv::ERR_MAX_TRIES);
+                }
+
+                if let Some(delay) = state.retry_delay() {
+                    let secs = delay.as_secs_f64().ceil() as u16;
+
+                    return failed(2, tlv::ERR_BACKOFF)
+                        .add(tlv::RETRY_DELAY, secs.to_le_bytes());
+                }
+
+                let srp = SrpServer::new(code);
+                let rpy = Tlv::new()
+                    .add(tlv::STATE, [2])
+                    .add(tlv::PUBLIC_KEY, srp.public_key())
+                    .add(tlv::SALT, srp.salt());
+
+                self.srp = Some(srp);
+                self.setup_key = None;
+                rpy
+            }
+            Some(3) => {
+                let result = self
+                    .srp
+                    .as_ref()
+                    .zip(req.get(tlv::PUBLIC_KEY).zip(req.get(tlv::PROOF)));
+
+                match result.and_then(|(srp, (a, p))| srp.verify(a, p)) {
+                    Some((key, proof)) => {
+                        self.setup_key = Some(key);
+                        Tlv::new().add(tlv::STATE, [4]).add(tlv::PROOF, proof)
+                    }
+                    None => {
+                        warn!("pair-setup failed: wrong setup code");
+                        self.srp = None;
+                        if let Err(e) = state.setup_failed() {
+                            warn!("couldn't save failed attempt -- {}", e)
+                        }
+                        failed(4, tlv::ERR_AUTHENTICATION)
+                    }
+                }
+            }
+            Some(5) => match self.exchange(state, &req) {
+                Some(rpy) => rpy,
+                None => failed(6, tlv::ERR_AUTHENTICATION),
+            },
+            _ => failed(2, tlv::ERR_UNKNOWN),
+        }
+    }
+
+    // Finishes pair-setup by exchanging long-term public keys with
+    // the controller.
+
+    fn exchange(&mut self, state: &mut State, req: &Tlv) -> Option<Tlv> {
+        let shared = self.setup_key.take()?;
+        let key = crypto::hkdf(
+            b"Pair-Setup-Encrypt-Salt",
+            &shared,
+            b"Pair-Setup-Encrypt-Info",
+        );
+        let data =
+            crypto::open(&key, b"PS-Msg05", req.get(tlv::ENCRYPTED_DATA)?)?;
+        let sub = Tlv::decode(&data)?;
+        let id = sub.get(tlv::IDENTIFIER)?;
+        let ltpk = sub.get(tlv::PUBLIC_KEY)?;
+        let controller =
+            VerifyingKey::from_bytes(ltpk.try_into().ok()?).ok()?;
+        let x = crypto::hkdf(
+            b"Pair-Setup-Controller-Sign-Salt",
+            &shared,
+            b"Pair-Setup-Controller-Sign-Info",
+        );
+
+        if !verify_signature(
+            &controller,
+            &[&x[..], id, ltpk].concat(),
+            sub.get(tlv::SIGNATURE)?,
+        ) {
+            return None;
+        }
+
+        let id = std::str::from_utf8(id).ok()?;
+
+        if let Err(e) = state.add_pairing(id, &controller, true) {
+            warn!("couldn't save pairing -- {}", e);
+            return Some(failed(6, tlv::ERR_UNKNOWN));
+        }
+        info!("paired with controller {}", id);
+
+        let x = crypto::hkdf(
+            b"Pair-Setup-Accessory-Sign-Salt",
+            &shared,
+            b"Pair-Setup-Accessory-Sign-Info",
+        );
+        let device_id = state.device_id().as_bytes();
+        let accessory = state.signing_key().verifying_key();
+        let sig = state
+            .signing_key()
+            .sign(&[&x[..], device_id, accessory.as_bytes()].concat());
+        let sub = Tlv::new()
+            .add(tlv::IDENTIFIER, device_id)
+            .add(tlv::PUBLIC_KEY, accessory.as_bytes())
+            .add(tlv::SIGNATURE, sig.to_bytes());
+
+        Some(Tlv::new().add(tlv::STATE, [6]).add(
+            tlv::ENCRYPTED_DATA,
+            crypto::seal(&key, b"PS-Msg06", &sub.encode()),
+        ))
+    }
+
+    // Handles a pair-verify request. When the controller is verified,
+    // returns the session to use and the controller's ID.
+
+    pub fn verify(
+        &mut self,
+        state: &State,
+        body: &[u8],
+    ) -> (Tlv, Option<(Session, String)>) {
+        let Some(req) = Tlv::decode(body) else {
+            return (failed(2, tlv::ERR_UNKNOWN), None);
+        };
+
+        match req.get_u8(tlv::STATE) {
+            Some(1) => match self.verify_start(state, &req) {
+                Some(rpy) => (rpy, None),
+                None => (failed(2, tlv::ERR_UNKNOWN), None),
+            },
+            Some(3) => match self.verify_finish(state, &req) {
+                Some(v) => (Tlv::new().add(tlv::STATE, [4]), Some(v)),
+                None => (failed(4, tlv::ERR_AUTHENTICATION), None),
+            },
+            _ => (failed(2, tlv::ERR_UNKNOWN), None),
+        }
+    }
+
+    fn verify_start(&mut self, state: &State, req: &Tlv) -> Option<Tlv> {
+        let controller_pk: [u8; 32] =
+            req.get(tlv::PUBLIC_KEY)?.try_into().ok()?;
+        let secret =
+            x25519_dalek::EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let accessory_pk = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&controller_pk.into()).to_bytes();
+        let device_id = state.device_id().as_bytes();
+        let sig = state
+            .signing_key()
+            .sign(&[&accessory_pk[..], device_id, &controller_pk].concat());
+        let key = crypto::hkdf(
+            b"Pair-Verify-Encrypt-Salt",
+            &shared,
+            b"Pair-Verify-Encrypt-Info",
+        );
+        let sub = Tlv::new()
+            .add(tlv::IDENTIFIER, device_id)
+            .add(tlv::SIGNATURE, sig.to_bytes());
+
+        self.verify = Some(Verify {
+            shared,
+            accessory_pk,
+            controller_pk,
+            key,
+        });
+
+        Some(
+            Tlv::new()
+                .add(tlv::STATE, [2])
+                .add(tlv::PUBLIC_KEY, accessory_pk)
+                .add(
+                    tlv::ENCRYPTED_DATA,
+                    crypto::seal(&key, b"PV-Msg02", &sub.encode()),
+                ),
+        )
+    }
+
+    fn verify_finish(
+        &mut self,
+        state: &State,
+        req: &Tlv,
+    ) -> Option<(Session, String)> {
+        let v = self.verify.take()?;
+        let data =
+            crypto::open(&v.key, b"PV-Msg03", req.get(tlv::ENCRYPTED_DATA)?)?;
+        let sub = Tlv::decode(&data)?;
+        let id = std::str::from_utf8(sub.get(tlv::IDENTIFIER)?).ok()?;
+        let (controller, _) = state.pairing(id)?;
+
+        verify_signature(
+            &controller,
+            &[&v.controller_pk[..], id.as_bytes(), &v.accessory_pk].concat(),
+            sub.get(tlv::SIGNATURE)?,
+        )
+        .then(|| (Session::new(&v.shared), id.to_string()))
+    }
+}
+
+// Handles a request to add, remove, or list pairings. Only admin
+// controllers can make them.
+
+pub fn manage(state: &mut State, admin: bool, body: &[u8]) -> Tlv {
+    let Some(req) = Tlv::decode(body) else {
+        return failed(2, tlv::ERR_UNKNOWN);
+    };
+
+    if !admin {
+        return failed(2, tlv::ERR_AUTHENTICATION);
+    }
+
+    let id = req
+        .get(tlv::IDENTIFIER)
+        .and_then(|v| std::str::from_utf8(v).ok());
+
+    let result = match (req.get_u8(tlv::METHOD), id) {
+        (Some(METHOD_ADD), Some(id)) => {
+            let Some(key) = req
+                .get(tlv::PUBLIC_KEY)
+                .and_then(|v| v.try_into().ok())
+                .and_then(|v| VerifyingKey::from_bytes(v).ok())
+            else {
+                return failed(2, tlv::ERR_UNKNOWN);
+            };
+
+            // A controller that's already paired can only have its
+            // permissions changed.
+
+            if state.pairing(id).is_some_and(|(k, _)| k != key) {
+                return failed(2, tlv::ERR_UNKNOWN);
+            }
+
+            info!("adding controller {}", id);
+            state.add_pairing(
+                id,
+                &key,
+                req.get_u8(tlv::PERMISSIONS).unwrap_or(0) & 1 == 1,
+            )
+        }
+        (Some(METHOD_REMOVE), Some(id)) => {
+            info!("removing controller {}", id);
+            state.remove_pairing(id).and_then(|_| {
+                // Without an admin, nobody could manage the pairings,
+                // so they're all removed.
+
+                if state.pairings().iter().all(|(_, _, admin)| !admin) {
+                    for (id, _, _) in state.pairings() {
+                        state.remove_pairing(&id)?
+                    }
+                }
+                Ok(())
+            })
+        }
+        (Some(METHOD_LIST), _) => {
+            let mut rpy = Tlv::new().add(tlv::STATE, [2]);
+
+            for (idx, (id, key, admin)) in
+                state.pairings().into_iter().enumerate()
+            {
+                if idx > 0 {
+                    rpy = rpy.add(tlv::SEPARATOR, []);
+                }
+                rpy = rpy
+                    .add(tlv::IDENTIFIER, id)
+                    .add(tlv::PUBLIC_KEY, key.as_bytes())
+                    .add(tlv::PERMISSIONS, [admin as u8]);
+            }
+            return rpy;
+        }
+        _ => return failed(2, tlv::ERR_UNKNOWN),
+    };
+
+    match result {
+        Ok(()) => Tlv::new().add(tlv::STATE, [2]),
+        Err(e) => {
+            warn!("couldn't save pairings -- {}", e);
+            failed(2, tlv::ERR_UNKNOWN)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::homekit::crypto::tests::{client_session, srp_client};
+    use ed25519_dalek::SigningKey;
+
+    pub const CODE: &str = "031-45-154";
+
+    pub fn temp_state(name: &str) -> State {
+        let path = std::env::temp_dir().join(format!(
+            "drmem-homekit-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        State::load(path).unwrap()
+    }
+
+    // Plays the controller's side of pair-setup.
+
+    pub fn pair(
+        pairing: &mut Pairing,
+        state: &mut State,
+        id: &str,
+        key: &SigningKey,
+    ) -> Tlv {
+        let rpy = pairing.setup(
+            state,
+            CODE,
+            &Tlv::new()
+                .add(tlv::STATE, [1])
+                .add(tlv::METHOD, [0])
+                .encode(),
+        );
+        let (a_pub, proof, shared) = srp_client(
+            CODE,
+            rpy.get(tlv::SALT).unwrap(),
+            rpy.get(tlv::PUBLIC_KEY).unwrap(),
+        );
+        let rpy = pairing.setup(
+            state,
+            CODE,
+            &Tlv::new()
+                .add(tlv::STATE, [3])
+                .add(tlv::PUBLIC_KEY, a_pub)
+                .add(tlv::PROOF, proof)
+                .encode(),
+        );
+
+        assert_eq!(rpy.get_u8(tlv::STATE), Some(4));
+        assert_eq!(rpy.get(tlv::ERROR), None);
+
+        let x = crypto::hkdf(
+            b"Pair-Setup-Controller-Sign-Salt",
+            &shared,
+            b"Pair-Setup-Controller-Sign-Info",
+        );
+        let ltpk = key.verifying_key();
+        let sig = key.sign(&[&x[..], id.as_bytes(), ltpk.as_bytes()].concat());
+        let sub = Tlv::new()
+            .add(tlv::IDENTIFIER, id)
+            .add(tlv::PUBLIC_KEY, ltpk.as_bytes())
+            .add(tlv::SIGNATURE, sig.to_bytes());
+        let enc = crypto::hkdf(
+            b"Pair-Setup-Encrypt-Salt",
+            &shared,
+            b"Pair-Setup-Encrypt-Info",
+        );
+
+        pairing.setup(
+            state,
+            CODE,
+            &Tlv::new()
+                .add(tlv::STATE, [5])
+                .add(
+                    tlv::ENCRYPTED_DATA,
+                    crypto::seal(&enc, b"PS-Msg05", &sub.encode()),
+                )
+                .encode(),
+        )
+    }
+
+    // Plays the controller's side of pair-verify. Returns the
+    // accessory's reply to the last request and the controller's
+    // session.
+
+    pub fn verify(
+        pairing: &mut Pairing,
+        state: &State,
+        id: &str,
+        key: &SigningKey,
+    ) -> ((Tlv, Option<(Session, String)>), Session) {
+        let secret = x25519_dalek::StaticSecret::from([9; 32]);
+        let controller_pk = x25519_dalek::PublicKey::from(&secret);
+        let (rpy, _) = pairing.verify(
+            state,
+            &Tlv::new()
+                .add(tlv::STATE, [1])
+                .add(tlv::PUBLIC_KEY, controller_pk.as_bytes())
+                .encode(),
+        );
+        let accessory_pk: [u8; 32] =
+            rpy.get(tlv::PUBLIC_KEY).unwrap().try_into().unwrap();
+        let shared = secret.diffie_hellman(&accessory_pk.into()).to_bytes();
+        let enc = crypto::hkdf(
+            b"Pair-Verify-Encrypt-Salt",
+            &shared,
+            b"Pair-Verify-Encrypt-Info",
+        );
+
+        // Check the accessory's signature.
+
+        let sub = Tlv::decode(
+            &crypto::open(
+                &enc,
+                b"PV-Msg02",
+                rpy.get(tlv::ENCRYPTED_DATA).unwrap(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert!(verify_signature(
+            &state.signing_key().verifying_key(),
+            &[
+                &accessory_pk[..],
+                state.device_id().as_bytes(),
+                controller_pk.as_bytes()
+            ]
+            .concat(),
+            sub.get(tlv::SIGNATURE).unwrap()
+        ));
+
+        let sig = key.sign(
+            &[controller_pk.as_bytes(), id.as_bytes(), &accessory_pk[..]]
+                .concat(),
+        );
+        let sub = Tlv::new()
+            .add(tlv::IDENTIFIER, id)
+            .add(tlv::SIGNATURE, sig.to_bytes());
+
+        (
+            pairing.verify(
+                state,
+                &Tlv::new()
+                    .add(tlv::STATE, [3])
+                    .add(
+                        tlv::ENCRYPTED_DATA,
+                        crypto::seal(&enc, b"PV-Msg03", &sub.encode()),
+                    )
+                    .encode(),
+            ),
+            client_session(&shared),
+        )
+    }
+
+    #[test]
+    fn test_pairing() {
+        let mut state = temp_state("pairing");
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let mut pairing = Pairing::default();
+
+        // An unpaired controller can't be verified.
+
+        let ((rpy, session), _) = verify(&mut pairing, &state, "ctl-1", &key);
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_AUTHENTICATION][..]));
+        assert!(session.is_none());
+
+        // Pair the controller.
+
+        let rpy = pair(&mut pairing, &mut state, "ctl-1", &key);
+
+        assert_eq!(rpy.get_u8(tlv::STATE), Some(6));
+        assert_eq!(state.pairing("ctl-1"), Some((key.verifying_key(), true)));
+
+        // Pair-setup is refused once the bridge is paired.
+
+        let rpy = pairing.setup(
+            &mut state,
+            CODE,
+            &Tlv::new().add(tlv::STATE, [1]).encode(),
+        );
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_UNAVAILABLE][..]));
+
+        // The controller can now start a session.
+
+        let ((rpy, session), mut client) =
+            verify(&mut pairing, &state, "ctl-1", &key);
+        let (mut session, id) = session.unwrap();
+
+        assert_eq!(rpy.get_u8(tlv::STATE), Some(4));
+        assert_eq!(id, "ctl-1");
+
+        let mut buf = client.encrypt(b"GET /accessories");
+
+        assert_eq!(
+            session.decrypt(&mut buf),
+            Some(b"GET /accessories".to_vec())
+        );
+
+        // A controller using the wrong key can't.
+
+        let ((_, session), _) = verify(
+            &mut pairing,
+            &state,
+            "ctl-1",
+            &SigningKey::from_bytes(&[2; 32]),
+        );
+
+        assert!(session.is_none());
+    }
+
+    #[test]
+    fn test_wrong_code() {
+        let mut state = temp_state("code");
+        let mut pairing = Pairing::default();
+        let rpy = pairing.setup(
+            &mut state,
+            "111-22-333",
+            &Tlv::new().add(tlv::STATE, [1]).encode(),
+        );
+        let (a_pub, proof, _) = srp_client(
+            CODE,
+            rpy.get(tlv::SALT).unwrap(),
+            rpy.get(tlv::PUBLIC_KEY).unwrap(),
+        );
+        let rpy = pairing.setup(
+            &mut state,
+            "111-22-333",
+            &Tlv::new()
+                .add(tlv::STATE, [3])
+                .add(tlv::PUBLIC_KEY, a_pub)
+                .add(tlv::PROOF, proof)
+                .encode(),
+        );
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_AUTHENTICATION][..]));
+        assert!(!state.is_paired());
+        assert_eq!(state.failed_setups(), 1);
+
+        // The controller has to wait before trying again.
+
+        let start = Tlv::new().add(tlv::STATE, [1]).encode();
+        let rpy = pairing.setup(&mut state, CODE, &start);
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_BACKOFF][..]));
+        assert_eq!(rpy.get(tlv::RETRY_DELAY), Some(&[1, 0][..]));
+
+        // Too many wrong codes and pair-setup is refused for good.
+
+        while state.failed_setups() < MAX_TRIES {
+            state.setup_failed().unwrap()
+        }
+
+        let rpy = pairing.setup(&mut state, CODE, &start);
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_MAX_TRIES][..]));
+    }
+
+    #[test]
+    fn test_manage() {
+        let mut state = temp_state("manage");
+        let admin = SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let user = SigningKey::from_bytes(&[2; 32]).verifying_key();
+
+        state.add_pairing("admin", &admin, true).unwrap();
+
+        let add = Tlv::new()
+            .add(tlv::METHOD, [METHOD_ADD])
+            .add(tlv::IDENTIFIER, "user")
+            .add(tlv::PUBLIC_KEY, user.as_bytes())
+            .add(tlv::PERMISSIONS, [0])
+            .encode();
+
+        // Only admins can manage pairings.
+
+        let rpy = manage(&mut state, false, &add);
+
+        assert_eq!(rpy.get(tlv::ERROR), Some(&[tlv::ERR_AUTHENTICATION][..]));
+
+        let rpy = manage(&mut state, true, &add);
+
+        assert_eq!(rpy.get(tlv::ERROR), None);
+        assert_eq!(state.pairing("user"), Some((user, false)));
+
+        let rpy = manage(
+            &mut state,
+            true,
+            &Tlv::new().add(tlv::METHOD, [METHOD_LIST]).encode(),
+        );
+        let buf = rpy.encode();
+
+        assert!(buf.windows(5).any(|w| w == b"admin"));
+        assert!(buf.windows(4).any(|w| w == b"user"));
+
+        // Removing the last admin removes everyone.
+
+        let rpy = manage(
+            &mut state,
+            true,
+            &Tlv::new()
+                .add(tlv::METHOD, [METHOD_REMOVE])
+                .add(tlv::IDENTIFIER, "admin")
+                .encode(),
+        );
+
+        assert_eq!(rpy.get(tlv::ERROR), None);
+        assert!(!state.is_paired());
+    }
+}
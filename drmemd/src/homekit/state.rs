@@ -0,0 +1,300 @@
+// Keeps the bridge's identity and the controllers paired with it.
+// They have to survive restarts, or every controller would need to be
+// paired again, so they're saved to a file each time they change.
+//
+// The failed pair-setup attempts are saved, too, so restarting the
+// bridge doesn't give someone guessing the setup code more tries.
+
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+// The longest time a controller has to wait after a failed
+// pair-setup attempt.
+
+const MAX_BACKOFF: Duration = Duration::from_secs(3_600);
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    let mut buf = [0; N];
+
+    if s.len() != N * 2 {
+        return None;
+    }
+
+    for (idx, b) in buf.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(idx * 2..idx * 2 + 2)?, 16).ok()?
+    }
+    Some(buf)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Pairing {
+    public_key: String,
+    admin: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Saved {
+    device_id: String,
+    secret_key: String,
+    config_number: u32,
+    layout: u32,
+    pairings: BTreeMap<String, Pairing>,
+    #[serde(default)]
+    failed_setups: u32,
+}
+
+pub struct State {
+    path: PathBuf,
+    saved: Saved,
+    key: SigningKey,
+    // Pair-setup isn't accepted again until this time.
+    backoff: Option<Instant>,
+}
+
+impl State {
+    // Loads the state from its file. If the file doesn't exist, a new
+    // identity is created.
+
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let bad_data = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        match std::fs::read(&path) {
+            Ok(data) => {
+                let saved: Saved = serde_json::from_slice(&data)
+                    .map_err(|e| bad_data(e.to_string()))?;
+                let key = from_hex::<32>(&saved.secret_key)
+                    .ok_or_else(|| bad_data("bad secret key".into()))?;
+
+                Ok(State {
+                    path,
+                    saved,
+                    key: SigningKey::from_bytes(&key),
+                    backoff: None,
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut rng = rand::rngs::OsRng;
+                let mut id = [0u8; 6];
+
+                rng.fill_bytes(&mut id);
+
+                let key = SigningKey::generate(&mut rng);
+                let state = State {
+                    path,
+                    saved: Saved {
+                        device_id: id
+                            .iter()
+                            .map(|b| format!("{:02X}", b))
+                            .collect::<Vec<_>>()
+                            .join(":"),
+                        secret_key: to_hex(key.as_bytes()),
+                        config_number: 1,
+                        layout: 0,
+                        pairings: BTreeMap::new(),
+                        failed_setups: 0,
+                    },
+                    key,
+                    backoff: None,
+                };
+
+                state.save()?;
+                Ok(state)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(&self.saved)?;
+        let tmp = self.path.with_extension("tmp");
+
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    // The bridge's identifier, which looks like a MAC address.
+
+    pub fn device_id(&self) -> &str {
+        &self.saved.device_id
+    }
+
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.key
+    }
+
+    // The configuration number tells controllers when the accessories
+    // have changed. It's increased when the layout's hash changes.
+
+    pub fn config_number(&self) -> u32 {
+        self.saved.config_number
+    }
+
+    pub fn set_layout(&mut self, layout: u32) -> io::Result<()> {
+        if self.saved.layout != layout {
+            self.saved.layout = layout;
+            self.saved.config_number =
+                self.saved.config_number.wrapping_add(1).max(1);
+            self.save()?
+        }
+        Ok(())
+    }
+
+    pub fn is_paired(&self) -> bool {
+        !self.saved.pairings.is_empty()
+    }
+
+    // Returns the public key of a paired controller and whether it's
+    // an admin.
+
+    pub fn pairing(&self, id: &str) -> Option<(VerifyingKey, bool)> {
+        let p = self.saved.pairings.get(id)?;
+        let key = VerifyingKey::from_bytes(&from_hex(&p.public_key)?).ok()?;
+
+        Some((key, p.admin))
+    }
+
+    pub fn pairings(&self) -> Vec<(String, VerifyingKey, bool)> {
+        self.saved
+            .pairings
+            .keys()
+            .filter_map(|id| {
+                self.pairing(id)
+                    .map(|(key, admin)| (id.clone(), key, admin))
+            })
+            .collect()
+    }
+
+    // The number of failed pair-setup attempts since the last
+    // pairing was added.
+
+    pub fn failed_setups(&self) -> u32 {
+        self.saved.failed_setups
+    }
+
+    // Records a failed pair-setup attempt. The next attempt has to
+    // wait a second, and each failure doubles the wait, up to
+    // `MAX_BACKOFF`.
+
+    pub fn setup_failed(&mut self) -> io::Result<()> {
+        let delay = Duration::from_secs(1 << self.saved.failed_setups.min(12))
+            .min(MAX_BACKOFF);
+
+        self.saved.failed_setups = self.saved.failed_setups.saturating_add(1);
+        self.backoff = Some(Instant::now() + delay);
+        self.save()
+    }
+
+    // Returns how long pair-setup has to wait before it's tried
+    // again, if it has to.
+
+    pub fn retry_delay(&self) -> Option<Duration> {
+        self.backoff
+            .map(|v| v.saturating_duration_since(Instant::now()))
+            .filter(|v| !v.is_zero())
+    }
+
+    // Adds a controller. Adding one clears the failed pair-setup
+    // attempts.
+
+    pub fn add_pairing(
+        &mut self,
+        id: &str,
+        key: &VerifyingKey,
+        admin: bool,
+    ) -> io::Result<()> {
+        self.saved.failed_setups = 0;
+        self.backoff = None;
+        self.saved.pairings.insert(
+            id.into(),
+            Pairing {
+                public_key: to_hex(key.as_bytes()),
+                admin,
+            },
+        );
+        self.save()
+    }
+
+    pub fn remove_pairing(&mut self, id: &str) -> io::Result<()> {
+        if self.saved.pairings.remove(id).is_some() {
+            self.save()?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let path = std::env::temp_dir()
+            .join(format!("drmem-homekit-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut state = State::load(path.clone()).unwrap();
+        let id = state.device_id().to_string();
+        let controller = SigningKey::from_bytes(&[3; 32]).verifying_key();
+
+        assert_eq!(id.len(), 17);
+        assert!(!state.is_paired());
+        assert_eq!(state.config_number(), 1);
+
+        state.set_layout(10).unwrap();
+        state.set_layout(10).unwrap();
+        state.add_pairing("ctl", &controller, true).unwrap();
+
+        // The state is the same after reloading it.
+
+        let mut state = State::load(path.clone()).unwrap();
+
+        assert_eq!(state.device_id(), id);
+        assert_eq!(state.config_number(), 2);
+        assert_eq!(state.pairing("ctl"), Some((controller, true)));
+        assert_eq!(state.pairing("other"), None);
+        assert_eq!(state.pairings().len(), 1);
+
+        state.remove_pairing("ctl").unwrap();
+        assert!(!State::load(path.clone()).unwrap().is_paired());
+
+        // Failed pair-setup attempts are kept across restarts. Each
+        // one doubles the wait before the next.
+
+        assert_eq!(state.retry_delay(), None);
+        state.setup_failed().unwrap();
+        assert!(state.retry_delay() <= Some(Duration::from_secs(1)));
+        state.setup_failed().unwrap();
+        assert!(state.retry_delay() > Some(Duration::from_secs(1)));
+
+        let mut state = State::load(path.clone()).unwrap();
+
+        assert_eq!(state.failed_setups(), 2);
+        assert_eq!(state.retry_delay(), None);
+
+        for _ in 0..20 {
+            state.setup_failed().unwrap()
+        }
+        assert!(state.retry_delay() <= Some(MAX_BACKOFF));
+
+        state.add_pairing("ctl", &controller, true).unwrap();
+        assert_eq!(state.failed_setups(), 0);
+        assert_eq!(state.retry_delay(), None);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_hex::<2>("0aFf"), Some([0x0a, 0xff]));
+        assert_eq!(from_hex::<2>("0aF"), None);
+        assert_eq!(from_hex::<1>("zz"), None);
+    }
+}
@@ -0,0 +1,131 @@
+// Encodes and decodes the TLV8 format used by the pairing requests.
+// Each item is a type byte, a length byte, and up to 255 bytes of
+// data. Longer data is split across consecutive items of the same
+// type.
+
+pub const METHOD: u8 = 0x00;
+pub const IDENTIFIER: u8 = 0x01;
+pub const SALT: u8 = 0x02;
+pub const PUBLIC_KEY: u8 = 0x03;
+pub const PROOF: u8 = 0x04;
+pub const ENCRYPTED_DATA: u8 = 0x05;
+pub const STATE: u8 = 0x06;
+pub const ERROR: u8 = 0x07;
+pub const RETRY_DELAY: u8 = 0x08;
+pub const SIGNATURE: u8 = 0x0a;
+pub const PERMISSIONS: u8 = 0x0b;
+pub const SEPARATOR: u8 = 0xff;
+
+// The error codes returned in `ERROR` items.
+
+pub const ERR_UNKNOWN: u8 = 0x01;
+pub const ERR_AUTHENTICATION: u8 = 0x02;
+pub const ERR_BACKOFF: u8 = 0x03;
+pub const ERR_MAX_TRIES: u8 = 0x05;
+pub const ERR_UNAVAILABLE: u8 = 0x06;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Tlv(Vec<(u8, Vec<u8>)>);
+
+impl Tlv {
+    pub fn new() -> Self {
+        Tlv::default()
+    }
+
+    // Adds an item. Items are encoded in the order they're added.
+
+    pub fn add(mut self, ty: u8, data: impl AsRef<[u8]>) -> Self {
+        self.0.push((ty, data.as_ref().to_vec()));
+        self
+    }
+
+    // Returns the data of the first item with the given type.
+
+    pub fn get(&self, ty: u8) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(t, _)| *t == ty)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn get_u8(&self, ty: u8) -> Option<u8> {
+        match self.get(ty)? {
+            [v] => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+
+        for (ty, data) in &self.0 {
+            if data.is_empty() {
+                buf.extend([*ty, 0]);
+            }
+            for chunk in data.chunks(255) {
+                buf.extend([*ty, chunk.len() as u8]);
+                buf.extend(chunk);
+            }
+        }
+        buf
+    }
+
+    // Decodes a buffer. Consecutive items with the same type are
+    // joined. Returns `None` if the buffer is truncated.
+
+    pub fn decode(mut buf: &[u8]) -> Option<Self> {
+        let mut items: Vec<(u8, Vec<u8>)> = vec![];
+        let mut last_len = 0;
+
+        while let [ty, len, rest @ ..] = buf {
+            let len = *len as usize;
+            let data = rest.get(..len)?;
+
+            // An item continues the previous one if it has the same
+            // type and the previous one was full.
+
+            match items.last_mut() {
+                Some((t, v)) if t == ty && last_len == 255 => v.extend(data),
+                _ => items.push((*ty, data.to_vec())),
+            }
+            last_len = len;
+            buf = &rest[len..];
+        }
+
+        buf.is_empty().then_some(Tlv(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tlv() {
+        let long: Vec<u8> = (0..300).map(|v| v as u8).collect();
+        let tlv = Tlv::new()
+            .add(STATE, [1])
+            .add(PUBLIC_KEY, &long)
+            .add(IDENTIFIER, b"abc")
+            .add(SEPARATOR, []);
+        let buf = tlv.encode();
+
+        assert_eq!(&buf[..3], &[STATE, 1, 1]);
+        assert_eq!(&buf[3..5], &[PUBLIC_KEY, 255]);
+        assert_eq!(&buf[260..262], &[PUBLIC_KEY, 45]);
+        assert_eq!(buf.len(), 3 + 2 + 255 + 2 + 45 + 5 + 2);
+
+        let tlv = Tlv::decode(&buf).unwrap();
+
+        assert_eq!(tlv.get_u8(STATE), Some(1));
+        assert_eq!(tlv.get(PUBLIC_KEY), Some(long.as_slice()));
+        assert_eq!(tlv.get(IDENTIFIER), Some(&b"abc"[..]));
+        assert_eq!(tlv.get(SEPARATOR), Some(&[][..]));
+        assert_eq!(tlv.get(ERROR), None);
+
+        // Truncated buffers are rejected.
+
+        assert_eq!(Tlv::decode(&buf[..buf.len() - 3]), None);
+        assert_eq!(Tlv::decode(&[STATE]), None);
+    }
+}
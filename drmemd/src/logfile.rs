@@ -0,0 +1,195 @@
+// Writes the log to a file, for systems where stdout isn't saved. The
+// file is rotated when it gets too big or a new hour or day starts.
+// Rotating renames "drmemd.log" to "drmemd.log.1", after moving the
+// older files up by one, and starts a new, empty file. Files past the
+// configured number to keep are deleted.
+//
+// Problems with the file can't be logged, so they're written to
+// stderr.
+
+use crate::config::{Log, Rotate};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    rotate: Rotate,
+    keep: usize,
+    // Identifies the hour or day the file was started in.
+    period: i64,
+}
+
+fn period(rotate: Rotate, time: &DateTime<Local>) -> i64 {
+    let day = time.num_days_from_ce() as i64;
+
+    match rotate {
+        Rotate::Never => 0,
+        Rotate::Hourly => day * 24 + time.hour() as i64,
+        Rotate::Daily => day,
+    }
+}
+
+// Returns the name of the `n`th rotated file.
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+impl LogFile {
+    // Opens the log file named in the configuration. An existing file
+    // is appended to.
+
+    pub fn open(cfg: &Log) -> io::Result<Self> {
+        let path = cfg.file.clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no log file given")
+        })?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let meta = file.metadata()?;
+
+        // If the file was last written in an earlier hour or day, the
+        // first message rotates it.
+
+        let started = meta
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+
+        Ok(LogFile {
+            path,
+            file,
+            size: meta.len(),
+            max_size: cfg.max_size.map(|v| v * 1024 * 1024),
+            rotate: cfg.rotate,
+            keep: cfg.keep,
+            period: period(cfg.rotate, &started),
+        })
+    }
+
+    fn needs_rotation(&self, now: &DateTime<Local>, len: usize) -> bool {
+        self.size > 0
+            && (self.max_size.is_some_and(|v| self.size + len as u64 > v)
+                || period(self.rotate, now) != self.period)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Drop the oldest file and move the others up by one.
+
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                match fs::rename(
+                    rotated(&self.path, n),
+                    rotated(&self.path, n + 1),
+                ) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(e)
+                    }
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?
+        } else {
+            fs::remove_file(&self.path)?
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = Local::now();
+
+        if self.needs_rotation(&now, buf.len()) {
+            // Keep writing to the current file if it can't be
+            // rotated. The period is updated either way so the
+            // failure is only reported once.
+
+            self.period = period(self.rotate, &now);
+            if let Err(e) = self.rotate() {
+                eprintln!(
+                    "couldn't rotate log file {} -- {}",
+                    self.path.display(),
+                    e
+                )
+            }
+        }
+
+        let len = self.file.write(buf)?;
+
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join("drmem-test-logfile");
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("drmemd.log");
+        let cfg = Log {
+            file: Some(path.clone()),
+            max_size: Some(1),
+            rotate: Rotate::Never,
+            keep: 2,
+            ..Log::default()
+        };
+        let mut log = LogFile::open(&cfg).unwrap();
+        let line = vec![b'x'; 700 * 1024];
+
+        // Each write pushes the file past 1 MiB, so every write after
+        // the first one rotates.
+
+        for _ in 0..4 {
+            log.write_all(&line).unwrap();
+        }
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), line.len() as u64);
+        assert!(rotated(&path, 1).exists());
+        assert!(rotated(&path, 2).exists());
+        assert!(!rotated(&path, 3).exists());
+
+        // Reopening appends to the file.
+
+        let mut log = LogFile::open(&cfg).unwrap();
+
+        assert_eq!(log.size, line.len() as u64);
+        log.write_all(b"x\n").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), line.len() as u64 + 2);
+
+        // A new period rotates the file, too.
+
+        log.rotate = Rotate::Daily;
+        log.period -= 1;
+        log.write_all(b"y\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"y\n");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
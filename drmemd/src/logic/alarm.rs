@@ -0,0 +1,547 @@
+// A logic block can define alarms. An alarm has a condition, which
+// is an expression using the block's inputs. When the condition has
+// been `true` for the alarm's delay, the alarm is raised and a
+// notification is sent to its sink, one of the block's outputs. If
+// the alarm has a repeat interval, the notification is sent again
+// until the condition clears or the alarm is acknowledged. An alarm
+// is acknowledged when its `ack` input becomes `true`.
+//
+// Notifications are JSON objects, sent to the sink as strings, which
+// hold the alarm's name, severity, event, message and the time it
+// was sent. The message is built from a template in which `{NAME}` is
+// replaced with the value of an input or definition.
+
+use drmem_api::{device, Error, Result};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::{compile, config, solar, tod, Output};
+
+// The events reported for an alarm.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Event {
+    Raised,
+    Acknowledged,
+    Cleared,
+}
+
+impl Event {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Event::Raised => "raised",
+            Event::Acknowledged => "acknowledged",
+            Event::Cleared => "cleared",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Normal,
+    Pending(Instant),
+    Active { next: Option<Instant>, acked: bool },
+}
+
+// A piece of a message template.
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Var(usize),
+}
+
+struct Alarm {
+    name: String,
+    severity: config::Severity,
+    cond: compile::Program,
+    message: Vec<Part>,
+    delay: Option<Duration>,
+    repeat: Option<Duration>,
+    ack: Option<usize>,
+    ack_prev: bool,
+    status: Status,
+}
+
+pub struct Alarms {
+    alarms: Vec<Alarm>,
+    sinks: HashMap<usize, (device::Name, Output)>,
+    dry_run: bool,
+}
+
+fn check_interval(alarm: &str, key: &str, v: Option<f64>) -> Result<()> {
+    if v.is_some_and(|v| !(v.is_finite() && v > 0.0)) {
+        Err(Error::ConfigError(format!(
+            "alarm '{}' needs a positive '{}'",
+            alarm, key
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks the parts of the alarms' configuration that don't need the
+// expressions to be compiled.
+
+pub fn validate(
+    cfg: &[config::Alarm],
+    inputs: &HashMap<String, device::Name>,
+    outputs: &HashMap<String, device::Name>,
+) -> Result<()> {
+    for (idx, alarm) in cfg.iter().enumerate() {
+        if alarm.name.is_empty() {
+            return Err(Error::ConfigError("alarms need a name".into()));
+        }
+
+        if cfg[..idx].iter().any(|a| a.name == alarm.name) {
+            return Err(Error::ConfigError(format!(
+                "alarm '{}' is defined more than once",
+                &alarm.name
+            )));
+        }
+
+        if !outputs.contains_key(&alarm.sink) {
+            return Err(Error::ConfigError(format!(
+                "sink '{}' of alarm '{}' isn't defined in 'outputs'",
+                &alarm.sink, &alarm.name
+            )));
+        }
+
+        if let Some(ack) =
+            alarm.ack.as_ref().filter(|k| !inputs.contains_key(*k))
+        {
+            return Err(Error::ConfigError(format!(
+                "ack '{}' of alarm '{}' isn't defined in 'inputs'",
+                ack, &alarm.name
+            )));
+        }
+
+        check_interval(&alarm.name, "delay", alarm.delay)?;
+        check_interval(&alarm.name, "repeat", alarm.repeat)?
+    }
+    Ok(())
+}
+
+// Splits a message template into text and the indices of the
+// variables it uses. `{{` and `}}` are used for literal braces.
+
+fn parse_message(msg: &str, names: &[String]) -> Result<Vec<Part>> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut chars = msg.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{')
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}')
+            }
+            '{' => {
+                let mut name = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => {
+                            return Err(Error::ConfigError(
+                                "message has an unterminated variable".into(),
+                            ))
+                        }
+                    }
+                }
+
+                let idx =
+                    names.iter().position(|n| *n == name).ok_or_else(|| {
+                        Error::ConfigError(format!(
+                            "message uses unknown variable '{}'",
+                            name
+                        ))
+                    })?;
+
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)))
+                }
+                parts.push(Part::Var(idx))
+            }
+            c => text.push(c),
+        }
+    }
+
+    if !text.is_empty() {
+        parts.push(Part::Text(text))
+    }
+    Ok(parts)
+}
+
+impl Alarm {
+    fn message(&self, inputs: &[Option<device::Value>]) -> String {
+        self.message
+            .iter()
+            .map(|part| match part {
+                Part::Text(s) => s.clone(),
+                Part::Var(idx) => match &inputs[*idx] {
+                    Some(device::Value::Str(s)) => s.to_string(),
+                    Some(v) => v.to_string(),
+                    None => "?".into(),
+                },
+            })
+            .collect()
+    }
+
+    // Evaluates the condition and returns the event to report, if
+    // any.
+
+    fn check(
+        &mut self,
+        inputs: &[Option<device::Value>],
+        time: &tod::Info,
+        solar: Option<&solar::Info>,
+        now: Instant,
+    ) -> Option<Event> {
+        let active = compile::eval(&mut self.cond.0, inputs, time, solar)
+            == Some(device::Value::Bool(true));
+
+        // Only the change of the `ack` input to `true` acknowledges
+        // the alarm.
+
+        let acked = self
+            .ack
+            .is_some_and(|idx| inputs[idx] == Some(device::Value::Bool(true)));
+        let ack_now = acked && !self.ack_prev;
+
+        self.ack_prev = acked;
+
+        match self.status {
+            Status::Normal | Status::Pending(_) if !active => {
+                self.status = Status::Normal;
+                None
+            }
+            Status::Normal => match self.delay {
+                Some(delay) => {
+                    self.status = Status::Pending(now + delay);
+                    None
+                }
+                None => Some(self.raise(now)),
+            },
+            Status::Pending(at) => (at <= now).then(|| self.raise(now)),
+            Status::Active { .. } if !active => {
+                self.status = Status::Normal;
+                Some(Event::Cleared)
+            }
+            Status::Active { acked: false, .. } if ack_now => {
+                self.status = Status::Active {
+                    next: None,
+                    acked: true,
+                };
+                Some(Event::Acknowledged)
+            }
+            Status::Active { next: Some(at), .. } if at <= now => {
+                Some(self.raise(now))
+            }
+            Status::Active { .. } => None,
+        }
+    }
+
+    fn raise(&mut self, now: Instant) -> Event {
+        self.status = Status::Active {
+            next: self.repeat.map(|r| now + r),
+            acked: false,
+        };
+        Event::Raised
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match self.status {
+            Status::Pending(at) | Status::Active { next: Some(at), .. } => {
+                Some(at)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Alarms {
+    // Builds the alarms from a configuration that has been checked
+    // by `validate`. Conditions and messages can use the names in
+    // `inputs`. `outputs` holds the names of the block's outputs.
+
+    pub fn compile(
+        cfg: &[config::Alarm],
+        inputs: &[String],
+        outputs: &[String],
+        dry_run: bool,
+    ) -> Result<Alarms> {
+        let env = (inputs, outputs);
+        let alarms = cfg
+            .iter()
+            .map(|a| {
+                let cond = compile::Program::compile(
+                    &format!("{} -> {{{}}}", &a.when, &a.sink),
+                    &env,
+                )
+                .map(compile::Program::optimize)?;
+                let message =
+                    parse_message(&a.message, inputs).map_err(|e| match e {
+                        Error::ConfigError(s) => Error::ConfigError(format!(
+                            "alarm '{}': {}",
+                            &a.name, s
+                        )),
+                        e => e,
+                    })?;
+
+                Ok(Alarm {
+                    name: a.name.clone(),
+                    severity: a.severity,
+                    cond,
+                    message,
+                    delay: a.delay.map(Duration::from_secs_f64),
+                    repeat: a.repeat.map(Duration::from_secs_f64),
+                    ack: a
+                        .ack
+                        .as_ref()
+                        .and_then(|k| inputs.iter().position(|n| n == k)),
+                    ack_prev: false,
+                    status: Status::Normal,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Alarms {
+            alarms,
+            sinks: HashMap::new(),
+            dry_run,
+        })
+    }
+
+    // Returns the indices of the block outputs used as sinks.
+
+    pub fn outputs_used(&self) -> Vec<usize> {
+        let mut result: Vec<usize> =
+            self.alarms.iter().map(|a| a.cond.1).collect();
+
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    // Gives the alarms the channel used to set the block output at
+    // index `idx`.
+
+    pub fn add_output(&mut self, idx: usize, dev: device::Name, out: Output) {
+        self.sinks.insert(idx, (dev, out));
+    }
+
+    pub fn outputs_mut(&mut self) -> impl Iterator<Item = &mut Output> {
+        self.sinks.values_mut().map(|(_, out)| out)
+    }
+
+    pub fn exprs_mut(&mut self) -> impl Iterator<Item = &mut compile::Expr> {
+        self.alarms.iter_mut().map(|a| &mut a.cond.0)
+    }
+
+    // Returns the time-of-day field needed by the conditions, if any.
+
+    pub fn uses_time(&self) -> Option<tod::TimeField> {
+        self.alarms
+            .iter()
+            .filter_map(|a| a.cond.0.uses_time())
+            .min()
+    }
+
+    pub fn uses_solar(&self) -> bool {
+        self.alarms.iter().any(|a| a.cond.0.uses_solar())
+    }
+
+    // Returns the earliest time an alarm's delay, or repeat interval,
+    // expires.
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.alarms.iter().filter_map(Alarm::deadline).min()
+    }
+
+    // Evaluates the alarms and sends notifications for the ones that
+    // changed. Returns the number of notifications that failed.
+
+    pub async fn update(
+        &mut self,
+        inputs: &[Option<device::Value>],
+        time: &tod::Info,
+        solar: Option<&solar::Info>,
+    ) -> usize {
+        let now = Instant::now();
+        let mut notes = vec![];
+
+        for alarm in self.alarms.iter_mut() {
+            if let Some(event) = alarm.check(inputs, time, solar, now) {
+                let msg = alarm.message(inputs);
+
+                match event {
+                    Event::Raised => warn!("alarm '{}': {}", &alarm.name, &msg),
+                    _ => info!("alarm '{}' {}", &alarm.name, event.as_str()),
+                }
+
+                notes.push((
+                    alarm.cond.1,
+                    serde_json::json!({
+                        "name": &alarm.name,
+                        "severity": format!("{:?}", alarm.severity)
+                            .to_lowercase(),
+                        "event": event.as_str(),
+                        "message": msg,
+                        "stamp": chrono::Utc::now().to_rfc3339(),
+                    })
+                    .to_string(),
+                ))
+            }
+        }
+
+        if self.dry_run {
+            for (idx, note) in &notes {
+                if let Some((dev, _)) = self.sinks.get(idx) {
+                    info!("dry run: would send {} to '{}'", note, dev)
+                }
+            }
+            0
+        } else {
+            // Each notification is sent, in order, since several
+            // alarms can share a sink.
+
+            let mut failures = 0;
+
+            for (idx, note) in notes {
+                if let Some((_, out)) = self.sinks.get_mut(&idx) {
+                    if !out.send(device::Value::Str(note.into())).await {
+                        failures += 1
+                    }
+                }
+            }
+            failures
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alarm(when: &str) -> config::Alarm {
+        config::Alarm {
+            name: "sump".into(),
+            when: when.into(),
+            sink: "notify".into(),
+            message: "level is {level}".into(),
+            severity: config::Severity::Critical,
+            delay: None,
+            repeat: None,
+            ack: None,
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let names = [String::from("a"), String::from("b")];
+
+        assert_eq!(
+            parse_message("{a} and {{b}} are {b}", &names).unwrap(),
+            vec![
+                Part::Var(0),
+                Part::Text(" and {b} are ".into()),
+                Part::Var(1)
+            ]
+        );
+        assert!(parse_message("{c}", &names).is_err());
+        assert!(parse_message("{a", &names).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut cfg = alarm("{level} > 10");
+
+        cfg.delay = Some(5.0);
+        cfg.repeat = Some(60.0);
+        cfg.ack = Some("ack".into());
+
+        let inputs = [String::from("level"), String::from("ack")];
+        let outputs = [String::from("notify")];
+        let mut alarms =
+            Alarms::compile(&[cfg], &inputs, &outputs, false).unwrap();
+        let a = &mut alarms.alarms[0];
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let start = Instant::now();
+        let mut check = |level: i32, ack: bool, secs: u64| {
+            let inp = [
+                Some(device::Value::Int(level)),
+                Some(device::Value::Bool(ack)),
+            ];
+
+            a.check(&inp, &time, None, start + Duration::from_secs(secs))
+        };
+
+        // The condition has to hold for the delay.
+
+        assert_eq!(check(20, false, 0), None);
+        assert_eq!(check(5, false, 2), None);
+        assert_eq!(check(20, false, 3), None);
+        assert_eq!(check(20, false, 7), None);
+        assert_eq!(check(20, false, 8), Some(Event::Raised));
+
+        // The notification repeats until it's acknowledged.
+
+        assert_eq!(check(20, false, 30), None);
+        assert_eq!(check(20, false, 68), Some(Event::Raised));
+        assert_eq!(check(20, true, 70), Some(Event::Acknowledged));
+        assert_eq!(check(20, true, 200), None);
+        assert_eq!(check(5, true, 210), Some(Event::Cleared));
+        assert_eq!(check(5, false, 220), None);
+        assert_eq!(
+            a.message(&[Some(device::Value::Int(5)), None]),
+            "level is 5"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let inputs = HashMap::from([(
+            String::from("level"),
+            device::Name::create("sump:level").unwrap(),
+        )]);
+        let outputs = HashMap::from([(
+            String::from("notify"),
+            device::Name::create("notify:phone").unwrap(),
+        )]);
+        let good = alarm("{level} > 10");
+
+        assert!(
+            validate(std::slice::from_ref(&good), &inputs, &outputs).is_ok()
+        );
+        assert!(
+            validate(&[good.clone(), good.clone()], &inputs, &outputs).is_err()
+        );
+
+        let mut cfg = good.clone();
+
+        cfg.sink = "bad".into();
+        assert!(validate(&[cfg], &inputs, &outputs).is_err());
+
+        let mut cfg = good.clone();
+
+        cfg.ack = Some("bad".into());
+        assert!(validate(&[cfg], &inputs, &outputs).is_err());
+
+        for v in [0.0, -1.0, f64::NAN] {
+            let mut cfg = good.clone();
+
+            cfg.repeat = Some(v);
+            assert!(validate(&[cfg], &inputs, &outputs).is_err());
+        }
+    }
+}
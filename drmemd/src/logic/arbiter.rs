@@ -0,0 +1,168 @@
+// When more than one logic block, or a person using a client, sets
+// the same device, the arbiter decides who gets to control it.
+//
+// Each logic block has a priority (0, by default.) When a source
+// sets a device, it claims the device at its priority. A setting is
+// only sent if no other source has claimed the device at a higher
+// priority. Within a priority, the last source to set the device
+// wins. Manual settings always go through and claim the device at
+// priority 0. A logic block's claims are dropped when it stops, so
+// lower priority blocks can take over.
+
+use drmem_api::device;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// The priority used by settings made through a client.
+
+pub const MANUAL_PRIORITY: i32 = 0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Logic(Arc<str>),
+    Manual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claim {
+    pub source: Source,
+    pub priority: i32,
+    pub stamp: SystemTime,
+}
+
+// A handle to the claims on the output devices. Clones refer to the
+// same set of claims.
+
+#[derive(Clone, Default)]
+pub struct Arbiter(Arc<Mutex<HashMap<device::Name, Vec<Claim>>>>);
+
+impl Arbiter {
+    // Records that `source` set the device, replacing its previous
+    // claim.
+
+    fn record(claims: &mut Vec<Claim>, source: Source, priority: i32) {
+        claims.retain(|c| c.source != source);
+        claims.push(Claim {
+            source,
+            priority,
+            stamp: SystemTime::now(),
+        })
+    }
+
+    // Returns `true` if `source` may set the device. If it may, its
+    // claim is recorded.
+
+    pub fn claim(
+        &self,
+        dev: &device::Name,
+        source: &Source,
+        priority: i32,
+    ) -> bool {
+        let mut map = self.0.lock().unwrap();
+        let claims = map.entry(dev.clone()).or_default();
+
+        if claims
+            .iter()
+            .any(|c| c.source != *source && c.priority > priority)
+        {
+            false
+        } else {
+            Arbiter::record(claims, source.clone(), priority);
+            true
+        }
+    }
+
+    // Records a manual setting of the device.
+
+    pub fn manual(&self, dev: &device::Name) {
+        let mut map = self.0.lock().unwrap();
+
+        Arbiter::record(
+            map.entry(dev.clone()).or_default(),
+            Source::Manual,
+            MANUAL_PRIORITY,
+        )
+    }
+
+    // Drops all the claims made by `source`.
+
+    pub fn release(&self, source: &Source) {
+        let mut map = self.0.lock().unwrap();
+
+        map.values_mut()
+            .for_each(|claims| claims.retain(|c| c.source != *source));
+        map.retain(|_, claims| !claims.is_empty())
+    }
+
+    // Returns the source controlling each claimed device: the claim
+    // with the highest priority and, within that priority, the most
+    // recent one.
+
+    pub fn owners(&self) -> Vec<(device::Name, Claim)> {
+        let map = self.0.lock().unwrap();
+        let mut result: Vec<(device::Name, Claim)> = map
+            .iter()
+            .filter_map(|(dev, claims)| {
+                claims
+                    .iter()
+                    .max_by_key(|c| (c.priority, c.stamp))
+                    .map(|c| (dev.clone(), c.clone()))
+            })
+            .collect();
+
+        result.sort_unstable_by_key(|(dev, _)| dev.to_string());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        let arb = Arbiter::default();
+        let dev = device::Name::create("room:light").unwrap();
+        let a = Source::Logic("a".into());
+        let b = Source::Logic("b".into());
+        let owner = |arb: &Arbiter| {
+            arb.owners().into_iter().next().map(|(_, c)| c.source)
+        };
+
+        assert_eq!(owner(&arb), None);
+
+        // Equal priorities: the last writer wins.
+
+        assert!(arb.claim(&dev, &a, 0));
+        assert!(arb.claim(&dev, &b, 0));
+        assert_eq!(owner(&arb), Some(b.clone()));
+        arb.manual(&dev);
+        assert_eq!(owner(&arb), Some(Source::Manual));
+
+        // A higher priority takes over and locks out the others.
+
+        assert!(arb.claim(&dev, &a, 5));
+        assert!(!arb.claim(&dev, &b, 0));
+        assert!(arb.claim(&dev, &a, 5));
+        assert_eq!(owner(&arb), Some(a.clone()));
+
+        // Once the higher priority source is gone, the others get
+        // control back.
+
+        arb.release(&a);
+        assert_eq!(owner(&arb), Some(Source::Manual));
+        assert!(arb.claim(&dev, &b, 0));
+        assert_eq!(owner(&arb), Some(b.clone()));
+
+        // Lower priority blocks yield to manual settings.
+
+        arb.release(&b);
+        arb.manual(&dev);
+        assert!(!arb.claim(&dev, &a, -1));
+        arb.release(&Source::Manual);
+        assert!(arb.claim(&dev, &a, -1));
+        arb.release(&a);
+        assert!(arb.owners().is_empty());
+    }
+}
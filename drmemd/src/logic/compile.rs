@@ -9,6 +9,7 @@
 //     #.##              floating point numbers
 //     "TEXT"            strings
 //     {NAME}            variable named NAME (from config params)
+//     {NAME.age}        seconds since input NAME last changed
 //     #rrggbb or
 //     #name		 RGB color values
 //
@@ -23,6 +24,7 @@
 //     {utc:year}
 //     {utc:DOW}	day of week (Monday = 0, Sunday = 6)
 //     {utc:DOY}	day of year from 0 to 365
+//     {utc:weekend}	true on Saturday and Sunday
 //
 //     {local:second}
 //     {local:minute}
@@ -32,6 +34,10 @@
 //     {local:year}
 //     {local:DOW}	day of week (Monday = 0, Sunday = 6)
 //     {local:DOY}	day of year from 0 to 365
+//     {local:weekend}	true on Saturday and Sunday
+//     {local:holiday}	true if the date is in the configured holidays
+//     {local:offset}	minutes local time is ahead of UTC
+//     {local:dst}	true while daylight saving time is in effect
 //
 // There is a built-in type, "solar", that provides solar position in
 // the sky.
@@ -40,6 +46,10 @@
 //     {solar:az}	azimuth of sun
 //     {solar:ra}	right ascension of sun
 //     {solar:dec}	declination of sun
+//     {solar:sunrise}	local time of sunrise (fractional hours)
+//     {solar:sunset}	local time of sunset (fractional hours)
+//     {solar:noon}	local time of solar noon (fractional hours)
+//     {solar:dark}	true if the sun is below the horizon
 //
 // The token "->" represents assignment. The only item that can be on
 // the right hand side of the arrow is a variable referring to a
@@ -55,13 +65,81 @@
 //     =,<>,<,<=,>,>=    Perform the comparison and return a boolean
 //
 //     +,-,*,/,%         Perform addition, subtraction, multiplication,
-//                       division, and modulo operations
-
+//                       division, and modulo operations (strings can
+//                       be concatenated with +)
+//
+// Functions are called by following their (upper case) name with a
+// comma-separated list of arguments in parentheses. Each call keeps
+// its own state, if the function needs any.
+//
+//     PID(input, setpoint, kp, ki, kd)
+//     PID(input, setpoint, kp, ki, kd, min, max)
+//                       Computes the output of a PID controller
+//                       (limited to 0 - 100, if no limits are given)
+//     HYST(value, on, off)
+//                       Returns a boolean that turns on when value
+//                       reaches `on` and off when it reaches `off`
+//     DELAY_ON(x, secs) Returns `true` after x has been `true` for secs
+//     DELAY_OFF(x, secs)
+//                       Returns `false` after x has been `false` for secs
+//     HOLD(x, secs)     Same as DELAY_OFF; keeps x `true` for secs
+//                       after it was last `true`
+//     DEBOUNCE(x, secs) Returns x after it has held its value for secs
+//     AVG(x, secs)      Returns the average of x over the last secs
+//     MIN(x, secs)      Returns the minimum of x over the last secs
+//     MAX(x, secs)      Returns the maximum of x over the last secs
+//     SUM(x, secs)      Returns the integral of x over the last secs
+//     RATE(x, secs)     Returns the change per second of x over the
+//                       last secs
+//     INTEGRATE(x, reset)
+//                       Accumulates x over time (x * seconds) and
+//                       clears the total when reset becomes true
+//     EWMA(x, alpha)    Returns the exponentially weighted moving
+//                       average of x (0 < alpha <= 1)
+//     RISING(x)         Returns true when x changes from false to true
+//     FALLING(x)        Returns true when x changes from true to false
+//     COUNT(event, reset)
+//                       Counts the rising edges of event and clears
+//                       the count when reset becomes true
+//     LATCH(set, reset) Returns a boolean that is turned on by set and
+//                       off by reset (reset has priority)
+//     RANDOM(lo, hi)    Returns a random number from lo to hi
+//     RANDOM(lo, hi, trigger)
+//                       Same as RANDOM(lo, hi), but the number only
+//                       changes when trigger becomes true
+//     FORMAT(fmt, ...)  Returns fmt with each "{}" replaced by the next
+//                       argument ("{:.N}" shows N decimal places)
+//     CONTAINS(s, sub)  Returns true if string s contains sub
+//     NUMBER(s)         Converts string s to an integer or float
+//     RGB(r, g, b)      Returns a color from components in 0 - 255
+//     HSV(h, s, v)      Returns a color from a hue, in degrees, and a
+//                       saturation and value in 0 - 1
+//     BLEND(c1, c2, t)  Mixes two colors (t = 0 is c1, t = 1 is c2)
+//     DIM(c, k)         Multiplies the brightness of a color by k
+//     CLAMP(x, lo, hi)  Limits x to the range lo - hi
+//     MAP(x, in_lo, in_hi, out_lo, out_hi)
+//                       Scales x from the input range to the output
+//                       range
+//     CONVERT(x, from, to)
+//                       Converts x from the units named by string
+//                       `from` to the units named by `to`
+//     TABLE(x, [(x0, y0), (x1, y1), ...])
+//                       Interpolates x in a table of points
+//     AVG_HIST({NAME}, secs)
+//     MIN_HIST({NAME}, secs)
+//     MAX_HIST({NAME}, secs)
+//     SUM_HIST({NAME}, secs)
+//                       Returns the average, minimum, maximum, or sum
+//                       of the readings of input NAME over the last
+//                       secs, including the stored history
+
+use super::func::Func;
+use super::history;
 use super::solar;
 use super::tod;
 use drmem_api::{device, Error, Result};
 use lrlex::lrlex_mod;
-use lrpar::lrpar_mod;
+use lrpar::{lrpar_mod, LexError, LexParseError, Lexeme, NonStreamingLexer};
 use std::fmt;
 use tracing::error;
 
@@ -80,6 +158,10 @@ pub enum TimeField {
     DoY,
     Month,
     Year,
+    Weekend,
+    Holiday,
+    Offset,
+    Dst,
 }
 
 impl std::fmt::Display for TimeField {
@@ -96,6 +178,10 @@ impl std::fmt::Display for TimeField {
             TimeField::Month => write!(f, "month"),
             TimeField::Year => write!(f, "year"),
             TimeField::DoY => write!(f, "DOY"),
+            TimeField::Weekend => write!(f, "weekend"),
+            TimeField::Holiday => write!(f, "holiday"),
+            TimeField::Offset => write!(f, "offset"),
+            TimeField::Dst => write!(f, "dst"),
         }
     }
 }
@@ -106,6 +192,10 @@ pub enum SolarField {
     Azimuth,
     RightAscension,
     Declination,
+    Sunrise,
+    Sunset,
+    Noon,
+    Dark,
 }
 
 impl std::fmt::Display for SolarField {
@@ -118,14 +208,168 @@ impl std::fmt::Display for SolarField {
             SolarField::Azimuth => write!(f, "az"),
             SolarField::RightAscension => write!(f, "ra"),
             SolarField::Declination => write!(f, "dec"),
+            SolarField::Sunrise => write!(f, "sunrise"),
+            SolarField::Sunset => write!(f, "sunset"),
+            SolarField::Noon => write!(f, "noon"),
+            SolarField::Dark => write!(f, "dark"),
+        }
+    }
+}
+
+// The type of the values computed by an expression. `Any` is used
+// when the type isn't known until the expression is evaluated, like
+// the result of a function or an input that hasn't reported a value.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Bool,
+    Int,
+    Flt,
+    Str,
+    Color,
+    Any,
+}
+
+impl Type {
+    pub fn of(v: &device::Value) -> Type {
+        match v {
+            device::Value::Bool(_) => Type::Bool,
+            device::Value::Int(_) => Type::Int,
+            device::Value::Flt(_) => Type::Flt,
+            device::Value::Str(_) => Type::Str,
+            device::Value::Color(_) => Type::Color,
+        }
+    }
+
+    // Returns `true` if a value of this type can be used where a
+    // value of type `other` is expected.
+
+    pub fn fits(self, other: Type) -> bool {
+        self == other || self == Type::Any || other == Type::Any
+    }
+
+    // Returns the types a value of this type could have when it's
+    // evaluated.
+
+    fn values(self) -> &'static [Type] {
+        match self {
+            Type::Bool => &[Type::Bool],
+            Type::Int => &[Type::Int],
+            Type::Flt => &[Type::Flt],
+            Type::Str => &[Type::Str],
+            Type::Color => &[Type::Color],
+            Type::Any => {
+                &[Type::Bool, Type::Int, Type::Flt, Type::Str, Type::Color]
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Bool => write!(f, "boolean"),
+            Type::Int => write!(f, "integer"),
+            Type::Flt => write!(f, "float"),
+            Type::Str => write!(f, "string"),
+            Type::Color => write!(f, "color"),
+            Type::Any => write!(f, "unknown"),
+        }
+    }
+}
+
+// These functions return the type of an operator's result, given the
+// types of its operands, or `None` if the operator doesn't accept
+// them. They follow the `eval_as_*` functions below.
+
+fn is_number(t: Type) -> bool {
+    matches!(t, Type::Int | Type::Flt)
+}
+
+fn logic_type(a: Type, b: Type) -> Option<Type> {
+    (a == Type::Bool && b == Type::Bool).then_some(Type::Bool)
+}
+
+fn eq_type(a: Type, b: Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Bool, Type::Bool) | (Type::Str, Type::Str) => Some(Type::Bool),
+        _ => (is_number(a) && is_number(b)).then_some(Type::Bool),
+    }
+}
+
+fn order_type(a: Type, b: Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Str, Type::Str) => Some(Type::Bool),
+        _ => (is_number(a) && is_number(b)).then_some(Type::Bool),
+    }
+}
+
+fn arith_type(a: Type, b: Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Int, Type::Int | Type::Bool) | (Type::Bool, Type::Int) => {
+            Some(Type::Int)
+        }
+        (Type::Flt, Type::Flt | Type::Int | Type::Bool)
+        | (Type::Int | Type::Bool, Type::Flt) => Some(Type::Flt),
+        _ => None,
+    }
+}
+
+fn add_type(a: Type, b: Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Str, Type::Str) => Some(Type::Str),
+        _ => arith_type(a, b),
+    }
+}
+
+fn div_type(a: Type, b: Type) -> Option<Type> {
+    match (a, b) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Flt, Type::Flt | Type::Int) | (Type::Int, Type::Flt) => {
+            Some(Type::Flt)
+        }
+        _ => None,
+    }
+}
+
+// Applies one of the functions above to the types of two operands.
+// If an operand's type isn't known, each type it could have is
+// tried. The operands are only rejected if no combination works.
+
+fn combine(
+    a: Type,
+    b: Type,
+    rule: fn(Type, Type) -> Option<Type>,
+) -> Option<Type> {
+    let mut result = None;
+
+    for x in a.values() {
+        for y in b.values() {
+            if let Some(t) = rule(*x, *y) {
+                result = match result {
+                    Some(prev) if prev != t => Some(Type::Any),
+                    _ => Some(t),
+                }
+            }
         }
     }
+    result
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Lit(device::Value),
     Var(usize),
+
+    // Holds the index of the variable containing the age of an
+    // input (the seconds since its value last changed.)
+    Age(usize),
+
+    // Holds an aggregate of an input's recent readings: the kind of
+    // aggregate, the index of the input, the window in seconds and
+    // the latest value, which the logic block updates.
+    Hist(history::Agg, usize, u32, Option<device::Value>),
+
     TimeVal(&'static str, TimeField, fn(&tod::Info) -> device::Value),
     SolarVal(SolarField, fn(&solar::Info) -> device::Value),
 
@@ -146,6 +390,8 @@ pub enum Expr {
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
     Rem(Box<Expr>, Box<Expr>),
+
+    Func(Func, Vec<Expr>),
 }
 
 impl Expr {
@@ -153,8 +399,11 @@ impl Expr {
         match self {
             Expr::Lit(_)
             | Expr::Var(_)
+            | Expr::Age(_)
+            | Expr::Hist(..)
             | Expr::TimeVal(..)
-            | Expr::SolarVal(..) => 10,
+            | Expr::SolarVal(..)
+            | Expr::Func(..) => 10,
             Expr::Not(_) => 9,
             Expr::Mul(_, _) | Expr::Div(_, _) | Expr::Rem(_, _) => 5,
             Expr::Add(_, _) | Expr::Sub(_, _) => 4,
@@ -175,16 +424,38 @@ impl Expr {
             Expr::TimeVal(_, TimeField::Minute, _) => {
                 Some(tod::TimeField::Minute)
             }
-            Expr::TimeVal(_, TimeField::Hour, _) => Some(tod::TimeField::Hour),
+            // The offset from UTC only changes at the start of an
+            // hour.
+            Expr::TimeVal(_, TimeField::Hour, _)
+            | Expr::TimeVal(_, TimeField::Offset, _)
+            | Expr::TimeVal(_, TimeField::Dst, _) => Some(tod::TimeField::Hour),
             Expr::TimeVal(_, TimeField::Day, _)
             | Expr::TimeVal(_, TimeField::DoW, _)
-            | Expr::TimeVal(_, TimeField::DoY, _) => Some(tod::TimeField::Day),
+            | Expr::TimeVal(_, TimeField::DoY, _)
+            | Expr::TimeVal(_, TimeField::Weekend, _)
+            | Expr::TimeVal(_, TimeField::Holiday, _) => {
+                Some(tod::TimeField::Day)
+            }
             Expr::TimeVal(_, TimeField::Month, _) => {
                 Some(tod::TimeField::Month)
             }
             Expr::TimeVal(_, TimeField::Year, _) => Some(tod::TimeField::Year),
+
+            // Ages increase with time, so expressions using them
+            // are evaluated every second.
+            Expr::Age(_) => Some(tod::TimeField::Second),
+
+            // Old readings leave the window of an aggregate as time
+            // passes, so expressions using them are evaluated every
+            // minute.
+            Expr::Hist(..) => Some(tod::TimeField::Minute),
             Expr::SolarVal(..) | Expr::Lit(_) | Expr::Var(_) => None,
             Expr::Not(e) => e.uses_time(),
+            Expr::Func(func, args) => args
+                .iter()
+                .filter_map(Expr::uses_time)
+                .chain(func.uses_time())
+                .min(),
             Expr::Mul(a, b)
             | Expr::Div(a, b)
             | Expr::Rem(a, b)
@@ -209,8 +480,13 @@ impl Expr {
     pub fn uses_solar(&self) -> bool {
         match self {
             Expr::SolarVal(..) => true,
-            Expr::TimeVal(..) | Expr::Lit(_) | Expr::Var(_) => false,
+            Expr::TimeVal(..)
+            | Expr::Lit(_)
+            | Expr::Var(_)
+            | Expr::Age(_)
+            | Expr::Hist(..) => false,
             Expr::Not(e) => e.uses_solar(),
+            Expr::Func(_, args) => args.iter().any(Expr::uses_solar),
             Expr::Mul(a, b)
             | Expr::Div(a, b)
             | Expr::Rem(a, b)
@@ -224,6 +500,219 @@ impl Expr {
         }
     }
 
+    // Traverses an expression and adds the saved state of each
+    // function call to `state`. Every call adds an entry, so
+    // `restore_state` can match the entries to the calls.
+
+    pub fn save_state(&self, state: &mut Vec<Option<f64>>) {
+        match self {
+            Expr::Func(func, args) => {
+                state.push(func.save());
+                args.iter().for_each(|e| e.save_state(state))
+            }
+            Expr::SolarVal(..)
+            | Expr::TimeVal(..)
+            | Expr::Lit(_)
+            | Expr::Var(_)
+            | Expr::Age(_)
+            | Expr::Hist(..) => (),
+            Expr::Not(e) => e.save_state(state),
+            Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Lt(a, b)
+            | Expr::LtEq(a, b)
+            | Expr::Eq(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                a.save_state(state);
+                b.save_state(state)
+            }
+        }
+    }
+
+    // Restores the state of the function calls in an expression
+    // using entries created by `save_state`.
+
+    pub fn restore_state(
+        &mut self,
+        state: &mut impl Iterator<Item = Option<f64>>,
+    ) {
+        match self {
+            Expr::Func(func, args) => {
+                if let Some(Some(v)) = state.next() {
+                    func.restore(v)
+                }
+                args.iter_mut().for_each(|e| e.restore_state(state))
+            }
+            Expr::SolarVal(..)
+            | Expr::TimeVal(..)
+            | Expr::Lit(_)
+            | Expr::Var(_)
+            | Expr::Age(_)
+            | Expr::Hist(..) => (),
+            Expr::Not(e) => e.restore_state(state),
+            Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Lt(a, b)
+            | Expr::LtEq(a, b)
+            | Expr::Eq(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                a.restore_state(state);
+                b.restore_state(state)
+            }
+        }
+    }
+
+    // Traverses an expression and adds the input, and window, of
+    // each history aggregate to `used`.
+
+    pub fn history_used(&self, used: &mut Vec<(usize, u32)>) {
+        match self {
+            Expr::Hist(_, idx, secs, _) => used.push((*idx, *secs)),
+            Expr::SolarVal(..)
+            | Expr::TimeVal(..)
+            | Expr::Lit(_)
+            | Expr::Var(_)
+            | Expr::Age(_) => (),
+            Expr::Not(e) => e.history_used(used),
+            Expr::Func(_, args) => {
+                args.iter().for_each(|e| e.history_used(used))
+            }
+            Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Lt(a, b)
+            | Expr::LtEq(a, b)
+            | Expr::Eq(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                a.history_used(used);
+                b.history_used(used)
+            }
+        }
+    }
+
+    // Traverses an expression and sets the value of each history
+    // aggregate to the one computed by `f`.
+
+    pub fn update_history(
+        &mut self,
+        f: &impl Fn(history::Agg, usize, u32) -> Option<device::Value>,
+    ) {
+        match self {
+            Expr::Hist(agg, idx, secs, value) => *value = f(*agg, *idx, *secs),
+            Expr::SolarVal(..)
+            | Expr::TimeVal(..)
+            | Expr::Lit(_)
+            | Expr::Var(_)
+            | Expr::Age(_) => (),
+            Expr::Not(e) => e.update_history(f),
+            Expr::Func(_, args) => {
+                args.iter_mut().for_each(|e| e.update_history(f))
+            }
+            Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Lt(a, b)
+            | Expr::LtEq(a, b)
+            | Expr::Eq(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => {
+                a.update_history(f);
+                b.update_history(f)
+            }
+        }
+    }
+
+    // Traverses an expression and returns `true` if it uses the
+    // variable at index `idx`.
+
+    pub fn uses_var(&self, idx: usize) -> bool {
+        match self {
+            Expr::Var(v) | Expr::Age(v) | Expr::Hist(_, v, ..) => *v == idx,
+            Expr::SolarVal(..) | Expr::TimeVal(..) | Expr::Lit(_) => false,
+            Expr::Not(e) => e.uses_var(idx),
+            Expr::Func(_, args) => args.iter().any(|e| e.uses_var(idx)),
+            Expr::Mul(a, b)
+            | Expr::Div(a, b)
+            | Expr::Rem(a, b)
+            | Expr::Add(a, b)
+            | Expr::Sub(a, b)
+            | Expr::Lt(a, b)
+            | Expr::LtEq(a, b)
+            | Expr::Eq(a, b)
+            | Expr::And(a, b)
+            | Expr::Or(a, b) => a.uses_var(idx) || b.uses_var(idx),
+        }
+    }
+
+    // Determines the type of the values computed by the expression.
+    // `vars` holds the type of each variable. If an operator is
+    // given values it can't use, an error describing it is returned.
+
+    pub fn check(&self, vars: &[Type]) -> std::result::Result<Type, String> {
+        let binary = |op: &str, a: &Expr, b: &Expr, rule| {
+            let (a, b) = (a.check(vars)?, b.check(vars)?);
+
+            combine(a, b, rule).ok_or_else(|| {
+                format!("{} can't be used with {} and {} values", op, a, b)
+            })
+        };
+
+        match self {
+            Expr::Lit(v) => Ok(Type::of(v)),
+            Expr::Var(n) => Ok(vars.get(*n).copied().unwrap_or(Type::Any)),
+            Expr::Age(_) => Ok(Type::Int),
+            Expr::Hist(..) => Ok(Type::Flt),
+            Expr::TimeVal(
+                _,
+                TimeField::Weekend | TimeField::Holiday | TimeField::Dst,
+                _,
+            )
+            | Expr::SolarVal(SolarField::Dark, _) => Ok(Type::Bool),
+            Expr::TimeVal(..) => Ok(Type::Int),
+            Expr::SolarVal(..) => Ok(Type::Flt),
+            Expr::Not(e) => {
+                let t = e.check(vars)?;
+
+                combine(t, Type::Bool, logic_type).ok_or_else(|| {
+                    format!("'not' can't be used with {} values", t)
+                })
+            }
+            Expr::And(a, b) => binary("'and'", a, b, logic_type),
+            Expr::Or(a, b) => binary("'or'", a, b, logic_type),
+            Expr::Eq(a, b) => binary("'=' or '<>'", a, b, eq_type),
+            Expr::Lt(a, b) => binary("'<' or '>'", a, b, order_type),
+            Expr::LtEq(a, b) => binary("'<=' or '>='", a, b, order_type),
+            Expr::Add(a, b) => binary("'+'", a, b, add_type),
+            Expr::Sub(a, b) => binary("'-'", a, b, arith_type),
+            Expr::Mul(a, b) => binary("'*'", a, b, arith_type),
+            Expr::Div(a, b) => binary("'/'", a, b, div_type),
+            Expr::Rem(a, b) => binary("'%'", a, b, div_type),
+
+            // Functions check their arguments when they're
+            // evaluated, so only the arguments' own operators are
+            // checked here.
+            Expr::Func(_, args) => {
+                for arg in args {
+                    arg.check(vars)?;
+                }
+                Ok(Type::Any)
+            }
+        }
+    }
+
     fn fmt_subexpr(&self, e: &Expr, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let my_prec = self.precedence();
 
@@ -240,6 +729,10 @@ impl fmt::Display for Expr {
         match self {
             Expr::Lit(v) => write!(f, "{}", &v),
             Expr::Var(v) => write!(f, "inp[{}]", &v),
+            Expr::Age(v) => write!(f, "age[{}]", &v),
+            Expr::Hist(agg, v, secs, _) => {
+                write!(f, "{}(inp[{}], {})", agg.name(), &v, secs)
+            }
 
             Expr::TimeVal(cat, fld, _) => write!(f, "{{{}:{}}}", cat, fld),
 
@@ -309,6 +802,27 @@ impl fmt::Display for Expr {
                 write!(f, " % ")?;
                 self.fmt_subexpr(b, f)
             }
+
+            Expr::Func(func, args) => {
+                write!(f, "{}(", func)?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                if let Func::Table(points) = func {
+                    write!(f, ", [")?;
+                    for (idx, (x, y)) in points.iter().enumerate() {
+                        if idx > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "({:?}, {:?})", x, y)?;
+                    }
+                    write!(f, "]")?;
+                }
+                write!(f, ")")
+            }
         }
     }
 }
@@ -336,7 +850,17 @@ impl Program {
 
         res.unwrap_or_else(|| {
             let res = errs.iter().fold(s.to_owned(), |mut acc, e| {
-                acc.push_str(&format!("\n    {}", &e));
+                let span = match e {
+                    LexParseError::LexError(e) => e.span(),
+                    LexParseError::ParseError(e) => e.lexeme().span(),
+                };
+                let ((line, col), _) = lexer.line_col(span);
+
+                acc.push_str(&format!("\n    line {}, column {}: ", line, col));
+                match lexer.span_str(span) {
+                    "" => acc.push_str("unexpected end of expression"),
+                    tok => acc.push_str(&format!("unexpected '{}'", tok)),
+                }
                 acc
             });
 
@@ -357,7 +881,7 @@ impl fmt::Display for Program {
 // indicating what the error was.
 
 pub fn eval(
-    e: &Expr,
+    e: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -366,33 +890,59 @@ pub fn eval(
         // Literals hold actual `device::Values`, so simply return it.
         Expr::Lit(v) => Some(v.clone()),
 
-        Expr::Var(n) => eval_as_var(*n, inp),
+        Expr::Var(n) | Expr::Age(n) => eval_as_var(*n, inp),
+
+        Expr::Hist(.., v) => v.clone(),
 
         Expr::TimeVal(_, _, f) => Some(f(time)),
 
         Expr::SolarVal(_, f) => solar.map(f),
 
-        Expr::Not(ref e) => eval_as_not_expr(e, inp, time, solar),
+        Expr::Not(ref mut e) => eval_as_not_expr(e, inp, time, solar),
+
+        Expr::Or(ref mut a, ref mut b) => {
+            eval_as_or_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Or(ref a, ref b) => eval_as_or_expr(a, b, inp, time, solar),
+        Expr::And(ref mut a, ref mut b) => {
+            eval_as_and_expr(a, b, inp, time, solar)
+        }
 
-        Expr::And(ref a, ref b) => eval_as_and_expr(a, b, inp, time, solar),
+        Expr::Eq(ref mut a, ref mut b) => {
+            eval_as_eq_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Eq(ref a, ref b) => eval_as_eq_expr(a, b, inp, time, solar),
+        Expr::Lt(ref mut a, ref mut b) => {
+            eval_as_lt_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Lt(ref a, ref b) => eval_as_lt_expr(a, b, inp, time, solar),
+        Expr::LtEq(ref mut a, ref mut b) => {
+            eval_as_lteq_expr(a, b, inp, time, solar)
+        }
 
-        Expr::LtEq(ref a, ref b) => eval_as_lteq_expr(a, b, inp, time, solar),
+        Expr::Add(ref mut a, ref mut b) => {
+            eval_as_add_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Add(ref a, ref b) => eval_as_add_expr(a, b, inp, time, solar),
+        Expr::Sub(ref mut a, ref mut b) => {
+            eval_as_sub_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Sub(ref a, ref b) => eval_as_sub_expr(a, b, inp, time, solar),
+        Expr::Mul(ref mut a, ref mut b) => {
+            eval_as_mul_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Mul(ref a, ref b) => eval_as_mul_expr(a, b, inp, time, solar),
+        Expr::Div(ref mut a, ref mut b) => {
+            eval_as_div_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Div(ref a, ref b) => eval_as_div_expr(a, b, inp, time, solar),
+        Expr::Rem(ref mut a, ref mut b) => {
+            eval_as_rem_expr(a, b, inp, time, solar)
+        }
 
-        Expr::Rem(ref a, ref b) => eval_as_rem_expr(a, b, inp, time, solar),
+        Expr::Func(ref mut func, ref mut args) => {
+            eval_as_func(func, args, inp, time, solar)
+        }
     }
 }
 
@@ -409,7 +959,7 @@ fn eval_as_var(
 // booleans as values and simply complements the value.
 
 fn eval_as_not_expr(
-    e: &Expr,
+    e: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -427,8 +977,8 @@ fn eval_as_not_expr(
 // OR expressions. If the first subexpression is `true`, the second
 // subexpression isn't evaluated.
 fn eval_as_or_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -461,8 +1011,8 @@ fn eval_as_or_expr(
 // AND expressions. If the first subexpression is `false`, the second
 // subexpression isn't evaluated.
 fn eval_as_and_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -494,8 +1044,8 @@ fn eval_as_and_expr(
 
 // EQ expressions. Both expressions must be of the same type.
 fn eval_as_eq_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -529,8 +1079,8 @@ fn eval_as_eq_expr(
 
 // LT expressions. Both expressions must be of the same type.
 fn eval_as_lt_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -561,8 +1111,8 @@ fn eval_as_lt_expr(
 
 // LT_EQ expressions. Both expressions must be of the same type.
 fn eval_as_lteq_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -593,8 +1143,8 @@ fn eval_as_lteq_expr(
 
 // ADD expressions.
 fn eval_as_add_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -624,6 +1174,9 @@ fn eval_as_add_expr(
         (Some(device::Value::Flt(a)), Some(device::Value::Int(b))) => {
             Some(device::Value::Flt(a + b as f64))
         }
+        (Some(device::Value::Str(a)), Some(device::Value::Str(b))) => {
+            Some(device::Value::Str(format!("{}{}", a, b).into()))
+        }
         (Some(a), Some(b)) => {
             error!("cannot add {} and {} types together", &a, &b);
             None
@@ -634,8 +1187,8 @@ fn eval_as_add_expr(
 
 // SUB expressions.
 fn eval_as_sub_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -675,8 +1228,8 @@ fn eval_as_sub_expr(
 
 // MUL expressions.
 fn eval_as_mul_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -716,8 +1269,8 @@ fn eval_as_mul_expr(
 
 // DIV expressions.
 fn eval_as_div_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -753,8 +1306,8 @@ fn eval_as_div_expr(
 
 // REM expressions.
 fn eval_as_rem_expr(
-    a: &Expr,
-    b: &Expr,
+    a: &mut Expr,
+    b: &mut Expr,
     inp: &[Option<device::Value>],
     time: &tod::Info,
     solar: Option<&solar::Info>,
@@ -784,6 +1337,21 @@ fn eval_as_rem_expr(
     }
 }
 
+// Function calls. All the arguments are evaluated and their values
+// are handed to the function, which may update its internal state.
+fn eval_as_func(
+    func: &mut Func,
+    args: &mut [Expr],
+    inp: &[Option<device::Value>],
+    time: &tod::Info,
+    solar: Option<&solar::Info>,
+) -> Option<device::Value> {
+    let args: Vec<Option<device::Value>> =
+        args.iter_mut().map(|e| eval(e, inp, time, solar)).collect();
+
+    func.eval(&args, time)
+}
+
 // This function takes an expression and tries to reduce it.
 
 pub fn optimize(e: Expr) -> Expr {
@@ -836,6 +1404,11 @@ pub fn optimize(e: Expr) -> Expr {
             }
         }
 
+        // Optimize each argument of a function call.
+        Expr::Func(func, args) => {
+            Expr::Func(func, args.into_iter().map(optimize).collect())
+        }
+
         _ => e,
     }
 }
@@ -849,7 +1422,7 @@ mod tests {
 
     fn to_expr(expr: &str) -> Expr {
         let env: Env = (
-            &[String::from("a"), String::from("b")],
+            &[String::from("a"), String::from("b"), String::from("a.age")],
             &[String::from("c")],
         );
 
@@ -888,10 +1461,21 @@ mod tests {
         assert!(Program::compile("{local:year} -> {bulb}", &env).is_ok());
         assert!(Program::compile("{local:DOW} -> {bulb}", &env).is_ok());
         assert!(Program::compile("{local:DOY} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{utc:weekend} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{local:weekend} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{local:holiday} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{local:offset} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{local:dst} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{utc:dst} -> {bulb}", &env).is_err());
+        assert!(Program::compile("{utc:holiday} -> {bulb}", &env).is_err());
         assert!(Program::compile("{solar:alt} -> {bulb}", &env).is_ok());
         assert!(Program::compile("{solar:az} -> {bulb}", &env).is_ok());
         assert!(Program::compile("{solar:ra} -> {bulb}", &env).is_ok());
         assert!(Program::compile("{solar:dec} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{solar:sunrise} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{solar:sunset} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{solar:noon} -> {bulb}", &env).is_ok());
+        assert!(Program::compile("{solar:dark} -> {bulb}", &env).is_ok());
 
         // Don't allow bad categories or fields.
 
@@ -899,6 +1483,65 @@ mod tests {
         assert!(Program::compile("{utc:bad} -> {bulb}", &env).is_err());
         assert!(Program::compile("{local:bad} -> {bulb}", &env).is_err());
 
+        // Functions must exist and have the correct number of
+        // arguments.
+
+        assert!(Program::compile("PID() -> {bulb}", &env).is_err());
+        assert!(Program::compile("PID(1, 2, 3, 4) -> {bulb}", &env).is_err());
+        assert!(Program::compile("pid(1, 2, 3, 4, 5) -> {bulb}", &env).is_err());
+        assert!(Program::compile("BAD(1) -> {bulb}", &env).is_err());
+        assert!(
+            Program::compile("PID({on_time}, 1, 2, 3, 4) -> {bulb}", &env)
+                .is_ok()
+        );
+        assert!(Program::compile(
+            "PID({on_time}, 1, 2, 3, 4, -10, 10) -> {bulb}",
+            &env
+        )
+        .is_ok());
+        assert!(Program::compile(
+            "{on_time} > RANDOM(0, 60, {local:hour} = 12) -> {bulb}",
+            &env
+        )
+        .is_ok());
+        assert!(Program::compile("RANDOM(0) -> {bulb}", &env).is_err());
+        assert!(Program::compile(
+            "FORMAT(\"{}: {:.1}\", {switch}, {on_time}) -> {bulb}",
+            &env
+        )
+        .is_ok());
+        assert!(Program::compile("FORMAT() -> {bulb}", &env).is_err());
+
+        // Tables of points are only accepted as the last argument of
+        // TABLE. The points must be numbers.
+
+        assert!(Program::compile(
+            "TABLE({on_time}, [(0, 0), (10, 25.5), (20, 30)]) -> {bulb}",
+            &env
+        )
+        .is_ok());
+        assert!(Program::compile("TABLE({on_time}) -> {bulb}", &env).is_err());
+        assert!(
+            Program::compile("TABLE([(0, 0), (1, 1)], 1) -> {bulb}", &env)
+                .is_err()
+        );
+        assert!(Program::compile(
+            "TABLE({on_time}, [(0, 0), ({switch}, 1)]) -> {bulb}",
+            &env
+        )
+        .is_err());
+        assert!(Program::compile(
+            "CLAMP({on_time}, 0, [(0, 0), (1, 1)]) -> {bulb}",
+            &env
+        )
+        .is_err());
+        assert!(Program::compile(
+            "DIM(BLEND(#blue, #red, {on_time} / 30.0), \
+             1.0 - 0.5 * ({local:hour} >= 22)) -> {bulb}",
+            &env
+        )
+        .is_ok());
+
         // Don't allow whitespace.
 
         assert!(Program::compile("{ switch} -> {bulb}", &env).is_err());
@@ -908,6 +1551,52 @@ mod tests {
         assert!(Program::compile("{utc: second} -> {bulb}", &env).is_err());
         assert!(Program::compile("{utc:second } -> {bulb}", &env).is_err());
 
+        // Inputs have an age. Other fields, and the ages of names
+        // that aren't inputs, are errors.
+
+        let age_env: Env = (
+            &[String::from("door"), String::from("door.age")],
+            &[String::from("bulb")],
+        );
+
+        assert_eq!(
+            Program::compile("{door.age} > 900 -> {bulb}", &age_env),
+            Ok(Program(
+                Expr::Lt(
+                    Box::new(Expr::Lit(device::Value::Int(900))),
+                    Box::new(Expr::Age(1))
+                ),
+                0
+            ))
+        );
+        assert!(Program::compile("{door.size} -> {bulb}", &age_env).is_err());
+        assert!(Program::compile("{lamp.age} -> {bulb}", &age_env).is_err());
+        assert!(Program::compile("{door. age} -> {bulb}", &age_env).is_err());
+
+        // History aggregates need a variable and a positive, integer
+        // number of seconds.
+
+        assert_eq!(
+            Program::compile("SUM_HIST({switch}, 86400) < 5 -> {bulb}", &env),
+            Ok(Program(
+                Expr::Lt(
+                    Box::new(Expr::Hist(history::Agg::Sum, 0, 86400, None)),
+                    Box::new(Expr::Lit(device::Value::Int(5)))
+                ),
+                0
+            ))
+        );
+        assert!(Program::compile("AVG_HIST({switch}) -> {bulb}", &env).is_err());
+        assert!(
+            Program::compile("AVG_HIST({switch}, 0) -> {bulb}", &env).is_err()
+        );
+        assert!(Program::compile("AVG_HIST({switch}, 1.5) -> {bulb}", &env)
+            .is_err());
+        assert!(
+            Program::compile("AVG_HIST({switch} + 1, 60) -> {bulb}", &env)
+                .is_err()
+        );
+
         // Test proper compilations.
 
         assert_eq!(
@@ -1100,17 +1789,18 @@ mod tests {
     fn test_eval_not_expr() {
         const TRUE: device::Value = device::Value::Bool(true);
         const FALSE: device::Value = device::Value::Bool(false);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         // Test for uninitialized and initialized variables.
 
         assert_eq!(
-            eval(&Expr::Not(Box::new(Expr::Var(0))), &[None], &time, None),
+            eval(&mut Expr::Not(Box::new(Expr::Var(0))), &[None], &time, None),
             None
         );
         assert_eq!(
             eval(
-                &Expr::Not(Box::new(Expr::Var(0))),
+                &mut Expr::Not(Box::new(Expr::Var(0))),
                 &[Some(device::Value::Bool(true))],
                 &time,
                 None
@@ -1121,11 +1811,11 @@ mod tests {
         // Test literal values.
 
         assert_eq!(
-            eval(&Expr::Not(Box::new(Expr::Lit(FALSE))), &[], &time, None),
+            eval(&mut Expr::Not(Box::new(Expr::Lit(FALSE))), &[], &time, None),
             Some(TRUE)
         );
         assert_eq!(
-            eval(&Expr::Not(Box::new(Expr::Lit(TRUE))), &[], &time, None),
+            eval(&mut Expr::Not(Box::new(Expr::Lit(TRUE))), &[], &time, None),
             Some(FALSE)
         );
 
@@ -1133,7 +1823,7 @@ mod tests {
 
         assert_eq!(
             eval(
-                &Expr::Not(Box::new(Expr::Lit(device::Value::Int(1)))),
+                &mut Expr::Not(Box::new(Expr::Lit(device::Value::Int(1)))),
                 &[],
                 &time,
                 None
@@ -1147,13 +1837,14 @@ mod tests {
         const TRUE: device::Value = device::Value::Bool(true);
         const FALSE: device::Value = device::Value::Bool(false);
         const ONE: device::Value = device::Value::Int(1);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         // Test uninitialized and initialized variables.
 
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), Some(FALSE)],
                 &time,
                 None
@@ -1162,7 +1853,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), Some(TRUE)],
                 &time,
                 None
@@ -1171,7 +1862,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(TRUE), Some(FALSE)],
                 &time,
                 None
@@ -1180,7 +1871,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(TRUE), None],
                 &time,
                 None
@@ -1189,7 +1880,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), None],
                 &time,
                 None
@@ -1198,7 +1889,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[None, Some(TRUE)],
                 &time,
                 None
@@ -1207,7 +1898,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::Or(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[None, Some(FALSE)],
                 &time,
                 None
@@ -1219,7 +1910,7 @@ mod tests {
 
         assert_eq!(
             eval(
-                &Expr::Or(
+                &mut Expr::Or(
                     Box::new(Expr::Lit(FALSE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1231,7 +1922,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(
+                &mut Expr::Or(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1243,7 +1934,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(
+                &mut Expr::Or(
                     Box::new(Expr::Lit(FALSE)),
                     Box::new(Expr::Lit(TRUE))
                 ),
@@ -1255,7 +1946,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(TRUE))),
+                &mut Expr::Or(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(TRUE))
+                ),
                 &[],
                 &time,
                 None
@@ -1267,7 +1961,10 @@ mod tests {
 
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TRUE))),
+                &mut Expr::Or(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TRUE))
+                ),
                 &[],
                 &time,
                 None
@@ -1276,7 +1973,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Lit(FALSE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Or(
+                    Box::new(Expr::Lit(FALSE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1289,7 +1989,10 @@ mod tests {
         // `false`.
         assert_eq!(
             eval(
-                &Expr::Or(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Or(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1303,13 +2006,14 @@ mod tests {
         const TRUE: device::Value = device::Value::Bool(true);
         const FALSE: device::Value = device::Value::Bool(false);
         const ONE: device::Value = device::Value::Int(1);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         // Test uninitialized and initialized variables.
 
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), Some(FALSE)],
                 &time,
                 None
@@ -1318,7 +2022,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), Some(TRUE)],
                 &time,
                 None
@@ -1327,7 +2031,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(TRUE), Some(FALSE)],
                 &time,
                 None
@@ -1336,7 +2040,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(TRUE), Some(TRUE)],
                 &time,
                 None
@@ -1345,7 +2049,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(TRUE), None],
                 &time,
                 None
@@ -1354,7 +2058,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[Some(FALSE), None],
                 &time,
                 None
@@ -1363,7 +2067,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[None, Some(TRUE)],
                 &time,
                 None
@@ -1372,7 +2076,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
+                &mut Expr::And(Box::new(Expr::Var(0)), Box::new(Expr::Var(1))),
                 &[None, Some(FALSE)],
                 &time,
                 None
@@ -1384,7 +2088,7 @@ mod tests {
 
         assert_eq!(
             eval(
-                &Expr::And(
+                &mut Expr::And(
                     Box::new(Expr::Lit(FALSE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1396,7 +2100,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(
+                &mut Expr::And(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1408,7 +2112,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(
+                &mut Expr::And(
                     Box::new(Expr::Lit(FALSE)),
                     Box::new(Expr::Lit(TRUE))
                 ),
@@ -1420,7 +2124,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(
+                &mut Expr::And(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(TRUE))
                 ),
@@ -1432,7 +2136,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TRUE))),
+                &mut Expr::And(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TRUE))
+                ),
                 &[],
                 &time,
                 None
@@ -1445,7 +2152,7 @@ mod tests {
         // `true`.
         assert_eq!(
             eval(
-                &Expr::And(
+                &mut Expr::And(
                     Box::new(Expr::Lit(FALSE)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -1457,7 +2164,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::And(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::And(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1473,11 +2183,15 @@ mod tests {
         const ONE: device::Value = device::Value::Int(1);
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Eq(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Eq(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1486,7 +2200,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::Eq(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -1495,7 +2212,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(
+                &mut Expr::Eq(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1507,7 +2224,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(
+                &mut Expr::Eq(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -1519,7 +2236,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(FALSE))),
+                &mut Expr::Eq(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(FALSE))
+                ),
                 &[],
                 &time,
                 None
@@ -1528,7 +2248,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(
+                &mut Expr::Eq(
                     Box::new(Expr::Lit(device::Value::Str("same".into()))),
                     Box::new(Expr::Lit(device::Value::Str("same".into())))
                 ),
@@ -1540,7 +2260,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Eq(
+                &mut Expr::Eq(
                     Box::new(Expr::Lit(device::Value::Str("same".into()))),
                     Box::new(Expr::Lit(device::Value::Str("not same".into())))
                 ),
@@ -1559,11 +2279,15 @@ mod tests {
         const ONE: device::Value = device::Value::Int(1);
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Lt(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Lt(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1572,7 +2296,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::Lt(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -1581,7 +2308,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::Lt(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -1590,7 +2320,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(FALSE))),
+                &mut Expr::Lt(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(FALSE))
+                ),
                 &[],
                 &time,
                 None
@@ -1599,7 +2332,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(
+                &mut Expr::Lt(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1611,7 +2344,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(
+                &mut Expr::Lt(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1623,7 +2356,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(
+                &mut Expr::Lt(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(TWO))
                 ),
@@ -1635,7 +2368,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(
+                &mut Expr::Lt(
                     Box::new(Expr::Lit(device::Value::Str("abc".into()))),
                     Box::new(Expr::Lit(device::Value::Str("abc".into())))
                 ),
@@ -1647,7 +2380,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Lt(
+                &mut Expr::Lt(
                     Box::new(Expr::Lit(device::Value::Str("abc".into()))),
                     Box::new(Expr::Lit(device::Value::Str("abcd".into())))
                 ),
@@ -1666,11 +2399,15 @@ mod tests {
         const ONE: device::Value = device::Value::Int(1);
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::LtEq(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::LtEq(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1679,7 +2416,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::LtEq(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -1688,7 +2428,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::LtEq(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -1697,7 +2440,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1709,7 +2452,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1721,7 +2464,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1733,7 +2476,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(TWO))
                 ),
@@ -1745,7 +2488,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(device::Value::Str("abcd".into()))),
                     Box::new(Expr::Lit(device::Value::Str("abc".into())))
                 ),
@@ -1757,7 +2500,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(device::Value::Str("abc".into()))),
                     Box::new(Expr::Lit(device::Value::Str("abc".into())))
                 ),
@@ -1769,7 +2512,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::LtEq(
+                &mut Expr::LtEq(
                     Box::new(Expr::Lit(device::Value::Str("abc".into()))),
                     Box::new(Expr::Lit(device::Value::Str("abcd".into())))
                 ),
@@ -1789,11 +2532,15 @@ mod tests {
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
         const FP_TWO: device::Value = device::Value::Flt(2.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Add(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Add(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1802,7 +2549,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Add(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1811,7 +2561,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1823,7 +2573,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1835,7 +2585,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1847,7 +2597,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1859,7 +2609,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -1871,7 +2621,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Add(
+                &mut Expr::Add(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1881,6 +2631,30 @@ mod tests {
             ),
             Some(device::Value::Flt(3.0))
         );
+        assert_eq!(
+            eval(
+                &mut Expr::Add(
+                    Box::new(Expr::Lit(device::Value::Str("abc".into()))),
+                    Box::new(Expr::Lit(device::Value::Str("def".into())))
+                ),
+                &[],
+                &time,
+                None
+            ),
+            Some(device::Value::Str("abcdef".into()))
+        );
+        assert_eq!(
+            eval(
+                &mut Expr::Add(
+                    Box::new(Expr::Lit(device::Value::Str("abc".into()))),
+                    Box::new(Expr::Lit(ONE))
+                ),
+                &[],
+                &time,
+                None
+            ),
+            None
+        );
     }
 
     #[test]
@@ -1891,11 +2665,15 @@ mod tests {
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
         const FP_TWO: device::Value = device::Value::Flt(2.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Sub(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Sub(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1904,7 +2682,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Sub(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -1913,7 +2694,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1925,7 +2706,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1937,7 +2718,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1949,7 +2730,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -1961,7 +2742,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -1973,7 +2754,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Sub(
+                &mut Expr::Sub(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -1993,11 +2774,15 @@ mod tests {
         const TWO: device::Value = device::Value::Int(2);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
         const FP_TWO: device::Value = device::Value::Flt(2.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Mul(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Mul(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -2006,7 +2791,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Mul(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -2015,7 +2803,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2027,7 +2815,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2039,7 +2827,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2051,7 +2839,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2063,7 +2851,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -2075,7 +2863,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Mul(
+                &mut Expr::Mul(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2097,11 +2885,15 @@ mod tests {
         const FP_ZERO: device::Value = device::Value::Flt(0.0);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
         const FP_TWO: device::Value = device::Value::Flt(2.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Div(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Div(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -2110,7 +2902,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Div(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -2119,7 +2914,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2131,7 +2926,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2143,7 +2938,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2155,7 +2950,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2167,7 +2962,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ONE))
                 ),
@@ -2179,7 +2974,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2191,7 +2986,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ZERO))),
+                &mut Expr::Div(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ZERO))
+                ),
                 &[],
                 &time,
                 None
@@ -2200,7 +2998,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ZERO))
                 ),
@@ -2212,7 +3010,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ZERO))
                 ),
@@ -2224,7 +3022,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Div(
+                &mut Expr::Div(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ZERO))
                 ),
@@ -2247,11 +3045,15 @@ mod tests {
         const FP_ZERO: device::Value = device::Value::Flt(0.0);
         const FP_ONE: device::Value = device::Value::Flt(1.0);
         const FP_TWO: device::Value = device::Value::Flt(2.0);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
         assert_eq!(
             eval(
-                &Expr::Rem(Box::new(Expr::Lit(ONE)), Box::new(Expr::Lit(TWO))),
+                &mut Expr::Rem(
+                    Box::new(Expr::Lit(ONE)),
+                    Box::new(Expr::Lit(TWO))
+                ),
                 &[],
                 &time,
                 None
@@ -2260,7 +3062,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(Box::new(Expr::Lit(TRUE)), Box::new(Expr::Lit(ONE))),
+                &mut Expr::Rem(
+                    Box::new(Expr::Lit(TRUE)),
+                    Box::new(Expr::Lit(ONE))
+                ),
                 &[],
                 &time,
                 None
@@ -2269,7 +3074,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2281,7 +3086,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FP_TWO))
                 ),
@@ -2293,7 +3098,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(TRUE)),
                     Box::new(Expr::Lit(FP_ONE))
                 ),
@@ -2305,7 +3110,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(FALSE))
                 ),
@@ -2317,7 +3122,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(FP_ONE)),
                     Box::new(Expr::Lit(TWO))
                 ),
@@ -2329,7 +3134,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(ONE)),
                     Box::new(Expr::Lit(FP_TWO))
                 ),
@@ -2341,7 +3146,10 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(Box::new(Expr::Lit(TWO)), Box::new(Expr::Lit(ZERO))),
+                &mut Expr::Rem(
+                    Box::new(Expr::Lit(TWO)),
+                    Box::new(Expr::Lit(ZERO))
+                ),
                 &[],
                 &time,
                 None
@@ -2350,7 +3158,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(FP_ZERO))
                 ),
@@ -2362,7 +3170,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(ZERO))
                 ),
@@ -2374,7 +3182,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(FP_TWO)),
                     Box::new(Expr::Lit(FP_ZERO))
                 ),
@@ -2386,7 +3194,7 @@ mod tests {
         );
         assert_eq!(
             eval(
-                &Expr::Rem(
+                &mut Expr::Rem(
                     Box::new(Expr::Lit(TWO)),
                     Box::new(Expr::Lit(NEG_ONE))
                 ),
@@ -2401,9 +3209,10 @@ mod tests {
     #[test]
     fn test_eval() {
         const FALSE: device::Value = device::Value::Bool(false);
-        let time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
 
-        assert_eq!(eval(&Expr::Lit(FALSE), &[], &time, None), Some(FALSE));
+        assert_eq!(eval(&mut Expr::Lit(FALSE), &[], &time, None), Some(FALSE));
     }
 
     // This function tests the optimizations that can be done on an
@@ -2671,6 +3480,10 @@ mod tests {
                 "4 < inp[0] * (3 + inp[1]) -> out[1]",
             ),
             ("{utc:second} -> {c}", "{utc:second} -> out[1]"),
+            (
+                "PID({a}, {b} + 1, 1, 0.5, 0) -> {c}",
+                "PID(inp[0], inp[1] + 1, 1, 0.5, 0) -> out[1]",
+            ),
             ("{utc:minute} -> {c}", "{utc:minute} -> out[1]"),
             ("{utc:hour} -> {c}", "{utc:hour} -> out[1]"),
             ("{utc:day} -> {c}", "{utc:day} -> out[1]"),
@@ -2686,6 +3499,10 @@ mod tests {
             ("{local:year} -> {c}", "{local:year} -> out[1]"),
             ("{local:DOW} -> {c}", "{local:DOW} -> out[1]"),
             ("{local:DOY} -> {c}", "{local:DOY} -> out[1]"),
+            (
+                "TABLE({a}, [(0, 1), (2.5, -3)]) -> {c}",
+                "TABLE(inp[0], [(0.0, 1.0), (2.5, -3.0)]) -> out[1]",
+            ),
         ];
 
         for (in_val, out_val) in TESTS {
@@ -2708,9 +3525,9 @@ mod tests {
     ) -> Option<device::Value> {
         let env: Env = (&[], &[String::from("a")]);
         let expr = format!("{} -> {{a}}", expr);
-        let prog = Program::compile(&expr, &env).unwrap();
+        let mut prog = Program::compile(&expr, &env).unwrap();
 
-        eval(&prog.0, &[], &time, solar)
+        eval(&mut prog.0, &[], time, solar)
     }
 
     #[test]
@@ -2725,7 +3542,8 @@ mod tests {
             chrono::Local
                 .with_ymd_and_hms(2001, 6, 7, 8, 9, 10)
                 .single()
-                .unwrap(),
+                .unwrap()
+                .fixed_offset(),
         ));
 
         let solar = Arc::new(solar::SolarInfo {
@@ -2733,6 +3551,9 @@ mod tests {
             azimuth: 2.0,
             right_ascension: 3.0,
             declination: 4.0,
+            sunrise: 6.0,
+            sunset: 18.0,
+            noon: 12.0,
         });
 
         assert_eq!(evaluate("1 / 0", &time, None), None);
@@ -2856,6 +3677,22 @@ mod tests {
             evaluate("{solar:dec}", &time, Some(&solar)),
             Some(device::Value::Flt(4.0))
         );
+        assert_eq!(
+            evaluate("{solar:sunrise}", &time, Some(&solar)),
+            Some(device::Value::Flt(6.0))
+        );
+        assert_eq!(
+            evaluate("{solar:sunset}", &time, Some(&solar)),
+            Some(device::Value::Flt(18.0))
+        );
+        assert_eq!(
+            evaluate("{solar:noon}", &time, Some(&solar)),
+            Some(device::Value::Flt(12.0))
+        );
+        assert_eq!(
+            evaluate("{solar:dark}", &time, Some(&solar)),
+            Some(device::Value::Bool(false))
+        );
     }
 
     #[test]
@@ -2870,6 +3707,10 @@ mod tests {
             ("#green", None),
             ("\"test\"", None),
             ("{solar:alt}", None),
+            // Ages change with time.
+            ("{a.age}", Some(tod::TimeField::Second)),
+            ("{a.age} > 60 and {b}", Some(tod::TimeField::Second)),
+            ("SUM_HIST({a}, 3600)", Some(tod::TimeField::Minute)),
             // Make sure the time values return the proper field.
             ("{utc:second}", Some(tod::TimeField::Second)),
             ("{utc:minute}", Some(tod::TimeField::Minute)),
@@ -2887,6 +3728,10 @@ mod tests {
             ("{local:DOY}", Some(tod::TimeField::Day)),
             ("{local:month}", Some(tod::TimeField::Month)),
             ("{local:year}", Some(tod::TimeField::Year)),
+            ("{local:weekend}", Some(tod::TimeField::Day)),
+            ("{local:holiday}", Some(tod::TimeField::Day)),
+            ("{local:offset}", Some(tod::TimeField::Hour)),
+            ("{local:dst}", Some(tod::TimeField::Hour)),
             // Now test more complicated expressions to make sure each
             // subtree is correctly compared.
             ("not (2 > 3)", None),
@@ -2895,6 +3740,17 @@ mod tests {
             ("2 + {utc:second}", Some(tod::TimeField::Second)),
             ("{local:hour} + {utc:minute}", Some(tod::TimeField::Minute)),
             ("{local:minute} + {utc:day}", Some(tod::TimeField::Minute)),
+            // Functions that need to be evaluated periodically
+            // request the time.
+            ("PID({a}, 1, 1, 0, 0)", Some(tod::TimeField::Second)),
+            ("HYST({a}, 18, 20)", None),
+            ("RISING({a})", None),
+            ("DELAY_ON({a}, 10)", Some(tod::TimeField::Second)),
+            ("DELAY_OFF({a}, 10)", Some(tod::TimeField::Second)),
+            ("DEBOUNCE({a}, 10)", Some(tod::TimeField::Second)),
+            ("AVG({a}, 10)", Some(tod::TimeField::Second)),
+            ("MAX({a}, 10)", Some(tod::TimeField::Second)),
+            ("EWMA({a}, 0.5)", None),
         ];
 
         for (expr, result) in DATA {
@@ -2924,6 +3780,8 @@ mod tests {
             ("{solar:dec}", true),
             ("{solar:ra}", true),
             ("{solar:az}", true),
+            ("{solar:sunset}", true),
+            ("{solar:dark}", true),
             // Now test more complicated expressions to make sure each
             // subtree is correctly compared.
             ("not (2 > 3)", false),
@@ -2931,6 +3789,8 @@ mod tests {
             ("{solar:alt} + 2", true),
             ("2 + {solar:az}", true),
             ("{solar:dec} + {solar:az}", true),
+            ("PID({a}, 1, 1, 0, 0)", false),
+            ("PID({solar:alt}, 1, 1, 0, 0)", true),
         ];
 
         for (expr, result) in DATA {
@@ -2942,4 +3802,90 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_check() {
+        // `a` is an integer and `b`'s type isn't known.
+
+        const VARS: &[Type] = &[Type::Int, Type::Any, Type::Int];
+        const GOOD: &[(&str, Type)] = &[
+            ("{a}", Type::Int),
+            ("{b}", Type::Any),
+            ("{a.age}", Type::Int),
+            ("{a} + 1", Type::Int),
+            ("{a} + 1.5", Type::Flt),
+            ("{a} / 2", Type::Int),
+            ("\"x\" + \"y\"", Type::Str),
+            ("{a} > 2.0 and {utc:weekend}", Type::Bool),
+            ("not {solar:dark}", Type::Bool),
+            ("{solar:alt} * 2", Type::Flt),
+            ("{utc:hour} % 12", Type::Int),
+            ("AVG_HIST({a}, 60) < 10", Type::Bool),
+            ("PID({a}, 1, 1, 0, 0)", Type::Any),
+            // An operand of unknown type is accepted if one of its
+            // possible types works.
+            ("{b} and true", Type::Bool),
+            ("{b} + {a}", Type::Any),
+            ("{b} = \"on\"", Type::Bool),
+        ];
+        const BAD: &[&str] = &[
+            "not {a}",
+            "{a} and true",
+            "{a} = \"on\"",
+            "{a} < true",
+            "\"x\" + 1",
+            "\"x\" - \"y\"",
+            "#red * 2",
+            "true / 2",
+            "{b} + #red",
+            "{utc:weekend} > 3",
+            "PID(\"x\" + 1, 1, 1, 0, 0)",
+        ];
+
+        for (expr, result) in GOOD {
+            assert_eq!(
+                to_expr(expr).check(VARS),
+                Ok(*result),
+                "error using {}",
+                expr
+            );
+        }
+
+        for expr in BAD {
+            assert!(to_expr(expr).check(VARS).is_err(), "error using {}", expr);
+        }
+
+        assert_eq!(
+            to_expr("{a} + \"x\"").check(VARS),
+            Err(String::from(
+                "'+' can't be used with integer and string values"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_error_position() {
+        let env: Env = (&[String::from("a")], &[String::from("c")]);
+        let error = |expr: &str| match Program::compile(expr, &env) {
+            Err(Error::ParseError(msg)) => msg,
+            _ => panic!("{} should have failed", expr),
+        };
+
+        // Syntax errors report the offending token.
+
+        assert!(error("{a} + * 2 -> {c}")
+            .ends_with("line 1, column 7: unexpected '*'"));
+        assert!(error("{a} +\n  ) -> {c}")
+            .ends_with("line 2, column 3: unexpected ')'"));
+        assert!(error("{a} + 2").ends_with("unexpected end of expression"));
+
+        // Unknown names report the text where they appear.
+
+        assert_eq!(
+            error("{a} and {b} -> {c}"),
+            "line 1, column 9: variable 'b' is not defined (at '{b}')"
+        );
+        assert!(error("{utc:secs} -> {c}").starts_with("line 1, column 1: "));
+        assert!(error("1 + FOO({a}) -> {c}").starts_with("line 1, column 5: "));
+    }
 }
@@ -0,0 +1,186 @@
+// A logic block with a `prefix` reports how it's running with a set
+// of read-only devices:
+//
+//     eval-count        number of times the block was evaluated
+//     eval-time         duration, in milliseconds, of the last
+//                       evaluation
+//     setting-failures  number of settings rejected by drivers
+//     expr-errors       number of times an expression couldn't compute
+//                       a result even though its inputs had values
+//
+// The counts start at 0 when the block starts. Since a block can be
+// evaluated every second, the devices are updated at most once every
+// `REPORT_INTERVAL`.
+
+use crate::monitor::MAX_HISTORY;
+use drmem_api::{driver, Result};
+use tokio::time::{Duration, Instant};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Devices {
+    evals: driver::ReadOnlyDevice<i32>,
+    duration: driver::ReadOnlyDevice<f64>,
+    failures: driver::ReadOnlyDevice<i32>,
+    errors: driver::ReadOnlyDevice<i32>,
+}
+
+pub struct Diagnostics {
+    evals: i32,
+    duration: Duration,
+    failures: i32,
+    errors: i32,
+    report_at: Instant,
+    pending: bool,
+    devs: Devices,
+}
+
+impl Diagnostics {
+    // Registers the diagnostic devices of a block.
+
+    pub async fn new(reg: &driver::RequestChan) -> Result<Self> {
+        Ok(Diagnostics {
+            evals: 0,
+            duration: Duration::ZERO,
+            failures: 0,
+            errors: 0,
+            report_at: Instant::now(),
+            pending: false,
+            devs: Devices {
+                evals: reg
+                    .add_ro_device("eval-count".parse()?, None, MAX_HISTORY)
+                    .await?,
+                duration: reg
+                    .add_ro_device(
+                        "eval-time".parse()?,
+                        Some("ms"),
+                        MAX_HISTORY,
+                    )
+                    .await?,
+                failures: reg
+                    .add_ro_device(
+                        "setting-failures".parse()?,
+                        None,
+                        MAX_HISTORY,
+                    )
+                    .await?,
+                errors: reg
+                    .add_ro_device("expr-errors".parse()?, None, MAX_HISTORY)
+                    .await?,
+            },
+        })
+    }
+
+    // Records an evaluation of the block.
+
+    fn update(&mut self, duration: Duration, failures: usize, errors: usize) {
+        self.evals = self.evals.saturating_add(1);
+        self.duration = duration;
+        self.failures = self.failures.saturating_add(failures as i32);
+        self.errors = self.errors.saturating_add(errors as i32);
+        self.pending = true
+    }
+
+    pub async fn record(
+        &mut self,
+        duration: Duration,
+        failures: usize,
+        errors: usize,
+    ) {
+        self.update(duration, failures, errors);
+
+        if self.report_at <= Instant::now() {
+            self.report().await
+        }
+    }
+
+    // Returns the time the latest values should be reported, if they
+    // haven't been.
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.then_some(self.report_at)
+    }
+
+    pub async fn report(&mut self) {
+        self.devs.evals.report_update(self.evals).await;
+        self.devs
+            .duration
+            .report_update(self.duration.as_secs_f64() * 1000.0)
+            .await;
+        self.devs.failures.report_update(self.failures).await;
+        self.devs.errors.report_update(self.errors).await;
+        self.pending = false;
+        self.report_at = Instant::now() + REPORT_INTERVAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drmem_api::device;
+    use std::sync::{Arc, Mutex};
+
+    fn recorder<T: Into<device::Value> + Clone>(
+        log: &Arc<Mutex<Vec<device::Value>>>,
+    ) -> driver::ReadOnlyDevice<T> {
+        let log = log.clone();
+
+        driver::ReadOnlyDevice::new(Box::new(move |v| {
+            log.lock().unwrap().push(v);
+            Box::pin(async {})
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut diag = Diagnostics {
+            evals: 0,
+            duration: Duration::ZERO,
+            failures: 0,
+            errors: 0,
+            report_at: Instant::now(),
+            pending: false,
+            devs: Devices {
+                evals: recorder(&log),
+                duration: recorder(&log),
+                failures: recorder(&log),
+                errors: recorder(&log),
+            },
+        };
+
+        assert_eq!(diag.deadline(), None);
+
+        // The first evaluation is reported right away.
+
+        diag.record(Duration::from_millis(2), 1, 0).await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                device::Value::Int(1),
+                device::Value::Flt(2.0),
+                device::Value::Int(1),
+                device::Value::Int(0)
+            ]
+        );
+        assert_eq!(diag.deadline(), None);
+
+        // Later ones wait for the report interval.
+
+        log.lock().unwrap().clear();
+        diag.record(Duration::from_millis(4), 0, 2).await;
+        assert!(log.lock().unwrap().is_empty());
+        assert!(diag.deadline().is_some());
+
+        diag.report().await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                device::Value::Int(2),
+                device::Value::Flt(4.0),
+                device::Value::Int(1),
+                device::Value::Int(2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,2149 @@
+// This module contains the functions that can be called from logic
+// node expressions. Some functions need to keep state between
+// evaluations, so each function call in an expression owns an
+// instance of `Func`, which holds the state for that call.
+
+use super::{tod, units};
+use drmem_api::{device, Error, Result};
+use std::{collections::VecDeque, fmt};
+use tracing::error;
+
+// Holds the state of a PID controller.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pid {
+    prev: Option<(chrono::DateTime<chrono::Utc>, f64)>,
+    integral: f64,
+    output: f64,
+}
+
+impl Pid {
+    // Computes the next output of the controller. The integral term
+    // is only updated when time has advanced since the previous
+    // sample, so evaluations caused by input changes, between
+    // time-of-day ticks, return the previous output.
+    //
+    // The integral term is clamped to the output limits, which
+    // prevents it from winding up while the output is saturated.
+    // The derivative term uses the change in the input, rather than
+    // the change in error, so changing the setpoint doesn't cause a
+    // spike in the output.
+
+    pub fn update(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        input: f64,
+        setpoint: f64,
+        (kp, ki, kd): (f64, f64, f64),
+        (min, max): (f64, f64),
+    ) -> f64 {
+        let error = setpoint - input;
+
+        match self.prev {
+            Some((stamp, _)) if stamp >= now => return self.output,
+            Some((stamp, prev_input)) => {
+                let dt = (now - stamp).num_milliseconds() as f64 / 1000.0;
+
+                self.integral =
+                    (self.integral + ki * error * dt).clamp(min, max);
+                self.output = (kp * error + self.integral
+                    - kd * (input - prev_input) / dt)
+                    .clamp(min, max);
+            }
+            None => self.output = (kp * error + self.integral).clamp(min, max),
+        }
+        self.prev = Some((now, input));
+        self.output
+    }
+}
+
+// Computes the state of a hysteresis block. When `on` is less than
+// `off`, the output turns on when the value drops to `on` and turns
+// off when it rises to `off`. When `on` is greater than `off`, the
+// sense is reversed. Between the thresholds, the output keeps its
+// previous state.
+
+fn hysteresis(state: bool, value: f64, on: f64, off: f64) -> bool {
+    if on < off {
+        if value <= on {
+            true
+        } else if value >= off {
+            false
+        } else {
+            state
+        }
+    } else if value >= on {
+        true
+    } else if value <= off {
+        false
+    } else {
+        state
+    }
+}
+
+// Returns the number of seconds between two timestamps.
+
+fn secs_since(
+    now: chrono::DateTime<chrono::Utc>,
+    stamp: chrono::DateTime<chrono::Utc>,
+) -> f64 {
+    (now - stamp).num_milliseconds() as f64 / 1000.0
+}
+
+// Holds the state of a `DEBOUNCE` call. The output only follows the
+// input after the input has held a new value for the debounce time.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Debounce {
+    output: Option<bool>,
+    pending: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Debounce {
+    pub fn update(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        value: bool,
+        secs: f64,
+    ) -> bool {
+        match self.output {
+            // The first value is used as-is so the output has a
+            // value at startup.
+            None => self.output = Some(value),
+
+            Some(v) if v == value => self.pending = None,
+
+            Some(_) => {
+                let since = *self.pending.get_or_insert(now);
+
+                if secs_since(now, since) >= secs {
+                    self.output = Some(value);
+                    self.pending = None
+                }
+            }
+        }
+        self.output.unwrap_or(value)
+    }
+}
+
+// Returns when a window of `secs` seconds, ending at `now`, starts.
+// Windows reaching back further than `chrono` can represent (a huge
+// or infinite width) start at the earliest time, so they hold every
+// sample.
+
+fn window_start(
+    now: chrono::DateTime<chrono::Utc>,
+    secs: f64,
+) -> chrono::DateTime<chrono::Utc> {
+    chrono::TimeDelta::try_milliseconds((secs * 1000.0) as i64)
+        .and_then(|v| now.checked_sub_signed(v))
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC)
+}
+
+// Holds the recent history of an input for the window functions.
+// The input is treated as a step function; a sample is only added
+// when the value changes. The oldest sample may be older than the
+// window, since it holds the value at the start of the window.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Window {
+    samples: VecDeque<(chrono::DateTime<chrono::Utc>, f64)>,
+}
+
+impl Window {
+    // Adds a sample to the window and drops samples that no longer
+    // affect the window.
+
+    pub fn update(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        value: f64,
+        secs: f64,
+    ) {
+        match self.samples.back_mut() {
+            Some((stamp, v)) if *stamp == now => *v = value,
+            Some((_, v)) if *v == value => (),
+            _ => self.samples.push_back((now, value)),
+        }
+
+        let start = window_start(now, secs);
+
+        while self.samples.len() > 1 && self.samples[1].0 <= start {
+            self.samples.pop_front();
+        }
+    }
+
+    // Returns an iterator of the values in the window along with the
+    // number of seconds each value was held.
+
+    fn segments(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        secs: f64,
+    ) -> impl Iterator<Item = (f64, f64)> + '_ {
+        let start = window_start(now, secs);
+        let ends = self.samples.iter().skip(1).map(|(t, _)| *t).chain([now]);
+
+        self.samples
+            .iter()
+            .zip(ends)
+            .map(move |((t, v), end)| (*v, secs_since(end, start.max(*t))))
+    }
+
+    // Returns the time-weighted average of the values in the window.
+    // If the window holds less than the requested duration, the
+    // average of the available data is returned.
+
+    pub fn average(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        secs: f64,
+    ) -> Option<f64> {
+        let (sum, total) = self
+            .segments(now, secs)
+            .fold((0.0, 0.0), |(sum, total), (v, dt)| {
+                (sum + v * dt, total + dt)
+            });
+
+        if total > 0.0 {
+            Some(sum / total)
+        } else {
+            self.samples.back().map(|(_, v)| *v)
+        }
+    }
+
+    // Returns the smallest value in the window.
+
+    pub fn min(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        secs: f64,
+    ) -> Option<f64> {
+        self.segments(now, secs).map(|(v, _)| v).reduce(f64::min)
+    }
+
+    // Returns the largest value in the window.
+
+    pub fn max(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        secs: f64,
+    ) -> Option<f64> {
+        self.segments(now, secs).map(|(v, _)| v).reduce(f64::max)
+    }
+
+    // Returns the integral of the values in the window (i.e. each
+    // value multiplied by the number of seconds it was held.)
+
+    pub fn sum(&self, now: chrono::DateTime<chrono::Utc>, secs: f64) -> f64 {
+        self.segments(now, secs).map(|(v, dt)| v * dt).sum()
+    }
+
+    // Returns the change, per second, between the value at the start
+    // of the window and the latest value. If the window holds less
+    // than the requested duration, the rate is computed over the
+    // available data.
+
+    pub fn rate(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        secs: f64,
+    ) -> Option<f64> {
+        let (first_stamp, first) = self.samples.front()?;
+        let (_, last) = self.samples.back()?;
+        let start = window_start(now, secs);
+        let span = secs_since(now, start.max(*first_stamp));
+
+        if span > 0.0 {
+            Some((last - first) / span)
+        } else {
+            Some(0.0)
+        }
+    }
+}
+
+// Holds the state of an `INTEGRATE` call. The input is treated as a
+// step function, so each value is multiplied by the time until the
+// next evaluation.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Integrator {
+    prev: Option<(chrono::DateTime<chrono::Utc>, f64)>,
+    total: f64,
+    reset: bool,
+}
+
+impl Integrator {
+    // Adds the previous value, weighted by the time that has passed,
+    // to the total. A rising edge on `reset` clears the total.
+
+    pub fn update(
+        &mut self,
+        now: chrono::DateTime<chrono::Utc>,
+        value: f64,
+        reset: bool,
+    ) -> f64 {
+        if reset && !self.reset {
+            self.total = 0.0;
+        } else if let Some((stamp, v)) = self.prev {
+            self.total += v * secs_since(now, stamp);
+        }
+        self.prev = Some((now, value));
+        self.reset = reset;
+        self.total
+    }
+}
+
+// Holds the state of a `COUNT` call.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Counter {
+    event: Option<bool>,
+    reset: bool,
+    count: i32,
+}
+
+impl Counter {
+    // Increments the count on a rising edge of `event`. A rising
+    // edge on `reset` clears the count and takes priority over an
+    // event in the same evaluation.
+
+    pub fn update(&mut self, event: bool, reset: bool) -> i32 {
+        if reset && !self.reset {
+            self.count = 0;
+        } else if event && self.event == Some(false) {
+            self.count = self.count.saturating_add(1);
+        }
+        self.event = Some(event);
+        self.reset = reset;
+        self.count
+    }
+}
+
+// Holds the state of a `RANDOM` call. Without a trigger, a new value
+// is picked each evaluation. With a trigger, the value is only picked
+// again on the trigger's rising edge.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Random {
+    value: Option<f64>,
+    trigger: bool,
+}
+
+impl Random {
+    pub fn update(&mut self, lo: f64, hi: f64, trigger: Option<bool>) -> f64 {
+        use rand::Rng;
+
+        let pick = match trigger {
+            Some(t) => {
+                let edge = t && !self.trigger;
+
+                self.trigger = t;
+                edge || self.value.is_none()
+            }
+            None => true,
+        };
+
+        match self.value {
+            Some(v) if !pick => v,
+            _ => {
+                let v = if lo < hi {
+                    rand::thread_rng().gen_range(lo..hi)
+                } else {
+                    lo
+                };
+
+                self.value = Some(v);
+                v
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Func {
+    Pid(Pid),
+    Hyst(bool),
+
+    // Holds the time at which the input became `true`.
+    DelayOn(Option<chrono::DateTime<chrono::Utc>>),
+
+    // Holds the last time the input was seen `true`. `HOLD` is
+    // another name for `DELAY_OFF`.
+    DelayOff(Option<chrono::DateTime<chrono::Utc>>),
+    Hold(Option<chrono::DateTime<chrono::Utc>>),
+
+    Debounce(Debounce),
+    Avg(Window),
+    Min(Window),
+    Max(Window),
+    Sum(Window),
+    Rate(Window),
+    Integrate(Integrator),
+
+    // Holds the smoothed value of the input.
+    Ewma(Option<f64>),
+
+    // Hold the previous value of the input.
+    Rising(Option<bool>),
+    Falling(Option<bool>),
+
+    Count(Counter),
+    Latch(bool),
+    Random(Random),
+    Format,
+    Contains,
+    Number,
+    Rgb,
+    Hsv,
+    Blend,
+    Dim,
+    Clamp,
+    Map,
+    Convert,
+
+    // Holds the points of the table, sorted by `x`.
+    Table(Vec<(f64, f64)>),
+}
+
+impl Func {
+    // Looks up a function by name and makes sure it was given the
+    // correct number of arguments.
+
+    pub fn new(name: &str, nargs: usize) -> Result<Func> {
+        match name {
+            "PID" if nargs == 5 || nargs == 7 => Ok(Func::Pid(Pid::default())),
+            "PID" => Err(Error::ParseError(String::from(
+                "PID takes 5 or 7 arguments",
+            ))),
+            "HYST" if nargs == 3 => Ok(Func::Hyst(false)),
+            "HYST" => {
+                Err(Error::ParseError(String::from("HYST takes 3 arguments")))
+            }
+            "DELAY_ON" if nargs == 2 => Ok(Func::DelayOn(None)),
+            "DELAY_OFF" if nargs == 2 => Ok(Func::DelayOff(None)),
+            "HOLD" if nargs == 2 => Ok(Func::Hold(None)),
+            "DEBOUNCE" if nargs == 2 => Ok(Func::Debounce(Debounce::default())),
+            "AVG" if nargs == 2 => Ok(Func::Avg(Window::default())),
+            "MIN" if nargs == 2 => Ok(Func::Min(Window::default())),
+            "MAX" if nargs == 2 => Ok(Func::Max(Window::default())),
+            "SUM" if nargs == 2 => Ok(Func::Sum(Window::default())),
+            "RATE" if nargs == 2 => Ok(Func::Rate(Window::default())),
+            "INTEGRATE" if nargs == 2 => {
+                Ok(Func::Integrate(Integrator::default()))
+            }
+            "EWMA" if nargs == 2 => Ok(Func::Ewma(None)),
+            "RISING" if nargs == 1 => Ok(Func::Rising(None)),
+            "FALLING" if nargs == 1 => Ok(Func::Falling(None)),
+            "COUNT" if nargs == 2 => Ok(Func::Count(Counter::default())),
+            "LATCH" if nargs == 2 => Ok(Func::Latch(false)),
+            "RANDOM" if nargs == 2 || nargs == 3 => {
+                Ok(Func::Random(Random::default()))
+            }
+            "RANDOM" => Err(Error::ParseError(String::from(
+                "RANDOM takes 2 or 3 arguments",
+            ))),
+            "FORMAT" if nargs >= 1 => Ok(Func::Format),
+            "FORMAT" => Err(Error::ParseError(String::from(
+                "FORMAT takes at least 1 argument",
+            ))),
+            "CONTAINS" if nargs == 2 => Ok(Func::Contains),
+            "NUMBER" if nargs == 1 => Ok(Func::Number),
+            "NUMBER" => {
+                Err(Error::ParseError(String::from("NUMBER takes 1 argument")))
+            }
+            "RGB" if nargs == 3 => Ok(Func::Rgb),
+            "HSV" if nargs == 3 => Ok(Func::Hsv),
+            "BLEND" if nargs == 3 => Ok(Func::Blend),
+            "DIM" if nargs == 2 => Ok(Func::Dim),
+            "CLAMP" if nargs == 3 => Ok(Func::Clamp),
+            "MAP" if nargs == 5 => Ok(Func::Map),
+            "CONVERT" if nargs == 3 => Ok(Func::Convert),
+            "TABLE" => Err(Error::ParseError(String::from(
+                "TABLE takes a value and a table of points",
+            ))),
+            "MAP" => {
+                Err(Error::ParseError(String::from("MAP takes 5 arguments")))
+            }
+            "RGB" | "HSV" | "BLEND" | "CLAMP" | "CONVERT" => {
+                Err(Error::ParseError(format!("{} takes 3 arguments", name)))
+            }
+            "COUNT" | "LATCH" | "CONTAINS" | "DIM" | "EWMA" => {
+                Err(Error::ParseError(format!("{} takes 2 arguments", name)))
+            }
+            "RISING" | "FALLING" => {
+                Err(Error::ParseError(format!("{} takes 1 argument", name)))
+            }
+            "DELAY_ON" | "DELAY_OFF" | "HOLD" | "DEBOUNCE" | "AVG" | "MIN"
+            | "MAX" | "SUM" | "RATE" | "INTEGRATE" => {
+                Err(Error::ParseError(format!("{} takes 2 arguments", name)))
+            }
+            _ => Err(Error::ParseError(format!("unknown function '{}'", name))),
+        }
+    }
+
+    // Looks up a function that was given a table of points as its
+    // last argument. `nargs` includes the table. Only `TABLE` takes a
+    // table. It needs at least two points and the `x` values must
+    // increase.
+
+    pub fn with_table(
+        name: &str,
+        nargs: usize,
+        points: Vec<(f64, f64)>,
+    ) -> Result<Func> {
+        match name {
+            "TABLE" if nargs == 2 => {
+                if points.len() < 2 {
+                    Err(Error::ParseError(String::from(
+                        "TABLE needs at least 2 points",
+                    )))
+                } else if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    Err(Error::ParseError(String::from(
+                        "TABLE points must be in increasing order of x",
+                    )))
+                } else {
+                    Ok(Func::Table(points))
+                }
+            }
+            "TABLE" => Func::new(name, nargs),
+            _ => Err(Error::ParseError(format!(
+                "{} doesn't take a table of points",
+                name
+            ))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Func::Pid(_) => "PID",
+            Func::Hyst(_) => "HYST",
+            Func::DelayOn(_) => "DELAY_ON",
+            Func::DelayOff(_) => "DELAY_OFF",
+            Func::Hold(_) => "HOLD",
+            Func::Debounce(_) => "DEBOUNCE",
+            Func::Avg(_) => "AVG",
+            Func::Min(_) => "MIN",
+            Func::Max(_) => "MAX",
+            Func::Sum(_) => "SUM",
+            Func::Rate(_) => "RATE",
+            Func::Integrate(_) => "INTEGRATE",
+            Func::Ewma(_) => "EWMA",
+            Func::Rising(_) => "RISING",
+            Func::Falling(_) => "FALLING",
+            Func::Count(_) => "COUNT",
+            Func::Latch(_) => "LATCH",
+            Func::Random(_) => "RANDOM",
+            Func::Format => "FORMAT",
+            Func::Contains => "CONTAINS",
+            Func::Number => "NUMBER",
+            Func::Rgb => "RGB",
+            Func::Hsv => "HSV",
+            Func::Blend => "BLEND",
+            Func::Dim => "DIM",
+            Func::Clamp => "CLAMP",
+            Func::Map => "MAP",
+            Func::Convert => "CONVERT",
+            Func::Table(_) => "TABLE",
+        }
+    }
+
+    // Returns the time-of-day field needed by the function, if it
+    // has to be evaluated periodically.
+
+    pub fn uses_time(&self) -> Option<tod::TimeField> {
+        match self {
+            Func::Pid(_)
+            | Func::DelayOn(_)
+            | Func::DelayOff(_)
+            | Func::Hold(_)
+            | Func::Debounce(_)
+            | Func::Avg(_)
+            | Func::Min(_)
+            | Func::Max(_)
+            | Func::Sum(_)
+            | Func::Rate(_)
+            | Func::Integrate(_) => Some(tod::TimeField::Second),
+            Func::Hyst(_)
+            | Func::Ewma(_)
+            | Func::Rising(_)
+            | Func::Falling(_)
+            | Func::Count(_)
+            | Func::Latch(_)
+            | Func::Random(_)
+            | Func::Format
+            | Func::Contains
+            | Func::Number
+            | Func::Rgb
+            | Func::Hsv
+            | Func::Blend
+            | Func::Dim
+            | Func::Clamp
+            | Func::Map
+            | Func::Convert
+            | Func::Table(_) => None,
+        }
+    }
+
+    // Returns the part of the function's state that should survive
+    // a restart. Functions that accumulate a value, or hold a mode,
+    // return it. Functions whose state only depends on recent
+    // history return `None`.
+
+    pub fn save(&self) -> Option<f64> {
+        match self {
+            Func::Pid(state) => Some(state.integral),
+            Func::Hyst(state) | Func::Latch(state) => {
+                Some(if *state { 1.0 } else { 0.0 })
+            }
+            Func::Integrate(state) => Some(state.total),
+            Func::Count(state) => Some(state.count as f64),
+            Func::Random(state) => state.value,
+            Func::Ewma(state) => *state,
+            _ => None,
+        }
+    }
+
+    // Restores the state returned by a previous call to `save`.
+
+    pub fn restore(&mut self, value: f64) {
+        match self {
+            Func::Pid(state) => state.integral = value,
+            Func::Hyst(state) | Func::Latch(state) => *state = value != 0.0,
+            Func::Integrate(state) => state.total = value,
+            Func::Count(state) => state.count = value as i32,
+            Func::Random(state) => state.value = Some(value),
+            Func::Ewma(state) => *state = Some(value),
+            _ => (),
+        }
+    }
+
+    // Computes the function's value using the values of its
+    // arguments. If any argument doesn't have a value, the function
+    // doesn't either.
+
+    pub fn eval(
+        &mut self,
+        args: &[Option<device::Value>],
+        time: &tod::Info,
+    ) -> Option<device::Value> {
+        let name = self.name();
+
+        match self {
+            Func::Pid(state) => {
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("PID", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+                let (min, max) = if v.len() == 7 {
+                    (v[5], v[6])
+                } else {
+                    (0.0, 100.0)
+                };
+
+                if min > max {
+                    error!("PID has invalid limits: {} > {}", min, max);
+                    return None;
+                }
+
+                Some(device::Value::Flt(state.update(
+                    time.0,
+                    v[0],
+                    v[1],
+                    (v[2], v[3], v[4]),
+                    (min, max),
+                )))
+            }
+
+            Func::Hyst(state) => {
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("HYST", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+
+                if v[1] == v[2] {
+                    error!("HYST thresholds must be different: {}", v[1]);
+                    return None;
+                }
+
+                *state = hysteresis(*state, v[0], v[1], v[2]);
+                Some(device::Value::Bool(*state))
+            }
+
+            // The output becomes `true` after the input has been
+            // `true` for the delay time. It becomes `false` as soon
+            // as the input does.
+            Func::DelayOn(since) => {
+                let (value, secs) = to_bool_and_secs("DELAY_ON", args)?;
+                let now = time.0;
+
+                if value {
+                    let since = *since.get_or_insert(now);
+
+                    Some(device::Value::Bool(secs_since(now, since) >= secs))
+                } else {
+                    *since = None;
+                    Some(device::Value::Bool(false))
+                }
+            }
+
+            // The output becomes `true` as soon as the input
+            // does. It becomes `false` after the input has been
+            // `false` for the delay time.
+            Func::DelayOff(last) | Func::Hold(last) => {
+                let (value, secs) = to_bool_and_secs(name, args)?;
+                let now = time.0;
+
+                if value {
+                    *last = Some(now);
+                    Some(device::Value::Bool(true))
+                } else {
+                    Some(device::Value::Bool(
+                        last.map(|t| secs_since(now, t) < secs)
+                            .unwrap_or(false),
+                    ))
+                }
+            }
+
+            Func::Debounce(state) => {
+                let (value, secs) = to_bool_and_secs("DEBOUNCE", args)?;
+
+                Some(device::Value::Bool(state.update(time.0, value, secs)))
+            }
+
+            Func::Avg(window) => {
+                let (value, secs) = to_flt_and_window("AVG", args)?;
+
+                window.update(time.0, value, secs);
+                window.average(time.0, secs).map(device::Value::Flt)
+            }
+
+            Func::Min(window) => {
+                let (value, secs) = to_flt_and_window("MIN", args)?;
+
+                window.update(time.0, value, secs);
+                window.min(time.0, secs).map(device::Value::Flt)
+            }
+
+            Func::Max(window) => {
+                let (value, secs) = to_flt_and_window("MAX", args)?;
+
+                window.update(time.0, value, secs);
+                window.max(time.0, secs).map(device::Value::Flt)
+            }
+
+            Func::Sum(window) => {
+                let (value, secs) = to_flt_and_window("SUM", args)?;
+
+                window.update(time.0, value, secs);
+                Some(device::Value::Flt(window.sum(time.0, secs)))
+            }
+
+            Func::Rate(window) => {
+                let (value, secs) = to_flt_and_window("RATE", args)?;
+
+                window.update(time.0, value, secs);
+                window.rate(time.0, secs).map(device::Value::Flt)
+            }
+
+            Func::Integrate(state) => {
+                let value = to_flt("INTEGRATE", args[0].as_ref())?;
+                let reset = match &args[1] {
+                    Some(device::Value::Bool(v)) => *v,
+                    Some(v) => {
+                        error!("INTEGRATE requires a boolean reset: {}", v);
+                        return None;
+                    }
+                    None => false,
+                };
+
+                Some(device::Value::Flt(state.update(time.0, value, reset)))
+            }
+
+            // Smooths the input with an exponentially weighted
+            // moving average. Each evaluation moves the average
+            // `alpha` of the way toward the input, so smaller values
+            // of `alpha` smooth more. The first value is used as-is.
+            Func::Ewma(state) => {
+                let value = to_flt("EWMA", args[0].as_ref())?;
+                let alpha = to_flt("EWMA", args[1].as_ref())?;
+
+                if !(alpha > 0.0 && alpha <= 1.0) {
+                    error!("EWMA requires 0 < alpha <= 1: {}", alpha);
+                    return None;
+                }
+
+                let avg = state.map_or(value, |s| s + alpha * (value - s));
+
+                *state = Some(avg);
+                Some(device::Value::Flt(avg))
+            }
+
+            // The edge functions return `true` for the one evaluation
+            // in which the input changes state. The first value of
+            // the input isn't considered a transition.
+            Func::Rising(prev) => {
+                let value = to_bool("RISING", args[0].as_ref())?;
+                let edge = *prev == Some(false) && value;
+
+                *prev = Some(value);
+                Some(device::Value::Bool(edge))
+            }
+
+            Func::Falling(prev) => {
+                let value = to_bool("FALLING", args[0].as_ref())?;
+                let edge = *prev == Some(true) && !value;
+
+                *prev = Some(value);
+                Some(device::Value::Bool(edge))
+            }
+
+            Func::Count(state) => {
+                let event = to_bool("COUNT", args[0].as_ref())?;
+                let reset = to_bool("COUNT", args[1].as_ref())?;
+
+                Some(device::Value::Int(state.update(event, reset)))
+            }
+
+            // A set/reset latch. `set` turns the output on and `reset`
+            // turns it off. If both are `true`, reset wins. If
+            // neither is, the output keeps its state.
+            Func::Latch(state) => {
+                let set = to_bool("LATCH", args[0].as_ref())?;
+                let reset = to_bool("LATCH", args[1].as_ref())?;
+
+                *state = !reset && (set || *state);
+                Some(device::Value::Bool(*state))
+            }
+
+            // Returns a random number in the range `lo` to `hi`. The
+            // optional third argument is a boolean that picks a new
+            // value when it becomes `true`.
+            Func::Random(state) => {
+                let lo = to_flt("RANDOM", args[0].as_ref())?;
+                let hi = to_flt("RANDOM", args[1].as_ref())?;
+                let trigger = match args.get(2) {
+                    Some(v) => Some(to_bool("RANDOM", v.as_ref())?),
+                    None => None,
+                };
+
+                if lo > hi {
+                    error!("RANDOM has an invalid range: {} > {}", lo, hi);
+                    return None;
+                }
+
+                Some(device::Value::Flt(state.update(lo, hi, trigger)))
+            }
+
+            Func::Format => {
+                let v = args.iter().cloned().collect::<Option<Vec<_>>>()?;
+
+                match &v[0] {
+                    device::Value::Str(fmt) => format(fmt, &v[1..])
+                        .map(|v| device::Value::Str(v.into())),
+                    v => {
+                        error!("FORMAT requires a string format: {}", v);
+                        None
+                    }
+                }
+            }
+
+            Func::Contains => {
+                let s = to_str("CONTAINS", args[0].as_ref())?;
+                let sub = to_str("CONTAINS", args[1].as_ref())?;
+
+                Some(device::Value::Bool(s.contains(sub)))
+            }
+
+            // Converts a string to a number. Strings that look like
+            // integers become integers; everything else is parsed as
+            // a float.
+            Func::Number => {
+                let s = to_str("NUMBER", args[0].as_ref())?.trim();
+
+                if let Ok(v) = s.parse::<i32>() {
+                    Some(device::Value::Int(v))
+                } else if let Ok(v) = s.parse::<f64>() {
+                    Some(device::Value::Flt(v))
+                } else {
+                    error!("NUMBER can't convert \"{}\"", s);
+                    None
+                }
+            }
+
+            // Limits a value to a range. If all the arguments are
+            // integers, so is the result.
+            Func::Clamp => {
+                let int = |v: &Option<device::Value>| match v {
+                    Some(device::Value::Int(v)) => Some(*v),
+                    _ => None,
+                };
+
+                if let (Some(x), Some(lo), Some(hi)) =
+                    (int(&args[0]), int(&args[1]), int(&args[2]))
+                {
+                    if lo > hi {
+                        error!("CLAMP has an invalid range: {} > {}", lo, hi);
+                        return None;
+                    }
+                    return Some(device::Value::Int(x.clamp(lo, hi)));
+                }
+
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("CLAMP", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+
+                if v[1] > v[2] {
+                    error!("CLAMP has an invalid range: {} > {}", v[1], v[2]);
+                    return None;
+                }
+                Some(device::Value::Flt(v[0].clamp(v[1], v[2])))
+            }
+
+            // Linearly maps a value from one range to another. The
+            // result isn't limited to the output range.
+            Func::Map => {
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("MAP", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+
+                if v[1] == v[2] {
+                    error!("MAP requires a non-empty input range: {}", v[1]);
+                    return None;
+                }
+                Some(device::Value::Flt(
+                    v[3] + (v[0] - v[1]) * (v[4] - v[3]) / (v[2] - v[1]),
+                ))
+            }
+
+            // Converts a number from one unit to another.
+            Func::Convert => {
+                let v = to_flt("CONVERT", args[0].as_ref())?;
+                let from = to_str("CONVERT", args[1].as_ref())?;
+                let to = to_str("CONVERT", args[2].as_ref())?;
+
+                if let Some(conv) = units::Conversion::new(from, to) {
+                    Some(device::Value::Flt(conv.apply(v)))
+                } else {
+                    error!("CONVERT can't convert \"{}\" to \"{}\"", from, to);
+                    None
+                }
+            }
+
+            // Looks up `x` in a table of points, interpolating
+            // between them.
+            Func::Table(points) => {
+                let x = to_flt("TABLE", args[0].as_ref())?;
+
+                Some(device::Value::Flt(interpolate(points, x)))
+            }
+
+            // Builds a color from red, green, and blue components,
+            // each in the range 0 - 255.
+            Func::Rgb => {
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("RGB", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+                let c = |v: f64| v.clamp(0.0, 255.0).round() as u8;
+
+                Some(device::Value::Color(palette::LinSrgba::new(
+                    c(v[0]),
+                    c(v[1]),
+                    c(v[2]),
+                    255,
+                )))
+            }
+
+            // Builds a color from a hue, in degrees, and a saturation
+            // and value, each in the range 0 - 1.
+            Func::Hsv => {
+                use palette::{FromColor, WithAlpha};
+
+                let v = args
+                    .iter()
+                    .map(|v| to_flt("HSV", v.as_ref()))
+                    .collect::<Option<Vec<f64>>>()?;
+                let hsv = palette::Hsv::new(
+                    v[0] as f32,
+                    v[1].clamp(0.0, 1.0) as f32,
+                    v[2].clamp(0.0, 1.0) as f32,
+                );
+
+                Some(device::Value::Color(
+                    palette::LinSrgb::from_color(hsv)
+                        .into_format::<u8>()
+                        .with_alpha(255u8),
+                ))
+            }
+
+            // Mixes two colors. A `t` of 0 returns the first color
+            // and 1 returns the second.
+            Func::Blend => {
+                let a = to_color("BLEND", args[0].as_ref())?;
+                let b = to_color("BLEND", args[1].as_ref())?;
+                let t = to_flt("BLEND", args[2].as_ref())?.clamp(0.0, 1.0);
+                let mix = |a: u8, b: u8| {
+                    (a as f64 + (b as f64 - a as f64) * t).round() as u8
+                };
+
+                Some(device::Value::Color(palette::LinSrgba::new(
+                    mix(a.red, b.red),
+                    mix(a.green, b.green),
+                    mix(a.blue, b.blue),
+                    mix(a.alpha, b.alpha),
+                )))
+            }
+
+            // Scales the brightness of a color. The alpha channel
+            // isn't affected.
+            Func::Dim => {
+                let c = to_color("DIM", args[0].as_ref())?;
+                let k = to_flt("DIM", args[1].as_ref())?;
+
+                if k < 0.0 {
+                    error!("DIM requires a non-negative factor: {}", k);
+                    return None;
+                }
+
+                let scale =
+                    |v: u8| (v as f64 * k).clamp(0.0, 255.0).round() as u8;
+
+                Some(device::Value::Color(palette::LinSrgba::new(
+                    scale(c.red),
+                    scale(c.green),
+                    scale(c.blue),
+                    c.alpha,
+                )))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Interpolates `x` in a table of points. Values outside the table
+// use the first or last point.
+
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    match points.iter().position(|(px, _)| x < *px) {
+        Some(0) => points[0].1,
+        Some(idx) => {
+            let ((x0, y0), (x1, y1)) = (points[idx - 1], points[idx]);
+
+            y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+        }
+        None => points[points.len() - 1].1,
+    }
+}
+
+// Converts an argument to a floating point value.
+
+fn to_flt(name: &str, v: Option<&device::Value>) -> Option<f64> {
+    match v {
+        Some(device::Value::Flt(v)) => Some(*v),
+        Some(device::Value::Int(v)) => Some(*v as f64),
+        Some(v) => {
+            error!("{} requires numeric arguments: {}", name, v);
+            None
+        }
+        None => None,
+    }
+}
+
+// Converts the arguments of the time-delay functions. The first is a
+// boolean and the second is a non-negative number of seconds.
+
+fn to_bool_and_secs(
+    name: &str,
+    args: &[Option<device::Value>],
+) -> Option<(bool, f64)> {
+    let secs = to_flt(name, args[1].as_ref())?;
+
+    if secs < 0.0 {
+        error!("{} requires a non-negative delay: {}", name, secs);
+        return None;
+    }
+
+    to_bool(name, args[0].as_ref()).map(|v| (v, secs))
+}
+
+// Converts an argument to a color.
+
+fn to_color(
+    name: &str,
+    v: Option<&device::Value>,
+) -> Option<palette::LinSrgba<u8>> {
+    match v {
+        Some(device::Value::Color(v)) => Some(*v),
+        Some(v) => {
+            error!("{} requires a color argument: {}", name, v);
+            None
+        }
+        None => None,
+    }
+}
+
+// Converts an argument to a string slice.
+
+fn to_str<'a>(name: &str, v: Option<&'a device::Value>) -> Option<&'a str> {
+    match v {
+        Some(device::Value::Str(v)) => Some(v),
+        Some(v) => {
+            error!("{} requires a string argument: {}", name, v);
+            None
+        }
+        None => None,
+    }
+}
+
+// Builds the result of a `FORMAT` call. Each "{}" in the format
+// string is replaced by the next argument. A "{:.N}" is replaced by
+// the next argument, which must be a number, with N decimal places.
+// "{{" and "}}" insert literal braces. The number of placeholders
+// has to match the number of arguments.
+
+fn format(fmt: &str, args: &[device::Value]) -> Option<String> {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut rest = fmt;
+
+    while let Some(idx) = rest.find(['{', '}']) {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            out.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+
+        let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+            error!("FORMAT has an unmatched brace: \"{}\"", fmt);
+            return None;
+        };
+        let Some(arg) = args.next() else {
+            error!("FORMAT needs more arguments: \"{}\"", fmt);
+            return None;
+        };
+
+        match (&rest[1..end], arg) {
+            ("", device::Value::Str(v)) => out.push_str(v),
+            ("", v @ device::Value::Color(_)) => {
+                out.push_str(v.to_string().trim_matches('"'))
+            }
+            ("", v) => write!(out, "{}", v).ok()?,
+            (spec, v) => {
+                let prec = spec.strip_prefix(":.").and_then(|p| p.parse().ok());
+
+                match (prec, to_flt("FORMAT", Some(v))) {
+                    (Some(prec), Some(v)) => {
+                        write!(out, "{:.*}", prec, v).ok()?
+                    }
+                    (None, _) => {
+                        error!("FORMAT has a bad placeholder: {{{}}}", spec);
+                        return None;
+                    }
+                    (_, None) => return None,
+                }
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+
+    if args.next().is_some() {
+        error!("FORMAT has too many arguments: \"{}\"", fmt);
+        return None;
+    }
+
+    out.push_str(rest);
+    Some(out)
+}
+
+// Converts an argument to a boolean value.
+
+fn to_bool(name: &str, v: Option<&device::Value>) -> Option<bool> {
+    match v {
+        Some(device::Value::Bool(v)) => Some(*v),
+        Some(v) => {
+            error!("{} requires a boolean argument: {}", name, v);
+            None
+        }
+        None => None,
+    }
+}
+
+// Converts the arguments of the window functions. The first is a
+// number and the second is the (positive) width of the window, in
+// seconds.
+
+fn to_flt_and_window(
+    name: &str,
+    args: &[Option<device::Value>],
+) -> Option<(f64, f64)> {
+    let value = to_flt(name, args[0].as_ref())?;
+    let secs = to_flt(name, args[1].as_ref())?;
+
+    if secs > 0.0 {
+        Some((value, secs))
+    } else {
+        error!("{} requires a positive window: {}", name, secs);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_func_lookup() {
+        assert!(Func::new("PID", 4).is_err());
+        assert!(Func::new("PID", 5).is_ok());
+        assert!(Func::new("PID", 6).is_err());
+        assert!(Func::new("PID", 7).is_ok());
+        assert!(Func::new("HYST", 2).is_err());
+        assert!(Func::new("HYST", 3).is_ok());
+        assert!(Func::new("DELAY_ON", 1).is_err());
+        assert!(Func::new("DELAY_ON", 2).is_ok());
+        assert!(Func::new("DELAY_OFF", 2).is_ok());
+        assert!(Func::new("HOLD", 1).is_err());
+        assert!(Func::new("HOLD", 2).is_ok());
+        assert!(Func::new("DEBOUNCE", 3).is_err());
+        assert!(Func::new("AVG", 1).is_err());
+        assert!(Func::new("AVG", 2).is_ok());
+        assert!(Func::new("MIN", 2).is_ok());
+        assert!(Func::new("MAX", 3).is_err());
+        assert!(Func::new("SUM", 2).is_ok());
+        assert!(Func::new("RATE", 2).is_ok());
+        assert!(Func::new("INTEGRATE", 1).is_err());
+        assert!(Func::new("INTEGRATE", 2).is_ok());
+        assert!(Func::new("EWMA", 1).is_err());
+        assert!(Func::new("EWMA", 2).is_ok());
+        assert!(Func::new("RISING", 1).is_ok());
+        assert!(Func::new("FALLING", 2).is_err());
+        assert!(Func::new("COUNT", 1).is_err());
+        assert!(Func::new("COUNT", 2).is_ok());
+        assert!(Func::new("LATCH", 2).is_ok());
+        assert!(Func::new("LATCH", 3).is_err());
+        assert!(Func::new("RANDOM", 1).is_err());
+        assert!(Func::new("RANDOM", 2).is_ok());
+        assert!(Func::new("RANDOM", 3).is_ok());
+        assert!(Func::new("RANDOM", 4).is_err());
+        assert!(Func::new("FORMAT", 0).is_err());
+        assert!(Func::new("FORMAT", 4).is_ok());
+        assert!(Func::new("CONTAINS", 2).is_ok());
+        assert!(Func::new("CONTAINS", 1).is_err());
+        assert!(Func::new("NUMBER", 1).is_ok());
+        assert!(Func::new("NUMBER", 2).is_err());
+        assert!(Func::new("RGB", 3).is_ok());
+        assert!(Func::new("HSV", 4).is_err());
+        assert!(Func::new("BLEND", 3).is_ok());
+        assert!(Func::new("DIM", 2).is_ok());
+        assert!(Func::new("DIM", 3).is_err());
+        assert!(Func::new("CLAMP", 3).is_ok());
+        assert!(Func::new("CLAMP", 2).is_err());
+        assert!(Func::new("MAP", 5).is_ok());
+        assert!(Func::new("MAP", 3).is_err());
+        assert!(Func::new("CONVERT", 3).is_ok());
+        assert!(Func::new("CONVERT", 2).is_err());
+        assert!(Func::new("FOO", 1).is_err());
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        // Turn on below 18 and off above 20.
+
+        assert!(hysteresis(false, 17.0, 18.0, 20.0));
+        assert!(hysteresis(false, 18.0, 18.0, 20.0));
+        assert!(!hysteresis(false, 19.0, 18.0, 20.0));
+        assert!(hysteresis(true, 19.0, 18.0, 20.0));
+        assert!(!hysteresis(true, 20.0, 18.0, 20.0));
+        assert!(!hysteresis(true, 21.0, 18.0, 20.0));
+
+        // Turn on above 20 and off below 18.
+
+        assert!(hysteresis(false, 21.0, 20.0, 18.0));
+        assert!(!hysteresis(false, 19.0, 20.0, 18.0));
+        assert!(hysteresis(true, 19.0, 20.0, 18.0));
+        assert!(!hysteresis(true, 17.0, 20.0, 18.0));
+
+        // Check the function's state is kept between evaluations.
+
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let mut func = Func::new("HYST", 3).unwrap();
+        let mut eval = |v: f64| {
+            func.eval(
+                &[
+                    Some(device::Value::Flt(v)),
+                    Some(device::Value::Int(18)),
+                    Some(device::Value::Int(20)),
+                ],
+                &time,
+            )
+        };
+
+        assert_eq!(eval(19.0), Some(device::Value::Bool(false)));
+        assert_eq!(eval(17.5), Some(device::Value::Bool(true)));
+        assert_eq!(eval(19.0), Some(device::Value::Bool(true)));
+        assert_eq!(eval(20.5), Some(device::Value::Bool(false)));
+        assert_eq!(eval(19.0), Some(device::Value::Bool(false)));
+    }
+
+    // Evaluates a time-delay function with a boolean input and a
+    // delay of 5 seconds at an offset from a fixed time.
+
+    fn eval_delay(func: &mut Func, secs: i64, value: bool) -> bool {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let time = std::sync::Arc::new((
+            t0 + Duration::seconds(secs),
+            chrono::Local::now().fixed_offset(),
+        ));
+
+        match func.eval(
+            &[
+                Some(device::Value::Bool(value)),
+                Some(device::Value::Int(5)),
+            ],
+            &time,
+        ) {
+            Some(device::Value::Bool(v)) => v,
+            v => panic!("unexpected result: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_delays() {
+        let mut func = Func::new("DELAY_ON", 2).unwrap();
+
+        assert!(!eval_delay(&mut func, 0, false));
+        assert!(!eval_delay(&mut func, 1, true));
+        assert!(!eval_delay(&mut func, 5, true));
+        assert!(eval_delay(&mut func, 6, true));
+        assert!(!eval_delay(&mut func, 7, false));
+        assert!(!eval_delay(&mut func, 8, true));
+        assert!(!eval_delay(&mut func, 9, false));
+        assert!(!eval_delay(&mut func, 20, true));
+
+        let mut func = Func::new("DELAY_OFF", 2).unwrap();
+
+        assert!(!eval_delay(&mut func, 0, false));
+        assert!(eval_delay(&mut func, 1, true));
+        assert!(eval_delay(&mut func, 2, false));
+        assert!(eval_delay(&mut func, 5, false));
+        assert!(eval_delay(&mut func, 7, true));
+        assert!(eval_delay(&mut func, 8, false));
+        assert!(!eval_delay(&mut func, 12, false));
+
+        // A momentary `true` is stretched by HOLD.
+
+        let mut func = Func::new("HOLD", 2).unwrap();
+
+        assert!(!eval_delay(&mut func, 0, false));
+        assert!(eval_delay(&mut func, 1, true));
+        assert!(eval_delay(&mut func, 1, false));
+        assert!(eval_delay(&mut func, 3, false));
+        assert!(eval_delay(&mut func, 4, true));
+        assert!(eval_delay(&mut func, 8, false));
+        assert!(!eval_delay(&mut func, 9, false));
+
+        // Debounced values follow the initial value but ignore
+        // changes that don't last for the debounce time.
+
+        let mut func = Func::new("DEBOUNCE", 2).unwrap();
+
+        assert!(eval_delay(&mut func, 0, true));
+        assert!(eval_delay(&mut func, 1, false));
+        assert!(eval_delay(&mut func, 3, true));
+        assert!(eval_delay(&mut func, 4, false));
+        assert!(eval_delay(&mut func, 8, false));
+        assert!(!eval_delay(&mut func, 9, false));
+        assert!(!eval_delay(&mut func, 10, true));
+        assert!(!eval_delay(&mut func, 14, true));
+        assert!(eval_delay(&mut func, 15, true));
+    }
+
+    #[test]
+    fn test_window() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let at = |secs| t0 + Duration::seconds(secs);
+        let mut window = Window::default();
+
+        assert_eq!(window.average(t0, 10.0), None);
+
+        // With a single sample, the average is the sample.
+
+        window.update(at(0), 10.0, 10.0);
+        assert_eq!(window.average(at(0), 10.0), Some(10.0));
+        assert_eq!(window.average(at(5), 10.0), Some(10.0));
+
+        // The average is weighted by how long each value was held.
+
+        window.update(at(5), 20.0, 10.0);
+        assert_eq!(window.average(at(10), 10.0), Some(15.0));
+
+        // Repeated values don't add samples. Old samples slide out
+        // of the window.
+
+        window.update(at(11), 20.0, 10.0);
+        assert_eq!(window.samples.len(), 2);
+        window.update(at(15), 20.0, 10.0);
+        assert_eq!(window.samples.len(), 1);
+        assert_eq!(window.average(at(15), 10.0), Some(20.0));
+
+        // Updates at the same timestamp replace the value.
+
+        window.update(at(20), 30.0, 10.0);
+        window.update(at(20), 40.0, 10.0);
+        assert_eq!(window.samples.len(), 2);
+        assert_eq!(window.average(at(25), 10.0), Some(30.0));
+    }
+
+    #[test]
+    fn test_window_min_max_sum() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let at = |secs| t0 + Duration::seconds(secs);
+        let mut window = Window::default();
+
+        assert_eq!(window.min(t0, 10.0), None);
+        assert_eq!(window.max(t0, 10.0), None);
+        assert_eq!(window.sum(t0, 10.0), 0.0);
+
+        window.update(at(0), 10.0, 10.0);
+        window.update(at(2), 30.0, 10.0);
+        window.update(at(4), 20.0, 10.0);
+
+        assert_eq!(window.min(at(5), 10.0), Some(10.0));
+        assert_eq!(window.max(at(5), 10.0), Some(30.0));
+        assert_eq!(window.sum(at(5), 10.0), 100.0);
+
+        // Once the gust slides out of the window, it no longer
+        // affects the maximum.
+
+        window.update(at(14), 20.0, 10.0);
+        assert_eq!(window.min(at(14), 10.0), Some(20.0));
+        assert_eq!(window.max(at(14), 10.0), Some(20.0));
+        assert_eq!(window.sum(at(14), 10.0), 200.0);
+    }
+
+    #[test]
+    fn test_window_rate() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let at = |secs| t0 + Duration::seconds(secs);
+        let mut window = Window::default();
+
+        assert_eq!(window.rate(t0, 10.0), None);
+
+        window.update(at(0), 1000.0, 10.0);
+        assert_eq!(window.rate(at(0), 10.0), Some(0.0));
+
+        // Before the window is full, the rate uses the available
+        // data.
+
+        window.update(at(5), 990.0, 10.0);
+        assert_eq!(window.rate(at(5), 10.0), Some(-2.0));
+
+        // Once full, the rate is computed across the window.
+
+        window.update(at(10), 980.0, 10.0);
+        assert_eq!(window.rate(at(10), 10.0), Some(-2.0));
+        window.update(at(16), 980.0, 10.0);
+        assert_eq!(window.rate(at(16), 10.0), Some(-1.0));
+        window.update(at(20), 980.0, 10.0);
+        assert_eq!(window.rate(at(20), 10.0), Some(0.0));
+    }
+
+    #[test]
+    fn test_window_large() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let at = |secs| t0 + Duration::seconds(secs);
+
+        // Windows wider than `chrono` can represent hold every
+        // sample instead of panicking.
+
+        for secs in [1.0e15, 1.0e300, f64::INFINITY] {
+            let mut window = Window::default();
+
+            window.update(at(0), 10.0, secs);
+            window.update(at(10), 20.0, secs);
+            window.update(at(20), 30.0, secs);
+
+            assert_eq!(window.samples.len(), 3);
+            assert_eq!(window.average(at(30), secs), Some(20.0));
+            assert_eq!(window.min(at(30), secs), Some(10.0));
+            assert_eq!(window.max(at(30), secs), Some(30.0));
+            assert_eq!(window.sum(at(30), secs), 600.0);
+            assert_eq!(window.rate(at(30), secs), Some(2.0 / 3.0));
+        }
+    }
+
+    #[test]
+    fn test_ewma() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let flt = |v: f64| Some(device::Value::Flt(v));
+        let mut func = Func::new("EWMA", 2).unwrap();
+
+        // The first value starts the average. Each following value
+        // moves it `alpha` of the way.
+
+        assert_eq!(func.eval(&[flt(10.0), flt(0.25)], &time), flt(10.0));
+        assert_eq!(func.eval(&[flt(18.0), flt(0.25)], &time), flt(12.0));
+        assert_eq!(
+            func.eval(&[Some(device::Value::Int(20)), flt(0.5)], &time),
+            flt(16.0)
+        );
+        assert_eq!(func.save(), Some(16.0));
+
+        // An alpha of 1 follows the input.
+
+        assert_eq!(func.eval(&[flt(3.0), flt(1.0)], &time), flt(3.0));
+
+        // Bad or missing arguments don't produce a value and don't
+        // disturb the average.
+
+        assert_eq!(func.eval(&[flt(5.0), flt(0.0)], &time), None);
+        assert_eq!(func.eval(&[flt(5.0), flt(1.5)], &time), None);
+        assert_eq!(func.eval(&[None, flt(0.5)], &time), None);
+        assert_eq!(func.eval(&[flt(5.0), flt(0.5)], &time), flt(4.0));
+
+        // A restored average continues from the saved value.
+
+        let mut func = Func::new("EWMA", 2).unwrap();
+
+        func.restore(100.0);
+        assert_eq!(func.eval(&[flt(0.0), flt(0.5)], &time), flt(50.0));
+    }
+
+    #[test]
+    fn test_edges() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let eval = |func: &mut Func, v: bool| {
+            func.eval(&[Some(device::Value::Bool(v))], &time)
+        };
+        let mut rising = Func::new("RISING", 1).unwrap();
+        let mut falling = Func::new("FALLING", 1).unwrap();
+
+        const DATA: &[(bool, bool, bool)] = &[
+            (true, false, false),
+            (true, false, false),
+            (false, false, true),
+            (false, false, false),
+            (true, true, false),
+            (true, false, false),
+            (false, false, true),
+        ];
+
+        for (input, r, f) in DATA {
+            assert_eq!(
+                eval(&mut rising, *input),
+                Some(device::Value::Bool(*r))
+            );
+            assert_eq!(
+                eval(&mut falling, *input),
+                Some(device::Value::Bool(*f))
+            );
+        }
+
+        // Missing or non-boolean inputs don't produce a value.
+
+        assert_eq!(rising.eval(&[None], &time), None);
+        assert_eq!(rising.eval(&[Some(device::Value::Int(1))], &time), None);
+    }
+
+    #[test]
+    fn test_latch() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let mut func = Func::new("LATCH", 2).unwrap();
+        let mut eval = |set: bool, reset: bool| {
+            func.eval(
+                &[
+                    Some(device::Value::Bool(set)),
+                    Some(device::Value::Bool(reset)),
+                ],
+                &time,
+            )
+        };
+
+        const DATA: &[(bool, bool, bool)] = &[
+            (false, false, false),
+            (true, false, true),
+            (false, false, true),
+            (false, true, false),
+            (false, false, false),
+            (true, false, true),
+            (true, true, false),
+            (true, false, true),
+        ];
+
+        for (set, reset, result) in DATA {
+            assert_eq!(eval(*set, *reset), Some(device::Value::Bool(*result)));
+        }
+    }
+
+    #[test]
+    fn test_random() {
+        let mut state = Random::default();
+
+        // Without a trigger, each value is in range.
+
+        for _ in 0..100 {
+            let v = state.update(10.0, 20.0, None);
+
+            assert!((10.0..20.0).contains(&v));
+        }
+
+        // An empty range returns the lower limit.
+
+        assert_eq!(state.update(5.0, 5.0, None), 5.0);
+
+        // With a trigger, the value is only picked on the rising
+        // edge (or if there isn't a value yet.)
+
+        let mut state = Random::default();
+        let v = state.update(0.0, 1000.0, Some(true));
+
+        assert_eq!(state.update(0.0, 1000.0, Some(true)), v);
+        assert_eq!(state.update(0.0, 1000.0, Some(false)), v);
+        assert_eq!(state.update(2000.0, 2000.0, Some(false)), v);
+        assert_eq!(state.update(2000.0, 2000.0, Some(true)), 2000.0);
+        assert_eq!(state.update(0.0, 1000.0, Some(true)), 2000.0);
+
+        // A bad range doesn't produce a value.
+
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let mut func = Func::new("RANDOM", 2).unwrap();
+
+        assert_eq!(
+            func.eval(
+                &[Some(device::Value::Flt(2.0)), Some(device::Value::Flt(1.0))],
+                &time
+            ),
+            None
+        );
+        assert!(func
+            .eval(
+                &[Some(device::Value::Int(1)), Some(device::Value::Int(2))],
+                &time
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn test_strings() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let s = |v: &str| Some(device::Value::Str(v.into()));
+        let mut func = Func::new("FORMAT", 1).unwrap();
+
+        assert_eq!(func.eval(&[s("hello")], &time), s("hello"));
+        assert_eq!(
+            func.eval(
+                &[
+                    s("{}: {:.1}{}"),
+                    s("temp"),
+                    Some(device::Value::Flt(21.46)),
+                    s("C")
+                ],
+                &time
+            ),
+            s("temp: 21.5C")
+        );
+        assert_eq!(
+            func.eval(
+                &[
+                    s("{{{}}} {} {:.2}"),
+                    Some(device::Value::Bool(true)),
+                    Some(device::Value::Int(-3)),
+                    Some(device::Value::Int(2))
+                ],
+                &time
+            ),
+            s("{true} -3 2.00")
+        );
+        assert_eq!(
+            func.eval(
+                &[
+                    s("color {}"),
+                    Some(device::Value::Color(palette::LinSrgba::new(
+                        255, 0, 128, 255
+                    )))
+                ],
+                &time
+            ),
+            s("color #ff0080")
+        );
+        assert_eq!(func.eval(&[s("{}")], &time), None);
+        assert_eq!(func.eval(&[s("x"), s("y")], &time), None);
+        assert_eq!(func.eval(&[s("{")], &time), None);
+        assert_eq!(func.eval(&[s("}")], &time), None);
+        assert_eq!(func.eval(&[s("{:x}"), s("y")], &time), None);
+        assert_eq!(func.eval(&[s("{:.1}"), s("y")], &time), None);
+        assert_eq!(func.eval(&[s("{}"), None], &time), None);
+        assert_eq!(func.eval(&[Some(device::Value::Int(1))], &time), None);
+
+        let mut func = Func::new("CONTAINS", 2).unwrap();
+
+        assert_eq!(
+            func.eval(&[s("Partly Cloudy"), s("Cloud")], &time),
+            Some(device::Value::Bool(true))
+        );
+        assert_eq!(
+            func.eval(&[s("Partly Cloudy"), s("Rain")], &time),
+            Some(device::Value::Bool(false))
+        );
+        assert_eq!(
+            func.eval(
+                &[s("Partly Cloudy"), Some(device::Value::Int(1))],
+                &time
+            ),
+            None
+        );
+
+        let mut func = Func::new("NUMBER", 1).unwrap();
+
+        assert_eq!(
+            func.eval(&[s(" 42 ")], &time),
+            Some(device::Value::Int(42))
+        );
+        assert_eq!(
+            func.eval(&[s("-1.5")], &time),
+            Some(device::Value::Flt(-1.5))
+        );
+        assert_eq!(func.eval(&[s("abc")], &time), None);
+        assert_eq!(func.eval(&[Some(device::Value::Int(1))], &time), None);
+    }
+
+    #[test]
+    fn test_colors() {
+        use palette::LinSrgba;
+
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let color =
+            |r, g, b, a| Some(device::Value::Color(LinSrgba::new(r, g, b, a)));
+        let num = |v: f64| Some(device::Value::Flt(v));
+        let mut func = Func::new("RGB", 3).unwrap();
+
+        assert_eq!(
+            func.eval(
+                &[num(255.0), Some(device::Value::Int(128)), num(-5.0)],
+                &time
+            ),
+            color(255, 128, 0, 255)
+        );
+        assert_eq!(
+            func.eval(&[num(300.0), num(0.0), num(10.4)], &time),
+            color(255, 0, 10, 255)
+        );
+        assert_eq!(
+            func.eval(
+                &[num(0.0), num(0.0), Some(device::Value::Bool(true))],
+                &time
+            ),
+            None
+        );
+
+        let mut func = Func::new("HSV", 3).unwrap();
+
+        assert_eq!(
+            func.eval(&[num(0.0), num(1.0), num(1.0)], &time),
+            color(255, 0, 0, 255)
+        );
+        assert_eq!(
+            func.eval(&[num(240.0), num(1.0), num(1.0)], &time),
+            color(0, 0, 255, 255)
+        );
+        assert_eq!(
+            func.eval(&[num(120.0), num(0.0), num(0.0)], &time),
+            color(0, 0, 0, 255)
+        );
+        assert_eq!(
+            func.eval(&[num(0.0), num(0.0), num(1.0)], &time),
+            color(255, 255, 255, 255)
+        );
+
+        let mut func = Func::new("BLEND", 3).unwrap();
+
+        assert_eq!(
+            func.eval(
+                &[color(0, 0, 255, 255), color(255, 0, 0, 255), num(0.0)],
+                &time
+            ),
+            color(0, 0, 255, 255)
+        );
+        assert_eq!(
+            func.eval(
+                &[color(0, 0, 255, 255), color(255, 0, 0, 255), num(0.25)],
+                &time
+            ),
+            color(64, 0, 191, 255)
+        );
+        assert_eq!(
+            func.eval(
+                &[color(0, 0, 255, 255), color(255, 0, 0, 0), num(2.0)],
+                &time
+            ),
+            color(255, 0, 0, 0)
+        );
+        assert_eq!(
+            func.eval(&[color(0, 0, 255, 255), num(1.0), num(0.5)], &time),
+            None
+        );
+
+        let mut func = Func::new("DIM", 2).unwrap();
+
+        assert_eq!(
+            func.eval(&[color(200, 100, 50, 128), num(0.5)], &time),
+            color(100, 50, 25, 128)
+        );
+        assert_eq!(
+            func.eval(&[color(200, 100, 50, 255), num(2.0)], &time),
+            color(255, 200, 100, 255)
+        );
+        assert_eq!(
+            func.eval(&[color(200, 100, 50, 255), num(-1.0)], &time),
+            None
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let flt = |v: f64| Some(device::Value::Flt(v));
+        let mut func = Func::with_table(
+            "TABLE",
+            2,
+            vec![(0.0, 10.0), (10.0, 20.0), (20.0, 0.0)],
+        )
+        .unwrap();
+
+        // Values between points are interpolated. Values outside the
+        // table use the nearest end.
+
+        assert_eq!(func.eval(&[flt(0.0)], &time), flt(10.0));
+        assert_eq!(func.eval(&[flt(5.0)], &time), flt(15.0));
+        assert_eq!(
+            func.eval(&[Some(device::Value::Int(15))], &time),
+            flt(10.0)
+        );
+        assert_eq!(func.eval(&[flt(20.0)], &time), flt(0.0));
+        assert_eq!(func.eval(&[flt(-5.0)], &time), flt(10.0));
+        assert_eq!(func.eval(&[flt(25.0)], &time), flt(0.0));
+        assert_eq!(func.eval(&[None], &time), None);
+        assert_eq!(func.eval(&[Some(device::Value::Bool(true))], &time), None);
+
+        // Tables need at least two points in increasing order, and
+        // only TABLE takes one.
+
+        assert!(Func::new("TABLE", 2).is_err());
+        assert!(Func::with_table("TABLE", 2, vec![(0.0, 1.0)]).is_err());
+        assert!(
+            Func::with_table("TABLE", 3, vec![(0.0, 1.0), (1.0, 2.0)]).is_err()
+        );
+        assert!(
+            Func::with_table("TABLE", 2, vec![(1.0, 1.0), (1.0, 2.0)]).is_err()
+        );
+        assert!(
+            Func::with_table("TABLE", 2, vec![(2.0, 1.0), (1.0, 2.0)]).is_err()
+        );
+        assert!(
+            Func::with_table("CLAMP", 3, vec![(0.0, 1.0), (1.0, 2.0)]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_clamp_map() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let int = |v: i32| Some(device::Value::Int(v));
+        let flt = |v: f64| Some(device::Value::Flt(v));
+        let mut func = Func::new("CLAMP", 3).unwrap();
+
+        assert_eq!(func.eval(&[int(5), int(0), int(10)], &time), int(5));
+        assert_eq!(func.eval(&[int(-5), int(0), int(10)], &time), int(0));
+        assert_eq!(func.eval(&[int(15), int(0), int(10)], &time), int(10));
+        assert_eq!(func.eval(&[int(5), int(10), int(0)], &time), None);
+        assert_eq!(func.eval(&[flt(5.5), int(0), int(5)], &time), flt(5.0));
+        assert_eq!(
+            func.eval(&[int(-1), flt(-0.5), flt(0.5)], &time),
+            flt(-0.5)
+        );
+        assert_eq!(func.eval(&[flt(0.0), flt(1.0), flt(0.0)], &time), None);
+        assert_eq!(func.eval(&[None, int(0), int(10)], &time), None);
+
+        let mut func = Func::new("MAP", 5).unwrap();
+
+        assert_eq!(
+            func.eval(&[int(0), int(0), int(1023), int(0), int(100)], &time),
+            flt(0.0)
+        );
+        assert_eq!(
+            func.eval(&[int(1023), int(0), int(1023), int(0), int(100)], &time),
+            flt(100.0)
+        );
+        assert_eq!(
+            func.eval(&[flt(25.0), int(0), int(100), int(100), int(0)], &time),
+            flt(75.0)
+        );
+        assert_eq!(
+            func.eval(&[int(200), int(0), int(100), int(0), int(10)], &time),
+            flt(20.0)
+        );
+        assert_eq!(
+            func.eval(&[int(1), int(5), int(5), int(0), int(10)], &time),
+            None
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let s = |v: &str| Some(device::Value::Str(v.into()));
+        let near = |v: Option<device::Value>, expected: f64| match v {
+            Some(device::Value::Flt(v)) => (v - expected).abs() < 1e-9,
+            _ => false,
+        };
+        let mut func = Func::new("CONVERT", 3).unwrap();
+
+        assert!(near(
+            func.eval(
+                &[Some(device::Value::Int(100)), s("°C"), s("°F")],
+                &time
+            ),
+            212.0
+        ));
+        assert!(near(
+            func.eval(
+                &[Some(device::Value::Flt(1.0)), s("in"), s("mm")],
+                &time
+            ),
+            25.4
+        ));
+        assert_eq!(
+            func.eval(
+                &[Some(device::Value::Flt(1.0)), s("in"), s("°F")],
+                &time
+            ),
+            None
+        );
+        assert_eq!(func.eval(&[s("1.0"), s("in"), s("mm")], &time), None);
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let b = |v: bool| Some(device::Value::Bool(v));
+
+        // Functions that don't keep a long-term state don't save
+        // anything.
+
+        assert_eq!(Func::new("RISING", 1).unwrap().save(), None);
+        assert_eq!(Func::new("AVG", 2).unwrap().save(), None);
+        assert_eq!(Func::new("RANDOM", 2).unwrap().save(), None);
+
+        // A restored count continues from the saved value.
+
+        let mut func = Func::new("COUNT", 2).unwrap();
+
+        func.restore(41.0);
+        assert_eq!(func.save(), Some(41.0));
+        assert_eq!(
+            func.eval(&[b(false), b(false)], &time),
+            Some(device::Value::Int(41))
+        );
+        assert_eq!(
+            func.eval(&[b(true), b(false)], &time),
+            Some(device::Value::Int(42))
+        );
+
+        let mut func = Func::new("LATCH", 2).unwrap();
+
+        assert_eq!(func.save(), Some(0.0));
+        func.restore(1.0);
+        assert_eq!(func.eval(&[b(false), b(false)], &time), b(true));
+
+        // A PID controller starts with its saved integral term.
+
+        let mut func = Func::new("PID", 5).unwrap();
+        let flt = |v: f64| Some(device::Value::Flt(v));
+
+        func.restore(25.0);
+        assert_eq!(
+            func.eval(
+                &[flt(20.0), flt(20.0), flt(1.0), flt(1.0), flt(0.0)],
+                &time
+            ),
+            flt(25.0)
+        );
+        assert_eq!(func.save(), Some(25.0));
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut state = Counter::default();
+
+        // The initial value of the event isn't counted.
+
+        assert_eq!(state.update(true, false), 0);
+        assert_eq!(state.update(false, false), 0);
+        assert_eq!(state.update(true, false), 1);
+        assert_eq!(state.update(true, false), 1);
+        assert_eq!(state.update(false, false), 1);
+        assert_eq!(state.update(true, false), 2);
+
+        // The reset clears the count on its rising edge and takes
+        // priority over an event.
+
+        assert_eq!(state.update(false, true), 0);
+        assert_eq!(state.update(true, true), 1);
+        assert_eq!(state.update(false, false), 1);
+        assert_eq!(state.update(true, true), 0);
+    }
+
+    #[test]
+    fn test_integrator() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let at = |secs| t0 + Duration::seconds(secs);
+        let mut state = Integrator::default();
+
+        assert_eq!(state.update(at(0), 100.0, false), 0.0);
+        assert_eq!(state.update(at(1), 100.0, false), 100.0);
+        assert_eq!(state.update(at(1), 50.0, false), 100.0);
+        assert_eq!(state.update(at(3), 50.0, false), 200.0);
+
+        // A rising edge of the reset clears the total. Holding the
+        // reset doesn't stop the accumulation.
+
+        assert_eq!(state.update(at(4), 50.0, true), 0.0);
+        assert_eq!(state.update(at(5), 50.0, true), 50.0);
+        assert_eq!(state.update(at(6), 50.0, false), 100.0);
+        assert_eq!(state.update(at(7), 50.0, true), 0.0);
+    }
+
+    #[test]
+    fn test_pid() {
+        let t0 = chrono::Utc
+            .with_ymd_and_hms(2000, 1, 2, 3, 4, 5)
+            .single()
+            .unwrap();
+        let t1 = t0 + Duration::seconds(1);
+        let t2 = t0 + Duration::seconds(2);
+
+        // Proportional-only control.
+
+        let mut pid = Pid::default();
+
+        assert_eq!(
+            pid.update(t0, 20.0, 25.0, (2.0, 0.0, 0.0), (0.0, 100.0)),
+            10.0
+        );
+        assert_eq!(
+            pid.update(t1, 30.0, 25.0, (2.0, 0.0, 0.0), (0.0, 100.0)),
+            0.0
+        );
+
+        // The integral term accumulates each second. Updates
+        // between ticks return the previous output.
+
+        let mut pid = Pid::default();
+
+        assert_eq!(
+            pid.update(t0, 20.0, 25.0, (0.0, 1.0, 0.0), (0.0, 100.0)),
+            0.0
+        );
+        assert_eq!(
+            pid.update(t1, 20.0, 25.0, (0.0, 1.0, 0.0), (0.0, 100.0)),
+            5.0
+        );
+        assert_eq!(
+            pid.update(t1, 0.0, 25.0, (0.0, 1.0, 0.0), (0.0, 100.0)),
+            5.0
+        );
+        assert_eq!(
+            pid.update(t2, 20.0, 25.0, (0.0, 1.0, 0.0), (0.0, 100.0)),
+            10.0
+        );
+
+        // The integral term doesn't wind up past the limits, so the
+        // output responds as soon as the error changes sign.
+
+        let mut pid = Pid::default();
+
+        pid.update(t0, 0.0, 100.0, (0.0, 1.0, 0.0), (0.0, 10.0));
+        assert_eq!(
+            pid.update(t1, 0.0, 100.0, (0.0, 1.0, 0.0), (0.0, 10.0)),
+            10.0
+        );
+        assert_eq!(
+            pid.update(t2, 102.0, 100.0, (0.0, 1.0, 0.0), (0.0, 10.0)),
+            8.0
+        );
+
+        // The derivative term opposes changes in the input.
+
+        let mut pid = Pid::default();
+
+        pid.update(t0, 20.0, 25.0, (0.0, 0.0, 1.0), (-100.0, 100.0));
+        assert_eq!(
+            pid.update(t1, 22.0, 25.0, (0.0, 0.0, 1.0), (-100.0, 100.0)),
+            -2.0
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// Expressions can use aggregates of an input's recent readings, like
+// `AVG_HIST({rain}, 86400)`. When a logic block starts, it reads the
+// input's history, within the longest window its expressions use,
+// from the backend. After that, each reading of the input is added.
+// This way, the aggregates are correct right after a restart.
+
+use drmem_api::device;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Agg {
+    Avg,
+    Min,
+    Max,
+    Sum,
+}
+
+impl Agg {
+    pub fn from_name(name: &str) -> Option<Agg> {
+        match name {
+            "AVG_HIST" => Some(Agg::Avg),
+            "MIN_HIST" => Some(Agg::Min),
+            "MAX_HIST" => Some(Agg::Max),
+            "SUM_HIST" => Some(Agg::Sum),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Agg::Avg => "AVG_HIST",
+            Agg::Min => "MIN_HIST",
+            Agg::Max => "MAX_HIST",
+            Agg::Sum => "SUM_HIST",
+        }
+    }
+}
+
+// Holds the readings of an input that are within `window` of the
+// latest one. Only numeric readings are kept.
+
+pub struct Buffer {
+    window: Duration,
+    points: VecDeque<(SystemTime, f64)>,
+}
+
+impl Buffer {
+    pub fn new(window: Duration) -> Self {
+        Buffer {
+            window,
+            points: VecDeque::new(),
+        }
+    }
+
+    // Adds a reading. Readings that aren't newer than the latest one
+    // are ignored, since a device's stream starts with the reading
+    // that was already read from its history.
+
+    pub fn push(&mut self, ts: SystemTime, value: &device::Value) {
+        let value = match value {
+            device::Value::Int(v) => *v as f64,
+            device::Value::Flt(v) => *v,
+            _ => return,
+        };
+
+        if self.points.back().is_some_and(|(last, _)| *last >= ts) {
+            return;
+        }
+
+        self.points.push_back((ts, value));
+
+        if let Some(oldest) = ts.checked_sub(self.window) {
+            while self.points.front().is_some_and(|(t, _)| *t < oldest) {
+                self.points.pop_front();
+            }
+        }
+    }
+
+    // Computes the aggregate of the readings received within
+    // `window` of `now`. The sum of no readings is 0. The other
+    // aggregates need at least one reading.
+
+    pub fn aggregate(
+        &self,
+        agg: Agg,
+        window: Duration,
+        now: SystemTime,
+    ) -> Option<device::Value> {
+        let oldest = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut values = self
+            .points
+            .iter()
+            .filter(|(t, _)| *t >= oldest && *t <= now)
+            .map(|(_, v)| *v)
+            .peekable();
+
+        let result = match agg {
+            Agg::Sum => values.sum(),
+            _ if values.peek().is_none() => return None,
+            Agg::Avg => {
+                let (n, sum) =
+                    values.fold((0, 0.0), |(n, sum), v| (n + 1, sum + v));
+
+                sum / n as f64
+            }
+            Agg::Min => values.fold(f64::INFINITY, f64::min),
+            Agg::Max => values.fold(f64::NEG_INFINITY, f64::max),
+        };
+
+        Some(device::Value::Flt(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut buf = Buffer::new(Duration::from_secs(100));
+        let agg = |buf: &Buffer, agg: Agg, window: u64, now: u64| {
+            buf.aggregate(agg, Duration::from_secs(window), at(now))
+        };
+
+        assert_eq!(agg(&buf, Agg::Sum, 100, 0), Some(device::Value::Flt(0.0)));
+        assert_eq!(agg(&buf, Agg::Avg, 100, 0), None);
+
+        buf.push(at(0), &device::Value::Int(4));
+        buf.push(at(10), &device::Value::Flt(2.0));
+        buf.push(at(20), &device::Value::Bool(true));
+        buf.push(at(30), &device::Value::Int(6));
+
+        // Readings that aren't newer than the last one are ignored.
+
+        buf.push(at(30), &device::Value::Int(100));
+
+        assert_eq!(agg(&buf, Agg::Avg, 100, 30), Some(device::Value::Flt(4.0)));
+        assert_eq!(agg(&buf, Agg::Min, 100, 30), Some(device::Value::Flt(2.0)));
+        assert_eq!(agg(&buf, Agg::Max, 100, 30), Some(device::Value::Flt(6.0)));
+        assert_eq!(
+            agg(&buf, Agg::Sum, 100, 30),
+            Some(device::Value::Flt(12.0))
+        );
+
+        // Only readings within the window are used.
+
+        assert_eq!(agg(&buf, Agg::Sum, 25, 30), Some(device::Value::Flt(8.0)));
+        assert_eq!(agg(&buf, Agg::Max, 10, 50), None);
+
+        // Readings older than the buffer's window are dropped.
+
+        buf.push(at(105), &device::Value::Int(1));
+        assert_eq!(buf.points.len(), 3);
+    }
+}
@@ -24,6 +24,8 @@
 %epp DIV "/"
 %epp REM "%"
 %epp COLON ":"
+%epp DOT "."
+%epp COMMA ","
 %epp LBRACE "{"
 %epp RBRACE "}"
 
@@ -35,9 +37,12 @@ Logic -> Result<Program>:
 	let v = $4.map_err(|_| Error::ParseError(
 	        String::from("error reading target device")
             ))?;
-	let s = $lexer.span_str(v.span());
+	let lexer = $lexer;
+	let s = lexer.span_str(v.span());
+	let target = parse_device(s, p.1)
+	    .map_err(|e| locate(lexer, v.span(), e))?;
 
-	Ok(Program($1?, parse_device(s, p.1)?))
+	Ok(Program($1?, target))
     }
     ;
 
@@ -127,15 +132,17 @@ Factor -> Result<Expr>:
     | "FALSE" { Ok(Expr::Lit(device::Value::Bool(false))) }
     | "INT"
       {
-	  let s = get_str("literal integer", $1, $lexer)?;
+	  let lexer = $lexer;
+	  let s = get_str("literal integer", $1, lexer)?;
 
-          parse_int(s)
+	  parse_int(s).map_err(|e| locate(lexer, $span, e))
       }
     | "FLT"
       {
-	  let s = get_str("literal floating point", $1, $lexer)?;
+	  let lexer = $lexer;
+	  let s = get_str("literal floating point", $1, lexer)?;
 
-	  parse_flt(s)
+	  parse_flt(s).map_err(|e| locate(lexer, $span, e))
       }
     | "STRING"
     {
@@ -145,7 +152,8 @@ Factor -> Result<Expr>:
     }
     | "COLOR"
     {
-	let s = get_str("literal color", $1, $lexer)?;
+	let lexer = $lexer;
+	let s = get_str("literal color", $1, lexer)?;
 
 	match LinSrgba::<u8>::from_str(s) {
 	    Ok(v) => Ok(Expr::Lit(device::Value::Color(v))),
@@ -161,14 +169,99 @@ Factor -> Result<Expr>:
 			            .into_format::<u8>()
 			            .with_alpha(255u8)
 		            ))),
-		            None => Err(Error::ParseError(
+		            None => Err(locate(lexer, $span, Error::ParseError(
 			        format!("invalid color '{}'", s)
-		            ))
+		            )))
 		        }
 	        }
 	}
     }
     | Device { $1 }
+    | "FUNC" "(" Args ")"
+    {
+	let lexer = $lexer;
+	let span = $span;
+	let name = get_str("function name", $1, lexer)?;
+	let mut args = $3?;
+
+	if let Some(agg) = history::Agg::from_name(name) {
+	    return parse_history(agg, args).map_err(|e| locate(lexer, span, e));
+	}
+
+	// A table of points can only be the last argument.
+
+	let table = match args.last() {
+	    Some(Arg::Table(_)) => args.pop(),
+	    _ => None,
+	};
+	let nargs = args.len();
+	let exprs = args
+	    .into_iter()
+	    .map(|arg| match arg {
+		Arg::Expr(e) => Ok(e),
+		Arg::Table(_) => Err(Error::ParseError(
+		    format!("{} only accepts a table as its last argument", name)
+		)),
+	    })
+	    .collect::<Result<Vec<Expr>>>()
+	    .map_err(|e| locate(lexer, span, e))?;
+	let func = match table {
+	    Some(Arg::Table(points)) => Func::with_table(name, nargs + 1, points),
+	    _ => Func::new(name, nargs),
+	};
+
+	Ok(Expr::Func(func.map_err(|e| locate(lexer, span, e))?, exprs))
+    }
+    ;
+
+Args -> Result<Vec<Arg>>:
+      Args "COMMA" Arg
+      {
+	  let mut args = $1?;
+
+	  args.push($3?);
+	  Ok(args)
+      }
+    | Arg { Ok(vec![$1?]) }
+    ;
+
+Arg -> Result<Arg>:
+      BoolExpr { Ok(Arg::Expr($1?)) }
+    | "[" Points "]" { Ok(Arg::Table($2?)) }
+    ;
+
+Points -> Result<Vec<(f64, f64)>>:
+      Points "COMMA" Point
+      {
+	  let mut points = $1?;
+
+	  points.push($3?);
+	  Ok(points)
+      }
+    | Point { Ok(vec![$1?]) }
+    ;
+
+Point -> Result<(f64, f64)>:
+    "(" Number "COMMA" Number ")" { Ok(($2?, $4?)) }
+    ;
+
+Number -> Result<f64>:
+      "INT"
+      {
+	  let s = get_str("table value", $1, $lexer)?;
+
+	  s.parse::<f64>().map_err(|_| Error::ParseError(
+	      format!("bad table value {}", s)
+	  ))
+      }
+    | "FLT"
+      {
+	  let s = get_str("table value", $1, $lexer)?;
+
+	  s.parse::<f64>().map_err(|_| Error::ParseError(
+	      format!("bad table value {}", s)
+	  ))
+      }
     ;
 
 Device -> Result<Expr>:
@@ -178,13 +271,24 @@ Device -> Result<Expr>:
 	let cat = get_str("built-in category", $2, lexer)?;
 	let fld = get_str("built-in field", $4, lexer)?;
 
-	parse_builtin(cat, fld)
+	parse_builtin(cat, fld).map_err(|e| locate(lexer, $span, e))
     }
     | "LBRACE" "IDENTIFIER" "RBRACE"
     {
-	let s = get_str("device name", $2, $lexer)?;
+	let lexer = $lexer;
+	let s = get_str("device name", $2, lexer)?;
 
-	Ok(Expr::Var(parse_device(s, p.0)?))
+	parse_device(s, p.0)
+	    .map(Expr::Var)
+	    .map_err(|e| locate(lexer, $span, e))
+    }
+    | "LBRACE" "IDENTIFIER" "DOT" "IDENTIFIER" "RBRACE"
+    {
+	let lexer = $lexer;
+	let s = get_str("device name", $2, lexer)?;
+	let fld = get_str("input field", $4, lexer)?;
+
+	parse_field(s, fld, p.0).map_err(|e| locate(lexer, $span, e))
     }
     ;
 
@@ -197,11 +301,11 @@ Unknown -> ():
 use drmem_api::{Result, Error, device};
 use chrono::{Timelike, Datelike};
 use palette::{LinSrgba, LinSrgb, Srgb, named, WithAlpha};
-use super::{TimeField, SolarField, super::tod, super::solar, Expr, Program};
+use super::{TimeField, SolarField, super::history, super::tod, super::solar, Expr, Func, Program};
 use std::str::FromStr;
 
 use lrlex::{DefaultLexeme, DefaultLexerTypes};
-use lrpar::NonStreamingLexer;
+use lrpar::{NonStreamingLexer, Span};
 
 // This complicated beast is an attempt to remove the boilerplate code
 // used when processing the terminal tokens (i.e. the leaf values of
@@ -219,6 +323,34 @@ fn get_str<'a, 'input>(
     Ok(lexer.span_str(lexeme.span()))
 }
 
+// Adds the position of the text at `span` to an error found while
+// building the expression tree.
+
+fn locate<'a, 'input>(
+    lexer: &'a (dyn NonStreamingLexer<'input, DefaultLexerTypes> + 'a),
+    span: Span,
+    e: Error,
+) -> Error {
+    match e {
+	Error::ParseError(msg) => {
+	    let ((line, col), _) = lexer.line_col(span);
+
+	    Error::ParseError(format!(
+		"line {}, column {}: {} (at '{}')",
+		line, col, msg, lexer.span_str(span)
+	    ))
+	}
+	e => e,
+    }
+}
+
+// A function argument is either an expression or a table of points.
+
+enum Arg {
+    Expr(Expr),
+    Table(Vec<(f64, f64)>),
+}
+
 // Any functions here are in scope for all the grammar actions above.
 
 fn parse_int(s: &str) -> Result<Expr> {
@@ -246,6 +378,39 @@ fn parse_device(name: &str, env: &[String]) -> Result<usize> {
     Err(Error::ParseError(format!("variable '{}' is not defined", &name)))
 }
 
+// History aggregates take an input and a window, in seconds. Whether
+// the variable is an input, rather than a definition, is checked when
+// the logic block starts.
+
+fn parse_history(agg: history::Agg, args: Vec<Arg>) -> Result<Expr> {
+    match args.as_slice() {
+	[Arg::Expr(Expr::Var(idx)), Arg::Expr(Expr::Lit(device::Value::Int(secs)))]
+	    if *secs > 0 =>
+	    Ok(Expr::Hist(agg, *idx, *secs as u32, None)),
+	_ => Err(Error::ParseError(format!(
+	    "{} needs a variable and a positive number of seconds",
+	    agg.name()
+	))),
+    }
+}
+
+// Each input has an "age" variable, named "NAME.age", which holds
+// the number of seconds since the input last changed. Definitions
+// don't have an age.
+
+fn parse_field(name: &str, fld: &str, env: &[String]) -> Result<Expr> {
+    match fld {
+	"age" => parse_device(&format!("{}.age", name), env)
+	    .map(Expr::Age)
+	    .map_err(|_| Error::ParseError(
+		format!("'{}' isn't an input, so it has no age", name)
+	    )),
+	_ => Err(Error::ParseError(
+	    format!("unknown field '{}' of '{}'", fld, name)
+	)),
+    }
+}
+
 const CAT_UTC: &str = "utc";
 const CAT_LOCAL: &str = "local";
 const CAT_SOLAR: &str = "solar";
@@ -258,10 +423,18 @@ const FLD_MONTH: &str = "month";
 const FLD_YEAR: &str = "year";
 const FLD_DOW: &str = "DOW";
 const FLD_DOY: &str = "DOY";
+const FLD_WEEKEND: &str = "weekend";
+const FLD_HOLIDAY: &str = "holiday";
+const FLD_OFFSET: &str = "offset";
+const FLD_DST: &str = "dst";
 const FLD_ALT: &str = "alt";
 const FLD_AZ: &str = "az";
 const FLD_RA: &str = "ra";
 const FLD_DEC: &str = "dec";
+const FLD_SUNRISE: &str = "sunrise";
+const FLD_SUNSET: &str = "sunset";
+const FLD_NOON: &str = "noon";
+const FLD_DARK: &str = "dark";
 
 fn get_utc_second(info: &tod::Info) -> device::Value {
     device::Value::Int(info.0.second() as i32)
@@ -295,6 +468,10 @@ fn get_utc_day_of_year(info: &tod::Info) -> device::Value {
     device::Value::Int(info.0.ordinal0() as i32)
 }
 
+fn get_utc_weekend(info: &tod::Info) -> device::Value {
+    device::Value::Bool(info.0.weekday().num_days_from_monday() >= 5)
+}
+
 fn get_local_second(info: &tod::Info) -> device::Value {
     device::Value::Int(info.1.second() as i32)
 }
@@ -327,6 +504,22 @@ fn get_local_day_of_year(info: &tod::Info) -> device::Value {
     device::Value::Int(info.1.ordinal0() as i32)
 }
 
+fn get_local_weekend(info: &tod::Info) -> device::Value {
+    device::Value::Bool(info.1.weekday().num_days_from_monday() >= 5)
+}
+
+fn get_local_holiday(info: &tod::Info) -> device::Value {
+    device::Value::Bool(tod::is_holiday(&info.1.date_naive()))
+}
+
+fn get_local_offset(info: &tod::Info) -> device::Value {
+    device::Value::Int(info.1.offset().local_minus_utc() / 60)
+}
+
+fn get_local_dst(info: &tod::Info) -> device::Value {
+    device::Value::Bool(tod::is_dst(&info.0))
+}
+
 fn get_solar_altitude(info: &solar::Info) -> device::Value {
     device::Value::Flt(info.elevation)
 }
@@ -343,6 +536,22 @@ fn get_solar_declination(info: &solar::Info) -> device::Value {
     device::Value::Flt(info.declination)
 }
 
+fn get_solar_sunrise(info: &solar::Info) -> device::Value {
+    device::Value::Flt(info.sunrise)
+}
+
+fn get_solar_sunset(info: &solar::Info) -> device::Value {
+    device::Value::Flt(info.sunset)
+}
+
+fn get_solar_noon(info: &solar::Info) -> device::Value {
+    device::Value::Flt(info.noon)
+}
+
+fn get_solar_dark(info: &solar::Info) -> device::Value {
+    device::Value::Bool(info.is_dark())
+}
+
 fn parse_builtin(cat: &str, fld: &str) -> Result<Expr> {
     match (cat, fld) {
 	(CAT_UTC, FLD_SECOND) => Ok(Expr::TimeVal(
@@ -369,6 +578,9 @@ fn parse_builtin(cat: &str, fld: &str) -> Result<Expr> {
 	(CAT_UTC, FLD_DOY) => Ok(Expr::TimeVal(
             CAT_UTC, TimeField::DoY, get_utc_day_of_year
         )),
+	(CAT_UTC, FLD_WEEKEND) => Ok(Expr::TimeVal(
+            CAT_UTC, TimeField::Weekend, get_utc_weekend
+        )),
 	(CAT_LOCAL, FLD_SECOND) => Ok(Expr::TimeVal(
             CAT_LOCAL, TimeField::Second, get_local_second
         )),
@@ -393,6 +605,18 @@ fn parse_builtin(cat: &str, fld: &str) -> Result<Expr> {
 	(CAT_LOCAL, FLD_DOY) => Ok(Expr::TimeVal(
             CAT_LOCAL, TimeField::DoY, get_local_day_of_year
         )),
+	(CAT_LOCAL, FLD_WEEKEND) => Ok(Expr::TimeVal(
+            CAT_LOCAL, TimeField::Weekend, get_local_weekend
+        )),
+	(CAT_LOCAL, FLD_HOLIDAY) => Ok(Expr::TimeVal(
+            CAT_LOCAL, TimeField::Holiday, get_local_holiday
+        )),
+	(CAT_LOCAL, FLD_OFFSET) => Ok(Expr::TimeVal(
+            CAT_LOCAL, TimeField::Offset, get_local_offset
+	)),
+	(CAT_LOCAL, FLD_DST) => Ok(Expr::TimeVal(
+            CAT_LOCAL, TimeField::Dst, get_local_dst
+	)),
 	(CAT_SOLAR, FLD_ALT) => Ok(Expr::SolarVal(
 	    SolarField::Elevation, get_solar_altitude
         )),
@@ -405,6 +629,18 @@ fn parse_builtin(cat: &str, fld: &str) -> Result<Expr> {
 	(CAT_SOLAR, FLD_DEC) => Ok(Expr::SolarVal(
             SolarField::Declination, get_solar_declination
         )),
+	(CAT_SOLAR, FLD_SUNRISE) => Ok(Expr::SolarVal(
+            SolarField::Sunrise, get_solar_sunrise
+        )),
+	(CAT_SOLAR, FLD_SUNSET) => Ok(Expr::SolarVal(
+            SolarField::Sunset, get_solar_sunset
+        )),
+	(CAT_SOLAR, FLD_NOON) => Ok(Expr::SolarVal(
+            SolarField::Noon, get_solar_noon
+        )),
+	(CAT_SOLAR, FLD_DARK) => Ok(Expr::SolarVal(
+            SolarField::Dark, get_solar_dark
+        )),
 	_ => Err(Error::ParseError(
 		 format!("unknown built-in: {}:{}", cat, fld)
 	     ))
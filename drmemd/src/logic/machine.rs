@@ -0,0 +1,383 @@
+// A logic block can contain a state machine. The machine is always in
+// one of a set of named states. Each state may give values for some
+// of the block's outputs, which are set when the state is entered,
+// and a list of transitions. A transition has a guard expression and
+// a target state. Each time the block is evaluated, the guards of the
+// current state are checked, in order, and the machine moves to the
+// target of the first one that's `true`.
+//
+// The guards of every state are evaluated each time, even though
+// only the current state's results are used. This way, functions
+// that keep state, like `DELAY_ON`, see every change of their inputs.
+
+use drmem_api::{device, driver, Error, Result};
+use futures::future::join_all;
+use std::collections::HashMap;
+use tracing::info;
+
+use super::{compile, config, solar, tod, Output};
+
+struct State {
+    settings: Vec<(usize, device::Value)>,
+    transitions: Vec<compile::Program>,
+}
+
+pub struct Machine {
+    names: Vec<String>,
+    states: Vec<State>,
+    current: usize,
+    outputs: HashMap<usize, (device::Name, Output)>,
+    report: Option<driver::ReadOnlyDevice<String>>,
+    dry_run: bool,
+}
+
+// Checks the parts of the machine's configuration that don't need
+// the expressions to be compiled. State names are used as targets in
+// the compiled guards, so they have to be valid identifiers.
+
+pub fn validate(
+    cfg: &config::Machine,
+    outputs: &HashMap<String, device::Name>,
+) -> Result<()> {
+    if !cfg.states.contains_key(&cfg.initial) {
+        return Err(Error::ConfigError(format!(
+            "initial state '{}' isn't defined",
+            &cfg.initial
+        )));
+    }
+
+    for (name, state) in &cfg.states {
+        let mut chars = name.chars();
+
+        if !(chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        {
+            return Err(Error::ConfigError(format!(
+                "'{}' isn't a valid state name",
+                name
+            )));
+        }
+
+        for (k, v) in &state.outputs {
+            if !outputs.contains_key(k) {
+                return Err(Error::ConfigError(format!(
+                    "state '{}' sets '{}', which isn't defined in 'outputs'",
+                    name, k
+                )));
+            }
+            if device::Value::try_from(v).is_err() {
+                return Err(Error::ConfigError(format!(
+                    "state '{}' has a bad value for '{}'",
+                    name, k
+                )));
+            }
+        }
+
+        if let Some(t) = state
+            .transitions
+            .iter()
+            .find(|t| !cfg.states.contains_key(&t.to))
+        {
+            return Err(Error::ConfigError(format!(
+                "state '{}' has a transition to unknown state '{}'",
+                name, &t.to
+            )));
+        }
+    }
+    Ok(())
+}
+
+impl Machine {
+    // Builds a machine from a configuration that has been checked by
+    // `validate`. The guards can use the names in `inputs`. `outputs`
+    // holds the names of the block's outputs.
+
+    pub fn compile(
+        cfg: &config::Machine,
+        inputs: &[String],
+        outputs: &[String],
+        dry_run: bool,
+    ) -> Result<Machine> {
+        let mut names: Vec<String> = cfg.states.keys().cloned().collect();
+
+        names.sort_unstable();
+
+        let env = (inputs, &names[..]);
+        let mut states = Vec::with_capacity(names.len());
+
+        for name in &names {
+            let state = &cfg.states[name];
+            let mut settings = Vec::with_capacity(state.outputs.len());
+
+            for (k, v) in &state.outputs {
+                if let (Some(idx), Ok(v)) = (
+                    outputs.iter().position(|o| o == k),
+                    device::Value::try_from(v),
+                ) {
+                    settings.push((idx, v))
+                }
+            }
+
+            let transitions = state
+                .transitions
+                .iter()
+                .map(|t| {
+                    compile::Program::compile(
+                        &format!("{} -> {{{}}}", &t.when, &t.to),
+                        &env,
+                    )
+                    .map(compile::Program::optimize)
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            states.push(State {
+                settings,
+                transitions,
+            })
+        }
+
+        Ok(Machine {
+            current: names.iter().position(|n| *n == cfg.initial).unwrap_or(0),
+            names,
+            states,
+            outputs: HashMap::new(),
+            report: None,
+            dry_run,
+        })
+    }
+
+    // Returns the indices of the block outputs set by the machine.
+
+    pub fn outputs_used(&self) -> Vec<usize> {
+        let mut result: Vec<usize> = self
+            .states
+            .iter()
+            .flat_map(|s| s.settings.iter().map(|(idx, _)| *idx))
+            .collect();
+
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+
+    // Gives the machine the channel used to set the block output at
+    // index `idx`.
+
+    pub fn add_output(&mut self, idx: usize, dev: device::Name, out: Output) {
+        self.outputs.insert(idx, (dev, out));
+    }
+
+    // Sets the device that reports the name of the current state.
+
+    pub fn set_report(&mut self, report: driver::ReadOnlyDevice<String>) {
+        self.report = Some(report)
+    }
+
+    pub fn outputs_mut(&mut self) -> impl Iterator<Item = &mut Output> {
+        self.outputs.values_mut().map(|(_, out)| out)
+    }
+
+    pub fn exprs_mut(&mut self) -> impl Iterator<Item = &mut compile::Expr> {
+        self.states
+            .iter_mut()
+            .flat_map(|s| s.transitions.iter_mut().map(|p| &mut p.0))
+    }
+
+    fn guards(&self) -> impl Iterator<Item = &compile::Expr> {
+        self.states
+            .iter()
+            .flat_map(|s| s.transitions.iter().map(|p| &p.0))
+    }
+
+    // Returns the time-of-day field needed by the guards, if any.
+
+    pub fn uses_time(&self) -> Option<tod::TimeField> {
+        self.guards().filter_map(compile::Expr::uses_time).min()
+    }
+
+    pub fn uses_solar(&self) -> bool {
+        self.guards().any(compile::Expr::uses_solar)
+    }
+
+    // Evaluates the guards and moves to the next state, if one of the
+    // current state's guards is `true`. Returns `true` if the state
+    // changed.
+
+    pub fn step(
+        &mut self,
+        inputs: &[Option<device::Value>],
+        time: &tod::Info,
+        solar: Option<&solar::Info>,
+    ) -> bool {
+        let mut next = None;
+
+        for (idx, state) in self.states.iter_mut().enumerate() {
+            for compile::Program(guard, to) in state.transitions.iter_mut() {
+                let result = compile::eval(guard, inputs, time, solar);
+
+                if idx == self.current
+                    && next.is_none()
+                    && result == Some(device::Value::Bool(true))
+                {
+                    next = Some(*to)
+                }
+            }
+        }
+
+        match next {
+            Some(to) if to != self.current => {
+                info!(
+                    "state '{}' -> '{}'",
+                    &self.names[self.current], &self.names[to]
+                );
+                self.current = to;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Reports the current state and sets the outputs it specifies.
+    // Returns the number of settings that failed.
+
+    pub async fn enter(&mut self) -> usize {
+        let state = &self.states[self.current];
+
+        if let Some(report) = self.report.as_mut() {
+            report.report_update(self.names[self.current].clone()).await
+        }
+
+        if self.dry_run {
+            for (idx, value) in &state.settings {
+                if let Some((dev, _)) = self.outputs.get(idx) {
+                    info!("dry run: would set '{}' to {}", dev, value)
+                }
+            }
+            0
+        } else {
+            join_all(self.outputs.iter_mut().filter_map(|(idx, (_, out))| {
+                state
+                    .settings
+                    .iter()
+                    .find(|(i, _)| i == idx)
+                    .map(|(_, v)| out.send(v.clone()))
+            }))
+            .await
+            .iter()
+            .filter(|ok| !**ok)
+            .count()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(states: &[(&str, &[(&str, &str)])]) -> config::Machine {
+        config::Machine {
+            initial: "idle".into(),
+            states: states
+                .iter()
+                .map(|(name, transitions)| {
+                    (
+                        name.to_string(),
+                        config::MachineState {
+                            outputs: HashMap::new(),
+                            transitions: transitions
+                                .iter()
+                                .map(|(when, to)| config::Transition {
+                                    when: when.to_string(),
+                                    to: to.to_string(),
+                                })
+                                .collect(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let cfg = machine(&[
+            ("idle", &[("{a} > 10", "running")]),
+            ("running", &[("{b}", "idle"), ("{a} < 5", "done")]),
+            ("done", &[("{a} > 10", "running")]),
+        ]);
+        let inputs = [String::from("a"), String::from("b")];
+        let outputs = [String::from("out")];
+        let mut m = Machine::compile(&cfg, &inputs, &outputs, false).unwrap();
+        let time = std::sync::Arc::new((
+            chrono::Utc::now(),
+            chrono::Local::now().fixed_offset(),
+        ));
+        let mut step = |a: i32, b: bool| {
+            let inp =
+                [Some(device::Value::Int(a)), Some(device::Value::Bool(b))];
+
+            m.step(&inp, &time, None)
+                .then(|| m.names[m.current].clone())
+        };
+
+        assert_eq!(step(5, false), None);
+        assert_eq!(step(20, false), Some("running".into()));
+        assert_eq!(step(20, false), None);
+
+        // Transitions are checked in order.
+
+        assert_eq!(step(0, true), Some("idle".into()));
+        assert_eq!(step(20, false), Some("running".into()));
+        assert_eq!(step(0, false), Some("done".into()));
+
+        // Missing inputs don't cause a transition.
+
+        assert!(!m.step(&[None, None], &time, None));
+        assert_eq!(m.names[m.current], "done");
+    }
+
+    #[test]
+    fn test_validate() {
+        let outputs = HashMap::from([(
+            String::from("out"),
+            device::Name::create("device:out").unwrap(),
+        )]);
+        let good = machine(&[("idle", &[("{a}", "busy")]), ("busy", &[])]);
+
+        assert!(validate(&good, &outputs).is_ok());
+
+        // The initial state and the targets of transitions have to be
+        // defined.
+
+        let mut cfg = good.clone();
+
+        cfg.initial = "bad".into();
+        assert!(validate(&cfg, &outputs).is_err());
+        assert!(validate(&machine(&[("idle", &[("{a}", "bad")])]), &outputs)
+            .is_err());
+
+        // State names have to be identifiers.
+
+        assert!(validate(
+            &machine(&[("idle", &[]), ("spin-dry", &[])]),
+            &outputs
+        )
+        .is_err());
+
+        // States can only set outputs of the block, to valid values.
+
+        for (k, v) in [
+            ("bad", toml::value::Value::Boolean(true)),
+            ("out", toml::value::Value::Integer(1 << 40)),
+        ] {
+            let mut cfg = good.clone();
+
+            cfg.states
+                .get_mut("busy")
+                .unwrap()
+                .outputs
+                .insert(k.into(), v);
+            assert!(validate(&cfg, &outputs).is_err());
+        }
+    }
+}
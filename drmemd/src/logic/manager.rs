@@ -0,0 +1,516 @@
+// The logic manager owns the running logic blocks. It starts the
+// blocks in the configuration and, when asked to reload, compares a
+// new set of `[[logic]]` sections with the running set. Blocks that
+// were removed are stopped, new or changed blocks are (re)started,
+// and unchanged blocks keep running. Drivers aren't affected.
+
+use crate::events;
+use drmem_api::{client, driver, Error, Result};
+use futures::future::pending;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::Infallible;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    task::{AbortHandle, JoinHandle, JoinSet},
+};
+use tracing::{error, info, info_span, warn};
+use tracing_futures::Instrument;
+
+use super::{arbiter, config, solar, status, tod, Node};
+
+// Reports the result of a reload.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+// Describes a running logic block: its configuration and its latest
+// activity.
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+pub struct BlockInfo {
+    pub cfg: config::Logic,
+    pub status: status::Snapshot,
+}
+
+// Reports whether the logic blocks are running. `stopped` holds the
+// blocks that stopped because of an error and haven't been restarted
+// by a reload.
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+#[derive(Debug, Default, PartialEq)]
+pub struct Health {
+    pub running: usize,
+    pub stopped: Vec<String>,
+}
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+pub enum Request {
+    Reload {
+        cfg: Vec<config::Logic>,
+        rpy_chan: oneshot::Sender<Result<Summary>>,
+    },
+    GetBlocks {
+        rpy_chan: oneshot::Sender<Vec<BlockInfo>>,
+    },
+    GetHealth {
+        rpy_chan: oneshot::Sender<Health>,
+    },
+}
+
+// A handle used to send requests to the logic manager.
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+#[derive(Clone)]
+pub struct RequestChan {
+    req_chan: mpsc::Sender<Request>,
+    arbiter: arbiter::Arbiter,
+}
+
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+impl RequestChan {
+    pub fn new(
+        req_chan: mpsc::Sender<Request>,
+        arbiter: arbiter::Arbiter,
+    ) -> Self {
+        RequestChan { req_chan, arbiter }
+    }
+
+    // Replaces the running logic blocks with the ones in `cfg`.
+
+    pub async fn reload(&self, cfg: Vec<config::Logic>) -> Result<Summary> {
+        let (tx, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::Reload { cfg, rpy_chan: tx })
+            .await?;
+        rx.await?
+    }
+
+    // Returns information about the running logic blocks, sorted by
+    // name.
+
+    pub async fn get_blocks(&self) -> Result<Vec<BlockInfo>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::GetBlocks { rpy_chan: tx })
+            .await?;
+        Ok(rx.await?)
+    }
+
+    // Returns how many blocks are running and which ones stopped.
+
+    pub async fn get_health(&self) -> Result<Health> {
+        let (tx, rx) = oneshot::channel();
+
+        self.req_chan
+            .send(Request::GetHealth { rpy_chan: tx })
+            .await?;
+        Ok(rx.await?)
+    }
+
+    // Records that a client set a device, so logic blocks with a
+    // lower priority leave it alone.
+
+    pub fn manual_setting(&self, dev: &drmem_api::device::Name) {
+        self.arbiter.manual(dev)
+    }
+
+    // Returns the source controlling each device that has been set
+    // by a logic block or a client.
+
+    pub fn get_owners(&self) -> Vec<(drmem_api::device::Name, arbiter::Claim)> {
+        self.arbiter.owners()
+    }
+}
+
+// Holds the information about a running logic block. The generation
+// number lets us ignore the exit status of a block that has already
+// been replaced.
+
+struct Block {
+    cfg: config::Logic,
+    generation: u64,
+    handle: AbortHandle,
+    status: status::Status,
+}
+
+struct Manager {
+    c_req: client::RequestChan,
+    d_req: mpsc::Sender<driver::Request>,
+    tx_tod: broadcast::Sender<tod::Info>,
+    tx_solar: broadcast::Sender<solar::Info>,
+    arbiter: arbiter::Arbiter,
+    blocks: HashMap<String, Block>,
+    stopped: BTreeSet<String>,
+    tasks: JoinSet<(String, u64, Result<Infallible>)>,
+    generation: u64,
+}
+
+impl Manager {
+    fn start_block(&mut self, cfg: config::Logic) {
+        let name = cfg.name.clone();
+        let status = status::Status::default();
+
+        self.stopped.remove(&name);
+
+        let fut = Node::start(
+            self.c_req.clone(),
+            self.tx_tod.subscribe(),
+            self.tx_solar.subscribe(),
+            cfg.clone(),
+            status.clone(),
+            self.arbiter.clone(),
+            self.d_req.clone(),
+        );
+
+        self.generation += 1;
+
+        let generation = self.generation;
+        let handle = self.tasks.spawn({
+            let name = name.clone();
+
+            async move { (name, generation, fut.await) }
+        });
+
+        self.blocks.insert(
+            name,
+            Block {
+                cfg,
+                generation,
+                handle,
+                status,
+            },
+        );
+    }
+
+    // Stops a running block and drops its claims on devices.
+
+    fn stop_block(&self, block: &Block) {
+        block.handle.abort();
+        self.arbiter
+            .release(&arbiter::Source::Logic(block.cfg.name.as_str().into()))
+    }
+
+    // Makes the set of running blocks match the configuration.
+
+    fn update(&mut self, cfg: Vec<config::Logic>) -> Result<Summary> {
+        let mut names = HashSet::with_capacity(cfg.len());
+
+        for block in &cfg {
+            if !names.insert(block.name.as_str()) {
+                return Err(Error::ConfigError(format!(
+                    "logic block '{}' is defined more than once",
+                    &block.name
+                )));
+            }
+        }
+
+        let mut summary = Summary::default();
+
+        // Forget the blocks that stopped and were removed from the
+        // configuration.
+
+        self.stopped.retain(|name| names.contains(name.as_str()));
+
+        // Stop the blocks that are no longer in the configuration.
+
+        let removed: Vec<String> = self
+            .blocks
+            .keys()
+            .filter(|k| !names.contains(k.as_str()))
+            .cloned()
+            .collect();
+
+        for name in removed {
+            if let Some(block) = self.blocks.remove(&name) {
+                info!("stopping logic block '{}'", &name);
+                self.stop_block(&block);
+                summary.stopped.push(name)
+            }
+        }
+
+        // Start new blocks and restart the ones whose configuration
+        // changed.
+
+        for block in cfg {
+            match self.blocks.get(&block.name) {
+                Some(prev) if prev.cfg == block => {
+                    summary.unchanged.push(block.name)
+                }
+                prev => {
+                    if let Some(prev) = prev {
+                        info!("restarting logic block '{}'", &block.name);
+                        self.stop_block(prev)
+                    }
+                    summary.started.push(block.name.clone());
+                    self.start_block(block)
+                }
+            }
+        }
+
+        summary.started.sort();
+        summary.stopped.sort();
+        summary.unchanged.sort();
+        Ok(summary)
+    }
+
+    fn get_blocks(&self) -> Vec<BlockInfo> {
+        let mut result: Vec<BlockInfo> = self
+            .blocks
+            .values()
+            .map(|b| BlockInfo {
+                cfg: b.cfg.clone(),
+                status: b.status.snapshot(),
+            })
+            .collect();
+
+        result.sort_unstable_by(|a, b| a.cfg.name.cmp(&b.cfg.name));
+        result
+    }
+
+    fn get_health(&self) -> Health {
+        Health {
+            running: self.blocks.len(),
+            stopped: self.stopped.iter().cloned().collect(),
+        }
+    }
+
+    // Records a block that stopped on its own.
+
+    fn block_stopped(&mut self, name: &str, generation: Option<u64>) {
+        if self
+            .blocks
+            .get(name)
+            .is_some_and(|b| generation.is_none_or(|g| b.generation == g))
+        {
+            if let Some(block) = self.blocks.remove(name) {
+                self.stop_block(&block)
+            }
+            self.stopped.insert(name.into());
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<Request>) -> Infallible {
+        loop {
+            #[rustfmt::skip]
+	    tokio::select! {
+		Some(req) = rx.recv() => {
+		    match req {
+			Request::Reload { cfg, rpy_chan } => {
+			    let _ = rpy_chan.send(self.update(cfg));
+			}
+			Request::GetBlocks { rpy_chan } => {
+			    let _ = rpy_chan.send(self.get_blocks());
+			}
+			Request::GetHealth { rpy_chan } => {
+			    let _ = rpy_chan.send(self.get_health());
+			}
+		    }
+		}
+
+		// Report blocks that exit. If the block is still
+		// the current one, forget about it so the next
+		// reload restarts it.
+
+		Some(result) = self.tasks.join_next() => {
+		    match result {
+			Ok((name, generation, Err(e))) => {
+			    error!("logic block '{}' stopped -- {}", &name, &e);
+			    events::publish(
+				events::Kind::LogicStopped,
+				name.as_str(),
+				e.to_string(),
+			    );
+			    self.block_stopped(&name, Some(generation))
+			}
+			Ok((_, _, Ok(_))) => unreachable!(),
+			Err(e) if e.is_panic() => {
+			    error!("logic block terminated due to panic");
+
+			    let name = self
+				.blocks
+				.iter()
+				.find(|(_, b)| b.handle.id() == e.id())
+				.map(|(name, _)| name.clone())
+				.unwrap_or_default();
+
+			    events::publish(
+				events::Kind::LogicStopped,
+				name.as_str(),
+				"logic block terminated due to panic",
+			    );
+			    self.block_stopped(&name, None)
+			}
+			Err(_) => (),
+		    }
+		}
+
+		else => return pending().await
+	    }
+        }
+    }
+}
+
+// Starts the logic manager and the logic blocks in `cfg`. Requests
+// are read from `rx`.
+
+pub fn start(
+    c_req: client::RequestChan,
+    d_req: mpsc::Sender<driver::Request>,
+    tx_tod: broadcast::Sender<tod::Info>,
+    tx_solar: broadcast::Sender<solar::Info>,
+    arbiter: arbiter::Arbiter,
+    cfg: Vec<config::Logic>,
+    rx: mpsc::Receiver<Request>,
+) -> Result<JoinHandle<Result<Infallible>>> {
+    let mut mgr = Manager {
+        c_req,
+        d_req,
+        tx_tod,
+        tx_solar,
+        arbiter,
+        blocks: HashMap::new(),
+        stopped: BTreeSet::new(),
+        tasks: JoinSet::new(),
+        generation: 0,
+    };
+
+    if mgr.update(cfg)?.started.is_empty() {
+        warn!("no logic blocks defined")
+    }
+
+    Ok(tokio::spawn(
+        async move { Ok(mgr.run(rx).await) }
+            .instrument(info_span!("logic-manager")),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drmem_api::device;
+
+    fn block(name: &str, expr: &str) -> config::Logic {
+        config::Logic {
+            name: name.into(),
+            summary: None,
+            inputs: HashMap::new(),
+            init: HashMap::new(),
+            outputs: [(
+                "out".into(),
+                device::Name::create("device:out").unwrap(),
+            )]
+            .into_iter()
+            .collect(),
+            deadband: HashMap::new(),
+            min_interval: HashMap::new(),
+            defs: HashMap::new(),
+            exprs: vec![expr.into()],
+            state: None,
+            priority: 0,
+            dry_run: false,
+            prefix: None,
+            units: HashMap::new(),
+            timeout: HashMap::new(),
+            fallback: HashMap::new(),
+            machine: None,
+            alarms: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        // The blocks will fail to start since nothing handles the
+        // client requests, but the manager still tracks them.
+
+        let (tx_clnt, _rx_clnt) = mpsc::channel(10);
+        let (tx_tod, _) = broadcast::channel(1);
+        let (tx_solar, _) = broadcast::channel(1);
+        let mut mgr = Manager {
+            c_req: client::RequestChan::new(tx_clnt),
+            d_req: mpsc::channel(1).0,
+            tx_tod,
+            tx_solar,
+            arbiter: arbiter::Arbiter::default(),
+            blocks: HashMap::new(),
+            stopped: BTreeSet::new(),
+            tasks: JoinSet::new(),
+            generation: 0,
+        };
+
+        assert_eq!(
+            mgr.update(vec![
+                block("a", "true -> {out}"),
+                block("b", "1 -> {out}")
+            ]),
+            Ok(Summary {
+                started: vec!["a".into(), "b".into()],
+                ..Summary::default()
+            })
+        );
+
+        // Change one block, drop another, and add a new one.
+
+        assert_eq!(
+            mgr.update(vec![
+                block("a", "true -> {out}"),
+                block("c", "2 -> {out}")
+            ]),
+            Ok(Summary {
+                started: vec!["c".into()],
+                stopped: vec!["b".into()],
+                unchanged: vec!["a".into()],
+            })
+        );
+        assert_eq!(
+            mgr.update(vec![
+                block("a", "false -> {out}"),
+                block("c", "2 -> {out}")
+            ]),
+            Ok(Summary {
+                started: vec!["a".into()],
+                stopped: vec![],
+                unchanged: vec!["c".into()],
+            })
+        );
+
+        // Duplicate names are rejected and nothing changes.
+
+        assert!(mgr
+            .update(vec![block("a", "true -> {out}"), block("a", "1 -> {out}")])
+            .is_err());
+        assert_eq!(mgr.blocks.len(), 2);
+
+        // Blocks that stop are reported until a reload restarts
+        // them or removes them.
+
+        let generation = mgr.blocks["a"].generation;
+
+        mgr.block_stopped("a", Some(generation + 1));
+        assert!(mgr.get_health().stopped.is_empty());
+
+        mgr.block_stopped("a", Some(generation));
+        mgr.block_stopped("c", None);
+        assert_eq!(
+            mgr.get_health(),
+            Health {
+                running: 0,
+                stopped: vec!["a".into(), "c".into()]
+            }
+        );
+
+        let _ = mgr.update(vec![block("a", "true -> {out}")]);
+        assert_eq!(
+            mgr.get_health(),
+            Health {
+                running: 1,
+                stopped: vec![]
+            }
+        );
+    }
+}
@@ -1,21 +1,58 @@
-use drmem_api::{client, device, driver, Result};
+use drmem_api::{client, device, driver, Error, Result};
 use futures::future::{join_all, pending};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::{
-    sync::{broadcast, oneshot},
-    task::JoinHandle,
+    sync::{broadcast, mpsc, oneshot},
+    time::{Duration, Instant},
 };
 use tokio_stream::{wrappers::BroadcastStream, StreamExt, StreamMap};
 use tracing::{debug, error, info, info_span, warn};
 use tracing_futures::Instrument;
 
-use super::config;
+use super::{clock, config, core::unresponsive};
 
+mod alarm;
+pub mod arbiter;
 mod compile;
+mod diag;
+mod func;
+mod history;
+mod machine;
+pub mod manager;
 pub mod solar;
+mod state;
+pub mod status;
 pub mod tod;
+mod units;
+
+// Converts a reading into the units the expressions expect.
+
+fn convert(
+    conversion: &Option<units::Conversion>,
+    value: device::Value,
+) -> device::Value {
+    match (conversion, value) {
+        (Some(c), device::Value::Int(v)) => {
+            device::Value::Flt(c.apply(v as f64))
+        }
+        (Some(c), device::Value::Flt(v)) => device::Value::Flt(c.apply(v)),
+        (_, v) => v,
+    }
+}
+
+// Returns `true` if an expression that didn't compute a result had
+// values for all the variables it uses. This means the expression
+// has an error, like using values of the wrong type.
+
+fn is_expr_error(e: &compile::Expr, inputs: &[Option<device::Value>]) -> bool {
+    inputs
+        .iter()
+        .enumerate()
+        .all(|(idx, v)| v.is_some() || !e.uses_var(idx))
+}
 
 // These are some helpful type aliases.
 
@@ -30,11 +67,20 @@ type Inputs = Option<device::Value>;
 type InputStream = StreamMap<usize, device::DataStream<device::Reading>>;
 
 // Manages settings to a device. It makes sure we don't send duplicate
-// settings and it encapsulates the request/reply transaction.
+// settings and it encapsulates the request/reply transaction. It can
+// also ignore small changes to numeric settings and limit how often
+// settings are sent.
 
 pub struct Output {
     prev: Option<device::Value>,
     chan: driver::TxDeviceSetting,
+    deadband: Option<f64>,
+    min_interval: Option<Duration>,
+    next_send: Instant,
+    pending: Option<device::Value>,
+    last_set: Option<SystemTime>,
+    arbiter: Option<(arbiter::Arbiter, device::Name, arbiter::Source, i32)>,
+    dev: Option<device::Name>,
 }
 
 impl Output {
@@ -42,49 +88,275 @@ impl Output {
     // setting channel and starts with its setting history cleared.
 
     pub fn create(chan: driver::TxDeviceSetting) -> Self {
-        Output { prev: None, chan }
+        Output {
+            prev: None,
+            chan,
+            deadband: None,
+            min_interval: None,
+            next_send: Instant::now(),
+            pending: None,
+            last_set: None,
+            arbiter: None,
+            dev: None,
+        }
+    }
+
+    // Names the device the settings go to, so a driver that doesn't
+    // reply in time is reported as unresponsive.
+
+    pub fn with_device(self, dev: device::Name) -> Self {
+        Output {
+            dev: Some(dev),
+            ..self
+        }
+    }
+
+    // Sets the limits applied to settings. A numeric setting within
+    // `deadband` of the previous setting isn't sent. Settings are
+    // sent, at most, once per `min_interval`.
+
+    pub fn with_limits(
+        self,
+        deadband: Option<f64>,
+        min_interval: Option<Duration>,
+    ) -> Self {
+        Output {
+            deadband,
+            min_interval,
+            ..self
+        }
     }
 
-    // Attempts to set the associated device to a new value.
+    // Makes the settings subject to arbitration. Before sending a
+    // setting to `dev`, `source` has to claim the device at
+    // `priority`.
+
+    pub fn with_arbiter(
+        self,
+        arbiter: arbiter::Arbiter,
+        dev: device::Name,
+        source: arbiter::Source,
+        priority: i32,
+    ) -> Self {
+        Output {
+            arbiter: Some((arbiter, dev, source, priority)),
+            ..self
+        }
+    }
+
+    // Returns `true` if the value is close enough to the previous
+    // setting that it shouldn't be sent.
+
+    fn within_deadband(&self, value: &device::Value) -> bool {
+        let as_flt = |v: &device::Value| match v {
+            device::Value::Int(v) => Some(*v as f64),
+            device::Value::Flt(v) => Some(*v),
+            _ => None,
+        };
+
+        match (self.deadband, self.prev.as_ref().and_then(as_flt)) {
+            (Some(db), Some(prev)) => {
+                as_flt(value).is_some_and(|v| (v - prev).abs() < db)
+            }
+            _ => false,
+        }
+    }
+
+    // Attempts to set the associated device to a new value. If the
+    // minimum interval hasn't passed since the last setting, the
+    // value is held until `flush()` is called.
 
     pub async fn send(&mut self, value: device::Value) -> bool {
         // Only attempt the setting if it is different than the
         // previous setting we sent.
 
         if let Some(prev) = self.prev.as_ref() {
-            if *prev == value {
+            if *prev == value || self.within_deadband(&value) {
+                self.pending = None;
                 return true;
             }
         }
 
-        // Create the reply channel.
+        if self.min_interval.is_some() && Instant::now() < self.next_send {
+            self.pending = Some(value);
+            return true;
+        }
+
+        self.transmit(value).await
+    }
+
+    // Returns the last time a setting was accepted by the driver.
 
-        let (tx_rpy, rx_rpy) = oneshot::channel();
+    pub fn last_set(&self) -> Option<SystemTime> {
+        self.last_set
+    }
 
-        // Send the setting to the driver.
+    // Returns the time a held setting should be sent, if there's one.
 
-        if let Ok(()) = self.chan.send((value.clone(), tx_rpy)).await {
-            match rx_rpy.await {
-                Ok(Ok(v)) => {
-                    // If the driver adjusted our setting, add a
-                    // warning to the log.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|_| self.next_send)
+    }
 
-                    if v != value {
-                        warn!(
-                            "driver adjusted setting from {} to {}",
-                            &value, &v
-                        )
+    // Sends the held setting, if there's one.
+
+    pub async fn flush(&mut self) -> bool {
+        match self.pending.take() {
+            Some(value) => self.transmit(value).await,
+            None => true,
+        }
+    }
+
+    async fn transmit(&mut self, value: device::Value) -> bool {
+        self.pending = None;
+
+        // If another source, with a higher priority, controls the
+        // device, drop the setting. It isn't an error; the device
+        // simply isn't ours to control right now.
+
+        if let Some((arbiter, dev, source, priority)) = &self.arbiter {
+            if !arbiter.claim(dev, source, *priority) {
+                debug!("'{}' is controlled by a higher priority source", dev);
+                return true;
+            }
+        }
+
+        // Send the setting to the driver and wait for its reply. A
+        // driver that doesn't reply in time fails the setting, so it
+        // can't stall the logic block.
+
+        let request = async {
+            let (tx_rpy, rx_rpy) = oneshot::channel();
+
+            self.chan.send((value.clone(), tx_rpy)).await.map_err(|_| {
+                Error::MissingPeer("driver not accepting settings".into())
+            })?;
+            rx_rpy.await.unwrap_or_else(|_| {
+                Err(Error::MissingPeer("driver broke connection".into()))
+            })
+        };
+
+        match unresponsive::wait(self.dev.as_ref(), request).await {
+            Ok(v) => {
+                // If the driver adjusted our setting, add a warning
+                // to the log.
+
+                if v != value {
+                    warn!("driver adjusted setting from {} to {}", &value, &v)
+                }
+                self.prev = Some(value);
+                self.last_set = Some(clock::now());
+                if let Some(interval) = self.min_interval {
+                    self.next_send = Instant::now() + interval
+                }
+                true
+            }
+            Err(e) => {
+                error!("setting failed : {}", &e);
+                false
+            }
+        }
+    }
+}
+
+// The name used when logic blocks register devices with the core.
+
+const DRIVER_NAME: &str = "logic";
+
+// When a logic block is in dry-run mode, its settings are reported,
+// instead of sent. Each output device's setting is reported when it
+// changes.
+
+struct DryRun {
+    devs: Vec<device::Name>,
+    prev: Vec<Option<device::Value>>,
+    report: Option<driver::ReadOnlyDevice<String>>,
+}
+
+impl DryRun {
+    async fn report(&mut self, results: &[Option<device::Value>]) {
+        for ((dev, prev), value) in
+            self.devs.iter().zip(self.prev.iter_mut()).zip(results)
+        {
+            if let Some(value) = value {
+                if prev.as_ref() != Some(value) {
+                    info!("dry run: would set '{}' to {}", dev, value);
+                    if let Some(report) = self.report.as_mut() {
+                        report
+                            .report_update(format!("{} = {}", dev, value))
+                            .await
                     }
-                    self.prev = Some(value);
-                    return true;
+                    *prev = Some(value.clone())
                 }
-                Ok(Err(e)) => error!("driver rejected setting : {}", &e),
-                Err(e) => error!("setting failed : {}", &e),
             }
+        }
+    }
+}
+
+// Watches an input that has a `timeout`. If the input doesn't report
+// a reading within the timeout, it's considered stale and its value
+// is replaced with the fallback value (or no value, so expressions
+// using it don't produce a result.)
+
+struct Watchdog {
+    name: String,
+    timeout: Duration,
+    fallback: Option<device::Value>,
+    expires: Instant,
+    stale: bool,
+}
+
+impl Watchdog {
+    fn new(name: &str, secs: f64, fallback: Option<device::Value>) -> Self {
+        let timeout = Duration::from_secs_f64(secs);
+
+        Watchdog {
+            name: name.into(),
+            timeout,
+            fallback,
+            expires: Instant::now() + timeout,
+            stale: false,
+        }
+    }
+
+    // Returns the time the input becomes stale, if it isn't already.
+
+    fn deadline(&self) -> Option<Instant> {
+        (!self.stale).then_some(self.expires)
+    }
+
+    // Records a reading. Returns `true` if the reading is recent
+    // enough to be used. The first reading of a device may be an old
+    // one from the backend, so it's checked, too.
+
+    fn feed(&mut self, ts: SystemTime) -> bool {
+        let age = clock::now().duration_since(ts).unwrap_or(Duration::ZERO);
+
+        if age < self.timeout {
+            if self.stale {
+                info!("input '{}' is reporting again", &self.name);
+                self.stale = false
+            }
+            self.expires = Instant::now() + (self.timeout - age);
+            true
         } else {
-            error!("driver not accepting settings")
+            self.expire();
+            false
+        }
+    }
+
+    fn expire(&mut self) {
+        if !self.stale {
+            warn!(
+                "input '{}' hasn't reported in {:?} -- using {}",
+                &self.name,
+                self.timeout,
+                self.fallback
+                    .as_ref()
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "no value".into())
+            );
+            self.stale = true
         }
-        false
     }
 }
 
@@ -95,13 +367,34 @@ pub struct Node {
     solar_ch: Option<broadcast::Receiver<solar::Info>>,
     def_exprs: Vec<compile::Program>,
     exprs: Vec<(compile::Program, Output)>,
+    state: Option<(Vec<String>, state::Persist)>,
+    status: status::Status,
+    dry_run: Option<DryRun>,
+    conversions: Vec<Option<units::Conversion>>,
+
+    // Holds the time each input device last changed value. The
+    // ages of the inputs are stored at the end of `inputs`.
+    changed: Vec<Option<chrono::DateTime<chrono::Utc>>>,
+
+    // Holds the watchdog of each input device that has a timeout.
+    watchdogs: Vec<Option<Watchdog>>,
+
+    machine: Option<machine::Machine>,
+    alarms: Option<alarm::Alarms>,
+
+    // Holds the recent readings of inputs used by history
+    // aggregates.
+    history: HashMap<usize, history::Buffer>,
+
+    diag: Option<diag::Diagnostics>,
 }
 
 impl Node {
     // Iterate through the input device mapping. As we work through
     // the list, build three things:
     //
-    // 1) An array of the variable and definition names.
+    // 1) An array of the variable and definition names, followed by
+    // the names of the input ages ("NAME.age").
     //
     // 2) A chained set of streams which provide the readings.
     //
@@ -113,8 +406,9 @@ impl Node {
         vars: &HashMap<String, device::Name>,
         defs: &HashMap<String, String>,
     ) -> Result<(Vec<String>, InputStream, Vec<compile::Program>)> {
-        let mut inputs = Vec::with_capacity(vars.len() + defs.len());
-        let mut def_exprs = Vec::with_capacity(defs.len());
+        let mut inputs = Vec::with_capacity(vars.len() * 2 + defs.len());
+        let mut def_exprs: Vec<compile::Program> =
+            Vec::with_capacity(defs.len());
         let mut in_stream = StreamMap::with_capacity(vars.len());
 
         // Iterate through the input variable definitions. This maps
@@ -147,40 +441,135 @@ impl Node {
         // already verified the 'defs' names don't conflict with
         // 'inputs' names.)
 
-        for (name, expr) in defs {
-            // Add the definition's target name to the list of names.
+        let first_def = inputs.len();
+
+        inputs.extend(defs.keys().cloned());
 
-            inputs.push(name.clone());
+        // Each input device has a variable holding its age.
 
-            // Compile the expression. The length of the input slice
-            // is clipped to the size of the input variables. We do
-            // this so we don't include any variables created by
-            // definitions. This includes loops (a definition
-            // referring to itself) and referring to other defintions
-            // (because we can't enforce an order of evaluation.) The
-            // "outputs" are also the inputs since `defs` calculate
-            // values used by expressions and save their result in an
-            // input parameter.
+        let first_age = inputs.len();
 
-            let env = (&inputs[..vars.len()], &inputs[..]);
+        let ages: Vec<String> = inputs[..first_def]
+            .iter()
+            .map(|name| format!("{}.age", name))
+            .collect();
+
+        inputs.extend(ages);
+
+        // Compile the expressions. The "outputs" are also the inputs
+        // since `defs` calculate values used by expressions and save
+        // their result in an input parameter. Definitions may refer
+        // to other definitions, so all the names are available to
+        // each expression.
+
+        let mut unordered = Vec::with_capacity(defs.len());
+
+        for (name, expr) in defs {
+            let env = (&inputs[..], &inputs[..]);
             let result = compile::Program::compile(
                 &format!("{} -> {{{}}}", &expr, &name),
                 &env,
             )?;
 
             debug!("inp[{}] = {}", result.1, &result.0);
+            unordered.push(result);
+        }
 
-            // Add the program to the list of programs.
+        // Order the definitions so each one is evaluated after the
+        // definitions it uses. If no remaining definition is ready,
+        // there's a loop (which includes a definition referring to
+        // itself.)
 
-            def_exprs.push(result);
+        while !unordered.is_empty() {
+            let ready = unordered.iter().position(|compile::Program(e, _)| {
+                (first_def..first_age).all(|idx| {
+                    !e.uses_var(idx) || def_exprs.iter().any(|p| p.1 == idx)
+                })
+            });
+
+            match ready {
+                Some(idx) => def_exprs.push(unordered.swap_remove(idx)),
+                None => {
+                    let mut names: Vec<&str> = unordered
+                        .iter()
+                        .map(|p| inputs[p.1].as_str())
+                        .collect();
+
+                    names.sort_unstable();
+                    return Err(drmem_api::Error::ConfigError(format!(
+                        "'defs' have a circular reference: {}",
+                        names.join(", ")
+                    )));
+                }
+            }
         }
 
         Ok((inputs, in_stream, def_exprs))
     }
 
+    // Builds the unit conversion for each input listed in `units`.
+    // The units of an input device are obtained from the core. The
+    // returned vector has an entry for each element of `inputs`.
+
+    async fn setup_units(
+        c_req: &client::RequestChan,
+        inputs: &[String],
+        vars: &HashMap<String, device::Name>,
+        units: &HashMap<String, String>,
+    ) -> Result<Vec<Option<units::Conversion>>> {
+        let mut result = vec![None; inputs.len()];
+
+        for (name, to) in units {
+            let dev = &vars[name];
+            let from = c_req
+                .get_device_info(Some(dev.to_string()))
+                .await?
+                .pop()
+                .and_then(|info| info.units)
+                .ok_or_else(|| {
+                    drmem_api::Error::ConfigError(format!(
+                        "device '{}' doesn't report its units",
+                        dev
+                    ))
+                })?;
+            let conv = units::Conversion::new(&from, to).ok_or_else(|| {
+                drmem_api::Error::ConfigError(format!(
+                    "can't convert '{}' from \"{}\" to \"{}\"",
+                    dev, from, to
+                ))
+            })?;
+
+            debug!("converting '{}' from \"{}\" to \"{}\"", dev, from, to);
+            if let Some(idx) = inputs.iter().position(|v| v == name) {
+                result[idx] = Some(conv)
+            }
+        }
+        Ok(result)
+    }
+
+    // Returns the type of a device's values, which is the type of its
+    // latest reading. If the device hasn't reported a value, or can't
+    // be queried, its type isn't known.
+
+    async fn device_type(
+        c_req: &client::RequestChan,
+        dev: &device::Name,
+    ) -> compile::Type {
+        match c_req.get_device_info(Some(dev.to_string())).await {
+            Ok(mut info) => info
+                .pop()
+                .and_then(|info| info.last_point)
+                .map(|reading| compile::Type::of(&reading.value))
+                .unwrap_or(compile::Type::Any),
+            Err(_) => compile::Type::Any,
+        }
+    }
+
     async fn setup_outputs(
         c_req: &client::RequestChan,
         vars: &HashMap<String, device::Name>,
+        deadband: &HashMap<String, f64>,
+        min_interval: &HashMap<String, f64>,
     ) -> Result<(Vec<String>, Vec<Output>)> {
         let mut outputs = Vec::with_capacity(vars.len());
         let mut out_chans = Vec::with_capacity(vars.len());
@@ -195,7 +584,16 @@ impl Node {
                     // is also the index in the vector, so we know
                     // which entry to update.
 
-                    out_chans.push(Output::create(ch));
+                    out_chans.push(
+                        Output::create(ch)
+                            .with_device(dev.clone())
+                            .with_limits(
+                                deadband.get(vv).copied(),
+                                min_interval
+                                    .get(vv)
+                                    .map(|v| Duration::from_secs_f64(*v)),
+                            ),
+                    );
                     outputs.push(vv.clone());
 
                     debug!("out[{}] controls {}", outputs.len(), &dev)
@@ -212,17 +610,107 @@ impl Node {
     // Creates an instance of `Node` and initializes its state using
     // the configuration information.
 
+    // Reads the last saved state of the node and restores it into
+    // the expressions. Returns the object used to save future
+    // changes.
+
+    async fn setup_state(
+        c_req: &client::RequestChan,
+        dev: &device::Name,
+        keys: &[String],
+        progs: impl Iterator<Item = &mut compile::Program>,
+    ) -> Result<state::Persist> {
+        // Monitoring a device returns its last value right away, if
+        // it has one. If nothing arrives quickly, there's no saved
+        // state.
+
+        let mut strm = c_req.monitor_device(dev.clone(), None, None).await?;
+        let saved =
+            match tokio::time::timeout(Duration::from_millis(500), strm.next())
+                .await
+            {
+                Ok(Some(device::Reading {
+                    value: device::Value::Str(s),
+                    ..
+                })) => s.to_string(),
+                Ok(Some(device::Reading { value, .. })) => {
+                    warn!(
+                        "state device '{}' has a non-string value: {}",
+                        dev, value
+                    );
+                    String::new()
+                }
+                _ => String::new(),
+            };
+
+        if !saved.is_empty() {
+            info!("restoring state from '{}'", dev);
+            state::decode(
+                &saved,
+                keys.iter()
+                    .map(String::as_str)
+                    .zip(progs.map(|compile::Program(e, _)| e)),
+            );
+        }
+
+        let chan = c_req.get_setting_chan(dev.clone(), false).await?;
+
+        Ok(state::Persist::new(chan, saved))
+    }
+
+    // Reads the stored readings of an input, within `window`, into a
+    // history buffer. The stream of readings only ends when a newer
+    // reading arrives, so reading stops when the backend pauses.
+
+    async fn setup_history(
+        c_req: &client::RequestChan,
+        dev: &device::Name,
+        window: Duration,
+        conversion: Option<units::Conversion>,
+    ) -> history::Buffer {
+        let mut buf = history::Buffer::new(window);
+        let end = chrono::Utc::now();
+        let start = end
+            - chrono::Duration::from_std(window)
+                .unwrap_or(chrono::Duration::zero());
+
+        match c_req
+            .monitor_device(dev.clone(), Some(start), Some(end))
+            .await
+        {
+            Ok(mut strm) => {
+                while let Ok(Some(reading)) = tokio::time::timeout(
+                    Duration::from_millis(500),
+                    strm.next(),
+                )
+                .await
+                {
+                    buf.push(reading.ts, &convert(&conversion, reading.value))
+                }
+            }
+            Err(e) => warn!("couldn't read the history of '{}' -- {}", dev, e),
+        }
+        buf
+    }
+
     async fn init(
         c_req: client::RequestChan,
         c_time: broadcast::Receiver<tod::Info>,
         c_solar: broadcast::Receiver<solar::Info>,
         cfg: config::Logic,
+        status: status::Status,
+        arbiter: arbiter::Arbiter,
+        d_req: mpsc::Sender<driver::Request>,
     ) -> Result<Node> {
         debug!("compiling expressions");
 
-        if cfg.exprs.is_empty() {
+        if cfg.exprs.is_empty()
+            && cfg.machine.is_none()
+            && cfg.alarms.is_empty()
+        {
             return Err(drmem_api::Error::ConfigError(
-                "configuration doesn't define any expressions".into(),
+                "configuration doesn't define any expressions, state machine, or alarms"
+                    .into(),
             ));
         }
 
@@ -302,11 +790,154 @@ impl Node {
             }
         }
 
-        let (inputs, in_stream, def_exprs) =
+        // Validate the initial values of inputs. Each one has to
+        // belong to an input and has to be a valid device value.
+
+        let mut init = HashMap::with_capacity(cfg.init.len());
+
+        for (k, v) in &cfg.init {
+            if !cfg.inputs.contains_key(k) {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "'{}' in 'init' isn't defined in 'inputs'",
+                    k
+                )));
+            }
+            init.insert(
+                k.as_str(),
+                device::Value::try_from(v).map_err(|_| {
+                    drmem_api::Error::ConfigError(format!(
+                        "'init' has a bad value for '{}'",
+                        k
+                    ))
+                })?,
+            );
+        }
+
+        // Validate the input timeouts. Each one has to belong to an
+        // input and be positive. Fallback values are only used when
+        // an input times out.
+
+        for (k, v) in &cfg.timeout {
+            if !cfg.inputs.contains_key(k) {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "'{}' in 'timeout' isn't defined in 'inputs'",
+                    k
+                )));
+            }
+            if !(v.is_finite() && *v > 0.0) {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "'timeout' has a bad value for '{}': {}",
+                    k, v
+                )));
+            }
+        }
+
+        let mut fallback = HashMap::with_capacity(cfg.fallback.len());
+
+        for (k, v) in &cfg.fallback {
+            if !cfg.timeout.contains_key(k) {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "'{}' in 'fallback' doesn't have a 'timeout'",
+                    k
+                )));
+            }
+            fallback.insert(
+                k.as_str(),
+                device::Value::try_from(v).map_err(|_| {
+                    drmem_api::Error::ConfigError(format!(
+                        "'fallback' has a bad value for '{}'",
+                        k
+                    ))
+                })?,
+            );
+        }
+
+        if let Some(m) = &cfg.machine {
+            machine::validate(m, &cfg.outputs)?
+        }
+
+        alarm::validate(&cfg.alarms, &cfg.inputs, &cfg.outputs)?;
+
+        // Units can only be given for inputs.
+
+        if let Some(k) = cfg.units.keys().find(|k| !cfg.inputs.contains_key(*k))
+        {
+            return Err(drmem_api::Error::ConfigError(format!(
+                "'{}' in 'units' isn't defined in 'inputs'",
+                k
+            )));
+        }
+
+        // Validate the output limits. Each one has to belong to an
+        // output and can't be negative.
+
+        for (key, map) in [
+            ("deadband", &cfg.deadband),
+            ("min_interval", &cfg.min_interval),
+        ] {
+            for (k, v) in map {
+                if !cfg.outputs.contains_key(k) {
+                    return Err(drmem_api::Error::ConfigError(format!(
+                        "'{}' in '{}' isn't defined in 'outputs'",
+                        k, key
+                    )));
+                }
+                if !(v.is_finite() && *v >= 0.0) {
+                    return Err(drmem_api::Error::ConfigError(format!(
+                        "'{}' has a bad value for '{}': {}",
+                        key, k, v
+                    )));
+                }
+            }
+        }
+
+        // The state device is written by the node, so it can't be
+        // used by any expressions.
+
+        if let Some(dev) = &cfg.state {
+            if cfg
+                .inputs
+                .values()
+                .chain(cfg.outputs.values())
+                .any(|v| v == dev)
+            {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "state device '{}' is also used as an input or output",
+                    dev
+                )));
+            }
+        }
+
+        let (inputs, in_stream, mut def_exprs) =
             Node::setup_inputs(&c_req, &cfg.inputs, &cfg.defs).await?;
 
-        let (outputs, out_chans) =
-            Node::setup_outputs(&c_req, &cfg.outputs).await?;
+        let conversions =
+            Node::setup_units(&c_req, &inputs, &cfg.inputs, &cfg.units).await?;
+
+        let (outputs, out_chans) = Node::setup_outputs(
+            &c_req,
+            &cfg.outputs,
+            &cfg.deadband,
+            &cfg.min_interval,
+        )
+        .await?;
+
+        // The outputs compete with other logic blocks, and manual
+        // settings, for control of their devices.
+
+        let source = arbiter::Source::Logic(cfg.name.as_str().into());
+        let out_chans: Vec<Output> = out_chans
+            .into_iter()
+            .zip(&outputs)
+            .map(|(out, name)| {
+                out.with_arbiter(
+                    arbiter.clone(),
+                    cfg.outputs[name].clone(),
+                    source.clone(),
+                    cfg.priority,
+                )
+            })
+            .collect();
 
         // Create the input/output environment that the compiler can
         // use to compute the variables in the expression.
@@ -316,15 +947,16 @@ impl Node {
         // Iterate through the vector of strings. For each, compile it
         // into a `Program` type. Report the success or failure.
 
-        let exprs: Result<Vec<compile::Program>> = cfg
+        let exprs: Result<Vec<(compile::Program, &String)>> = cfg
             .exprs
             .iter()
             .map(|s| {
                 compile::Program::compile(s.as_str(), &env)
                     .map(compile::Program::optimize)
+                    .map(|prog| (prog, s))
             })
             .inspect(|e| match e {
-                Ok(ex) => debug!("out[{}] = {}", ex.1, &ex.0),
+                Ok((ex, _)) => debug!("out[{}] = {}", ex.1, &ex.0),
                 Err(e) => error!("{}", &e),
             })
             .collect();
@@ -334,8 +966,8 @@ impl Node {
         // output variables are in a hash map, so the vector is built
         // in whatever order the map uses. This might not be the same
         // order that the expressions are given. By sorting the
-        // expressions, we line them up so they can be zipped together
-        // later in this function.
+        // expressions, their results are reported, and sent, in the
+        // order of the outputs.
         //
         // XXX: This should be refactored. The parser should return
         // the output variable name instead of an index in the output
@@ -352,9 +984,246 @@ impl Node {
         // for unused output devices.
 
         exprs[..].sort_unstable_by(
-            |compile::Program(_, a), compile::Program(_, b)| a.cmp(b),
+            |(compile::Program(_, a), _), (compile::Program(_, b), _)| a.cmp(b),
+        );
+
+        // Let clients see the names of the inputs and the
+        // expressions.
+
+        status.init(
+            &inputs[..inputs.len() - cfg.inputs.len()],
+            exprs.iter().map(|(compile::Program(_, idx), src)| {
+                (src.as_str(), outputs[*idx].as_str())
+            }),
         );
 
+        // If the node saves its state, build the key used for each
+        // expression and restore the previous state. Definitions use
+        // the same text that was compiled for them.
+
+        let keys: Vec<String> = def_exprs
+            .iter()
+            .map(|compile::Program(_, idx)| {
+                let name = &inputs[*idx];
+
+                format!("{} -> {{{}}}", &cfg.defs[name], name)
+            })
+            .chain(exprs.iter().map(|(_, src)| (*src).clone()))
+            .collect();
+        let mut exprs: Vec<compile::Program> =
+            exprs.drain(..).map(|(prog, _)| prog).collect();
+
+        // Each output can only be set by one expression, by the
+        // state machine, or by alarms. Hand the setting channels to
+        // their users.
+
+        let mut out_chans: Vec<Option<Output>> =
+            out_chans.into_iter().map(Some).collect();
+        let expr_chans = exprs
+            .iter()
+            .map(|compile::Program(_, idx)| {
+                out_chans[*idx].take().ok_or_else(|| {
+                    drmem_api::Error::ConfigError(format!(
+                        "'{}' is set by more than one expression",
+                        &outputs[*idx]
+                    ))
+                })
+            })
+            .collect::<Result<Vec<Output>>>()?;
+
+        let mut machine = match &cfg.machine {
+            Some(m) => {
+                let mut m = machine::Machine::compile(
+                    m,
+                    &inputs,
+                    &outputs,
+                    cfg.dry_run,
+                )?;
+
+                for idx in m.outputs_used() {
+                    let out = out_chans[idx].take().ok_or_else(|| {
+                        drmem_api::Error::ConfigError(format!(
+                            "'{}' is set by an expression and the state machine",
+                            &outputs[idx]
+                        ))
+                    })?;
+
+                    m.add_output(idx, cfg.outputs[&outputs[idx]].clone(), out)
+                }
+                Some(m)
+            }
+            None => None,
+        };
+
+        let mut alarms = if cfg.alarms.is_empty() {
+            None
+        } else {
+            let mut a = alarm::Alarms::compile(
+                &cfg.alarms,
+                &inputs,
+                &outputs,
+                cfg.dry_run,
+            )?;
+
+            for idx in a.outputs_used() {
+                let out = out_chans[idx].take().ok_or_else(|| {
+                    drmem_api::Error::ConfigError(format!(
+                        "'{}' is an alarm sink and is set by an expression or the state machine",
+                        &outputs[idx]
+                    ))
+                })?;
+
+                a.add_output(idx, cfg.outputs[&outputs[idx]].clone(), out)
+            }
+            Some(a)
+        };
+
+        // Check the types used by the expressions. An input has the
+        // type of its device, or of its initial value, and inputs
+        // with units are converted to floats. A definition has the
+        // type of its expression. Each expression has to compute
+        // values its output device accepts.
+
+        let mut types = vec![compile::Type::Any; inputs.len()];
+
+        for (idx, name) in inputs[..cfg.inputs.len()].iter().enumerate() {
+            types[idx] = if conversions[idx].is_some() {
+                compile::Type::Flt
+            } else {
+                match Node::device_type(&c_req, &cfg.inputs[name]).await {
+                    compile::Type::Any => init
+                        .get(name.as_str())
+                        .map(compile::Type::of)
+                        .unwrap_or(compile::Type::Any),
+                    t => t,
+                }
+            }
+        }
+
+        for t in types[inputs.len() - cfg.inputs.len()..].iter_mut() {
+            *t = compile::Type::Int
+        }
+
+        for (compile::Program(e, idx), src) in def_exprs.iter().zip(&keys) {
+            types[*idx] = e.check(&types).map_err(|e| {
+                drmem_api::Error::ConfigError(format!("'{}': {}", src, e))
+            })?
+        }
+
+        for (compile::Program(e, idx), src) in
+            exprs.iter().zip(&keys[def_exprs.len()..])
+        {
+            let t = e.check(&types).map_err(|e| {
+                drmem_api::Error::ConfigError(format!("'{}': {}", src, e))
+            })?;
+            let dev = &cfg.outputs[&outputs[*idx]];
+            let expected = Node::device_type(&c_req, dev).await;
+
+            if !t.fits(expected) {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "'{}' computes {} values, but '{}' takes {} values",
+                    src, t, dev, expected
+                )));
+            }
+        }
+
+        // Find the inputs used by history aggregates and read their
+        // stored readings, within the longest window used.
+
+        let mut used = vec![];
+
+        def_exprs
+            .iter_mut()
+            .chain(exprs.iter_mut())
+            .map(|compile::Program(e, _)| e)
+            .chain(machine.iter_mut().flat_map(machine::Machine::exprs_mut))
+            .chain(alarms.iter_mut().flat_map(alarm::Alarms::exprs_mut))
+            .for_each(|e| e.history_used(&mut used));
+
+        let mut windows: HashMap<usize, u32> = HashMap::new();
+
+        for (idx, secs) in used {
+            if idx >= cfg.inputs.len() {
+                return Err(drmem_api::Error::ConfigError(format!(
+                    "history of '{}' isn't available; it isn't in 'inputs'",
+                    &inputs[idx]
+                )));
+            }
+
+            let w = windows.entry(idx).or_default();
+
+            *w = (*w).max(secs)
+        }
+
+        let mut history = HashMap::with_capacity(windows.len());
+
+        for (idx, secs) in windows {
+            let buf = Node::setup_history(
+                &c_req,
+                &cfg.inputs[&inputs[idx]],
+                Duration::from_secs(secs.into()),
+                conversions[idx],
+            )
+            .await;
+
+            history.insert(idx, buf);
+        }
+
+        // Devices created by the block are registered under its
+        // `prefix`.
+
+        let reg = cfg.prefix.as_ref().map(|prefix| {
+            driver::RequestChan::new(DRIVER_NAME.into(), prefix, &d_req)
+        });
+
+        // Blocks with a `prefix` report how they're running.
+
+        let diag = match &reg {
+            Some(reg) => Some(diag::Diagnostics::new(reg).await?),
+            None => None,
+        };
+
+        // If the block has a state machine, the name of the current
+        // state is reported by its `state` device.
+
+        if let (Some(m), Some(reg)) = (machine.as_mut(), &reg) {
+            m.set_report(reg.add_ro_device("state".parse()?, None, None).await?)
+        }
+
+        // In dry-run mode, the results are reported in the log and,
+        // if the block has a `prefix`, to its `dry-run` device.
+
+        let dry_run = if cfg.dry_run {
+            let report = if let Some(reg) = &reg {
+                Some(reg.add_ro_device("dry-run".parse()?, None, None).await?)
+            } else {
+                None
+            };
+
+            warn!("dry run -- settings won't be sent");
+            Some(DryRun {
+                devs: exprs
+                    .iter()
+                    .map(|compile::Program(_, idx)| {
+                        cfg.outputs[&outputs[*idx]].clone()
+                    })
+                    .collect(),
+                prev: vec![None; exprs.len()],
+                report,
+            })
+        } else {
+            None
+        };
+
+        let state = if let Some(dev) = &cfg.state {
+            let progs = def_exprs.iter_mut().chain(exprs.iter_mut());
+            let persist = Node::setup_state(&c_req, dev, &keys, progs).await?;
+
+            Some((keys, persist))
+        } else {
+            None
+        };
+
         // Look at each expression and see if it needs the
         // time-of-day.
 
@@ -362,6 +1231,8 @@ impl Node {
             .iter()
             .chain(&def_exprs)
             .filter_map(|compile::Program(e, _)| e.uses_time())
+            .chain(machine.as_ref().and_then(machine::Machine::uses_time))
+            .chain(alarms.as_ref().and_then(alarm::Alarms::uses_time))
             .min();
 
         // Look at each expression and see if it needs any solar
@@ -370,29 +1241,62 @@ impl Node {
         let needs_solar = exprs
             .iter()
             .chain(&def_exprs)
-            .any(|compile::Program(e, _)| e.uses_solar());
+            .any(|compile::Program(e, _)| e.uses_solar())
+            || machine.as_ref().is_some_and(machine::Machine::uses_solar)
+            || alarms.as_ref().is_some_and(alarm::Alarms::uses_solar);
 
         // Return the initialized `Node`.
 
         Ok(Node {
-            inputs: vec![None; inputs.len()],
+            inputs: inputs
+                .iter()
+                .map(|name| init.get(name.as_str()).cloned())
+                .collect(),
             in_stream,
             time_ch: needs_time
                 .map(|tf| tod::time_filter(BroadcastStream::new(c_time), tf)),
             solar_ch: if needs_solar { Some(c_solar) } else { None },
             def_exprs,
-            exprs: exprs.drain(..).zip(out_chans).collect(),
+            exprs: exprs.drain(..).zip(expr_chans).collect(),
+            machine,
+            alarms,
+            history,
+            diag,
+            state,
+            status,
+            dry_run,
+            conversions,
+            changed: vec![None; cfg.inputs.len()],
+            watchdogs: inputs[..cfg.inputs.len()]
+                .iter()
+                .map(|name| {
+                    cfg.timeout.get(name).map(|secs| {
+                        Watchdog::new(
+                            name,
+                            *secs,
+                            fallback.get(name.as_str()).cloned(),
+                        )
+                    })
+                })
+                .collect(),
         })
     }
 
     // Runs the node logic. This method should never return.
 
     async fn run(mut self) -> Result<Infallible> {
-        let mut time = Arc::new((chrono::Utc::now(), chrono::Local::now()));
+        let now = chrono::Utc::now();
+        let mut time = Arc::new((now, tod::to_local(&now)));
         let mut solar = None;
 
         info!("starting");
 
+        // A state machine sets the outputs of its initial state.
+
+        if let Some(m) = self.machine.as_mut() {
+            m.enter().await;
+        }
+
         loop {
             // Create a future that yields the time-of-day using the
             // TimeFilter. If no expression uses time, then `time_ch`
@@ -418,12 +1322,49 @@ impl Node {
                 }
             };
 
-            #[rustfmt::skip]
-	    tokio::select! {
-		biased;
+            // If the state of the node needs to be saved, this is
+            // when it should happen.
 
-		// If we need the solar channel, wait for the next
-		// update.
+            let save_at = self.state.as_ref().and_then(|(_, p)| p.deadline());
+
+            // Find the earliest time a held output setting should be
+            // sent.
+
+            let flush_at = self
+                .exprs
+                .iter_mut()
+                .map(|(_, out)| out)
+                .chain(self.machine.iter_mut().flat_map(|m| m.outputs_mut()))
+                .chain(self.alarms.iter_mut().flat_map(|a| a.outputs_mut()))
+                .filter_map(|out| out.deadline())
+                .min();
+
+            // Find the earliest time an alarm's delay, or repeat
+            // interval, expires.
+
+            let alarm_at =
+                self.alarms.as_ref().and_then(alarm::Alarms::deadline);
+
+            // Find the time the diagnostic devices should be updated.
+
+            let report_at =
+                self.diag.as_ref().and_then(diag::Diagnostics::deadline);
+
+            // Find the earliest time an input becomes stale.
+
+            let stale_at = self
+                .watchdogs
+                .iter()
+                .flatten()
+                .filter_map(Watchdog::deadline)
+                .min();
+
+            #[rustfmt::skip]
+	    tokio::select! {
+		biased;
+
+		// If we need the solar channel, wait for the next
+		// update.
 
 		v = wait_for_solar => {
 		    match v {
@@ -455,65 +1396,299 @@ impl Node {
 
 		Some((idx, reading)) = self.in_stream.next() => {
 		    // Save the reading in our array for future
-		    // recalculations.
+		    // recalculations. If the input has different
+		    // units than the expressions expect, convert it.
 
-		    self.inputs[idx] = Some(reading.value);
+		    let value = convert(&self.conversions[idx], reading.value);
+
+		    // Inputs used by history aggregates keep their
+		    // recent readings.
+
+		    if let Some(buf) = self.history.get_mut(&idx) {
+			buf.push(reading.ts, &value)
+		    }
+
+		    // Remember when the input changed, so its age can
+		    // be computed.
+
+		    if self.changed[idx].is_none()
+			|| self.inputs[idx].as_ref() != Some(&value)
+		    {
+			self.changed[idx] = Some(reading.ts.into())
+		    }
+
+		    // If the input has a timeout, restart it. A reading
+		    // that's already too old is replaced by the fallback
+		    // value.
+
+		    self.inputs[idx] = match self.watchdogs[idx].as_mut() {
+			Some(w) => {
+			    if w.feed(reading.ts) {
+				Some(value)
+			    } else {
+				w.fallback.clone()
+			    }
+			}
+			None => Some(value),
+		    };
+		}
+
+		// Replace the value of inputs that haven't reported
+		// within their timeout.
+
+		_ = tokio::time::sleep_until(
+		    stale_at.unwrap_or_else(Instant::now)
+		), if stale_at.is_some() => {
+		    let now = Instant::now();
+
+		    for (idx, w) in self.watchdogs.iter_mut().enumerate() {
+			if let Some(w) = w.as_mut().filter(|w| {
+			    w.deadline().is_some_and(|t| t <= now)
+			}) {
+			    w.expire();
+			    self.inputs[idx] = w.fallback.clone()
+			}
+		    }
+		}
+
+		// Check the alarms whose delay, or repeat interval,
+		// has expired.
+
+		_ = tokio::time::sleep_until(
+		    alarm_at.unwrap_or_else(Instant::now)
+		), if alarm_at.is_some() => {}
+
+		// Send output settings that were held because of
+		// their minimum interval. No inputs changed, so the
+		// expressions don't need to be evaluated.
+
+		_ = tokio::time::sleep_until(
+		    flush_at.unwrap_or_else(Instant::now)
+		), if flush_at.is_some() => {
+		    let now = Instant::now();
+
+		    join_all(
+			self.exprs
+			    .iter_mut()
+			    .map(|(_, out)| out)
+			    .chain(
+				self.machine.iter_mut().flat_map(|m| m.outputs_mut())
+			    )
+			    .chain(
+				self.alarms.iter_mut().flat_map(|a| a.outputs_mut())
+			    )
+			    .filter_map(|out| {
+				out.deadline()
+				    .filter(|t| *t <= now)
+				    .map(|_| out.flush())
+			    })
+		    )
+		    .await;
+		    self.status.set_outputs(
+			self.exprs.iter().map(|(_, out)| out.last_set())
+		    );
+		    continue
+		}
+
+		// Update the diagnostic devices. This doesn't change
+		// any inputs, so the expressions don't need to be
+		// evaluated.
+
+		_ = tokio::time::sleep_until(
+		    report_at.unwrap_or_else(Instant::now)
+		), if report_at.is_some() => {
+		    if let Some(diag) = self.diag.as_mut() {
+			diag.report().await
+		    }
+		    continue
+		}
+
+		// Save the node's state. This doesn't change any
+		// inputs, so the expressions don't need to be
+		// evaluated.
+
+		_ = tokio::time::sleep_until(
+		    save_at.unwrap_or_else(Instant::now)
+		), if save_at.is_some() => {
+		    if let Some((_, persist)) = self.state.as_mut() {
+			persist.save().await
+		    }
+		    continue
 		}
 	    }
 
+            let started = Instant::now();
+            let mut failures = 0;
+
+            // Update the ages of the inputs.
+
+            let first_age = self.inputs.len() - self.changed.len();
+
+            for (idx, ts) in self.changed.iter().enumerate() {
+                self.inputs[first_age + idx] =
+                    ts.map(|ts| {
+                        device::Value::Int(
+                            (time.0 - ts).num_seconds().max(0) as i32
+                        )
+                    })
+            }
+
+            // Update the history aggregates.
+
+            if !self.history.is_empty() {
+                let now = clock::now();
+                let history = &self.history;
+                let f = |agg, idx, secs: u32| {
+                    history.get(&idx).and_then(|buf| {
+                        buf.aggregate(
+                            agg,
+                            Duration::from_secs(secs.into()),
+                            now,
+                        )
+                    })
+                };
+
+                self.def_exprs
+                    .iter_mut()
+                    .chain(self.exprs.iter_mut().map(|(p, _)| p))
+                    .map(|compile::Program(e, _)| e)
+                    .chain(
+                        self.machine
+                            .iter_mut()
+                            .flat_map(machine::Machine::exprs_mut),
+                    )
+                    .chain(
+                        self.alarms
+                            .iter_mut()
+                            .flat_map(alarm::Alarms::exprs_mut),
+                    )
+                    .for_each(|e| e.update_history(&f))
+            }
+
             // Calculate each expression of the `defs` array. Store
             // each expression's result in the associated `input`
             // cell.
 
-            self.def_exprs
-                .iter()
-                .for_each(|compile::Program(expr, idx)| {
+            self.def_exprs.iter_mut().for_each(
+                |compile::Program(expr, idx)| {
                     self.inputs[*idx] =
                         compile::eval(expr, &self.inputs, &time, solar.as_ref())
-                });
+                },
+            );
+
+            // Let the state machine move to its next state.
+
+            if let Some(m) = self.machine.as_mut() {
+                if m.step(&self.inputs, &time, solar.as_ref()) {
+                    failures += m.enter().await
+                }
+            }
 
             // Calculate each of the final expressions. If there are
-            // more than one expressions in this node, they are
-            // evaluated concurrently.
+            // more than one expressions in this node, their settings
+            // are sent concurrently.
 
-            join_all(self.exprs.iter_mut().filter_map(
-                |(compile::Program(expr, _), out)| {
+            let results: Vec<Option<device::Value>> = self
+                .exprs
+                .iter_mut()
+                .map(|(compile::Program(expr, _), _)| {
                     compile::eval(expr, &self.inputs, &time, solar.as_ref())
-                        .map(|v| out.send(v))
-                },
-            ))
-            .await;
+                })
+                .collect();
+
+            if let Some(dry_run) = self.dry_run.as_mut() {
+                dry_run.report(&results).await
+            } else {
+                failures +=
+                    join_all(self.exprs.iter_mut().zip(&results).filter_map(
+                        |((_, out), v)| v.clone().map(|v| out.send(v)),
+                    ))
+                    .await
+                    .iter()
+                    .filter(|ok| !**ok)
+                    .count();
+            }
+
+            // Check the alarms and send their notifications.
+
+            if let Some(a) = self.alarms.as_mut() {
+                failures += a.update(&self.inputs, &time, solar.as_ref()).await
+            }
+
+            // Record the evaluation in the diagnostic devices.
+
+            if let Some(diag) = self.diag.as_mut() {
+                let errors = self
+                    .def_exprs
+                    .iter()
+                    .filter(|compile::Program(e, idx)| {
+                        self.inputs[*idx].is_none()
+                            && is_expr_error(e, &self.inputs)
+                    })
+                    .count()
+                    + self
+                        .exprs
+                        .iter()
+                        .zip(&results)
+                        .filter(|((compile::Program(e, _), _), v)| {
+                            v.is_none() && is_expr_error(e, &self.inputs)
+                        })
+                        .count();
+
+                diag.record(started.elapsed(), failures, errors).await
+            }
+
+            // Let clients see what happened.
+
+            self.status.update(&self.inputs, &results);
+            self.status
+                .set_outputs(self.exprs.iter().map(|(_, out)| out.last_set()));
+
+            // Record the state of the functions in the expressions,
+            // if the node saves it.
+
+            if let Some((keys, persist)) = self.state.as_mut() {
+                persist.update(state::encode(
+                    keys.iter().map(String::as_str).zip(
+                        self.def_exprs
+                            .iter()
+                            .chain(self.exprs.iter().map(|(p, _)| p))
+                            .map(|compile::Program(e, _)| e),
+                    ),
+                ))
+            }
         }
     }
 
-    // Starts a new instance of a logic node.
+    // Runs a new instance of a logic node. The returned future is
+    // meant to be placed in a task by the logic manager.
 
-    pub fn start(
+    pub async fn start(
         c_req: client::RequestChan,
         rx_tod: broadcast::Receiver<tod::Info>,
         rx_solar: broadcast::Receiver<solar::Info>,
         cfg: config::Logic,
-    ) -> JoinHandle<Result<Infallible>> {
+        status: status::Status,
+        arbiter: arbiter::Arbiter,
+        d_req: mpsc::Sender<driver::Request>,
+    ) -> Result<Infallible> {
         let name = cfg.name.clone();
 
-        // Put the node in the background.
+        // Create a new instance and let it initialize itself. If an
+        // error occurs, return it.
 
-        tokio::spawn(async move {
-            // Create a new instance and let it initialize itself. If
-            // an error occurs, return it.
-
-            let node = Node::init(c_req, rx_tod, rx_solar, cfg)
+        let node =
+            Node::init(c_req, rx_tod, rx_solar, cfg, status, arbiter, d_req)
                 .instrument(info_span!("logic-init", name = &name))
                 .await?;
 
-            node.run().instrument(info_span!("logic", name)).await
-        })
+        node.run().instrument(info_span!("logic", name)).await
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{config, solar, tod, Node};
+    use super::{compile, config, is_expr_error, solar, tod, Node};
     use drmem_api::{
         client::{self, Request},
         device, driver, Error, Result,
@@ -583,12 +1758,15 @@ mod test {
             // Start the logic block with the proper communciation
             // channels and configuration.
 
-            let node = Node::start(
+            let node = task::spawn(Node::start(
                 client::RequestChan::new(tx_req),
                 tx_tod.subscribe(),
                 tx_solar.subscribe(),
                 cfg,
-            );
+                super::status::Status::default(),
+                super::arbiter::Arbiter::default(),
+                mpsc::channel(1).0,
+            ));
 
             // Create the 'stop' channel.
 
@@ -629,6 +1807,24 @@ mod test {
                                     Error::ProtocolError("bad request".into()),
                                 ));
                             }
+                            Request::SetDeviceMeta { rpy_chan, .. } => {
+                                let _ = rpy_chan.send(Err(
+                                    Error::ProtocolError("bad request".into()),
+                                ));
+                            }
+                            Request::MonitorDevices { rpy_chan, .. } => {
+                                let _ = rpy_chan.send(Err(
+                                    Error::ProtocolError("bad request".into()),
+                                ));
+                            }
+                            Request::AggregateHistory { rpy_chan, .. } => {
+                                let _ = rpy_chan.send(Err(
+                                    Error::ProtocolError("bad request".into()),
+                                ));
+                            }
+                            Request::Ping { rpy_chan } => {
+                                let _ = rpy_chan.send(Ok(()));
+                            }
                             Request::MonitorDevice {
                                 name, rpy_chan, ..
                             } => {
@@ -727,6 +1923,74 @@ mod test {
         h.await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_send_deadband() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut o = super::Output::create(tx).with_limits(Some(1.0), None);
+        let h = task::spawn(async move {
+            assert!(o.send(device::Value::Flt(10.0)).await);
+            assert!(o.send(device::Value::Flt(10.5)).await);
+            assert!(o.send(device::Value::Int(9)).await);
+            assert!(o.send(device::Value::Flt(11.0)).await);
+            assert!(o.send(device::Value::Bool(true)).await);
+        });
+
+        for expected in [
+            device::Value::Flt(10.0),
+            device::Value::Int(9),
+            device::Value::Flt(11.0),
+            device::Value::Bool(true),
+        ] {
+            let (v, tx) = rx.recv().await.unwrap();
+
+            assert_eq!(v, expected);
+            assert!(tx.send(Ok(v)).is_ok());
+        }
+
+        h.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_min_interval() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut o = super::Output::create(tx)
+            .with_limits(None, Some(Duration::from_millis(100)));
+        let h = task::spawn(async move {
+            // The first setting goes out right away. The next two
+            // are held and only the last one is sent when the
+            // interval has passed.
+
+            assert!(o.send(device::Value::Int(1)).await);
+            assert_eq!(o.deadline(), None);
+            assert!(o.send(device::Value::Int(2)).await);
+            assert!(o.send(device::Value::Int(3)).await);
+
+            let deadline = o.deadline().unwrap();
+
+            time::sleep_until(deadline).await;
+            assert!(o.flush().await);
+            assert_eq!(o.deadline(), None);
+
+            // Returning to the value that was sent drops the held
+            // setting.
+
+            assert!(o.send(device::Value::Int(4)).await);
+            assert!(o.deadline().is_some());
+            assert!(o.send(device::Value::Int(3)).await);
+            assert_eq!(o.deadline(), None);
+        });
+
+        for expected in [device::Value::Int(1), device::Value::Int(3)] {
+            let (v, tx) = rx.recv().await.unwrap();
+
+            assert_eq!(v, expected);
+            assert!(tx.send(Ok(v)).is_ok());
+        }
+
+        h.await.unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+
     // Builds a future that will create a node. When awaiting on this
     // future, another task needs to handle potential requests
     // initiated by the node, over the `mpsc_rx` channel. These
@@ -745,7 +2009,15 @@ mod test {
         let c_req = client::RequestChan::new(mpsc_tx);
         let (tod_tx, c_time) = broadcast::channel(10);
         let (sol_tx, c_solar) = broadcast::channel(10);
-        let node_fut = Node::init(c_req, c_time, c_solar, cfg);
+        let node_fut = Node::init(
+            c_req,
+            c_time,
+            c_solar,
+            cfg,
+            super::status::Status::default(),
+            super::arbiter::Arbiter::default(),
+            mpsc::channel(1).0,
+        );
 
         (node_fut, mpsc_rx, tod_tx, sol_tx)
     }
@@ -773,6 +2045,18 @@ mod test {
                 .collect(),
             defs: defs.iter().map(|&(a, b)| (a.into(), b.into())).collect(),
             exprs: exprs.iter().map(|&a| a.into()).collect(),
+            init: HashMap::new(),
+            deadband: HashMap::new(),
+            min_interval: HashMap::new(),
+            state: None,
+            priority: 0,
+            dry_run: false,
+            prefix: None,
+            units: HashMap::new(),
+            timeout: HashMap::new(),
+            fallback: HashMap::new(),
+            machine: None,
+            alarms: vec![],
         }
     }
 
@@ -846,6 +2130,116 @@ mod test {
             assert!(matches!(node.await, Err(Error::ConfigError(_))));
         }
 
+        // Test that we reject definitions that refer to themselves
+        // or to each other in a loop.
+
+        for defs in [
+            &[("a", "{a} + 1")][..],
+            &[("a", "{b} + 1"), ("b", "{c} + 1"), ("c", "{a} + 1")][..],
+        ] {
+            let cfg = build_config(
+                &[],
+                &[("out", "device:out")],
+                defs,
+                &["{a} -> {out}"],
+            );
+            let (node, _, _, _) = init_node(cfg);
+
+            assert!(matches!(node.await, Err(Error::ConfigError(_))));
+        }
+
+        // Test that we reject initial values for unknown inputs or
+        // with bad values.
+
+        for (name, value) in [
+            ("bad", toml::value::Value::Integer(1)),
+            ("in", toml::value::Value::Integer(1 << 40)),
+        ] {
+            let mut cfg = build_config(
+                &[("in", "device:in")],
+                &[("out", "device:out")],
+                &[],
+                &["{in} -> {out}"],
+            );
+
+            cfg.init.insert(name.into(), value);
+
+            let (node, _, _, _) = init_node(cfg);
+
+            assert!(matches!(node.await, Err(Error::ConfigError(_))));
+        }
+
+        // Test that we reject units for unknown inputs.
+
+        {
+            let mut cfg = build_config(
+                &[("in", "device:in")],
+                &[("out", "device:out")],
+                &[],
+                &["{in} -> {out}"],
+            );
+
+            cfg.units.insert("bad".into(), "°F".into());
+
+            let (node, _, _, _) = init_node(cfg);
+
+            assert!(matches!(node.await, Err(Error::ConfigError(_))));
+        }
+
+        // Test that we reject timeouts for unknown inputs or with
+        // bad values, and fallback values without a timeout.
+
+        for (timeout, fallback) in [
+            (Some(("bad", 1.0)), None),
+            (Some(("in", 0.0)), None),
+            (Some(("in", f64::NAN)), None),
+            (None, Some(("in", toml::value::Value::Boolean(false)))),
+            (
+                Some(("in", 1.0)),
+                Some(("in", toml::value::Value::Integer(1 << 40))),
+            ),
+        ] {
+            let mut cfg = build_config(
+                &[("in", "device:in")],
+                &[("out", "device:out")],
+                &[],
+                &["{in} -> {out}"],
+            );
+
+            cfg.timeout.extend(timeout.map(|(k, v)| (k.into(), v)));
+            cfg.fallback.extend(fallback.map(|(k, v)| (k.into(), v)));
+
+            let (node, _, _, _) = init_node(cfg);
+
+            assert!(matches!(node.await, Err(Error::ConfigError(_))));
+        }
+
+        // Test that a state machine has to start in a defined state.
+
+        {
+            let mut cfg = build_config(
+                &[("in", "device:in")],
+                &[("out", "device:out")],
+                &[],
+                &[],
+            );
+
+            cfg.machine = Some(config::Machine {
+                initial: "idle".into(),
+                states: HashMap::from([(
+                    "busy".into(),
+                    config::MachineState {
+                        outputs: HashMap::new(),
+                        transitions: vec![],
+                    },
+                )]),
+            });
+
+            let (node, _, _, _) = init_node(cfg);
+
+            assert!(matches!(node.await, Err(Error::ConfigError(_))));
+        }
+
         // Test that we reject two outputs with the same device.
 
         {
@@ -919,6 +2313,95 @@ mod test {
         assert_eq!(emu.await.unwrap(), Ok(true));
     }
 
+    // Test that initial values let a block compute a result before
+    // all of its inputs have reported.
+
+    #[tokio::test]
+    async fn test_init_values() {
+        let mut cfg = build_config(
+            &[("in1", "device:in1"), ("in2", "device:in2")],
+            &[("out", "device:out")],
+            &[],
+            &["{in1} + {in2} -> {out}"],
+        );
+
+        cfg.init
+            .insert("in2".into(), toml::value::Value::Integer(10));
+
+        let (tx_in1, rx_in1) = mpsc::channel(100);
+        let (tx_in2, rx_in2) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:in1".into(), rx_in1), ("device:in2".into(), rx_in2)],
+            vec![("device:out".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        // Only the first input reports. The initial value of the
+        // second is used.
+
+        assert!(tx_in1.send(device::Value::Int(1)).await.is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Int(11));
+
+        // Once the second input reports, its value replaces the
+        // initial value.
+
+        assert!(tx_in2.send(device::Value::Int(2)).await.is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Int(3));
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
+    // Test that a block in dry-run mode doesn't send settings.
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let mut cfg = build_config(
+            &[("in", "device:in")],
+            &[("out", "device:out")],
+            &[],
+            &["{in} > 5 -> {out}"],
+        );
+
+        cfg.dry_run = true;
+
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:in".into(), rx_in)],
+            vec![("device:out".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        assert!(tx_in.send(device::Value::Int(10)).await.is_ok());
+        assert!(tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            rx_out.recv()
+        )
+        .await
+        .is_err());
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
     // Test a basic logic block in which an input device's value is
     // used in a calculation and then forwarded to an output device.
 
@@ -974,6 +2457,376 @@ mod test {
         assert_eq!(emu.await.unwrap(), Ok(true));
     }
 
+    #[test]
+    fn test_expr_error() {
+        let env = (
+            &[String::from("a"), String::from("b")][..],
+            &[String::from("out")][..],
+        );
+        let prog = |s| compile::Program::compile(s, &env).unwrap().0;
+
+        // Missing inputs aren't errors; bad types are.
+
+        assert!(!is_expr_error(
+            &prog("{a} + 1 -> {out}"),
+            &[None, Some(device::Value::Int(1))]
+        ));
+        assert!(is_expr_error(
+            &prog("{a} + 1 -> {out}"),
+            &[Some(device::Value::Bool(true)), None]
+        ));
+    }
+
+    // Test that type errors are found when the block starts. The
+    // emulator doesn't report device types, so the types come from
+    // the initial values.
+
+    #[tokio::test]
+    async fn test_type_check() {
+        for (defs, exprs) in [
+            (&[][..], &["not {a} -> {out}"][..]),
+            (&[("b", "{a} + \"!\"")][..], &["{b} -> {out}"][..]),
+            (&[("b", "{a} > 1")][..], &["{b} / 2 -> {out}"][..]),
+        ] {
+            let mut cfg = build_config(
+                &[("a", "device:a")],
+                &[("out", "device:out")],
+                defs,
+                exprs,
+            );
+
+            cfg.init.insert("a".into(), toml::value::Value::Integer(1));
+
+            let (_, rx_in) = mpsc::channel(100);
+            let (tx_out, _) = mpsc::channel(100);
+
+            let (_, _, emu, _tx_stop) = Emulator::start(
+                vec![("device:a".into(), rx_in)],
+                vec![("device:out".into(), tx_out)],
+                cfg,
+            )
+            .await
+            .unwrap();
+
+            assert!(
+                matches!(emu.await.unwrap(), Err(Error::ConfigError(_))),
+                "{:?} should have failed",
+                exprs
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let cfg = build_config(
+            &[("rain", "device:rain")],
+            &[("out", "device:out")],
+            &[],
+            &["AVG_HIST({rain}, 3600) -> {out}"],
+        );
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:rain".into(), rx_in)],
+            vec![("device:out".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        // The emulator has no stored history, so the aggregate only
+        // uses the readings received by the block.
+
+        for (reading, avg) in [(10, 10.0), (20, 15.0), (0, 10.0)] {
+            assert!(tx_in.send(device::Value::Int(reading)).await.is_ok());
+
+            let (value, rpy) = rx_out.recv().await.unwrap();
+            let _ = rpy.send(Ok(value.clone()));
+
+            assert_eq!(value, device::Value::Flt(avg));
+        }
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
+    // Test that inputs report their age.
+
+    #[tokio::test]
+    async fn test_input_age() {
+        let cfg = build_config(
+            &[("door", "device:in")],
+            &[("out", "device:out")],
+            &[],
+            &["{door.age} > 600 -> {out}"],
+        );
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (tx_tod, _, emu, tx_stop) = Emulator::start(
+            vec![("device:in".into(), rx_in)],
+            vec![("device:out".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+        let tod = |secs| {
+            Arc::new((
+                chrono::Utc::now() + chrono::Duration::seconds(secs),
+                chrono::Local::now().fixed_offset(),
+            ))
+        };
+
+        // The input just changed, so it's young.
+
+        assert!(tx_in.send(device::Value::Bool(true)).await.is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(false));
+
+        // Move time forward.
+
+        assert!(tx_tod.send(tod(901)).is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(true));
+
+        // A new value resets the age.
+
+        assert!(tx_in.send(device::Value::Bool(false)).await.is_ok());
+        assert!(tx_tod.send(tod(0)).is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(false));
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
+    // Test that inputs which stop reporting use their fallback
+    // value.
+
+    #[tokio::test]
+    async fn test_input_timeout() {
+        let mut cfg = build_config(
+            &[("in", "device:in")],
+            &[("out", "device:out")],
+            &[],
+            &["{in} -> {out}"],
+        );
+
+        cfg.timeout.insert("in".into(), 0.2);
+        cfg.fallback
+            .insert("in".into(), toml::value::Value::Boolean(false));
+
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:in".into(), rx_in)],
+            vec![("device:out".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        // While the input keeps reporting, its value is used.
+
+        for _ in 0..3 {
+            assert!(tx_in.send(device::Value::Bool(true)).await.is_ok());
+            time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(true));
+        assert!(rx_out.try_recv().is_err());
+
+        // When the input stops reporting, the fallback is used.
+
+        let (value, rpy) = time::timeout(Duration::from_secs(1), rx_out.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(false));
+
+        // Once it reports again, its value is used.
+
+        assert!(tx_in.send(device::Value::Bool(true)).await.is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(true));
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
+    #[tokio::test]
+    async fn test_state_machine() {
+        let mut cfg = build_config(
+            &[("power", "device:power")],
+            &[("done", "device:done")],
+            &[],
+            &[],
+        );
+        let state =
+            |done: bool, transitions: &[(&str, &str)]| config::MachineState {
+                outputs: HashMap::from([(
+                    "done".into(),
+                    toml::value::Value::Boolean(done),
+                )]),
+                transitions: transitions
+                    .iter()
+                    .map(|(when, to)| config::Transition {
+                        when: when.to_string(),
+                        to: to.to_string(),
+                    })
+                    .collect(),
+            };
+
+        cfg.machine = Some(config::Machine {
+            initial: "idle".into(),
+            states: HashMap::from([
+                ("idle".into(), state(false, &[("{power} > 10", "running")])),
+                (
+                    "running".into(),
+                    state(false, &[("{power} < 5", "finished")]),
+                ),
+                (
+                    "finished".into(),
+                    state(true, &[("{power} > 10", "running")]),
+                ),
+            ]),
+        });
+
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:power".into(), rx_in)],
+            vec![("device:done".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        // Entering the initial state sets its outputs.
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(false));
+
+        // Moving to "running" doesn't change the output, so nothing
+        // is sent. Dropping the power finishes the cycle.
+
+        for power in [20, 15, 2] {
+            assert!(tx_in.send(device::Value::Int(power)).await.is_ok());
+        }
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(true));
+
+        // A new cycle starts when the power comes back.
+
+        assert!(tx_in.send(device::Value::Int(3)).await.is_ok());
+        assert!(tx_in.send(device::Value::Int(30)).await.is_ok());
+
+        let (value, rpy) = rx_out.recv().await.unwrap();
+        let _ = rpy.send(Ok(value.clone()));
+
+        assert_eq!(value, device::Value::Bool(false));
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
+    #[tokio::test]
+    async fn test_alarm() {
+        let mut cfg = build_config(
+            &[("level", "device:level")],
+            &[("notify", "device:notify")],
+            &[],
+            &[],
+        );
+
+        cfg.alarms.push(config::Alarm {
+            name: "sump".into(),
+            when: "{level} > 10".into(),
+            sink: "notify".into(),
+            message: "sump level is {level}".into(),
+            severity: config::Severity::Critical,
+            delay: None,
+            repeat: Some(0.2),
+            ack: None,
+        });
+
+        let (tx_in, rx_in) = mpsc::channel(100);
+        let (tx_out, mut rx_out) = mpsc::channel(100);
+
+        let (_, _, emu, tx_stop) = Emulator::start(
+            vec![("device:level".into(), rx_in)],
+            vec![("device:notify".into(), tx_out)],
+            cfg,
+        )
+        .await
+        .unwrap();
+
+        async fn next(rx: &mut driver::RxDeviceSetting) -> serde_json::Value {
+            let (value, rpy) = time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let _ = rpy.send(Ok(value.clone()));
+
+            match value {
+                device::Value::Str(s) => serde_json::from_str(&s).unwrap(),
+                v => panic!("unexpected setting {}", v),
+            }
+        }
+
+        // Raising the level raises the alarm, and the notification
+        // is repeated while the level stays high.
+
+        assert!(tx_in.send(device::Value::Int(20)).await.is_ok());
+
+        for _ in 0..2 {
+            let note = next(&mut rx_out).await;
+
+            assert_eq!(note["name"], "sump");
+            assert_eq!(note["severity"], "critical");
+            assert_eq!(note["event"], "raised");
+            assert_eq!(note["message"], "sump level is 20");
+        }
+
+        // Lowering the level clears it.
+
+        assert!(tx_in.send(device::Value::Int(5)).await.is_ok());
+        assert_eq!(next(&mut rx_out).await["event"], "cleared");
+
+        let _ = tx_stop.send(());
+
+        assert_eq!(emu.await.unwrap(), Ok(true));
+    }
+
     // Test a basic logic block in which forwards a solar parameter to
     // a memory device.
 
@@ -1005,7 +2858,10 @@ mod test {
                 elevation: 1.0,
                 azimuth: 2.0,
                 right_ascension: 3.0,
-                declination: 4.0
+                declination: 4.0,
+                sunrise: 6.0,
+                sunset: 18.0,
+                noon: 12.0
             }))
             .is_ok());
 
@@ -1204,5 +3060,47 @@ mod test {
 
             assert_eq!(emu.await.unwrap(), Ok(true));
         }
+
+        // This section uses definitions that refer to other
+        // definitions. They need to be evaluated in the correct
+        // order to produce the expected value.
+
+        {
+            let cfg = build_config(
+                &[("in", IN1)],
+                &[("out1", OUT1)],
+                &[
+                    ("def3", "{def2} * {def1}"),
+                    ("def1", "{in} * 10"),
+                    ("def2", "{def1} + 1"),
+                ],
+                &["{def3} + {def2} -> {out1}"],
+            );
+            let (tx_in, rx_in) = mpsc::channel(100);
+            let (tx_out1, mut rx_out1) = mpsc::channel(100);
+
+            let (_, _, emu, tx_stop) = Emulator::start(
+                vec![(IN1.into(), rx_in)],
+                vec![(OUT1.into(), tx_out1)],
+                cfg,
+            )
+            .await
+            .unwrap();
+
+            assert!(tx_in.send(device::Value::Int(4)).await.is_ok());
+
+            let (value, rpy) =
+                time::timeout(Duration::from_millis(100), rx_out1.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+            assert_eq!(value, device::Value::Int(1681));
+
+            let _ = rpy.send(Ok(value.clone()));
+            let _ = tx_stop.send(());
+
+            assert_eq!(emu.await.unwrap(), Ok(true));
+        }
     }
 }
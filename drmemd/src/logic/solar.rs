@@ -15,6 +15,59 @@ pub struct SolarInfo {
     pub azimuth: f64,
     pub right_ascension: f64,
     pub declination: f64,
+    pub sunrise: f64,
+    pub sunset: f64,
+    pub noon: f64,
+}
+
+// The elevation of the sun's center, in degrees, at sunrise and
+// sunset. It accounts for atmospheric refraction and the radius of
+// the sun's disk.
+
+const SUNRISE_ELEVATION: f64 = -0.833;
+
+impl SolarInfo {
+    // Returns `true` if the sun is below the horizon.
+
+    pub fn is_dark(&self) -> bool {
+        self.elevation < SUNRISE_ELEVATION
+    }
+}
+
+// Computes the times of sunrise, solar noon, and sunset, in
+// fractional UTC hours, for the given location. `delta` is the sun's
+// declination and `eot` is the equation of time (both in degrees.)
+// The results may be outside the range 0 - 24; the caller needs to
+// wrap them after converting them to local time.
+//
+// If the sun doesn't set (polar day), sunrise and sunset are placed
+// 12 hours on either side of noon. If it doesn't rise (polar night),
+// they are both set to noon.
+
+fn get_sun_times(lat: f64, long: f64, delta: f64, eot: f64) -> (f64, f64, f64) {
+    let noon = 12.0 - eot / 15.0 - long / 15.0;
+    let lat_sc = lat.to_radians().sin_cos();
+    let delta_sc = delta.to_radians().sin_cos();
+    let cos_h0 = (SUNRISE_ELEVATION.to_radians().sin() - lat_sc.0 * delta_sc.0)
+        / (lat_sc.1 * delta_sc.1);
+    let half_day = if cos_h0 < -1.0 {
+        12.0
+    } else if cos_h0 > 1.0 {
+        0.0
+    } else {
+        cos_h0.acos().to_degrees() / 15.0
+    };
+
+    (noon - half_day, noon, noon + half_day)
+}
+
+// Converts fractional UTC hours, for the day containing `time`, into
+// fractional local hours in the range 0 - 24.
+
+fn to_local_hours(hours: f64, time: &chrono::DateTime<chrono::Utc>) -> f64 {
+    let offset = super::tod::to_local(time).offset().local_minus_utc();
+
+    (hours + offset as f64 / 3600.0).rem_euclid(24.0)
 }
 
 pub type Info = Arc<SolarInfo>;
@@ -97,6 +150,19 @@ fn get_solar_position(
     let azimuth: f64 =
         (f64::atan2(-sx, -sy).to_degrees() + 180.0).rem_euclid(360.0);
 
+    // Compute sunrise, sunset, and solar noon in local time.
+
+    let (sunrise, noon, sunset) = get_sun_times(lat, long, delta, eot);
+    let (sunrise, noon, sunset) = if sunset - sunrise >= 24.0 {
+        (0.0, to_local_hours(noon, time), 24.0)
+    } else {
+        (
+            to_local_hours(sunrise, time),
+            to_local_hours(noon, time),
+            to_local_hours(sunset, time),
+        )
+    };
+
     debug!(
         "alt: {:.2}, az: {:.2}, ra: {:.2}, dec: {:.2}",
         round(elevation, 0.02),
@@ -110,6 +176,9 @@ fn get_solar_position(
         azimuth: round(azimuth, 0.1),
         right_ascension: round(alpha, 0.1),
         declination: round(delta, 0.1),
+        sunrise: round(sunrise, 0.01),
+        sunset: round(sunset, 0.01),
+        noon: round(noon, 0.01),
     })
 }
 
@@ -148,13 +217,50 @@ pub fn create_task(
 
 #[cfg(test)]
 mod tests {
-    use super::get_solar_position;
+    use super::{get_solar_position, get_sun_times};
     use chrono::TimeZone;
 
     fn close_enough(a: f64, b: f64, delta: f64) -> bool {
         (a - b).abs() <= delta
     }
 
+    #[test]
+    fn test_sun_times() {
+        // At the equator, on an equinox, the day is a little longer
+        // than 12 hours due to refraction and the size of the sun.
+
+        let (rise, noon, set) = get_sun_times(0.0, 0.0, 0.0, 0.0);
+
+        assert_eq!(noon, 12.0);
+        assert!(close_enough(rise, 5.94, 0.01), "sunrise: {}", rise);
+        assert!(close_enough(set, 18.06, 0.01), "sunset: {}", set);
+
+        // Solar noon moves with longitude and the equation of time.
+
+        let (_, noon, _) = get_sun_times(0.0, -90.0, 0.0, 0.0);
+
+        assert_eq!(noon, 18.0);
+
+        let (_, noon, _) = get_sun_times(0.0, 0.0, 0.0, 3.0);
+
+        assert_eq!(noon, 11.8);
+
+        // Summer days are longer than winter days.
+
+        let (rise, _, set) = get_sun_times(45.0, 0.0, 23.0, 0.0);
+
+        assert!(set - rise > 15.0, "summer: {}", set - rise);
+
+        let (rise, _, set) = get_sun_times(45.0, 0.0, -23.0, 0.0);
+
+        assert!(set - rise < 9.0, "winter: {}", set - rise);
+
+        // Check polar day and night.
+
+        assert_eq!(get_sun_times(80.0, 0.0, 23.0, 0.0), (0.0, 12.0, 24.0));
+        assert_eq!(get_sun_times(80.0, 0.0, -23.0, 0.0), (12.0, 12.0, 12.0));
+    }
+
     struct TestData {
         year: i32,
         month: u32,
@@ -0,0 +1,206 @@
+// This module saves the internal state of a logic block's functions
+// (counters, latches, integrators, etc.) so it survives a restart.
+// The state is written, as a JSON string, to a device named in the
+// block's configuration -- typically a memory device. When the block
+// starts, it reads the last value of the device and restores the
+// state of each expression.
+//
+// The state of each expression is stored using the expression's
+// source text as the key. If an expression is edited, its saved state
+// is ignored and its functions start fresh.
+
+use drmem_api::device;
+use std::collections::{BTreeMap, HashMap};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use super::{compile, Output};
+
+// The minimum time between writes to the state device. Some
+// functions, like `PID`, update their state every second, so this
+// keeps the device's history from growing too quickly.
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Builds the string holding the state of a set of expressions. Each
+// item is the source text of an expression along with its compiled
+// form. Expressions without any saved state are left out.
+
+pub fn encode<'a>(
+    progs: impl Iterator<Item = (&'a str, &'a compile::Expr)>,
+) -> String {
+    let map: BTreeMap<&str, Vec<Option<f64>>> = progs
+        .filter_map(|(key, expr)| {
+            let mut state = vec![];
+
+            expr.save_state(&mut state);
+            if state.iter().any(Option::is_some) {
+                Some((key, state))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    serde_json::to_string(&map).unwrap_or_default()
+}
+
+// Restores the state of a set of expressions from a string created
+// by `encode`.
+
+pub fn decode<'a>(
+    s: &str,
+    progs: impl Iterator<Item = (&'a str, &'a mut compile::Expr)>,
+) {
+    match serde_json::from_str::<HashMap<String, Vec<Option<f64>>>>(s) {
+        Ok(mut map) => {
+            for (key, expr) in progs {
+                if let Some(state) = map.remove(key) {
+                    expr.restore_state(&mut state.into_iter())
+                }
+            }
+            if !map.is_empty() {
+                info!("ignoring saved state of {} old expression(s)", map.len())
+            }
+        }
+        Err(e) => warn!("ignoring bad saved state: {}", e),
+    }
+}
+
+// Writes the state of a logic block to its state device. Changes are
+// written right away, unless the previous write was recent. In that
+// case, the latest state is written once `SAVE_INTERVAL` has passed.
+
+pub struct Persist {
+    out: Output,
+    saved: String,
+    pending: Option<String>,
+    next_save: Instant,
+}
+
+impl Persist {
+    // Creates a new `Persist` which writes to the provided setting
+    // channel. `saved` is the state that was restored.
+
+    pub fn new(
+        chan: drmem_api::driver::TxDeviceSetting,
+        saved: String,
+    ) -> Self {
+        Persist {
+            out: Output::create(chan),
+            saved,
+            pending: None,
+            next_save: Instant::now(),
+        }
+    }
+
+    // Records the current state of the logic block.
+
+    pub fn update(&mut self, state: String) {
+        self.pending = if state != self.saved {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    // Returns the time the pending state should be written, if
+    // there's one.
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|_| self.next_save)
+    }
+
+    // Writes the pending state to the device.
+
+    pub async fn save(&mut self) {
+        if let Some(state) = self.pending.take() {
+            if self
+                .out
+                .send(device::Value::Str(state.as_str().into()))
+                .await
+            {
+                self.saved = state
+            }
+            self.next_save = Instant::now() + SAVE_INTERVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_encode_decode() {
+        let env = (
+            &[String::from("a"), String::from("b")][..],
+            &[String::from("c")][..],
+        );
+        let compile = |s: &str| compile::Program::compile(s, &env).unwrap().0;
+        let src = [
+            "COUNT({a}, {b}) + COUNT({b}, {a}) -> {c}",
+            "LATCH({a}, {b}) -> {c}",
+            "RISING({a}) -> {c}",
+        ];
+        let mut exprs: Vec<compile::Expr> =
+            src.iter().map(|s| compile(s)).collect();
+        let time =
+            Arc::new((chrono::Utc::now(), chrono::Local::now().fixed_offset()));
+
+        // Evaluate the expressions a few times so they have some
+        // state.
+
+        for (a, b) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let inp =
+                [Some(device::Value::Bool(a)), Some(device::Value::Bool(b))];
+
+            for e in exprs.iter_mut() {
+                compile::eval(e, &inp, &time, None);
+            }
+        }
+
+        let state = encode(src.iter().copied().zip(exprs.iter()));
+
+        assert_eq!(
+            state,
+            r#"{"COUNT({a}, {b}) + COUNT({b}, {a}) -> {c}":[1.0,0.0],"LATCH({a}, {b}) -> {c}":[0.0]}"#
+        );
+
+        // Restore the state into freshly compiled expressions. The
+        // second expression was edited, so it doesn't get restored.
+
+        let src = [
+            "COUNT({a}, {b}) + COUNT({b}, {a}) -> {c}",
+            "LATCH({b}, {a}) -> {c}",
+        ];
+        let mut fresh: Vec<compile::Expr> =
+            src.iter().map(|s| compile(s)).collect();
+
+        decode(&state, src.iter().copied().zip(fresh.iter_mut()));
+
+        assert_eq!(
+            compile::eval(
+                &mut fresh[0],
+                &[
+                    Some(device::Value::Bool(false)),
+                    Some(device::Value::Bool(false))
+                ],
+                &time,
+                None
+            ),
+            Some(device::Value::Int(1))
+        );
+        assert_eq!(
+            encode(src.iter().copied().zip(fresh.iter())),
+            r#"{"COUNT({a}, {b}) + COUNT({b}, {a}) -> {c}":[1.0,0.0],"LATCH({b}, {a}) -> {c}":[0.0]}"#
+        );
+
+        // Bad state is ignored.
+
+        decode("garbage", src.iter().copied().zip(fresh.iter_mut()));
+    }
+}
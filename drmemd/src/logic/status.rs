@@ -0,0 +1,141 @@
+// This module records what a logic block is doing so it can be
+// inspected by clients. A running block updates its `Status` after
+// each evaluation. The logic manager hands out copies of the latest
+// snapshot when asked.
+
+use drmem_api::device;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Holds the latest activity of an expression.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Expr {
+    pub source: String,
+    pub output: String,
+    pub result: Option<device::Reading>,
+    pub last_set: Option<SystemTime>,
+}
+
+// Holds the latest activity of a logic block. `inputs` contains the
+// names of the inputs and definitions, along with their value and
+// when the value last changed.
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub inputs: Vec<(String, Option<device::Reading>)>,
+    pub exprs: Vec<Expr>,
+}
+
+// A handle to the status of a logic block. Clones refer to the same
+// status.
+
+#[derive(Clone, Default)]
+pub struct Status(Arc<Mutex<Snapshot>>);
+
+impl Status {
+    // Returns a copy of the current status.
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.0.lock().unwrap().clone()
+    }
+
+    // Sets the names of the inputs and the expressions. This clears
+    // any previous activity.
+
+    pub fn init<'a>(
+        &self,
+        inputs: &[String],
+        exprs: impl Iterator<Item = (&'a str, &'a str)>,
+    ) {
+        *self.0.lock().unwrap() = Snapshot {
+            inputs: inputs.iter().map(|name| (name.clone(), None)).collect(),
+            exprs: exprs
+                .map(|(source, output)| Expr {
+                    source: source.into(),
+                    output: output.into(),
+                    ..Expr::default()
+                })
+                .collect(),
+        }
+    }
+
+    // Records the results of an evaluation. Inputs only get a new
+    // timestamp when their value changes.
+
+    pub fn update(
+        &self,
+        inputs: &[Option<device::Value>],
+        results: &[Option<device::Value>],
+    ) {
+        let ts = SystemTime::now();
+        let mut snap = self.0.lock().unwrap();
+
+        for ((_, prev), value) in snap.inputs.iter_mut().zip(inputs) {
+            if prev.as_ref().map(|r| &r.value) != value.as_ref() {
+                *prev = value.clone().map(|v| device::Reading { ts, value: v })
+            }
+        }
+
+        for (expr, value) in snap.exprs.iter_mut().zip(results) {
+            expr.result =
+                value.clone().map(|value| device::Reading { ts, value })
+        }
+    }
+
+    // Records the last time each expression's output was set.
+
+    pub fn set_outputs(&self, times: impl Iterator<Item = Option<SystemTime>>) {
+        let mut snap = self.0.lock().unwrap();
+
+        for (expr, ts) in snap.exprs.iter_mut().zip(times) {
+            expr.last_set = ts
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update() {
+        let status = Status::default();
+
+        status
+            .init(&["a".into(), "b".into()], [("{a} -> {c}", "c")].into_iter());
+
+        status.update(
+            &[Some(device::Value::Bool(true)), None],
+            &[Some(device::Value::Bool(true))],
+        );
+
+        let snap = status.snapshot();
+        let ts = snap.inputs[0].1.as_ref().unwrap().ts;
+
+        assert_eq!(snap.inputs[1], ("b".into(), None));
+        assert_eq!(
+            snap.exprs[0].result.as_ref().map(|r| &r.value),
+            Some(&device::Value::Bool(true))
+        );
+        assert_eq!(snap.exprs[0].last_set, None);
+
+        // An unchanged input keeps its timestamp.
+
+        status.update(
+            &[Some(device::Value::Bool(true)), Some(device::Value::Int(1))],
+            &[None],
+        );
+        status.set_outputs([Some(ts)].into_iter());
+
+        let snap = status.snapshot();
+
+        assert_eq!(snap.inputs[0].1.as_ref().unwrap().ts, ts);
+        assert_eq!(
+            snap.inputs[1].1.as_ref().map(|r| &r.value),
+            Some(&device::Value::Int(1))
+        );
+        assert_eq!(snap.exprs[0].result, None);
+        assert_eq!(snap.exprs[0].last_set, Some(ts));
+    }
+}
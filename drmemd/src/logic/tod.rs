@@ -1,20 +1,22 @@
-use chrono::{Datelike, Timelike};
+use chrono::{Datelike, TimeZone, Timelike};
 use core::pin::Pin;
 use core::task::{Context, Poll};
-use std::sync::Arc;
+use serde_derive::Deserialize;
+use std::sync::{Arc, OnceLock};
 use tokio::{sync::broadcast, time};
 use tokio_stream::{wrappers::BroadcastStream, Stream};
-use tracing::{info, info_span, warn};
+use tracing::{debug, info, info_span, warn};
 use tracing_futures::Instrument;
 
 // Information related to time-of-day. We keep both UTC and local time
-// so clients don't have to convert between the time zones. It is
-// stored in an `Arc` so it can be cheaply sent and received over a
-// broadcast channel.
+// so clients don't have to convert between the time zones. Local time
+// is in the configured time zone, so it carries its offset from UTC.
+// It is stored in an `Arc` so it can be cheaply sent and received
+// over a broadcast channel.
 
 pub type Info = Arc<(
     chrono::DateTime<chrono::Utc>,
-    chrono::DateTime<chrono::Local>,
+    chrono::DateTime<chrono::FixedOffset>,
 )>;
 
 // Each variant of this enumeration selects a field of a Date/Time
@@ -109,6 +111,124 @@ impl Stream for TimeFilter {
     }
 }
 
+// Describes a holiday in the configuration. Holidays are written as
+// "MM-DD", for holidays that fall on the same date every year, or
+// "YYYY-MM-DD", for holidays that move around.
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Holiday {
+    Annual(u32, u32),
+    Once(chrono::NaiveDate),
+}
+
+impl Holiday {
+    pub fn matches(&self, date: &chrono::NaiveDate) -> bool {
+        match self {
+            Holiday::Annual(mo, da) => date.month() == *mo && date.day() == *da,
+            Holiday::Once(v) => v == date,
+        }
+    }
+}
+
+impl TryFrom<String> for Holiday {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if let Ok(date) = chrono::NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+            return Ok(Holiday::Once(date));
+        }
+
+        // Use a leap year to validate annual holidays so Feb 29th is
+        // accepted.
+
+        chrono::NaiveDate::parse_from_str(&format!("2000-{}", &s), "%Y-%m-%d")
+            .map(|date| Holiday::Annual(date.month(), date.day()))
+            .map_err(|_| format!("invalid holiday '{}'", &s))
+    }
+}
+
+// The holidays are global to DrMem, so they're saved here when the
+// configuration is loaded.
+
+static HOLIDAYS: OnceLock<Vec<Holiday>> = OnceLock::new();
+
+pub fn set_holidays(holidays: Vec<Holiday>) {
+    if HOLIDAYS.set(holidays).is_err() {
+        warn!("holidays have already been set")
+    }
+}
+
+// Returns `true` if the date is one of the configured holidays.
+
+pub fn is_holiday(date: &chrono::NaiveDate) -> bool {
+    HOLIDAYS
+        .get()
+        .map(|v| v.iter().any(|h| h.matches(date)))
+        .unwrap_or(false)
+}
+
+// The time zone used for local time. It's global to DrMem, like the
+// holidays. Without one, the host's time zone is used, which is often
+// UTC in a container.
+
+static TIMEZONE: OnceLock<chrono_tz::Tz> = OnceLock::new();
+
+pub fn set_timezone(tz: Option<chrono_tz::Tz>) {
+    if let Some(tz) = tz {
+        if TIMEZONE.set(tz).is_err() {
+            warn!("time zone has already been set")
+        }
+    }
+}
+
+fn local_in(
+    tz: Option<&chrono_tz::Tz>,
+    time: &chrono::DateTime<chrono::Utc>,
+) -> chrono::DateTime<chrono::FixedOffset> {
+    match tz {
+        Some(tz) => time.with_timezone(tz).fixed_offset(),
+        None => time.with_timezone(&chrono::Local).fixed_offset(),
+    }
+}
+
+// Converts a UTC time to DrMem's local time.
+
+pub fn to_local(
+    time: &chrono::DateTime<chrono::Utc>,
+) -> chrono::DateTime<chrono::FixedOffset> {
+    local_in(TIMEZONE.get(), time)
+}
+
+// Returns `true` if daylight saving time is in effect. A time zone's
+// standard offset is taken to be the smaller of its offsets on
+// January 1st and July 1st, which works in both hemispheres.
+
+fn dst_in(
+    tz: Option<&chrono_tz::Tz>,
+    time: &chrono::DateTime<chrono::Utc>,
+) -> bool {
+    let offset = |t: &chrono::DateTime<chrono::Utc>| {
+        local_in(tz, t).offset().local_minus_utc()
+    };
+
+    [1, 7]
+        .iter()
+        .filter_map(|mo| {
+            chrono::Utc
+                .with_ymd_and_hms(time.year(), *mo, 1, 0, 0, 0)
+                .single()
+        })
+        .map(|t| offset(&t))
+        .min()
+        .map(|std| offset(time) > std)
+        .unwrap_or(false)
+}
+
+pub fn is_dst(time: &chrono::DateTime<chrono::Utc>) -> bool {
+    dst_in(TIMEZONE.get(), time)
+}
+
 pub fn time_filter(
     stream: BroadcastStream<Info>,
     field: TimeField,
@@ -127,18 +247,46 @@ fn initial_delay() -> u64 {
     ((10020 - extra) % 1000) as u64
 }
 
+// The longest the task waits for the clock to catch up after it was
+// stepped back.
+
+const MAX_HOLD: time::Duration = time::Duration::from_secs(3600);
+
+// Returns `true` if `now` shouldn't be sent because the clock was
+// stepped back behind `prev`, the last time that was sent. Sending
+// it would repeat times that were already sent, so schedules using
+// them would happen twice. Steps larger than `MAX_HOLD` mean the
+// clock was wrong, so the new time is used.
+
+fn hold(
+    prev: Option<&chrono::DateTime<chrono::Utc>>,
+    now: &chrono::DateTime<chrono::Utc>,
+) -> bool {
+    prev.is_some_and(|prev| {
+        now <= prev && (*prev - *now).to_std().is_ok_and(|v| v <= MAX_HOLD)
+    })
+}
+
 async fn run(tx: broadcast::Sender<Info>) {
     let mut interval = time::interval_at(
         time::Instant::now() + time::Duration::from_millis(initial_delay()),
         time::Duration::from_secs(1),
     );
+    let mut prev = None;
 
     info!("starting time-of-day task");
 
-    while tx
-        .send(Arc::new((chrono::Utc::now(), chrono::Local::now())))
-        .is_ok()
-    {
+    loop {
+        let now: chrono::DateTime<chrono::Utc> = crate::clock::now().into();
+
+        if hold(prev.as_ref(), &now) {
+            debug!("waiting for the clock to catch up");
+        } else {
+            if tx.send(Arc::new((now, to_local(&now)))).is_err() {
+                break;
+            }
+            prev = Some(now)
+        }
         let _ = interval.tick().await;
     }
     warn!("no remaining clients ... terminating");
@@ -157,7 +305,9 @@ pub fn create_task() -> (broadcast::Sender<Info>, broadcast::Receiver<Info>) {
 
 #[cfg(test)]
 mod tests {
-    use super::{time_filter, Info, TimeField};
+    use super::{
+        dst_in, hold, local_in, time_filter, Holiday, Info, TimeField,
+    };
     use chrono::{Local, TimeZone, Utc};
     use core::pin::Pin;
     use futures::future::poll_fn;
@@ -172,7 +322,8 @@ mod tests {
                 .unwrap(),
             Local::with_ymd_and_hms(&Local, yr, mo, da, hr, mn, se)
                 .single()
-                .unwrap(),
+                .unwrap()
+                .fixed_offset(),
         ))
     }
 
@@ -305,4 +456,89 @@ mod tests {
             .await
         }
     }
+
+    #[test]
+    fn test_timezone() {
+        use chrono::Timelike;
+
+        let tz: chrono_tz::Tz = "America/Chicago".parse().unwrap();
+
+        // Chicago is 6 hours behind UTC in the winter and 5 hours
+        // behind in the summer.
+
+        let winter = Utc.with_ymd_and_hms(2024, 1, 15, 12, 0, 0).unwrap();
+        let summer = Utc.with_ymd_and_hms(2024, 7, 15, 12, 0, 0).unwrap();
+
+        assert_eq!(local_in(Some(&tz), &winter).hour(), 6);
+        assert_eq!(local_in(Some(&tz), &summer).hour(), 7);
+        assert_eq!(local_in(Some(&tz), &summer), summer);
+        assert_eq!(local_in(None, &summer), summer);
+    }
+
+    #[test]
+    fn test_hold() {
+        let prev = Utc.with_ymd_and_hms(2024, 1, 15, 12, 0, 0).unwrap();
+        let secs = chrono::TimeDelta::seconds;
+
+        assert!(!hold(None, &prev));
+        assert!(!hold(Some(&prev), &(prev + secs(1))));
+
+        // After a step back, times already sent are held back, unless
+        // the step was too large.
+
+        assert!(hold(Some(&prev), &prev));
+        assert!(hold(Some(&prev), &(prev - secs(1800))));
+        assert!(!hold(Some(&prev), &(prev - secs(7200))));
+    }
+
+    #[test]
+    fn test_dst() {
+        use chrono::Timelike;
+
+        let chicago: chrono_tz::Tz = "America/Chicago".parse().unwrap();
+        let sydney: chrono_tz::Tz = "Australia/Sydney".parse().unwrap();
+        let phoenix: chrono_tz::Tz = "America/Phoenix".parse().unwrap();
+
+        let winter = Utc.with_ymd_and_hms(2024, 1, 15, 12, 0, 0).unwrap();
+        let summer = Utc.with_ymd_and_hms(2024, 7, 15, 12, 0, 0).unwrap();
+
+        assert!(!dst_in(Some(&chicago), &winter));
+        assert!(dst_in(Some(&chicago), &summer));
+        assert!(dst_in(Some(&sydney), &winter));
+        assert!(!dst_in(Some(&sydney), &summer));
+        assert!(!dst_in(Some(&phoenix), &summer));
+
+        // The hour from 1am to 2am happens twice when Chicago leaves
+        // daylight saving time. Only the flag tells them apart.
+
+        let first = Utc.with_ymd_and_hms(2024, 11, 3, 6, 30, 0).unwrap();
+        let second = Utc.with_ymd_and_hms(2024, 11, 3, 7, 30, 0).unwrap();
+
+        assert_eq!(local_in(Some(&chicago), &first).hour(), 1);
+        assert_eq!(local_in(Some(&chicago), &second).hour(), 1);
+        assert!(dst_in(Some(&chicago), &first));
+        assert!(!dst_in(Some(&chicago), &second));
+    }
+
+    #[test]
+    fn test_holidays() {
+        use chrono::NaiveDate;
+
+        let xmas = Holiday::try_from(String::from("12-25")).unwrap();
+        let easter = Holiday::try_from(String::from("2024-03-31")).unwrap();
+
+        assert_eq!(xmas, Holiday::Annual(12, 25));
+        assert!(Holiday::try_from(String::from("02-29")).is_ok());
+        assert!(Holiday::try_from(String::from("13-01")).is_err());
+        assert!(Holiday::try_from(String::from("2023-02-29")).is_err());
+        assert!(Holiday::try_from(String::from("christmas")).is_err());
+
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert!(xmas.matches(&date(2024, 12, 25)));
+        assert!(xmas.matches(&date(2030, 12, 25)));
+        assert!(!xmas.matches(&date(2024, 12, 24)));
+        assert!(easter.matches(&date(2024, 3, 31)));
+        assert!(!easter.matches(&date(2025, 3, 31)));
+    }
 }
@@ -0,0 +1,162 @@
+// Converts values between engineering units. Devices report the units
+// of their values, so a logic block can convert readings into the
+// units its expressions expect. The `CONVERT` function uses the same
+// table.
+//
+// Each unit is defined by the dimension it measures and a linear
+// mapping to the base unit of that dimension (base = x * scale +
+// offset.) Units can only be converted to other units of the same
+// dimension.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dimension {
+    Temperature,
+    Length,
+    Speed,
+    Pressure,
+    Volume,
+    Energy,
+    Power,
+    Time,
+}
+
+struct Unit {
+    names: &'static [&'static str],
+    dim: Dimension,
+    scale: f64,
+    offset: f64,
+}
+
+const fn unit(
+    names: &'static [&'static str],
+    dim: Dimension,
+    scale: f64,
+    offset: f64,
+) -> Unit {
+    Unit {
+        names,
+        dim,
+        scale,
+        offset,
+    }
+}
+
+const UNITS: &[Unit] = &[
+    // Temperature. The base unit is the kelvin.
+    unit(&["K"], Dimension::Temperature, 1.0, 0.0),
+    unit(&["°C", "C", "degC"], Dimension::Temperature, 1.0, 273.15),
+    unit(
+        &["°F", "F", "degF"],
+        Dimension::Temperature,
+        5.0 / 9.0,
+        459.67 * 5.0 / 9.0,
+    ),
+    // Length. The base unit is the meter.
+    unit(&["m"], Dimension::Length, 1.0, 0.0),
+    unit(&["km"], Dimension::Length, 1000.0, 0.0),
+    unit(&["cm"], Dimension::Length, 0.01, 0.0),
+    unit(&["mm"], Dimension::Length, 0.001, 0.0),
+    unit(&["in"], Dimension::Length, 0.0254, 0.0),
+    unit(&["ft"], Dimension::Length, 0.3048, 0.0),
+    unit(&["mi"], Dimension::Length, 1609.344, 0.0),
+    // Speed. The base unit is meters per second.
+    unit(&["m/s"], Dimension::Speed, 1.0, 0.0),
+    unit(&["km/h", "kph"], Dimension::Speed, 1.0 / 3.6, 0.0),
+    unit(&["mph"], Dimension::Speed, 0.44704, 0.0),
+    unit(&["kn", "knots"], Dimension::Speed, 1852.0 / 3600.0, 0.0),
+    // Pressure. The base unit is the pascal.
+    unit(&["Pa"], Dimension::Pressure, 1.0, 0.0),
+    unit(&["hPa", "mbar"], Dimension::Pressure, 100.0, 0.0),
+    unit(&["kPa"], Dimension::Pressure, 1000.0, 0.0),
+    unit(&["bar"], Dimension::Pressure, 100_000.0, 0.0),
+    unit(&["psi"], Dimension::Pressure, 6894.757, 0.0),
+    unit(&["inHg"], Dimension::Pressure, 3386.389, 0.0),
+    // Volume. The base unit is the liter.
+    unit(&["l", "L"], Dimension::Volume, 1.0, 0.0),
+    unit(&["ml", "mL"], Dimension::Volume, 0.001, 0.0),
+    unit(&["gal"], Dimension::Volume, 3.785411784, 0.0),
+    unit(&["m³", "m3"], Dimension::Volume, 1000.0, 0.0),
+    // Energy. The base unit is the watt-hour.
+    unit(&["Wh"], Dimension::Energy, 1.0, 0.0),
+    unit(&["kWh"], Dimension::Energy, 1000.0, 0.0),
+    unit(&["J"], Dimension::Energy, 1.0 / 3600.0, 0.0),
+    // Power. The base unit is the watt.
+    unit(&["W"], Dimension::Power, 1.0, 0.0),
+    unit(&["kW"], Dimension::Power, 1000.0, 0.0),
+    // Time. The base unit is the second.
+    unit(&["s"], Dimension::Time, 1.0, 0.0),
+    unit(&["min"], Dimension::Time, 60.0, 0.0),
+    unit(&["h"], Dimension::Time, 3600.0, 0.0),
+    unit(&["d"], Dimension::Time, 86400.0, 0.0),
+];
+
+fn find(name: &str) -> Option<&'static Unit> {
+    let name = name.trim();
+
+    UNITS.iter().find(|u| u.names.contains(&name))
+}
+
+// Converts a value from one unit to another.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conversion {
+    scale: f64,
+    offset: f64,
+}
+
+impl Conversion {
+    // Returns the conversion from the `from` units to the `to`
+    // units. Returns `None` if either unit is unknown or they don't
+    // measure the same thing.
+
+    pub fn new(from: &str, to: &str) -> Option<Self> {
+        let (from, to) = (find(from)?, find(to)?);
+
+        if from.dim == to.dim {
+            Some(Conversion {
+                scale: from.scale / to.scale,
+                offset: (from.offset - to.offset) / to.scale,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn apply(&self, v: f64) -> f64 {
+        v * self.scale + self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(v: f64, from: &str, to: &str) -> Option<f64> {
+        Conversion::new(from, to).map(|c| c.apply(v))
+    }
+
+    fn close(a: Option<f64>, b: f64) -> bool {
+        a.is_some_and(|a| (a - b).abs() < 1e-4)
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert!(close(convert(100.0, "°C", "°F"), 212.0));
+        assert!(close(convert(32.0, "F", "C"), 0.0));
+        assert!(close(convert(-40.0, "degF", "degC"), -40.0));
+        assert!(close(convert(0.0, "°C", "K"), 273.15));
+        assert!(close(convert(451.0, "°F", "°F"), 451.0));
+
+        assert!(close(convert(1.0, "in", "mm"), 25.4));
+        assert!(close(convert(36.0, "km/h", "m/s"), 10.0));
+        assert!(close(convert(1013.25, "hPa", "inHg"), 29.9213));
+        assert!(close(convert(2.5, "kWh", "Wh"), 2500.0));
+        assert!(close(convert(90.0, "min", "h"), 1.5));
+
+        // Units have to measure the same thing and be known.
+
+        assert_eq!(Conversion::new("°C", "mm"), None);
+        assert_eq!(Conversion::new("°C", "furlong"), None);
+        assert_eq!(Conversion::new("parsec", "m"), None);
+    }
+}
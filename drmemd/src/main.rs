@@ -4,16 +4,41 @@
 #[macro_use]
 extern crate lazy_static;
 
-use drmem_api::{driver::RequestChan, Error, Result};
-use futures::{future, FutureExt};
+use drmem_api::{Error, Result};
+use futures::future;
+#[cfg(any(
+    feature = "graphql",
+    feature = "grpc",
+    feature = "mqtt",
+    feature = "webhooks",
+    feature = "homekit",
+    feature = "federation"
+))]
+use futures::FutureExt;
 use std::convert::Infallible;
 use tokio::task::JoinHandle;
 use tracing::{error, trace, warn};
 
+mod clock;
 mod config;
 mod core;
+mod discovery;
 mod driver;
+mod events;
+mod logfile;
 mod logic;
+mod monitor;
+mod reload;
+mod secrets;
+mod settings;
+mod shutdown;
+mod syslog;
+mod systemd;
+
+// Device name patterns are used by the simple backend, by the
+// GraphQL access rules, by webhooks, and by the `[[device]]` limits.
+
+mod glob;
 
 pub mod backends;
 
@@ -23,6 +48,32 @@ pub mod backends;
 #[cfg(feature = "graphql")]
 mod graphql;
 
+// The 'grpc' feature adds a gRPC server for machine clients.
+
+#[cfg(feature = "grpc")]
+mod grpc;
+
+// The 'mqtt' feature adds a bridge to an MQTT broker.
+
+#[cfg(feature = "mqtt")]
+mod mqtt;
+
+// The 'webhooks' feature POSTs device changes to web services.
+
+#[cfg(feature = "webhooks")]
+mod webhooks;
+
+// The 'homekit' feature adds a bridge to Apple's HomeKit.
+
+#[cfg(feature = "homekit")]
+mod homekit;
+
+// The 'federation' feature lets `drmemd` nodes use each other's
+// devices.
+
+#[cfg(feature = "federation")]
+mod federation;
+
 // Initializes the `drmemd` application. It determines the
 // configuration and sets up the logger. It returns `Some(Config)`
 // with the found configuration, if the applications is to run. It
@@ -35,12 +86,62 @@ async fn init_app() -> Option<config::Config> {
     if let Some(cfg) = config::get().await {
         // Initialize the log system. The max log level is determined
         // by the user (either through the config file or the command
-        // line.)
-
-        let subscriber = tracing_subscriber::fmt()
-            .with_max_level(cfg.get_log_level())
-            .with_target(false)
-            .finish();
+        // line.) It can be changed by reloading the configuration.
+
+        use tracing_subscriber::{fmt, prelude::*};
+
+        // Messages go to stdout unless a log file or syslog server
+        // was configured.
+
+        let file = match &cfg.log.file {
+            Some(path) => match logfile::LogFile::open(&cfg.log) {
+                Ok(f) => Some(
+                    fmt::layer()
+                        .with_target(false)
+                        .with_ansi(false)
+                        .with_writer(std::sync::Mutex::new(f)),
+                ),
+                Err(e) => {
+                    eprintln!(
+                        "ERROR: couldn't open log file {} -- {}",
+                        path.display(),
+                        e
+                    );
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        // syslog adds its own timestamp and the level is part of the
+        // message's priority.
+
+        let syslog = match &cfg.log.syslog {
+            Some(syslog_cfg) => match syslog::Syslog::open(syslog_cfg) {
+                Ok(v) => Some(
+                    fmt::layer()
+                        .without_time()
+                        .with_level(false)
+                        .with_target(false)
+                        .with_ansi(false)
+                        .with_writer(v),
+                ),
+                Err(e) => {
+                    eprintln!("ERROR: couldn't open syslog -- {}", e);
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        let stdout = (file.is_none() && syslog.is_none())
+            .then(|| fmt::layer().with_target(false));
+
+        let subscriber = tracing_subscriber::registry()
+            .with(reload::log_filter(&cfg))
+            .with(stdout)
+            .with(file)
+            .with(syslog);
 
         tracing::subscriber::set_global_default(subscriber)
             .expect("Unable to set global default subscriber");
@@ -79,12 +180,43 @@ async fn wrap_task(
 
 async fn run() -> Result<()> {
     if let Some(cfg) = init_app().await {
-        let drv_tbl = driver::DriverDb::create();
+        let started = tokio::time::Instant::now();
+        let drv_tbl = if cfg.simulate {
+            warn!("simulating the drivers that use hardware");
+            driver::DriverDb::simulated()
+        } else {
+            driver::DriverDb::create()
+        };
+
+        events::set_capacity(cfg.channels.events);
+        core::unresponsive::set_timeout(cfg.get_setting_timeout());
+
+        // Add the drivers found in plugins.
+
+        #[cfg(feature = "plugins")]
+        let drv_tbl = match &cfg.plugin_dir {
+            Some(dir) => drv_tbl.with_plugins(dir)?,
+            None => drv_tbl,
+        };
 
         // Start the core task. It returns a handle to a channel with
         // which to make requests. It also returns the task handle.
-
-        let (tx_drv_req, tx_clnt_req, core_task) = core::start(&cfg).await?;
+        // The client APIs get their own handle, which only reaches
+        // the devices allowed by the `[clients]` section.
+
+        #[cfg_attr(
+            not(any(
+                feature = "graphql",
+                feature = "grpc",
+                feature = "mqtt",
+                feature = "webhooks",
+                feature = "homekit",
+                feature = "federation"
+            )),
+            allow(unused_variables)
+        )]
+        let (tx_drv_req, tx_clnt_req, tx_ext_req, core_task) =
+            core::start(&cfg).await?;
 
         trace!("starting core tasks");
 
@@ -93,6 +225,26 @@ async fn run() -> Result<()> {
 
         let mut tasks = vec![wrap_task(core_task)];
 
+        // Create the channel used to make requests of the logic
+        // manager. The GraphQL server uses it to reload the logic
+        // blocks. It's also used to stop them when shutting down.
+
+        let (tx_logic, rx_logic) =
+            tokio::sync::mpsc::channel(cfg.channels.requests);
+
+        // Create the channel clients use to stop, start, and add
+        // driver instances. The requests are handled by the loop
+        // below, which owns the instances.
+
+        #[cfg_attr(not(feature = "graphql"), allow(unused_variables))]
+        let (tx_inst, mut rx_inst) =
+            tokio::sync::mpsc::channel(cfg.channels.requests);
+
+        // The arbiter decides which logic block, or client, controls
+        // a device when more than one of them sets it.
+
+        let arbiter = logic::arbiter::Arbiter::default();
+
         // If the "graphql" feature is specified, start up the web
         // server which accepts GraphQL queries.
 
@@ -104,7 +256,12 @@ async fn run() -> Result<()> {
             let f = graphql::server(
                 &cfg.graphql,
                 drv_tbl.clone(),
-                tx_clnt_req.clone(),
+                tx_ext_req.clone(),
+                logic::manager::RequestChan::new(
+                    tx_logic.clone(),
+                    arbiter.clone(),
+                ),
+                driver::instances::RequestChan::new(tx_inst.clone()),
             )
             .then(|_| async {
                 Err(Error::OperationError("graphql server exited".to_owned()))
@@ -113,47 +270,129 @@ async fn run() -> Result<()> {
             tasks.push(wrap_task(tokio::spawn(f)));
         }
 
+        // If the "grpc" feature is specified, start the gRPC server.
+        // Like the GraphQL server, it should never exit.
+
+        #[cfg(feature = "grpc")]
+        {
+            let f = grpc::server(
+                &cfg.grpc,
+                tx_ext_req.clone(),
+                logic::manager::RequestChan::new(
+                    tx_logic.clone(),
+                    arbiter.clone(),
+                ),
+            )
+            .then(|_| async {
+                Err(Error::OperationError("gRPC server exited".to_owned()))
+            });
+
+            tasks.push(wrap_task(tokio::spawn(f)));
+        }
+
+        // If the "mqtt" feature is specified, start the MQTT bridge.
+
+        #[cfg(feature = "mqtt")]
+        {
+            let f = mqtt::bridge(
+                &cfg.mqtt,
+                tx_ext_req.clone(),
+                logic::manager::RequestChan::new(
+                    tx_logic.clone(),
+                    arbiter.clone(),
+                ),
+            )?
+            .then(|_| async {
+                Err(Error::OperationError("MQTT bridge exited".to_owned()))
+            });
+
+            tasks.push(wrap_task(tokio::spawn(f)));
+        }
+
+        // If the "webhooks" feature is specified, start each
+        // configured webhook.
+
+        #[cfg(feature = "webhooks")]
+        for hook in &cfg.webhook {
+            let f =
+                webhooks::webhook(hook, tx_ext_req.clone()).then(|_| async {
+                    Err(Error::OperationError("webhook exited".to_owned()))
+                });
+
+            tasks.push(wrap_task(tokio::spawn(f)));
+        }
+
+        // If the "homekit" feature is specified and the bridge is
+        // configured, start it.
+
+        #[cfg(feature = "homekit")]
+        if let Some(hk_cfg) = &cfg.homekit {
+            let f = homekit::bridge(
+                hk_cfg,
+                tx_ext_req.clone(),
+                logic::manager::RequestChan::new(
+                    tx_logic.clone(),
+                    arbiter.clone(),
+                ),
+            )
+            .then(|_| async {
+                Err(Error::OperationError("HomeKit bridge exited".to_owned()))
+            });
+
+            tasks.push(wrap_task(tokio::spawn(f)));
+        }
+
+        // If the "federation" feature is specified and configured,
+        // connect to the peers and offer them this node's devices.
+
+        #[cfg(feature = "federation")]
+        if let Some(fed_cfg) = &cfg.federation {
+            let f = federation::start(
+                fed_cfg,
+                tx_ext_req.clone(),
+                tx_drv_req.clone(),
+            )
+            .then(|_| async {
+                Err(Error::OperationError("federation exited".to_owned()))
+            });
+
+            tasks.push(wrap_task(tokio::spawn(f)));
+        }
+
         // Iterate through the list of drivers specified in the
         // configuration file.
 
         trace!("starting driver instances");
 
-        for driver in cfg.driver {
-            let driver_name: drmem_api::driver::Name =
-                driver.name.clone().into();
-
-            // If the driver exists in the driver table, an instance
-            // can be started. If it doesn't exist, report an error
-            // and exit.
-
-            if let Some(driver_info) = drv_tbl.get_driver(&driver_name) {
-                let chan = RequestChan::new(
-                    driver_name.clone(),
-                    &driver.prefix,
-                    &tx_drv_req,
-                );
-
-                // Call the function that manages instances of this
-                // driver. If it returns `Ok()`, the value is a Future
-                // that implements the driver. If `Err()` is returned,
-                // then the devices couldn't be registered or some
-                // other serious error occurred.
-
-                let instance = (driver_info.2)(
-                    driver_name,
-                    driver.cfg.unwrap_or_default().clone(),
-                    chan,
-                    driver.max_history,
-                )
-                .await?;
-
-                // Push the driver instance at the end of the vector.
-
-                tasks.push(wrap_task(tokio::spawn(instance.map(Ok))))
-            } else {
-                error!("no driver named {}", driver.name);
-                return Err(Error::NotFound);
-            }
+        let mut drivers = driver::instances::Instances::new(
+            drv_tbl.clone(),
+            tx_drv_req.clone(),
+            secrets::Store::load(&cfg.secrets)?,
+        );
+
+        for driver in cfg.driver.iter().cloned() {
+            drivers.start(driver).await?
+        }
+
+        // Now that the drivers have registered their devices, put
+        // the outputs in a known state. This is done before the
+        // logic blocks start so they take over from there.
+
+        settings::apply(
+            &cfg.startup.setting,
+            &tx_clnt_req,
+            cfg.startup.get_timeout(),
+            "at startup",
+        )
+        .await;
+
+        // Add the devices that report on `drmemd` itself. Not having
+        // them isn't fatal.
+
+        if let Err(e) =
+            monitor::start(&tx_drv_req, drv_tbl.status().clone(), started).await
+        {
+            warn!("couldn't add monitoring devices -- {}", e)
         }
 
         // Create a nested scope so that the tod and solar handles are
@@ -165,6 +404,9 @@ async fn run() -> Result<()> {
             // blocks *may* have an expression that uses the
             // time-of-day.
 
+            logic::tod::set_timezone(cfg.get_timezone());
+            logic::tod::set_holidays(cfg.holidays);
+
             let (tx_tod, _) = logic::tod::create_task();
 
             // Start the solar task. This, too, needs to be done
@@ -173,23 +415,76 @@ async fn run() -> Result<()> {
             let (tx_solar, _) =
                 logic::solar::create_task(cfg.latitude, cfg.longitude);
 
-            // Iterate through the [[logic]] sections of the config.
+            // Start the logic manager, which starts the [[logic]]
+            // sections of the config.
+
+            tasks.push(wrap_task(logic::manager::start(
+                tx_clnt_req.clone(),
+                tx_drv_req.clone(),
+                tx_tod,
+                tx_solar,
+                arbiter.clone(),
+                cfg.logic,
+                rx_logic,
+            )?));
+        }
+
+        // The drivers could only register their devices once the
+        // backend was ready, so everything is up and running.
 
-            for logic in cfg.logic {
-                tasks.push(wrap_task(logic::Node::start(
-                    tx_clnt_req.clone(),
-                    tx_tod.subscribe(),
-                    tx_solar.subscribe(),
-                    logic,
-                )));
+        systemd::ready();
+
+        let logic = logic::manager::RequestChan::new(tx_logic, arbiter);
+        let mut hangup = reload::Hangup::new();
+
+        // Now run all the tasks until one of them fails or we're
+        // asked to stop. SIGHUP reloads the configuration.
+
+        let tasks = future::join_all(tasks);
+
+        tokio::pin!(tasks);
+
+        loop {
+            tokio::select! {
+                _ = &mut tasks => {
+                    warn!("shutting down");
+                    systemd::stopping();
+                    return Ok(());
+                }
+                _ = shutdown::signal() => break,
+                _ = hangup.recv() => reload::reload(&mut drivers, &logic).await,
+                Some(req) = rx_inst.recv() => {
+                    drivers.handle(req, cfg.shutdown.get_timeout()).await
+                }
             }
         }
 
-        // Now run all the tasks.
+        warn!("shutting down gracefully");
+        systemd::stopping();
+
+        let limit = cfg.shutdown.get_timeout();
+
+        // Stop the logic blocks first so they don't undo the
+        // safe-state settings.
+
+        if let Err(e) = logic.reload(vec![]).await {
+            warn!("couldn't stop logic blocks -- {}", e)
+        }
+
+        settings::apply(
+            &cfg.shutdown.setting,
+            &tx_clnt_req,
+            limit,
+            "before exiting",
+        )
+        .await;
 
-        let _ = future::join_all(tasks).await;
+        // Tell the drivers to stop and give them time to run their
+        // shutdown hooks. The core keeps running so the readings
+        // they report get saved.
 
-        warn!("shutting down")
+        shutdown::request();
+        drivers.join(limit).await
     }
     Ok(())
 }
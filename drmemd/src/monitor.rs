@@ -0,0 +1,204 @@
+// `drmemd` reports on itself with a set of read-only devices, under
+// `drmem:daemon`, so logic blocks and clients can watch it like any
+// other part of the system:
+//
+//     version       the version of `drmemd`
+//     uptime        seconds since `drmemd` started
+//     drivers       number of driver instances that are running
+//     tasks         number of tokio tasks that are alive
+//     rss           resident memory, in MiB (only on Linux)
+//     clients       number of clients with an open connection
+//     dropped       readings clients missed because they fell behind
+//     unresponsive  devices whose driver didn't reply to the last
+//                   setting
+//
+// The devices are updated every `REPORT_INTERVAL`.
+
+use crate::{backends::channels, core::unresponsive, driver::status};
+use drmem_api::{driver, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tracing::info_span;
+use tracing_futures::Instrument;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// How many readings the backend keeps of each device that `drmemd`
+// adds about itself, its driver instances, and its logic blocks. It's
+// about a day of readings at `REPORT_INTERVAL`. A `[[device]]` entry
+// can change it.
+
+pub const MAX_HISTORY: Option<usize> = Some(8_640);
+
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+// Counts a client connection while it's open. Client APIs that keep
+// a connection open (e.g. GraphQL subscriptions) hold one of these
+// for each of them.
+
+#[cfg_attr(
+    not(any(feature = "graphql", feature = "homekit")),
+    allow(dead_code)
+)]
+pub struct Client(());
+
+#[cfg_attr(
+    not(any(feature = "graphql", feature = "homekit")),
+    allow(dead_code)
+)]
+impl Client {
+    pub fn connect() -> Self {
+        CLIENTS.fetch_add(1, Ordering::Relaxed);
+        Client(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        CLIENTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn to_i32(v: usize) -> i32 {
+    i32::try_from(v).unwrap_or(i32::MAX)
+}
+
+// Returns the resident memory of the process, in MiB. Only Linux
+// reports it.
+
+fn rss() -> Option<f64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+    parse_rss(&status)
+}
+
+fn parse_rss(status: &str) -> Option<f64> {
+    let kb = status
+        .lines()
+        .find_map(|v| v.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kb as f64 / 1024.0)
+}
+
+struct Devices {
+    version: driver::ReadOnlyDevice<String>,
+    uptime: driver::ReadOnlyDevice<i32>,
+    drivers: driver::ReadOnlyDevice<i32>,
+    tasks: driver::ReadOnlyDevice<i32>,
+    rss: driver::ReadOnlyDevice<f64>,
+    clients: driver::ReadOnlyDevice<i32>,
+    dropped: driver::ReadOnlyDevice<i32>,
+    unresponsive: driver::ReadOnlyDevice<i32>,
+}
+
+impl Devices {
+    async fn register(reg: &driver::RequestChan) -> Result<Self> {
+        Ok(Devices {
+            version: reg
+                .add_ro_device("version".parse()?, None, MAX_HISTORY)
+                .await?,
+            uptime: reg
+                .add_ro_device("uptime".parse()?, Some("s"), MAX_HISTORY)
+                .await?,
+            drivers: reg
+                .add_ro_device("drivers".parse()?, None, MAX_HISTORY)
+                .await?,
+            tasks: reg
+                .add_ro_device("tasks".parse()?, None, MAX_HISTORY)
+                .await?,
+            rss: reg
+                .add_ro_device("rss".parse()?, Some("MiB"), MAX_HISTORY)
+                .await?,
+            clients: reg
+                .add_ro_device("clients".parse()?, None, MAX_HISTORY)
+                .await?,
+            dropped: reg
+                .add_ro_device("dropped".parse()?, None, MAX_HISTORY)
+                .await?,
+            unresponsive: reg
+                .add_ro_device("unresponsive".parse()?, None, MAX_HISTORY)
+                .await?,
+        })
+    }
+}
+
+async fn run(mut devs: Devices, drivers: status::Table, started: Instant) {
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+
+    devs.version
+        .report_update(env!("CARGO_PKG_VERSION").into())
+        .await;
+
+    loop {
+        interval.tick().await;
+
+        devs.uptime
+            .report_update(
+                i32::try_from(started.elapsed().as_secs()).unwrap_or(i32::MAX),
+            )
+            .await;
+        devs.drivers.report_update(to_i32(drivers.running())).await;
+        devs.tasks
+            .report_update(to_i32(
+                tokio::runtime::Handle::current()
+                    .metrics()
+                    .num_alive_tasks(),
+            ))
+            .await;
+        if let Some(v) = rss() {
+            devs.rss.report_update(v).await
+        }
+        devs.clients
+            .report_update(to_i32(CLIENTS.load(Ordering::Relaxed)))
+            .await;
+        devs.dropped
+            .report_update(
+                i32::try_from(channels::total_dropped()).unwrap_or(i32::MAX),
+            )
+            .await;
+        devs.unresponsive
+            .report_update(to_i32(unresponsive::count()))
+            .await
+    }
+}
+
+// Registers the devices and starts the task that updates them.
+
+pub async fn start(
+    req_chan: &mpsc::Sender<driver::Request>,
+    drivers: status::Table,
+    started: Instant,
+) -> Result<()> {
+    let reg = driver::RequestChan::new(
+        "drmemd".into(),
+        &"drmem:daemon".parse()?,
+        req_chan,
+    );
+    let devs = Devices::register(&reg).await?;
+
+    tokio::spawn(run(devs, drivers, started).instrument(info_span!("monitor")));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rss() {
+        assert_eq!(
+            parse_rss("Name:\tdrmemd\nVmRSS:\t   20480 kB\nThreads:\t4\n"),
+            Some(20.0)
+        );
+        assert_eq!(parse_rss("Name:\tdrmemd\n"), None);
+        assert_eq!(parse_rss("VmRSS:\tlots kB\n"), None);
+    }
+}
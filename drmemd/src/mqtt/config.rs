@@ -0,0 +1,73 @@
+use drmem_api::{Error, Result};
+use serde_derive::Deserialize;
+use std::path::PathBuf;
+
+fn def_host() -> String {
+    String::from("localhost")
+}
+
+fn def_port() -> u16 {
+    1883
+}
+
+fn def_client_id() -> String {
+    String::from("drmem")
+}
+
+fn def_topic() -> String {
+    String::from("drmem")
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    // The address of the MQTT broker.
+    #[serde(default = "def_host")]
+    pub host: String,
+    #[serde(default = "def_port")]
+    pub port: u16,
+    // The client ID used when connecting to the broker. It needs to
+    // be unique, so each `drmemd` using a broker needs its own.
+    #[serde(default = "def_client_id")]
+    pub client_id: String,
+    // The first level of every topic used by the bridge.
+    #[serde(default = "def_topic")]
+    pub topic: String,
+    // The credentials sent to brokers that require them.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Connect with TLS. The broker's certificate has to be signed by
+    // a public authority or, if it's given, the one in `ca`.
+    #[serde(default)]
+    pub tls: bool,
+    pub ca: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn uses_tls(&self) -> bool {
+        self.tls || self.ca.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.password.is_some() && self.username.is_none() {
+            return Err(Error::ConfigError(
+                "MQTT 'password' needs a 'username'".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: def_host(),
+            port: def_port(),
+            client_id: def_client_id(),
+            topic: def_topic(),
+            username: None,
+            password: None,
+            tls: false,
+            ca: None,
+        }
+    }
+}
@@ -0,0 +1,440 @@
+// Bridges DrMem and an MQTT broker so the devices can be used by the
+// many tools that speak MQTT. Every reading of a device is published,
+// and retained, as JSON to
+//
+//     <topic>/<device>/value
+//
+// and values published to
+//
+//     <topic>/<device>/set
+//
+// are sent to the device as settings. Like the other client
+// interfaces, a setting takes control of the device away from logic
+// blocks.
+
+use crate::events;
+use chrono::{DateTime, Utc};
+use drmem_api::{client, device, Error, Result};
+use futures::Future;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS, TlsConfiguration,
+    Transport,
+};
+use serde_json::{json, Value as Json};
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_rustls::rustls::{self, crypto::ring};
+use tokio_stream::{StreamExt, StreamMap};
+use tracing::{error, info, warn};
+
+pub mod config;
+
+// The number of requests that can be queued for the broker.
+
+const CHAN_SIZE: usize = 100;
+
+// How long to wait before reconnecting to the broker.
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Converts a reading to the JSON published for it. Colors are arrays
+// holding their red, green, blue, and alpha components.
+
+fn reading_to_json(r: &device::Reading) -> Json {
+    json!({
+        "stamp": DateTime::<Utc>::from(r.ts).to_rfc3339(),
+        "value": match &r.value {
+            device::Value::Bool(v) => json!(v),
+            device::Value::Int(v) => json!(v),
+            device::Value::Flt(v) => json!(v),
+            device::Value::Str(v) => json!(v.as_ref()),
+            device::Value::Color(v) => json!([v.red, v.green, v.blue, v.alpha]),
+        },
+    })
+}
+
+// Converts the payload of a setting to a value. It holds a JSON
+// value: a boolean, number, string, or an array of 3 or 4 color
+// components.
+
+fn parse_setting(payload: &[u8]) -> Option<device::Value> {
+    let comp = |v: &Json| v.as_u64().and_then(|v| u8::try_from(v).ok());
+
+    match serde_json::from_slice(payload).ok()? {
+        Json::Bool(v) => Some(v.into()),
+        Json::Number(v) => match v.as_i64() {
+            Some(v) => i32::try_from(v).ok().map(Into::into),
+            None => v.as_f64().map(Into::into),
+        },
+        Json::String(v) => Some(v.into()),
+        Json::Array(v) if v.len() == 3 || v.len() == 4 => Some(
+            palette::LinSrgba::<u8>::new(
+                comp(&v[0])?,
+                comp(&v[1])?,
+                comp(&v[2])?,
+                v.get(3).map_or(Some(255), comp)?,
+            )
+            .into(),
+        ),
+        _ => None,
+    }
+}
+
+#[derive(Clone)]
+struct Bridge {
+    topic: String,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+}
+
+impl Bridge {
+    // Returns the device named in the topic of a setting.
+
+    fn setting_device(&self, topic: &str) -> Option<device::Name> {
+        topic
+            .strip_prefix(self.topic.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix("/set")?
+            .parse()
+            .ok()
+    }
+
+    // Forwards a setting received from the broker to its device.
+
+    fn handle_setting(&self, topic: &str, payload: &[u8]) {
+        let Some(name) = self.setting_device(topic) else {
+            return;
+        };
+        let Some(value) = parse_setting(payload) else {
+            warn!("ignoring badly formed setting for '{}'", &name);
+            return;
+        };
+
+        self.lchan.manual_setting(&name);
+
+        let cchan = self.cchan.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = cchan.set_value(name.clone(), value).await {
+                warn!("couldn't set '{}' -- {}", &name, e)
+            }
+        });
+    }
+
+    // Adds the device to the set being published.
+
+    async fn monitor(
+        &self,
+        streams: &mut StreamMap<String, device::DataStream<device::Reading>>,
+        name: device::Name,
+    ) {
+        let key = name.to_string();
+
+        if !streams.contains_key(&key) {
+            match self.cchan.monitor_device(name, None, None).await {
+                Ok(s) => {
+                    streams.insert(key, s);
+                }
+
+                // Devices hidden from clients aren't found. They
+                // simply aren't bridged.
+                Err(Error::NotFound) => (),
+                Err(e) => warn!("couldn't monitor '{}' -- {}", key, e),
+            }
+        }
+    }
+
+    // Publishes a reading. If the broker can't be reached, and
+    // enough readings are waiting for it, the reading is dropped. The
+    // device's next reading will replace it.
+
+    fn publish(&self, client: &AsyncClient, dev: &str, r: &device::Reading) {
+        let topic = format!("{}/{}/value", &self.topic, dev);
+        let payload = reading_to_json(r).to_string();
+
+        if let Err(e) =
+            client.try_publish(topic, QoS::AtLeastOnce, true, payload)
+        {
+            warn!("couldn't publish reading of '{}' -- {}", dev, e)
+        }
+    }
+
+    async fn run(self, cfg: MqttOptions) {
+        let (client, event_loop) = AsyncClient::new(cfg, CHAN_SIZE);
+
+        tokio::spawn(self.clone().handle_broker(client.clone(), event_loop));
+
+        // Listen for new devices before getting the current ones so
+        // none are missed.
+
+        let mut new_devices = events::subscribe();
+        let mut streams = StreamMap::new();
+
+        match self.cchan.get_device_info(None).await {
+            Ok(devs) => {
+                for dev in devs {
+                    self.monitor(&mut streams, dev.name).await
+                }
+            }
+            Err(e) => {
+                error!("couldn't get the list of devices -- {}", e);
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                Some((dev, reading)) = streams.next() => {
+                    self.publish(&client, &dev, &reading)
+                }
+
+                ev = new_devices.recv() => match ev {
+                    Ok(ev) if ev.kind == events::Kind::DeviceRegistered => {
+                        if let Ok(name) = ev.source.parse() {
+                            self.monitor(&mut streams, name).await
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => {
+                        error!("event bus closed");
+                        break;
+                    }
+                },
+            }
+        }
+    }
+
+    // Handles the connection to the broker, which reconnects when
+    // it's lost. Subscriptions are made each time the bridge connects
+    // since the broker doesn't keep them after a disconnect.
+
+    async fn handle_broker(
+        self,
+        client: AsyncClient,
+        mut event_loop: EventLoop,
+    ) {
+        let set_topic = format!("{}/+/set", &self.topic);
+
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker");
+
+                    if let Err(e) =
+                        client.try_subscribe(&set_topic, QoS::AtLeastOnce)
+                    {
+                        error!("couldn't subscribe to settings -- {}", e)
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(msg))) => {
+                    self.handle_setting(&msg.topic, &msg.payload)
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    warn!("MQTT connection failed -- {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await
+                }
+            }
+        }
+    }
+}
+
+// Returns the TLS configuration used to reach the broker. Its
+// certificate has to be signed by the authority in `ca` or, if it
+// isn't given, by one of the usual public authorities. The `ring`
+// provider is named explicitly, like the federation connections do.
+
+fn tls_config(ca: Option<&Path>) -> Result<TlsConfiguration> {
+    let tls_err = |e: rustls::Error| {
+        Error::ConfigError(format!("MQTT TLS configuration -- {}", e))
+    };
+    let mut roots = rustls::RootCertStore::empty();
+
+    match ca {
+        Some(ca) => {
+            let file = File::open(ca).map_err(|e| {
+                Error::ConfigError(format!(
+                    "couldn't open {} -- {}",
+                    ca.display(),
+                    e
+                ))
+            })?;
+
+            for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                let cert = cert.map_err(|e| {
+                    Error::ConfigError(format!(
+                        "bad certificate in {} -- {}",
+                        ca.display(),
+                        e
+                    ))
+                })?;
+
+                roots.add(cert).map_err(tls_err)?
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let cfg = rustls::ClientConfig::builder_with_provider(Arc::new(
+        ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(tls_err)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    Ok(TlsConfiguration::Rustls(Arc::new(cfg)))
+}
+
+// Returns the options used to connect to the broker.
+
+fn options(cfg: &config::Config) -> Result<MqttOptions> {
+    let mut opts = MqttOptions::new(&cfg.client_id, &cfg.host, cfg.port);
+
+    opts.set_keep_alive(Duration::from_secs(30));
+
+    if let Some(username) = &cfg.username {
+        opts.set_credentials(
+            username,
+            cfg.password.as_deref().unwrap_or_default(),
+        );
+    }
+
+    if cfg.uses_tls() {
+        opts.set_transport(Transport::tls_with_config(tls_config(
+            cfg.ca.as_deref(),
+        )?));
+    }
+    Ok(opts)
+}
+
+// Returns a future that runs the MQTT bridge. An error is returned if
+// the TLS configuration can't be built.
+
+pub fn bridge(
+    cfg: &config::Config,
+    cchan: client::RequestChan,
+    lchan: crate::logic::manager::RequestChan,
+) -> Result<impl Future<Output = ()>> {
+    let opts = options(cfg)?;
+
+    let bridge = Bridge {
+        topic: cfg.topic.clone(),
+        cchan,
+        lchan,
+    };
+
+    info!(
+        "MQTT bridge using broker at {}:{}{}",
+        &cfg.host,
+        cfg.port,
+        if cfg.uses_tls() { " with TLS" } else { "" }
+    );
+    Ok(bridge.run(opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_payloads() {
+        let reading = |value| device::Reading {
+            ts: UNIX_EPOCH,
+            value,
+        };
+
+        assert_eq!(
+            reading_to_json(&reading(device::Value::Int(5))).to_string(),
+            "{\"stamp\":\"1970-01-01T00:00:00+00:00\",\"value\":5}"
+        );
+        assert_eq!(
+            reading_to_json(&reading(device::Value::Color(
+                palette::LinSrgba::new(1, 2, 3, 4)
+            )))["value"],
+            json!([1, 2, 3, 4])
+        );
+
+        assert_eq!(parse_setting(b"true"), Some(device::Value::Bool(true)));
+        assert_eq!(parse_setting(b"-7"), Some(device::Value::Int(-7)));
+        assert_eq!(parse_setting(b"2.5"), Some(device::Value::Flt(2.5)));
+        assert_eq!(
+            parse_setting(b"\"on\""),
+            Some(device::Value::Str("on".into()))
+        );
+        assert_eq!(
+            parse_setting(b"[255, 0, 10]"),
+            Some(device::Value::Color(palette::LinSrgba::new(
+                255, 0, 10, 255
+            )))
+        );
+        assert_eq!(parse_setting(b"[256, 0, 10]"), None);
+        assert_eq!(parse_setting(b"[1, 2]"), None);
+        assert_eq!(parse_setting(b"null"), None);
+        assert_eq!(parse_setting(b"on"), None);
+        assert_eq!(parse_setting(b"3000000000"), None);
+    }
+
+    #[test]
+    fn test_topics() {
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        let (ltx, _) = tokio::sync::mpsc::channel(1);
+        let bridge = Bridge {
+            topic: "drmem".into(),
+            cchan: client::RequestChan::new(tx),
+            lchan: crate::logic::manager::RequestChan::new(
+                ltx,
+                Default::default(),
+            ),
+        };
+
+        assert_eq!(
+            bridge.setting_device("drmem/room:light/set"),
+            Some("room:light".parse().unwrap())
+        );
+        assert_eq!(bridge.setting_device("drmem/room:light/value"), None);
+        assert_eq!(bridge.setting_device("other/room:light/set"), None);
+        assert_eq!(bridge.setting_device("drmemx/room:light/set"), None);
+        assert_eq!(bridge.setting_device("drmem/bad name/set"), None);
+    }
+
+    #[test]
+    fn test_options() {
+        let cfg: config::Config = toml::from_str("").unwrap();
+        let opts = options(&cfg).unwrap();
+
+        assert!(opts.credentials().is_none());
+        assert!(matches!(opts.transport(), Transport::Tcp));
+
+        let cfg: config::Config = toml::from_str(
+            r#"
+port = 8883
+username = "drmem"
+password = "secret"
+tls = true
+"#,
+        )
+        .unwrap();
+        let opts = options(&cfg).unwrap();
+        let login = opts.credentials().unwrap();
+
+        assert_eq!(login.username, "drmem");
+        assert_eq!(login.password, "secret");
+        assert!(matches!(opts.transport(), Transport::Tls(_)));
+
+        // A missing CA file is a configuration error, as is a
+        // password without a username.
+
+        let cfg: config::Config =
+            toml::from_str("ca = \"/nonexistent/ca.pem\"").unwrap();
+
+        assert!(cfg.uses_tls());
+        assert!(options(&cfg).is_err());
+
+        let cfg: config::Config =
+            toml::from_str("password = \"secret\"").unwrap();
+
+        assert!(cfg.validate().is_err());
+    }
+}
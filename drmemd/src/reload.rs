@@ -0,0 +1,424 @@
+// Re-reads `drmem.toml` when `drmemd` receives SIGHUP. Driver
+// instances and logic blocks that were added are started, removed
+// ones are stopped, and ones whose configuration changed are
+// restarted. The log levels are updated, too. Other sections, like the
+// back-end or the GraphQL server, only take effect after a restart.
+
+use crate::{config, driver::instances::Instances, logic, secrets};
+use std::{collections::HashMap, fmt, sync::OnceLock};
+use tracing::{
+    error, field, info, level_filters::LevelFilter, span, subscriber::Interest,
+    warn, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, Layer},
+    registry::LookupSpan,
+    reload, Registry,
+};
+
+static LOG_FILTER: OnceLock<reload::Handle<LogFilter, Registry>> =
+    OnceLock::new();
+
+// Decides which messages are logged. A message logged by a driver
+// instance uses the instance's `log_level`, if it has one. Otherwise
+// the level of the longest module path in `[log.modules]` that
+// matches the message's target is used. If none match, the global
+// level is used.
+
+pub struct LogFilter {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    instances: HashMap<String, LevelFilter>,
+    max: LevelFilter,
+}
+
+// Saved in the extensions of an "instance" span so its messages can
+// be matched with the instance's log level.
+
+struct Instance(String);
+
+impl field::Visit for Instance {
+    fn record_debug(&mut self, f: &field::Field, v: &dyn fmt::Debug) {
+        if f.name() == "prefix" {
+            self.0 = format!("{:?}", v)
+        }
+    }
+}
+
+impl LogFilter {
+    pub fn new(cfg: &config::Config) -> Self {
+        let level = LevelFilter::from_level(cfg.get_log_level());
+        let to_filter = |v: &str| {
+            config::parse_level(v)
+                .map(LevelFilter::from_level)
+                .unwrap_or(level)
+        };
+        let mut modules: Vec<(String, LevelFilter)> = cfg
+            .log
+            .modules
+            .iter()
+            .map(|(k, v)| (k.clone(), to_filter(v)))
+            .collect();
+
+        // Longer paths are more specific, so they're checked first.
+
+        modules.sort_by_key(|v| std::cmp::Reverse(v.0.len()));
+
+        let instances: HashMap<String, LevelFilter> = cfg
+            .driver
+            .iter()
+            .filter_map(|v| {
+                v.log_level
+                    .as_deref()
+                    .map(|l| (v.prefix.to_string(), to_filter(l)))
+            })
+            .collect();
+        let max = modules
+            .iter()
+            .map(|v| v.1)
+            .chain(instances.values().copied())
+            .fold(level, std::cmp::max);
+
+        LogFilter {
+            level,
+            modules,
+            instances,
+            max,
+        }
+    }
+
+    fn has_overrides(&self) -> bool {
+        !(self.modules.is_empty() && self.instances.is_empty())
+    }
+
+    fn module_level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(m, _)| {
+                target
+                    .strip_prefix(m.as_str())
+                    .is_some_and(|v| v.is_empty() || v.starts_with("::"))
+            })
+            .map(|v| v.1)
+            .unwrap_or(self.level)
+    }
+
+    fn instance_level<S>(&self, ctx: &Context<'_, S>) -> Option<LevelFilter>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.instances.is_empty() {
+            return None;
+        }
+
+        // The innermost instance span is the one that applies.
+
+        for span in ctx.lookup_current()?.scope() {
+            if let Some(inst) = span.extensions().get::<Instance>() {
+                return self.instances.get(&inst.0).copied();
+            }
+        }
+        None
+    }
+}
+
+impl<S> Layer<S> for LogFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.max < *meta.level() {
+            Interest::never()
+        } else if self.has_overrides() {
+            Interest::sometimes()
+        } else {
+            Interest::always()
+        }
+    }
+
+    // Spans are kept up to the most verbose level in use so the
+    // instance spans exist when an instance logs more than the rest.
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        let level = if meta.is_span() {
+            self.max
+        } else {
+            self.instance_level(&ctx)
+                .unwrap_or_else(|| self.module_level(meta.target()))
+        };
+
+        level >= *meta.level()
+    }
+
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        if attrs.metadata().name() == "instance" {
+            if let Some(span) = ctx.span(id) {
+                let mut inst = Instance(String::new());
+
+                attrs.record(&mut inst);
+                span.extensions_mut().insert(inst)
+            }
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max)
+    }
+}
+
+// Returns the layer that sets the log levels. Reloading the
+// configuration changes them.
+
+pub fn log_filter(cfg: &config::Config) -> reload::Layer<LogFilter, Registry> {
+    let (layer, handle) = reload::Layer::new(LogFilter::new(cfg));
+
+    let _ = LOG_FILTER.set(handle);
+    layer
+}
+
+// Receives the SIGHUP signals. If they can't be caught, `recv()`
+// never returns.
+
+pub struct Hangup(#[cfg(unix)] Option<tokio::signal::unix::Signal>);
+
+impl Hangup {
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            Hangup(
+                signal(SignalKind::hangup())
+                    .map_err(|e| warn!("can't catch SIGHUP -- {}", e))
+                    .ok(),
+            )
+        }
+
+        #[cfg(not(unix))]
+        Hangup()
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(sig) = &mut self.0 {
+            sig.recv().await;
+            return;
+        }
+
+        std::future::pending().await
+    }
+}
+
+// Reloads the configuration. If the file can't be read, or has an
+// error, nothing is changed.
+
+pub async fn reload(
+    instances: &mut Instances,
+    logic: &logic::manager::RequestChan,
+) {
+    info!("reloading configuration");
+
+    let cfg = match config::reload().await {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("couldn't reload configuration -- {}", e);
+            return;
+        }
+    };
+
+    match secrets::Store::load(&cfg.secrets) {
+        Ok(v) => instances.set_secrets(v),
+        Err(e) => {
+            error!("couldn't load secrets -- {}", e);
+            return;
+        }
+    }
+
+    if let Some(handle) = LOG_FILTER.get() {
+        if let Err(e) = handle.reload(LogFilter::new(&cfg)) {
+            warn!("couldn't change log level -- {}", e)
+        }
+    }
+
+    match instances
+        .update(cfg.driver, cfg.shutdown.get_timeout())
+        .await
+    {
+        Ok(v) => {
+            info!("drivers started: {:?}, stopped: {:?}", v.started, v.stopped)
+        }
+        Err(e) => error!("couldn't update drivers -- {}", e),
+    }
+
+    match logic.reload(cfg.logic).await {
+        Ok(v) => info!(
+            "logic blocks started: {:?}, stopped: {:?}",
+            v.started, v.stopped
+        ),
+        Err(e) => error!("couldn't update logic blocks -- {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing::{
+        callsite::{DefaultCallsite, Identifier},
+        debug,
+        field::FieldSet,
+        info_span,
+        metadata::Kind,
+        trace, Level,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn filter(text: &str) -> LogFilter {
+        let text = format!("latitude = 0.0\nlongitude = 0.0\n{}", text);
+
+        LogFilter::new(&toml::from_str::<config::Config>(&text).unwrap())
+    }
+
+    // Saves the targets of the messages that get through the filter.
+
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+            let mut inst = Instance(String::new());
+
+            event.record(&mut inst);
+            self.0.lock().unwrap().push(inst.0)
+        }
+    }
+
+    #[test]
+    fn test_modules() {
+        let f = filter(
+            r#"
+log_level = "warn"
+
+[log.modules]
+drmemd = "info"
+"drmemd::logic" = "debug"
+"drmemd::log" = "trace"
+"#,
+        );
+
+        assert_eq!(
+            f.module_level("drmemd::logic::compile"),
+            LevelFilter::DEBUG
+        );
+        assert_eq!(f.module_level("drmemd::logic"), LevelFilter::DEBUG);
+        assert_eq!(f.module_level("drmemd::log"), LevelFilter::TRACE);
+
+        // A module path only matches whole path components.
+
+        assert_eq!(f.module_level("drmemd::logfile"), LevelFilter::INFO);
+        assert_eq!(f.module_level("drmemd"), LevelFilter::INFO);
+        assert_eq!(f.module_level("drmemdx"), LevelFilter::WARN);
+        assert_eq!(f.module_level("hyper::server"), LevelFilter::WARN);
+        assert_eq!(f.max, LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn test_instances() {
+        let f = filter(
+            r#"
+log_level = "warn"
+
+[[driver]]
+name = "memory"
+prefix = "sump"
+log_level = "debug"
+cfg = {}
+
+[[driver]]
+name = "memory"
+prefix = "porch"
+cfg = {}
+"#,
+        );
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sub = Registry::default().with(f).with(Capture(seen.clone()));
+
+        tracing::subscriber::with_default(sub, || {
+            debug!(prefix = "none", "outside of an instance");
+
+            info_span!("instance", prefix = %"porch").in_scope(|| {
+                debug!(prefix = "porch", "instance without a level")
+            });
+
+            info_span!("instance", prefix = %"sump").in_scope(|| {
+                debug!(prefix = "sump", "instance with a level");
+                trace!(prefix = "sump", "more than the instance's level");
+
+                // The innermost instance span is the one that applies.
+
+                info_span!("instance", prefix = %"porch")
+                    .in_scope(|| debug!(prefix = "nested", "nested instance"))
+            });
+        });
+
+        assert_eq!(*seen.lock().unwrap(), vec!["\"sump\"".to_string()]);
+    }
+
+    // Builds the metadata of a callsite at `level`.
+
+    macro_rules! callsite {
+        ($level:expr) => {{
+            static CALLSITE: DefaultCallsite = DefaultCallsite::new(&META);
+            static META: Metadata<'static> = Metadata::new(
+                "test",
+                "drmemd::test",
+                $level,
+                None,
+                None,
+                None,
+                FieldSet::new(&[], Identifier(&CALLSITE)),
+                Kind::EVENT,
+            );
+
+            &META
+        }};
+    }
+
+    #[test]
+    fn test_interest() {
+        let info = callsite!(Level::INFO);
+        let debug = callsite!(Level::DEBUG);
+        let trace = callsite!(Level::TRACE);
+        let interest =
+            |f: &LogFilter, meta| Layer::<Registry>::register_callsite(f, meta);
+
+        // Without overrides, the global level decides each callsite
+        // once.
+
+        let f = filter("log_level = \"debug\"");
+
+        assert!(interest(&f, info).is_always());
+        assert!(interest(&f, debug).is_always());
+        assert!(interest(&f, trace).is_never());
+
+        // With overrides, callsites up to the most verbose level have
+        // to be checked each time.
+
+        let f = filter(
+            r#"
+log_level = "warn"
+
+[log.modules]
+"drmemd::logic" = "debug"
+"#,
+        );
+
+        assert!(interest(&f, info).is_sometimes());
+        assert!(interest(&f, debug).is_sometimes());
+        assert!(interest(&f, trace).is_never());
+    }
+}
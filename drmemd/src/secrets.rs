@@ -0,0 +1,202 @@
+// Holds the secrets that driver parameters refer to, so API keys and
+// passwords don't have to be kept in `drmem.toml`. A parameter whose
+// value is "@name" gets the value of the secret `name`. A value that
+// really starts with "@" is written with "@@". References are
+// resolved when an instance starts so `--print-config` only shows
+// the names.
+//
+// Secrets come from a TOML file of `name = "value"` pairs, which
+// other users must not be able to read, or, when `drmemd` is built
+// with the 'keyring' feature, from the OS keyring.
+
+use crate::config;
+use drmem_api::{driver::DriverConfig, Error, Result};
+use std::collections::HashMap;
+use toml::value::Value;
+
+#[derive(Default)]
+pub struct Store {
+    values: HashMap<String, String>,
+    #[cfg(feature = "keyring")]
+    keyring: Option<String>,
+}
+
+// Makes sure only the owner can read the secrets file.
+
+#[cfg(unix)]
+fn check_mode(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let meta = std::fs::metadata(path).map_err(|e| {
+        Error::ConfigError(format!("couldn't read {} -- {}", path.display(), e))
+    })?;
+
+    if meta.permissions().mode() & 0o077 != 0 {
+        Err(Error::ConfigError(format!(
+            "{} can be read by other users",
+            path.display()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+fn check_mode(_: &std::path::Path) -> Result<()> {
+    Ok(())
+}
+
+impl Store {
+    pub fn load(cfg: &config::Secrets) -> Result<Self> {
+        #[cfg(not(feature = "keyring"))]
+        if cfg.keyring.is_some() {
+            return Err(Error::ConfigError(
+                "drmemd was built without the 'keyring' feature".into(),
+            ));
+        }
+
+        let values = match &cfg.file {
+            Some(path) => {
+                check_mode(path)?;
+                std::fs::read_to_string(path)
+                    .map_err(|e| format!("{}", e))
+                    .and_then(|v| {
+                        toml::from_str(&v).map_err(|e| format!("{}", e))
+                    })
+                    .map_err(|e| {
+                        Error::ConfigError(format!(
+                            "{} -- {}",
+                            path.display(),
+                            e
+                        ))
+                    })?
+            }
+            None => HashMap::new(),
+        };
+
+        Ok(Store {
+            values,
+            #[cfg(feature = "keyring")]
+            keyring: cfg.keyring.clone(),
+        })
+    }
+
+    fn lookup(&self, name: &str) -> Result<String> {
+        if let Some(v) = self.values.get(name) {
+            return Ok(v.clone());
+        }
+
+        #[cfg(feature = "keyring")]
+        if let Some(service) = &self.keyring {
+            match keyring::Entry::new(service, name)
+                .and_then(|v| v.get_password())
+            {
+                Ok(v) => return Ok(v),
+                Err(keyring::Error::NoEntry) => (),
+                Err(e) => {
+                    return Err(Error::ConfigError(format!(
+                        "couldn't get secret '{}' from keyring -- {}",
+                        name, e
+                    )))
+                }
+            }
+        }
+
+        Err(Error::ConfigError(format!("unknown secret '{}'", name)))
+    }
+
+    fn resolve_value(&self, v: &Value) -> Result<Value> {
+        Ok(match v {
+            Value::String(v) if v.starts_with("@@") => {
+                Value::String(v[1..].into())
+            }
+            Value::String(v) if v.starts_with('@') => {
+                Value::String(self.lookup(&v[1..])?)
+            }
+            Value::Array(v) => Value::Array(
+                v.iter()
+                    .map(|v| self.resolve_value(v))
+                    .collect::<Result<_>>()?,
+            ),
+            Value::Table(v) => Value::Table(self.resolve(v)?),
+            v => v.clone(),
+        })
+    }
+
+    // Returns a copy of a driver's parameters with the references to
+    // secrets replaced by their values.
+
+    pub fn resolve(&self, cfg: &DriverConfig) -> Result<DriverConfig> {
+        cfg.iter()
+            .map(|(k, v)| self.resolve_value(v).map(|v| (k.clone(), v)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let store = Store {
+            values: [("wu_key".to_string(), "abc123".to_string())]
+                .into_iter()
+                .collect(),
+            #[cfg(feature = "keyring")]
+            keyring: None,
+        };
+
+        let cfg: DriverConfig = toml::from_str(
+            r#"
+station = "KILCHICA123"
+key = "@wu_key"
+chat_id = "@@drmem_alerts"
+keys = ["@wu_key", 5]
+auth = { password = "@wu_key" }
+"#,
+        )
+        .unwrap();
+        let cfg = store.resolve(&cfg).unwrap();
+
+        assert_eq!(cfg["station"].as_str(), Some("KILCHICA123"));
+        assert_eq!(cfg["key"].as_str(), Some("abc123"));
+        assert_eq!(cfg["chat_id"].as_str(), Some("@drmem_alerts"));
+        assert_eq!(cfg["keys"][0].as_str(), Some("abc123"));
+        assert_eq!(cfg["keys"][1].as_integer(), Some(5));
+        assert_eq!(cfg["auth"]["password"].as_str(), Some("abc123"));
+
+        let cfg: DriverConfig = toml::from_str("key = \"@other\"").unwrap();
+
+        assert!(store.resolve(&cfg).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join("drmem-test-secrets.toml");
+        let cfg = config::Secrets {
+            file: Some(path.clone()),
+            keyring: None,
+        };
+
+        std::fs::write(&path, "wu_key = \"abc123\"\n").unwrap();
+
+        // Files that other users can read are rejected.
+
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o644))
+            .unwrap();
+        assert!(Store::load(&cfg).is_err());
+
+        std::fs::set_permissions(&path, PermissionsExt::from_mode(0o600))
+            .unwrap();
+        assert_eq!(
+            Store::load(&cfg).unwrap().lookup("wu_key").unwrap(),
+            "abc123"
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
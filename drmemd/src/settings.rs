@@ -0,0 +1,102 @@
+// Applies the lists of settings from the configuration. The
+// `[startup]` settings put outputs in a known state once the drivers
+// have registered their devices, rather than whatever the hardware
+// retained after a power failure. The `[shutdown]` settings leave
+// devices in a safe state (e.g. a heater turned off) before `drmemd`
+// exits.
+
+use crate::config;
+use drmem_api::{client, device};
+use std::time::Duration;
+use tracing::{info, warn};
+
+// Applies the settings, in order. A setting that fails, or takes
+// longer than `limit`, is logged and skipped so the rest still get
+// applied. `when` describes the moment, for the log.
+
+pub async fn apply(
+    settings: &[config::Setting],
+    cchan: &client::RequestChan,
+    limit: Duration,
+    when: &str,
+) {
+    for s in settings {
+        let Ok(value) = device::Value::try_from(&s.value) else {
+            continue;
+        };
+
+        match tokio::time::timeout(
+            limit,
+            cchan.set_value(s.device.clone(), value.clone()),
+        )
+        .await
+        {
+            Ok(Ok(v)) => info!("set {} to {} {}", &s.device, v, when),
+            Ok(Err(e)) => warn!("couldn't set {} -- {}", &s.device, e),
+            Err(_) => warn!("timed out setting {} to {}", &s.device, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_settings() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let cchan = client::RequestChan::new(tx);
+        let setting = |dev: &str, value: toml::Value| config::Setting {
+            device: dev.parse().unwrap(),
+            value,
+        };
+        let settings = [
+            setting("heater:enable", false.into()),
+            setting("pump:speed", 0.into()),
+            setting("lamp:level", 0.0.into()),
+        ];
+
+        // Fake the core. The heater accepts its setting, the pump
+        // never replies, and the lamp's driver is gone.
+
+        let core = tokio::spawn(async move {
+            let mut seen = vec![];
+            let mut held = vec![];
+
+            while let Some(req) = rx.recv().await {
+                if let client::Request::SetDevice {
+                    name,
+                    value,
+                    rpy_chan,
+                } = req
+                {
+                    seen.push((name.to_string(), value.clone()));
+
+                    match name.to_string().as_str() {
+                        "heater:enable" => {
+                            let _ = rpy_chan.send(Ok(value));
+                        }
+                        "pump:speed" => held.push(rpy_chan),
+                        _ => drop(rpy_chan),
+                    }
+                }
+            }
+            seen
+        });
+
+        apply(&settings, &cchan, Duration::from_millis(50), "in test").await;
+        drop(cchan);
+
+        // Every setting was tried, in order, even after one timed
+        // out.
+
+        assert_eq!(
+            core.await.unwrap(),
+            vec![
+                ("heater:enable".into(), device::Value::Bool(false)),
+                ("pump:speed".into(), device::Value::Int(0)),
+                ("lamp:level".into(), device::Value::Flt(0.0)),
+            ]
+        );
+    }
+}
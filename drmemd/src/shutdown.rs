@@ -0,0 +1,77 @@
+// Coordinates stopping `drmemd`. When SIGTERM or SIGINT arrives, the
+// logic blocks are stopped, the configured "safe state" settings are
+// applied (see `settings.rs`), and then the driver instances are told
+// to stop. Each instance gets to run its shutdown hook, which can turn
+// off hardware and report final readings, before `drmemd` exits.
+
+use std::sync::LazyLock;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+static STOP: LazyLock<watch::Sender<bool>> =
+    LazyLock::new(|| watch::channel(false).0);
+
+// Tells the driver managers to stop their instances.
+
+pub fn request() {
+    STOP.send_replace(true);
+}
+
+// Resolves once a shutdown has been requested.
+
+pub async fn requested() {
+    let _ = STOP.subscribe().wait_for(|v| *v).await;
+}
+
+// Lets a single driver instance be stopped, when it's removed from
+// the configuration, without stopping the others. Dropping the
+// `watch::Sender` also stops the instance.
+
+#[derive(Clone)]
+pub struct Stop(watch::Receiver<bool>);
+
+impl Stop {
+    pub fn new() -> (watch::Sender<bool>, Stop) {
+        let (tx, rx) = watch::channel(false);
+
+        (tx, Stop(rx))
+    }
+
+    // Resolves once the instance, or all of `drmemd`, has to stop.
+
+    pub async fn requested(&self) {
+        let mut rx = self.0.clone();
+
+        tokio::select! {
+            _ = rx.wait_for(|v| *v) => (),
+            _ = requested() => ()
+        }
+    }
+}
+
+// Resolves when the process is asked to stop.
+
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = term.recv() => info!("received SIGTERM"),
+                _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+            },
+            Err(e) => {
+                warn!("can't catch SIGTERM -- {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                info!("received SIGINT")
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("received SIGINT")
+    }
+}
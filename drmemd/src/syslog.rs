@@ -0,0 +1,306 @@
+// Sends the log to a syslog server, using the RFC 3164 format. The
+// local server is reached through its Unix socket. Remote servers are
+// reached over UDP or TCP. If a TCP connection drops, it's reopened
+// for the next message.
+//
+// Problems sending messages can't be logged, so they're written to
+// stderr.
+
+use crate::config;
+use chrono::Local;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::Duration,
+};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+// Where the local server listens, on the systems we know about.
+
+#[cfg(unix)]
+const LOCAL_PATHS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Transport {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(String, Option<TcpStream>),
+}
+
+fn tcp_connect(addr: &str) -> io::Result<TcpStream> {
+    let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "address didn't resolve")
+    })?;
+    let stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)?;
+
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(stream)
+}
+
+impl Transport {
+    fn open(address: Option<&str>) -> io::Result<Self> {
+        match address {
+            None => Self::local(),
+            Some(v) => {
+                if let Some(addr) = v.strip_prefix("udp://") {
+                    let addr =
+                        addr.to_socket_addrs()?.next().ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::NotFound,
+                                "address didn't resolve",
+                            )
+                        })?;
+                    let sock = UdpSocket::bind(if addr.is_ipv4() {
+                        "0.0.0.0:0"
+                    } else {
+                        "[::]:0"
+                    })?;
+
+                    sock.connect(addr)?;
+                    Ok(Transport::Udp(sock))
+                } else if let Some(addr) = v.strip_prefix("tcp://") {
+                    // Not being able to reach the server, yet, isn't
+                    // an error. The connection is retried as messages
+                    // are logged.
+
+                    Ok(Transport::Tcp(addr.into(), tcp_connect(addr).ok()))
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "unknown syslog address",
+                    ))
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn local() -> io::Result<Self> {
+        let sock = UnixDatagram::unbound()?;
+
+        for path in LOCAL_PATHS {
+            if sock.connect(path).is_ok() {
+                return Ok(Transport::Local(sock));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no local syslog server",
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn local() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no local syslog server",
+        ))
+    }
+
+    fn send(&mut self, msg: &str) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Transport::Local(sock) => sock.send(msg.as_bytes()).map(|_| ()),
+            Transport::Udp(sock) => sock.send(msg.as_bytes()).map(|_| ()),
+
+            // Messages sent over TCP are separated by newlines.
+            Transport::Tcp(addr, stream) => {
+                if stream.is_none() {
+                    *stream = Some(tcp_connect(addr)?)
+                }
+
+                let result = stream
+                    .as_mut()
+                    .map(|s| s.write_all(format!("{}\n", msg).as_bytes()))
+                    .unwrap_or(Ok(()));
+
+                if result.is_err() {
+                    *stream = None
+                }
+                result
+            }
+        }
+    }
+}
+
+pub struct Syslog {
+    transport: Mutex<Transport>,
+    facility: u8,
+    // Only remote servers need to be told which host sent the
+    // message.
+    hostname: Option<String>,
+    pid: u32,
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|v| {
+            std::fs::read_to_string(v)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        })
+        .unwrap_or_else(|| "localhost".into())
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+impl Syslog {
+    pub fn open(cfg: &config::Syslog) -> io::Result<Self> {
+        Ok(Syslog {
+            transport: Mutex::new(Transport::open(cfg.address.as_deref())?),
+            facility: cfg.facility as u8,
+            hostname: cfg.address.as_ref().map(|_| hostname()),
+            pid: std::process::id(),
+        })
+    }
+
+    fn format(&self, level: &Level, msg: &str) -> String {
+        let pri = self.facility * 8 + severity(level);
+        let time = Local::now().format("%b %e %H:%M:%S");
+
+        match &self.hostname {
+            Some(host) => {
+                format!(
+                    "<{}>{} {} drmemd[{}]: {}",
+                    pri, time, host, self.pid, msg
+                )
+            }
+            None => format!("<{}>{} drmemd[{}]: {}", pri, time, self.pid, msg),
+        }
+    }
+
+    fn send(&self, level: &Level, msg: &str) {
+        let msg = self.format(level, msg);
+        let mut transport =
+            self.transport.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Err(e) = transport.send(&msg) {
+            eprintln!("couldn't send message to syslog -- {}", e)
+        }
+    }
+}
+
+// Collects one formatted message and sends it when it's dropped.
+
+pub struct Message<'a> {
+    syslog: &'a Syslog,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        let msg = String::from_utf8_lossy(&self.buf);
+        let msg = msg.trim_end();
+
+        if !msg.is_empty() {
+            self.syslog.send(&self.level, msg)
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: Level::INFO,
+            buf: vec![],
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Message {
+            syslog: self,
+            level: *meta.level(),
+            buf: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let cfg = config::Syslog {
+            address: Some(format!("udp://{}", server.local_addr().unwrap())),
+            facility: config::Facility::Local3,
+        };
+        let syslog = Syslog::open(&cfg).unwrap();
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        {
+            let mut msg = syslog.make_writer();
+
+            msg.level = Level::WARN;
+            msg.write_all(b"disk is getting full\n").unwrap();
+        }
+
+        let mut buf = [0u8; 512];
+        let len = server.recv(&mut buf).unwrap();
+        let msg = std::str::from_utf8(&buf[..len]).unwrap();
+
+        // local3 is 19; warnings are severity 4.
+
+        assert!(msg.starts_with("<156>"));
+        assert!(msg.ends_with(&format!(
+            " {} drmemd[{}]: disk is getting full",
+            hostname(),
+            std::process::id()
+        )));
+    }
+
+    #[test]
+    fn test_tcp() {
+        use std::io::{BufRead, BufReader};
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let cfg = config::Syslog {
+            address: Some(format!("tcp://{}", server.local_addr().unwrap())),
+            facility: config::Facility::Daemon,
+        };
+        let syslog = Syslog::open(&cfg).unwrap();
+
+        syslog.send(&Level::ERROR, "one");
+        syslog.send(&Level::DEBUG, "two");
+
+        let (conn, _) = server.accept().unwrap();
+        let mut lines = BufReader::new(conn).lines();
+
+        assert!(lines.next().unwrap().unwrap().starts_with("<27>"));
+        assert!(lines.next().unwrap().unwrap().starts_with("<31>"));
+    }
+}
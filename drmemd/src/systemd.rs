@@ -0,0 +1,104 @@
+// Tells systemd how `drmemd` is doing, when it runs as a
+// `Type=notify` service. READY is sent once the backend and the
+// drivers have started and STOPPING when a shutdown begins. If the
+// unit sets `WatchdogSec=`, the core task pets the watchdog so systemd
+// restarts a `drmemd` that has hung.
+//
+// Without the `systemd` feature, or when `drmemd` wasn't started by
+// systemd, these functions do nothing.
+
+use std::time::Duration;
+#[cfg(feature = "systemd")]
+use tracing::{info, warn};
+
+#[cfg(feature = "systemd")]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("couldn't notify systemd -- {}", e)
+    }
+}
+
+// Reports that `drmemd` is up and running.
+
+pub fn ready() {
+    #[cfg(feature = "systemd")]
+    notify(sd_notify::NotifyState::Ready)
+}
+
+// Reports that `drmemd` is shutting down.
+
+pub fn stopping() {
+    #[cfg(feature = "systemd")]
+    notify(sd_notify::NotifyState::Stopping)
+}
+
+// Returns how often the watchdog has to be petted, or `None` if it
+// isn't enabled. systemd gets pinged at twice the rate it requires.
+
+fn watchdog_period() -> Option<Duration> {
+    #[cfg(feature = "systemd")]
+    {
+        let mut usec = 0;
+
+        if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+            info!("petting the systemd watchdog every {} µs", usec / 2);
+            return Some(Duration::from_micros(usec / 2));
+        }
+    }
+    None
+}
+
+// Pets the watchdog. It's driven by the core task's loop so, if the
+// core stops handling requests, systemd stops hearing from `drmemd`.
+
+pub struct Watchdog(Option<tokio::time::Interval>);
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog(watchdog_period().map(|v| {
+            let mut interval = tokio::time::interval(v);
+
+            interval.set_missed_tick_behavior(
+                tokio::time::MissedTickBehavior::Delay,
+            );
+            interval
+        }))
+    }
+
+    // Resolves when it's time to pet the watchdog. If the watchdog
+    // isn't enabled, it never resolves.
+
+    pub async fn tick(&mut self) {
+        match &mut self.0 {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn pet(&self) {
+        #[cfg(feature = "systemd")]
+        notify(sd_notify::NotifyState::Watchdog)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_disabled() {
+        // The tests aren't run by systemd, so the watchdog is off and
+        // never asks to be petted.
+
+        let mut wd = Watchdog::new();
+
+        assert!(wd.0.is_none());
+        assert!(tokio::time::timeout(Duration::from_millis(10), wd.tick())
+            .await
+            .is_err());
+        ready();
+        stopping();
+    }
+}
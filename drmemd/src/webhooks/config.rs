@@ -0,0 +1,64 @@
+use drmem_api::{Error, Result};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde_derive::Deserialize;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+
+fn def_timeout() -> u64 {
+    10
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    // The URL to which the changes are POSTed.
+    pub url: String,
+    // The patterns of the devices whose changes are sent.
+    pub devices: Vec<String>,
+    // How long, in milliseconds, a device has to keep its value before
+    // the change is sent. Changes that are replaced sooner than this
+    // are never sent. 0 sends every change.
+    #[serde(default)]
+    pub debounce: u64,
+    // How long, in seconds, to wait for the receiver to reply.
+    #[serde(default = "def_timeout")]
+    pub timeout: u64,
+    // Extra headers added to each request, like authorization tokens.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // The body of each request. Strings in it can use `{device}`,
+    // `{value}`, and `{stamp}`. A string that's only `{value}` is
+    // replaced by the value itself.
+    pub payload: Option<Json>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.url.starts_with("http://")
+            || self.url.starts_with("https://"))
+        {
+            return Err(Error::ConfigError(format!(
+                "webhook URL '{}' needs to be an HTTP or HTTPS URL",
+                &self.url
+            )));
+        }
+
+        if self.devices.is_empty() {
+            return Err(Error::ConfigError(format!(
+                "webhook for '{}' doesn't match any devices",
+                &self.url
+            )));
+        }
+
+        for (k, v) in &self.headers {
+            if HeaderName::from_bytes(k.as_bytes()).is_err()
+                || HeaderValue::from_str(v).is_err()
+            {
+                return Err(Error::ConfigError(format!(
+                    "webhook for '{}' has a bad header '{}'",
+                    &self.url, k
+                )));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,412 @@
+// Sends the changes of devices to web services that can't subscribe
+// to the GraphQL interface. Each webhook in the configuration names a
+// URL and the patterns of the devices it watches. When one of the
+// devices changes value, a JSON body describing the change is POSTed
+// to the URL.
+//
+// A webhook can wait for a device to keep its new value for a while
+// before sending it, so a noisy device only sends the value it
+// settles on.
+
+use crate::{events, glob::Pattern};
+use chrono::{DateTime, Utc};
+use drmem_api::{client, device};
+use futures::Future;
+use serde_json::{json, Value as Json};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::{broadcast::error::RecvError, Semaphore},
+    time::Instant,
+};
+use tokio_stream::{StreamExt, StreamMap};
+use tracing::{error, info, warn};
+
+pub mod config;
+
+// The number of requests to a webhook that can be waiting for a
+// reply. Changes that happen while this many are waiting are
+// dropped.
+
+const MAX_IN_FLIGHT: usize = 10;
+
+fn value_to_json(v: &device::Value) -> Json {
+    match v {
+        device::Value::Bool(v) => json!(v),
+        device::Value::Int(v) => json!(v),
+        device::Value::Flt(v) => json!(v),
+        device::Value::Str(v) => json!(v.as_ref()),
+        device::Value::Color(v) => json!([v.red, v.green, v.blue, v.alpha]),
+    }
+}
+
+// Returns the value as it appears inside a string of a payload.
+// Strings aren't quoted.
+
+fn value_to_text(v: &device::Value) -> String {
+    match v {
+        device::Value::Str(v) => v.to_string(),
+        _ => v.to_string().trim_matches('"').into(),
+    }
+}
+
+// Builds the body sent for a change. Without a template, it holds the
+// device name, the timestamp, and the value.
+
+fn render(template: Option<&Json>, dev: &str, r: &device::Reading) -> Json {
+    let stamp = DateTime::<Utc>::from(r.ts).to_rfc3339();
+
+    fn fill(t: &Json, dev: &str, stamp: &str, r: &device::Reading) -> Json {
+        match t {
+            Json::String(s) if s == "{value}" => value_to_json(&r.value),
+            Json::String(s) => Json::String(
+                s.replace("{device}", dev)
+                    .replace("{stamp}", stamp)
+                    .replace("{value}", &value_to_text(&r.value)),
+            ),
+            Json::Array(v) => {
+                Json::Array(v.iter().map(|v| fill(v, dev, stamp, r)).collect())
+            }
+            Json::Object(v) => Json::Object(
+                v.iter()
+                    .map(|(k, v)| (k.clone(), fill(v, dev, stamp, r)))
+                    .collect(),
+            ),
+            _ => t.clone(),
+        }
+    }
+
+    match template {
+        Some(t) => fill(t, dev, &stamp, r),
+        None => json!({
+            "device": dev,
+            "stamp": stamp,
+            "value": value_to_json(&r.value),
+        }),
+    }
+}
+
+// Decides which readings are changes that need to be sent. The first
+// reading of a device is its current value, so it isn't a change.
+
+struct Changes {
+    debounce: Duration,
+    // The last value sent, or the first one seen, for each device.
+    sent: HashMap<String, device::Value>,
+    // Changes waiting for the debounce time to pass.
+    pending: HashMap<String, (device::Reading, Instant)>,
+}
+
+impl Changes {
+    fn new(debounce: Duration) -> Self {
+        Changes {
+            debounce,
+            sent: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // Records a reading. Returns it if it should be sent now.
+
+    fn update(
+        &mut self,
+        dev: &str,
+        r: device::Reading,
+        now: Instant,
+    ) -> Option<device::Reading> {
+        match self.sent.get(dev) {
+            None => {
+                self.sent.insert(dev.into(), r.value);
+                None
+            }
+
+            // Going back to the value that was last sent cancels the
+            // change that's waiting.
+            Some(v) if *v == r.value => {
+                self.pending.remove(dev);
+                None
+            }
+
+            Some(_) if self.debounce.is_zero() => {
+                self.sent.insert(dev.into(), r.value.clone());
+                Some(r)
+            }
+
+            Some(_) => {
+                self.pending.insert(dev.into(), (r, now + self.debounce));
+                None
+            }
+        }
+    }
+
+    // Returns when the next waiting change is due.
+
+    fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, due)| *due).min()
+    }
+
+    // Removes and returns the changes that are due.
+
+    fn take_due(&mut self, now: Instant) -> Vec<(String, device::Reading)> {
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, t))| *t <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        due.into_iter()
+            .filter_map(|dev| {
+                let (r, _) = self.pending.remove(&dev)?;
+
+                self.sent.insert(dev.clone(), r.value.clone());
+                Some((dev, r))
+            })
+            .collect()
+    }
+}
+
+struct Webhook {
+    url: String,
+    devices: Vec<Pattern>,
+    headers: HashMap<String, String>,
+    payload: Option<Json>,
+    client: reqwest::Client,
+    slots: Arc<Semaphore>,
+    cchan: client::RequestChan,
+}
+
+impl Webhook {
+    fn wants(&self, name: &str) -> bool {
+        self.devices.iter().any(|p| p.matches(name))
+    }
+
+    // Adds the device to the set being watched, if it matches one of
+    // the patterns.
+
+    async fn monitor(
+        &self,
+        streams: &mut StreamMap<String, device::DataStream<device::Reading>>,
+        name: device::Name,
+    ) {
+        let key = name.to_string();
+
+        if self.wants(&key) && !streams.contains_key(&key) {
+            match self.cchan.monitor_device(name, None, None).await {
+                Ok(s) => {
+                    streams.insert(key, s);
+                }
+                Err(e) => warn!("couldn't monitor '{}' -- {}", key, e),
+            }
+        }
+    }
+
+    // POSTs a change in the background.
+
+    fn send(&self, dev: &str, r: &device::Reading) {
+        let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+            warn!("dropping change of '{}' -- too many requests waiting", dev);
+            return;
+        };
+        let mut req = self.client.post(&self.url).json(&render(
+            self.payload.as_ref(),
+            dev,
+            r,
+        ));
+
+        for (k, v) in &self.headers {
+            req = req.header(k, v)
+        }
+
+        let dev = dev.to_string();
+
+        tokio::spawn(async move {
+            match req.send().await.and_then(|v| v.error_for_status()) {
+                Ok(_) => (),
+                Err(e) => warn!("couldn't send change of '{}' -- {}", dev, e),
+            }
+            drop(slot)
+        });
+    }
+
+    async fn run(self, mut changes: Changes) {
+        // Listen for new devices before getting the current ones so
+        // none are missed.
+
+        let mut new_devices = events::subscribe();
+        let mut streams = StreamMap::new();
+
+        match self.cchan.get_device_info(None).await {
+            Ok(devs) => {
+                for dev in devs {
+                    self.monitor(&mut streams, dev.name).await
+                }
+            }
+            Err(e) => {
+                error!("couldn't get the list of devices -- {}", e);
+                return;
+            }
+        }
+
+        loop {
+            let due = changes.next_due();
+
+            tokio::select! {
+                Some((dev, reading)) = streams.next() => {
+                    if let Some(r) = changes.update(&dev, reading, Instant::now()) {
+                        self.send(&dev, &r)
+                    }
+                }
+
+                _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)),
+                    if due.is_some() => {
+                    for (dev, r) in changes.take_due(Instant::now()) {
+                        self.send(&dev, &r)
+                    }
+                }
+
+                ev = new_devices.recv() => match ev {
+                    Ok(ev) if ev.kind == events::Kind::DeviceRegistered => {
+                        if let Ok(name) = ev.source.parse() {
+                            self.monitor(&mut streams, name).await
+                        }
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => {
+                        error!("event bus closed");
+                        break;
+                    }
+                },
+            }
+        }
+    }
+}
+
+// Returns a future that runs a webhook.
+
+pub fn webhook(
+    cfg: &config::Config,
+    cchan: client::RequestChan,
+) -> impl Future<Output = ()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(cfg.timeout))
+        .build()
+        .unwrap_or_default();
+    let hook = Webhook {
+        url: cfg.url.clone(),
+        devices: cfg.devices.iter().map(|v| Pattern::create(v)).collect(),
+        headers: cfg.headers.clone(),
+        payload: cfg.payload.clone(),
+        client,
+        slots: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        cchan,
+    };
+
+    info!("webhook sending changes to {}", &cfg.url);
+    hook.run(Changes::new(Duration::from_millis(cfg.debounce)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn reading(value: device::Value) -> device::Reading {
+        device::Reading {
+            ts: UNIX_EPOCH,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_payloads() {
+        let r = reading(device::Value::Flt(2.5));
+
+        assert_eq!(
+            render(None, "sump:level", &r),
+            json!({
+                "device": "sump:level",
+                "stamp": "1970-01-01T00:00:00+00:00",
+                "value": 2.5,
+            })
+        );
+
+        let template = json!({
+            "text": "{device} is at {value}",
+            "level": "{value}",
+            "tags": ["{stamp}", 5],
+        });
+
+        assert_eq!(
+            render(Some(&template), "sump:level", &r),
+            json!({
+                "text": "sump:level is at 2.5",
+                "level": 2.5,
+                "tags": ["1970-01-01T00:00:00+00:00", 5],
+            })
+        );
+
+        let template = json!({ "text": "{value}!" });
+
+        assert_eq!(
+            render(
+                Some(&template),
+                "a:b",
+                &reading(device::Value::Str("on".into()))
+            ),
+            json!({ "text": "on!" })
+        );
+        assert_eq!(
+            render(
+                Some(&template),
+                "a:b",
+                &reading(device::Value::Color(palette::LinSrgba::new(
+                    255, 0, 16, 255
+                )))
+            ),
+            json!({ "text": "#ff0010!" })
+        );
+    }
+
+    #[test]
+    fn test_changes() {
+        let now = Instant::now();
+        let on = || reading(device::Value::Bool(true));
+        let off = || reading(device::Value::Bool(false));
+
+        // Without debouncing, every change is sent right away. The
+        // first reading and repeated values aren't changes.
+
+        let mut changes = Changes::new(Duration::ZERO);
+
+        assert_eq!(changes.update("a:b", off(), now), None);
+        assert_eq!(changes.update("a:b", off(), now), None);
+        assert_eq!(changes.update("a:b", on(), now), Some(on()));
+        assert_eq!(changes.update("a:b", on(), now), None);
+        assert_eq!(changes.update("a:c", on(), now), None);
+        assert_eq!(changes.next_due(), None);
+
+        // With debouncing, a change waits. A later change replaces it
+        // and restarts the wait.
+
+        let ms = Duration::from_millis;
+        let mut changes = Changes::new(ms(100));
+        let level = |v: i32| reading(device::Value::Int(v));
+
+        assert_eq!(changes.update("a:b", level(1), now), None);
+        assert_eq!(changes.update("a:b", level(2), now), None);
+        assert_eq!(changes.next_due(), Some(now + ms(100)));
+        assert_eq!(changes.update("a:b", level(3), now + ms(50)), None);
+        assert!(changes.take_due(now + ms(100)).is_empty());
+        assert_eq!(
+            changes.take_due(now + ms(150)),
+            vec![("a:b".to_string(), level(3))]
+        );
+        assert_eq!(changes.next_due(), None);
+
+        // Going back to the value that was sent cancels the change.
+
+        assert_eq!(changes.update("a:b", level(4), now + ms(200)), None);
+        assert_eq!(changes.update("a:b", level(3), now + ms(250)), None);
+        assert!(changes.take_due(now + ms(500)).is_empty());
+    }
+}